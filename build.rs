@@ -1,7 +1,54 @@
+use std::env;
 use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 const PROTO_DIR: &str = "./protos";
 
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|value| value.trim().to_owned())
+}
+
+// tonic-build only asks cargo to rerun this script when the protos change, so HEAD and the
+// ref it points to are watched as well to keep the hash current after a commit or checkout
+fn watch_git_head() {
+    let mut paths = vec![git(&["rev-parse", "--git-path", "HEAD"])];
+    if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        paths.push(git(&["rev-parse", "--git-path", &head_ref]));
+    }
+    paths.push(git(&["rev-parse", "--git-path", "packed-refs"]));
+
+    for path in paths.into_iter().flatten() {
+        if PathBuf::from(&path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+// Reported by the reflection service, builds from a source archive have no git hash.
+// The timestamp is the last time this script ran, which is best-effort: a rebuild that
+// doesn't touch the protos or the git HEAD keeps the previous value.
+fn emit_build_info() {
+    let git_hash = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_owned());
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
+    watch_git_head();
+    println!("cargo:rustc-env=NVOS_GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=NVOS_BUILD_TIMESTAMP={}", timestamp);
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    emit_build_info();
     let entries: Vec<String> = fs::read_dir(PROTO_DIR)
         .expect("Failed to list proto directory")
         .filter_map(|entry| {
@@ -20,7 +67,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    // picked up by the gRPC server reflection service
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR")?).join("nvos_descriptor.bin");
     tonic_build::configure()
+        .file_descriptor_set_path(descriptor_path)
         .build_server(true)
         .build_transport(true)
         .build_client(false)
@@ -5,11 +5,16 @@ use std::{sync::Arc, time::Duration};
 use tokio::sync::broadcast;
 use tokio::time;
 
+use crate::config::ConfigSectionADB;
+
 const DEFAULT_ADB_HOST: &str = "localhost";
 const DEFAULT_ADB_PORT: u16 = 5037;
 const DEFAULT_ADB_READ_TIMEOUT: Duration = Duration::from_secs(1);
 const DEFAULT_ADB_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
 const CONNECTION_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CONNECTION_EVENT_CAPACITY: usize = 16;
+pub const DEFAULT_PORT_RESTORE_ATTEMPTS: u32 = 3;
+pub const DEFAULT_PORT_RESTORE_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum PortType {
@@ -34,6 +39,167 @@ impl Port {
     }
 }
 
+// The subset of the device API used for port mappings, lets the restore logic run without a real device
+pub trait PortMapper {
+    fn forward_port(&self, local_port: u16, remote_port: u16) -> Result<(), DeviceError>;
+    fn reverse_port(&self, remote_port: u16, local_port: u16) -> Result<(), DeviceError>;
+}
+
+impl PortMapper for Device {
+    fn forward_port(&self, local_port: u16, remote_port: u16) -> Result<(), DeviceError> {
+        Device::forward_port(self, local_port, remote_port).map(|_| ())
+    }
+
+    fn reverse_port(&self, remote_port: u16, local_port: u16) -> Result<(), DeviceError> {
+        Device::reverse_port(self, remote_port, local_port).map(|_| ())
+    }
+}
+
+fn apply_port<T: PortMapper>(device: &T, port: &Port) -> Result<(), DeviceError> {
+    match port.port_type {
+        PortType::Forward => device.forward_port(port.local_port_num, port.remote_port_num),
+        PortType::Reverse => device.reverse_port(port.remote_port_num, port.local_port_num),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PortRestorePolicy {
+    pub attempts: u32,
+    pub retry_delay: Duration,
+}
+
+impl Default for PortRestorePolicy {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_PORT_RESTORE_ATTEMPTS,
+            retry_delay: DEFAULT_PORT_RESTORE_DELAY,
+        }
+    }
+}
+
+// Re-applies every mapping, retrying each one on its own. Returns the mappings that could not be restored
+pub async fn restore_ports<T: PortMapper>(
+    device: &Mutex<Option<T>>,
+    ports: &[Port],
+    policy: PortRestorePolicy,
+) -> Vec<Port> {
+    let mut failed = Vec::new();
+    for port in ports {
+        let mut attempt = 1;
+        loop {
+            // the device is only locked per attempt so the delay doesn't block other users
+            let result = match device.lock().as_ref() {
+                Some(device) => apply_port(device, port),
+                None => Err(DeviceError::Adb("device not connected".to_string())),
+            };
+
+            match result {
+                Ok(_) => {
+                    debug!("Restored port mapping: {:?}", port);
+                    break;
+                }
+                Err(e) if attempt < policy.attempts => {
+                    warn!(
+                        "Failed to restore mapping {:?} (attempt {}/{}): {}",
+                        port, attempt, policy.attempts, e
+                    );
+                    attempt += 1;
+                    time::sleep(policy.retry_delay).await;
+                }
+                Err(e) => {
+                    error!(
+                        "Giving up on restoring mapping {:?} after {} attempt(s): {}",
+                        port, attempt, e
+                    );
+                    failed.push(port.clone());
+                    break;
+                }
+            }
+        }
+    }
+
+    failed
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEventKind {
+    Connected,
+    Disconnected,
+    // sent after a reconnect once every mapping has been tried
+    PortsRestored { restored: Vec<Port>, failed: Vec<Port> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionEvent {
+    pub kind: ConnectionEventKind,
+    pub serial: String,
+}
+
+// Keeps track of the attached device and tells subscribers when it changes, does nothing when events are disabled
+pub struct ConnectionTracker {
+    serial: Option<String>,
+    sender: Option<broadcast::Sender<ConnectionEvent>>,
+}
+
+impl ConnectionTracker {
+    pub fn new(sender: Option<broadcast::Sender<ConnectionEvent>>) -> Self {
+        Self { serial: None, sender }
+    }
+
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    fn emit(&self, serial: &str, kind: ConnectionEventKind) {
+        if let Some(sender) = &self.sender {
+            // Sending only fails when nobody is subscribed
+            let _ = sender.send(ConnectionEvent { kind, serial: serial.to_string() });
+        }
+    }
+
+    pub fn connected(&mut self, serial: &str) {
+        if self.serial.as_deref() == Some(serial) {
+            return;
+        }
+
+        self.disconnected();
+        self.serial = Some(serial.to_string());
+        self.emit(serial, ConnectionEventKind::Connected);
+    }
+
+    pub fn disconnected(&mut self) {
+        if let Some(serial) = self.serial.take() {
+            self.emit(&serial, ConnectionEventKind::Disconnected);
+        }
+    }
+
+    pub fn ports_restored(&self, attempted: &[Port], failed: &[Port]) {
+        if let Some(serial) = self.serial() {
+            let restored = attempted.iter().filter(|x| !failed.contains(x)).cloned().collect();
+            self.emit(serial, ConnectionEventKind::PortsRestored { restored, failed: failed.to_vec() });
+        }
+    }
+}
+
+// Reports the new device and puts its port mappings back. Returns the mappings that could not be restored
+pub async fn reconnect<T: PortMapper>(
+    device: &Mutex<Option<T>>,
+    serial: &str,
+    ports: &[Port],
+    policy: PortRestorePolicy,
+    tracker: &mut ConnectionTracker,
+) -> Vec<Port> {
+    tracker.connected(serial);
+    if ports.is_empty() {
+        debug!("No connections to restore, aborting.");
+        return Vec::new();
+    }
+
+    let failed = restore_ports(device, ports, policy).await;
+    tracker.ports_restored(ports, &failed);
+    failed
+}
+
 #[derive(Clone, Debug)]
 enum WorkerMessage {
     Shutdown,
@@ -43,6 +209,7 @@ pub struct AdbServer {
     device: Arc<Mutex<Option<Device>>>,
     channel: broadcast::Sender<WorkerMessage>,
     forwarded_connections: Arc<RwLock<Vec<Port>>>,
+    connection_events: Option<broadcast::Sender<ConnectionEvent>>,
 }
 
 impl AdbServer {
@@ -60,6 +227,27 @@ impl AdbServer {
         port: u16,
         read_timeout: Duration,
         write_timeout: Duration,
+    ) -> Self {
+        Self::with_restore_policy(host, port, read_timeout, write_timeout, PortRestorePolicy::default())
+    }
+
+    pub fn with_restore_policy(
+        host: &str,
+        port: u16,
+        read_timeout: Duration,
+        write_timeout: Duration,
+        restore_policy: PortRestorePolicy,
+    ) -> Self {
+        Self::with_options(host, port, read_timeout, write_timeout, restore_policy, true)
+    }
+
+    pub fn with_options(
+        host: &str,
+        port: u16,
+        read_timeout: Duration,
+        write_timeout: Duration,
+        restore_policy: PortRestorePolicy,
+        connection_events: bool,
     ) -> Self {
         let mut adb_host = Host::default();
         adb_host.host = Some(host.to_string());
@@ -72,14 +260,19 @@ impl AdbServer {
             device: Arc::new(Mutex::new(None)),
             channel: sender,
             forwarded_connections: Arc::new(RwLock::new(Vec::new())),
+            connection_events: match connection_events {
+                true => Some(broadcast::channel(CONNECTION_EVENT_CAPACITY).0),
+                false => None,
+            },
         };
 
         let device = server.device.clone();
         let forwarded_connections = server.forwarded_connections.clone();
+        let tracker = ConnectionTracker::new(server.connection_events.clone());
 
         debug!("Spawning heartbeat thread");
         tokio::spawn(async move {
-            AdbServerWorker::new(adb_host, device, forwarded_connections, receiver)
+            AdbServerWorker::new(adb_host, device, forwarded_connections, receiver, restore_policy, tracker)
                 .run()
                 .await;
         });
@@ -87,6 +280,25 @@ impl AdbServer {
         server
     }
 
+    // None when ADB is turned off, in which case no worker is spawned
+    pub fn from_config(config: &ConfigSectionADB) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        Some(Self::with_options(
+            &config.server_host,
+            config.server_port,
+            Duration::from_millis(config.read_timeout_ms),
+            Duration::from_millis(config.write_timeout_ms),
+            PortRestorePolicy {
+                attempts: config.port_restore_attempts,
+                retry_delay: Duration::from_millis(config.port_restore_delay_ms),
+            },
+            config.connection_events,
+        ))
+    }
+
     pub fn get_device(&self) -> Result<MappedMutexGuard<'_, Device>, DeviceError> {
         let guard = self.device.lock();
         match guard.is_some() {
@@ -135,11 +347,7 @@ impl AdbServer {
             // Caller wants the port forwarded NOW
             // This will fail if no device is connected to the network
             let device = self.get_device()?;
-
-            match port_type {
-                PortType::Forward => device.forward_port(local_port, remote_port),
-                PortType::Reverse => device.reverse_port(remote_port, local_port),
-            }?;
+            apply_port(&*device, &Port::new(port_type.clone(), local_port, remote_port))?;
         }
 
         self.forwarded_connections.write().push(Port::new(
@@ -213,6 +421,11 @@ impl AdbServer {
         self.device.lock().is_some()
     }
 
+    // None when connection events are turned off in the config
+    pub fn subscribe_connection_events(&self) -> Option<broadcast::Receiver<ConnectionEvent>> {
+        self.connection_events.as_ref().map(|x| x.subscribe())
+    }
+
     pub fn shutdown(&self) {
         debug!("Shutting down ADB server");
         let _ = self.channel.send(WorkerMessage::Shutdown);
@@ -236,6 +449,8 @@ struct AdbServerWorker {
     device: Arc<Mutex<Option<Device>>>,
     forwarded_connections: Arc<RwLock<Vec<Port>>>,
     channel: broadcast::Receiver<WorkerMessage>,
+    restore_policy: PortRestorePolicy,
+    tracker: ConnectionTracker,
     is_connected: bool,
 }
 
@@ -245,12 +460,16 @@ impl AdbServerWorker {
         device: Arc<Mutex<Option<Device>>>,
         forwarded_connections: Arc<RwLock<Vec<Port>>>,
         channel: broadcast::Receiver<WorkerMessage>,
+        restore_policy: PortRestorePolicy,
+        tracker: ConnectionTracker,
     ) -> Self {
         Self {
             host,
             device,
             forwarded_connections,
             channel,
+            restore_policy,
+            tracker,
             is_connected: false,
         }
     }
@@ -286,11 +505,12 @@ impl AdbServerWorker {
         }
 
         if self.device.lock().is_none() {
-            if !self.connect_device().await {
-                return;
-            }
+            let serial = match self.connect_device().await {
+                Some(serial) => serial,
+                None => return
+            };
 
-            self.restore_port_map().await;
+            self.restore_port_map(&serial).await;
             return;
         }
 
@@ -301,6 +521,7 @@ impl AdbServerWorker {
             Err(e) => {
                 debug!("Lost server connection: {}", e);
                 *device = None;
+                self.tracker.disconnected();
                 self.is_connected = false;
                 return;
             }
@@ -309,6 +530,7 @@ impl AdbServerWorker {
         if devices.len() == 0 && device.is_some() {
             debug!("Lost device connection");
             *device = None;
+            self.tracker.disconnected();
         }
     }
 
@@ -325,10 +547,11 @@ impl AdbServerWorker {
         }
     }
 
-    async fn connect_device(&mut self) -> bool {
+    // Returns the serial of the device that was connected
+    async fn connect_device(&mut self) -> Option<String> {
         if !self.is_connected {
             error!("Failed to connect to device: not connected to adb server");
-            return false;
+            return None;
         }
 
         let host = &self.host;
@@ -343,51 +566,30 @@ impl AdbServerWorker {
             Ok(_) => {},
             Err(e) => {
                 error!("Failed to create a device tunnel: {}", e);
-                return false;
+                return None;
             }
         }
 
         match host_cloned.device_or_default::<String>(None, AndroidStorageInput::Auto) {
             Ok(device) => {
                 debug!("Got a device! serial: {}", device.serial);
+                let serial = device.serial.clone();
                 let mut guard = self.device.lock();
                 *guard = Some(device);
-                return true;
+                return Some(serial);
             }
             Err(_) => {
                 // No devices or unauthorized
-                return false;
+                return None;
             }
         }
     }
 
-    async fn restore_port_map(&mut self) {
+    async fn restore_port_map(&mut self, serial: &str) {
         let connections = self.forwarded_connections.read().clone();
-
-        if connections.len() == 0 {
-            debug!("No connections to restore, aborting.");
-            return;
-        }
-
-        let guard = self.device.lock();
-        let device = match *guard {
-            Some(ref d) => d,
-            None => {
-                error!("Failed to restore ADB connection mappings: device not connected");
-                return;
-            }
-        };
-
-        for port in connections {
-            let r = match port.port_type {
-                PortType::Forward => device.forward_port(port.local_port_num, port.local_port_num),
-                PortType::Reverse => device.reverse_port(port.remote_port_num, port.local_port_num),
-            };
-
-            match r {
-                Ok(_) => debug!("Restored port mapping: {:?}", port),
-                Err(_) => warn!("Failed to restore mapping {:?}: {}", port, r.unwrap_err())
-            }
+        let failed = reconnect(&self.device, serial, &connections, self.restore_policy, &mut self.tracker).await;
+        if failed.len() > 0 {
+            warn!("{} of {} port mapping(s) could not be restored", failed.len(), connections.len());
         }
     }
 }
@@ -1,10 +1,109 @@
 use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use parking_lot::RwLock;
+use crate::config::BusControllerConfig;
+use crate::device::BusEntry;
+use crate::gpio::{self, Debouncer, GpioBorrowChecker, GpioError, PinDirection};
+
 pub trait BusController: Any + Send + Sync {
     fn name(&self) -> String;
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn as_gpio_mut(&mut self) -> Option<&mut (dyn GpioController + 'static)> {
+        None
+    }
+    // Pin IDs this controller currently holds leases on
+    fn owned_pins(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    // Closes whatever is still open on shutdown, including what a device failed to close itself.
+    // Keeps going past failures and returns them.
+    fn release_all(&mut self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
+// Direct pin access for bus controllers that expose raw GPIO pins.
+// Pins configured through set_direction/write_pin stay leased until release_pin is called.
+pub trait GpioController {
+    fn read_pin(&mut self, pin: u8) -> Result<u8, GpioError>;
+    fn write_pin(&mut self, pin: u8, value: u8) -> Result<(), GpioError>;
+    fn set_direction(&mut self, pin: u8, direction: PinDirection) -> Result<(), GpioError>;
+    fn release_pin(&mut self, pin: u8) -> Result<(), GpioError>;
+    // Whether the pin is currently leased through set_direction/write_pin
+    fn holds_pin(&self, pin: u8) -> bool;
+
+    // Batch writes only touch pins that are already held, nothing is written if any pin in the batch isn't.
+    // Every failing pin is reported.
+    fn set_values(&mut self, values: &[(u8, u8)]) -> Result<(), Vec<(u8, GpioError)>> {
+        let pins: Vec<u8> = values.iter().map(|(pin, _)| *pin).collect();
+        self.check_held(&pins)?;
+
+        let failures: Vec<(u8, GpioError)> = values.iter()
+            .filter_map(|(pin, value)| self.write_pin(*pin, *value).err().map(|err| (*pin, err)))
+            .collect();
+
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(failures)
+        }
+    }
+
+    // Samples the pin until it reads the same for a whole window, for inputs like mechanical switches.
+    // Blocks for at least the window, errors when the pin keeps changing for DEBOUNCE_MAX_WINDOWS of them.
+    fn read_debounced(&mut self, pin: u8, window_ms: u64) -> Result<u8, GpioError> {
+        let window = Duration::from_millis(window_ms);
+        let mut debouncer = Debouncer::new(window);
+        for _ in 0..debouncer.max_samples() {
+            if let Some(value) = debouncer.push(self.read_pin(pin)?) {
+                return Ok(value);
+            }
+
+            thread::sleep(gpio::DEBOUNCE_SAMPLE_INTERVAL);
+        }
+
+        Err(GpioError::Other(format!("pin {} did not settle within {:?}", pin, window * gpio::DEBOUNCE_MAX_WINDOWS)))
+    }
+
+    // Values come back in the order the pins were given
+    fn read_values(&mut self, pins: &[u8]) -> Result<Vec<u8>, Vec<(u8, GpioError)>> {
+        self.check_held(pins)?;
+
+        let mut values = Vec::with_capacity(pins.len());
+        let mut failures = Vec::new();
+        for pin in pins {
+            match self.read_pin(*pin) {
+                Ok(value) => values.push(value),
+                Err(err) => failures.push((*pin, err))
+            }
+        }
+
+        match failures.is_empty() {
+            true => Ok(values),
+            false => Err(failures)
+        }
+    }
+
+    fn check_held(&self, pins: &[u8]) -> Result<(), Vec<(u8, GpioError)>> {
+        let missing: Vec<(u8, GpioError)> = pins.iter()
+            .filter(|pin| !self.holds_pin(**pin))
+            .map(|pin| (*pin, GpioError::LeaseNotFound))
+            .collect();
+
+        match missing.is_empty() {
+            true => Ok(()),
+            false => Err(missing)
+        }
+    }
+}
+
+// Lock shared by devices on the same bus
+pub mod bus_lock; // BusLock, BusClient
+
 // Bus implementations
 pub mod raw; // RawBusController
 pub mod i2c; // I2CBusController
@@ -14,4 +113,110 @@ pub mod uart; // UARTBusController
 // Alternative sysfs implementations
 pub mod raw_sysfs;
 pub mod pwm_sysfs;
-pub mod i2c_sysfs;
\ No newline at end of file
+pub mod i2c_sysfs;
+
+pub type ControllerConstructor = fn(&Arc<RwLock<GpioBorrowChecker>>, &mut BusControllerConfig) -> Result<BusEntry, String>;
+
+fn entry<T: BusController, E: ToString>(bus: Result<T, E>) -> Result<BusEntry, String> {
+    bus.map(|bus| BusEntry::new(Arc::new(RwLock::new(bus))))
+        .map_err(|err| err.to_string())
+}
+
+// Maps controller names from the config to their constructors, names are matched case-insensitively
+#[derive(Default)]
+pub struct ControllerRegistry {
+    constructors: HashMap<String, ControllerConstructor>
+}
+
+impl ControllerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Every controller compiled into the server
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register("raw", |gpio, config| entry(raw::RawBusController::from_config(gpio, config)));
+        registry.register("raw_sysfs", |gpio, config| entry(raw_sysfs::SysfsRawBusController::from_config(gpio, config)));
+        registry.register("pwm", |gpio, config| entry(pwm::PWMBusController::from_config(gpio, config)));
+        registry.register("pwm_sysfs", |gpio, config| entry(pwm_sysfs::SysfsPWMBusController::from_config(gpio, config)));
+        registry.register("uart", |gpio, config| entry(uart::UARTBusController::from_config(gpio, config)));
+        registry.register("i2c", |gpio, config| entry(i2c::I2CBusController::from_config(gpio, config)));
+        registry.register("i2c_sysfs", |gpio, config| entry(i2c_sysfs::SysfsI2CBusController::from_config(gpio, config)));
+        registry
+    }
+
+    // Replaces any constructor already registered under the name
+    pub fn register(&mut self, name: &str, constructor: ControllerConstructor) {
+        self.constructors.insert(name.to_lowercase(), constructor);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.constructors.contains_key(&name.to_lowercase())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.constructors.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn build(&self, gpio: &Arc<RwLock<GpioBorrowChecker>>, config: &mut BusControllerConfig) -> Result<BusEntry, String> {
+        match self.constructors.get(&config.name.to_lowercase()) {
+            Some(constructor) => constructor(gpio, config),
+            None => Err(format!("Bus controller {} is not implemented by this server", config.name))
+        }
+    }
+}
+
+// Pins a controller claims in its config, read without touching hardware.
+// Malformed data yields no pins, building the controller reports it properly.
+pub fn declared_pins(config: &BusControllerConfig) -> Vec<u8> {
+    let mut pins: Vec<u8> = match config.name.to_lowercase().as_str() {
+        "pwm" => serde_json::from_value::<pwm::PWMConfigData>(config.data.clone())
+            .map(|data| data.channels.into_values().collect())
+            .unwrap_or_default(),
+        "pwm_sysfs" => serde_json::from_value::<pwm_sysfs::SysfsPWMConfigData>(config.data.clone())
+            .map(|data| data.channels.into_values().map(|x| x.gpio_num).collect())
+            .unwrap_or_default(),
+        "i2c" | "i2c_sysfs" => serde_json::from_value::<i2c::I2cConfigData>(config.data.clone())
+            .map(|data| data.channels.into_values().flat_map(|x| x.to_arr()).collect())
+            .unwrap_or_default(),
+        "uart" => serde_json::from_value::<uart::UARTConfigData>(config.data.clone())
+            .map(|data| data.internal_ports.unwrap_or_default().into_values().flat_map(|x| x.pins()).collect())
+            .unwrap_or_default(),
+        _ => Vec::new()
+    };
+
+    pins.sort();
+    pins.dedup();
+    pins
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinConflict {
+    pub pin: u8,
+    // In config order, the first controller is the one that gets to keep the pin
+    pub controllers: Vec<String>
+}
+
+impl Display for PinConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pin {} is declared by multiple bus controllers: {}", self.pin, self.controllers.join(", "))
+    }
+}
+
+// Overlaps within one controller are rejected by the controller itself, this only looks across controllers
+pub fn find_pin_conflicts(configs: &[BusControllerConfig]) -> Vec<PinConflict> {
+    let mut users: BTreeMap<u8, Vec<String>> = BTreeMap::new();
+    for config in configs {
+        for pin in declared_pins(config) {
+            users.entry(pin).or_default().push(config.name.clone());
+        }
+    }
+
+    users.into_iter()
+        .filter(|(_, controllers)| controllers.len() > 1)
+        .map(|(pin, controllers)| PinConflict { pin, controllers })
+        .collect()
+}
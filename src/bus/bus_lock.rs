@@ -0,0 +1,181 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Instant;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+use crate::supervisor::{LockMetrics, LockWaitStats};
+
+// A waiting device is let through after a higher priority one went ahead of it this many times
+pub const MAX_PRIORITY_BYPASSES: u32 = 4;
+
+// How a shared bus picks the next device once it is released
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BusLockPolicy {
+    // plain mutex, cheapest but a device polling in a tight loop can keep the others waiting
+    #[default]
+    Unfair,
+    // first come, first served
+    Fair,
+    // highest priority first, ties and bypassed waiters are served in arrival order
+    Priority,
+}
+
+struct Waiter {
+    ticket: u64,
+    priority: u8,
+    bypassed: u32,
+}
+
+#[derive(Default)]
+struct WaitQueue {
+    next_ticket: u64,
+    held: bool,
+    // kept in ticket order
+    waiters: Vec<Waiter>,
+}
+
+impl WaitQueue {
+    fn enqueue(&mut self, priority: u8) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiters.push(Waiter { ticket, priority, bypassed: 0 });
+        ticket
+    }
+
+    fn next(&self, policy: BusLockPolicy) -> Option<u64> {
+        if policy == BusLockPolicy::Priority {
+            if let Some(waiter) = self.waiters.iter().find(|x| x.bypassed >= MAX_PRIORITY_BYPASSES) {
+                return Some(waiter.ticket);
+            }
+
+            return self.waiters.iter().max_by_key(|x| (x.priority, Reverse(x.ticket))).map(|x| x.ticket);
+        }
+
+        self.waiters.first().map(|x| x.ticket)
+    }
+
+    fn take(&mut self, ticket: u64) {
+        self.waiters.retain(|x| x.ticket != ticket);
+        // everyone that arrived earlier was just passed over
+        for waiter in self.waiters.iter_mut().take_while(|x| x.ticket < ticket) {
+            waiter.bypassed += 1;
+        }
+
+        self.held = true;
+    }
+}
+
+// A bus shared by several devices, each of them locks it through its own BusClient so the waits can be told apart
+pub struct BusLock<T> {
+    policy: BusLockPolicy,
+    data: Mutex<T>,
+    queue: Mutex<WaitQueue>,
+    released: Condvar,
+    clients: Mutex<HashMap<String, Arc<LockMetrics>>>,
+}
+
+impl<T> BusLock<T> {
+    pub fn new(data: T, policy: BusLockPolicy) -> Self {
+        Self {
+            policy,
+            data: Mutex::new(data),
+            queue: Mutex::new(WaitQueue::default()),
+            released: Condvar::new(),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Clients with the same name share their wait metrics, so a restarted device keeps its history
+    pub fn client(self: &Arc<Self>, name: &str, priority: u8) -> BusClient<T> {
+        let metrics = self.clients.lock().entry(name.to_string()).or_default().clone();
+        BusClient { bus: self.clone(), priority, metrics }
+    }
+
+    // Wait times of every client that locked this bus, sorted by name
+    pub fn wait_stats(&self) -> Vec<(String, LockWaitStats)> {
+        let mut stats: Vec<(String, LockWaitStats)> = self.clients.lock().iter()
+            .map(|(name, metrics)| (name.clone(), metrics.snapshot()))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+
+    fn lock_as(&self, priority: u8, metrics: &LockMetrics) -> BusGuard<'_, T> {
+        let started = Instant::now();
+        if self.policy == BusLockPolicy::Unfair {
+            let guard = self.data.lock();
+            metrics.record(started.elapsed(), true);
+            return BusGuard { bus: self, guard, queued: false };
+        }
+
+        let mut queue = self.queue.lock();
+        let ticket = queue.enqueue(priority);
+        while queue.held || queue.next(self.policy) != Some(ticket) {
+            self.released.wait(&mut queue);
+        }
+
+        queue.take(ticket);
+        drop(queue);
+
+        // only the queue holder gets here, so this never blocks for long
+        let guard = self.data.lock();
+        metrics.record(started.elapsed(), true);
+        BusGuard { bus: self, guard, queued: true }
+    }
+
+    fn release(&self) {
+        self.queue.lock().held = false;
+        self.released.notify_all();
+    }
+}
+
+// One device's handle on a shared bus
+pub struct BusClient<T> {
+    bus: Arc<BusLock<T>>,
+    priority: u8,
+    metrics: Arc<LockMetrics>,
+}
+
+impl<T> Clone for BusClient<T> {
+    fn clone(&self) -> Self {
+        Self { bus: self.bus.clone(), priority: self.priority, metrics: self.metrics.clone() }
+    }
+}
+
+impl<T> BusClient<T> {
+    pub fn lock(&self) -> BusGuard<'_, T> {
+        self.bus.lock_as(self.priority, &self.metrics)
+    }
+}
+
+pub struct BusGuard<'a, T> {
+    bus: &'a BusLock<T>,
+    guard: MutexGuard<'a, T>,
+    queued: bool,
+}
+
+impl<T> Deref for BusGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for BusGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for BusGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.queued {
+            // the data guard is dropped right after this, the next waiter only blocks on it briefly
+            self.bus.release();
+        }
+    }
+}
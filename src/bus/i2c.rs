@@ -1,4 +1,5 @@
 use crate::bus::BusController;
+use crate::bus::bus_lock::BusLockPolicy;
 use crate::gpio::GpioBorrowChecker;
 use crate::config::{BusControllerConfig, ConfigError};
 use log::warn;
@@ -12,39 +13,140 @@ use uuid::Uuid;
 use rppal::i2c::{I2c, Error};
 
 // helper methods for interfacing with devices over I2C
+pub fn set_slave_address(bus: &mut I2c, address: I2cAddress) -> Result<(), Error> {
+    bus.set_addr_10bit(address.addressing().is_ten_bit())?;
+    bus.set_slave_address(address.address())
+}
+
 pub fn write_command(
     bus: &mut I2c,
-    address: u8,
+    address: I2cAddress,
     command: u8,
 ) -> Result<(), Error> {
-    bus.set_slave_address(address as u16)?;
+    set_slave_address(bus, address)?;
     bus.write(&[command])?;
     Ok(())
 }
 
 pub fn write_register(
     bus: &mut I2c,
-    address: u8,
+    address: I2cAddress,
     register: u8,
     data: u8,
 ) -> Result<(), Error> {
-    bus.set_slave_address(address as u16)?;
+    set_slave_address(bus, address)?;
     bus.write(&[register, data])?;
     Ok(())
 }
 
 pub fn read_register(
     bus: &mut I2c,
-    address: u8,
+    address: I2cAddress,
     register: u8,
     buf: &mut [u8],
 ) -> Result<(), Error> {
-    bus.set_slave_address(address as u16)?;
+    set_slave_address(bus, address)?;
     bus.write(&[register])?;
     bus.read(buf)?;
     Ok(())
 }
 
+// 7-bit covers nearly every chip, 10-bit addressing has to be asked for per device
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum I2cAddressing {
+    #[default]
+    SevenBit,
+    TenBit
+}
+
+impl I2cAddressing {
+    pub fn max_address(&self) -> u16 {
+        match self {
+            I2cAddressing::SevenBit => 0x7F,
+            I2cAddressing::TenBit => 0x3FF
+        }
+    }
+
+    pub fn is_ten_bit(&self) -> bool {
+        *self == I2cAddressing::TenBit
+    }
+}
+
+impl Display for I2cAddressing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            I2cAddressing::SevenBit => "7-bit",
+            I2cAddressing::TenBit => "10-bit"
+        })
+    }
+}
+
+// Slave address along with the addressing mode it has to be sent with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct I2cAddress {
+    address: u16,
+    addressing: I2cAddressing
+}
+
+impl I2cAddress {
+    pub fn new(address: u16, addressing: I2cAddressing) -> Result<Self, I2CError> {
+        if address > addressing.max_address() {
+            return Err(I2CError::InvalidAddress(address));
+        }
+
+        Ok(I2cAddress { address, addressing })
+    }
+
+    // For fixed chip addresses, the kernel rejects anything past 0x7F when the address is set
+    pub const fn seven_bit(address: u8) -> Self {
+        I2cAddress { address: address as u16, addressing: I2cAddressing::SevenBit }
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    pub fn addressing(&self) -> I2cAddressing {
+        self.addressing
+    }
+}
+
+impl Display for I2cAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.addressing {
+            I2cAddressing::SevenBit => write!(f, "{}", self.address),
+            I2cAddressing::TenBit => write!(f, "{} (10-bit)", self.address)
+        }
+    }
+}
+
+// Where a driver finds its chip, flattened into the driver's config so the fields sit next to its own
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct I2cDeviceConfig {
+    pub bus_id: u8,
+    pub device_address: u16,
+    // 10-bit addressing is only needed for chips strapped onto the extended address range
+    #[serde(default)]
+    pub addressing: I2cAddressing,
+    // only used when the bus has the priority lock policy, higher goes first
+    #[serde(default)]
+    pub bus_priority: u8,
+}
+
+impl I2cDeviceConfig {
+    pub fn new(bus_id: u8, device_address: u16) -> Self {
+        I2cDeviceConfig { bus_id, device_address, addressing: I2cAddressing::SevenBit, bus_priority: 0 }
+    }
+
+    // The device address checked against the range of its addressing mode
+    pub fn address(&self) -> Result<I2cAddress, ConfigError> {
+        I2cAddress::new(self.device_address, self.addressing).map_err(|_| ConfigError::InvalidEntry(format!(
+            "invalid device address: {}, {} addresses go up to {}",
+            self.device_address, self.addressing, self.addressing.max_address()
+        )))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct I2CPinDefinition {
     pub sda: u8,
@@ -128,12 +230,24 @@ fn rppal_map_err(err: Error, default_err_msg: &str) -> I2CError {
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct I2cConfigData {
-    pub channels: HashMap<u8, I2CPinDefinition>
+    pub channels: HashMap<u8, I2CPinDefinition>,
+    // SMBus packet error checking, only applied by the sysfs controller
+    #[serde(default)]
+    pub pec: bool,
+    // raw register access over RPC, only honoured by the sysfs controller
+    #[serde(default)]
+    pub debug_enabled: bool,
+    // kernel timeout for a single transfer, raise it for slaves that clock-stretch. Only honoured by the sysfs controller
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    // how devices sharing a bus take turns, keyed by bus id. Only honoured by the sysfs controller
+    #[serde(default)]
+    pub lock_policies: HashMap<u8, BusLockPolicy>
 }
 
 impl I2cConfigData {
     pub fn new(channels: HashMap<u8, I2CPinDefinition>) -> Self {
-        Self { channels }
+        Self { channels, pec: false, debug_enabled: false, timeout_ms: None, lock_policies: HashMap::new() }
     }
 }
 
@@ -153,6 +267,23 @@ impl BusController for I2CBusController {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn owned_pins(&self) -> Vec<u8> {
+        self.owned_buses.keys().filter_map(|bus_id| self.pin_config.get(bus_id)).flat_map(|x| x.to_arr()).collect()
+    }
+    // unlike close this doesn't wait for the last user, a device that failed to stop may still hold the bus
+    fn release_all(&mut self) -> Vec<String> {
+        let mut borrow_checker = self.gpio_borrow.write();
+        self.owned_buses.drain()
+            .filter_map(|(bus_id, info)| {
+                let rc = Arc::strong_count(&info.bus);
+                if rc > 1 {
+                    warn!("Releasing I2C bus {} while {} reference(s) to it are still held", bus_id, rc - 1);
+                }
+
+                borrow_checker.release(&info.lease_id).err().map(|err| format!("bus {}: {}", bus_id, err))
+            })
+            .collect()
+    }
 }
 
 impl I2CBusController {
@@ -199,6 +330,7 @@ impl I2CBusController {
     }
 
     pub fn from_config(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>, config: &mut BusControllerConfig) -> Result<Self, I2CError> {
+        gpio_borrow.read().ensure_configured().map_err(I2CError::InvalidConfig)?;
         let data: I2cConfigData = match serde_json::from_value(config.data.clone()) {
             Ok(d) => d,
             Err(e) => {
@@ -1,16 +1,18 @@
 use super::{
-    i2c::{I2CError, I2CPinDefinition, I2cConfigData},
+    bus_lock::{BusClient, BusLock, BusLockPolicy},
+    i2c::{I2CError, I2CPinDefinition, I2cAddress, I2cConfigData, I2cDeviceConfig},
     BusController,
 };
 use crate::{
     config::{BusControllerConfig, ConfigError},
     gpio::GpioBorrowChecker,
+    supervisor::LockWaitStats,
 };
 use i2c_linux::I2c;
 use log::warn;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::RwLock;
 use serde_json::Value;
-use std::{any::Any, collections::HashMap, fs::File, path::Path, sync::Arc, io::{Write, Error, Read}, os::fd::AsRawFd};
+use std::{any::Any, collections::HashMap, fs::File, path::Path, sync::Arc, io::{Write, Error, ErrorKind, Read}, os::fd::AsRawFd, time::Duration};
 use uuid::Uuid;
 
 const I2C_CLASS_PATH: &str = "/sys/class/i2c-dev";
@@ -19,56 +21,174 @@ const I2C_DEVICE_PATH: &str = "/dev";
 // helper methods for interfacing with devices over I2C
 pub fn write_command<T: Write + AsRawFd>(
     bus: &mut I2c<T>,
-    address: u8,
+    address: I2cAddress,
     command: u8,
 ) -> Result<(), Error> {
-    bus.smbus_set_slave_address(address as u16, false)?;
-    bus.write(&[command])?;
+    SmbusTransfer::set_slave_address(bus, address)?;
+    bus.write_all(&[command])?;
     Ok(())
 }
 
-pub fn write_register<T: Write + AsRawFd>(
-    bus: &mut I2c<T>,
-    address: u8,
+// The register helpers only need the slave address ioctl from SmbusTransfer, so they also run against fake buses
+pub fn write_register<T: SmbusTransfer + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
     register: u8,
     data: u8,
 ) -> Result<(), Error> {
-    bus.smbus_set_slave_address(address as u16, false)?;
-    bus.write(&[register, data])?;
+    bus.set_slave_address(address)?;
+    bus.write_all(&[register, data])?;
     Ok(())
 }
 
-pub fn read_register<T: Read + Write + AsRawFd>(
-    bus: &mut I2c<T>,
-    address: u8,
+pub fn read_register<T: SmbusTransfer + Read + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
     register: u8,
     buf: &mut [u8],
 ) -> Result<(), Error> {
-    bus.smbus_set_slave_address(address as u16, false)?;
-    bus.write(&[register])?;
+    bus.set_slave_address(address)?;
+    bus.write_all(&[register])?;
+    bus.read_exact(buf)?;
+    Ok(())
+}
+
+pub fn read_block<T: Read + Write + AsRawFd>(
+    bus: &mut I2c<T>,
+    address: I2cAddress,
+    start_register: u8,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    SmbusTransfer::set_slave_address(bus, address)?;
+    transfer_block(bus, start_register, buf)
+}
+
+// Writes the start register once and reads buf.len() bytes back in a single transfer,
+// the chip is expected to auto-increment its register pointer
+pub(crate) fn transfer_block<T: Read + Write + ?Sized>(
+    bus: &mut T,
+    start_register: u8,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    bus.write_all(&[start_register])?;
     bus.read_exact(buf)?;
     Ok(())
 }
 
+// SMBus byte transfers, the kernel adds and checks the PEC byte on these when PEC is enabled on the bus.
+// Plain read/write transfers are never covered by PEC, so drivers that opt in have to use the _pec helpers.
+pub trait SmbusTransfer {
+    fn set_slave_address(&mut self, address: I2cAddress) -> Result<(), Error>;
+    fn read_byte_data(&mut self, register: u8) -> Result<u8, Error>;
+    fn write_byte_data(&mut self, register: u8, value: u8) -> Result<(), Error>;
+    // SMBus words go out low byte first
+    fn read_word_data(&mut self, register: u8) -> Result<u16, Error>;
+    fn write_word_data(&mut self, register: u8, value: u16) -> Result<(), Error>;
+}
+
+impl<T: AsRawFd> SmbusTransfer for I2c<T> {
+    fn set_slave_address(&mut self, address: I2cAddress) -> Result<(), Error> {
+        self.smbus_set_slave_address(address.address(), address.addressing().is_ten_bit())
+    }
+
+    fn read_byte_data(&mut self, register: u8) -> Result<u8, Error> {
+        self.smbus_read_byte_data(register)
+    }
+
+    fn write_byte_data(&mut self, register: u8, value: u8) -> Result<(), Error> {
+        self.smbus_write_byte_data(register, value)
+    }
+
+    fn read_word_data(&mut self, register: u8) -> Result<u16, Error> {
+        self.smbus_read_word_data(register)
+    }
+
+    fn write_word_data(&mut self, register: u8, value: u16) -> Result<(), Error> {
+        self.smbus_write_word_data(register, value)
+    }
+}
+
+// Reads consecutive registers one byte at a time, each byte is a separate PEC checked transfer
+pub fn read_register_pec<T: SmbusTransfer + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
+    register: u8,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    bus.set_slave_address(address)?;
+    for (offset, value) in buf.iter_mut().enumerate() {
+        *value = bus.read_byte_data(register.wrapping_add(offset as u8))?;
+    }
+
+    Ok(())
+}
+
+pub fn write_register_pec<T: SmbusTransfer + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
+    register: u8,
+    data: u8,
+) -> Result<(), Error> {
+    bus.set_slave_address(address)?;
+    bus.write_byte_data(register, data)
+}
+
+// Register access for drivers, goes through the PEC checked transfers when the controller has PEC on
+pub fn read_register_with<T: SmbusTransfer + Read + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
+    register: u8,
+    buf: &mut [u8],
+    pec: bool,
+) -> Result<(), Error> {
+    match pec {
+        true => read_register_pec(bus, address, register, buf),
+        false => read_register(bus, address, register, buf),
+    }
+}
+
+pub fn write_register_with<T: SmbusTransfer + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
+    register: u8,
+    data: u8,
+    pec: bool,
+) -> Result<(), Error> {
+    match pec {
+        true => write_register_pec(bus, address, register, data),
+        false => write_register(bus, address, register, data),
+    }
+}
+
+// Transfers that ran out of time, usually a slave clock-stretching past the bus timeout rather than a broken device
+pub fn is_timeout_error(err: &Error) -> bool {
+    matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
 fn sysfs_map_err(err: std::io::Error, default_err_msg: &str) -> I2CError {
     I2CError::HardwareError(format!("{}: {}", default_err_msg.to_string(), err))
 }
+pub type SharedI2cBus = Arc<BusLock<I2c<File>>>;
+
+// Every open/get counts as one user of the bus, the GPIO lease is held until the last user closes it
 struct I2cInfo {
     bus_id: u8,
     lease_id: Uuid,
-    bus: Arc<Mutex<I2c<File>>>,
+    bus: SharedI2cBus,
+    users: usize,
 }
 
 impl I2cInfo {
-    fn new(bus_id: u8, lease_id: Uuid, bus: I2c<File>) -> Self {
-        Self::with_rc(bus_id, lease_id, Arc::new(Mutex::new(bus)))
+    fn new(bus_id: u8, lease_id: Uuid, bus: I2c<File>, policy: BusLockPolicy) -> Self {
+        Self::with_rc(bus_id, lease_id, Arc::new(BusLock::new(bus, policy)))
     }
 
-    fn with_rc(bus_id: u8, lease_id: Uuid, bus: Arc<Mutex<I2c<File>>>) -> Self {
+    fn with_rc(bus_id: u8, lease_id: Uuid, bus: SharedI2cBus) -> Self {
         I2cInfo {
             bus_id,
             lease_id,
             bus,
+            users: 1,
         }
     }
 }
@@ -77,6 +197,10 @@ pub struct SysfsI2CBusController {
     gpio_borrow: Arc<RwLock<GpioBorrowChecker>>,
     pin_config: HashMap<u8, I2CPinDefinition>,
     owned_buses: HashMap<u8, I2cInfo>,
+    pec: bool,
+    debug_enabled: bool,
+    timeout: Option<Duration>,
+    lock_policies: HashMap<u8, BusLockPolicy>,
 }
 
 impl BusController for SysfsI2CBusController {
@@ -89,6 +213,23 @@ impl BusController for SysfsI2CBusController {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn owned_pins(&self) -> Vec<u8> {
+        self.owned_buses.keys().filter_map(|bus_id| self.pin_config.get(bus_id)).flat_map(|x| x.to_arr()).collect()
+    }
+    // unlike close this doesn't wait for the last user, a device that failed to stop may still hold the bus
+    fn release_all(&mut self) -> Vec<String> {
+        let mut borrow_checker = self.gpio_borrow.write();
+        self.owned_buses.drain()
+            .filter_map(|(bus_id, info)| {
+                let rc = Arc::strong_count(&info.bus);
+                if rc > 1 {
+                    warn!("Releasing I2C bus {} while {} reference(s) to it are still held", bus_id, rc - 1);
+                }
+
+                borrow_checker.release(&info.lease_id).err().map(|err| format!("bus {}: {}", bus_id, err))
+            })
+            .collect()
+    }
 }
 
 impl SysfsI2CBusController {
@@ -103,6 +244,14 @@ impl SysfsI2CBusController {
             ));
         }
 
+        Self::with_pin_config(gpio_borrow, pin_config)
+    }
+
+    // Same as new but skips the sysfs check, the buses are opened through open_with/get_with instead
+    pub(crate) fn with_pin_config(
+        gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>,
+        pin_config: HashMap<u8, I2CPinDefinition>,
+    ) -> Result<Self, I2CError> {
         let gpio_checker = gpio_borrow.read();
 
         for (bus_id, definition) in &pin_config {
@@ -141,13 +290,69 @@ impl SysfsI2CBusController {
             gpio_borrow: gpio_borrow.clone(),
             pin_config: pin_config,
             owned_buses: HashMap::new(),
+            pec: false,
+            debug_enabled: false,
+            timeout: None,
+            lock_policies: HashMap::new(),
         })
     }
 
+    // Enables SMBus packet error checking on every bus this controller opens
+    pub fn with_pec(mut self, pec: bool) -> Self {
+        self.pec = pec;
+        self
+    }
+
+    // Drivers check this to decide whether to use the _pec register helpers
+    pub fn pec_enabled(&self) -> bool {
+        self.pec
+    }
+
+    // Allows raw register access through the I2C debug service
+    pub fn with_debug_enabled(mut self, debug_enabled: bool) -> Self {
+        self.debug_enabled = debug_enabled;
+        self
+    }
+
+    pub fn debug_enabled(&self) -> bool {
+        self.debug_enabled
+    }
+
+    // Applied to every bus this controller opens, None keeps the kernel default
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // Drivers that poll slow chips use this to tell how long a timed out transfer blocked
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    // Only applies to buses opened after this, a bus without a policy uses a plain mutex
+    pub fn with_lock_policy(mut self, bus_id: u8, policy: BusLockPolicy) -> Self {
+        self.lock_policies.insert(bus_id, policy);
+        self
+    }
+
+    pub fn lock_policy(&self, bus_id: u8) -> BusLockPolicy {
+        self.lock_policies.get(&bus_id).copied().unwrap_or_default()
+    }
+
+    // How long each device waited for the open buses, sorted by bus id
+    pub fn wait_stats(&self) -> Vec<(u8, String, LockWaitStats)> {
+        let mut bus_ids: Vec<u8> = self.owned_buses.keys().copied().collect();
+        bus_ids.sort();
+        bus_ids.into_iter()
+            .flat_map(|bus_id| self.owned_buses[&bus_id].bus.wait_stats().into_iter().map(move |(client, stats)| (bus_id, client, stats)))
+            .collect()
+    }
+
     pub fn from_config(
         gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>,
         config: &mut BusControllerConfig,
     ) -> Result<Self, I2CError> {
+        gpio_borrow.read().ensure_configured().map_err(I2CError::InvalidConfig)?;
         let data: I2cConfigData = match serde_json::from_value(config.data.clone()) {
             Ok(d) => d,
             Err(e) => {
@@ -168,10 +373,26 @@ impl SysfsI2CBusController {
             }
         };
 
-        Self::new(gpio_borrow, data.channels)
+        let mut controller = Self::new(gpio_borrow, data.channels)?
+            .with_pec(data.pec)
+            .with_debug_enabled(data.debug_enabled)
+            .with_timeout(data.timeout_ms.map(Duration::from_millis));
+        for (bus_id, policy) in data.lock_policies {
+            controller = controller.with_lock_policy(bus_id, policy);
+        }
+
+        Ok(controller)
     }
 
-    pub fn open(&mut self, bus_id: u8) -> Result<Arc<Mutex<I2c<File>>>, I2CError> {
+    pub fn open(&mut self, bus_id: u8) -> Result<SharedI2cBus, I2CError> {
+        self.open_with(bus_id, |path| I2c::from_path(path))
+    }
+
+    pub(crate) fn open_with<F: FnOnce(&Path) -> Result<I2c<File>, Error>>(
+        &mut self,
+        bus_id: u8,
+        open: F,
+    ) -> Result<SharedI2cBus, I2CError> {
         if self.owned_buses.contains_key(&bus_id) {
             return Err(I2CError::ChannelBusy(bus_id));
         }
@@ -188,34 +409,68 @@ impl SysfsI2CBusController {
             ));
         }
 
-        let bus = I2c::from_path(Path::new(I2C_DEVICE_PATH).join(format!("i2c-{}", bus_id)))
+        let bus = open(Path::new(I2C_DEVICE_PATH).join(format!("i2c-{}", bus_id)).as_path())
             .map_err(|err| sysfs_map_err(err, &format!("Internal sysfs error while opening I2C bus {}", bus_id)))?;
 
+        if self.pec {
+            bus.smbus_set_pec(true)
+                .map_err(|err| sysfs_map_err(err, &format!("Failed to enable PEC on I2C bus {}", bus_id)))?;
+        }
+
+        if let Some(timeout) = self.timeout {
+            bus.i2c_set_timeout(timeout)
+                .map_err(|err| sysfs_map_err(err, &format!("Failed to set the timeout on I2C bus {}", bus_id)))?;
+        }
+
         let borrow_id = borrow_checker.borrow_many(definition.to_vec())
             .map_err(|err| I2CError::HardwareError(err.to_string()))?;
 
-        let bus_info = I2cInfo::new(bus_id, borrow_id, bus);
+        let bus_info = I2cInfo::new(bus_id, borrow_id, bus, self.lock_policy(bus_id));
         let result = bus_info.bus.clone();
         self.owned_buses.insert(bus_id, bus_info);
         Ok(result)
     }
 
-    pub fn get(&mut self, bus_id: u8) -> Result<Arc<Mutex<I2c<File>>>, I2CError> {
-        let res = self.owned_buses.get(&bus_id);
-        let bus = match res {
-            Some(info) => info.bus.clone(),
-            None => self.open(bus_id)?
-        };
+    // Shares an already open bus with another device, every successful call has to be paired with a close
+    pub fn get(&mut self, bus_id: u8) -> Result<SharedI2cBus, I2CError> {
+        self.get_with(bus_id, |path| I2c::from_path(path))
+    }
 
-        Ok(bus)
+    // Shares the bus a device sits on, queued at the device's configured priority
+    pub fn client(&mut self, device: &I2cDeviceConfig, name: &str) -> Result<BusClient<I2c<File>>, I2CError> {
+        Ok(self.get(device.bus_id)?.client(name, device.bus_priority))
     }
 
+    pub(crate) fn get_with<F: FnOnce(&Path) -> Result<I2c<File>, Error>>(
+        &mut self,
+        bus_id: u8,
+        open: F,
+    ) -> Result<SharedI2cBus, I2CError> {
+        match self.owned_buses.get_mut(&bus_id) {
+            Some(info) => {
+                info.users += 1;
+                Ok(info.bus.clone())
+            }
+            None => self.open_with(bus_id, open)
+        }
+    }
+
+    pub fn user_count(&self, bus_id: u8) -> usize {
+        self.owned_buses.get(&bus_id).map(|x| x.users).unwrap_or(0)
+    }
+
+    // Drops one user of the bus, the bus itself is only closed once nobody else is using it
     pub fn close(&mut self, bus_id: u8) -> Result<(), I2CError> {
-        let info = match self.owned_buses.get(&bus_id) {
+        let info = match self.owned_buses.get_mut(&bus_id) {
             Some(info) => info,
             None => return Err(I2CError::LeaseNotFound)
         };
 
+        if info.users > 1 {
+            info.users -= 1;
+            return Ok(());
+        }
+
         let rc = Arc::strong_count(&info.bus);
         if rc > 1 {
             warn!("Attempted to close I2C bus {} while still holding {} reference(s) to it", bus_id, rc - 1);
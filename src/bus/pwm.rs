@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::fmt::Display;
 use std::sync::Arc;
 use parking_lot::RwLock;
-use rppal::pwm::{Channel, Pwm, Error};
+use rppal::pwm::{Channel, Pwm, Error, Polarity};
 use serde_json::Value;
 use uuid::Uuid;
 use std::any::Any;
@@ -16,6 +16,8 @@ use serde::{Serialize, Deserialize};
 pub enum PWMError {
     InvalidConfig(String),
     ChannelNotFound(u8),
+    // the channel is configured but its sysfs node is missing, holds the path that was tried
+    ChannelNodeNotFound(u8, String),
     LeaseNotFound,
     Unsupported,
     ChannelBusy(u8),
@@ -29,6 +31,7 @@ impl Display for PWMError {
         f.write_str(&match self {
             PWMError::InvalidConfig(msg) => format!("invalid config: {}", msg),
             PWMError::ChannelNotFound(channel_id) => format!("pwm channel {} does not exist", channel_id),
+            PWMError::ChannelNodeNotFound(channel_id, path) => format!("pwm channel {} is not available: {} does not exist", channel_id, path),
             PWMError::LeaseNotFound => format!("pwm channel is not open"),
             PWMError::Unsupported => format!("not supported"),
             PWMError::ChannelBusy(channel_id) => format!("pwm channel {} is busy", channel_id),
@@ -41,19 +44,37 @@ impl Display for PWMError {
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct PWMConfigData {
-    pub channels: HashMap<u8, u8>
+    pub channels: HashMap<u8, u8>,
+    #[serde(default)]
+    pub ignore_polarity_errors: bool
 }
 
 impl PWMConfigData {
     pub fn new(channels: HashMap<u8, u8>) -> Self {
-        Self { channels }
+        Self { channels, ignore_polarity_errors: false }
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum PWMChannelBacking {
+    // rppal hardware channel number
+    Rppal(u8),
+    Sysfs { chip_num: u8, chip_channel: u8 }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PWMChannelInfo {
+    pub channel_id: u8,
+    pub backing: PWMChannelBacking,
+    pub gpio_pin: u8,
+    pub busy: bool
+}
+
 pub struct PWMBusController {
     gpio_borrow: Arc<RwLock<GpioBorrowChecker>>,
     pin_config: HashMap<u8, u8>,
-    owned_channels: HashMap<u8, Uuid>
+    owned_channels: HashMap<u8, Uuid>,
+    ignore_polarity_errors: bool
 }
 
 impl BusController for PWMBusController {
@@ -66,6 +87,15 @@ impl BusController for PWMBusController {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn owned_pins(&self) -> Vec<u8> {
+        self.owned_channels.keys().filter_map(|channel| self.pin_config.get(channel).copied()).collect()
+    }
+    fn release_all(&mut self) -> Vec<String> {
+        let channels: Vec<u8> = self.owned_channels.keys().copied().collect();
+        channels.into_iter()
+            .filter_map(|channel| self.close(channel).err().map(|err| format!("channel {}: {}", channel, err)))
+            .collect()
+    }
 }
 
 fn channel_to_u8(channel: Channel) -> Option<u8> {
@@ -91,6 +121,58 @@ fn rppal_map_err(err: Error, default_err_msg: &str) -> PWMError {
     }
 }
 
+pub(crate) trait PolarityControl {
+    fn set_polarity(&self, polarity: Polarity) -> Result<(), Error>;
+    fn polarity(&self) -> Result<Polarity, Error>;
+}
+
+impl PolarityControl for Pwm {
+    fn set_polarity(&self, polarity: Polarity) -> Result<(), Error> {
+        Pwm::set_polarity(self, polarity)
+    }
+
+    fn polarity(&self) -> Result<Polarity, Error> {
+        Pwm::polarity(self)
+    }
+}
+
+// Some PWM drivers don't expose the polarity attribute at all
+pub(crate) fn probe_polarity_support<T: PolarityControl>(pwm: &T) -> bool {
+    pwm.polarity().is_ok()
+}
+
+// Sets the polarity and reads it back, drivers may accept the write without applying it
+pub(crate) fn set_polarity_checked<T: PolarityControl>(pwm: &T, polarity: Polarity) -> Result<(), PWMError> {
+    if let Err(err) = pwm.set_polarity(polarity) {
+        warn!("Failed to set PWM polarity to {:?}: {}", polarity, err);
+        return Err(PWMError::Unsupported);
+    }
+
+    match pwm.polarity() {
+        Ok(actual) if actual == polarity => Ok(()),
+        Ok(actual) => {
+            warn!("PWM polarity read back as {:?} after setting it to {:?}", actual, polarity);
+            Err(PWMError::Unsupported)
+        },
+        Err(err) => Err(rppal_map_err(err, "Internal RPPAL error while reading PWM polarity"))
+    }
+}
+
+pub(crate) fn apply_polarity<T: PolarityControl>(pwm: &T, polarity: Polarity, ignore_errors: bool) -> Result<(), PWMError> {
+    let result = match probe_polarity_support(pwm) {
+        true => set_polarity_checked(pwm, polarity),
+        false => Err(PWMError::Unsupported)
+    };
+
+    match result {
+        Err(err) if ignore_errors => {
+            warn!("Failed to set PWM polarity, continuing as configured: {}", err);
+            Ok(())
+        },
+        result => result
+    }
+}
+
 impl PWMBusController {
     pub fn new(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>, pin_config: HashMap<u8, u8>) -> Result<Self, PWMError> {
         let gpio_checker = gpio_borrow.read();
@@ -123,11 +205,13 @@ impl PWMBusController {
         Ok(PWMBusController { 
             gpio_borrow: gpio_borrow.clone(), 
             pin_config: pin_config, 
-            owned_channels: HashMap::new()
+            owned_channels: HashMap::new(),
+            ignore_polarity_errors: false
         })
     }
 
     pub fn from_config(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>, config: &mut BusControllerConfig) -> Result<Self, PWMError> {
+        gpio_borrow.read().ensure_configured().map_err(PWMError::InvalidConfig)?;
         let data: PWMConfigData = match serde_json::from_value(config.data.clone()) {
             Ok(d) => d,
             Err(e) => {
@@ -147,10 +231,20 @@ impl PWMBusController {
             }
         };
 
-        Self::new(gpio_borrow, data.channels)
+        let mut controller = Self::new(gpio_borrow, data.channels)?;
+        controller.ignore_polarity_errors = data.ignore_polarity_errors;
+        Ok(controller)
     }
 
     pub fn open(&mut self, channel: u8) -> Result<Pwm, PWMError> {
+        self.open_with(channel, Pwm::new)
+    }
+
+    pub(crate) fn open_with<T: PolarityControl, F: FnOnce(Channel) -> Result<T, Error>>(
+        &mut self,
+        channel: u8,
+        open: F
+    ) -> Result<T, PWMError> {
         if self.owned_channels.contains_key(&channel) {
             return Err(PWMError::ChannelBusy(channel));
         }
@@ -166,15 +260,11 @@ impl PWMBusController {
             return Err(PWMError::HardwareError(GpioError::Busy(*pin).to_string()));
         }
 
-        let bus = Pwm::new(u8_to_channel(channel).unwrap())
+        let bus = open(u8_to_channel(channel).unwrap())
             .map_err(|err| rppal_map_err(err, &format!("Internal RPPAL error while opening PWM channel {}", channel)))?;
 
-        // Operation not be supported
-        // for now just emit a warning
-        // TODO: add a support check and error out if we still can't set the polarity
-        if let Err(err) = bus.set_polarity(rppal::pwm::Polarity::Normal) {
-            warn!("Failed to reset PWM polarity: {}", err);
-        }
+        // Inverted polarity would flip every duty cycle, refuse the channel unless told otherwise
+        apply_polarity(&bus, Polarity::Normal, self.ignore_polarity_errors)?;
 
         let borrow_id = borrow_checker.borrow_one(*pin)
             .map_err(|err| PWMError::HardwareError(err.to_string()))?;
@@ -183,6 +273,19 @@ impl PWMBusController {
         Ok(bus)
     }
 
+    // Every configured channel ordered by ID, busy while a device has it open
+    pub fn list_channels(&self) -> Vec<PWMChannelInfo> {
+        let mut channels: Vec<PWMChannelInfo> = self.pin_config.iter().map(|(channel, pin)| PWMChannelInfo {
+            channel_id: *channel,
+            backing: PWMChannelBacking::Rppal(*channel),
+            gpio_pin: *pin,
+            busy: self.owned_channels.contains_key(channel)
+        }).collect();
+
+        channels.sort_by_key(|x| x.channel_id);
+        channels
+    }
+
     pub fn close(&mut self, channel: u8) -> Result<(), PWMError> {
         let id = match self.owned_channels.get(&channel) {
             Some(i) => i,
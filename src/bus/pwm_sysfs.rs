@@ -1,4 +1,4 @@
-use super::{pwm::PWMError, BusController};
+use super::{pwm::{PWMChannelBacking, PWMChannelInfo, PWMError}, BusController};
 use crate::{
     config::{BusControllerConfig, ConfigError},
     gpio::{GpioBorrowChecker, GpioError},
@@ -7,7 +7,7 @@ use log::warn;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{collections::HashMap, sync::Arc, path::Path, fs::OpenOptions, io::Write};
+use std::{collections::HashMap, sync::Arc, path::{Path, PathBuf}, fs::OpenOptions, io::Write};
 use sysfs_pwm::{Error, Pwm};
 use uuid::Uuid;
 
@@ -38,21 +38,67 @@ impl PWMChannel {
     }
 }
 
+// Two channels driven inversely for H-bridge style LED drivers, channel_b is the inverted one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ComplementaryPair {
+    pub channel_a: u8,
+    pub channel_b: u8,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
-struct SysfsPWMConfigData {
-    channels: HashMap<u8, PWMChannel>,
+pub(crate) struct SysfsPWMConfigData {
+    pub(crate) channels: HashMap<u8, PWMChannel>,
+    #[serde(default)]
+    pub(crate) complementary_pairs: Vec<ComplementaryPair>,
 }
 
 impl SysfsPWMConfigData {
     fn new(channels: HashMap<u8, PWMChannel>) -> Self {
-        Self { channels }
+        Self { channels, complementary_pairs: Vec::new() }
     }
 }
 
+// Duty cycles to program for a complementary pair, both in ns from the start of the period.
+// A is high from 0 to a_duty_ns, B runs inverted so it is low until b_duty_ns and high after it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComplementaryTiming {
+    pub period_ns: u32,
+    pub a_duty_ns: u32,
+    pub b_duty_ns: u32,
+}
+
+// sysfs channels are edge aligned, so the dead time sits between A falling and B rising.
+// A is cut short when there isn't room for the dead time, B then stays off for the whole period.
+pub fn complementary_timing(period_ns: u32, duty: f32, dead_time_ns: u32) -> Result<ComplementaryTiming, PWMError> {
+    if period_ns == 0 {
+        return Err(PWMError::InvalidConfig("PWM period is not set".to_string()));
+    }
+
+    if !(0.0..=1.0).contains(&duty) {
+        return Err(PWMError::InvalidConfig(format!("duty cycle {} is out of range, expected 0 to 1", duty)));
+    }
+
+    if dead_time_ns >= period_ns {
+        return Err(PWMError::InvalidConfig(format!(
+            "dead time of {}ns does not fit in a period of {}ns",
+            dead_time_ns, period_ns
+        )));
+    }
+
+    let a_duty_ns = ((period_ns as f64 * duty as f64).round() as u32).min(period_ns - dead_time_ns);
+    Ok(ComplementaryTiming {
+        period_ns,
+        a_duty_ns,
+        b_duty_ns: a_duty_ns + dead_time_ns,
+    })
+}
+
 pub struct SysfsPWMBusController {
     gpio_borrow: Arc<RwLock<GpioBorrowChecker>>,
     pin_config: HashMap<u8, PWMChannel>,
+    complementary_pairs: Vec<ComplementaryPair>,
     owned_channels: HashMap<u8, Uuid>,
+    sysfs_root: PathBuf,
 }
 
 impl BusController for SysfsPWMBusController {
@@ -67,6 +113,17 @@ impl BusController for SysfsPWMBusController {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn owned_pins(&self) -> Vec<u8> {
+        self.owned_channels.keys().filter_map(|channel| self.pin_config.get(channel).map(|x| x.gpio_num)).collect()
+    }
+
+    fn release_all(&mut self) -> Vec<String> {
+        let channels: Vec<u8> = self.owned_channels.keys().copied().collect();
+        channels.into_iter()
+            .filter_map(|channel| self.close(channel).err().map(|err| format!("channel {}: {}", channel, err)))
+            .collect()
+    }
 }
 
 impl SysfsPWMBusController {
@@ -74,11 +131,29 @@ impl SysfsPWMBusController {
         gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>,
         pin_config: HashMap<u8, PWMChannel>,
     ) -> Result<Self, PWMError> {
-        let path = Path::new(SYSFS_PWM_PATH);
-        if !path.exists() || !path.is_dir() {
+        Self::with_sysfs_root(gpio_borrow, pin_config, Path::new(SYSFS_PWM_PATH))
+    }
+
+    // Same as new but looks for the pwmchip nodes under root instead of /sys/class/pwm
+    pub(crate) fn with_sysfs_root(
+        gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>,
+        pin_config: HashMap<u8, PWMChannel>,
+        root: &Path,
+    ) -> Result<Self, PWMError> {
+        if !root.is_dir() {
             return Err(PWMError::OsError("PWM is not supported on this system".to_string()));
         }
 
+        let mut controller = Self::with_pin_config(gpio_borrow, pin_config)?;
+        controller.sysfs_root = root.to_path_buf();
+        Ok(controller)
+    }
+
+    // Same as new but skips the sysfs check, channels are opened through open_with instead
+    pub(crate) fn with_pin_config(
+        gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>,
+        pin_config: HashMap<u8, PWMChannel>,
+    ) -> Result<Self, PWMError> {
         let gpio_checker = gpio_borrow.read();
 
         for (channel_id, channel_data) in &pin_config {
@@ -107,14 +182,47 @@ impl SysfsPWMBusController {
         Ok(SysfsPWMBusController {
             gpio_borrow: gpio_borrow.clone(),
             pin_config: pin_config,
+            complementary_pairs: Vec::new(),
             owned_channels: HashMap::new(),
+            sysfs_root: PathBuf::from(SYSFS_PWM_PATH),
         })
     }
 
+    pub fn with_complementary_pairs(mut self, pairs: Vec<ComplementaryPair>) -> Result<Self, PWMError> {
+        for (index, pair) in pairs.iter().enumerate() {
+            for channel in [pair.channel_a, pair.channel_b] {
+                if !self.pin_config.contains_key(&channel) {
+                    return Err(PWMError::InvalidConfig(format!(
+                        "complementary pair {} -> {} uses undefined channel {}",
+                        pair.channel_a, pair.channel_b, channel
+                    )));
+                }
+
+                if pairs[..index].iter().any(|x| x.channel_a == channel || x.channel_b == channel) {
+                    return Err(PWMError::InvalidConfig(format!(
+                        "PWM channel {} is part of more than one complementary pair",
+                        channel
+                    )));
+                }
+            }
+
+            if pair.channel_a == pair.channel_b {
+                return Err(PWMError::InvalidConfig(format!(
+                    "complementary pair uses channel {} on both sides",
+                    pair.channel_a
+                )));
+            }
+        }
+
+        self.complementary_pairs = pairs;
+        Ok(self)
+    }
+
     pub fn from_config(
         gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>,
         config: &mut BusControllerConfig,
     ) -> Result<Self, PWMError> {
+        gpio_borrow.read().ensure_configured().map_err(PWMError::InvalidConfig)?;
         let data: SysfsPWMConfigData = match serde_json::from_value(config.data.clone()) {
             Ok(d) => d,
             Err(e) => {
@@ -135,10 +243,55 @@ impl SysfsPWMBusController {
             }
         };
 
-        Self::new(gpio_borrow, data.channels)
+        Self::new(gpio_borrow, data.channels)?.with_complementary_pairs(data.complementary_pairs)
     }
 
     pub fn open(&mut self, channel: u8) -> Result<Pwm, PWMError> {
+        let sysfs_root = self.sysfs_root.clone();
+        self.open_with(channel, |pwm_data| {
+            // a chip that isn't there makes the export fail with a bare ENOENT, report the path instead
+            let chip_path = sysfs_root.join(format!("pwmchip{}", pwm_data.chip_num));
+            if !chip_path.is_dir() {
+                return Err(PWMError::ChannelNodeNotFound(channel, chip_path.display().to_string()));
+            }
+
+            let bus = Pwm::new(pwm_data.chip_num as u32, pwm_data.chip_channel as u32)
+                .and_then(|pwm| pwm.export().map(|_| pwm))
+                .map_err(|err| {
+                    sysfs_map_err(
+                        err,
+                        &format!(
+                            "Internal sysfs error while opening PWM channel {} (channel {} on chip {})",
+                            channel, pwm_data.chip_channel, pwm_data.chip_num
+                        ),
+                    )
+                })?;
+
+            // make sure the channel node actually showed up before anything is written to it
+            let channel_path = chip_path.join(format!("pwm{}", pwm_data.chip_channel));
+            if !channel_path.is_dir() {
+                let _ = bus.unexport();
+                return Err(PWMError::ChannelNodeNotFound(channel, channel_path.display().to_string()));
+            }
+
+            // Try to reset PWM polarity if supported
+            // error out if polarity can't be set
+            let polarity_path = channel_path.join("polarity");
+            if polarity_path.exists() {
+                OpenOptions::new().write(true).open(polarity_path)
+                    .and_then(|mut fd| fd.write_all(b"normal"))
+                    .map_err(|err| PWMError::HardwareError(format!("failed to reset PWM polarity: {}", err)))?;
+            }
+
+            Ok(bus)
+        })
+    }
+
+    pub(crate) fn open_with<T, F: FnOnce(&PWMChannel) -> Result<T, PWMError>>(
+        &mut self,
+        channel: u8,
+        open: F,
+    ) -> Result<T, PWMError> {
         if self.owned_channels.contains_key(&channel) {
             return Err(PWMError::ChannelBusy(channel));
         }
@@ -155,27 +308,7 @@ impl SysfsPWMBusController {
             ));
         }
 
-        let bus = Pwm::new(pwm_data.chip_num as u32, pwm_data.chip_channel as u32)
-            .and_then(|pwm| pwm.export().map(|_| pwm))
-            .map_err(|err| {
-                sysfs_map_err(
-                    err,
-                    &format!(
-                        "Internal sysfs error while opening PWM channel {} (channel {} on chip {})",
-                        channel, pwm_data.chip_channel, pwm_data.chip_num
-                    ),
-                )
-            })?;
-
-        // Try to reset PWM polarity if supported
-        // error out if polarity can't be set
-        let polarity_path = Path::new(SYSFS_PWM_PATH).join(format!("pwmchip{}/pwm{}/polarity", pwm_data.chip_num, pwm_data.chip_channel));
-        if polarity_path.exists() {
-            OpenOptions::new().write(true).open(polarity_path)
-                .and_then(|mut fd| fd.write_all(b"normal"))
-                .map_err(|err| PWMError::HardwareError(format!("failed to reset PWM polarity: {}", err)))?;
-        }
-
+        let bus = open(pwm_data)?;
         let borrow_id = borrow_checker.borrow_one(pwm_data.gpio_num)
             .map_err(|err| PWMError::HardwareError(err.to_string()))?;
         
@@ -184,6 +317,72 @@ impl SysfsPWMBusController {
         Ok(bus)
     }
 
+    // Every configured channel ordered by ID, busy while a device has it open
+    pub fn list_channels(&self) -> Vec<PWMChannelInfo> {
+        let mut channels: Vec<PWMChannelInfo> = self.pin_config.iter().map(|(channel, data)| PWMChannelInfo {
+            channel_id: *channel,
+            backing: PWMChannelBacking::Sysfs { chip_num: data.chip_num, chip_channel: data.chip_channel },
+            gpio_pin: data.gpio_num,
+            busy: self.owned_channels.contains_key(channel),
+        }).collect();
+
+        channels.sort_by_key(|x| x.channel_id);
+        channels
+    }
+
+    pub fn complementary_pairs(&self) -> &[ComplementaryPair] {
+        &self.complementary_pairs
+    }
+
+    // Checks that the channels form a configured pair and are both open before anything is written
+    pub(crate) fn check_complementary(&self, channel_a: u8, channel_b: u8) -> Result<(&PWMChannel, &PWMChannel), PWMError> {
+        if !self.complementary_pairs.iter().any(|x| x.channel_a == channel_a && x.channel_b == channel_b) {
+            return Err(PWMError::InvalidConfig(format!(
+                "PWM channels {} and {} are not configured as a complementary pair",
+                channel_a, channel_b
+            )));
+        }
+
+        let mut channels = [channel_a, channel_b].into_iter().map(|channel| {
+            if !self.owned_channels.contains_key(&channel) {
+                return Err(PWMError::LeaseNotFound);
+            }
+
+            self.pin_config.get(&channel).ok_or(PWMError::ChannelNotFound(channel))
+        });
+
+        Ok((channels.next().unwrap()?, channels.next().unwrap()?))
+    }
+
+    // Drives channel_b as the inverse of channel_a with dead_time_ns between them, B takes A's period
+    pub fn set_complementary(&mut self, channel_a: u8, channel_b: u8, duty: f32, dead_time_ns: u32) -> Result<(), PWMError> {
+        let (data_a, data_b) = self.check_complementary(channel_a, channel_b)?;
+        let map_err = |err: Error| sysfs_map_err(err, &format!(
+            "Internal sysfs error while driving complementary PWM channels {} and {}",
+            channel_a, channel_b
+        ));
+
+        let pwm_a = Pwm::new(data_a.chip_num as u32, data_a.chip_channel as u32).map_err(map_err)?;
+        let pwm_b = Pwm::new(data_b.chip_num as u32, data_b.chip_channel as u32).map_err(map_err)?;
+        let timing = complementary_timing(pwm_a.get_period_ns().map_err(map_err)?, duty, dead_time_ns)?;
+
+        // polarity can only be changed while the channel is disabled
+        pwm_b.enable(false).map_err(map_err)?;
+        pwm_b.set_duty_cycle_ns(0).map_err(map_err)?;
+        pwm_b.set_period_ns(timing.period_ns).map_err(map_err)?;
+        let polarity_path = self.sysfs_root.join(format!("pwmchip{}/pwm{}/polarity", data_b.chip_num, data_b.chip_channel));
+        OpenOptions::new().write(true).open(polarity_path)
+            .and_then(|mut fd| fd.write_all(b"inversed"))
+            .map_err(|err| PWMError::HardwareError(format!("failed to invert PWM channel {}: {}", channel_b, err)))?;
+
+        // A is shortened before B moves so the two are never on together
+        pwm_a.set_duty_cycle_ns(timing.a_duty_ns).map_err(map_err)?;
+        pwm_b.set_duty_cycle_ns(timing.b_duty_ns).map_err(map_err)?;
+        pwm_a.enable(true).map_err(map_err)?;
+        pwm_b.enable(true).map_err(map_err)?;
+        Ok(())
+    }
+
     pub fn close(&mut self, channel: u8) -> Result<(), PWMError> {
         let id = match self.owned_channels.get(&channel) {
             Some(i) => i,
@@ -1,12 +1,13 @@
-use crate::bus::BusController;
+use crate::bus::{BusController, GpioController};
 use crate::config::BusControllerConfig;
-use crate::gpio::{GpioBorrowChecker, GpioError};
+use crate::gpio::{GpioBorrowChecker, GpioError, PinDirection};
 use std::any::Any;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
 use parking_lot::RwLock;
 use uuid::Uuid;
-use rppal::gpio::{Gpio, Pin, InputPin, OutputPin, IoPin, Error, Mode};
+use rppal::gpio::{Gpio, Pin, InputPin, OutputPin, IoPin, Error, Mode, Level, Trigger};
 
 fn rppal_map_err(err: Error, default_err_msg: &str) -> GpioError {
     match err {
@@ -17,6 +18,14 @@ fn rppal_map_err(err: Error, default_err_msg: &str) -> GpioError {
     }
 }
 
+// Checked before a pin is handed out as a plain rppal pin, which always works on line levels
+pub(crate) fn ensure_line_level(gpio_borrow: &GpioBorrowChecker, pin: u8) -> Result<(), GpioError> {
+    match gpio_borrow.get(&pin)?.is_active_low() {
+        true => Err(GpioError::Unsupported(format!("pin {} is active low and can only be used through the GPIO interface", pin))),
+        false => Ok(())
+    }
+}
+
 pub enum InputMode {
     Normal,
     PullUp,
@@ -32,7 +41,8 @@ pub enum OutputMode {
 pub struct RawBusController {
     gpio_controller: Gpio,
     gpio_borrow: Arc<RwLock<GpioBorrowChecker>>,
-    owned_pins: HashMap<u8, Uuid>
+    owned_pins: HashMap<u8, Uuid>,
+    held_pins: HashMap<u8, IoPin>
 }
 
 impl BusController for RawBusController {
@@ -45,6 +55,91 @@ impl BusController for RawBusController {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn as_gpio_mut(&mut self) -> Option<&mut (dyn GpioController + 'static)> {
+        Some(self)
+    }
+    fn owned_pins(&self) -> Vec<u8> {
+        self.owned_pins.keys().copied().collect()
+    }
+    fn release_all(&mut self) -> Vec<String> {
+        self.held_pins.clear();
+        let pins: Vec<u8> = self.owned_pins.keys().copied().collect();
+        pins.into_iter()
+            .filter_map(|pin| self.close(pin).err().map(|err| format!("pin {}: {}", pin, err)))
+            .collect()
+    }
+}
+
+impl GpioController for RawBusController {
+    fn read_pin(&mut self, pin: u8) -> Result<u8, GpioError> {
+        let level = match self.held_pins.get(&pin) {
+            Some(held) => held.read(),
+            None => {
+                // not leased through this interface, only hold the pin for the duration of the read
+                let transient = self.borrow_input(pin, InputMode::Normal)?;
+                let level = transient.read();
+                drop(transient);
+                self.close(pin)?;
+                level
+            }
+        };
+
+        let value = match level {
+            Level::High => 1,
+            Level::Low => 0
+        };
+        let value = self.gpio_borrow.read().get(&pin)?.apply_polarity(value);
+        if self.held_pins.contains_key(&pin) {
+            self.gpio_borrow.write().record_value(pin, value)?;
+        }
+
+        Ok(value)
+    }
+
+    fn write_pin(&mut self, pin: u8, value: u8) -> Result<(), GpioError> {
+        let value = (value != 0) as u8;
+        let level = self.gpio_borrow.read().get(&pin)?.apply_polarity(value);
+        if !self.held_pins.contains_key(&pin) {
+            self.set_direction(pin, PinDirection::Output)?;
+        }
+
+        let held = self.held_pins.get_mut(&pin).unwrap();
+        held.write(match level {
+            0 => Level::Low,
+            _ => Level::High
+        });
+        self.gpio_borrow.write().record_value(pin, value)
+    }
+
+    fn set_direction(&mut self, pin: u8, direction: PinDirection) -> Result<(), GpioError> {
+        let mode = match direction {
+            PinDirection::Input => Mode::Input,
+            PinDirection::Output => Mode::Output
+        };
+
+        if let Some(held) = self.held_pins.get_mut(&pin) {
+            held.set_mode(mode);
+            return self.gpio_borrow.write().record_direction(pin, direction);
+        }
+
+        let held = self.borrow_io(pin, mode)?;
+        self.held_pins.insert(pin, held);
+        Ok(())
+    }
+
+    fn release_pin(&mut self, pin: u8) -> Result<(), GpioError> {
+        match self.held_pins.remove(&pin) {
+            Some(held) => {
+                drop(held);
+                self.close(pin)
+            },
+            None => Err(GpioError::LeaseNotFound)
+        }
+    }
+
+    fn holds_pin(&self, pin: u8) -> bool {
+        self.held_pins.contains_key(&pin)
+    }
 }
 
 impl RawBusController {
@@ -55,49 +150,93 @@ impl RawBusController {
         Ok(RawBusController {
             gpio_controller: gpio,
             gpio_borrow: gpio_borrow.clone(), 
-            owned_pins: HashMap::new()
+            owned_pins: HashMap::new(),
+            held_pins: HashMap::new()
         })
     }
 
     pub fn from_config(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>, _config: &BusControllerConfig) -> Result<Self, GpioError> {
+        gpio_borrow.read().ensure_configured().map_err(GpioError::InvalidConfig)?;
         Self::new(gpio_borrow)
     }
 
+    // Pins handed out directly work on line levels and rppal can't invert them,
+    // so active low pins are only handed out through GpioController which applies the polarity itself
     pub fn open_in(&mut self, pin: u8, mode: InputMode) -> Result<InputPin, GpioError> {
+        ensure_line_level(&self.gpio_borrow.read(), pin)?;
+        self.borrow_input(pin, mode)
+    }
+
+    pub fn open_out(&mut self, pin: u8, mode: OutputMode) -> Result<OutputPin, GpioError> {
+        ensure_line_level(&self.gpio_borrow.read(), pin)?;
         if self.owned_pins.contains_key(&pin) {
             return Err(GpioError::Busy(pin));
         }
 
-        let pin = self.borrow_pin(pin)?;
+        let pin = self.borrow_pin(pin, Some(PinDirection::Output))?;
         Ok(match mode {
-            InputMode::Normal => pin.into_input(),
-            InputMode::PullUp => pin.into_input_pullup(),
-            InputMode::PullDown => pin.into_input_pulldown(),
+            OutputMode::Normal => pin.into_output(),
+            OutputMode::LogicHigh => pin.into_output_high(),
+            OutputMode::LogicLow => pin.into_output_low(),
         })
     }
 
-    pub fn open_out(&mut self, pin: u8, mode: OutputMode) -> Result<OutputPin, GpioError> {
+    pub fn open_io(&mut self, pin: u8, mode: Mode) -> Result<IoPin, GpioError> {
+        ensure_line_level(&self.gpio_borrow.read(), pin)?;
+        self.borrow_io(pin, mode)
+    }
+
+    fn borrow_input(&mut self, pin: u8, mode: InputMode) -> Result<InputPin, GpioError> {
         if self.owned_pins.contains_key(&pin) {
             return Err(GpioError::Busy(pin));
         }
 
-        let pin = self.borrow_pin(pin)?;
+        let pin = self.borrow_pin(pin, Some(PinDirection::Input))?;
         Ok(match mode {
-            OutputMode::Normal => pin.into_output(),
-            OutputMode::LogicHigh => pin.into_output_high(),
-            OutputMode::LogicLow => pin.into_output_low(),
+            InputMode::Normal => pin.into_input(),
+            InputMode::PullUp => pin.into_input_pullup(),
+            InputMode::PullDown => pin.into_input_pulldown(),
         })
     }
 
-    pub fn open_io(&mut self, pin: u8, mode: Mode) -> Result<IoPin, GpioError> {
+    fn borrow_io(&mut self, pin: u8, mode: Mode) -> Result<IoPin, GpioError> {
         if self.owned_pins.contains_key(&pin) {
             return Err(GpioError::Busy(pin));
         }
 
-        let pin = self.borrow_pin(pin)?;
+        let direction = match mode {
+            Mode::Input => Some(PinDirection::Input),
+            Mode::Output => Some(PinDirection::Output),
+            _ => None
+        };
+
+        let pin = self.borrow_pin(pin, direction)?;
         Ok(pin.into_io(mode))
     }
 
+    // Blocks until the line sees the edge, None when the timeout runs out first.
+    // The trigger is on line levels like the pins handed out above, the value that comes back is logical.
+    pub fn wait_for_edge(&mut self, pin: u8, trigger: Trigger, timeout: Duration) -> Result<Option<u8>, GpioError> {
+        let mut input = self.borrow_input(pin, InputMode::Normal)?;
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let result = input.set_async_interrupt(trigger, move |level| {
+                let _ = sender.try_send(level);
+            })
+            .map_err(|err| rppal_map_err(err, &format!("Internal RPPAL error while setting an interrupt on pin (ID {})", pin)))
+            .map(|_| receiver.recv_timeout(timeout).ok());
+
+        // dropping the pin clears the interrupt
+        drop(input);
+        self.close(pin)?;
+        let value = match result? {
+            Some(Level::High) => 1,
+            Some(Level::Low) => 0,
+            None => return Ok(None)
+        };
+
+        Ok(Some(self.gpio_borrow.read().get(&pin)?.apply_polarity(value)))
+    }
+
     pub fn close(&mut self, pin: u8) -> Result<(), GpioError> {
         let id = match self.owned_pins.get(&pin) {
             Some(i) => i,
@@ -109,7 +248,7 @@ impl RawBusController {
         Ok(())
     }
     
-    fn borrow_pin(&mut self, pin_id: u8) -> Result<Pin, GpioError> {
+    fn borrow_pin(&mut self, pin_id: u8, direction: Option<PinDirection>) -> Result<Pin, GpioError> {
         let mut borrow_checker = self.gpio_borrow.write();
         let bcm_id = borrow_checker.get(&pin_id)?.bcm_id();
 
@@ -126,6 +265,10 @@ impl RawBusController {
 
         let borrow_id = borrow_checker.borrow_one(pin_id)?;
         self.owned_pins.insert(pin_id, borrow_id);
+        if let Some(direction) = direction {
+            borrow_checker.record_direction(pin_id, direction)?;
+        }
+
         Ok(pin)
     }
 }
\ No newline at end of file
@@ -1,9 +1,9 @@
 use sysfs_gpio::{Pin, Direction, Error};
-use std::{sync::Arc, collections::HashMap, any::Any, path::Path};
+use std::{sync::Arc, collections::HashMap, any::Any, fs, path::{Path, PathBuf}};
 use parking_lot::RwLock;
 use uuid::Uuid;
-use crate::{gpio::{GpioBorrowChecker, GpioError}, config::BusControllerConfig};
-use super::BusController;
+use crate::{gpio::{GpioBorrowChecker, GpioError, PinDirection}, config::BusControllerConfig};
+use super::{BusController, GpioController};
 
 const SYSFS_GPIO_PATH: &str = "/sys/class/gpio";
 
@@ -17,9 +17,18 @@ fn sysfs_map_err(err: Error, default_err_msg: &str) -> GpioError {
     }
 }
 
+fn map_direction(direction: Direction) -> PinDirection {
+    match direction {
+        Direction::Out | Direction::High | Direction::Low => PinDirection::Output,
+        Direction::In => PinDirection::Input
+    }
+}
+
 pub struct SysfsRawBusController {
     gpio_borrow: Arc<RwLock<GpioBorrowChecker>>,
-    owned_pins: HashMap<u8, Uuid>
+    owned_pins: HashMap<u8, Uuid>,
+    held_pins: HashMap<u8, Pin>,
+    sysfs_root: PathBuf
 }
 
 impl BusController for SysfsRawBusController {
@@ -34,22 +43,113 @@ impl BusController for SysfsRawBusController {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+
+    fn as_gpio_mut(&mut self) -> Option<&mut (dyn GpioController + 'static)> {
+        Some(self)
+    }
+
+    fn owned_pins(&self) -> Vec<u8> {
+        self.owned_pins.keys().copied().collect()
+    }
+
+    // pins handed out by open_in/open_out are unexported through a fresh handle, close only goes by the BCM number
+    fn release_all(&mut self) -> Vec<String> {
+        self.held_pins.clear();
+        let pins: Vec<u8> = self.owned_pins.keys().copied().collect();
+        pins.into_iter()
+            .filter_map(|pin| {
+                let bcm_id = self.gpio_borrow.read().get(&pin).map(|x| x.bcm_id());
+                bcm_id.and_then(|bcm_id| self.close(Pin::new(bcm_id.into())))
+                    .err()
+                    .map(|err| format!("pin {}: {}", pin, err))
+            })
+            .collect()
+    }
+}
+
+impl GpioController for SysfsRawBusController {
+    fn read_pin(&mut self, pin: u8) -> Result<u8, GpioError> {
+        if let Some(held) = self.held_pins.get(&pin) {
+            let value = held.get_value()
+                .map_err(|err| sysfs_map_err(err, &format!("Internal sysfs error while reading pin (ID {})", pin)))?;
+            self.gpio_borrow.write().record_value(pin, value)?;
+            return Ok(value);
+        }
+
+        // not leased through this interface, only hold the pin for the duration of the read
+        let transient = self.open_in(pin)?;
+        let result = transient.get_value()
+            .map_err(|err| sysfs_map_err(err, &format!("Internal sysfs error while reading pin (ID {})", pin)));
+        self.close(transient)?;
+        result
+    }
+
+    fn write_pin(&mut self, pin: u8, value: u8) -> Result<(), GpioError> {
+        if !self.held_pins.contains_key(&pin) {
+            self.set_direction(pin, PinDirection::Output)?;
+        }
+
+        let value = (value != 0) as u8;
+        let held = self.held_pins.get(&pin).unwrap();
+        held.set_value(value)
+            .map_err(|err| sysfs_map_err(err, &format!("Internal sysfs error while writing pin (ID {})", pin)))?;
+        self.gpio_borrow.write().record_value(pin, value)
+    }
+
+    fn set_direction(&mut self, pin: u8, direction: PinDirection) -> Result<(), GpioError> {
+        let direction = match direction {
+            PinDirection::Input => Direction::In,
+            PinDirection::Output => Direction::Out
+        };
+
+        if let Some(held) = self.held_pins.get(&pin) {
+            held.set_direction(direction)
+                .map_err(|err| sysfs_map_err(err, &format!("Internal sysfs error while configuring pin (ID {})", pin)))?;
+            return self.gpio_borrow.write().record_direction(pin, map_direction(direction));
+        }
+
+        if self.owned_pins.contains_key(&pin) {
+            return Err(GpioError::Busy(pin));
+        }
+
+        let held = self.borrow_pin(pin, direction)?;
+        self.held_pins.insert(pin, held);
+        Ok(())
+    }
+
+    fn release_pin(&mut self, pin: u8) -> Result<(), GpioError> {
+        match self.held_pins.remove(&pin) {
+            Some(held) => self.close(held),
+            None => Err(GpioError::LeaseNotFound)
+        }
+    }
+
+    fn holds_pin(&self, pin: u8) -> bool {
+        self.held_pins.contains_key(&pin)
+    }
 }
 
 impl SysfsRawBusController {
     pub fn new(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>) -> Result<Self, GpioError> {
-        let path = Path::new(SYSFS_GPIO_PATH);
-        if !path.exists() || !path.is_dir() {
+        Self::with_sysfs_root(gpio_borrow, Path::new(SYSFS_GPIO_PATH))
+    }
+
+    // Same as new but looks for the gpio nodes under root instead of /sys/class/gpio
+    pub(crate) fn with_sysfs_root(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>, root: &Path) -> Result<Self, GpioError> {
+        if !root.is_dir() {
             return Err(GpioError::OsError("GPIO is not supported on this system".to_string()));
         }
 
         Ok(SysfsRawBusController { 
             gpio_borrow: gpio_borrow.clone(), 
-            owned_pins: HashMap::new()
+            owned_pins: HashMap::new(),
+            held_pins: HashMap::new(),
+            sysfs_root: root.to_path_buf()
         })
     }
 
     pub fn from_config(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>, _config: &BusControllerConfig) -> Result<Self, GpioError> {
+        gpio_borrow.read().ensure_configured().map_err(GpioError::InvalidConfig)?;
         Self::new(gpio_borrow)
     }
 
@@ -93,27 +193,55 @@ impl SysfsRawBusController {
             // reset pin state
             pin.set_value(0)
             .and(pin.set_direction(Direction::In))
+            .and(pin.set_active_low(false))
             .and(pin.unexport()).map_err(|err| sysfs_map_err(err, &format!("Internal sysfs error while closing pin (ID {})", pin_id)))?;
         }
 
         borrow_checker.release(id)?;
+        self.owned_pins.remove(&pin_id);
         Ok(())
     }
 
     fn borrow_pin(&mut self, pin_id: u8, direction: Direction) -> Result<Pin, GpioError> {
         let mut borrow_checker = self.gpio_borrow.write();
-        let bcm_id = borrow_checker.get(&pin_id)?.bcm_id();
+        let state = borrow_checker.get(&pin_id)?;
+        let (bcm_id, active_low) = (state.bcm_id(), state.is_active_low());
 
         if !borrow_checker.can_borrow_one(pin_id) {
             return Err(GpioError::Busy(pin_id));
         }
 
+        let pin_path = self.sysfs_root.join(format!("gpio{}", bcm_id));
+        if !pin_path.exists() && !self.has_chip_for(bcm_id) {
+            return Err(GpioError::PinNodeNotFound(pin_id, pin_path.display().to_string()));
+        }
+
         let pin = Pin::new(bcm_id.into());
-        pin.export().and(pin.set_direction(direction)).map_err(|err| sysfs_map_err(err, &format!("Internal sysfs error while opening pin (ID {})", pin_id)))?;
+        if !pin_path.exists() {
+            pin.export().map_err(|err| sysfs_map_err(err, &format!("Internal sysfs error while opening pin (ID {})", pin_id)))?;
+        }
+
+        if !pin_path.exists() {
+            let _ = pin.unexport();
+            return Err(GpioError::PinNodeNotFound(pin_id, pin_path.display().to_string()));
+        }
+
+        // the kernel inverts active low pins, so values read and written through the pin are always logical.
+        // Set on the node under the sysfs root, every pin this controller hands out goes through here
+        let direction_name = match direction {
+            Direction::In => "in",
+            Direction::Out => "out",
+            Direction::High => "high",
+            Direction::Low => "low"
+        };
+        fs::write(pin_path.join("active_low"), if active_low { "1" } else { "0" })
+            .and_then(|_| fs::write(pin_path.join("direction"), direction_name))
+            .map_err(|err| sysfs_map_err(Error::Io(err), &format!("Internal sysfs error while opening pin (ID {})", pin_id)))?;
 
         match borrow_checker.borrow_one(pin_id) {
             Ok(borrow_id) => {
                 self.owned_pins.insert(pin_id, borrow_id);
+                borrow_checker.record_direction(pin_id, map_direction(direction))?;
                 Ok(pin)
             },
             Err(err) => {
@@ -123,4 +251,25 @@ impl SysfsRawBusController {
             },
         }
     }
-}
\ No newline at end of file
+
+    // gpioN only shows up once the pin is exported, before that it has to fall in the range of one of the gpiochips.
+    // Without any gpiochip entries there is nothing to go on, the export gets to decide.
+    fn has_chip_for(&self, bcm_id: u8) -> bool {
+        let entries = match fs::read_dir(&self.sysfs_root) {
+            Ok(e) => e,
+            Err(_) => return true
+        };
+
+        let read_number = |path: PathBuf| fs::read_to_string(path).ok().and_then(|x| x.trim().parse::<u32>().ok());
+        let ranges: Vec<(u32, u32)> = entries.filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if !path.file_name()?.to_str()?.starts_with("gpiochip") {
+                return None;
+            }
+
+            Some((read_number(path.join("base"))?, read_number(path.join("ngpio"))?))
+        }).collect();
+
+        ranges.is_empty() || ranges.iter().any(|(base, count)| (*base..base + count).contains(&(bcm_id as u32)))
+    }
+}
@@ -6,6 +6,7 @@ use log::{warn, debug};
 use parking_lot::RwLock;
 use rppal::uart::{Uart, Parity, Error};
 use serde_json::Value;
+use sysfs_gpio::{Direction, Pin};
 use uuid::Uuid;
 use std::any::Any;
 use crate::gpio::GpioBorrowChecker;
@@ -17,20 +18,76 @@ use serde::{Serialize, Deserialize};
 pub struct UARTDefinition {
     pub path: String,
     pub rx: u8,
-    pub tx: u8
+    pub tx: u8,
+    // only leased while a port is opened with RTS/CTS flow control
+    #[serde(default)]
+    pub rts: Option<u8>,
+    #[serde(default)]
+    pub cts: Option<u8>,
+    // Mux select line asserted while the port is open, ports with one lets several share a path
+    #[serde(default)]
+    pub select: Option<u8>
+}
+
+// Serializeable, the Pi's UARTs only do hardware flow control
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlowControl {
+    #[default]
+    None,
+    RtsCts
 }
 
 impl UARTDefinition {
     pub fn new(path: &str, rx: u8, tx: u8) -> Self {
-        UARTDefinition { path: path.to_string(), rx, tx }
+        UARTDefinition { path: path.to_string(), rx, tx, rts: None, cts: None, select: None }
+    }
+
+    pub fn with_flow_control(mut self, rts: u8, cts: u8) -> Self {
+        self.rts = Some(rts);
+        self.cts = Some(cts);
+        self
+    }
+
+    pub fn with_select(mut self, select: u8) -> Self {
+        self.select = Some(select);
+        self
+    }
+
+    // Both ports sit behind one mux, only one of them can be open at a time
+    pub fn is_muxed_with(&self, other: &Self) -> bool {
+        self.path == other.path && self.select.is_some() && other.select.is_some()
     }
 
     pub fn overlap(&self, other: &Self) -> bool {
-        self.path == other.path ||
-        self.tx == other.tx ||
-        self.rx == other.rx ||
-        self.tx == other.rx ||
-        self.rx == other.tx
+        if self.is_muxed_with(other) {
+            // the line pins are shared, only the select lines have to stay apart
+            return self.select == other.select
+                || other.select.is_some_and(|x| self.pins().contains(&x))
+                || self.select.is_some_and(|x| other.pins().contains(&x));
+        }
+
+        let other_pins = other.pins();
+        self.path == other.path || self.pins().iter().any(|x| other_pins.contains(x))
+    }
+
+    // Every pin the port may use, RTS/CTS and the select line included
+    pub fn pins(&self) -> Vec<u8> {
+        let mut pins = self.to_vec();
+        pins.extend(self.rts);
+        pins.extend(self.cts);
+        pins.extend(self.select);
+        pins
+    }
+
+    // Pins leased when the port is opened with the given flow control
+    pub fn pins_for(&self, flow_control: FlowControl) -> Option<Vec<u8>> {
+        let mut pins = match flow_control {
+            FlowControl::None => self.to_vec(),
+            FlowControl::RtsCts => vec![self.rx, self.tx, self.rts?, self.cts?]
+        };
+
+        pins.extend(self.select);
+        Some(pins)
     }
 
     pub fn to_vec(&self) -> Vec<u8> {
@@ -44,16 +101,38 @@ impl UARTDefinition {
 
 struct UartInfo {
     path: String,
-    lease_id: Option<Uuid>
+    // internal port holding the path, muxed ports share it
+    port: Option<u8>,
+    lease_id: Option<Uuid>,
+    pins: Vec<u8>,
+    // BCM number and inactive level of the select line to deassert on close
+    select: Option<(u8, u8)>
 }
 
 impl UartInfo {
     fn new(path: &str) -> Self {
-        UartInfo { path: path.to_string(), lease_id: None }
+        UartInfo { path: path.to_string(), port: None, lease_id: None, pins: Vec::new(), select: None }
     }
 
-    fn with_lease(path: &str, lease_id: Uuid) -> Self {
-        UartInfo { path: path.to_string(), lease_id: Some(lease_id) }
+    fn with_lease(path: &str, port: u8, lease_id: Uuid, pins: Vec<u8>, select: Option<(u8, u8)>) -> Self {
+        UartInfo { path: path.to_string(), port: Some(port), lease_id: Some(lease_id), pins, select }
+    }
+}
+
+// Drives mux select lines by BCM number, lets tests record the toggling instead of touching sysfs
+pub trait SelectLines: Send + Sync {
+    fn write(&mut self, bcm_id: u8, level: u8) -> Result<(), UARTError>;
+}
+
+pub struct SysfsSelectLines;
+
+impl SelectLines for SysfsSelectLines {
+    fn write(&mut self, bcm_id: u8, level: u8) -> Result<(), UARTError> {
+        let pin = Pin::new(bcm_id as u64);
+        // the direction carries the level so the line never glitches to the other state
+        pin.export()
+            .and_then(|_| pin.set_direction(match level { 0 => Direction::Low, _ => Direction::High }))
+            .map_err(|err| UARTError::HardwareError(format!("could not drive UART select line (BCM {}): {}", bcm_id, err)))
     }
 }
 
@@ -85,7 +164,8 @@ impl Display for UARTError {
 pub struct UARTBusController {
     gpio_borrow: Arc<RwLock<GpioBorrowChecker>>,
     owned_ports: HashMap<String, UartInfo>,
-    internal_ports: HashMap<u8, UARTDefinition>
+    internal_ports: HashMap<u8, UARTDefinition>,
+    select_lines: Box<dyn SelectLines>
 }
 
 impl BusController for UARTBusController {
@@ -98,6 +178,18 @@ impl BusController for UARTBusController {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn owned_pins(&self) -> Vec<u8> {
+        // ports opened by path without a definition don't lease any pins
+        self.owned_ports.values()
+            .flat_map(|info| info.pins.iter().copied())
+            .collect()
+    }
+    fn release_all(&mut self) -> Vec<String> {
+        let paths: Vec<String> = self.owned_ports.keys().cloned().collect();
+        paths.into_iter()
+            .filter_map(|path| self.close_path(path.clone()).err().map(|err| format!("port {}: {}", path, err)))
+            .collect()
+    }
 }
 
 fn rppal_map_err(err: Error, default_err_msg: &str) -> UARTError {
@@ -109,6 +201,37 @@ fn rppal_map_err(err: Error, default_err_msg: &str) -> UARTError {
     }
 }
 
+// Line settings that can be changed on an open port, implemented by rppal's Uart
+pub trait UartSettings {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error>;
+    fn set_parity(&mut self, parity: Parity) -> Result<(), Error>;
+    fn set_data_bits(&mut self, data_bits: u8) -> Result<(), Error>;
+    fn set_stop_bits(&mut self, stop_bits: u8) -> Result<(), Error>;
+    fn set_hardware_flow_control(&mut self, enabled: bool) -> Result<(), Error>;
+}
+
+impl UartSettings for Uart {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error> {
+        Uart::set_baud_rate(self, baud_rate)
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> Result<(), Error> {
+        Uart::set_parity(self, parity)
+    }
+
+    fn set_data_bits(&mut self, data_bits: u8) -> Result<(), Error> {
+        Uart::set_data_bits(self, data_bits)
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: u8) -> Result<(), Error> {
+        Uart::set_stop_bits(self, stop_bits)
+    }
+
+    fn set_hardware_flow_control(&mut self, enabled: bool) -> Result<(), Error> {
+        Uart::set_hardware_flow_control(self, enabled)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct UARTConfigData {
     pub internal_ports: Option<HashMap<u8, UARTDefinition>>
@@ -125,10 +248,16 @@ impl UARTBusController {
         UARTBusController { 
             gpio_borrow: gpio_borrow.clone(), 
             owned_ports: HashMap::new(), 
-            internal_ports: HashMap::new() 
+            internal_ports: HashMap::new(),
+            select_lines: Box::new(SysfsSelectLines)
         }
     }
 
+    pub fn with_select_lines(mut self, select_lines: impl SelectLines + 'static) -> Self {
+        self.select_lines = Box::new(select_lines);
+        self
+    }
+
     pub fn with_internals(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>, internal_ports: HashMap<u8, UARTDefinition>) -> Result<Self, UARTError> {
         let gpio_checker = gpio_borrow.read();
 
@@ -162,6 +291,45 @@ impl UARTBusController {
                 )));
             }
 
+            match (definition.rts, definition.cts) {
+                (None, None) => {},
+                (Some(rts), Some(cts)) => {
+                    let pins = definition.pins();
+                    if pins.iter().enumerate().any(|(i, pin)| pins[..i].contains(pin)) {
+                        return Err(UARTError::InvalidConfig(
+                            format!("UART port is attempting to use the same pin twice: port {} (at {}) -> (RX: {}, TX: {}, RTS: {}, CTS: {})",
+                            id, definition.path, definition.rx, definition.tx, rts, cts
+                        )));
+                    }
+
+                    for (pin, name) in [(rts, "RTS"), (cts, "CTS")] {
+                        if !gpio_checker.has_pin(pin) {
+                            return Err(UARTError::InvalidConfig(
+                                format!("UART port is attempting to use invalid pin: port {} (at {}) pin {} ({})",
+                                id, definition.path, pin, name
+                            )));
+                        }
+                    }
+                },
+                _ => return Err(UARTError::InvalidConfig(
+                    format!("UART port needs both RTS and CTS pins for flow control: port {} (at {})", id, definition.path)
+                ))
+            }
+
+            if let Some(select) = definition.select {
+                if definition.pins().iter().filter(|x| **x == select).count() > 1 {
+                    return Err(UARTError::InvalidConfig(
+                        format!("UART port is attempting to use the same pin twice: port {} (at {}) select pin {}", id, definition.path, select)
+                    ));
+                }
+
+                if !gpio_checker.has_pin(select) {
+                    return Err(UARTError::InvalidConfig(
+                        format!("UART port is attempting to use invalid pin: port {} (at {}) pin {} (select)", id, definition.path, select)
+                    ));
+                }
+            }
+
             for (other_id, other_definition) in &internal_ports {
                 if id != other_id && definition.overlap(other_definition) {
                     return Err(UARTError::InvalidConfig(
@@ -175,11 +343,13 @@ impl UARTBusController {
         Ok(UARTBusController { 
             gpio_borrow: gpio_borrow.clone(), 
             internal_ports: internal_ports, 
-            owned_ports: HashMap::new()
+            owned_ports: HashMap::new(),
+            select_lines: Box::new(SysfsSelectLines)
         })
     }
 
     pub fn from_config(gpio_borrow: &Arc<RwLock<GpioBorrowChecker>>, config: &mut BusControllerConfig) -> Result<Self, UARTError> {
+        gpio_borrow.read().ensure_configured().map_err(UARTError::InvalidConfig)?;
         let data: UARTConfigData = match serde_json::from_value(config.data.clone()) {
             Ok(d) => {
                 let result = config.data.as_object().and_then(|x| Some(x.contains_key("internal_ports")));
@@ -214,49 +384,131 @@ impl UARTBusController {
         }
     }
 
-    pub fn open(&mut self, port: u8, baud_rate: u32, parity: Parity, data_bits: u8, stop_bits: u8) -> Result<Uart, UARTError> {
+    pub fn open(&mut self, port: u8, baud_rate: u32, parity: Parity, data_bits: u8, stop_bits: u8, flow_control: FlowControl) -> Result<Uart, UARTError> {
+        self.open_with(port, flow_control, |path| {
+            Uart::with_path(Path::new(path), baud_rate, parity, data_bits, stop_bits)
+                .map_err(|err| rppal_map_err(err, &format!("Internal RPPAL error while opening UART port {} (at {})", port, path)))
+        })
+    }
+
+    // Leases the port pins around a caller supplied open, lets tests stand in for the real device
+    pub(crate) fn open_with<T: UartSettings, F: FnOnce(&str) -> Result<T, UARTError>>(&mut self, port: u8, flow_control: FlowControl, open: F) -> Result<T, UARTError> {
         let definition = match self.internal_ports.get(&port) {
             Some(definition) => definition,
             None => return Err(UARTError::PortNotFound)
         };
 
+        // muxed ports share the path, so this also keeps a second one from being selected
         if self.owned_ports.contains_key(&definition.path) {
             return Err(UARTError::Busy);
         }
 
+        let pins = match definition.pins_for(flow_control) {
+            Some(pins) => pins,
+            None => return Err(UARTError::InvalidConfig(
+                format!("UART port {} (at {}) has no RTS/CTS pins for flow control", port, definition.path)
+            ))
+        };
+
         let mut borrow_checker = self.gpio_borrow.write();
-        if !borrow_checker.can_borrow_many(&definition.to_arr()) {
+        if !borrow_checker.can_borrow_many(&pins) {
             return Err(UARTError::HardwareError("internal UART channel pins are already in use".to_string()));
         }
 
-        let uart = Uart::with_path(
-            Path::new(&definition.path),
-            baud_rate,
-            parity,
-            data_bits,
-            stop_bits)
-            .map_err(|err| rppal_map_err(err, &format!("Internal RPPAL error while opening UART port {} (at {})", port, definition.path)))?;
+        // (BCM number, active level, inactive level)
+        let select = match definition.select {
+            Some(pin) => {
+                let state = borrow_checker.get(&pin).map_err(|err| UARTError::HardwareError(err.to_string()))?;
+                Some((state.bcm_id(), state.apply_polarity(1), state.apply_polarity(0)))
+            },
+            None => None
+        };
+
+        if let Some((bcm_id, active, _)) = select {
+            self.select_lines.write(bcm_id, active)?;
+        }
+
+        let uart = open(&definition.path).and_then(|mut uart| {
+            if flow_control == FlowControl::RtsCts {
+                uart.set_hardware_flow_control(true)
+                    .map_err(|err| rppal_map_err(err, &format!("Internal RPPAL error while enabling flow control on UART port {} (at {})", port, definition.path)))?;
+            }
 
-        let borrow_id = borrow_checker.borrow_many(definition.to_vec())
-            .map_err(|err| UARTError::HardwareError(err.to_string()))?;
+            Ok(uart)
+        });
+
+        let borrow_id = uart.and_then(|uart| borrow_checker.borrow_many(pins.clone())
+            .map(|id| (uart, id))
+            .map_err(|err| UARTError::HardwareError(err.to_string())));
+
+        let (uart, borrow_id) = match borrow_id {
+            Ok(opened) => opened,
+            Err(err) => {
+                if let Some((bcm_id, _, inactive)) = select {
+                    if let Err(e) = self.select_lines.write(bcm_id, inactive) {
+                        warn!("Failed to deassert the select line of UART port {}: {}", port, e);
+                    }
+                }
 
-        let uart_info = UartInfo::with_lease(&definition.path, borrow_id);
+                return Err(err);
+            }
+        };
+
+        let uart_info = UartInfo::with_lease(&definition.path, port, borrow_id, pins, select.map(|(bcm_id, _, inactive)| (bcm_id, inactive)));
         self.owned_ports.insert(definition.path.to_string(), uart_info);
         Ok(uart)
     }
 
-    pub fn open_path(&mut self, path: String, baud_rate: u32, parity: Parity, data_bits: u8, stop_bits: u8) -> Result<Uart, UARTError> {
+    // The open port's info, None when the port is closed or another port on its mux holds the path
+    fn owned_port(&self, port: u8) -> Result<&UartInfo, UARTError> {
+        let definition = match self.internal_ports.get(&port) {
+            Some(definition) => definition,
+            None => return Err(UARTError::PortNotFound)
+        };
+
+        match self.owned_ports.get(&definition.path) {
+            Some(info) if info.port == Some(port) => Ok(info),
+            _ => Err(UARTError::LeaseNotFound)
+        }
+    }
+
+    // Changes the line settings of an open port in place, the pin lease is kept as is
+    pub fn reconfigure<T: UartSettings>(&mut self, port: u8, uart: &mut T, baud_rate: u32, parity: Parity, data_bits: u8, stop_bits: u8) -> Result<(), UARTError> {
+        let path = &self.owned_port(port)?.path;
+        let err_msg = format!("Internal RPPAL error while reconfiguring UART port {} (at {})", port, path);
+        uart.set_baud_rate(baud_rate).map_err(|err| rppal_map_err(err, &err_msg))?;
+        uart.set_parity(parity).map_err(|err| rppal_map_err(err, &err_msg))?;
+        uart.set_data_bits(data_bits).map_err(|err| rppal_map_err(err, &err_msg))?;
+        uart.set_stop_bits(stop_bits).map_err(|err| rppal_map_err(err, &err_msg))?;
+        Ok(())
+    }
+
+    pub fn port_path(&self, port: u8) -> Option<&str> {
+        self.internal_ports.get(&port).map(|definition| definition.path.as_str())
+    }
+
+    pub fn lease_id(&self, port: u8) -> Option<Uuid> {
+        self.owned_port(port).ok().and_then(|info| info.lease_id)
+    }
+
+    // No pins are leased here, RTS/CTS on an unknown device is up to the caller
+    pub fn open_path(&mut self, path: String, baud_rate: u32, parity: Parity, data_bits: u8, stop_bits: u8, flow_control: FlowControl) -> Result<Uart, UARTError> {
         if self.owned_ports.contains_key(&path) {
             return Err(UARTError::Busy);
         }
 
-        let uart = Uart::with_path(
+        let err_msg = format!("Internal RPPAL error while opening UART device {}", path);
+        let mut uart = Uart::with_path(
             Path::new(&path),
             baud_rate,
             parity,
             data_bits,
             stop_bits)
-            .map_err(|err| rppal_map_err(err, &format!("Internal RPPAL error while opening UART device {}", path)))?;
+            .map_err(|err| rppal_map_err(err, &err_msg))?;
+
+        if flow_control == FlowControl::RtsCts {
+            uart.set_hardware_flow_control(true).map_err(|err| rppal_map_err(err, &err_msg))?;
+        }
 
         let uart_info = UartInfo::new(&path);
         self.owned_ports.insert(path, uart_info);
@@ -264,10 +516,8 @@ impl UARTBusController {
     }
 
     pub fn close(&mut self, port: u8) -> Result<(), UARTError> {
-        match self.internal_ports.get(&port) {
-            Some(definition) => self.close_path(definition.path.to_string()),
-            None => Err(UARTError::PortNotFound)
-        }
+        let path = self.owned_port(port)?.path.clone();
+        self.close_path(path)
     }
 
     pub fn close_path(&mut self, path: String) -> Result<(), UARTError> {
@@ -276,6 +526,11 @@ impl UARTBusController {
             None => return Err(UARTError::LeaseNotFound)
         };
 
+        // kept open when this fails, a select line left asserted must not let another port on the mux in
+        if let Some((bcm_id, inactive)) = info.select {
+            self.select_lines.write(bcm_id, inactive)?;
+        }
+
         if info.lease_id.is_some() {
             // Internal port, needs to be released.
             let mut borrow_checker = self.gpio_borrow.write();
@@ -1,11 +1,13 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 use intertrait::cast::CastRef;
+use log::warn;
 use nmea::{Satellite, Nmea};
 use serde::{Serialize, Deserialize};
 use strum::{EnumIter, IntoEnumIterator};
 
-use crate::device::{DeviceError, DeviceDriver};
+use crate::device::{DeviceError, DeviceDriver, DeviceLink};
 
 pub fn get_device_capabilities<T: DeviceDriver + ?Sized>(device: &T) -> Vec<CapabilityId> {
     let mut capabilities = Vec::<CapabilityId>::new();
@@ -15,10 +17,15 @@ pub fn get_device_capabilities<T: DeviceDriver + ?Sized>(device: &T) -> Vec<Capa
             CapabilityId::GPS => device.cast::<dyn GpsCapable>().is_some(),
             CapabilityId::LightSensor => device.cast::<dyn LightSensorCapable>().is_some(),
             CapabilityId::Thermometer => device.cast::<dyn ThermometerCapable>().is_some(),
-            CapabilityId::Barometer => device.cast::<dyn BarometerCapable>().is_some()
+            CapabilityId::Barometer => device.cast::<dyn BarometerCapable>().is_some(),
+            CapabilityId::SerialPort => device.cast::<dyn SerialPortCapable>().is_some(),
+            CapabilityId::Diagnostics => device.cast::<dyn DiagnosticsCapable>().is_some(),
+            CapabilityId::PowerMonitor => device.cast::<dyn PowerMonitorCapable>().is_some(),
+            CapabilityId::Servo => device.cast::<dyn ServoCapable>().is_some(),
+            CapabilityId::Switch => device.cast::<dyn SwitchCapable>().is_some()
         };
 
-        if has_capability {
+        if has_capability && device.supports_capability(capability) {
             capabilities.push(capability);
         }
     }
@@ -34,23 +41,112 @@ pub enum CapabilityId {
     GPS,
     LightSensor,
     Thermometer,
-    Barometer
+    Barometer,
+    SerialPort,
+    Diagnostics,
+    PowerMonitor,
+    Servo,
+    Switch
 }
 
 // Any capability APIs will go here
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+// Serialized by name so configs written before named modes existed still load
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(from = "String", into = "String")]
 pub enum LEDMode {
     Visible,
-    Infrared
+    Infrared,
+    // extra emitter banks, named in the driver config
+    Named(String)
+}
+
+impl LEDMode {
+    pub fn name(&self) -> &str {
+        match self {
+            LEDMode::Visible => "Visible",
+            LEDMode::Infrared => "Infrared",
+            LEDMode::Named(name) => name
+        }
+    }
+}
+
+impl From<String> for LEDMode {
+    fn from(name: String) -> Self {
+        if name.eq_ignore_ascii_case("visible") {
+            LEDMode::Visible
+        } else if name.eq_ignore_ascii_case("infrared") {
+            LEDMode::Infrared
+        } else {
+            LEDMode::Named(name)
+        }
+    }
+}
+
+impl From<LEDMode> for String {
+    fn from(mode: LEDMode) -> Self {
+        mode.name().to_owned()
+    }
 }
 
 pub trait LEDControllerCapable : Capability {
     fn get_mode(&self) -> Result<LEDMode, DeviceError>;
     fn set_mode(&mut self, mode: LEDMode) -> Result<(), DeviceError>;
+    fn get_modes(&self) -> Result<Vec<LEDMode>, DeviceError> {
+        Ok(vec![LEDMode::Visible, LEDMode::Infrared])
+    }
     fn get_brightness(&self) -> Result<f32, DeviceError>;
     fn set_brightness(&mut self, brightness: f32) -> Result<(), DeviceError>;
     fn get_power_state(&self) -> Result<bool, DeviceError>;
     fn set_power_state(&mut self, powered_on: bool) -> Result<(), DeviceError>;
+
+    // Brightness the LED is actually putting out. get_brightness keeps the target while the LED is
+    // powered off so it comes back at the same level.
+    fn get_effective_brightness(&self) -> Result<f32, DeviceError> {
+        match self.get_power_state()? {
+            true => self.get_brightness(),
+            false => Ok(0.0)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CoordinateFormat {
+    DecimalDegrees,
+    DegreesMinutesSeconds,
+    DegreesDecimalMinutes
+}
+
+pub const MAX_COORDINATE_PRECISION: u8 = 9;
+
+// Precision is the number of decimals on the last component, hemisphere letters are used instead of a sign
+// except for decimal degrees. The value is rounded as a whole so e.g. 59.999 seconds carries into the minutes.
+pub fn format_coordinate(value: f64, is_latitude: bool, format: CoordinateFormat, precision: u8) -> String {
+    let precision = precision.min(MAX_COORDINATE_PRECISION);
+    let decimals = precision as usize;
+    let scale = 10u64.pow(precision as u32);
+    let hemisphere = match (is_latitude, value < 0.0) {
+        (true, false) => 'N',
+        (true, true) => 'S',
+        (false, false) => 'E',
+        (false, true) => 'W'
+    };
+
+    match format {
+        CoordinateFormat::DecimalDegrees => format!("{:.*}", decimals, value),
+        CoordinateFormat::DegreesDecimalMinutes => {
+            let total = (value.abs() * 60.0 * scale as f64).round() as u64;
+            let degrees = total / (60 * scale);
+            let minutes = (total % (60 * scale)) as f64 / scale as f64;
+            format!("{}°{:.*}' {}", degrees, decimals, minutes, hemisphere)
+        }
+        CoordinateFormat::DegreesMinutesSeconds => {
+            let total = (value.abs() * 3600.0 * scale as f64).round() as u64;
+            let degrees = total / (3600 * scale);
+            let minutes = (total % (3600 * scale)) / (60 * scale);
+            let seconds = (total % (60 * scale)) as f64 / scale as f64;
+            format!("{}°{}'{:.*}\" {}", degrees, minutes, decimals, seconds, hemisphere)
+        }
+    }
 }
 
 pub trait GpsCapable : Capability {
@@ -63,40 +159,295 @@ pub trait GpsCapable : Capability {
     fn get_nmea(&self) -> Result<Nmea, DeviceError>;
     fn get_vertical_accuracy(&self) -> Result<f32, DeviceError>;
     fn get_horizontal_accuracy(&self) -> Result<f32, DeviceError>;
+
+    // Time since the receiver last sent a readable sentence
+    fn get_fix_age(&self) -> Result<Duration, DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+
+    // Names of the configured geofences the last position was inside of
+    fn get_active_geofences(&self) -> Result<Vec<String>, DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+
+    // Linked barometer and the share of the fused altitude taken from it
+    fn get_barometer(&self) -> Option<(&DeviceLink, f32)> {
+        None
+    }
+
+    // GPS altitude blended with the linked barometer's, either one is used alone when the other is unavailable
+    fn get_fused_altitude(&self) -> Result<f32, DeviceError> {
+        let barometer = match self.get_barometer() {
+            Some((barometer, _)) => barometer,
+            None => return self.get_altitude()
+        };
+
+        let barometer_altitude = match barometer.with_capability_mut::<dyn BarometerCapable, _>(|b| b.get_altitude()) {
+            Ok(altitude) => Some(altitude),
+            Err(e) => {
+                warn!("Failed to read altitude from barometer \"{}\", using GPS altitude only: {}", barometer.name(), e);
+                None
+            }
+        };
+
+        self.fuse_barometer_altitude(barometer_altitude)
+    }
+
+    // Blends an altitude read from the linked barometer by the caller, None when it couldn't be read
+    fn fuse_barometer_altitude(&self, barometer_altitude: Option<f32>) -> Result<f32, DeviceError> {
+        let (barometer_altitude, weight) = match (barometer_altitude, self.get_barometer()) {
+            (Some(altitude), Some((_, weight))) => (altitude, weight),
+            _ => return self.get_altitude()
+        };
+
+        match self.has_fix()? {
+            true => Ok(fuse_altitude(self.get_altitude()?, barometer_altitude, weight)),
+            false => Ok(barometer_altitude)
+        }
+    }
+
+    // Latitude and longitude as display strings, built on top of get_location
+    fn get_location_formatted(&self, format: CoordinateFormat, precision: u8) -> Result<(String, String), DeviceError> {
+        if precision > MAX_COORDINATE_PRECISION {
+            return Err(DeviceError::InvalidOperation(format!("precision cannot be above {} decimals", MAX_COORDINATE_PRECISION)));
+        }
+
+        let (latitude, longitude) = self.get_location()?;
+        Ok((
+            format_coordinate(latitude, true, format, precision),
+            format_coordinate(longitude, false, format, precision)
+        ))
+    }
+}
+
+pub fn fuse_altitude(gps_altitude: f32, barometer_altitude: f32, barometer_weight: f32) -> f32 {
+    let weight = barometer_weight.clamp(0.0, 1.0);
+    barometer_altitude * weight + gps_altitude * (1.0 - weight)
+}
+
+// the fit at an all infrared reading
+pub const MIN_COLOR_TEMPERATURE: f32 = 1350.0;
+pub const MAX_COLOR_TEMPERATURE: f32 = 15000.0;
+
+// Rough correlated color temperature in kelvin from the share of infrared in a broadband reading.
+// Hotter sources put less of their light into the IR band, the fit CCT = 1350 * (IR / full)^-0.74
+// puts incandescent light (ratio ~0.4) at ~2700K and overcast daylight (ratio ~0.12) at ~6500K.
+// Only meaningful for thermal sources and daylight, LEDs and fluorescent tubes emit almost no IR at any CCT.
+pub fn estimate_color_temperature(full_spectrum: u32, infrared: u32) -> Result<f32, DeviceError> {
+    if full_spectrum == 0 {
+        return Err(DeviceError::InvalidOperation("not enough light to estimate color temperature".to_string()));
+    }
+
+    if infrared > full_spectrum {
+        return Err(DeviceError::Other("infrared overflow".to_string()));
+    }
+
+    // no measurable IR at all would divide by zero, that is as blue as the estimate goes anyway
+    if infrared == 0 {
+        return Ok(MAX_COLOR_TEMPERATURE);
+    }
+
+    let ratio = infrared as f32 / full_spectrum as f32;
+    Ok((1350.0 * ratio.powf(-0.74)).clamp(MIN_COLOR_TEMPERATURE, MAX_COLOR_TEMPERATURE))
+}
+
+// Unit and expected range of one reading, so clients don't have to hardcode them
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadingUnit {
+    pub reading: String,
+    pub unit: String,
+    pub min: f32,
+    pub max: f32
 }
 
-pub trait LightSensorCapable : Capability {
+impl ReadingUnit {
+    pub fn new(reading: &str, unit: &str, min: f32, max: f32) -> Self {
+        Self { reading: reading.to_string(), unit: unit.to_string(), min, max }
+    }
+
+    // For drivers that don't know the range of their hardware
+    pub fn unbounded(reading: &str, unit: &str) -> Self {
+        Self::new(reading, unit, f32::NEG_INFINITY, f32::INFINITY)
+    }
+}
+
+// Linear correction for a sensor that drifted, the reading becomes raw * scale + offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadingCalibration {
+    pub scale: f32,
+    pub offset: f32
+}
+
+impl Default for ReadingCalibration {
+    fn default() -> Self {
+        Self { scale: 1.0, offset: 0.0 }
+    }
+}
+
+impl ReadingCalibration {
+    pub fn new(scale: f32, offset: f32) -> Result<Self, String> {
+        if !scale.is_finite() || scale == 0.0 {
+            return Err(format!("invalid calibration scale: {}, value must be finite and non-zero", scale));
+        }
+
+        if !offset.is_finite() {
+            return Err(format!("invalid calibration offset: {}, value must be finite", offset));
+        }
+
+        Ok(Self { scale, offset })
+    }
+
+    pub fn apply(&self, raw: f32) -> f32 {
+        raw * self.scale + self.offset
+    }
+}
+
+// Gain and integration interval settings shared by the sensor capabilities. A device taking more than one kind
+// of reading can keep a gain per reading, single reading sensors ignore which one is asked for.
+pub trait SensorCapable : Capability {
     fn get_supported_gains(&self) -> HashMap<u8, u16>;
     fn get_supported_intervals(&self) -> HashMap<u8, u16>;
+    fn get_gain(&self, reading: CapabilityId) -> Result<u16, DeviceError>;
+    fn set_gain(&mut self, reading: CapabilityId, gain_id: u8) -> Result<(), DeviceError>;
+    fn get_interval(&self) -> Result<u16, DeviceError>;
+    fn set_interval(&mut self, interval_id: u8) -> Result<(), DeviceError>;
+
+    // Correction applied to the final reading, kept per reading like the gain
+    fn get_calibration(&self, _reading: CapabilityId) -> Result<ReadingCalibration, DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+
+    fn set_calibration(&mut self, _reading: CapabilityId, _calibration: ReadingCalibration) -> Result<(), DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+}
+
+pub trait LightSensorCapable : SensorCapable {
     fn get_supported_channels(&self) -> HashMap<u8, String>;
     fn get_auto_gain_enabled(&self) -> Result<bool, DeviceError>;
     fn set_auto_gain_enabled(&mut self, enabled: bool) -> Result<(), DeviceError>;
-    fn get_gain(&self) -> Result<u16, DeviceError>;
-    fn set_gain(&mut self, gain_id: u8) -> Result<(), DeviceError>;
-    fn get_interval(&self) -> Result<u16, DeviceError>;
-    fn set_interval(&mut self, interval_id: u8) -> Result<(), DeviceError>;
     fn get_luminosity(&mut self, channel_id: u8) -> Result<u32, DeviceError>;
     fn get_illuminance(&mut self) -> Result<f32, DeviceError>;
+    fn get_lux_coefficient(&self) -> Result<f32, DeviceError>;
+    fn set_lux_coefficient(&mut self, coefficient: f32) -> Result<(), DeviceError>;
+    fn reset_filter(&mut self) -> Result<(), DeviceError>;
+
+    // Correlated color temperature in kelvin, for sensors whose channels allow an estimate
+    fn get_color_temperature(&mut self) -> Result<f32, DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+
+    fn get_units(&self) -> Vec<ReadingUnit> {
+        vec![
+            ReadingUnit::unbounded("illuminance", "lx"),
+            ReadingUnit::unbounded("luminosity", "counts")
+        ]
+    }
 }
 
-pub trait ThermometerCapable : Capability {
-    fn get_supported_gains(&self) -> HashMap<u8, u16>;
-    fn get_supported_intervals(&self) -> HashMap<u8, u16>;
-    fn get_gain(&self) -> Result<u16, DeviceError>;
-    fn set_gain(&mut self, gain_id: u8) -> Result<(), DeviceError>;
-    fn get_interval(&self) -> Result<u16, DeviceError>;
-    fn set_interval(&mut self, interval_id: u8) -> Result<(), DeviceError>;
+pub trait ThermometerCapable : SensorCapable {
     fn get_temperature_celsius(&mut self) -> Result<f32, DeviceError>;
     fn get_temperature_fahrenheit(&mut self) -> Result<f32, DeviceError>;
+    fn reset_filter(&mut self) -> Result<(), DeviceError>;
+
+    fn get_units(&self) -> Vec<ReadingUnit> {
+        vec![
+            ReadingUnit::unbounded("temperature_celsius", "°C"),
+            ReadingUnit::unbounded("temperature_fahrenheit", "°F")
+        ]
+    }
 }
 
-pub trait BarometerCapable : Capability {
-    fn get_supported_gains(&self) -> HashMap<u8, u16>;
-    fn get_supported_intervals(&self) -> HashMap<u8, u16>;
-    fn get_gain(&self) -> Result<u16, DeviceError>;
-    fn set_gain(&mut self, gain_id: u8) -> Result<(), DeviceError>;
-    fn get_interval(&self) -> Result<u16, DeviceError>;
-    fn set_interval(&mut self, interval_id: u8) -> Result<(), DeviceError>;
+pub trait BarometerCapable : SensorCapable {
     fn get_pressure(&mut self) -> Result<f32, DeviceError>;
     fn get_altitude(&mut self) -> Result<f32, DeviceError>;
+    fn get_reference_pressure(&self) -> Result<f32, DeviceError>;
+    fn set_reference_pressure(&mut self, pressure_hpa: f32) -> Result<(), DeviceError>;
+    fn reset_filter(&mut self) -> Result<(), DeviceError>;
+
+    fn get_units(&self) -> Vec<ReadingUnit> {
+        vec![
+            ReadingUnit::unbounded("pressure", "Pa"),
+            ReadingUnit::unbounded("altitude", "m")
+        ]
+    }
+}
+
+pub trait SerialPortCapable : Capability {
+    fn write_bytes(&mut self, data: &[u8]) -> Result<usize, DeviceError>;
+    fn read_bytes(&mut self, max: usize, timeout: Duration) -> Result<Vec<u8>, DeviceError>;
+    fn read_line(&mut self, timeout: Duration) -> Result<String, DeviceError>;
+    fn flush(&mut self) -> Result<(), DeviceError>;
+}
+
+pub trait PowerMonitorCapable : Capability {
+    fn get_bus_voltage(&mut self) -> Result<f32, DeviceError>;
+    fn get_current_ma(&mut self) -> Result<f32, DeviceError>;
+    fn get_power_mw(&mut self) -> Result<f32, DeviceError>;
+    fn set_calibration(&mut self, shunt_resistance_ohms: f32, max_expected_current_a: f32) -> Result<(), DeviceError>;
+}
+
+pub trait ServoCapable : Capability {
+    fn get_angle(&self) -> Result<f32, DeviceError>;
+    fn set_angle(&mut self, degrees: f32) -> Result<(), DeviceError>;
+    fn get_pulse_range(&self) -> Result<(u32, u32), DeviceError>;
+    fn set_pulse_range(&mut self, min_us: u32, max_us: u32) -> Result<(), DeviceError>;
+}
+
+// On/off actuators such as relays, true is on regardless of how the output is wired
+pub trait SwitchCapable : Capability {
+    fn get_state(&self) -> Result<bool, DeviceError>;
+    fn set_state(&mut self, on: bool) -> Result<(), DeviceError>;
+
+    // Returns the new state
+    fn toggle(&mut self) -> Result<bool, DeviceError> {
+        let state = !self.get_state()?;
+        self.set_state(state)?;
+        Ok(state)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub chip_id_verified: bool,
+    pub reported_chip_id: Option<u8>,
+    pub last_error: Option<String>,
+    pub uptime: Duration,
+}
+
+impl HealthReport {
+    pub fn new(reported_chip_id: Option<u8>, expected_chip_id: u8, last_error: Option<&DeviceError>, uptime: Duration) -> Self {
+        let chip_id_verified = reported_chip_id == Some(expected_chip_id);
+        HealthReport {
+            healthy: chip_id_verified,
+            chip_id_verified: chip_id_verified,
+            reported_chip_id: reported_chip_id,
+            last_error: last_error.map(|e| e.to_string()),
+            uptime: uptime,
+        }
+    }
+
+    // For drivers that check themselves by reading back their chip ID, a failed read becomes the last error
+    pub fn from_chip_id_read<E: std::error::Error + Send + Sync + 'static>(
+        chip_id: Result<u8, E>,
+        expected_chip_id: u8,
+        last_error: &mut Option<DeviceError>,
+        started_at: Option<Instant>
+    ) -> Self {
+        let reported_chip_id = match chip_id {
+            Ok(id) => Some(id),
+            Err(e) => {
+                *last_error = Some(DeviceError::hardware(format!("failed to identify chip: {}", e), e));
+                None
+            }
+        };
+
+        let uptime = started_at.map(|x| x.elapsed()).unwrap_or(Duration::ZERO);
+        HealthReport::new(reported_chip_id, expected_chip_id, last_error.as_ref(), uptime)
+    }
+}
+
+pub trait DiagnosticsCapable : Capability {
+    fn self_test(&mut self) -> Result<HealthReport, DeviceError>;
 }
\ No newline at end of file
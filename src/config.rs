@@ -3,8 +3,21 @@ use std::{collections::HashMap, net::IpAddr};
 use std::fmt::Display;
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
+use uuid::Uuid;
+use std::fs;
 use std::io::{Read, Write};
 
+// Layered loading from a URL, the config file and NVOS_ env vars
+pub mod source;
+// Running config shared with RPCs that change and save it
+pub mod store;
+
+// Bump together with a new step in migrate() whenever the config shape changes
+pub const CONFIG_VERSION: u32 = 1;
+
+// Controller and device data can use "gpio:LED_ENABLE" wherever a pin ID is expected
+pub const PIN_NAME_PREFIX: &str = "gpio:";
+
 #[derive(Debug, PartialEq)]
 pub enum ConfigError {
     SerializeError(String),
@@ -26,15 +39,76 @@ impl Display for ConfigError {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl std::error::Error for ConfigError {}
+
+fn validate_pem_file(path: &str, description: &str) -> Result<(), ConfigError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(err) => return Err(ConfigError::InvalidEntry(format!("failed to read {} at {}: {}", description, path, err)))
+    };
+
+    if !contents.contains("-----BEGIN ") || !contents.contains("-----END ") {
+        return Err(ConfigError::InvalidEntry(format!("{} at {} is not a PEM file", description, path)));
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiTokenConfig {
+    pub token: String,
+    pub scopes: Vec<String>
+}
+
+impl ApiTokenConfig {
+    pub fn new(token: String, scopes: Vec<String>) -> Self {
+        Self { token, scopes }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigSectionRPC {
     pub server_host: String,
-    pub server_port: u16
+    pub server_port: u16,
+    #[serde(default)]
+    pub tls_cert_path: Option<String>,
+    #[serde(default)]
+    pub tls_key_path: Option<String>,
+    #[serde(default)]
+    pub tls_client_ca_path: Option<String>,
+    #[serde(default)]
+    pub auth_tokens: Vec<ApiTokenConfig>,
+    #[serde(default)]
+    pub min_read_interval_ms: HashMap<String, u64>,
+    // Deadline for blocking sensor reads made by RPC handlers, 0 waits forever
+    #[serde(default = "default_command_timeout_ms")]
+    pub command_timeout_ms: u64,
+    // Plain HTTP JSON gateway on the same host, disabled unless a port is set
+    #[serde(default)]
+    pub rest_port: Option<u16>,
+    // Level every RPC call is logged at (method, device, status and latency), off unless set
+    #[serde(default)]
+    pub access_log: Option<String>,
+    // Calls handled at once before new ones are rejected with RESOURCE_EXHAUSTED, unlimited unless set
+    #[serde(default)]
+    pub max_in_flight_requests: Option<usize>
+}
+
+fn default_command_timeout_ms() -> u64 {
+    crate::rpc::timeout::DEFAULT_COMMAND_TIMEOUT_MS
 }
 
 impl ConfigSectionRPC {
     pub fn new(server_host: String, server_port: u16) -> Self {
-        Self { server_host, server_port }
+        Self { server_host, server_port, tls_cert_path: None, tls_key_path: None, tls_client_ca_path: None, auth_tokens: Vec::new(), min_read_interval_ms: HashMap::new(), command_timeout_ms: default_command_timeout_ms(), rest_port: None, access_log: None, max_in_flight_requests: None }
+    }
+
+    pub fn is_tls_enabled(&self) -> bool {
+        self.tls_cert_path.is_some() && self.tls_key_path.is_some()
+    }
+
+    pub fn access_log_level(&self) -> Option<log::Level> {
+        self.access_log.as_ref().and_then(|x| x.parse().ok())
     }
 
     pub fn validate(&self) -> Result<(), ConfigError> {
@@ -46,6 +120,47 @@ impl ConfigSectionRPC {
             return Err(ConfigError::InvalidEntry("invalid server port".to_string()));
         }
 
+        if let Some(rest_port) = self.rest_port {
+            if rest_port == 0 || rest_port == self.server_port {
+                return Err(ConfigError::InvalidEntry(format!("invalid REST gateway port: {}", rest_port)));
+            }
+        }
+
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                validate_pem_file(cert_path, "TLS certificate")?;
+                validate_pem_file(key_path, "TLS private key")?;
+            },
+            (None, None) => {
+                if self.tls_client_ca_path.is_some() {
+                    return Err(ConfigError::MissingEntry("client CA requires a TLS certificate and key to be set".to_string()));
+                }
+            },
+            _ => return Err(ConfigError::MissingEntry("TLS requires both a certificate and a private key".to_string()))
+        }
+
+        if let Some(ca_path) = &self.tls_client_ca_path {
+            validate_pem_file(ca_path, "TLS client CA")?;
+        }
+
+        if self.auth_tokens.iter().any(|x| x.token.trim().is_empty()) {
+            return Err(ConfigError::InvalidEntry("auth token cannot be empty".to_string()));
+        }
+
+        crate::rpc::auth::AuthTokens::from_config(&self.auth_tokens)?;
+
+        if let Some(level) = &self.access_log {
+            if level.parse::<log::Level>().is_err() {
+                return Err(ConfigError::InvalidEntry(format!("invalid access log level: {}", level)));
+            }
+        }
+
+        for method in self.min_read_interval_ms.keys() {
+            if !crate::rpc::rate_limit::RATE_LIMITED_METHODS.contains(&method.as_str()) {
+                return Err(ConfigError::InvalidEntry(format!("method {} does not support a minimum read interval", method)));
+            }
+        }
+
         Ok(())
     }
 }
@@ -56,20 +171,60 @@ impl Default for ConfigSectionRPC {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigSectionADB {
     pub server_host: String,
     pub server_port: u16,
     pub read_timeout_ms: u64,
-    pub write_timeout_ms: u64
+    pub write_timeout_ms: u64,
+    // How often each port mapping is tried again after the device reconnects
+    #[serde(default = "default_port_restore_attempts")]
+    pub port_restore_attempts: u32,
+    #[serde(default = "default_port_restore_delay_ms")]
+    pub port_restore_delay_ms: u64,
+    // Lets clients subscribe to device connects, disconnects and restored port mappings
+    #[serde(default = "default_connection_events")]
+    pub connection_events: bool,
+    // Off for units reached without an Android host, nothing is started and the network service answers unavailable
+    #[serde(default = "default_adb_enabled")]
+    pub enabled: bool
+}
+
+fn default_connection_events() -> bool {
+    true
+}
+
+fn default_adb_enabled() -> bool {
+    true
+}
+
+fn default_port_restore_attempts() -> u32 {
+    crate::adb::DEFAULT_PORT_RESTORE_ATTEMPTS
+}
+
+fn default_port_restore_delay_ms() -> u64 {
+    crate::adb::DEFAULT_PORT_RESTORE_DELAY.as_millis() as u64
 }
 
 impl ConfigSectionADB {
     pub fn new(server_host: String, server_port: u16, read_timeout_ms: u64, write_timeout_ms: u64) -> Self {
-        Self { server_host, server_port, read_timeout_ms, write_timeout_ms }
+        Self {
+            server_host,
+            server_port,
+            read_timeout_ms,
+            write_timeout_ms,
+            port_restore_attempts: default_port_restore_attempts(),
+            port_restore_delay_ms: default_port_restore_delay_ms(),
+            connection_events: default_connection_events(),
+            enabled: default_adb_enabled()
+        }
     }
 
     pub fn validate(&self) -> Result<(), ConfigError> {
+        if !self.enabled {
+            return Ok(());
+        }
+
         if let Err(err) = format!("{}:{}", self.server_host, self.server_port).to_socket_addrs() {
             return Err(ConfigError::InvalidEntry(format!("failed to parse server host: {}", err)));
         }
@@ -78,6 +233,10 @@ impl ConfigSectionADB {
             return Err(ConfigError::InvalidEntry("invalid server port".to_string()));
         }
 
+        if self.port_restore_attempts == 0 {
+            return Err(ConfigError::InvalidEntry("port restore attempts must be at least 1".to_string()));
+        }
+
         Ok(())
     }
 }
@@ -88,13 +247,58 @@ impl Default for ConfigSectionADB {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+// Older config files map pin IDs straight to BCM IDs
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GpioPinShape {
+    Bcm(u8),
+    Full {
+        bcm_id: u8,
+        #[serde(default)]
+        active_low: bool,
+        #[serde(default)]
+        name: Option<String>
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "GpioPinShape")]
+pub struct GpioPinConfig {
+    pub bcm_id: u8,
+    // Inverts reads and writes so 1 always means active, for parts wired to pull the line low
+    pub active_low: bool,
+    // Alias other config entries can use instead of the pin ID, see PIN_NAME_PREFIX
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>
+}
+
+impl GpioPinConfig {
+    pub fn new(bcm_id: u8, active_low: bool) -> Self {
+        Self { bcm_id, active_low, name: None }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+impl From<GpioPinShape> for GpioPinConfig {
+    fn from(shape: GpioPinShape) -> Self {
+        match shape {
+            GpioPinShape::Bcm(bcm_id) => Self::new(bcm_id, false),
+            GpioPinShape::Full { bcm_id, active_low, name } => Self { bcm_id, active_low, name }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct ConfigSectionGPIO {
-    pub pin_config: HashMap<u8, u8>
+    pub pin_config: HashMap<u8, GpioPinConfig>
 }
 
 impl ConfigSectionGPIO {
-    pub fn new(pin_config: HashMap<u8, u8>) -> Self {
+    pub fn new(pin_config: HashMap<u8, GpioPinConfig>) -> Self {
         Self { pin_config }
     }
 
@@ -102,7 +306,7 @@ impl ConfigSectionGPIO {
         let mut known_pin_ids = Vec::new();
         let mut known_bcm_ids = Vec::new();
 
-        for (id, bcm) in &self.pin_config {
+        for (id, bcm) in self.pin_config.iter().map(|(id, pin)| (id, &pin.bcm_id)) {
             if known_pin_ids.contains(&id) {
                 return Err(ConfigError::InvalidEntry(
                     format!("invalid pin configuration: ({} -> {}), pin ID {} is defined more than once", id, bcm, bcm)
@@ -119,24 +323,92 @@ impl ConfigSectionGPIO {
             known_bcm_ids.push(bcm);
         }
 
+        let mut known_names: Vec<&str> = Vec::new();
+        for (id, name) in self.pin_config.iter().filter_map(|(id, pin)| pin.name.as_deref().map(|name| (id, name))) {
+            if name.trim().is_empty() {
+                return Err(ConfigError::InvalidEntry(format!("invalid pin configuration: pin ID {} has an empty name", id)));
+            }
+
+            if known_names.iter().any(|x| x.eq_ignore_ascii_case(name)) {
+                return Err(ConfigError::DuplicateEntry(format!("GPIO pin name \"{}\" is used by more than one pin", name)));
+            }
+
+            known_names.push(name);
+        }
+
+        Ok(())
+    }
+
+    // Pin ID of the pin with this name, names are case insensitive
+    pub fn pin_id(&self, name: &str) -> Option<u8> {
+        self.pin_config.iter()
+            .find(|(_, pin)| pin.name.as_deref().is_some_and(|x| x.eq_ignore_ascii_case(name)))
+            .map(|(id, _)| *id)
+    }
+
+    // Replaces every "gpio:<name>" string in the data with its pin ID, numeric references are left alone
+    pub fn resolve_pin_names(&self, data: &mut Value) -> Result<(), ConfigError> {
+        match data {
+            Value::String(text) => {
+                if let Some(name) = text.strip_prefix(PIN_NAME_PREFIX) {
+                    let id = self.pin_id(name).ok_or_else(|| ConfigError::MissingEntry(
+                        format!("no GPIO pin is named \"{}\", add it as the name of a pin in the GPIO section", name)
+                    ))?;
+                    *data = Value::from(id);
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.resolve_pin_names(item)?;
+                }
+            }
+            Value::Object(map) => {
+                for item in map.values_mut() {
+                    self.resolve_pin_names(item)?;
+                }
+            }
+            _ => {}
+        }
+
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DeviceConfig {
     pub driver: String,
     pub friendly_name: Option<String>,
-    pub driver_data: Value
+    pub driver_data: Value,
+    // Bus controller or device names that have to be started before this device
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    // Devices that may not be connected, a failed start is retried in the background instead of dropping the device
+    #[serde(default)]
+    pub optional: bool,
+    // Free form labels for grouping devices in clients, e.g. "rack-1" or "outdoor"
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Fixed device address for clients that store it, a random one is assigned on every start otherwise
+    #[serde(default)]
+    pub address: Option<String>
 }
 
 impl DeviceConfig {
     pub fn new(driver: String, friendly_name: Option<String>, driver_data: Value) -> Self {
-        Self { driver, friendly_name, driver_data }
+        Self { driver, friendly_name, driver_data, depends_on: Vec::new(), optional: false, tags: Vec::new(), address: None }
     }
 
     pub fn new_without_data(driver: String, friendly_name: Option<String>) -> Self {
-        Self { driver, friendly_name, driver_data: Value::Null }
+        Self { driver, friendly_name, driver_data: Value::Null, depends_on: Vec::new(), optional: false, tags: Vec::new(), address: None }
+    }
+
+    pub fn parsed_address(&self) -> Result<Option<Uuid>, ConfigError> {
+        match &self.address {
+            Some(address) => Uuid::parse_str(address)
+                .map(Some)
+                .map_err(|err| ConfigError::InvalidEntry(format!("invalid device config: address {} is not a UUID: {}", address, err))),
+            None => Ok(None)
+        }
     }
 
     pub fn validate(&self) -> Result<(), ConfigError> {
@@ -144,30 +416,139 @@ impl DeviceConfig {
             return Err(ConfigError::InvalidEntry("invalid device config: driver name cannot be empty".to_string()));
         }
 
+        if self.depends_on.iter().any(|x| x.trim().is_empty()) {
+            return Err(ConfigError::InvalidEntry("invalid device config: dependency names cannot be empty".to_string()));
+        }
+
+        if self.tags.iter().any(|x| x.trim().is_empty()) {
+            return Err(ConfigError::InvalidEntry("invalid device config: tags cannot be empty".to_string()));
+        }
+
+        self.parsed_address()?;
         Ok(())
     }
+
+    pub fn validate_driver_data(&self) -> Result<(), ConfigError> {
+        crate::drivers::validate_driver_data(&self.driver, &self.driver_data)
+            .map_err(|err| ConfigError::SerializeError(format!("invalid {} driver data: {}", self.driver, err)))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigSectionDevices {
-    pub devices: Vec<DeviceConfig>
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default = "default_optional_retry_interval_ms")]
+    pub optional_retry_interval_ms: u64,
+    #[serde(default = "default_device_lock_timeout_ms")]
+    pub device_lock_timeout_ms: u64,
+    // restarts a device whose lock is held past the timeout once it's released, see DeviceServer::restart_when_released
+    #[serde(default)]
+    pub recover_stuck_locks: bool,
+    // reads every sensor once after the devices are registered and logs the results, see self_test::run_self_test
+    #[serde(default)]
+    pub startup_self_test: bool,
+    // hardware errors in a row before a device is reported unhealthy, a read that goes through resets the count
+    #[serde(default = "default_unhealthy_error_threshold")]
+    pub unhealthy_error_threshold: u64
+}
+
+fn default_optional_retry_interval_ms() -> u64 {
+    5000
+}
+
+fn default_device_lock_timeout_ms() -> u64 {
+    crate::supervisor::DEFAULT_DEVICE_LOCK_TIMEOUT_MS
+}
+
+fn default_unhealthy_error_threshold() -> u64 {
+    crate::events::DEFAULT_UNHEALTHY_ERROR_THRESHOLD
+}
+
+impl Default for ConfigSectionDevices {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
 }
 
 impl ConfigSectionDevices {
     pub fn new(devices: Vec<DeviceConfig>) -> Self {
-        Self { devices }
+        Self {
+            devices,
+            optional_retry_interval_ms: default_optional_retry_interval_ms(),
+            device_lock_timeout_ms: default_device_lock_timeout_ms(),
+            recover_stuck_locks: false,
+            startup_self_test: false,
+            unhealthy_error_threshold: default_unhealthy_error_threshold()
+        }
     }
 
     pub fn validate(&self) -> Result<(), ConfigError> {
-        for device in &self.devices {
-            device.validate()?;
+        if self.optional_retry_interval_ms == 0 {
+            return Err(ConfigError::InvalidEntry("optional device retry interval must be greater than zero".to_string()));
+        }
+
+        if self.device_lock_timeout_ms == 0 {
+            return Err(ConfigError::InvalidEntry("device lock timeout must be greater than zero".to_string()));
+        }
+
+        if self.unhealthy_error_threshold == 0 {
+            return Err(ConfigError::InvalidEntry("unhealthy error threshold must be greater than zero".to_string()));
+        }
+
+        let mut errors = Vec::new();
+        for (index, device) in self.devices.iter().enumerate() {
+            if let Err(err) = device.validate().and_then(|_| device.validate_driver_data()) {
+                errors.push(format!("device {}: {}", index, err));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(ConfigError::InvalidEntry(
+                format!("{} device config(s) failed validation: {}", errors.len(), errors.join("; "))
+            ));
+        }
+
+        self.validate_unique()
+    }
+
+    // Names and addresses both identify a device, registration would fail halfway through startup on a duplicate
+    fn validate_unique(&self) -> Result<(), ConfigError> {
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        let mut addresses: HashMap<Uuid, usize> = HashMap::new();
+        let mut errors = Vec::new();
+
+        for (index, device) in self.devices.iter().enumerate() {
+            if let Some(name) = &device.friendly_name {
+                match names.get(name.as_str()) {
+                    Some(first) => errors.push(format!("devices {} and {} have the same name \"{}\"", first, index, name)),
+                    None => {
+                        names.insert(name, index);
+                    }
+                }
+            }
+
+            // already checked in DeviceConfig::validate
+            if let Ok(Some(address)) = device.parsed_address() {
+                match addresses.get(&address) {
+                    Some(first) => errors.push(format!("devices {} and {} have the same address {}", first, index, address)),
+                    None => {
+                        addresses.insert(address, index);
+                    }
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(ConfigError::InvalidEntry(
+                format!("duplicate device config(s): {}", errors.join("; "))
+            ));
         }
 
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BusControllerConfig {
     pub name: String,
     pub data: Value
@@ -191,7 +572,7 @@ impl BusControllerConfig {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct ConfigSectionControllers {
     pub controllers: Vec<BusControllerConfig>   
 }
@@ -219,8 +600,107 @@ impl ConfigSectionControllers {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+// Bus controllers and devices of a unit, shaped like the same sections of the config file so an export can be
+// restored on this unit or cloned to another one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Topology {
+    pub controller_section: ConfigSectionControllers,
+    pub device_section: ConfigSectionDevices
+}
+
+impl Topology {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.controller_section.validate()?;
+        self.device_section.validate()?;
+        Ok(())
+    }
+
+    pub fn from_str(json_str: &str) -> Result<Topology, ConfigError> {
+        let topology: Topology = serde_json::from_str(json_str)
+            .map_err(|e| ConfigError::SerializeError(format!("failed to deserialize topology: {}", e)))?;
+
+        topology.validate()?;
+        Ok(topology)
+    }
+
+    pub fn to_str(&self, pretty: bool) -> Result<String, ConfigError> {
+        let result = match pretty {
+            true => serde_json::to_string_pretty(self),
+            false => serde_json::to_string(self)
+        };
+
+        result.map_err(|e| ConfigError::SerializeError(format!("failed to serialize topology: {}", e)))
+    }
+}
+
+const REDACTED_VALUE: &str = "<redacted>";
+const SENSITIVE_KEYS: [&str; 4] = ["token", "tls_key_path", "password", "secret"];
+
+// Replaces sensitive values anywhere in the tree, keys and nesting are left untouched
+pub fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SENSITIVE_KEYS.contains(&key.as_str()) && !value.is_null() {
+                    *value = Value::String(REDACTED_VALUE.to_string());
+                } else {
+                    redact_value(value);
+                }
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+// Upgrades a parsed config file to CONFIG_VERSION in place, returns the version it started at
+pub fn migrate(value: &mut Value) -> Result<u32, ConfigError> {
+    let config = match value.as_object_mut() {
+        Some(c) => c,
+        None => return Err(ConfigError::SerializeError("config file is not a JSON object".to_string()))
+    };
+
+    // files written before versioning was added have no version field
+    let version = match config.get("version") {
+        None => 0,
+        Some(v) => match v.as_u64() {
+            Some(v) => v as u32,
+            None => return Err(ConfigError::InvalidEntry(format!("invalid config version: {}", v)))
+        }
+    };
+
+    if version > CONFIG_VERSION {
+        return Err(ConfigError::InvalidEntry(format!(
+            "config version {} is newer than the supported version {}", version, CONFIG_VERSION
+        )));
+    }
+
+    if version < 1 {
+        let defaults = serde_json::to_value(Configuration::default())
+            .map_err(|e| ConfigError::SerializeError(format!("failed to serialize default config: {}", e)))?;
+
+        for (section, default) in defaults.as_object().unwrap() {
+            if !config.contains_key(section) {
+                config.insert(section.to_string(), default.clone());
+            }
+        }
+    }
+
+    config.insert("version".to_string(), Value::from(CONFIG_VERSION));
+    Ok(version)
+}
+
+fn default_backup_count() -> usize {
+    3
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Configuration {
+    #[serde(default)]
+    pub version: u32,
+    // Copies of the previous file kept on every save (.bak.1 is the newest), 0 turns backups off
+    #[serde(default = "default_backup_count")]
+    pub backup_count: usize,
     pub rpc_section: ConfigSectionRPC,
     pub adb_section: ConfigSectionADB,
     pub gpio_section: ConfigSectionGPIO,
@@ -228,7 +708,33 @@ pub struct Configuration {
     pub controller_section: ConfigSectionControllers
 }
 
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            backup_count: default_backup_count(),
+            rpc_section: ConfigSectionRPC::default(),
+            adb_section: ConfigSectionADB::default(),
+            gpio_section: ConfigSectionGPIO::default(),
+            device_section: ConfigSectionDevices::default(),
+            controller_section: ConfigSectionControllers::default()
+        }
+    }
+}
+
 impl Configuration {
+    pub fn topology(&self) -> Topology {
+        Topology {
+            controller_section: self.controller_section.clone(),
+            device_section: self.device_section.clone()
+        }
+    }
+
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.controller_section = topology.controller_section;
+        self.device_section = topology.device_section;
+    }
+
     pub fn validate(&self) -> Result<(), ConfigError> {
         self.rpc_section.validate()?;
         self.adb_section.validate()?;
@@ -239,7 +745,22 @@ impl Configuration {
     }
 
     pub fn from_reader<R: Read>(reader: R) -> Result<Configuration, ConfigError> {
-        let config: Configuration = match serde_json::from_reader(reader) {
+        Self::from_reader_migrated(reader).map(|(config, _)| config)
+    }
+
+    // Also returns the version the file was written with, anything older than CONFIG_VERSION should be saved back
+    pub fn from_reader_migrated<R: Read>(reader: R) -> Result<(Configuration, u32), ConfigError> {
+        let mut value: Value = match serde_json::from_reader(reader) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ConfigError::SerializeError(
+                    format!("failed to deserialize config file: {}", e)
+                ));
+            }
+        };
+
+        let original_version = migrate(&mut value)?;
+        let config: Configuration = match serde_json::from_value(value) {
             Ok(c) => c,
             Err(e) => {
                 return Err(ConfigError::SerializeError(
@@ -249,7 +770,7 @@ impl Configuration {
         };
     
         config.validate()?;
-        Ok(config)
+        Ok((config, original_version))
     }
 
     pub fn from_str(json_str: String) -> Result<Configuration, ConfigError> {
@@ -272,6 +793,24 @@ impl Configuration {
         }
     }
 
+    pub fn to_redacted_value(&self) -> Result<Value, ConfigError> {
+        let mut value = serde_json::to_value(self)
+            .map_err(|e| ConfigError::SerializeError(format!("failed to serialize config: {}", e)))?;
+
+        redact_value(&mut value);
+        Ok(value)
+    }
+
+    pub fn to_redacted_str(&self, pretty: bool) -> Result<String, ConfigError> {
+        let value = self.to_redacted_value()?;
+        let result = match pretty {
+            true => serde_json::to_string_pretty(&value),
+            false => serde_json::to_string(&value)
+        };
+
+        result.map_err(|e| ConfigError::SerializeError(format!("failed to serialize config: {}", e)))
+    }
+
     pub fn to_str(&self, pretty: bool) -> Result<String, ConfigError> {
         let result;
         if pretty {
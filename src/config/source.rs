@@ -0,0 +1,200 @@
+use super::{migrate, ConfigError, Configuration};
+use log::{debug, warn};
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::PathBuf,
+    time::Duration,
+};
+
+pub const ENV_PREFIX: &str = "NVOS_";
+// Single underscores are part of field names, nesting uses a double one
+const ENV_PATH_SEPARATOR: &str = "__";
+const DEFAULT_URL_TIMEOUT: Duration = Duration::from_secs(5);
+// Far more than any config needs, a larger response is a misconfigured or hostile server
+pub const MAX_URL_CONFIG_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigOrigin {
+    Url(String),
+    File(PathBuf),
+    // there was no file yet, the built in defaults were used
+    Default
+}
+
+pub struct LoadedConfig {
+    pub config: Configuration,
+    pub origin: ConfigOrigin,
+    // version the source was written with before migration
+    pub version: u32,
+    // env vars that were applied on top of the source
+    pub overrides: Vec<String>
+}
+
+impl LoadedConfig {
+    // Layered configs differ from the file on disk, they shouldn't be written back over it
+    pub fn is_layered(&self) -> bool {
+        matches!(self.origin, ConfigOrigin::Url(_)) || !self.overrides.is_empty()
+    }
+}
+
+// Loads the config from a URL if one is set, falling back to the file, then overlays NVOS_ env vars
+pub struct ConfigLoader {
+    path: PathBuf,
+    url: Option<String>,
+    env: Vec<(String, String)>,
+    url_timeout: Duration
+}
+
+impl ConfigLoader {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            url: None,
+            env: Vec::new(),
+            url_timeout: DEFAULT_URL_TIMEOUT
+        }
+    }
+
+    pub fn with_url(mut self, url: Option<String>) -> Self {
+        self.url = url;
+        self
+    }
+
+    // Variables without the NVOS_ prefix are dropped here
+    pub fn with_env<I: IntoIterator<Item = (String, String)>>(mut self, vars: I) -> Self {
+        self.env = vars.into_iter().filter(|(key, _)| key.starts_with(ENV_PREFIX)).collect();
+        self
+    }
+
+    pub fn with_url_timeout(mut self, timeout: Duration) -> Self {
+        self.url_timeout = timeout;
+        self
+    }
+
+    pub fn load(&self) -> Result<LoadedConfig, ConfigError> {
+        let (mut value, origin) = self.load_base()?;
+        let version = migrate(&mut value)?;
+        let overrides = apply_env_overrides(&mut value, self.env.iter().cloned())?;
+
+        let config: Configuration = serde_json::from_value(value)
+            .map_err(|e| ConfigError::SerializeError(format!("failed to deserialize config: {}", e)))?;
+
+        config.validate()?;
+        Ok(LoadedConfig { config, origin, version, overrides })
+    }
+
+    fn load_base(&self) -> Result<(Value, ConfigOrigin), ConfigError> {
+        if let Some(url) = &self.url {
+            match fetch_url(url, self.url_timeout).and_then(|body| parse_json(body.as_bytes(), "config URL response")) {
+                Ok(value) => return Ok((value, ConfigOrigin::Url(url.clone()))),
+                Err(e) => warn!("Failed to fetch config from {}, falling back to {}: {}", url, self.path.display(), e)
+            }
+        }
+
+        if !self.path.exists() {
+            let value = serde_json::to_value(Configuration::default())
+                .map_err(|e| ConfigError::SerializeError(format!("failed to serialize default config: {}", e)))?;
+
+            return Ok((value, ConfigOrigin::Default));
+        }
+
+        let file = File::open(&self.path)
+            .map_err(|e| ConfigError::Other(format!("failed to read config file: {}", e)))?;
+
+        Ok((parse_json(BufReader::new(file), "config file")?, ConfigOrigin::File(self.path.clone())))
+    }
+}
+
+fn parse_json<R: Read>(reader: R, description: &str) -> Result<Value, ConfigError> {
+    serde_json::from_reader(reader)
+        .map_err(|e| ConfigError::SerializeError(format!("failed to deserialize {}: {}", description, e)))
+}
+
+// NVOS_RPC_SECTION__SERVER_PORT=9000 sets rpc_section.server_port, array items are addressed by index.
+// Only fields that exist in the config are overridden, values are parsed as JSON unless the field holds a string.
+// Returns the variables that were applied.
+pub fn apply_env_overrides<I: IntoIterator<Item = (String, String)>>(value: &mut Value, vars: I) -> Result<Vec<String>, ConfigError> {
+    // sorted so a whole section is replaced before any of its fields
+    let mut vars: Vec<(String, String)> = vars.into_iter().collect();
+    vars.sort();
+
+    let mut applied = Vec::new();
+    for (key, raw) in vars {
+        let path = match key.strip_prefix(ENV_PREFIX) {
+            Some(p) => p.to_lowercase(),
+            None => continue
+        };
+
+        let segments: Vec<&str> = path.split(ENV_PATH_SEPARATOR).collect();
+        if segments.iter().any(|x| x.is_empty()) {
+            continue;
+        }
+
+        let target = match lookup_mut(value, &segments) {
+            Some(t) => t,
+            None => {
+                debug!("Ignoring environment variable {}, it does not match a config field", key);
+                continue;
+            }
+        };
+
+        *target = if target.is_string() {
+            Value::String(raw)
+        } else {
+            match serde_json::from_str(&raw) {
+                Ok(v) => v,
+                // unset optional fields take plain strings as well
+                Err(_) if target.is_null() => Value::String(raw),
+                Err(e) => return Err(ConfigError::InvalidEntry(format!(
+                    "environment variable {} is not valid JSON for {}: {}",
+                    key, segments.join("."), e
+                )))
+            }
+        };
+
+        applied.push(key);
+    }
+
+    Ok(applied)
+}
+
+fn lookup_mut<'a>(value: &'a mut Value, segments: &[&str]) -> Option<&'a mut Value> {
+    segments.iter().try_fold(value, |current, segment| match current {
+        Value::Object(map) => map.get_mut(*segment),
+        Value::Array(items) => segment.parse::<usize>().ok().and_then(move |i| items.get_mut(i)),
+        _ => None
+    })
+}
+
+// Fetches the config over HTTP or HTTPS from a provisioning server. The timeout covers the whole request, body
+// included, so a server that trickles its response can't hold up the boot.
+pub fn fetch_url(url: &str, timeout: Duration) -> Result<String, ConfigError> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(ConfigError::InvalidEntry(format!("only http:// and https:// config URLs are supported: {}", url)));
+    }
+
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let response = match agent.get(url).set("Accept", "application/json").call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(code, response)) => {
+            return Err(ConfigError::Other(format!("{} answered with \"{} {}\"", url, code, response.status_text())))
+        }
+        Err(e) => return Err(ConfigError::Other(format!("failed to fetch {}: {}", url, e)))
+    };
+
+    // one byte past the limit is enough to tell the body is too large
+    let mut body = Vec::new();
+    response.into_reader()
+        .take(MAX_URL_CONFIG_BYTES + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| ConfigError::Other(format!("failed to fetch {}: {}", url, e)))?;
+
+    if body.len() as u64 > MAX_URL_CONFIG_BYTES {
+        return Err(ConfigError::Other(format!("config from {} is larger than {} bytes", url, MAX_URL_CONFIG_BYTES)));
+    }
+
+    String::from_utf8(body)
+        .map_err(|e| ConfigError::SerializeError(format!("response from {} is not UTF-8: {}", url, e)))
+}
@@ -0,0 +1,170 @@
+use super::{ConfigError, Configuration, DeviceConfig, Topology};
+use log::{info, warn};
+use parking_lot::{Mutex, MutexGuard};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+// JSON merge patch (RFC 7396), objects are merged field by field and null removes a field
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(p) => p,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        match value {
+            Value::Null => {
+                target.remove(key);
+            }
+            _ => merge_patch(target.entry(key.clone()).or_insert(Value::Null), value)
+        }
+    }
+}
+
+pub fn backup_path(path: &Path, generation: usize) -> PathBuf {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(format!(".bak.{}", generation));
+    PathBuf::from(backup_path)
+}
+
+// Shifts .bak.1 -> .bak.2 and so on, copies the file to .bak.1 and prunes anything past count
+pub fn rotate_backups(path: &Path, count: usize) {
+    // left over from a larger count
+    let mut generation = count + 1;
+    while backup_path(path, generation).exists() {
+        if let Err(err) = fs::remove_file(backup_path(path, generation)) {
+            warn!("Failed to remove old config backup: {}", err);
+            break;
+        }
+
+        generation += 1;
+    }
+
+    if count == 0 || !path.exists() {
+        return;
+    }
+
+    for generation in (1..count).rev() {
+        let from = backup_path(path, generation);
+        if from.exists() {
+            if let Err(err) = fs::rename(&from, backup_path(path, generation + 1)) {
+                warn!("Failed to rotate config backup {}: {}", from.display(), err);
+            }
+        }
+    }
+
+    let newest = backup_path(path, 1);
+    match fs::copy(path, &newest) {
+        Ok(_) => info!("Backed up config file to {}", newest.display()),
+        Err(err) => warn!("Failed to backup config file: {}", err),
+    }
+}
+
+// Writes the config to path, previous files are kept next to it as rotated backups
+pub fn write_config_file(config: &Configuration, path: &Path) -> Result<(), ConfigError> {
+    rotate_backups(path, config.backup_count);
+
+    let file = File::create(path)
+        .map_err(|e| ConfigError::Other(format!("failed to open config file for write: {}", e)))?;
+
+    config.to_writer(BufWriter::new(file), true)
+}
+
+// The running config, shared with the RPCs that change it.
+// Device entries are found by the address the device was registered under.
+pub struct ConfigStore {
+    config: Mutex<Configuration>,
+    // None when the config is layered, changes then only last until the next restart
+    path: Option<PathBuf>,
+    device_entries: Mutex<HashMap<Uuid, usize>>,
+    // held across a read-modify-write of the config, see lock_updates
+    updates: tokio::sync::Mutex<()>
+}
+
+impl ConfigStore {
+    pub fn new(config: Configuration, path: Option<PathBuf>) -> Self {
+        Self {
+            config: Mutex::new(config),
+            path,
+            device_entries: Mutex::new(HashMap::new()),
+            updates: tokio::sync::Mutex::new(())
+        }
+    }
+
+    // Serializes updates that read the config, work on it and write it back, so one can't undo another's change.
+    // Held across awaits, config() is only locked for the individual reads and writes in between.
+    pub async fn lock_updates(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.updates.lock().await
+    }
+
+    pub fn config(&self) -> MutexGuard<'_, Configuration> {
+        self.config.lock()
+    }
+
+    // index is the device's position in device_section.devices
+    pub fn bind_device(&self, address: Uuid, index: usize) {
+        self.device_entries.lock().insert(address, index);
+    }
+
+    pub fn device_config(&self, address: &Uuid) -> Option<DeviceConfig> {
+        let index = *self.device_entries.lock().get(address)?;
+        self.config.lock().device_section.devices.get(index).cloned()
+    }
+
+    // Devices that were registered get the address they're running under, so a restore keeps it
+    pub fn export_topology(&self) -> Topology {
+        let mut topology = self.config.lock().topology();
+        for (address, index) in self.device_entries.lock().iter() {
+            if let Some(device) = topology.device_section.devices.get_mut(*index) {
+                device.address.get_or_insert_with(|| address.to_string());
+            }
+        }
+
+        topology
+    }
+
+    // Swaps in a new topology and saves the config file, entries are the registered devices and their positions
+    pub fn set_topology(&self, topology: Topology, entries: &[(Uuid, usize)]) -> Result<(), ConfigError> {
+        let mut config = self.config.lock();
+        config.set_topology(topology);
+        *self.device_entries.lock() = entries.iter().copied().collect();
+        match &self.path {
+            Some(path) => write_config_file(&config, path),
+            None => Ok(())
+        }
+    }
+
+    // Replaces a device's driver data and saves the config file
+    pub fn set_driver_data(&self, address: &Uuid, driver_data: Value) -> Result<(), ConfigError> {
+        let index = match self.device_entries.lock().get(address) {
+            Some(index) => *index,
+            None => return Err(ConfigError::MissingEntry(format!("device {} has no config entry", address)))
+        };
+
+        let mut config = self.config.lock();
+        let entry = match config.device_section.devices.get_mut(index) {
+            Some(entry) => entry,
+            None => return Err(ConfigError::MissingEntry(format!("device {} has no config entry", address)))
+        };
+
+        entry.driver_data = driver_data;
+        match &self.path {
+            Some(path) => write_config_file(&config, path),
+            None => Ok(())
+        }
+    }
+}
@@ -1,16 +1,22 @@
 use intertrait::CastFromSync;
 use intertrait::cast::{CastRef, CastMut};
-use log::warn;
+use log::{debug, info, warn};
 use uuid::Uuid;
-use crate::bus::BusController;
+use crate::bus::{BusController, GpioController};
 use crate::capabilities::{Capability, CapabilityId, get_device_capabilities};
 use crate::config::DeviceConfig;
+use crate::events::{DeviceEventKind, DeviceEventSink, DeviceEvents};
+use crate::supervisor::{LockMetrics, LockWaitStats, DEFAULT_DEVICE_LOCK_TIMEOUT_MS};
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 use unbox_box::BoxExt;
-use parking_lot::{RwLock, RwLockReadGuard, MappedRwLockReadGuard, RwLockWriteGuard, MappedRwLockWriteGuard};
+use serde_json::Value;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, MappedRwLockReadGuard, RwLockWriteGuard, MappedRwLockWriteGuard};
+
+const DEFAULT_BUS_LOCK_TIMEOUT: Duration = Duration::from_millis(250);
 
 fn assert_controller_locked(controller: &Arc<parking_lot::lock_api::RwLock<parking_lot::RawRwLock, dyn BusController>>) -> bool {
     if controller.is_locked_exclusive() {
@@ -30,19 +36,89 @@ pub trait DeviceDriver : CastFromSync  {
     fn stop(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError>;
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    // Lets a driver hide a capability it implements but can't currently serve
+    fn supports_capability(&self, _capability: CapabilityId) -> bool {
+        true
+    }
+
+    // Driver data that changed while running (e.g. a detected setting), written back to the config file
+    fn updated_driver_data(&self) -> Option<Value> {
+        None
+    }
+
+    // Takes a changed config without a stop/start, the caller validates it with a fresh driver first.
+    // NotSupported leaves the driver as it is, the change then applies on the next restart.
+    fn apply_config(&mut self, _config: &DeviceConfig) -> Result<(), DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+
+    // Bus controllers (by config name) that start() needs, checked before the device is started
+    fn required_controllers(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    // Handed over before every start, for drivers that raise events of their own
+    fn set_event_sink(&mut self, _events: DeviceEventSink) {}
+}
+
+// Plain copy of a device's identity and state, taken by DeviceServer::snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceSnapshot {
+    pub address: Uuid,
+    pub name: String,
+    pub driver_name: String,
+    pub capabilities: Vec<CapabilityId>,
+    pub is_running: bool,
+    pub is_healthy: bool,
+    pub tags: Vec<String>
+}
+
+// What snapshots report about a device, kept by the server so enumerating never waits on a device lock
+#[derive(Debug, Clone)]
+struct DeviceInfo {
+    name: String,
+    driver_name: String,
+    capabilities: Vec<CapabilityId>,
+    is_running: bool,
+    tags: Vec<String>
+}
+
+impl DeviceInfo {
+    fn of(device: &Device) -> Self {
+        Self {
+            name: device.device_name(),
+            driver_name: device.driver_name(),
+            capabilities: device.get_capabilities(),
+            is_running: device.is_running(),
+            tags: device.tags.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceOrder {
+    #[default]
+    Registration,
+    Name
 }
 
 pub struct Device {
     address: Uuid,
     name: String,
     driver: Box<dyn DeviceDriver>,
-    capabilities: Vec<CapabilityId>
+    capabilities: Vec<CapabilityId>,
+    depends_on: Vec<String>,
+    optional: bool,
+    tags: Vec<String>,
+    // handed over on registration, health is tracked by the server's events
+    events: Option<DeviceEventSink>
 }
 
 impl Device {
     pub fn from_driver(driver: Box<dyn DeviceDriver>, address: Option<Uuid>, friendly_name: Option<String>) -> Result<Self, DeviceError> {
         if friendly_name.as_ref().is_some_and(|x| x.is_empty()) {
-            return Err(DeviceError::InvalidConfig("invalid device name".to_string()))
+            return Err(DeviceError::invalid_config("invalid device name"))
         }
 
         let address = address.unwrap_or(Uuid::new_v4());
@@ -53,13 +129,20 @@ impl Device {
             address: address, 
             name: name, 
             driver: driver,
-            capabilities: cap_data
+            capabilities: cap_data,
+            depends_on: Vec::new(),
+            optional: false,
+            tags: Vec::new(),
+            events: None
         })
     }
 
     pub fn from_config<T: DeviceDriver>(config: &mut DeviceConfig, address: Option<Uuid>) -> Result<Self, DeviceError> {
         let driver: Box<dyn DeviceDriver> = Box::new(T::new(Some(config))?) as Box<dyn DeviceDriver>;
-        Self::from_driver(driver, address, config.friendly_name.clone())
+        Ok(Self::from_driver(driver, address, config.friendly_name.clone())?
+            .with_dependencies(config.depends_on.clone())
+            .with_optional(config.optional)
+            .with_tags(config.tags.clone()))
     }
 
     pub fn new<T: DeviceDriver>(address: Option<Uuid>, friendly_name: Option<String>) -> Result<Self, DeviceError> {
@@ -67,6 +150,39 @@ impl Device {
         Self::from_driver(driver, address, friendly_name)
     }
 
+    // Bus controller or device names that have to be available before this device is started
+    pub fn with_dependencies(mut self, depends_on: Vec<String>) -> Self {
+        self.depends_on = depends_on;
+        self
+    }
+
+    pub fn dependencies(&self) -> &[String] {
+        &self.depends_on
+    }
+
+    // Optional devices stay registered when they fail to start, see DeviceServer::retry_optional_devices
+    pub fn with_optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    pub fn is_optional(&self) -> bool {
+        self.optional
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|x| x == tag)
+    }
+
     pub fn address(&self) -> Uuid {
         self.address
     }
@@ -83,6 +199,12 @@ impl Device {
         self.driver.is_running()
     }
 
+    // Whether recent reads and the last self-test went fine, see DeviceEvents::is_healthy.
+    // Says nothing about whether the device is running, devices that were never registered count as healthy.
+    pub fn is_healthy(&self) -> bool {
+        self.events.as_ref().map_or(true, |x| x.is_healthy())
+    }
+
     pub fn as_any(&self) -> &dyn Any {
         self.driver.as_any()
     }
@@ -112,22 +234,79 @@ impl Device {
     pub fn get_capabilities(&self) -> Vec<CapabilityId> {
         self.capabilities.clone()
     }
+
+    pub fn refresh_capabilities(&mut self) {
+        self.capabilities = get_device_capabilities(self.driver.unbox_ref());
+    }
 }
 
-#[derive(Debug, PartialEq)]
+// Underlying error kept for debugging, shared so DeviceError stays cheap to clone
+pub type ErrorSource = Arc<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone)]
 pub enum DeviceError {
     NotFound(Uuid), 
     MissingController(String),
     DuplicateController,
     DuplicateDevice(String),
-    HardwareError(String),
+    HardwareError(String, Option<ErrorSource>),
     InvalidOperation(String),
-    InvalidConfig(String),
+    InvalidConfig(String, Option<ErrorSource>),
     NotSupported,
+    LockTimeout(String),
     Internal,
     Other(String)
 }
 
+impl DeviceError {
+    pub fn hardware<E: std::error::Error + Send + Sync + 'static>(desc: impl Into<String>, source: E) -> Self {
+        DeviceError::HardwareError(desc.into(), Some(Arc::new(source)))
+    }
+
+    // For hardware errors that have no underlying error to keep
+    pub fn hardware_error(desc: impl Into<String>) -> Self {
+        DeviceError::HardwareError(desc.into(), None)
+    }
+
+    pub fn invalid_config(desc: impl Into<String>) -> Self {
+        DeviceError::InvalidConfig(desc.into(), None)
+    }
+
+    pub fn invalid_config_from<E: std::error::Error + Send + Sync + 'static>(desc: impl Into<String>, source: E) -> Self {
+        DeviceError::InvalidConfig(desc.into(), Some(Arc::new(source)))
+    }
+}
+
+// Sources are only context, errors compare equal when they describe the same failure
+impl PartialEq for DeviceError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (DeviceError::NotFound(a), DeviceError::NotFound(b)) => a == b,
+            (DeviceError::MissingController(a), DeviceError::MissingController(b)) => a == b,
+            (DeviceError::DuplicateController, DeviceError::DuplicateController) => true,
+            (DeviceError::DuplicateDevice(a), DeviceError::DuplicateDevice(b)) => a == b,
+            (DeviceError::HardwareError(a, _), DeviceError::HardwareError(b, _)) => a == b,
+            (DeviceError::InvalidOperation(a), DeviceError::InvalidOperation(b)) => a == b,
+            (DeviceError::InvalidConfig(a, _), DeviceError::InvalidConfig(b, _)) => a == b,
+            (DeviceError::NotSupported, DeviceError::NotSupported) => true,
+            (DeviceError::LockTimeout(a), DeviceError::LockTimeout(b)) => a == b,
+            (DeviceError::Internal, DeviceError::Internal) => true,
+            (DeviceError::Other(a), DeviceError::Other(b)) => a == b,
+            _ => false
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeviceError::HardwareError(_, Some(source)) => Some(source.as_ref()),
+            DeviceError::InvalidConfig(_, Some(source)) => Some(source.as_ref()),
+            _ => None
+        }
+    }
+}
+
 impl Display for DeviceError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&match self {
@@ -135,23 +314,134 @@ impl Display for DeviceError {
             DeviceError::MissingController(name) => format!("bus controller \"{}\" was unavailable", name),
             DeviceError::DuplicateController => format!("bus controller of the same type is already registered"),
             DeviceError::DuplicateDevice(desc) => format!("duplicate device: {}", desc),
-            DeviceError::HardwareError(desc) => format!("a hardware error has occurred: {}", desc),
+            DeviceError::HardwareError(desc, _) => format!("a hardware error has occurred: {}", desc),
             DeviceError::InvalidOperation(desc) => format!("invalid operation: {}", desc),
-            DeviceError::InvalidConfig(desc) => format!("invalid config: {}", desc),
+            DeviceError::InvalidConfig(desc, _) => format!("invalid config: {}", desc),
             DeviceError::NotSupported => format!("operation is not supported"),
+            DeviceError::LockTimeout(desc) => format!("timed out waiting for {} to be released", desc),
             DeviceError::Internal => format!("internal error"),
             DeviceError::Other(desc) => format!("an unknown error has occurred: {}", desc)
         })
     }
 }
 
+// Reference from one device to another on the same server, for drivers that build on another device's readings.
+// Weak so a removed device isn't kept alive by the devices linking to it.
+#[derive(Clone)]
+pub struct DeviceLink {
+    name: String,
+    device: Weak<RwLock<Device>>,
+    timeout: Duration
+}
+
+impl DeviceLink {
+    pub fn new(name: &str, device: &Arc<RwLock<Device>>, timeout: Duration) -> Self {
+        Self { name: name.to_owned(), device: Arc::downgrade(device), timeout }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn upgrade(&self) -> Result<Arc<RwLock<Device>>, DeviceError> {
+        match self.device.upgrade() {
+            Some(device) => Ok(device),
+            None => Err(DeviceError::InvalidOperation(format!("linked device \"{}\" was removed", self.name)))
+        }
+    }
+
+    // Locks the linked device and runs the call against one of its capabilities
+    pub fn with_capability<T: Capability + 'static + ?Sized, R>(
+        &self,
+        call: impl FnOnce(&T) -> Result<R, DeviceError>
+    ) -> Result<R, DeviceError> {
+        let device = self.upgrade()?;
+        let device = match device.try_read_for(self.timeout) {
+            Some(device) => device,
+            None => return Err(DeviceError::LockTimeout(format!("linked device \"{}\"", self.name)))
+        };
+
+        if !device.is_running() {
+            return Err(DeviceError::InvalidOperation(format!("linked device \"{}\" is not running", self.name)));
+        }
+
+        match device.as_capability_ref::<T>() {
+            Some(capability) => call(capability),
+            None => Err(DeviceError::NotSupported)
+        }
+    }
+
+    pub fn with_capability_mut<T: Capability + 'static + ?Sized, R>(
+        &self,
+        call: impl FnOnce(&mut T) -> Result<R, DeviceError>
+    ) -> Result<R, DeviceError> {
+        let device = self.upgrade()?;
+        let mut device = match device.try_write_for(self.timeout) {
+            Some(device) => device,
+            None => return Err(DeviceError::LockTimeout(format!("linked device \"{}\"", self.name)))
+        };
+
+        if !device.is_running() {
+            return Err(DeviceError::InvalidOperation(format!("linked device \"{}\" is not running", self.name)));
+        }
+
+        match device.as_capability_mut::<T>() {
+            Some(capability) => call(capability),
+            None => Err(DeviceError::NotSupported)
+        }
+    }
+}
+
+// A bus controller handle kept as both a trait object and as Any, the two Arcs share one allocation.
+// Arc<dyn Any>::downcast compares TypeIds before handing back an Arc<RwLock<T>>, so typed access never
+// has to reinterpret a trait object pointer as a concrete one.
+pub struct BusEntry {
+    controller: Arc<RwLock<dyn BusController>>,
+    typed: Arc<dyn Any + Send + Sync>
+}
+
+impl BusEntry {
+    pub fn new<T: BusController>(bus: Arc<RwLock<T>>) -> Self {
+        BusEntry { controller: bus.clone(), typed: bus }
+    }
+}
+
+impl<T: BusController> From<Arc<RwLock<T>>> for BusEntry {
+    fn from(bus: Arc<RwLock<T>>) -> Self {
+        BusEntry::new(bus)
+    }
+}
+
+// Start order indices, and the devices left out of it with the dependency that couldn't be found
+pub type PartialStartOrder = (Vec<usize>, Vec<(usize, String)>);
+
 pub struct DeviceServer {
     bus_controllers: Vec<Arc<RwLock<dyn BusController>>>,
-    devices: HashMap<Uuid, Device>
+    // same controllers as bus_controllers, in the same order, used by get_bus_ptr
+    bus_handles: Vec<Arc<dyn Any + Send + Sync>>,
+    // names are cached so a controller can be reported while someone else holds its lock
+    bus_names: Vec<String>,
+    bus_lock_timeout: Duration,
+    // each device has its own lock so a slow device doesn't hold up the rest of the server
+    devices: HashMap<Uuid, Arc<RwLock<Device>>>,
+    // registration order, the map alone would enumerate in a different order on every run
+    device_order: Vec<Uuid>,
+    name_index: HashMap<String, Uuid>,
+    // updated whenever the server starts, stops, renames or removes a device, read by snapshot
+    device_info: Arc<Mutex<HashMap<Uuid, DeviceInfo>>>,
+    events: DeviceEvents,
+    device_lock_timeout: Duration,
+    recover_stuck_locks: bool,
+    // devices waiting on a stuck lock to be released before they're restarted
+    pending_restarts: Arc<Mutex<HashSet<Uuid>>>,
+    // optional devices that failed to start, a device that was stopped on purpose isn't in here
+    failed_starts: Arc<Mutex<HashSet<Uuid>>>,
+    lock_metrics: Arc<LockMetrics>
 }
 
 pub struct DeviceServerBuilder {
-    bus_controllers: Vec<Arc<RwLock<dyn BusController>>>,
+    bus_controllers: Vec<BusEntry>,
+    bus_lock_timeout: Duration,
     devices: Vec<Device>
 }
 
@@ -159,58 +449,225 @@ impl DeviceServerBuilder {
     pub fn configure() -> Self {
         DeviceServerBuilder { 
             bus_controllers: Vec::new(),
+            bus_lock_timeout: DEFAULT_BUS_LOCK_TIMEOUT,
             devices: Vec::new()
         }
     }
 
+    pub fn with_bus_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.bus_lock_timeout = timeout;
+        self
+    }
+
     pub fn add_device(mut self, device: Device) -> Self {
         self.devices.push(device);
         self
     }
 
     pub fn add_bus<T: BusController>(mut self, bus: T) -> Self {
-        self.bus_controllers.push(Arc::new(RwLock::new(bus)));
+        self.bus_controllers.push(BusEntry::new(Arc::new(RwLock::new(bus))));
         self
     }
 
     pub fn build(mut self, start_devices: bool) -> Result<DeviceServer, DeviceError> {
         let mut server = DeviceServer::new();
+        server.set_bus_lock_timeout(self.bus_lock_timeout);
 
         while let Some(bus) = self.bus_controllers.pop() {
             server.register_bus(bus)?;
         }
 
-        while let Some(device) = self.devices.pop() {
-            server.register_device(device, start_devices)?;
+        let order = server.resolve_start_order(&self.devices)?;
+        let mut devices: Vec<Option<Device>> = self.devices.into_iter().map(Some).collect();
+        for index in order {
+            if let Some(device) = devices[index].take() {
+                server.register_device(device, start_devices)?;
+            }
         }
 
         Ok(server)
     }
 }
 
+// Depth first walk that emits dependencies before their dependents, a cycle is returned as the looping path
+fn visit_dependencies(node: usize, edges: &[Vec<usize>], visited: &mut [bool], path: &mut Vec<usize>, order: &mut Vec<usize>) -> Result<(), Vec<usize>> {
+    if let Some(start) = path.iter().position(|x| *x == node) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(node);
+        return Err(cycle);
+    }
+
+    if visited[node] {
+        return Ok(());
+    }
+
+    path.push(node);
+    for dependency in &edges[node] {
+        visit_dependencies(*dependency, edges, visited, path, order)?;
+    }
+
+    path.pop();
+    visited[node] = true;
+    order.push(node);
+    Ok(())
+}
+
+// Background task that keeps retrying optional devices which weren't available when they were registered
+pub fn spawn_optional_device_retry(server: &Arc<RwLock<DeviceServer>>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    let server = server.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if !server.read().has_pending_optional_devices() {
+                continue;
+            }
+
+            // starting a device probes hardware, that stays off the async workers
+            let server = server.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || DeviceServer::retry_optional_devices(&server)).await {
+                warn!("Optional device retry failed: {}", e);
+            }
+        }
+    })
+}
+
 impl DeviceServer {
     pub fn new() -> Self {
         DeviceServer { 
             bus_controllers: Vec::new(),
-            devices: HashMap::new()
+            bus_handles: Vec::new(),
+            bus_names: Vec::new(),
+            bus_lock_timeout: DEFAULT_BUS_LOCK_TIMEOUT,
+            devices: HashMap::new(),
+            device_order: Vec::new(),
+            name_index: HashMap::new(),
+            device_info: Arc::new(Mutex::new(HashMap::new())),
+            events: DeviceEvents::new(),
+            device_lock_timeout: Duration::from_millis(DEFAULT_DEVICE_LOCK_TIMEOUT_MS),
+            recover_stuck_locks: false,
+            pending_restarts: Arc::new(Mutex::new(HashSet::new())),
+            failed_starts: Arc::new(Mutex::new(HashSet::new())),
+            lock_metrics: Arc::new(LockMetrics::new())
         }
     }
 
+    pub fn bus_count(&self) -> usize {
+        self.bus_controllers.len()
+    }
+
+    pub fn device_count(&self) -> usize {
+        self.device_order.len()
+    }
+
+    pub fn has_bus_named(&self, name: &str) -> bool {
+        self.bus_names.iter().any(|x| x.eq_ignore_ascii_case(name))
+    }
+
+    // Controllers the device's driver needs that aren't registered on this server
+    pub fn missing_controllers(&self, device: &Device) -> Vec<&'static str> {
+        device.as_ref().required_controllers()
+            .into_iter()
+            .filter(|name| !self.has_bus_named(name))
+            .collect()
+    }
+
+    fn provides_dependency(&self, name: &str) -> bool {
+        self.name_index.contains_key(name) || self.has_bus_named(name)
+    }
+
+    // Orders devices so each one comes after the devices it depends on, otherwise the given order is kept.
+    // Dependencies can also be bus controllers or devices that are already registered on this server.
+    pub fn resolve_start_order(&self, devices: &[Device]) -> Result<Vec<usize>, DeviceError> {
+        let (order, missing) = self.resolve_partial_start_order(devices)?;
+        match missing.first() {
+            Some((index, dependency)) => Err(DeviceError::invalid_config(format!(
+                "device \"{}\" depends on \"{}\", which is neither a bus controller nor a device",
+                devices[*index].device_name(), dependency
+            ))),
+            None => Ok(order)
+        }
+    }
+
+    // Like resolve_start_order, but a dependency that can't be found doesn't fail the whole order. It comes back with the
+    // index of the device that needs it, so the caller can skip that device and keep starting the rest.
+    pub fn resolve_partial_start_order(&self, devices: &[Device]) -> Result<PartialStartOrder, DeviceError> {
+        let index: HashMap<String, usize> = devices.iter()
+            .enumerate()
+            .map(|(i, device)| (device.device_name(), i))
+            .collect();
+
+        let mut edges: Vec<Vec<usize>> = Vec::with_capacity(devices.len());
+        let mut missing = Vec::new();
+        for (i, device) in devices.iter().enumerate() {
+            let mut deps = Vec::new();
+            for dependency in device.dependencies() {
+                match index.get(dependency) {
+                    Some(i) => deps.push(*i),
+                    None if self.provides_dependency(dependency) => {}
+                    None => missing.push((i, dependency.clone()))
+                }
+            }
+
+            edges.push(deps);
+        }
+
+        let mut visited = vec![false; devices.len()];
+        let mut path: Vec<usize> = Vec::new();
+        let mut order = Vec::with_capacity(devices.len());
+        for i in 0..devices.len() {
+            visit_dependencies(i, &edges, &mut visited, &mut path, &mut order)
+                .map_err(|cycle| DeviceError::invalid_config(format!(
+                    "device dependency cycle: {}",
+                    cycle.iter().map(|x| devices[*x].device_name()).collect::<Vec<String>>().join(" -> ")
+                )))?;
+        }
+
+        Ok((order, missing))
+    }
+
     pub fn register_device(&mut self, mut device: Device, start_device: bool) -> Result<Uuid, DeviceError> {
         if self.devices.contains_key(&device.address) {
             return Err(DeviceError::DuplicateDevice(format!("device with address {} already registered", device.address)));
         }
 
-        if self.devices.iter().any(|x| x.1.device_name() == device.device_name()) {
+        if self.name_index.contains_key(&device.name) {
             return Err(DeviceError::DuplicateDevice(format!("device with name {} already registered", device.device_name())));
         }
 
+        // fail before start so the error names the controller instead of whatever the driver hits first
+        if start_device {
+            if let Some(controller) = self.missing_controllers(&device).first() {
+                return Err(DeviceError::MissingController(controller.to_string()));
+            }
+        }
+
         let address = device.address();
+        device.events = Some(self.events.sink(address));
+        let mut started = false;
         if start_device && !device.as_ref().is_running() {
-            device.as_mut().start(self)?;    
+            device.as_mut().set_event_sink(self.events.sink(address));
+            match device.as_mut().start(self) {
+                Ok(_) => {
+                    device.refresh_capabilities();
+                    started = true;
+                }
+                Err(e) if device.optional => {
+                    warn!("Optional device {} is not available, it will be retried later: {}", device.device_name(), e);
+                    self.failed_starts.lock().insert(address);
+                }
+                Err(e) => return Err(e)
+            }
+        }
+
+        self.name_index.insert(device.device_name(), address);
+        self.device_info.lock().insert(address, DeviceInfo::of(&device));
+        self.devices.insert(address, Arc::new(RwLock::new(device)));
+        self.device_order.push(address);
+        self.events.emit(address, DeviceEventKind::DeviceRegistered);
+        if started {
+            self.events.emit(address, DeviceEventKind::DeviceStarted);
         }
 
-        self.devices.insert(address, device);
         // kept for compatibility
         Ok(address)
     }
@@ -220,65 +677,307 @@ impl DeviceServer {
             return Err(DeviceError::NotFound(address.to_owned()));
         }
 
-        let mut device = self.devices.remove(address).unwrap();
+        let device_ptr = self.devices.remove(address).unwrap();
+        let mut device = match device_ptr.try_write_for(self.device_lock_timeout) {
+            Some(device) => device,
+            None => {
+                self.devices.insert(address.to_owned(), device_ptr.clone());
+                return Err(DeviceError::LockTimeout(format!("device {}", address)));
+            }
+        };
+
         if device.is_running() {
             if let Err(e) = device.as_mut().stop(self) {
-                self.devices.insert(address.to_owned(), device);
+                drop(device);
+                self.devices.insert(address.to_owned(), device_ptr);
                 return Err(e);
             }
+
+            self.events.emit(*address, DeviceEventKind::DeviceStopped);
         }
         
+        self.name_index.remove(&device.name);
+        self.device_order.retain(|x| x != address);
+        self.device_info.lock().remove(address);
+        self.failed_starts.lock().remove(address);
+        self.events.clear_errors(address);
+        self.events.emit(*address, DeviceEventKind::DeviceRemoved);
+        Ok(())
+    }
+
+    // Removes every device, dependents first, then drops the bus controllers.
+    // Events and settings are kept so the server can be filled again.
+    pub fn clear(&mut self) -> Result<(), DeviceError> {
+        for address in self.device_order.clone().iter().rev() {
+            self.remove_device(address)?;
+        }
+
+        self.bus_controllers.clear();
+        self.bus_handles.clear();
+        self.bus_names.clear();
+        Ok(())
+    }
+
+    // Stops every device, dependents first, and only then has the bus controllers close whatever is left open,
+    // so pins and sysfs exports are freed even behind a device that failed to stop. Returns how many devices stopped.
+    pub fn shutdown(&mut self) -> usize {
+        let mut stopped = 0;
+        for address in self.device_order.clone().iter().rev() {
+            info!("Unloading device {}", address);
+            match self.remove_device(address) {
+                Ok(_) => stopped += 1,
+                Err(e) => warn!("Failed to gracefully shutdown device {}: {}", address, e)
+            }
+        }
+
+        for (controller, name) in self.bus_controllers.iter().zip(&self.bus_names).rev() {
+            info!("Releasing bus controller {}", name);
+            for err in controller.write().release_all() {
+                warn!("Bus controller {} failed to release {}", name, err);
+            }
+        }
+
+        self.bus_controllers.clear();
+        self.bus_handles.clear();
+        self.bus_names.clear();
+        stopped
+    }
+
+    pub fn rename_device(&mut self, address: &Uuid, name: &str) -> Result<(), DeviceError> {
+        let mut device = match self.devices.get(address) {
+            Some(device) => device.write(),
+            None => return Err(DeviceError::NotFound(address.to_owned()))
+        };
+
+        if name.is_empty() {
+            return Err(DeviceError::invalid_config("invalid device name"));
+        }
+
+        if device.name == name {
+            return Ok(());
+        }
+
+        if self.name_index.contains_key(name) {
+            return Err(DeviceError::DuplicateDevice(format!("device with name {} already registered", name)));
+        }
+
+        self.name_index.remove(&device.name);
+        self.name_index.insert(name.to_owned(), *address);
+        device.name = name.to_owned();
+        self.device_info.lock().insert(*address, DeviceInfo::of(&device));
         Ok(())
     }
 
     pub fn start_device(&mut self, address: &Uuid) -> Result<(), DeviceError> {
-        if let Some(device) = self.devices.get_mut(address) {
-            if device.is_running() {
+        if let Some(device) = self.devices.get(address) {
+            if device.read().is_running() {
                 return Err(DeviceError::InvalidOperation("device is already running".to_owned()));
             }
         } else {
             return Err(DeviceError::NotFound(address.to_owned()));
         }
     
-        let mut device = self.devices.remove(address).unwrap();
-        device.as_mut().start(self)?;
-        self.devices.insert(*address, device);
+        let device_ptr = self.devices.remove(address).unwrap();
+        let mut device = device_ptr.write();
+        device.as_mut().set_event_sink(self.events.sink(*address));
+        let result = device.as_mut().start(self);
+        device.refresh_capabilities();
+        self.device_info.lock().insert(*address, DeviceInfo::of(&device));
+        drop(device);
+        self.devices.insert(*address, device_ptr);
+        result?;
+
+        self.failed_starts.lock().remove(address);
+        self.events.emit(*address, DeviceEventKind::DeviceStarted);
         Ok(())
     }
 
+    // Tries to start every optional device that failed to start, returns the ones that came up.
+    // Each one is started against the server itself, write locked for one device at a time so other callers get in between.
+    pub fn retry_optional_devices(server: &Arc<RwLock<DeviceServer>>) -> Vec<Uuid> {
+        let pending: Vec<Uuid> = server.read().failed_starts.lock().iter().copied().collect();
+        let mut started = Vec::new();
+        for address in pending {
+            let mut server = server.write();
+            let device_ptr = match server.devices.get(&address) {
+                Some(device) => device.clone(),
+                None => continue
+            };
+
+            let mut device = match device_ptr.try_write_for(server.device_lock_timeout) {
+                Some(device) => device,
+                None => continue
+            };
+
+            if device.is_running() {
+                server.failed_starts.lock().remove(&address);
+                continue;
+            }
+
+            // out of the map while it starts, same as start_device
+            server.devices.remove(&address);
+            device.as_mut().set_event_sink(server.events.sink(address));
+            let result = device.as_mut().start(&mut server);
+            device.refresh_capabilities();
+            let info = DeviceInfo::of(&device);
+            drop(device);
+            server.devices.insert(address, device_ptr);
+            if let Err(e) = result {
+                debug!("Optional device {} is still unavailable: {}", address, e);
+                continue;
+            }
+
+            info!("Optional device {} is now available", address);
+            server.device_info.lock().insert(address, info);
+            server.failed_starts.lock().remove(&address);
+            server.events.emit(address, DeviceEventKind::DeviceStarted);
+            started.push(address);
+        }
+
+        started
+    }
+
+    pub fn has_pending_optional_devices(&self) -> bool {
+        !self.failed_starts.lock().is_empty()
+    }
+
     pub fn stop_device(&mut self, address: &Uuid) -> Result<(), DeviceError> {
-        if let Some(device) = self.devices.get_mut(address) {
-            if !device.is_running() {
+        if let Some(device) = self.devices.get(address) {
+            if !device.read().is_running() {
                 return Err(DeviceError::InvalidOperation("device is not currently running".to_owned()));
             }
         } else {
             return Err(DeviceError::NotFound(address.to_owned()));
         }
 
-        let device = self.devices.get_mut(&address).unwrap().as_mut();
-        if !device.is_running() {
-            return Err(DeviceError::InvalidOperation("device is not currently running".to_owned()));
-        }
-
-        let mut device = self.devices.remove(address).unwrap();
+        let device_ptr = self.devices.remove(address).unwrap();
+        let mut device = device_ptr.write();
         device.as_mut().stop(self)?;
-        self.devices.insert(*address, device);
+        device.refresh_capabilities();
+        self.device_info.lock().insert(*address, DeviceInfo::of(&device));
+        drop(device);
+        self.devices.insert(*address, device_ptr);
+        self.events.emit(*address, DeviceEventKind::DeviceStopped);
         Ok(())
     }
 
-    pub fn register_bus(&mut self, bus: Arc<RwLock<dyn BusController>>) -> Result<(), DeviceError> {
-        for controller in &self.bus_controllers {
-            let t1 = bus.read().as_any().type_id();
-            let t2 = controller.read().as_any().type_id();
-            if t1 == t2 {
+    pub fn register_bus(&mut self, bus: impl Into<BusEntry>) -> Result<(), DeviceError> {
+        let bus = bus.into();
+        for handle in &self.bus_handles {
+            if (**handle).type_id() == (*bus.typed).type_id() {
                 return Err(DeviceError::DuplicateController);
             }
         }
         
-        self.bus_controllers.push(bus);
+        self.bus_names.push(bus.controller.read().name());
+        self.bus_controllers.push(bus.controller);
+        self.bus_handles.push(bus.typed);
         Ok(())
     }
 
+    pub fn events(&self) -> &DeviceEvents {
+        &self.events
+    }
+
+    pub fn bus_lock_timeout(&self) -> Duration {
+        self.bus_lock_timeout
+    }
+
+    pub fn set_bus_lock_timeout(&mut self, timeout: Duration) {
+        self.bus_lock_timeout = timeout;
+    }
+
+    pub fn device_lock_timeout(&self) -> Duration {
+        self.device_lock_timeout
+    }
+
+    pub fn set_device_lock_timeout(&mut self, timeout: Duration) {
+        self.device_lock_timeout = timeout;
+    }
+
+    // Hardware errors in a row before a device reports itself unhealthy
+    pub fn set_unhealthy_error_threshold(&mut self, errors: u64) {
+        self.events.set_unhealthy_threshold(errors);
+    }
+
+    pub fn recovers_stuck_locks(&self) -> bool {
+        self.recover_stuck_locks
+    }
+
+    pub fn set_recover_stuck_locks(&mut self, recover: bool) {
+        self.recover_stuck_locks = recover;
+    }
+
+    pub fn lock_metrics(&self) -> &Arc<LockMetrics> {
+        &self.lock_metrics
+    }
+
+    pub fn lock_wait_stats(&self) -> LockWaitStats {
+        self.lock_metrics.snapshot()
+    }
+
+    // Names a device for log messages without locking it
+    pub fn describe_device(&self, address: &Uuid) -> String {
+        match self.name_index.iter().find(|(_, x)| *x == address) {
+            Some((name, _)) => format!("device \"{}\" ({})", name, address),
+            None => format!("device {}", address)
+        }
+    }
+
+    // Stops the device if it's running and starts it again, rebuilding the driver state from scratch
+    pub fn restart_device(&mut self, address: &Uuid) -> Result<(), DeviceError> {
+        let device = self.devices.get(address).ok_or(DeviceError::NotFound(*address))?;
+        if device.read().is_running() {
+            self.stop_device(address)?;
+        }
+
+        self.start_device(address)
+    }
+
+    // For a device whose lock was held past the timeout. The holder is never interrupted, a thread waits
+    // for it to let go and restarts the device after that. Returns false when no restart was scheduled.
+    pub fn restart_when_released(server: &Arc<RwLock<DeviceServer>>, address: &Uuid) -> bool {
+        let (device, pending, name) = {
+            let server = server.read();
+            if !server.recover_stuck_locks {
+                return false;
+            }
+
+            match server.devices.get(address) {
+                Some(device) => (device.clone(), server.pending_restarts.clone(), server.describe_device(address)),
+                None => return false
+            }
+        };
+
+        if !pending.lock().insert(*address) {
+            return false;
+        }
+
+        warn!("{} is held for longer than the lock timeout, it will be restarted once released", name);
+        let (server, restarts, description) = (server.clone(), pending.clone(), name.clone());
+        let address = *address;
+        let spawned = std::thread::Builder::new().name("device-restart".to_owned()).spawn(move || {
+            // only waits, the holder keeps running until it's done
+            drop(device.write());
+            drop(device);
+
+            match server.write().restart_device(&address) {
+                Ok(_) => info!("Restarted {} after its lock was released", name),
+                Err(e) => warn!("Failed to restart {} after its lock was released: {}", name, e)
+            }
+
+            pending.lock().remove(&address);
+        });
+
+        if let Err(e) = spawned {
+            warn!("Failed to schedule a restart of {}: {}", description, e);
+            restarts.lock().remove(&address);
+            return false;
+        }
+
+        true
+    }
+
     pub fn get_bus<T: BusController>(&self) -> Option<MappedRwLockReadGuard<'_, T>> {
         for controller in &self.bus_controllers {
             if assert_controller_locked(controller) {
@@ -309,19 +1008,76 @@ impl DeviceServer {
         None
     }
 
+    // Same as get_bus, but waits for write locked controllers instead of skipping them
+    pub fn get_bus_timeout<T: BusController>(&self, timeout: Duration) -> Option<MappedRwLockReadGuard<'_, T>> {
+        let deadline = Instant::now() + timeout;
+        for (controller, name) in self.bus_controllers.iter().zip(&self.bus_names) {
+            let r = match controller.try_read_until(deadline) {
+                Some(r) => r,
+                None => {
+                    warn!("Timed out after {:?} waiting for bus controller \"{}\" to be released", timeout, name);
+                    continue;
+                }
+            };
+
+            if (*r).as_any().is::<T>() {
+                return Some(RwLockReadGuard::map(r, |x| x.as_any().downcast_ref::<T>().unwrap()));
+            }
+        }
+
+        None
+    }
+
+    pub fn get_bus_mut_timeout<T: BusController>(&self, timeout: Duration) -> Option<MappedRwLockWriteGuard<'_, T>> {
+        let deadline = Instant::now() + timeout;
+        for (controller, name) in self.bus_controllers.iter().zip(&self.bus_names) {
+            // Peek with a read lock first so controllers of another type aren't held exclusively
+            let is_match = match controller.try_read_until(deadline) {
+                Some(r) => (*r).as_any().is::<T>(),
+                None => {
+                    warn!("Timed out after {:?} waiting for bus controller \"{}\" to be released", timeout, name);
+                    continue;
+                }
+            };
+
+            if !is_match {
+                continue;
+            }
+
+            return match controller.try_write_until(deadline) {
+                Some(w) => Some(RwLockWriteGuard::map(w, |x| x.as_any_mut().downcast_mut::<T>().unwrap())),
+                None => {
+                    warn!("Timed out after {:?} waiting for bus controller \"{}\" to be released", timeout, name);
+                    None
+                }
+            };
+        }
+
+        None
+    }
+
+    pub fn try_get_bus<T: BusController>(&self) -> Option<MappedRwLockReadGuard<'_, T>> {
+        self.get_bus_timeout(self.bus_lock_timeout)
+    }
+
+    pub fn try_get_bus_mut<T: BusController>(&self) -> Option<MappedRwLockWriteGuard<'_, T>> {
+        self.get_bus_mut_timeout(self.bus_lock_timeout)
+    }
+
+    // Only checks the stored TypeIds, so this doesn't need to lock the controller
     pub fn get_bus_ptr<T: BusController + 'static>(&self) -> Option<Arc<RwLock<T>>> {
+        self.bus_handles.iter()
+            .find_map(|handle| Arc::clone(handle).downcast::<RwLock<T>>().ok())
+    }
+
+    pub fn get_gpio_bus_mut(&self) -> Option<MappedRwLockWriteGuard<'_, dyn GpioController>> {
         for controller in &self.bus_controllers {
             if assert_controller_locked(controller) {
                 continue;   
             }
 
-            let _sanity_check = (*controller.read()).as_any().is::<T>();
-            if _sanity_check {
-                let arc = Arc::clone(controller);
-                unsafe {
-                    let arc_cast = Arc::from_raw(Arc::into_raw(arc) as *const RwLock<T>);
-                    return Some(arc_cast);
-                }
+            if let Ok(gpio) = RwLockWriteGuard::try_map(controller.write(), |x| x.as_gpio_mut()) {
+                return Some(gpio);
             }
         }
 
@@ -342,27 +1098,97 @@ impl DeviceServer {
         return false;
     }
 
-    pub fn get_device(&self, address: &Uuid) -> Option<&Device> {
-        self.devices.get(address)
+    pub fn get_device(&self, address: &Uuid) -> Option<RwLockReadGuard<'_, Device>> {
+        self.devices.get(address).map(|x| x.read())
     }
 
-    pub fn get_devices(&self) -> HashMap<&Uuid, &Device> {
-        self.devices.iter().collect()
+    // Lets callers lock a single device after releasing the server lock
+    pub fn get_device_ptr(&self, address: &Uuid) -> Option<Arc<RwLock<Device>>> {
+        self.devices.get(address).cloned()
     }
 
-    pub fn get_device_with_name(&self, name: &str) -> Option<&Device> {
-        self.devices.iter().find(|x| x.1.device_name() == name).map(|x| x.1)
+    // Addresses in registration order
+    pub fn get_device_addresses(&self) -> Vec<Uuid> {
+        self.device_order.clone()
     }
 
-    pub fn get_device_mut(&mut self, address: &Uuid) -> Option<&mut Device> {
-        self.devices.get_mut(address)
+    pub fn get_devices(&self) -> Vec<(&Uuid, RwLockReadGuard<'_, Device>)> {
+        self.device_order.iter()
+            .filter_map(|address| self.devices.get_key_value(address))
+            .map(|(address, device)| (address, device.read()))
+            .collect()
     }
 
-    pub fn get_device_with_name_mut(&mut self, name: &str) -> Option<&mut Device> {
-        self.devices.iter_mut().find(|x| x.1.device_name() == name).map(|x| x.1)
+    // Only looks at the name index, so a held device doesn't block the lookup
+    pub fn address_for_name(&self, name: &str) -> Option<Uuid> {
+        self.name_index.get(name).copied()
+    }
+
+    // Copies every device's state so callers can enumerate without holding any locks afterwards.
+    // Built from what the server keeps about its devices, a device that is busy or stuck doesn't hold it up.
+    pub fn snapshot(&self) -> Vec<DeviceSnapshot> {
+        self.snapshot_ordered(DeviceOrder::Registration)
+    }
+
+    pub fn snapshot_ordered(&self, order: DeviceOrder) -> Vec<DeviceSnapshot> {
+        let mut snapshots: Vec<DeviceSnapshot> = self.device_order.iter()
+            .filter_map(|address| self.snapshot_device(address))
+            .collect();
+
+        if order == DeviceOrder::Name {
+            // stable, so devices sharing a name keep their registration order
+            snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+
+        snapshots
+    }
+
+    pub fn snapshot_device(&self, address: &Uuid) -> Option<DeviceSnapshot> {
+        let device_info = self.device_info.lock();
+        let info = device_info.get(address)?;
+        Some(DeviceSnapshot {
+            address: *address,
+            name: info.name.clone(),
+            driver_name: info.driver_name.clone(),
+            capabilities: info.capabilities.clone(),
+            is_running: info.is_running,
+            is_healthy: self.events.is_healthy(address),
+            tags: info.tags.clone()
+        })
+    }
+
+    pub fn get_device_with_name(&self, name: &str) -> Option<RwLockReadGuard<'_, Device>> {
+        self.name_index.get(name).and_then(|address| self.get_device(address))
+    }
+
+    pub fn get_device_mut(&mut self, address: &Uuid) -> Option<RwLockWriteGuard<'_, Device>> {
+        self.devices.get(address).map(|x| x.write())
+    }
+
+    pub fn get_device_with_name_mut(&mut self, name: &str) -> Option<RwLockWriteGuard<'_, Device>> {
+        match self.name_index.get(name) {
+            Some(address) => self.devices.get(address).map(|x| x.write()),
+            None => None
+        }
     }
 
     pub fn has_device(&self, address: &Uuid) -> bool {
         self.devices.contains_key(address)
     }
+
+    // Resolves a device name or address into a link another driver can keep, usually done in start
+    pub fn link_device(&self, reference: &str) -> Result<DeviceLink, DeviceError> {
+        let address = match self.name_index.get(reference) {
+            Some(address) => *address,
+            None => match Uuid::parse_str(reference) {
+                Ok(address) => address,
+                Err(_) => return Err(DeviceError::InvalidOperation(format!("no device named \"{}\" is registered", reference)))
+            }
+        };
+
+        match self.devices.get(&address) {
+            Some(device) => Ok(DeviceLink::new(reference, device, self.device_lock_timeout)),
+            None => Err(DeviceError::NotFound(address))
+        }
+    }
 }
\ No newline at end of file
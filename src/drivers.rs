@@ -1,4 +1,116 @@
 pub mod sysfs_led;
 pub mod gps_uart;
 pub mod tsl2591_sysfs;
-pub mod bmp280_sysfs;
\ No newline at end of file
+pub mod bmp280_sysfs;
+pub mod serial_passthrough;
+pub mod filter;
+pub mod retry;
+pub mod ina219_sysfs;
+pub mod servo_pwm;
+pub mod led_group;
+pub mod pwm_ramp;
+pub mod geofence;
+pub mod gpio_relay;
+
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::config::DeviceConfig;
+use crate::device::{Device, DeviceDriver, DeviceError};
+
+use self::{
+    bmp280_sysfs::{Bmp280SysfsConfig, Bmp280SysfsDriver}, gpio_relay::{GpioRelay, GpioRelayConfig}, gps_uart::{UartGps, UartGpsConfig},
+    ina219_sysfs::{Ina219SysfsConfig, Ina219SysfsDriver}, led_group::{LedGroup, LedGroupConfig},
+    serial_passthrough::{SerialPassthrough, SerialPassthroughConfig}, servo_pwm::{ServoPwm, ServoPwmConfig},
+    sysfs_led::{SysfsLedController, SysfsLedControllerConfig}, tsl2591_sysfs::{Tsl2591SysfsConfig, Tsl2591SysfsDriver},
+};
+
+fn check_schema<T: DeserializeOwned>(data: &Value) -> Result<(), String> {
+    serde_json::from_value::<T>(data.clone())
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+// Checks driver data against the config struct of a known driver without constructing it.
+// Null data is accepted because drivers write their own defaults, unknown drivers are left to the loader
+pub fn validate_driver_data(driver: &str, data: &Value) -> Result<(), String> {
+    if *data == Value::Null {
+        return Ok(());
+    }
+
+    match driver {
+        "sysfs_generic_led" => check_schema::<SysfsLedControllerConfig>(data),
+        "gps_uart" => check_schema::<UartGpsConfig>(data),
+        "tsl2591_sysfs" => check_schema::<Tsl2591SysfsConfig>(data),
+        "bmp280_sysfs" => check_schema::<Bmp280SysfsConfig>(data),
+        "serial_passthrough" => check_schema::<SerialPassthroughConfig>(data),
+        "ina219_sysfs" => check_schema::<Ina219SysfsConfig>(data),
+        "servo_pwm" => check_schema::<ServoPwmConfig>(data),
+        "led_group" => check_schema::<LedGroupConfig>(data),
+        "gpio_relay" => check_schema::<GpioRelayConfig>(data),
+        _ => Ok(())
+    }
+}
+
+pub type DriverConstructor = fn(&mut DeviceConfig) -> Result<Device, DeviceError>;
+
+// Maps driver names from the config to their constructors, names are matched case-insensitively
+#[derive(Default)]
+pub struct DriverRegistry {
+    constructors: HashMap<String, DriverConstructor>
+}
+
+impl DriverRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Every driver compiled into the server
+    pub fn builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register_driver::<SysfsLedController>("sysfs_generic_led");
+        registry.register_driver::<UartGps>("gps_uart");
+        registry.register_driver::<Tsl2591SysfsDriver>("tsl2591_sysfs");
+        registry.register_driver::<Bmp280SysfsDriver>("bmp280_sysfs");
+        registry.register_driver::<SerialPassthrough>("serial_passthrough");
+        registry.register_driver::<Ina219SysfsDriver>("ina219_sysfs");
+        registry.register_driver::<ServoPwm>("servo_pwm");
+        registry.register_driver::<LedGroup>("led_group");
+        registry.register_driver::<GpioRelay>("gpio_relay");
+        registry
+    }
+
+    // Replaces any constructor already registered under the name
+    pub fn register(&mut self, name: &str, constructor: DriverConstructor) {
+        self.constructors.insert(name.to_lowercase(), constructor);
+    }
+
+    pub fn register_driver<T: DeviceDriver>(&mut self, name: &str) {
+        self.register(name, |config| {
+            let address = config.parsed_address().map_err(|e| DeviceError::invalid_config(e.to_string()))?;
+            Device::from_config::<T>(config, address)
+        });
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.constructors.contains_key(&name.to_lowercase())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.constructors.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn build(&self, config: &mut DeviceConfig) -> Result<Device, DeviceError> {
+        match self.constructors.get(&config.driver.to_lowercase()) {
+            Some(constructor) => constructor(config),
+            None => Err(DeviceError::invalid_config(format!(
+                "device driver {} is not supported by this server",
+                config.driver
+            )))
+        }
+    }
+}
@@ -1,7 +1,6 @@
 use i2c_linux::I2c;
 use intertrait::cast_to;
 use log::{debug, error, warn};
-use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
@@ -9,21 +8,24 @@ use std::{
     fs::File,
     io::{Error, Read, Write},
     os::fd::AsRawFd,
-    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    bus::i2c_sysfs::{self, SysfsI2CBusController},
-    capabilities::{Capability, ThermometerCapable, BarometerCapable},
+    bus::bus_lock::BusClient,
+    bus::i2c::{I2cAddress, I2cDeviceConfig},
+    bus::i2c_sysfs::{self, SmbusTransfer, SysfsI2CBusController},
+    capabilities::{Capability, CapabilityId, ReadingCalibration, SensorCapable, ThermometerCapable, BarometerCapable, DiagnosticsCapable, HealthReport, ReadingUnit},
     config::ConfigError,
     device::{DeviceDriver, DeviceError},
+    drivers::filter::EmaFilter,
+    drivers::retry::{self, StartRetry},
 };
-type I2cBus = Arc<Mutex<I2c<File>>>;
+type I2cBus = BusClient<I2c<File>>;
 
 const SPINWAIT_INTERVAL: u16 = 10;
-const DEFAULT_I2C_ADDR: u8 = 0x76;
+const DEFAULT_I2C_ADDR: u16 = 0x76;
 const CHIP_ID: u8 = 0x58;
 const COMMAND_BIT: u8 = 0x80;
 
@@ -37,6 +39,13 @@ const REGISTER_CONTROL: u8 = 0x74;
 const REGISTER_CONFIG: u8 = 0x75;
 const PRESSURE_MSB: u8 = 0x77;
 const TEMPERATURE_MSB: u8 = 0x7A;
+const MIN_REFERENCE_PRESSURE_HPA: f32 = 300.0;
+const MAX_REFERENCE_PRESSURE_HPA: f32 = 1200.0;
+// operating range from the datasheet
+const MIN_TEMPERATURE_C: f32 = -40.0;
+const MAX_TEMPERATURE_C: f32 = 85.0;
+const MIN_PRESSURE_PA: f32 = 30000.0;
+const MAX_PRESSURE_PA: f32 = 110000.0;
 
 enum PowerMode {
     Sleep = 0x00,
@@ -157,10 +166,35 @@ pub struct Bmp280SysfsConfig {
     pub default_thermometer_gain: u16,
     pub default_pressure_gain: u16,
     pub default_standby_time: u16,
-    pub device_address: u8,
+    #[serde(flatten)]
+    pub i2c: I2cDeviceConfig,
     pub device_ready_timeout: u16,
     pub pressure_at_sea_level: u32,
-    pub bus_id: u8,
+    // EMA weight of the newest temperature and pressure reading, 0 disables smoothing
+    #[serde(default)]
+    pub smoothing_alpha: f32,
+    // extra chip detection attempts in start for chips that are slow to power up, the delay doubles every time
+    #[serde(default)]
+    pub start_retries: u32,
+    #[serde(default = "default_start_retry_delay_ms")]
+    pub start_retry_delay_ms: u64,
+    // linear correction of the final readings, the pressure offset is in Pa
+    #[serde(default = "default_calibration_scale")]
+    pub temperature_calibration_scale: f32,
+    #[serde(default)]
+    pub temperature_calibration_offset: f32,
+    #[serde(default = "default_calibration_scale")]
+    pub pressure_calibration_scale: f32,
+    #[serde(default)]
+    pub pressure_calibration_offset: f32,
+}
+
+fn default_start_retry_delay_ms() -> u64 {
+    retry::DEFAULT_START_RETRY_DELAY_MS
+}
+
+fn default_calibration_scale() -> f32 {
+    1.0
 }
 
 impl Default for Bmp280SysfsConfig {
@@ -169,107 +203,153 @@ impl Default for Bmp280SysfsConfig {
             default_thermometer_gain: GainValue::_1X.into_multiplier(),
             default_pressure_gain: GainValue::_4X.into_multiplier(),
             default_standby_time: StandbyTime::_63MS.into_millis(),
-            device_address: DEFAULT_I2C_ADDR,
+            i2c: I2cDeviceConfig::new(0, DEFAULT_I2C_ADDR),
             device_ready_timeout: 100,
             pressure_at_sea_level: 101325,
-            bus_id: 0,
+            smoothing_alpha: 0.0,
+            start_retries: 0,
+            start_retry_delay_ms: retry::DEFAULT_START_RETRY_DELAY_MS,
+            temperature_calibration_scale: 1.0,
+            temperature_calibration_offset: 0.0,
+            pressure_calibration_scale: 1.0,
+            pressure_calibration_offset: 0.0,
         }
     }
 }
 
 // helper methods for managing the device
-fn set_mode_and_gain<T: Write + AsRawFd>(
-    bus: &mut I2c<T>,
-    address: u8,
+fn set_mode_and_gain<T: SmbusTransfer + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
     thermometer_gain: GainValue,
     pressure_gain: GainValue,
     mode: PowerMode,
+    pec: bool,
 ) -> Result<(), Error> {
     let data = ((thermometer_gain as u8) << 5) | ((pressure_gain as u8) << 3) | mode as u8;
-    i2c_sysfs::write_register(bus, address, COMMAND_BIT | REGISTER_CONTROL, data)
+    i2c_sysfs::write_register_with(bus, address, COMMAND_BIT | REGISTER_CONTROL, data, pec)
 }
 
-fn get_chip_id<T: Write + Read + AsRawFd>(bus: &mut I2c<T>, address: u8) -> Result<u8, Error> {
+fn get_chip_id<T: SmbusTransfer + Read + Write + ?Sized>(bus: &mut T, address: I2cAddress, pec: bool) -> Result<u8, Error> {
     let mut buf = [0u8; 1];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | REGISTER_ID, &mut buf)?;
+    i2c_sysfs::read_register_with(bus, address, COMMAND_BIT | REGISTER_ID, &mut buf, pec)?;
 
     Ok(buf[0])
 }
 
-fn read_adc<T: Write + Read + AsRawFd>(bus: &mut I2c<T>, address: u8) -> Result<(u32, u32), Error> {
+// Checks that a BMP280 answers at the address, this is the part of start that gets retried
+pub(crate) fn detect_chip<T: SmbusTransfer + Read + Write + ?Sized>(bus: &mut T, bus_id: u8, address: I2cAddress, pec: bool) -> Result<(), DeviceError> {
+    let chip_id = match get_chip_id(bus, address, pec) {
+        Ok(id) => id,
+        Err(e) => {
+            return Err(DeviceError::hardware(format!("failed to identify chip: {}", e), e))
+        }
+    };
+
+    if chip_id != CHIP_ID {
+        return Err(DeviceError::hardware_error(format!(
+            "bus {} address {} contains an invalid device - reported chipID {} but expected {}",
+            bus_id, address, chip_id, CHIP_ID
+        )));
+    }
+
+    Ok(())
+}
+
+fn read_adc<T: SmbusTransfer + Read + Write + ?Sized>(bus: &mut T, address: I2cAddress, pec: bool) -> Result<(u32, u32), Error> {
     let mut temp_buf = [0u8; 3];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | TEMPERATURE_MSB, &mut temp_buf)?;
+    i2c_sysfs::read_register_with(bus, address, COMMAND_BIT | TEMPERATURE_MSB, &mut temp_buf, pec)?;
 
     let temp =
         ((temp_buf[0] as u32) << 12) | ((temp_buf[1] as u32) << 4) | (temp_buf[2] as u32 >> 4);
 
     let mut press_buf = [0u8; 3];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | TEMPERATURE_MSB, &mut press_buf)?;
+    i2c_sysfs::read_register_with(bus, address, COMMAND_BIT | TEMPERATURE_MSB, &mut press_buf, pec)?;
     let press =
         ((press_buf[0] as u32) << 12) | ((press_buf[1] as u32) << 4) | (press_buf[2] as u32 >> 4);
 
     Ok((temp, press))
 }
 
-fn is_adc_valid<T: Write + Read + AsRawFd>(bus: &mut I2c<T>, address: u8) -> Result<bool, Error> {
+fn is_adc_valid<T: SmbusTransfer + Read + Write + ?Sized>(bus: &mut T, address: I2cAddress, pec: bool) -> Result<bool, Error> {
     let mut status_buf = [0u8; 1];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | REGISTER_STATUS, &mut status_buf)?;
+    i2c_sysfs::read_register_with(bus, address, COMMAND_BIT | REGISTER_STATUS, &mut status_buf, pec)?;
 
     return Ok(status_buf[0] & 0x09 == 0x00);
 }
 
 fn wait_adc_valid<T: Write + Read + AsRawFd>(
     bus: &mut I2c<T>,
-    address: u8,
+    address: I2cAddress,
     step: u16,
     timeout: u16,
+    bus_timeout: Option<Duration>,
+    pec: bool,
 ) -> Result<(), DeviceError> {
-    let mut elapsed = 0;
+    let elapsed = poll_adc_valid(bus, address, step, timeout, bus_timeout, pec)?;
+    debug!("ADC ready after ~{} ms", elapsed);
+    Ok(())
+}
+
+// Polls the status register until the chip is ready, returns the approximate time spent waiting.
+// Status reads that time out are the chip clock-stretching, they are retried and count the bus timeout towards the wait.
+pub(crate) fn poll_adc_valid<T: SmbusTransfer + Read + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
+    step: u16,
+    timeout: u16,
+    bus_timeout: Option<Duration>,
+    pec: bool,
+) -> Result<u16, DeviceError> {
+    let stall = bus_timeout.map(|x| x.as_millis().min(u16::MAX as u128) as u16).unwrap_or(0);
     let wait_interval = Duration::from_millis(step as u64);
+    let mut elapsed: u16 = 0;
+    let mut last_timeout: Option<Error> = None;
     loop {
         if elapsed >= timeout {
-            return Err(DeviceError::HardwareError(format!(
-                "timed out waiting for the chip to become ready"
-            )));
+            return Err(match last_timeout {
+                Some(e) => DeviceError::hardware(format!(
+                    "timed out waiting for the chip to become ready, status reads kept timing out: {}", e
+                ), e),
+                None => DeviceError::hardware_error("timed out waiting for the chip to become ready")
+            });
         }
 
-        match is_adc_valid(bus, address) {
-            Ok(result) => {
-                if result {
-                    break;
-                }
+        match is_adc_valid(bus, address, pec) {
+            Ok(true) => return Ok(elapsed),
+            Ok(false) => last_timeout = None,
+            Err(e) if i2c_sysfs::is_timeout_error(&e) => {
+                debug!("Status read timed out while waiting for the ADC, retrying: {}", e);
+                elapsed = elapsed.saturating_add(stall);
+                last_timeout = Some(e);
             }
             Err(e) => {
-                return Err(DeviceError::HardwareError(format!(
-                    "failed to read chip status: {}",
-                    e
-                )))
+                return Err(DeviceError::hardware(format!("failed to read chip status: {}", e), e))
             }
         };
 
-        elapsed += step;
+        elapsed = elapsed.saturating_add(step);
         thread::sleep(wait_interval)
     }
-
-    debug!("ADC ready after ~{} ms", elapsed);
-    Ok(())
 }
 
-fn set_standby_time<T: Write + AsRawFd>(
-    bus: &mut I2c<T>,
-    address: u8,
+fn set_standby_time<T: SmbusTransfer + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
     time: StandbyTime,
+    pec: bool,
 ) -> Result<(), Error> {
     let data = (time as u8) << 5;
-    i2c_sysfs::write_register(bus, address, COMMAND_BIT | REGISTER_CONFIG, data)
+    i2c_sysfs::write_register_with(bus, address, COMMAND_BIT | REGISTER_CONFIG, data, pec)
 }
 
-fn read_calib_data<T: Read + Write + AsRawFd>(
-    bus: &mut I2c<T>,
-    address: u8,
+fn read_calib_data<T: SmbusTransfer + Read + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
+    pec: bool,
 ) -> Result<CalibrationData, Error> {
     let mut calib_buf = [0u8; CALIB_DATA_LEN];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | REGISTER_CALIB0, &mut calib_buf)?;
+    i2c_sysfs::read_register_with(bus, address, COMMAND_BIT | REGISTER_CALIB0, &mut calib_buf, pec)?;
 
     Ok(CalibrationData {
         dig_T1: (calib_buf[1] as u16) << 8 | calib_buf[0] as u16,
@@ -325,13 +405,39 @@ fn compensate_values(temperature: i32, pressure: i32, calibration: &CalibrationD
     (temp, press)
 }
 
+// Hypsometric equation, the measured temperature stands in for the mean temperature of the air column
+pub(crate) fn hypsometric_altitude(pressure_hpa: f32, reference_hpa: f32, temperature_c: f32) -> f32 {
+    ((reference_hpa / pressure_hpa).powf(1.0 / 5.257) - 1.0) * (temperature_c + 273.15) / 0.0065
+}
+
+fn check_reference_pressure(pressure_hpa: f32) -> Result<(), String> {
+    if !(MIN_REFERENCE_PRESSURE_HPA..=MAX_REFERENCE_PRESSURE_HPA).contains(&pressure_hpa) {
+        return Err(format!(
+            "reference pressure of {} hPa is out of range ({} - {} hPa)",
+            pressure_hpa, MIN_REFERENCE_PRESSURE_HPA, MAX_REFERENCE_PRESSURE_HPA
+        ));
+    }
+
+    Ok(())
+}
+
 pub struct Bmp280SysfsDriver {
     config: Bmp280SysfsConfig,
+    address: I2cAddress,
+    reference_pressure: f32,
     bus: Option<I2cBus>,
+    bus_timeout: Option<Duration>,
+    pec: bool,
     calibration_data: Option<CalibrationData>,
     thermometer_gain: GainValue,
     pressure_gain: GainValue,
     standby_time: StandbyTime,
+    last_error: Option<DeviceError>,
+    temperature_filter: EmaFilter,
+    pressure_filter: EmaFilter,
+    temperature_calibration: ReadingCalibration,
+    pressure_calibration: ReadingCalibration,
+    started_at: Option<Instant>,
     is_loaded: bool,
 }
 
@@ -340,13 +446,13 @@ impl Bmp280SysfsDriver {
         let thermometer_gain = match GainValue::from_multiplier(config.default_thermometer_gain) {
             Some(g) => g,
             None => {
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::InvalidEntry(format!(
                         "invalid thermometer gain multiplier: {}, supported gain values are {}",
                         config.default_thermometer_gain,
                         SUPPORTED_GAIN_VALUES.map(|x| x.to_string()).join(", ")
                     ))
-                    .to_string(),
+                    .to_string()
                 ))
             }
         };
@@ -354,13 +460,13 @@ impl Bmp280SysfsDriver {
         let pressure_gain = match GainValue::from_multiplier(config.default_pressure_gain) {
             Some(g) => g,
             None => {
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::InvalidEntry(format!(
                         "invalid thermometer gain multiplier: {}, supported gain values are {}",
                         config.default_pressure_gain,
                         SUPPORTED_GAIN_VALUES.map(|x| x.to_string()).join(", ")
                     ))
-                    .to_string(),
+                    .to_string()
                 ))
             }
         };
@@ -368,28 +474,88 @@ impl Bmp280SysfsDriver {
         let standby_time = match StandbyTime::from_millis(config.default_standby_time) {
             Some(t) => t,
             None => {
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::InvalidEntry(format!(
                         "invalid standby time: {}, supported values are {}",
                         config.default_standby_time,
                         SUPPORTED_STANDBY_TIMES.map(|x| x.to_string()).join(", ")
                     ))
-                    .to_string(),
+                    .to_string()
                 ))
             }
         };
 
+        // the config stores the sea level pressure in Pa
+        let reference_pressure = config.pressure_at_sea_level as f32 / 100.0;
+        check_reference_pressure(reference_pressure).map_err(|e| {
+            DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string())
+        })?;
+
+        EmaFilter::validate_alpha(config.smoothing_alpha).map_err(|e| {
+            DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string())
+        })?;
+
+        let calibration = |scale: f32, offset: f32| ReadingCalibration::new(scale, offset).map_err(|e| {
+            DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string())
+        });
+        let temperature_calibration = calibration(config.temperature_calibration_scale, config.temperature_calibration_offset)?;
+        let pressure_calibration = calibration(config.pressure_calibration_scale, config.pressure_calibration_offset)?;
+
+        let smoothing_alpha = config.smoothing_alpha;
+        let address = config.i2c.address().map_err(|e| DeviceError::invalid_config(e.to_string()))?;
+
         Ok(Self {
             config: config,
+            address,
+            reference_pressure,
             bus: None,
+            bus_timeout: None,
+            pec: false,
             calibration_data: None,
             thermometer_gain: thermometer_gain,
             pressure_gain: pressure_gain,
             standby_time,
+            last_error: None,
+            temperature_filter: EmaFilter::new(smoothing_alpha),
+            pressure_filter: EmaFilter::new(smoothing_alpha),
+            temperature_calibration,
+            pressure_calibration,
+            started_at: None,
             is_loaded: false,
         })
     }
 
+    // Identifies and configures the chip, returns its calibration data
+    fn init_chip(&self, transaction: &mut I2c<File>) -> Result<CalibrationData, DeviceError> {
+        let address = self.address;
+        let bus_id = self.config.i2c.bus_id;
+        let pec = self.pec;
+        StartRetry::new(self.config.start_retries, self.config.start_retry_delay_ms)
+            .run("detect BMP280", || detect_chip(transaction, bus_id, address, pec))?;
+
+        wait_adc_valid(transaction, address, SPINWAIT_INTERVAL, self.config.device_ready_timeout, self.bus_timeout, pec)?;
+
+        let calibration = read_calib_data(transaction, address, pec)
+            .map_err(|e| DeviceError::hardware(format!("failed to read calibration data from chip: {}", e), e))?;
+
+        if let Err(e) = set_mode_and_gain(
+            transaction,
+            address,
+            self.thermometer_gain,
+            self.pressure_gain,
+            PowerMode::Normal,
+            pec,
+        ) {
+            return Err(DeviceError::hardware(format!("failed to enable and configure device: {}", e), e));
+        }
+
+        if let Err(e) = set_standby_time(transaction, address, self.standby_time, pec) {
+            warn!("Failed to set standby time: {}", e);
+        }
+
+        Ok(calibration)
+    }
+
     fn assert_state(&self, check_bus: bool) -> Result<(), DeviceError> {
         if self.is_loaded && (!check_bus || self.bus.is_some()) {
             Ok(())
@@ -425,7 +591,7 @@ impl Bmp280SysfsDriver {
         self.assert_state(true)?;
         let standby_millis = match SUPPORTED_STANDBY_TIMES.get(interval_id as usize) {
             Some(time) => time,
-            None => return Err(DeviceError::HardwareError(format!(
+            None => return Err(DeviceError::hardware_error(format!(
                 "standby time ID is not supported: {}",
                 interval_id
             ))),
@@ -439,20 +605,35 @@ impl Bmp280SysfsDriver {
             },
         };
 
-        let address = self.config.device_address;
+        let address = self.address;
         let mut transaction = self.bus.as_ref().unwrap().lock();
-        wait_adc_valid(&mut transaction, address, SPINWAIT_INTERVAL, self.standby_time.into_millis() + SPINWAIT_INTERVAL)?;
-        set_standby_time(&mut transaction, address, standby_time)
-            .map_err(|e| DeviceError::HardwareError(format!("failed to apply new standby time: {}", e)))?;
+        wait_adc_valid(&mut transaction, address, SPINWAIT_INTERVAL, self.standby_time.into_millis() + SPINWAIT_INTERVAL, self.bus_timeout, self.pec)?;
+        set_standby_time(&mut *transaction, address, standby_time, self.pec)
+            .map_err(|e| DeviceError::hardware(format!("failed to apply new standby time: {}", e), e))?;
 
         self.standby_time = standby_time;
         Ok(())
     }
 
+    // Both readings go through their filters together so altitude sees the same smoothed values
     fn get_sensor_data(&mut self) -> Result<(f32, f32), DeviceError> {
+        match self.read_sensor_data() {
+            // calibrated after smoothing so a calibration change applies to the very next reading
+            Ok((temp, press)) => Ok((
+                self.temperature_calibration.apply(self.temperature_filter.apply(temp)),
+                self.pressure_calibration.apply(self.pressure_filter.apply(press)),
+            )),
+            Err(e) => {
+                self.last_error = Some(e.clone());
+                Err(e)
+            }
+        }
+    }
+
+    fn read_sensor_data(&mut self) -> Result<(f32, f32), DeviceError> {
         self.assert_state(true)?;
 
-        let address = self.config.device_address;
+        let address = self.address;
         let calibration_data = match self.calibration_data.as_ref() {
             Some(data) => data,
             None => {
@@ -463,8 +644,8 @@ impl Bmp280SysfsDriver {
 
         let mut transaction = self.bus.as_ref().unwrap().lock();
         // technically we should wait for the ADCs to become valid rn buuut it seems like we can read them just fine
-        let (temp_raw, press_raw) = read_adc(&mut transaction, address)
-            .map_err(|e| DeviceError::HardwareError(format!("failed to read sensor data: {}", e)))?;
+        let (temp_raw, press_raw) = read_adc(&mut *transaction, address, self.pec)
+            .map_err(|e| DeviceError::hardware(format!("failed to read sensor data: {}", e), e))?;
 
         Ok(compensate_values(temp_raw as i32, press_raw as i32, calibration_data))
     }
@@ -479,6 +660,10 @@ impl DeviceDriver for Bmp280SysfsDriver {
         self
     }
 
+    fn required_controllers(&self) -> Vec<&'static str> {
+        vec!["i2c_sysfs"]
+    }
+
     fn name(&self) -> String {
         "bmp280_sysfs".to_string()
     }
@@ -494,8 +679,8 @@ impl DeviceDriver for Bmp280SysfsDriver {
         Self: Sized,
     {
         if config.is_none() {
-            return Err(DeviceError::InvalidConfig(
-                "this driver requires a configuration object but none was provided".to_owned(),
+            return Err(DeviceError::invalid_config(
+                "this driver requires a configuration object but none was provided".to_owned()
             ));
         }
 
@@ -507,17 +692,17 @@ impl DeviceDriver for Bmp280SysfsDriver {
                     match serde_json::to_value(Bmp280SysfsConfig::default()) {
                         Ok(c) => {
                             config.driver_data = c;
-                            return Err(DeviceError::InvalidConfig(
+                            return Err(DeviceError::invalid_config(
                                 ConfigError::MissingEntry(
                                     "device was missing config data, default config was written"
                                         .to_string(),
                                 )
-                                .to_string(),
+                                .to_string()
                             ));
                         }
                         Err(e) => {
                             warn!("Failed to write default configuration: {}", e);
-                            return Err(DeviceError::InvalidConfig(
+                            return Err(DeviceError::invalid_config(
                                 ConfigError::MissingEntry(
                                     format!("device was missing config data, default config failed to be written: {}", e)
                                 ).to_string()
@@ -526,12 +711,12 @@ impl DeviceDriver for Bmp280SysfsDriver {
                     }
                 }
 
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::SerializeError(format!(
                         "failed to deserialize device config data: {}",
                         e
                     ))
-                    .to_string(),
+                    .to_string()
                 ));
             }
         };
@@ -546,67 +731,42 @@ impl DeviceDriver for Bmp280SysfsDriver {
             ));
         }
 
-        let address = self.config.device_address;
-        let bus_id = self.config.bus_id;
-
+        let bus_id = self.config.i2c.bus_id;
         let mut i2c = match parent.get_bus_mut::<SysfsI2CBusController>() {
             Some(controller) => controller,
             None => return Err(DeviceError::MissingController("i2c_sysfs".to_string())),
         };
 
-        let bus = match i2c.get(bus_id) {
+        let bus = match i2c.client(&self.config.i2c, &format!("{}@{}", self.name(), self.address)) {
             Ok(bus) => bus,
-            Err(e) => return Err(DeviceError::HardwareError(e.to_string())),
+            Err(e) => return Err(DeviceError::hardware_error(e.to_string())),
         };
 
-        let mut transaction = bus.lock();
-        let chip_id = match get_chip_id(&mut transaction, address) {
-            Ok(id) => id,
+        self.bus_timeout = i2c.timeout();
+        self.pec = i2c.pec_enabled();
+        let init = self.init_chip(&mut bus.lock());
+        let calibration = match init {
+            Ok(calibration) => calibration,
             Err(e) => {
-                return Err(DeviceError::HardwareError(format!(
-                    "failed to identify chip: {}",
-                    e
-                )))
+                drop(bus);
+                if let Err(e) = i2c.close(bus_id) {
+                    warn!("Failed to release I2C bus {} while recovering from an error: {}", bus_id, e);
+                }
+
+                return Err(e);
             }
         };
 
-        if chip_id != CHIP_ID {
-            return Err(DeviceError::HardwareError(format!(
-                "bus {} address {} contains an invalid device - reported chipID {} but expected {}",
-                bus_id, address, chip_id, CHIP_ID
-            )));
-        }
-
-        wait_adc_valid(&mut transaction, address, SPINWAIT_INTERVAL, self.config.device_ready_timeout)?;
-
-        let calibration = read_calib_data(&mut transaction, address)
-            .map_err(|e| DeviceError::HardwareError(format!("failed to read calibration data from chip: {}", e)))?;
-
-        if let Err(e) = set_mode_and_gain(
-            &mut transaction,
-            address,
-            self.thermometer_gain,
-            self.pressure_gain,
-            PowerMode::Normal,
-        ) {
-            return Err(DeviceError::HardwareError(format!(
-                "failed to enable and configure device: {}",
-                e
-            )));
-        }
-
-        if let Err(e) = set_standby_time(&mut transaction, address, self.standby_time) {
-            warn!("Failed to set standby time: {}", e);
-        }
-
-        drop(transaction);
         self.bus = Some(bus);
         self.calibration_data = Some(calibration);
+        self.temperature_filter.reset();
+        self.pressure_filter.reset();
+        self.started_at = Some(Instant::now());
         self.is_loaded = true;
         Ok(())
     }
 
-    fn stop(&mut self, _parent: &mut crate::device::DeviceServer) -> Result<(), DeviceError> {
+    fn stop(&mut self, parent: &mut crate::device::DeviceServer) -> Result<(), DeviceError> {
         if !self.is_loaded {
             return Err(DeviceError::InvalidOperation(
                 "device unload requested but this device isn't loaded".to_string(),
@@ -615,15 +775,16 @@ impl DeviceDriver for Bmp280SysfsDriver {
 
         match self.bus {
             Some(ref bus) => {
-                let address = self.config.device_address;
+                let address = self.address;
                 let mut transaction = bus.lock();
 
                 if let Err(e) = set_mode_and_gain(
-                    &mut transaction,
+                    &mut *transaction,
                     address,
                     GainValue::_1X,
                     GainValue::_1X,
                     PowerMode::Sleep,
+                    self.pec,
                 ) {
                     warn!("Failed to disable device: {}", e);
                 }
@@ -631,8 +792,20 @@ impl DeviceDriver for Bmp280SysfsDriver {
             None => warn!("Failed to disable hardware: I2C bus was uninitialized"),
         };
 
-        self.bus = None;
+        // other devices may still be using the bus, the controller only closes it after the last one
+        if self.bus.take().is_some() {
+            match parent.get_bus_mut::<SysfsI2CBusController>() {
+                Some(mut i2c) => {
+                    if let Err(e) = i2c.close(self.config.i2c.bus_id) {
+                        warn!("Failed to release I2C bus {}: {}", self.config.i2c.bus_id, e);
+                    }
+                }
+                None => warn!("Failed to release I2C bus: controller is unavailable"),
+            }
+        }
+
         self.calibration_data = None;
+        self.started_at = None;
         self.is_loaded = false;
         Ok(())
     }
@@ -641,7 +814,7 @@ impl DeviceDriver for Bmp280SysfsDriver {
 impl Capability for Bmp280SysfsDriver {}
 
 #[cast_to]
-impl ThermometerCapable for Bmp280SysfsDriver {
+impl SensorCapable for Bmp280SysfsDriver {
     fn get_supported_gains(&self) -> HashMap<u8, u16> {
         self._get_supported_gains()
     }
@@ -650,13 +823,25 @@ impl ThermometerCapable for Bmp280SysfsDriver {
         self._get_supported_intervals()
     }
 
-    fn get_gain(&self) -> Result<u16, DeviceError> {
+    // Temperature and pressure oversampling are set separately
+    fn get_gain(&self, reading: CapabilityId) -> Result<u16, DeviceError> {
+        let gain = match reading {
+            CapabilityId::Thermometer => self.thermometer_gain,
+            CapabilityId::Barometer => self.pressure_gain,
+            _ => return Err(DeviceError::NotSupported)
+        };
+
         self.assert_state(false)?;
-        Ok(self.thermometer_gain.into_multiplier())
+        Ok(gain.into_multiplier())
     }
 
-    fn set_gain(&mut self, gain_id: u8) -> Result<(), DeviceError> {
+    fn set_gain(&mut self, reading: CapabilityId, gain_id: u8) -> Result<(), DeviceError> {
+        if !matches!(reading, CapabilityId::Thermometer | CapabilityId::Barometer) {
+            return Err(DeviceError::NotSupported);
+        }
+
         self.assert_state(true)?;
+
         let gain_multiplier = match SUPPORTED_GAIN_VALUES.get(gain_id as usize) {
             Some(gain) => gain,
             None => {
@@ -675,13 +860,19 @@ impl ThermometerCapable for Bmp280SysfsDriver {
             },
         };
 
-        let address = self.config.device_address;
+        let (thermometer_gain, pressure_gain) = match reading {
+            CapabilityId::Thermometer => (gain_value, self.pressure_gain),
+            _ => (self.thermometer_gain, gain_value)
+        };
+
+        let address = self.address;
         let mut transaction = self.bus.as_ref().unwrap().lock();
-        wait_adc_valid(&mut transaction, address, SPINWAIT_INTERVAL, self.standby_time.into_millis() + SPINWAIT_INTERVAL)?;
-        set_mode_and_gain(&mut transaction, address, gain_value, self.pressure_gain, PowerMode::Normal)
-            .map_err(|e| DeviceError::HardwareError(format!("failed to apply new gain value: {}", e)))?;
+        wait_adc_valid(&mut transaction, address, SPINWAIT_INTERVAL, self.standby_time.into_millis() + SPINWAIT_INTERVAL, self.bus_timeout, self.pec)?;
+        set_mode_and_gain(&mut *transaction, address, thermometer_gain, pressure_gain, PowerMode::Normal, self.pec)
+            .map_err(|e| DeviceError::hardware(format!("failed to apply new gain value: {}", e), e))?;
 
-        self.thermometer_gain = gain_value;
+        self.thermometer_gain = thermometer_gain;
+        self.pressure_gain = pressure_gain;
         Ok(())
     }
 
@@ -693,6 +884,28 @@ impl ThermometerCapable for Bmp280SysfsDriver {
         self._set_interval(interval_id)
     }
 
+    fn get_calibration(&self, reading: CapabilityId) -> Result<ReadingCalibration, DeviceError> {
+        match reading {
+            CapabilityId::Thermometer => Ok(self.temperature_calibration),
+            CapabilityId::Barometer => Ok(self.pressure_calibration),
+            _ => Err(DeviceError::NotSupported)
+        }
+    }
+
+    fn set_calibration(&mut self, reading: CapabilityId, calibration: ReadingCalibration) -> Result<(), DeviceError> {
+        let target = match reading {
+            CapabilityId::Thermometer => &mut self.temperature_calibration,
+            CapabilityId::Barometer => &mut self.pressure_calibration,
+            _ => return Err(DeviceError::NotSupported)
+        };
+
+        *target = ReadingCalibration::new(calibration.scale, calibration.offset).map_err(DeviceError::InvalidOperation)?;
+        Ok(())
+    }
+}
+
+#[cast_to]
+impl ThermometerCapable for Bmp280SysfsDriver {
     fn get_temperature_celsius(&mut self) -> Result<f32, DeviceError> {
         let (temp, _) = self.get_sensor_data()?; 
         Ok(temp)
@@ -702,71 +915,73 @@ impl ThermometerCapable for Bmp280SysfsDriver {
         let temp = self.get_temperature_celsius()?;
         Ok(temp * (9.0/5.0) + 32.0)
     }
+
+    fn reset_filter(&mut self) -> Result<(), DeviceError> {
+        self.temperature_filter.reset();
+        Ok(())
+    }
+
+    fn get_units(&self) -> Vec<ReadingUnit> {
+        vec![
+            ReadingUnit::new("temperature_celsius", "°C", MIN_TEMPERATURE_C, MAX_TEMPERATURE_C),
+            ReadingUnit::new("temperature_fahrenheit", "°F", MIN_TEMPERATURE_C * (9.0/5.0) + 32.0, MAX_TEMPERATURE_C * (9.0/5.0) + 32.0),
+        ]
+    }
 }
 
 #[cast_to]
 impl BarometerCapable for Bmp280SysfsDriver {
-    fn get_supported_gains(&self) -> HashMap<u8, u16> {
-        self._get_supported_gains()
+    fn get_pressure(&mut self) -> Result<f32, DeviceError> {
+        let (_, press) = self.get_sensor_data()?; 
+        Ok(press)
     }
 
-    fn get_supported_intervals(&self) -> HashMap<u8, u16> {
-        self._get_supported_intervals()
+    fn get_altitude(&mut self) -> Result<f32, DeviceError> {
+        let (temp, press) = self.get_sensor_data()?;
+        Ok(hypsometric_altitude(press / 100.0, self.reference_pressure, temp))
     }
 
-    fn get_gain(&self) -> Result<u16, DeviceError> {
-        self.assert_state(false)?;
-        Ok(self.pressure_gain.into_multiplier())
+    fn get_reference_pressure(&self) -> Result<f32, DeviceError> {
+        Ok(self.reference_pressure)
     }
 
-    fn set_gain(&mut self, gain_id: u8) -> Result<(), DeviceError> {
-        self.assert_state(true)?;
-        let gain_multiplier = match SUPPORTED_GAIN_VALUES.get(gain_id as usize) {
-            Some(gain) => gain,
-            None => {
-                return Err(DeviceError::InvalidOperation(format!(
-                    "gain value ID is not supported: {}",
-                    gain_id
-                )))
-            }
-        };
-
-        let gain_value = match GainValue::from_multiplier(*gain_multiplier) {
-            Some(gain) => gain,
-            None => {
-                error!("Failed to convert a gain multiplier of {}x to a GainValue because it is unsupported, but it is being offered in the list of supported gain values", gain_multiplier);
-                return Err(DeviceError::Internal);
-            },
-        };
-
-        let address = self.config.device_address;
-        let mut transaction = self.bus.as_ref().unwrap().lock();
-        wait_adc_valid(&mut transaction, address, SPINWAIT_INTERVAL, self.standby_time.into_millis() + SPINWAIT_INTERVAL)?;
-        set_mode_and_gain(&mut transaction, address, self.thermometer_gain, gain_value, PowerMode::Normal)
-            .map_err(|e| DeviceError::HardwareError(format!("failed to apply new gain value: {}", e)))?;
-
-        self.pressure_gain = gain_value;
+    fn set_reference_pressure(&mut self, pressure_hpa: f32) -> Result<(), DeviceError> {
+        check_reference_pressure(pressure_hpa).map_err(DeviceError::InvalidOperation)?;
+        self.reference_pressure = pressure_hpa;
         Ok(())
     }
 
-    fn get_interval(&self) -> Result<u16, DeviceError> {
-        self._get_interval()
+    fn reset_filter(&mut self) -> Result<(), DeviceError> {
+        self.pressure_filter.reset();
+        Ok(())
     }
 
-    fn set_interval(&mut self, interval_id: u8) -> Result<(), DeviceError> {
-        self._set_interval(interval_id)
+    // Pressure is reported in Pa, the altitude range follows from it at the current reference and 15 °C
+    fn get_units(&self) -> Vec<ReadingUnit> {
+        vec![
+            ReadingUnit::new("pressure", "Pa", MIN_PRESSURE_PA, MAX_PRESSURE_PA),
+            ReadingUnit::new(
+                "altitude",
+                "m",
+                hypsometric_altitude(MAX_PRESSURE_PA / 100.0, self.reference_pressure, 15.0),
+                hypsometric_altitude(MIN_PRESSURE_PA / 100.0, self.reference_pressure, 15.0),
+            ),
+        ]
     }
+}
 
-    fn get_pressure(&mut self) -> Result<f32, DeviceError> {
-        let (_, press) = self.get_sensor_data()?; 
-        Ok(press)
-    }
+#[cast_to]
+impl DiagnosticsCapable for Bmp280SysfsDriver {
+    fn self_test(&mut self) -> Result<HealthReport, DeviceError> {
+        if self.assert_state(true).is_err() {
+            return Ok(HealthReport::new(None, CHIP_ID, self.last_error.as_ref(), Duration::ZERO));
+        }
 
-    fn get_altitude(&mut self) -> Result<f32, DeviceError> {
-        let pressure = self.get_pressure()?;
-        let pressure_at_sea_level = self.config.pressure_at_sea_level as f32;
-        let altitude = (1.0 - (pressure / pressure_at_sea_level).powf(1.0 / 5.257)) * 44330.77;
+        let chip_id = {
+            let mut transaction = self.bus.as_ref().unwrap().lock();
+            get_chip_id(&mut *transaction, self.address, self.pec)
+        };
 
-        Ok(altitude)
+        Ok(HealthReport::from_chip_id_read(chip_id, CHIP_ID, &mut self.last_error, self.started_at))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,50 @@
+// Exponential moving average, alpha is the weight of the newest sample and 0 turns the filter off
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmaFilter {
+    alpha: f32,
+    value: Option<f32>,
+}
+
+impl EmaFilter {
+    pub fn new(alpha: f32) -> Self {
+        Self { alpha, value: None }
+    }
+
+    pub fn validate_alpha(alpha: f32) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&alpha) {
+            return Err(format!("smoothing alpha must be between 0 and 1, got {}", alpha));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.alpha > 0.0
+    }
+
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
+
+    pub fn value(&self) -> Option<f32> {
+        self.value
+    }
+
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        if !self.is_enabled() {
+            return sample;
+        }
+
+        let value = match self.value {
+            Some(last) => self.alpha * sample + (1.0 - self.alpha) * last,
+            None => sample,
+        };
+
+        self.value = Some(value);
+        value
+    }
+
+    pub fn reset(&mut self) {
+        self.value = None;
+    }
+}
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+// mean earth radius used by the haversine distance
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+// Region in decimal degrees, rectangles don't wrap around the antimeridian
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "shape")]
+pub enum GeofenceRegion {
+    Circle {
+        latitude: f64,
+        longitude: f64,
+        radius_meters: f64,
+    },
+    Rectangle {
+        min_latitude: f64,
+        min_longitude: f64,
+        max_latitude: f64,
+        max_longitude: f64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Geofence {
+    pub name: String,
+    #[serde(flatten)]
+    pub region: GeofenceRegion,
+}
+
+fn check_coordinate(latitude: f64, longitude: f64) -> Result<(), String> {
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err(format!("coordinate {}, {} is out of range", latitude, longitude));
+    }
+
+    Ok(())
+}
+
+// Great-circle distance between two points
+pub fn distance_meters(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (from.0.to_radians(), to.0.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (to.1 - from.1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
+impl GeofenceRegion {
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        match *self {
+            GeofenceRegion::Circle { latitude: center_lat, longitude: center_lon, radius_meters } => {
+                distance_meters((center_lat, center_lon), (latitude, longitude)) <= radius_meters
+            }
+            GeofenceRegion::Rectangle { min_latitude, min_longitude, max_latitude, max_longitude } => {
+                (min_latitude..=max_latitude).contains(&latitude) && (min_longitude..=max_longitude).contains(&longitude)
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            GeofenceRegion::Circle { latitude, longitude, radius_meters } => {
+                check_coordinate(latitude, longitude)?;
+                if !radius_meters.is_finite() || radius_meters <= 0.0 {
+                    return Err(format!("radius must be greater than zero, got {}", radius_meters));
+                }
+            }
+            GeofenceRegion::Rectangle { min_latitude, min_longitude, max_latitude, max_longitude } => {
+                check_coordinate(min_latitude, min_longitude)?;
+                check_coordinate(max_latitude, max_longitude)?;
+                if min_latitude > max_latitude || min_longitude > max_longitude {
+                    return Err("minimum corner is above the maximum corner".to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn validate_geofences(fences: &[Geofence]) -> Result<(), String> {
+    for (i, fence) in fences.iter().enumerate() {
+        if fence.name.trim().is_empty() {
+            return Err("geofence names cannot be empty".to_string());
+        }
+
+        if fences[..i].iter().any(|x| x.name == fence.name) {
+            return Err(format!("geofence \"{}\" is defined more than once", fence.name));
+        }
+
+        fence.region.validate().map_err(|e| format!("invalid geofence \"{}\": {}", fence.name, e))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeofenceTransition {
+    Entered(String),
+    Exited(String),
+}
+
+// Which fences the last known position was inside of
+#[derive(Debug, Clone, Default)]
+pub struct GeofenceTracker {
+    fences: Vec<Geofence>,
+    inside: Vec<bool>,
+}
+
+impl GeofenceTracker {
+    pub fn new(fences: Vec<Geofence>) -> Self {
+        let inside = vec![false; fences.len()];
+        Self { fences, inside }
+    }
+
+    // Fences entered or left since the last position
+    pub fn update(&mut self, latitude: f64, longitude: f64) -> Vec<GeofenceTransition> {
+        let mut transitions = Vec::new();
+        for (fence, inside) in self.fences.iter().zip(self.inside.iter_mut()) {
+            let now_inside = fence.region.contains(latitude, longitude);
+            if now_inside == *inside {
+                continue;
+            }
+
+            *inside = now_inside;
+            transitions.push(match now_inside {
+                true => GeofenceTransition::Entered(fence.name.clone()),
+                false => GeofenceTransition::Exited(fence.name.clone()),
+            });
+        }
+
+        transitions
+    }
+
+    pub fn active(&self) -> Vec<String> {
+        self.fences.iter().zip(&self.inside).filter(|(_, inside)| **inside).map(|(fence, _)| fence.name.clone()).collect()
+    }
+}
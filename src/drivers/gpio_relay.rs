@@ -0,0 +1,251 @@
+use crate::{
+    bus::raw::{OutputMode, RawBusController},
+    capabilities::{Capability, SwitchCapable},
+    config::{ConfigError, DeviceConfig},
+    device::{DeviceDriver, DeviceError, DeviceServer},
+};
+use intertrait::cast_to;
+use log::{debug, warn};
+use rppal::gpio::OutputPin;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GpioRelayConfig {
+    pub pin: u8,
+    // For relay boards that switch on when the line is pulled low
+    #[serde(default)]
+    pub active_low: bool,
+    // Safe state the relay is put in when the device is started and stopped
+    #[serde(default)]
+    pub default_state: bool,
+}
+
+// The line driving the relay, split out so the switching logic doesn't depend on a real pin
+pub trait RelayOutput: Send + Sync {
+    fn set_line(&mut self, high: bool) -> Result<(), DeviceError>;
+    // Keeps the last level on the line once the output is dropped instead of letting it float
+    fn keep_level_on_release(&mut self);
+}
+
+impl RelayOutput for OutputPin {
+    fn set_line(&mut self, high: bool) -> Result<(), DeviceError> {
+        match high {
+            true => self.set_high(),
+            false => self.set_low(),
+        }
+
+        Ok(())
+    }
+
+    // rppal puts the pin back to its previous mode on drop, which would let the relay switch after an unload
+    fn keep_level_on_release(&mut self) {
+        self.set_reset_on_drop(false);
+    }
+}
+
+pub struct GpioRelay {
+    config: GpioRelayConfig,
+    output: Option<Box<dyn RelayOutput>>,
+    state: bool,
+    is_loaded: bool,
+}
+
+impl GpioRelay {
+    fn from_config(config: GpioRelayConfig) -> Self {
+        let state = config.default_state;
+        Self {
+            config,
+            output: None,
+            state,
+            is_loaded: false,
+        }
+    }
+
+    fn assert_state(&self) -> Result<(), DeviceError> {
+        if self.is_loaded && self.output.is_some() {
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidOperation(
+                "device is in an invalid state".to_string(),
+            ))
+        }
+    }
+
+    // Line level that puts the relay in the given state
+    pub fn line_level(&self, on: bool) -> bool {
+        on != self.config.active_low
+    }
+
+    // Takes over the output and puts the relay in its safe state, start() does this with the pin from the raw controller
+    pub fn attach(&mut self, mut output: Box<dyn RelayOutput>) -> Result<(), DeviceError> {
+        output.keep_level_on_release();
+        output.set_line(self.line_level(self.config.default_state))?;
+        self.output = Some(output);
+        self.state = self.config.default_state;
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    // Puts the relay back in its safe state before handing the output back
+    pub fn detach(&mut self) -> Option<Box<dyn RelayOutput>> {
+        let mut output = self.output.take()?;
+        if let Err(e) = output.set_line(self.line_level(self.config.default_state)) {
+            warn!("Failed to put relay in its default state: {}", e);
+        }
+
+        self.state = self.config.default_state;
+        self.is_loaded = false;
+        Some(output)
+    }
+}
+
+impl DeviceDriver for GpioRelay {
+    fn name(&self) -> String {
+        "gpio_relay".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        if config.is_none() {
+            return Err(DeviceError::invalid_config("this driver requires a configuration object but none was provided"));
+        }
+
+        let config = config.unwrap();
+        let data: GpioRelayConfig = match serde_json::from_value(config.driver_data.clone()) {
+            Ok(d) => d,
+            Err(e) => {
+                if config.driver_data == Value::Null {
+                    match serde_json::to_value(GpioRelayConfig::default()) {
+                        Ok(c) => {
+                            config.driver_data = c;
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    "device was missing config data, default config was written"
+                                        .to_string(),
+                                )
+                                .to_string()
+                            ));
+                        }
+                        Err(e) => {
+                            warn!("Failed to write default configuration: {}", e);
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    format!("device was missing config data, default config failed to be written: {}", e)
+                                ).to_string()
+                            ));
+                        }
+                    }
+                }
+
+                return Err(DeviceError::invalid_config(
+                    ConfigError::SerializeError(format!(
+                        "failed to deserialize device config data: {}",
+                        e
+                    ))
+                    .to_string()
+                ));
+            }
+        };
+
+        Ok(Self::from_config(data))
+    }
+
+    fn start(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device load requested but this device is already loaded".to_string(),
+            ));
+        }
+
+        let mut gpio = match parent.get_bus_mut::<RawBusController>() {
+            Some(bus) => bus,
+            None => return Err(DeviceError::MissingController("raw".to_string())),
+        };
+
+        // opened at the safe level so the relay doesn't click on while starting
+        let mode = match self.line_level(self.config.default_state) {
+            true => OutputMode::LogicHigh,
+            false => OutputMode::LogicLow,
+        };
+
+        let pin = match gpio.open_out(self.config.pin, mode) {
+            Ok(pin) => pin,
+            Err(e) => {
+                return Err(DeviceError::hardware_error(format!(
+                    "could not get relay pin: {}",
+                    e
+                )))
+            }
+        };
+
+        if let Err(e) = self.attach(Box::new(pin)) {
+            if let Err(e) = gpio.close(self.config.pin) {
+                warn!("Failed to release relay pin: {}", e);
+            }
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if !self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device unload requested but this device isn't loaded".to_string(),
+            ));
+        }
+
+        // the safe state goes out first, even if the pin can't be released afterwards
+        if self.detach().is_some() {
+            match parent.get_bus_mut::<RawBusController>() {
+                Some(mut gpio) => {
+                    if let Err(e) = gpio.close(self.config.pin) {
+                        warn!("Failed to release relay pin while shutting down: {}", e);
+                    }
+                }
+                None => warn!("Raw GPIO controller is gone, relay pin was not released"),
+            }
+        }
+
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn required_controllers(&self) -> Vec<&'static str> {
+        vec!["raw"]
+    }
+}
+
+impl Capability for GpioRelay {}
+
+#[cast_to]
+impl SwitchCapable for GpioRelay {
+    fn get_state(&self) -> Result<bool, DeviceError> {
+        self.assert_state()?;
+        Ok(self.state)
+    }
+
+    fn set_state(&mut self, on: bool) -> Result<(), DeviceError> {
+        self.assert_state()?;
+        let level = self.line_level(on);
+        self.output.as_mut().unwrap().set_line(level)?;
+
+        debug!("new relay state: {}", on);
+        self.state = on;
+        Ok(())
+    }
+}
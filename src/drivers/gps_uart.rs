@@ -1,24 +1,45 @@
 use crate::{
-    bus::uart::UARTBusController,
-    device::{DeviceDriver, DeviceError}, config::{DeviceConfig, ConfigError}, capabilities::{GpsCapable, Capability},
+    bus::uart::{FlowControl, UARTBusController},
+    device::{DeviceDriver, DeviceError, DeviceLink}, config::{DeviceConfig, ConfigError}, capabilities::{GpsCapable, Capability},
+    drivers::geofence::{self, Geofence, GeofenceTracker, GeofenceTransition},
+    events::{DeviceEventKind, DeviceEventSink},
 };
 use intertrait::cast_to;
-use log::{debug, warn};
+use log::{debug, info, warn};
 use nmea::{Nmea, Satellite};
-use parking_lot::{Mutex, MutexGuard};
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
 use rppal::uart::Uart;
 use serde::{Serialize, Deserialize};
 use serde_json::Value;
 use std::{
     any::Any,
+    path::Path,
     sync::{mpsc, Arc},
     thread,
-    time::Duration
+    time::{Duration, Instant}
 };
 
 const WORKER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
-const CYCLE_BUFFER_SIZE: usize = 256;
+const DEFAULT_READ_BUFFER_SIZE: usize = 256;
+const MAX_READ_BUFFER_SIZE: usize = 16384;
+const DEFAULT_BAROMETER_WEIGHT: f32 = 0.7;
+const DEFAULT_FIX_STALENESS_MS: u32 = 5000;
+// a full buffer means more data is waiting, keep reading up to this many times before sleeping
+const MAX_READS_PER_CYCLE: usize = 16;
 const MAX_PRECISION_DILUTION: f32 = 20.0;
+const AUTODETECT_BAUD_RATES: [u32; 3] = [9600, 38400, 115200];
+const AUTODETECT_WINDOW: Duration = Duration::from_millis(1500);
+const AUTODETECT_READ_TIMEOUT: Duration = Duration::from_millis(100);
+const AUTODETECT_MAX_PENDING: usize = DEFAULT_READ_BUFFER_SIZE * 4;
+// upper bound on a blocking read in continuous mode, also how long a shutdown request can go unnoticed
+const CONTINUOUS_READ_TIMEOUT: Duration = Duration::from_millis(100);
+// wait after a failed read, doubled on every further failure in a row
+const READ_ERROR_BACKOFF: Duration = Duration::from_millis(20);
+const MAX_READ_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+// failed reads in a row before the receiver is treated as unplugged and the port is reopened
+pub(crate) const DISCONNECT_ERROR_THRESHOLD: u32 = 5;
+// reopen attempts without a successful read in between before the worker gives up
+pub(crate) const MAX_REOPEN_ATTEMPTS: u32 = 3;
 
 // Serializeable implementation of the rppal parity
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -35,6 +56,15 @@ pub enum Parity {
     Space,
 }
 
+// Polled reads whatever is buffered every polling interval, Continuous blocks on the port and
+// parses data as it arrives, for receivers that send more than the UART buffer holds in one interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ReadMode {
+    #[default]
+    Polled,
+    Continuous,
+}
+
 impl From<Parity> for rppal::uart::Parity {
     fn from(value: Parity) -> Self {
         match value {
@@ -54,8 +84,43 @@ pub struct UartGpsConfig {
     pub parity: Parity,
     pub data_bits: u8,
     pub stop_bits: u8,
+    // RTS/CTS needs the pins set on the UART port definition
+    #[serde(default)]
+    pub flow_control: FlowControl,
     pub polling_interval_ms: u32,
-    pub peak_accuracy_meters: f32
+    pub peak_accuracy_meters: f32,
+    #[serde(default)]
+    pub read_mode: ReadMode,
+    // probe common baud rates on start, also done when baud_rate is 0
+    #[serde(default)]
+    pub autodetect: bool,
+    // bytes read from the UART per call, raise it for receivers that send several constellations at high rates
+    #[serde(default = "default_read_buffer_size")]
+    pub read_buffer_size: usize,
+    // name or address of a barometer for fused altitude, list it in depends_on so it's started first
+    #[serde(default)]
+    pub barometer: Option<String>,
+    // share of the fused altitude taken from the barometer, it drifts less than GPS altitude over short periods
+    #[serde(default = "default_barometer_weight")]
+    pub barometer_weight: f32,
+    // a fix is reported as lost when no sentence was parsed for this long, e.g. after the antenna is covered
+    #[serde(default = "default_fix_staleness_ms")]
+    pub fix_staleness_ms: u32,
+    // regions that raise an event when the receiver enters or leaves them
+    #[serde(default)]
+    pub geofences: Vec<Geofence>
+}
+
+fn default_read_buffer_size() -> usize {
+    DEFAULT_READ_BUFFER_SIZE
+}
+
+fn default_barometer_weight() -> f32 {
+    DEFAULT_BAROMETER_WEIGHT
+}
+
+fn default_fix_staleness_ms() -> u32 {
+    DEFAULT_FIX_STALENESS_MS
 }
 
 impl Default for UartGpsConfig {
@@ -66,134 +131,558 @@ impl Default for UartGpsConfig {
             parity: Parity::None,
             data_bits: 8,
             stop_bits: 1,
+            flow_control: FlowControl::None,
             polling_interval_ms: 1000,
-            peak_accuracy_meters: 3.0
+            peak_accuracy_meters: 3.0,
+            read_mode: ReadMode::Polled,
+            autodetect: false,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            barometer: None,
+            barometer_weight: DEFAULT_BAROMETER_WEIGHT,
+            fix_staleness_ms: DEFAULT_FIX_STALENESS_MS,
+            geofences: Vec::new()
+        }
+    }
+}
+
+// Parsed receiver state shared between the worker and the driver
+#[derive(Default)]
+pub(crate) struct GpsState {
+    pub(crate) nmea: Nmea,
+    last_update: Option<Instant>,
+    // set once the worker gave up on a disconnected receiver
+    failure: Option<String>,
+    pub(crate) geofences: GeofenceTracker
+}
+
+impl GpsState {
+    pub(crate) fn with_geofences(fences: Vec<Geofence>) -> Self {
+        Self { geofences: GeofenceTracker::new(fences), ..Default::default() }
+    }
+
+    // Checks the current position against the geofences, nothing changes while there is no position
+    pub(crate) fn update_geofences(&mut self) -> Vec<GeofenceTransition> {
+        match (self.nmea.latitude, self.nmea.longitude) {
+            (Some(latitude), Some(longitude)) => self.geofences.update(latitude, longitude),
+            _ => Vec::new()
+        }
+    }
+
+    // Drops everything learned from the receiver, a reconnected receiver starts from scratch
+    pub(crate) fn reset(&mut self) {
+        self.nmea = Nmea::default();
+        self.last_update = None;
+    }
+
+    pub(crate) fn failure(&self) -> Option<&str> {
+        self.failure.as_deref()
+    }
+
+    pub(crate) fn parse(&mut self, sentence: &str, now: Instant) -> bool {
+        match self.nmea.parse(sentence) {
+            Ok(_) => {
+                self.last_update = Some(now);
+                true
+            },
+            Err(err) => {
+                debug!("Failed to parse sentence: \"{}\": {}", sentence, err);
+                false
+            }
+        }
+    }
+
+    // Time since the last parsed sentence, Duration::MAX before the first one
+    pub(crate) fn fix_age(&self, now: Instant) -> Duration {
+        match self.last_update {
+            Some(updated) => now.saturating_duration_since(updated),
+            None => Duration::MAX
         }
     }
+
+    pub(crate) fn has_fix(&self, now: Instant, staleness: Duration) -> bool {
+        self.nmea.fix_date.is_some() && self.fix_age(now) <= staleness
+    }
+}
+
+// Checks the framing and XOR checksum of a single NMEA sentence
+pub(crate) fn is_valid_nmea(sentence: &str) -> bool {
+    let body = match sentence.trim().strip_prefix('$') {
+        Some(body) => body,
+        None => return false
+    };
+
+    let (data, checksum) = match body.rsplit_once('*') {
+        Some((data, checksum)) if !data.is_empty() && checksum.len() == 2 => (data, checksum),
+        _ => return false
+    };
+
+    match u8::from_str_radix(checksum, 16) {
+        Ok(expected) => data.bytes().fold(0u8, |acc, x| acc ^ x) == expected,
+        Err(_) => false
+    }
 }
 
-enum WorkerMessage {
+// Splits received bytes into sentences, a sentence cut off at the end of a read is kept until the rest arrives
+pub(crate) struct SentenceAssembler {
+    pending: Vec<u8>,
+    max_pending: usize
+}
+
+impl SentenceAssembler {
+    pub(crate) fn new(max_pending: usize) -> Self {
+        Self { pending: Vec::new(), max_pending }
+    }
+
+    pub(crate) fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(data);
+
+        let mut sentences = Vec::new();
+        while let Some(index) = self.pending.iter().position(|x| *x == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=index).collect();
+            let sentence = String::from_utf8_lossy(&line).trim().to_string();
+            if !sentence.is_empty() {
+                sentences.push(sentence);
+            }
+        }
+
+        // a receiver that never sends a newline shouldn't grow this forever
+        if self.pending.len() > self.max_pending {
+            warn!("Dropping {} bytes of GPS data without a sentence terminator", self.pending.len());
+            self.pending.clear();
+        }
+
+        sentences
+    }
+
+    pub(crate) fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+}
+
+pub(crate) trait BaudProbe {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), DeviceError>;
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError>;
+}
+
+// Listens at each rate for up to window and settles on the first one that yields a valid sentence
+pub(crate) fn detect_baud_rate<T: BaudProbe>(probe: &mut T, rates: &[u32], window: Duration) -> Result<Option<u32>, DeviceError> {
+    let mut buffer = [0u8; DEFAULT_READ_BUFFER_SIZE];
+    for &rate in rates {
+        debug!("Probing GPS at {} baud", rate);
+        probe.set_baud_rate(rate)?;
+
+        let deadline = Instant::now() + window;
+        let mut pending = String::new();
+        while Instant::now() < deadline {
+            let bytes_read = match probe.read(&mut buffer) {
+                Ok(count) => count,
+                Err(err) => {
+                    debug!("Read failed while probing {} baud: {}", rate, err);
+                    break;
+                }
+            };
+
+            pending.push_str(&String::from_utf8_lossy(&buffer[0..bytes_read]));
+            while let Some(index) = pending.find('\n') {
+                let sentence: String = pending.drain(..=index).collect();
+                if is_valid_nmea(&sentence) {
+                    return Ok(Some(rate));
+                }
+            }
+
+            // Line noise at the wrong rate rarely contains a newline
+            if pending.len() > AUTODETECT_MAX_PENDING {
+                pending.clear();
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+struct UartProbe<'a> {
+    controller: &'a mut UARTBusController,
+    device: &'a mut Uart,
+    config: &'a UartGpsConfig
+}
+
+impl BaudProbe for UartProbe<'_> {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), DeviceError> {
+        self.controller.reconfigure(
+            self.config.uart_port,
+            &mut *self.device,
+            baud_rate,
+            self.config.parity.clone().into(),
+            self.config.data_bits,
+            self.config.stop_bits
+        ).map_err(|e| DeviceError::hardware_error(format!("could not reconfigure uart channel: {}", e)))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        self.device.read(buf)
+            .map_err(|e| DeviceError::hardware_error(format!("failed to read from uart channel: {}", e)))
+    }
+}
+
+// Where the worker reads NMEA data from, implemented by rppal's Uart
+pub(crate) trait NmeaSource: Send {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError>;
+
+    // Opens the device again after it went away, e.g. a USB receiver that was unplugged
+    fn reopen(&mut self) -> Result<(), DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+}
+
+impl NmeaSource for Uart {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        Uart::read(self, buf)
+            .map_err(|e| DeviceError::hardware_error(format!("failed to read from uart channel: {}", e)))
+    }
+}
+
+// Port the worker reads from along with what it takes to open it again.
+// The port stays leased on the UART controller, only the device node is reopened.
+struct UartSource {
+    uart: Uart,
+    path: String,
+    baud_rate: u32,
+    parity: Parity,
+    data_bits: u8,
+    stop_bits: u8,
+    flow_control: FlowControl,
+    read_timeout: Duration
+}
+
+impl NmeaSource for UartSource {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        NmeaSource::read(&mut self.uart, buf)
+    }
+
+    fn reopen(&mut self) -> Result<(), DeviceError> {
+        let map_err = |e: rppal::uart::Error| {
+            DeviceError::hardware_error(format!("could not reopen uart channel at {}: {}", self.path, e))
+        };
+
+        let mut uart = Uart::with_path(
+            Path::new(&self.path),
+            self.baud_rate,
+            self.parity.clone().into(),
+            self.data_bits,
+            self.stop_bits
+        ).map_err(map_err)?;
+
+        if self.flow_control == FlowControl::RtsCts {
+            uart.set_hardware_flow_control(true).map_err(map_err)?;
+        }
+
+        uart.set_read_mode(0, self.read_timeout).map_err(map_err)?;
+        self.uart = uart;
+        Ok(())
+    }
+}
+
+// How long to wait after the given number of failed reads in a row
+pub(crate) fn read_error_backoff(consecutive_errors: u32) -> Duration {
+    let doublings = consecutive_errors.saturating_sub(1).min(16);
+    READ_ERROR_BACKOFF.saturating_mul(1 << doublings).min(MAX_READ_ERROR_BACKOFF)
+}
+
+enum Reconnect {
+    Reopened,
+    Failed,
+    Shutdown
+}
+
+pub(crate) enum WorkerMessage {
     Shutdown,
 }
 
-struct GpsWorker {
-    device: Uart,
+pub(crate) struct GpsWorker<T: NmeaSource> {
+    device: T,
     command_channel: mpsc::Receiver<WorkerMessage>,
     shutdown_callback: mpsc::Sender<()>,
     poll_interval: u32,
-    state: Arc<Mutex<Nmea>>
+    buffer_size: usize,
+    read_mode: ReadMode,
+    state: Arc<Mutex<GpsState>>,
+    events: Option<DeviceEventSink>,
+    consecutive_errors: u32,
+    reopen_attempts: u32
 }
 
-impl GpsWorker {
-    fn new(
-        device: Uart,
+impl<T: NmeaSource> GpsWorker<T> {
+    pub(crate) fn new(
+        device: T,
         command_channel: mpsc::Receiver<WorkerMessage>,
         shutdown_callback: mpsc::Sender<()>,
         poll_interval: u32,
-        state: Arc<Mutex<Nmea>>
+        buffer_size: usize,
+        read_mode: ReadMode,
+        state: Arc<Mutex<GpsState>>
     ) -> Self {
         Self {
             device,
             command_channel,
             shutdown_callback,
             poll_interval,
-            state
+            buffer_size,
+            read_mode,
+            state,
+            events: None,
+            consecutive_errors: 0,
+            reopen_attempts: 0
         }
     }
 
-    fn run(&mut self) {
-        let mut buffer = [0u8; CYCLE_BUFFER_SIZE];
-        let mut partial_data = String::new();
+    pub(crate) fn with_events(mut self, events: DeviceEventSink) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    // Bytes read, None when the read failed
+    fn read_once(&mut self, buffer: &mut [u8], assembler: &mut SentenceAssembler) -> Option<usize> {
+        let bytes_read = match self.device.read(buffer) {
+            Ok(count) => count,
+            Err(err) => {
+                self.consecutive_errors += 1;
+                warn!("Failed to read data from device ({} in a row): {}", self.consecutive_errors, err);
+                return None;
+            }
+        };
+
+        self.consecutive_errors = 0;
+        self.reopen_attempts = 0;
+        let sentences = assembler.push(&buffer[0..bytes_read]);
+        if sentences.is_empty() {
+            return Some(bytes_read);
+        }
+
+        let transitions = {
+            let mut state = self.state.lock();
+            for sentence in sentences {
+                state.parse(&sentence, Instant::now());
+            }
+
+            state.update_geofences()
+        };
+
+        if let Some(events) = &self.events {
+            for transition in transitions {
+                events.emit(match transition {
+                    GeofenceTransition::Entered(geofence) => DeviceEventKind::GeofenceEntered { geofence },
+                    GeofenceTransition::Exited(geofence) => DeviceEventKind::GeofenceExited { geofence }
+                });
+            }
+        }
+
+        Some(bytes_read)
+    }
+
+    // Returns true when the worker should exit
+    fn handle_command(&self, command: WorkerMessage) -> bool {
+        match command {
+            WorkerMessage::Shutdown => {
+                debug!("Worker received shutdown request");
+                let _ = self.shutdown_callback.send(());
+                true
+            },
+        }
+    }
+
+    // Waits up to timeout for a command, returns true when the worker should exit
+    fn wait_for_command(&self, timeout: Duration) -> bool {
+        match self.command_channel.recv_timeout(timeout) {
+            Ok(command) => self.handle_command(command),
+            Err(mpsc::RecvTimeoutError::Timeout) => false,
+            Err(mpsc::RecvTimeoutError::Disconnected) => true
+        }
+    }
+
+    // Reopens the port after a disconnect, the receiver state is cleared first since it belongs to the old connection
+    fn reconnect(&mut self) -> Reconnect {
+        warn!("GPS receiver looks disconnected after {} failed reads, reopening the port", self.consecutive_errors);
+        self.state.lock().reset();
+
+        while self.reopen_attempts < MAX_REOPEN_ATTEMPTS {
+            self.reopen_attempts += 1;
+            if self.wait_for_command(read_error_backoff(self.reopen_attempts)) {
+                return Reconnect::Shutdown;
+            }
+
+            match self.device.reopen() {
+                Ok(_) => {
+                    info!("Reopened GPS receiver port");
+                    self.consecutive_errors = 0;
+                    return Reconnect::Reopened;
+                },
+                Err(e) => warn!("Failed to reopen GPS receiver port (attempt {} of {}): {}", self.reopen_attempts, MAX_REOPEN_ATTEMPTS, e)
+            }
+        }
+
+        Reconnect::Failed
+    }
+
+    pub(crate) fn run(&mut self) {
+        let mut buffer = vec![0u8; self.buffer_size];
+        let mut assembler = SentenceAssembler::new(self.buffer_size * 4);
         let poll_interval = Duration::from_millis(self.poll_interval as u64);
         loop {
-            // Process Nmea data
-            match self.device.read(&mut buffer) {
-                Ok(bytes_read) => {
-                    let received_data = String::from_utf8_lossy(&buffer[0..bytes_read]);
-                    partial_data.push_str(&received_data);
-
-                    let sentences: Vec<&str> = partial_data.split('\n').collect();
-                    for i in 0..sentences.len() - 1 {
-                        let sentence = sentences[i].trim();
-                        if sentence.is_empty() {
-                            warn!("Received an empty NMEA sentence, this is very weird.");
-                            continue;
+            let mut wait = match self.read_mode {
+                // the read blocks until data arrives, commands are only checked in between
+                ReadMode::Continuous => {
+                    self.read_once(&mut buffer, &mut assembler);
+                    Duration::ZERO
+                },
+                ReadMode::Polled => {
+                    for _ in 0..MAX_READS_PER_CYCLE {
+                        match self.read_once(&mut buffer, &mut assembler) {
+                            Some(bytes_read) if bytes_read == buffer.len() => continue,
+                            _ => break
                         }
-
-                        let mut state = self.state.lock();
-                        if let Err(err) = state.parse(sentence) {
-                            debug!("Failed to parse sentence: \"{}\": {}", sentence, err);
-                        };
                     }
 
-                    partial_data = sentences.last().map(|f| *f).unwrap_or("").to_string();
-                },
-                Err(err) => {
-                    warn!("Failed to read data from device: {}", err);
-                    continue;
+                    debug!("{}", self.state.lock().nmea.to_string());
+                    poll_interval
                 }
             };
 
-            debug!("{}", self.state.lock().to_string());
+            // a failing port returns at once, don't spin on it
+            if self.consecutive_errors > 0 {
+                wait = wait.max(read_error_backoff(self.consecutive_errors));
+            }
+
+            if self.wait_for_command(wait) {
+                return;
+            }
+
+            if self.consecutive_errors < DISCONNECT_ERROR_THRESHOLD {
+                continue;
+            }
 
-            if let Ok(command) =  self.command_channel.recv_timeout(poll_interval) {
-                match command {
-                    WorkerMessage::Shutdown => {
-                        debug!("Worker received shutdown request");
-                        let _ = self.shutdown_callback.send(());
-                        return;
-                    },
+            match self.reconnect() {
+                Reconnect::Reopened => assembler = SentenceAssembler::new(self.buffer_size * 4),
+                Reconnect::Shutdown => return,
+                Reconnect::Failed => {
+                    warn!("Giving up on the GPS receiver after {} reopen attempts", MAX_REOPEN_ATTEMPTS);
+                    self.state.lock().failure = Some(format!(
+                        "GPS receiver disconnected and could not be reopened after {} attempts",
+                        MAX_REOPEN_ATTEMPTS
+                    ));
+
+                    // nothing left to read, only wait for the driver to stop the worker
+                    while !self.wait_for_command(poll_interval.max(CONTINUOUS_READ_TIMEOUT)) {}
+                    return;
                 }
-            };
+            }
         }
     }
 }
 
 pub struct UartGps {
     config: UartGpsConfig,
-    state: Option<Arc<Mutex<Nmea>>>,
+    state: Option<Arc<Mutex<GpsState>>>,
     worker_channel: Option<Mutex<mpsc::Sender<WorkerMessage>>>,
     shutdown_callback: Option<Mutex<mpsc::Receiver<()>>>,
+    detected_baud_rate: Option<u32>,
+    barometer: Option<DeviceLink>,
+    events: Option<DeviceEventSink>,
     is_loaded: bool,
 }
 
 impl UartGps {
     fn from_config(config: UartGpsConfig) -> Result<Self, DeviceError> {
         if config.data_bits < 5 || config.data_bits > 9 {
-            return Err(DeviceError::InvalidConfig(
+            return Err(DeviceError::invalid_config(
                 ConfigError::InvalidEntry("data bit count is out of bounds: only 5-9 data bits are supported".to_string()).to_string()
             ));
         };
 
-        if config.baud_rate == 0 {
-            return Err(DeviceError::InvalidConfig(
-               ConfigError::InvalidEntry("baud rate cannot be 0".to_string()).to_string() 
+        if config.stop_bits != 1 && config.stop_bits != 2 {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("stop bit count can be either 1 or 2".to_string()).to_string()
             ));
         }
 
-        if config.stop_bits != 1 && config.stop_bits != 2 {
-            return Err(DeviceError::InvalidConfig(
-                ConfigError::InvalidEntry("stop bit count can be either 1 or 2".to_string()).to_string()
+        if config.read_buffer_size == 0 || config.read_buffer_size > MAX_READ_BUFFER_SIZE {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry(format!("read buffer size must be between 1 and {} bytes", MAX_READ_BUFFER_SIZE)).to_string()
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&config.barometer_weight) {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("barometer weight must be between 0 and 1".to_string()).to_string()
+            ));
+        }
+
+        if config.fix_staleness_ms == 0 {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("fix staleness must be greater than zero".to_string()).to_string()
             ));
         }
 
+        geofence::validate_geofences(&config.geofences).map_err(|e| {
+            DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string())
+        })?;
+
         Ok(Self {
             config: config,
             state: None,
             worker_channel: None,
             shutdown_callback: None,
+            detected_baud_rate: None,
+            barometer: None,
+            events: None,
             is_loaded: false,
         })
     }
 
-    fn get_state(&self) -> Result<MutexGuard<'_, Nmea>, DeviceError> {
+    fn needs_autodetect(&self) -> bool {
+        self.config.autodetect || self.config.baud_rate == 0
+    }
+
+    fn autodetect_baud_rate(&self, controller: &mut UARTBusController, device: &mut Uart) -> Result<u32, DeviceError> {
+        device.set_read_mode(0, AUTODETECT_READ_TIMEOUT)
+            .map_err(|e| DeviceError::hardware_error(format!("could not set uart read mode: {}", e)))?;
+
+        let mut probe = UartProbe { controller, device: &mut *device, config: &self.config };
+        let detected = detect_baud_rate(&mut probe, &AUTODETECT_BAUD_RATES, AUTODETECT_WINDOW)?;
+
+        // The worker polls without blocking
+        device.set_read_mode(0, Duration::ZERO)
+            .map_err(|e| DeviceError::hardware_error(format!("could not set uart read mode: {}", e)))?;
+
+        match detected {
+            Some(rate) => Ok(rate),
+            None => Err(DeviceError::hardware_error(format!(
+                "no NMEA data received at any of the probed baud rates ({})",
+                AUTODETECT_BAUD_RATES.map(|x| x.to_string()).join(", ")
+            )))
+        }
+    }
+
+    fn get_gps_state(&self) -> Result<MutexGuard<'_, GpsState>, DeviceError> {
         if !self.is_loaded || !self.state.is_some() {
             return Err(DeviceError::InvalidOperation(
                 "device is in an invalid state".to_string(),
             ));
         }
 
-        Ok(self.state.as_ref().unwrap().lock())
+        let state = self.state.as_ref().unwrap().lock();
+        if let Some(failure) = state.failure() {
+            return Err(DeviceError::hardware_error(failure.to_string()));
+        }
+
+        Ok(state)
+    }
+
+    fn get_state(&self) -> Result<MappedMutexGuard<'_, Nmea>, DeviceError> {
+        Ok(MutexGuard::map(self.get_gps_state()?, |state| &mut state.nmea))
+    }
+
+    fn fix_staleness(&self) -> Duration {
+        Duration::from_millis(self.config.fix_staleness_ms as u64)
     }
 }
 
@@ -208,7 +697,7 @@ impl DeviceDriver for UartGps {
 
     fn new(config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
         if config.is_none() {
-            return Err(DeviceError::InvalidConfig("this driver requires a configuration object but none was provided".to_owned()));
+            return Err(DeviceError::invalid_config("this driver requires a configuration object but none was provided"));
         }
 
         let config = config.unwrap();
@@ -219,17 +708,17 @@ impl DeviceDriver for UartGps {
                     match serde_json::to_value(UartGpsConfig::default()) {
                         Ok(c) => {
                             config.driver_data = c;
-                            return Err(DeviceError::InvalidConfig(
+                            return Err(DeviceError::invalid_config(
                                 ConfigError::MissingEntry(
                                     "device was missing config data, default config was written"
                                         .to_string(),
                                 )
-                                .to_string(),
+                                .to_string()
                             ));
                         }
                         Err(e) => {
                             warn!("Failed to write default configuration: {}", e);
-                            return Err(DeviceError::InvalidConfig(
+                            return Err(DeviceError::invalid_config(
                                 ConfigError::MissingEntry(
                                     format!("device was missing config data, default config failed to be written: {}", e)
                                 ).to_string()
@@ -238,12 +727,12 @@ impl DeviceDriver for UartGps {
                     }
                 }
 
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::SerializeError(format!(
                         "failed to deseiralize device config data: {}",
                         e
                     ))
-                    .to_string(),
+                    .to_string()
                 ));
             }
         };
@@ -261,29 +750,84 @@ impl DeviceDriver for UartGps {
             ));
         }
 
+        let barometer = match &self.config.barometer {
+            Some(reference) => Some(parent.link_device(reference)?),
+            None => None
+        };
+
         let mut uart = match parent.get_bus_mut::<UARTBusController>() {
             Some(bus) => bus,
             None => return Err(DeviceError::MissingController("uart".to_string())),
         };
 
         let config = &self.config;
-        let device = match uart.open(
+        let initial_baud_rate = match config.baud_rate {
+            0 => AUTODETECT_BAUD_RATES[0],
+            rate => rate
+        };
+
+        let mut device = match uart.open(
             config.uart_port,
-            config.baud_rate,
+            initial_baud_rate,
             config.parity.clone().into(),
             config.data_bits,
             config.stop_bits,
+            config.flow_control,
         ) {
             Ok(c) => c,
             Err(e) => {
-                return Err(DeviceError::HardwareError(format!(
+                return Err(DeviceError::hardware_error(format!(
                     "could not open uart channel: {}",
                     e
                 )))
             }
         };
 
-        let state = Arc::new(Mutex::new(Nmea::default()));
+        if self.needs_autodetect() {
+            match self.autodetect_baud_rate(&mut uart, &mut device) {
+                Ok(rate) => {
+                    info!("Detected GPS baud rate: {}", rate);
+                    self.config.baud_rate = rate;
+                    self.detected_baud_rate = Some(rate);
+                },
+                Err(e) => {
+                    if let Err(e) = uart.close(self.config.uart_port) {
+                        warn!("Failed to close UART channel after autodetect failed: {}", e);
+                    }
+
+                    return Err(e);
+                }
+            }
+        }
+
+        let read_timeout = match self.config.read_mode {
+            ReadMode::Continuous => CONTINUOUS_READ_TIMEOUT,
+            ReadMode::Polled => Duration::ZERO
+        };
+
+        if self.config.read_mode == ReadMode::Continuous {
+            if let Err(e) = device.set_read_mode(0, read_timeout) {
+                if let Err(e) = uart.close(self.config.uart_port) {
+                    warn!("Failed to close UART channel after setting the read mode failed: {}", e);
+                }
+
+                return Err(DeviceError::hardware_error(format!("could not set uart read mode: {}", e)));
+            }
+        }
+
+        let source = UartSource {
+            uart: device,
+            path: uart.port_path(self.config.uart_port).unwrap_or_default().to_string(),
+            baud_rate: self.config.baud_rate,
+            parity: self.config.parity.clone(),
+            data_bits: self.config.data_bits,
+            stop_bits: self.config.stop_bits,
+            flow_control: self.config.flow_control,
+            read_timeout
+        };
+
+        drop(uart);
+        let state = Arc::new(Mutex::new(GpsState::with_geofences(self.config.geofences.clone())));
         self.state = Some(state.clone());
 
         let (worker_sender, worker_receiver) = mpsc::channel::<WorkerMessage>();
@@ -291,16 +835,28 @@ impl DeviceDriver for UartGps {
         self.worker_channel = Some(Mutex::new(worker_sender));
         self.shutdown_callback = Some(Mutex::new(callback_receiver));
         let poll_interval = self.config.polling_interval_ms;
+        let buffer_size = self.config.read_buffer_size;
+        let read_mode = self.config.read_mode;
+        let events = self.events.clone();
 
         debug!("Spawning worker thread");
         thread::spawn(move || {
-            GpsWorker::new(device, 
+            let mut worker = GpsWorker::new(source, 
                 worker_receiver, 
                 callback_sender,
                 poll_interval,
-            state).run();
+                buffer_size,
+                read_mode,
+            state);
+
+            if let Some(events) = events {
+                worker = worker.with_events(events);
+            }
+
+            worker.run();
         });
 
+        self.barometer = barometer;
         self.is_loaded = true;
         Ok(())
     }
@@ -342,6 +898,7 @@ impl DeviceDriver for UartGps {
 
         self.is_loaded = false;
         self.state = None;
+        self.barometer = None;
 
         Ok(())
     }
@@ -353,6 +910,19 @@ impl DeviceDriver for UartGps {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+
+    fn required_controllers(&self) -> Vec<&'static str> {
+        vec!["uart"]
+    }
+
+    fn set_event_sink(&mut self, events: DeviceEventSink) {
+        self.events = Some(events);
+    }
+
+    fn updated_driver_data(&self) -> Option<Value> {
+        self.detected_baud_rate?;
+        serde_json::to_value(&self.config).ok()
+    }
 }
 
 impl Capability for UartGps {}
@@ -373,8 +943,17 @@ impl GpsCapable for UartGps {
     }
 
     fn has_fix(&self) -> Result<bool, DeviceError> {
-        let state = self.get_state()?;
-        Ok(state.fix_date.is_some())
+        let state = self.get_gps_state()?;
+        Ok(state.has_fix(Instant::now(), self.fix_staleness()))
+    }
+
+    fn get_fix_age(&self) -> Result<Duration, DeviceError> {
+        let state = self.get_gps_state()?;
+        Ok(state.fix_age(Instant::now()))
+    }
+
+    fn get_active_geofences(&self) -> Result<Vec<String>, DeviceError> {
+        Ok(self.get_gps_state()?.geofences.active())
     }
 
     fn get_speed(&self) -> Result<f32, DeviceError> {
@@ -416,4 +995,8 @@ impl GpsCapable for UartGps {
         let acc = self.config.peak_accuracy_meters * dop;
         Ok(acc)
     }
+
+    fn get_barometer(&self) -> Option<(&DeviceLink, f32)> {
+        self.barometer.as_ref().map(|barometer| (barometer, self.config.barometer_weight))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,445 @@
+use i2c_linux::I2c;
+use intertrait::cast_to;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::{Error, Read, Write},
+};
+
+use crate::{
+    bus::bus_lock::BusClient,
+    bus::i2c::{I2cAddress, I2cDeviceConfig},
+    bus::i2c_sysfs::{self, SmbusTransfer, SysfsI2CBusController},
+    capabilities::{Capability, PowerMonitorCapable},
+    config::ConfigError,
+    device::{DeviceDriver, DeviceError},
+};
+type I2cBus = BusClient<I2c<File>>;
+
+const DEFAULT_I2C_ADDR: u16 = 0x40;
+
+const REGISTER_CONFIG: u8 = 0x00;
+const REGISTER_BUS_VOLTAGE: u8 = 0x02;
+const REGISTER_POWER: u8 = 0x03;
+const REGISTER_CURRENT: u8 = 0x04;
+const REGISTER_CALIBRATION: u8 = 0x05;
+
+// Fixed by the chip, see the calibration section of the datasheet
+const CALIBRATION_SCALE: f32 = 0.04096;
+const CURRENT_REGISTER_RANGE: f32 = 32768.0;
+const POWER_LSB_MULTIPLIER: f32 = 20.0;
+const BUS_VOLTAGE_LSB_V: f32 = 0.004;
+const BUS_VOLTAGE_OVERFLOW: u16 = 0x01;
+// f32 rounding puts e.g. 0.1 ohm * 3.2 A just past 0.32 V
+const SHUNT_VOLTAGE_TOLERANCE_V: f32 = 1e-6;
+
+const CONFIG_BUS_RANGE_32V: u16 = 1 << 13;
+const CONFIG_ADC_12BIT: u16 = 0x03;
+const CONFIG_MODE_CONTINUOUS: u16 = 0x07;
+const CONFIG_MODE_POWER_DOWN: u16 = 0x00;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ShuntGain {
+    _40MV = 0x00,
+    _80MV = 0x01,
+    _160MV = 0x02,
+    _320MV = 0x03,
+}
+
+impl ShuntGain {
+    const fn into_range_volts(self) -> f32 {
+        match self {
+            ShuntGain::_40MV => 0.04,
+            ShuntGain::_80MV => 0.08,
+            ShuntGain::_160MV => 0.16,
+            ShuntGain::_320MV => 0.32,
+        }
+    }
+
+    // Picks the most sensitive range that still fits the expected shunt voltage
+    fn for_shunt_voltage(volts: f32) -> Option<Self> {
+        [ShuntGain::_40MV, ShuntGain::_80MV, ShuntGain::_160MV, ShuntGain::_320MV]
+            .into_iter()
+            .find(|x| volts <= x.into_range_volts() + SHUNT_VOLTAGE_TOLERANCE_V)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ina219Calibration {
+    pub register: u16,
+    pub current_lsb_a: f32,
+    pub power_lsb_w: f32,
+}
+
+impl Ina219Calibration {
+    pub fn compute(shunt_resistance_ohms: f32, max_expected_current_a: f32) -> Result<Self, String> {
+        if !shunt_resistance_ohms.is_finite() || shunt_resistance_ohms <= 0.0 {
+            return Err(format!("shunt resistance must be positive, got {} ohms", shunt_resistance_ohms));
+        }
+
+        if !max_expected_current_a.is_finite() || max_expected_current_a <= 0.0 {
+            return Err(format!("max expected current must be positive, got {} A", max_expected_current_a));
+        }
+
+        let current_lsb_a = max_expected_current_a / CURRENT_REGISTER_RANGE;
+        let register = (CALIBRATION_SCALE / (current_lsb_a * shunt_resistance_ohms)).trunc();
+        if register < 1.0 || register > u16::MAX as f32 {
+            return Err(format!(
+                "a {} ohm shunt with a max current of {} A gives an unusable calibration value of {}",
+                shunt_resistance_ohms, max_expected_current_a, register
+            ));
+        }
+
+        // the lowest bit of the calibration register is not writable
+        Ok(Self {
+            register: register as u16 & 0xFFFE,
+            current_lsb_a,
+            power_lsb_w: current_lsb_a * POWER_LSB_MULTIPLIER,
+        })
+    }
+
+    pub fn current_ma(&self, raw: u16) -> f32 {
+        (raw as i16) as f32 * self.current_lsb_a * 1000.0
+    }
+
+    pub fn power_mw(&self, raw: u16) -> f32 {
+        raw as f32 * self.power_lsb_w * 1000.0
+    }
+}
+
+// The voltage sits in the upper 13 bits, the overflow flag means current and power are not valid either
+pub fn bus_voltage_from_register(raw: u16) -> Result<f32, DeviceError> {
+    if raw & BUS_VOLTAGE_OVERFLOW != 0 {
+        return Err(DeviceError::hardware_error("power or current calculation overflowed, the calibration is out of range"));
+    }
+
+    Ok((raw >> 3) as f32 * BUS_VOLTAGE_LSB_V)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ina219SysfsConfig {
+    #[serde(flatten)]
+    pub i2c: I2cDeviceConfig,
+    pub shunt_resistance_ohms: f32,
+    pub max_expected_current_a: f32,
+    // Either a 16 V or a 32 V full scale range
+    pub bus_voltage_range: u8,
+}
+
+impl Default for Ina219SysfsConfig {
+    fn default() -> Self {
+        Self {
+            i2c: I2cDeviceConfig::new(0, DEFAULT_I2C_ADDR),
+            shunt_resistance_ohms: 0.1,
+            max_expected_current_a: 3.2,
+            bus_voltage_range: 32,
+        }
+    }
+}
+
+// helper methods for managing the device
+// The registers are 16 bit and MSB first, so with PEC they go over SMBus word transfers with the bytes swapped
+pub(crate) fn write_register<T: SmbusTransfer + Write + ?Sized>(bus: &mut T, address: I2cAddress, register: u8, value: u16, pec: bool) -> Result<(), Error> {
+    bus.set_slave_address(address)?;
+    if pec {
+        return bus.write_word_data(register, value.swap_bytes());
+    }
+
+    let [msb, lsb] = value.to_be_bytes();
+    bus.write_all(&[register, msb, lsb])?;
+    Ok(())
+}
+
+pub(crate) fn read_register<T: SmbusTransfer + Read + Write + ?Sized>(bus: &mut T, address: I2cAddress, register: u8, pec: bool) -> Result<u16, Error> {
+    if pec {
+        bus.set_slave_address(address)?;
+        return bus.read_word_data(register).map(u16::swap_bytes);
+    }
+
+    let mut buf = [0u8; 2];
+    i2c_sysfs::read_register(bus, address, register, &mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+// Current and power are only valid while the overflow flag in the bus voltage register is clear
+pub(crate) fn read_overflow_checked<T: SmbusTransfer + Read + Write + ?Sized>(bus: &mut T, address: I2cAddress, register: u8, pec: bool) -> Result<u16, DeviceError> {
+    let bus_voltage = read_register(bus, address, REGISTER_BUS_VOLTAGE, pec)
+        .map_err(|e| DeviceError::hardware(format!("failed to read sensor data: {}", e), e))?;
+    bus_voltage_from_register(bus_voltage)?;
+    read_register(bus, address, register, pec)
+        .map_err(|e| DeviceError::hardware(format!("failed to read sensor data: {}", e), e))
+}
+
+fn build_config_register(bus_range_32v: bool, gain: ShuntGain, mode: u16) -> u16 {
+    let range = if bus_range_32v { CONFIG_BUS_RANGE_32V } else { 0 };
+    range | ((gain as u16) << 11) | (CONFIG_ADC_12BIT << 7) | (CONFIG_ADC_12BIT << 3) | mode
+}
+
+pub struct Ina219SysfsDriver {
+    config: Ina219SysfsConfig,
+    address: I2cAddress,
+    bus: Option<I2cBus>,
+    pec: bool,
+    calibration: Ina219Calibration,
+    gain: ShuntGain,
+    is_loaded: bool,
+}
+
+impl Ina219SysfsDriver {
+    fn from_config(config: Ina219SysfsConfig) -> Result<Self, DeviceError> {
+        if config.bus_voltage_range != 16 && config.bus_voltage_range != 32 {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry(format!(
+                    "invalid bus voltage range: {}, supported values are 16, 32",
+                    config.bus_voltage_range
+                ))
+                .to_string()
+            ));
+        }
+
+        let (calibration, gain) = Self::calibrate(config.shunt_resistance_ohms, config.max_expected_current_a)
+            .map_err(|e| DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string()))?;
+
+        let address = config.i2c.address().map_err(|e| DeviceError::invalid_config(e.to_string()))?;
+
+        Ok(Self {
+            config,
+            address,
+            bus: None,
+            pec: false,
+            calibration,
+            gain,
+            is_loaded: false,
+        })
+    }
+
+    fn calibrate(shunt_resistance_ohms: f32, max_expected_current_a: f32) -> Result<(Ina219Calibration, ShuntGain), String> {
+        let calibration = Ina219Calibration::compute(shunt_resistance_ohms, max_expected_current_a)?;
+        let shunt_voltage = shunt_resistance_ohms * max_expected_current_a;
+        match ShuntGain::for_shunt_voltage(shunt_voltage) {
+            Some(gain) => Ok((calibration, gain)),
+            None => Err(format!(
+                "expected shunt voltage of {} V is above the {} V the chip can measure",
+                shunt_voltage,
+                ShuntGain::_320MV.into_range_volts()
+            )),
+        }
+    }
+
+    fn assert_state(&self) -> Result<(), DeviceError> {
+        if self.is_loaded && self.bus.is_some() {
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidOperation(
+                "device is in an invalid state".to_string(),
+            ))
+        }
+    }
+
+    fn write_calibration<T: SmbusTransfer + Write + ?Sized>(&self, bus: &mut T) -> Result<(), Error> {
+        let address = self.address;
+        write_register(bus, address, REGISTER_CALIBRATION, self.calibration.register, self.pec)?;
+        write_register(
+            bus,
+            address,
+            REGISTER_CONFIG,
+            build_config_register(self.config.bus_voltage_range == 32, self.gain, CONFIG_MODE_CONTINUOUS),
+            self.pec,
+        )
+    }
+
+    fn read(&self, register: u8) -> Result<u16, DeviceError> {
+        self.assert_state()?;
+        let mut transaction = self.bus.as_ref().unwrap().lock();
+        read_register(&mut *transaction, self.address, register, self.pec)
+            .map_err(|e| DeviceError::hardware(format!("failed to read sensor data: {}", e), e))
+    }
+
+    fn read_checked(&self, register: u8) -> Result<u16, DeviceError> {
+        self.assert_state()?;
+        let mut transaction = self.bus.as_ref().unwrap().lock();
+        read_overflow_checked(&mut *transaction, self.address, register, self.pec)
+    }
+}
+
+impl DeviceDriver for Ina219SysfsDriver {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+
+    fn required_controllers(&self) -> Vec<&'static str> {
+        vec!["i2c_sysfs"]
+    }
+
+    fn name(&self) -> String {
+        "ina219_sysfs".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(
+        config: Option<&mut crate::config::DeviceConfig>,
+    ) -> Result<Self, crate::device::DeviceError>
+    where
+        Self: Sized,
+    {
+        if config.is_none() {
+            return Err(DeviceError::invalid_config(
+                "this driver requires a configuration object but none was provided".to_owned()
+            ));
+        }
+
+        let config = config.unwrap();
+        let data: Ina219SysfsConfig = match serde_json::from_value(config.driver_data.clone()) {
+            Ok(d) => d,
+            Err(e) => {
+                if config.driver_data == Value::Null {
+                    match serde_json::to_value(Ina219SysfsConfig::default()) {
+                        Ok(c) => {
+                            config.driver_data = c;
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    "device was missing config data, default config was written"
+                                        .to_string(),
+                                )
+                                .to_string()
+                            ));
+                        }
+                        Err(e) => {
+                            warn!("Failed to write default configuration: {}", e);
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    format!("device was missing config data, default config failed to be written: {}", e)
+                                ).to_string()
+                            ));
+                        }
+                    }
+                }
+
+                return Err(DeviceError::invalid_config(
+                    ConfigError::SerializeError(format!(
+                        "failed to deserialize device config data: {}",
+                        e
+                    ))
+                    .to_string()
+                ));
+            }
+        };
+
+        Self::from_config(data)
+    }
+
+    fn start(&mut self, parent: &mut crate::device::DeviceServer) -> Result<(), DeviceError> {
+        if self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device load requested but this device is already loaded".to_string(),
+            ));
+        }
+
+        let bus_id = self.config.i2c.bus_id;
+        let mut i2c = match parent.get_bus_mut::<SysfsI2CBusController>() {
+            Some(controller) => controller,
+            None => return Err(DeviceError::MissingController("i2c_sysfs".to_string())),
+        };
+
+        let bus = match i2c.client(&self.config.i2c, &format!("{}@{}", self.name(), self.address)) {
+            Ok(bus) => bus,
+            Err(e) => return Err(DeviceError::hardware_error(e.to_string())),
+        };
+
+        self.pec = i2c.pec_enabled();
+        let init = self.write_calibration(&mut *bus.lock());
+        if let Err(e) = init {
+            drop(bus);
+            if let Err(e) = i2c.close(bus_id) {
+                warn!("Failed to release I2C bus {} while recovering from an error: {}", bus_id, e);
+            }
+
+            return Err(DeviceError::hardware(format!("failed to enable and calibrate device: {}", e), e));
+        }
+
+        self.bus = Some(bus);
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, parent: &mut crate::device::DeviceServer) -> Result<(), DeviceError> {
+        if !self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device unload requested but this device isn't loaded".to_string(),
+            ));
+        }
+
+        match self.bus {
+            Some(ref bus) => {
+                let mut transaction = bus.lock();
+                let config = build_config_register(self.config.bus_voltage_range == 32, self.gain, CONFIG_MODE_POWER_DOWN);
+                if let Err(e) = write_register(&mut *transaction, self.address, REGISTER_CONFIG, config, self.pec) {
+                    warn!("Failed to disable device: {}", e);
+                }
+            }
+            None => warn!("Failed to disable hardware: I2C bus was uninitialized"),
+        };
+
+        // other devices may still be using the bus, the controller only closes it after the last one
+        if self.bus.take().is_some() {
+            match parent.get_bus_mut::<SysfsI2CBusController>() {
+                Some(mut i2c) => {
+                    if let Err(e) = i2c.close(self.config.i2c.bus_id) {
+                        warn!("Failed to release I2C bus {}: {}", self.config.i2c.bus_id, e);
+                    }
+                }
+                None => warn!("Failed to release I2C bus: controller is unavailable"),
+            }
+        }
+
+        self.is_loaded = false;
+        Ok(())
+    }
+}
+
+impl Capability for Ina219SysfsDriver {}
+
+#[cast_to]
+impl PowerMonitorCapable for Ina219SysfsDriver {
+    fn get_bus_voltage(&mut self) -> Result<f32, DeviceError> {
+        bus_voltage_from_register(self.read(REGISTER_BUS_VOLTAGE)?)
+    }
+
+    fn get_current_ma(&mut self) -> Result<f32, DeviceError> {
+        Ok(self.calibration.current_ma(self.read_checked(REGISTER_CURRENT)?))
+    }
+
+    fn get_power_mw(&mut self) -> Result<f32, DeviceError> {
+        Ok(self.calibration.power_mw(self.read_checked(REGISTER_POWER)?))
+    }
+
+    fn set_calibration(&mut self, shunt_resistance_ohms: f32, max_expected_current_a: f32) -> Result<(), DeviceError> {
+        let (calibration, gain) = Self::calibrate(shunt_resistance_ohms, max_expected_current_a)
+            .map_err(DeviceError::invalid_config)?;
+
+        let previous = (self.calibration, self.gain);
+        self.calibration = calibration;
+        self.gain = gain;
+        if let Some(bus) = self.bus.as_ref() {
+            let mut transaction = bus.lock();
+            if let Err(e) = self.write_calibration(&mut *transaction) {
+                drop(transaction);
+                (self.calibration, self.gain) = previous;
+                return Err(DeviceError::hardware(format!("failed to apply new calibration: {}", e), e));
+            }
+        }
+
+        self.config.shunt_resistance_ohms = shunt_resistance_ohms;
+        self.config.max_expected_current_a = max_expected_current_a;
+        Ok(())
+    }
+}
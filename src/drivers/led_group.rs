@@ -0,0 +1,229 @@
+use crate::{
+    capabilities::{Capability, LEDControllerCapable, LEDMode},
+    config::{ConfigError, DeviceConfig},
+    device::{DeviceDriver, DeviceError, DeviceLink, DeviceServer},
+};
+use intertrait::cast_to;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LedGroupConfig {
+    // Names or addresses of the LED devices in the group, list them in depends_on as well so they start first
+    pub members: Vec<String>,
+}
+
+// Virtual LED that fans every command out to its members, reads come from the first member
+pub struct LedGroup {
+    config: LedGroupConfig,
+    members: Vec<DeviceLink>,
+    is_loaded: bool,
+}
+
+impl LedGroup {
+    fn from_config(config: LedGroupConfig) -> Result<Self, DeviceError> {
+        if config.members.is_empty() {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("LED group has no members".to_string()).to_string()
+            ));
+        }
+
+        for (index, member) in config.members.iter().enumerate() {
+            if member.trim().is_empty() {
+                return Err(DeviceError::invalid_config(
+                    ConfigError::InvalidEntry("LED group member names cannot be empty".to_string()).to_string()
+                ));
+            }
+
+            if config.members[..index].contains(member) {
+                return Err(DeviceError::invalid_config(
+                    ConfigError::InvalidEntry(format!("LED group member \"{}\" is listed more than once", member)).to_string()
+                ));
+            }
+        }
+
+        Ok(Self {
+            config: config,
+            members: Vec::new(),
+            is_loaded: false,
+        })
+    }
+
+    fn first_member(&self) -> Result<&DeviceLink, DeviceError> {
+        match self.members.first() {
+            Some(member) if self.is_loaded => Ok(member),
+            _ => Err(DeviceError::InvalidOperation(
+                "device is in an invalid state".to_string(),
+            ))
+        }
+    }
+
+    // Runs the call on every member even if some fail, the failures are reported together
+    fn for_each_member(
+        &self,
+        action: &str,
+        call: impl Fn(&mut dyn LEDControllerCapable) -> Result<(), DeviceError>
+    ) -> Result<(), DeviceError> {
+        self.first_member()?;
+
+        let failures: Vec<String> = self.members.iter()
+            .filter_map(|member| {
+                member.with_capability_mut::<dyn LEDControllerCapable, _>(|led| call(led))
+                    .err()
+                    .map(|e| format!("\"{}\": {}", member.name(), e))
+            })
+            .collect();
+
+        match failures.len() {
+            0 => Ok(()),
+            failed => Err(DeviceError::hardware_error(format!(
+                "failed to {} on {} of {} group members: {}",
+                action, failed, self.members.len(), failures.join(", ")
+            )))
+        }
+    }
+}
+
+impl DeviceDriver for LedGroup {
+    fn name(&self) -> String {
+        "led_group".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        if config.is_none() {
+            return Err(DeviceError::invalid_config("this driver requires a configuration object but none was provided"));
+        }
+
+        let config = config.unwrap();
+        let data: LedGroupConfig = match serde_json::from_value(config.driver_data.clone()) {
+            Ok(d) => d,
+            Err(e) => {
+                if config.driver_data == Value::Null {
+                    match serde_json::to_value(LedGroupConfig::default()) {
+                        Ok(c) => {
+                            config.driver_data = c;
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    "device was missing config data, default config was written"
+                                        .to_string(),
+                                )
+                                .to_string()
+                            ));
+                        }
+                        Err(e) => {
+                            warn!("Failed to write default configuration: {}", e);
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    format!("device was missing config data, default config failed to be written: {}", e)
+                                ).to_string()
+                            ));
+                        }
+                    }
+                }
+
+                return Err(DeviceError::invalid_config(
+                    ConfigError::SerializeError(format!(
+                        "failed to deserialize device config data: {}",
+                        e
+                    ))
+                    .to_string()
+                ));
+            }
+        };
+
+        Self::from_config(data)
+    }
+
+    fn start(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device load requested but this device is already loaded".to_string(),
+            ));
+        }
+
+        let mut members = Vec::new();
+        for reference in &self.config.members {
+            let member = parent.link_device(reference)?;
+            if let Err(e) = member.with_capability::<dyn LEDControllerCapable, _>(|_| Ok(())) {
+                return Err(DeviceError::InvalidOperation(format!(
+                    "LED group member \"{}\" is not a usable LED controller: {}",
+                    reference, e
+                )));
+            }
+
+            members.push(member);
+        }
+
+        debug!("LED group started with {} members", members.len());
+        self.members = members;
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if !self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device unload requested but this device isn't loaded".to_string(),
+            ));
+        }
+
+        // the members are left as they are, they are stopped on their own
+        self.members.clear();
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for LedGroup {}
+
+#[cast_to]
+impl LEDControllerCapable for LedGroup {
+    fn get_mode(&self) -> Result<LEDMode, DeviceError> {
+        self.first_member()?.with_capability::<dyn LEDControllerCapable, _>(|led| led.get_mode())
+    }
+
+    fn set_mode(&mut self, mode: LEDMode) -> Result<(), DeviceError> {
+        self.for_each_member("set mode", |led| led.set_mode(mode.clone()))
+    }
+
+    // Only modes every member supports
+    fn get_modes(&self) -> Result<Vec<LEDMode>, DeviceError> {
+        let mut modes = self.first_member()?.with_capability::<dyn LEDControllerCapable, _>(|led| led.get_modes())?;
+        for member in &self.members[1..] {
+            let member_modes = member.with_capability::<dyn LEDControllerCapable, _>(|led| led.get_modes())?;
+            modes.retain(|mode| member_modes.contains(mode));
+        }
+
+        Ok(modes)
+    }
+
+    fn get_brightness(&self) -> Result<f32, DeviceError> {
+        self.first_member()?.with_capability::<dyn LEDControllerCapable, _>(|led| led.get_brightness())
+    }
+
+    fn set_brightness(&mut self, brightness: f32) -> Result<(), DeviceError> {
+        self.for_each_member("set brightness", |led| led.set_brightness(brightness))
+    }
+
+    fn get_power_state(&self) -> Result<bool, DeviceError> {
+        self.first_member()?.with_capability::<dyn LEDControllerCapable, _>(|led| led.get_power_state())
+    }
+
+    fn set_power_state(&mut self, powered_on: bool) -> Result<(), DeviceError> {
+        self.for_each_member("set power state", |led| led.set_power_state(powered_on))
+    }
+}
@@ -0,0 +1,92 @@
+use crate::device::DeviceError;
+use log::warn;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+use sysfs_pwm::Pwm;
+
+// Time between duty cycle writes, short enough that the steps aren't visible
+pub const RAMP_STEP_INTERVAL: Duration = Duration::from_millis(10);
+
+pub(crate) trait DutyCycleOutput: Send + Sync {
+    fn set_duty_cycle_ns(&self, duty_cycle: u32) -> Result<(), DeviceError>;
+}
+
+impl DutyCycleOutput for Pwm {
+    fn set_duty_cycle_ns(&self, duty_cycle: u32) -> Result<(), DeviceError> {
+        Pwm::set_duty_cycle_ns(self, duty_cycle)
+            .map_err(|e| DeviceError::hardware_error(format!("could not set pwm duty cycle: {}", e)))
+    }
+}
+
+// Duty cycle written at each step, the last one is always the target
+pub fn ramp_steps(from: u32, to: u32, duration: Duration) -> Vec<u32> {
+    let steps = (duration.as_millis() / RAMP_STEP_INTERVAL.as_millis()).max(1) as i64;
+    let (from, to) = (from as i64, to as i64);
+    (1..=steps).map(|step| (from + (to - from) * step / steps) as u32).collect()
+}
+
+// Duty cycle ramp running on its own thread, dropping it cancels the ramp
+pub(crate) struct PowerRamp {
+    cancelled: Arc<AtomicBool>,
+    // last duty cycle that was written, so a cancelled ramp can be picked up from where it stopped
+    duty_cycle: Arc<AtomicU32>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PowerRamp {
+    pub(crate) fn start<T: DutyCycleOutput + 'static>(output: Arc<T>, from: u32, to: u32, duration: Duration) -> Self {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let duty_cycle = Arc::new(AtomicU32::new(from));
+        let (thread_cancelled, thread_duty_cycle) = (cancelled.clone(), duty_cycle.clone());
+        let handle = thread::spawn(move || {
+            for step in ramp_steps(from, to, duration) {
+                thread::sleep(RAMP_STEP_INTERVAL);
+                if thread_cancelled.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                if let Err(e) = output.set_duty_cycle_ns(step) {
+                    warn!("Stopping power ramp: {}", e);
+                    return;
+                }
+
+                thread_duty_cycle.store(step, Ordering::SeqCst);
+            }
+        });
+
+        Self { cancelled, duty_cycle, handle: Some(handle) }
+    }
+
+    // Waits for the ramp to reach its target
+    pub(crate) fn wait(mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+
+    // Nothing is written once this returns, so the caller can take over the channel.
+    // Returns the duty cycle the ramp got to.
+    pub(crate) fn cancel(mut self) -> u32 {
+        self.stop();
+        self.duty_cycle.load(Ordering::SeqCst)
+    }
+
+    fn stop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for PowerRamp {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,46 @@
+use crate::device::DeviceError;
+use log::warn;
+use std::{thread, time::Duration};
+
+pub const DEFAULT_START_RETRY_DELAY_MS: u64 = 50;
+// keeps the doubling from overflowing on silly retry counts
+const MAX_BACKOFF_SHIFT: u32 = 10;
+
+// Retry policy for chip detection in start, a chip that is still powering up gets a few more chances
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StartRetry {
+    retries: u32,
+    delay: Duration,
+}
+
+impl StartRetry {
+    pub fn new(retries: u32, delay_ms: u64) -> Self {
+        Self {
+            retries,
+            delay: Duration::from_millis(delay_ms),
+        }
+    }
+
+    // Runs the attempt until it succeeds or the retries run out, the delay doubles after every failure.
+    // Errors that another attempt won't fix are returned straight away.
+    pub fn run<T>(&self, action: &str, mut attempt: impl FnMut() -> Result<T, DeviceError>) -> Result<T, DeviceError> {
+        let mut tries = 0;
+        loop {
+            match attempt() {
+                Ok(result) => return Ok(result),
+                Err(e) if tries < self.retries && is_retryable(&e) => {
+                    let delay = self.delay * (1 << tries.min(MAX_BACKOFF_SHIFT));
+                    tries += 1;
+                    warn!("Failed to {} (attempt {} of {}), retrying in {:?}: {}", action, tries, self.retries + 1, delay, e);
+                    thread::sleep(delay);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// Only hardware errors are worth another attempt, that covers I/O failures and chip ID mismatches
+pub fn is_retryable(err: &DeviceError) -> bool {
+    matches!(err, DeviceError::HardwareError(..))
+}
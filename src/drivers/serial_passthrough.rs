@@ -0,0 +1,312 @@
+use crate::{
+    bus::uart::{FlowControl, UARTBusController},
+    capabilities::{Capability, SerialPortCapable},
+    config::{ConfigError, DeviceConfig},
+    device::{DeviceDriver, DeviceError, DeviceServer},
+    drivers::gps_uart::Parity,
+};
+use intertrait::cast_to;
+use log::warn;
+use parking_lot::Mutex;
+use rppal::uart::Uart;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    any::Any,
+    time::{Duration, Instant},
+};
+
+// rppal expresses read timeouts in tenths of a second stored in a u8
+const MAX_READ_TIMEOUT: Duration = Duration::from_millis(25500);
+const READ_CHUNK_SIZE: usize = 256;
+
+// Raw byte access to an open serial port, implemented by rppal's Uart.
+pub trait SerialTransport: Send {
+    fn write(&mut self, data: &[u8]) -> Result<usize, DeviceError>;
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceError>;
+    fn drain(&mut self) -> Result<(), DeviceError>;
+}
+
+impl SerialTransport for Uart {
+    fn write(&mut self, data: &[u8]) -> Result<usize, DeviceError> {
+        Uart::write(self, data)
+            .map_err(|e| DeviceError::hardware(format!("failed to write to serial port: {}", e), e))
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, DeviceError> {
+        self.set_read_mode(0, timeout.min(MAX_READ_TIMEOUT))
+            .map_err(|e| DeviceError::hardware(format!("failed to set serial read mode: {}", e), e))?;
+
+        Uart::read(self, buf)
+            .map_err(|e| DeviceError::hardware(format!("failed to read from serial port: {}", e), e))
+    }
+
+    fn drain(&mut self) -> Result<(), DeviceError> {
+        Uart::drain(self)
+            .map_err(|e| DeviceError::hardware(format!("failed to flush serial port: {}", e), e))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SerialPassthroughConfig {
+    pub uart_port: u8,
+    pub baud_rate: u32,
+    pub parity: Parity,
+    pub data_bits: u8,
+    pub stop_bits: u8,
+    // RTS/CTS needs the pins set on the UART port definition
+    #[serde(default)]
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialPassthroughConfig {
+    fn default() -> Self {
+        Self {
+            uart_port: Default::default(),
+            baud_rate: 115200,
+            parity: Parity::None,
+            data_bits: 8,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+pub struct SerialPassthrough {
+    config: SerialPassthroughConfig,
+    port: Option<Mutex<Box<dyn SerialTransport>>>,
+    rx_buffer: Vec<u8>,
+    is_loaded: bool,
+}
+
+impl SerialPassthrough {
+    fn from_config(config: SerialPassthroughConfig) -> Result<Self, DeviceError> {
+        if config.data_bits < 5 || config.data_bits > 9 {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("data bit count is out of bounds: only 5-9 data bits are supported".to_string()).to_string()
+            ));
+        };
+
+        if config.baud_rate == 0 {
+            return Err(DeviceError::invalid_config(
+               ConfigError::InvalidEntry("baud rate cannot be 0".to_string()).to_string()
+            ));
+        }
+
+        if config.stop_bits != 1 && config.stop_bits != 2 {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("stop bit count can be either 1 or 2".to_string()).to_string()
+            ));
+        }
+
+        Ok(Self {
+            config: config,
+            port: None,
+            rx_buffer: Vec::new(),
+            is_loaded: false,
+        })
+    }
+
+    // Builds an already running device on top of an arbitrary transport, bypassing the UART controller
+    pub fn with_transport(config: SerialPassthroughConfig, transport: Box<dyn SerialTransport>) -> Result<Self, DeviceError> {
+        let mut device = Self::from_config(config)?;
+        device.port = Some(Mutex::new(transport));
+        device.is_loaded = true;
+        Ok(device)
+    }
+
+    fn get_port(&mut self) -> Result<&mut Box<dyn SerialTransport>, DeviceError> {
+        match self.port.as_mut() {
+            Some(port) if self.is_loaded => Ok(port.get_mut()),
+            _ => Err(DeviceError::InvalidOperation(
+                "device is in an invalid state".to_string(),
+            )),
+        }
+    }
+}
+
+impl DeviceDriver for SerialPassthrough {
+    fn name(&self) -> String {
+        "serial_passthrough".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        if config.is_none() {
+            return Err(DeviceError::invalid_config("this driver requires a configuration object but none was provided"));
+        }
+
+        let config = config.unwrap();
+        let data: SerialPassthroughConfig = match serde_json::from_value(config.driver_data.clone()) {
+            Ok(d) => d,
+            Err(e) => {
+                if config.driver_data == Value::Null {
+                    match serde_json::to_value(SerialPassthroughConfig::default()) {
+                        Ok(c) => {
+                            config.driver_data = c;
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    "device was missing config data, default config was written"
+                                        .to_string(),
+                                )
+                                .to_string()
+                            ));
+                        }
+                        Err(e) => {
+                            warn!("Failed to write default configuration: {}", e);
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    format!("device was missing config data, default config failed to be written: {}", e)
+                                ).to_string()
+                            ));
+                        }
+                    }
+                }
+
+                return Err(DeviceError::invalid_config(
+                    ConfigError::SerializeError(format!(
+                        "failed to deserialize device config data: {}",
+                        e
+                    ))
+                    .to_string()
+                ));
+            }
+        };
+
+        Self::from_config(data)
+    }
+
+    fn start(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device load requested but this device is already loaded".to_string(),
+            ));
+        }
+
+        let mut uart = match parent.get_bus_mut::<UARTBusController>() {
+            Some(bus) => bus,
+            None => return Err(DeviceError::MissingController("uart".to_string())),
+        };
+
+        let config = &self.config;
+        let mut device = match uart.open(
+            config.uart_port,
+            config.baud_rate,
+            config.parity.clone().into(),
+            config.data_bits,
+            config.stop_bits,
+            config.flow_control,
+        ) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(DeviceError::hardware_error(format!(
+                    "could not open uart channel: {}",
+                    e
+                )))
+            }
+        };
+
+        if let Err(e) = device.set_write_mode(true) {
+            warn!("Failed to enable blocking writes, writes may be truncated: {}", e);
+        }
+
+        self.port = Some(Mutex::new(Box::new(device)));
+        self.rx_buffer.clear();
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if !self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device unload requested but this device isn't loaded".to_string(),
+            ));
+        }
+
+        // drop the handle before handing the channel back to the controller
+        self.port = None;
+        self.rx_buffer.clear();
+        self.is_loaded = false;
+
+        let mut uart = match parent.get_bus_mut::<UARTBusController>() {
+            Some(bus) => bus,
+            None => return Err(DeviceError::MissingController("uart".to_string())),
+        };
+
+        if let Err(e) = uart.close(self.config.uart_port) {
+            warn!("Failed to close UART channel while shutting down: {}", e);
+        }
+
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn required_controllers(&self) -> Vec<&'static str> {
+        // a transport attached through with_transport doesn't go through the UART controller
+        if self.port.is_some() {
+            return Vec::new();
+        }
+
+        vec!["uart"]
+    }
+}
+
+impl Capability for SerialPassthrough {}
+
+#[cast_to]
+impl SerialPortCapable for SerialPassthrough {
+    fn write_bytes(&mut self, data: &[u8]) -> Result<usize, DeviceError> {
+        self.get_port()?.write(data)
+    }
+
+    fn read_bytes(&mut self, max: usize, timeout: Duration) -> Result<Vec<u8>, DeviceError> {
+        // serve anything left over from a previous line read first
+        if !self.rx_buffer.is_empty() {
+            let count = max.min(self.rx_buffer.len());
+            return Ok(self.rx_buffer.drain(..count).collect());
+        }
+
+        let mut buf = vec![0u8; max];
+        let count = self.get_port()?.read(&mut buf, timeout)?;
+        buf.truncate(count);
+        Ok(buf)
+    }
+
+    fn read_line(&mut self, timeout: Duration) -> Result<String, DeviceError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(pos) = self.rx_buffer.iter().position(|&x| x == b'\n') {
+                let mut line: Vec<u8> = self.rx_buffer.drain(..=pos).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+
+                return Ok(String::from_utf8_lossy(&line).into_owned());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(DeviceError::Other("timed out waiting for a full line".to_string()));
+            }
+
+            let mut buf = [0u8; READ_CHUNK_SIZE];
+            let count = self.get_port()?.read(&mut buf, deadline - now)?;
+            self.rx_buffer.extend_from_slice(&buf[..count]);
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        self.get_port()?.drain()
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{
+    bus::pwm_sysfs::SysfsPWMBusController,
+    capabilities::{Capability, ServoCapable},
+    config::{ConfigError, DeviceConfig},
+    device::{DeviceDriver, DeviceError, DeviceServer},
+};
+use intertrait::cast_to;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+use sysfs_pwm::Pwm;
+
+const NANOS_PER_MICRO: u32 = 1000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServoPwmConfig {
+    pub pwm_channel: u8,
+    pub pwm_period_us: u32,
+    pub min_pulse_us: u32,
+    pub max_pulse_us: u32,
+    // Angles outside of this range are clamped before being mapped to a pulse width
+    pub min_angle: f32,
+    pub max_angle: f32,
+    pub default_angle: f32,
+}
+
+impl Default for ServoPwmConfig {
+    fn default() -> Self {
+        Self {
+            pwm_channel: Default::default(),
+            // standard 50 Hz hobby servo timing
+            pwm_period_us: 20000,
+            min_pulse_us: 1000,
+            max_pulse_us: 2000,
+            min_angle: 0.0,
+            max_angle: 180.0,
+            default_angle: 90.0,
+        }
+    }
+}
+
+fn check_pulse_range(min_us: u32, max_us: u32, period_us: u32) -> Result<(), String> {
+    if min_us >= max_us {
+        return Err(format!("minimum pulse width ({} us) must be below the maximum ({} us)", min_us, max_us));
+    }
+
+    if max_us > period_us {
+        return Err(format!("maximum pulse width ({} us) cannot be larger than the period ({} us)", max_us, period_us));
+    }
+
+    Ok(())
+}
+
+pub struct ServoPwm {
+    config: ServoPwmConfig,
+    pwm: Option<Pwm>,
+    angle: f32,
+    is_loaded: bool,
+}
+
+impl ServoPwm {
+    fn from_config(config: ServoPwmConfig) -> Result<Self, DeviceError> {
+        if config.pwm_period_us == 0 || config.pwm_period_us.checked_mul(NANOS_PER_MICRO).is_none() {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry(format!("PWM period of {} us is out of range", config.pwm_period_us))
+                    .to_string()
+            ));
+        }
+
+        check_pulse_range(config.min_pulse_us, config.max_pulse_us, config.pwm_period_us)
+            .map_err(|e| DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string()))?;
+
+        if !config.min_angle.is_finite() || !config.max_angle.is_finite() || config.min_angle >= config.max_angle {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("minimum angle must be below the maximum angle".to_string()).to_string()
+            ));
+        }
+
+        let angle = config.default_angle.clamp(config.min_angle, config.max_angle);
+        Ok(Self {
+            config,
+            pwm: None,
+            angle,
+            is_loaded: false,
+        })
+    }
+
+    fn assert_state(&self, check_pwm: bool) -> Result<(), DeviceError> {
+        if self.is_loaded && (!check_pwm || self.pwm.is_some()) {
+            Ok(())
+        } else {
+            Err(DeviceError::InvalidOperation(
+                "device is in an invalid state".to_string(),
+            ))
+        }
+    }
+
+    // Linear map from the angle range onto the pulse range, the angle is clamped first
+    pub fn pulse_width_us(&self, degrees: f32) -> u32 {
+        let angle = degrees.clamp(self.config.min_angle, self.config.max_angle);
+        let position = (angle - self.config.min_angle) / (self.config.max_angle - self.config.min_angle);
+        let span = (self.config.max_pulse_us - self.config.min_pulse_us) as f32;
+        self.config.min_pulse_us + (span * position).round() as u32
+    }
+
+    fn write_pulse(&self, pulse_us: u32) -> Result<(), DeviceError> {
+        let pwm = self.pwm.as_ref().unwrap();
+        if let Err(e) = pwm.set_period_ns(self.config.pwm_period_us * NANOS_PER_MICRO) {
+            return Err(DeviceError::hardware_error(format!(
+                "failed to set angle: could not set pwm period: {}",
+                e
+            )));
+        }
+
+        if let Err(e) = pwm.set_duty_cycle_ns(pulse_us * NANOS_PER_MICRO) {
+            return Err(DeviceError::hardware_error(format!(
+                "failed to set angle: could not set pwm duty cycle: {}",
+                e
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl DeviceDriver for ServoPwm {
+    fn name(&self) -> String {
+        "servo_pwm".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        if config.is_none() {
+            return Err(DeviceError::invalid_config("this driver requires a configuration object but none was provided"));
+        }
+
+        let config = config.unwrap();
+        let data: ServoPwmConfig = match serde_json::from_value(config.driver_data.clone()) {
+            Ok(d) => d,
+            Err(e) => {
+                if config.driver_data == Value::Null {
+                    match serde_json::to_value(ServoPwmConfig::default()) {
+                        Ok(c) => {
+                            config.driver_data = c;
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    "device was missing config data, default config was written"
+                                        .to_string(),
+                                )
+                                .to_string()
+                            ));
+                        }
+                        Err(e) => {
+                            warn!("Failed to write default configuration: {}", e);
+                            return Err(DeviceError::invalid_config(
+                                ConfigError::MissingEntry(
+                                    format!("device was missing config data, default config failed to be written: {}", e)
+                                ).to_string()
+                            ));
+                        }
+                    }
+                }
+
+                return Err(DeviceError::invalid_config(
+                    ConfigError::SerializeError(format!(
+                        "failed to deserialize device config data: {}",
+                        e
+                    ))
+                    .to_string()
+                ));
+            }
+        };
+
+        Self::from_config(data)
+    }
+
+    fn start(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device load requested but this device is already loaded".to_string(),
+            ));
+        }
+
+        let mut pwm = match parent.get_bus_mut::<SysfsPWMBusController>() {
+            Some(bus) => bus,
+            None => return Err(DeviceError::MissingController("sysfs_pwm".to_string())),
+        };
+
+        let channel = match pwm.open(self.config.pwm_channel) {
+            Ok(channel) => channel,
+            Err(e) => {
+                return Err(DeviceError::hardware_error(format!(
+                    "could not get servo pwm channel: {}",
+                    e
+                )))
+            }
+        };
+
+        if let Err(e) = channel.enable(true) {
+            warn!("Failed to enable servo PWM channel: {}", e);
+        }
+
+        self.pwm = Some(channel);
+        self.is_loaded = true;
+        if let Err(e) = self.set_angle(self.config.default_angle) {
+            warn!("Failed to set initial angle: {}", e);
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if !self.is_loaded {
+            return Err(DeviceError::InvalidOperation(
+                "device unload requested but this device isn't loaded".to_string(),
+            ));
+        }
+
+        if self.pwm.is_some() {
+            let mut pwm = match parent.get_bus_mut::<SysfsPWMBusController>() {
+                Some(bus) => bus,
+                None => return Err(DeviceError::MissingController("sysfs_pwm".to_string())),
+            };
+
+            // a disabled channel stops driving the servo instead of holding the last position
+            if let Err(e) = self.pwm.as_ref().unwrap().enable(false) {
+                warn!("Failed to disable servo PWM channel: {}", e);
+            }
+
+            if let Err(e) = pwm.close(self.config.pwm_channel) {
+                warn!("Failed to close servo PWM channel while shutting down: {}", e);
+            }
+
+            self.pwm = None;
+        }
+
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn required_controllers(&self) -> Vec<&'static str> {
+        vec!["pwm_sysfs"]
+    }
+}
+
+impl Capability for ServoPwm {}
+
+#[cast_to]
+impl ServoCapable for ServoPwm {
+    fn get_angle(&self) -> Result<f32, DeviceError> {
+        self.assert_state(false)?;
+        Ok(self.angle)
+    }
+
+    fn set_angle(&mut self, degrees: f32) -> Result<(), DeviceError> {
+        self.assert_state(true)?;
+        if !degrees.is_finite() {
+            return Err(DeviceError::InvalidOperation(format!("invalid angle: {}", degrees)));
+        }
+
+        let angle = degrees.clamp(self.config.min_angle, self.config.max_angle);
+        self.write_pulse(self.pulse_width_us(angle))?;
+
+        debug!("new angle: {}", angle);
+        self.angle = angle;
+        Ok(())
+    }
+
+    fn get_pulse_range(&self) -> Result<(u32, u32), DeviceError> {
+        Ok((self.config.min_pulse_us, self.config.max_pulse_us))
+    }
+
+    fn set_pulse_range(&mut self, min_us: u32, max_us: u32) -> Result<(), DeviceError> {
+        check_pulse_range(min_us, max_us, self.config.pwm_period_us).map_err(DeviceError::InvalidOperation)?;
+        self.config.min_pulse_us = min_us;
+        self.config.max_pulse_us = max_us;
+
+        // keep the servo at the same angle under the new range
+        if self.is_loaded {
+            self.write_pulse(self.pulse_width_us(self.angle))?;
+        }
+
+        Ok(())
+    }
+}
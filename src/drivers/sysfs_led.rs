@@ -3,12 +3,16 @@ use crate::{
     capabilities::{Capability, LEDControllerCapable, LEDMode},
     config::{ConfigError, DeviceConfig},
     device::{DeviceDriver, DeviceError, DeviceServer},
+    drivers::pwm_ramp::PowerRamp,
 };
 use intertrait::cast_to;
 use log::{warn, debug};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::any::Any;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
 use sysfs_gpio::Pin;
 use sysfs_pwm::Pwm;
 
@@ -26,6 +30,31 @@ pub struct SysfsLedControllerConfig {
     pub pwm_period: u32,
     pub pwm_0_brightness_duty_cycle: u32,
     pub pwm_100_brightness_duty_cycle: u32,
+    // Brightness is raised to this power before being mapped to a duty cycle, 1.0 keeps the mapping linear
+    #[serde(default = "default_brightness_gamma")]
+    pub brightness_gamma: f32,
+    // Switched together with mode_switch_pin, for rigs with more than two emitter banks
+    #[serde(default)]
+    pub extra_mode_switch_pins: Vec<u8>,
+    // Mode name -> GPIO state of each mode switch pin (mode_switch_pin first), replaces the ir/vis states when set
+    #[serde(default)]
+    pub modes: BTreeMap<String, Vec<u8>>,
+    // Power changes fade the duty cycle over this long instead of switching at once, 0 switches immediately
+    #[serde(default)]
+    pub power_ramp_ms: u64,
+    // Brightness cap while in the Infrared/Visible mode, requests above it are clamped. Named modes are not capped.
+    #[serde(default = "default_max_brightness")]
+    pub max_brightness_ir: f32,
+    #[serde(default = "default_max_brightness")]
+    pub max_brightness_visible: f32,
+}
+
+fn default_brightness_gamma() -> f32 {
+    1.0
+}
+
+fn default_max_brightness() -> f32 {
+    1.0
 }
 
 impl Default for SysfsLedControllerConfig {
@@ -45,14 +74,76 @@ impl Default for SysfsLedControllerConfig {
             pwm_period: 100,
             pwm_0_brightness_duty_cycle: 0,
             pwm_100_brightness_duty_cycle: 100,
+            brightness_gamma: default_brightness_gamma(),
+            extra_mode_switch_pins: Vec::new(),
+            modes: BTreeMap::new(),
+            power_ramp_ms: 0,
+            max_brightness_ir: default_max_brightness(),
+            max_brightness_visible: default_max_brightness(),
+        }
+    }
+}
+
+fn invalid_entry(message: String) -> DeviceError {
+    DeviceError::invalid_config(ConfigError::InvalidEntry(message).to_string())
+}
+
+fn max_brightness(config: &SysfsLedControllerConfig, mode: &LEDMode) -> f32 {
+    match mode {
+        LEDMode::Infrared => config.max_brightness_ir,
+        LEDMode::Visible => config.max_brightness_visible,
+        LEDMode::Named(_) => 1.0,
+    }
+}
+
+// GPIO states of every mode, the two-mode config maps onto Visible/Infrared on a single pin
+fn build_mode_table(config: &SysfsLedControllerConfig) -> Result<Vec<(LEDMode, Vec<u8>)>, DeviceError> {
+    if config.modes.is_empty() {
+        if config.ir_mode_gpio_state == config.vis_mode_gpio_state {
+            return Err(invalid_entry("GPIO values for modes overlap".to_string()));
+        }
+
+        return Ok(vec![
+            (LEDMode::Visible, vec![config.vis_mode_gpio_state]),
+            (LEDMode::Infrared, vec![config.ir_mode_gpio_state]),
+        ]);
+    }
+
+    let pin_count = 1 + config.extra_mode_switch_pins.len();
+    let mut table: Vec<(LEDMode, Vec<u8>)> = Vec::new();
+    for (name, states) in &config.modes {
+        if name.trim().is_empty() {
+            return Err(invalid_entry("LED mode names cannot be empty".to_string()));
+        }
+
+        if states.len() != pin_count {
+            return Err(invalid_entry(format!(
+                "LED mode \"{}\" has {} GPIO states but {} mode switch pins are configured",
+                name, states.len(), pin_count
+            )));
+        }
+
+        let mode = LEDMode::from(name.clone());
+        if let Some((other, _)) = table.iter().find(|(m, s)| *m == mode || s == states) {
+            return Err(invalid_entry(format!(
+                "LED modes \"{}\" and \"{}\" overlap",
+                other.name(), name
+            )));
         }
+
+        table.push((mode, states.clone()));
     }
+
+    Ok(table)
 }
 
 pub struct SysfsLedController {
     config: SysfsLedControllerConfig,
-    mode_switch_pin: Option<Pin>,
-    brightness_pin: Option<Pwm>,
+    mode_table: Vec<(LEDMode, Vec<u8>)>,
+    mode_switch_pins: Vec<Pin>,
+    brightness_pin: Option<Arc<Pwm>>,
+    // fade started by the last power change, any other brightness or power command cancels it
+    power_ramp: Option<PowerRamp>,
     mode: LEDMode,
     brightness: f32,
     power_state_on: bool,
@@ -61,45 +152,62 @@ pub struct SysfsLedController {
 
 impl SysfsLedController {
     fn from_config(config: SysfsLedControllerConfig) -> Result<Self, DeviceError> {
-        let mode = config.default_mode;
-        let brightness = config.default_brightness;
+        let mode = config.default_mode.clone();
         let power_state = config.default_power_state_on;
 
         if config.power_off_gpio_state == config.power_on_gpio_state {
-            return Err(DeviceError::InvalidConfig(
-                ConfigError::InvalidEntry("GPIO values for power states overlap".to_string()).to_string(),
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("GPIO values for power states overlap".to_string()).to_string()
             ));
         }
 
-        if config.ir_mode_gpio_state == config.vis_mode_gpio_state {
-            return Err(DeviceError::InvalidConfig(
-                ConfigError::InvalidEntry("GPIO values for modes overlap".to_string()).to_string(),
-            ));
+        let mode_table = build_mode_table(&config)?;
+        if !mode_table.iter().any(|(m, _)| *m == mode) {
+            return Err(invalid_entry(format!("default LED mode \"{}\" is not configured", mode.name())));
+        }
+
+        if config.extra_mode_switch_pins.contains(&config.mode_switch_pin) {
+            return Err(invalid_entry("mode switch pins overlap".to_string()));
         }
 
         if config.pwm_period == 0 {
-            return Err(DeviceError::InvalidConfig(
+            return Err(DeviceError::invalid_config(
                 ConfigError::InvalidEntry("PWM period must be greater than zero".to_string())
-                    .to_string(),
+                    .to_string()
             ));
         }
 
         if config.pwm_0_brightness_duty_cycle == config.pwm_100_brightness_duty_cycle {
-            return Err(DeviceError::InvalidConfig(
-                ConfigError::InvalidEntry("PWM duty cycles overlap".to_string()).to_string(),
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry("PWM duty cycles overlap".to_string()).to_string()
             ));
         }
 
         if config.pwm_100_brightness_duty_cycle > config.pwm_period {
-            return Err(DeviceError::InvalidConfig(
+            return Err(DeviceError::invalid_config(
                 ConfigError::InvalidEntry("PWM duty cycle cannot be larger than the period".to_string()).to_string()
             ));
         }
 
+        if !config.brightness_gamma.is_finite() || config.brightness_gamma <= 0.0 {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry(format!("brightness gamma must be greater than zero, got {}", config.brightness_gamma)).to_string()
+            ));
+        }
+
+        for (name, cap) in [("max_brightness_ir", config.max_brightness_ir), ("max_brightness_visible", config.max_brightness_visible)] {
+            if !(0.0..=1.0).contains(&cap) {
+                return Err(invalid_entry(format!("{} must be between 0 and 1, got {}", name, cap)));
+            }
+        }
+
+        let brightness = max_brightness(&config, &mode).min(config.default_brightness);
         Ok(Self {
             config: config,
-            mode_switch_pin: None,
+            mode_table: mode_table,
+            mode_switch_pins: Vec::new(),
             brightness_pin: None,
+            power_ramp: None,
             mode: mode,
             brightness: brightness,
             power_state_on: power_state,
@@ -107,8 +215,72 @@ impl SysfsLedController {
         })
     }
 
+    // Duty cycle for a powered on LED at the given brightness, after gamma correction
+    pub fn duty_cycle_ns(&self, brightness: f32) -> u32 {
+        let min = self.config.pwm_0_brightness_duty_cycle as f32;
+        let max = self.config.pwm_100_brightness_duty_cycle as f32;
+        let level = brightness.clamp(0.0, 1.0).powf(self.config.brightness_gamma);
+        (min + (max - min) * level).round() as u32
+    }
+
+    // Brightness that is actually applied for a request while in the given mode
+    pub fn brightness_for_mode(&self, brightness: f32, mode: &LEDMode) -> f32 {
+        brightness.clamp(0.0, max_brightness(&self.config, mode))
+    }
+
+    // GPIO state of each mode switch pin for the mode, in the order the pins are configured
+    pub fn mode_gpio_states(&self, mode: &LEDMode) -> Result<&[u8], DeviceError> {
+        match self.mode_table.iter().find(|(m, _)| m == mode) {
+            Some((_, states)) => Ok(states),
+            None => Err(DeviceError::InvalidOperation(format!(
+                "LED mode is not supported: {}",
+                mode.name()
+            )))
+        }
+    }
+
+    // Stops a running power ramp, returns the duty cycle it got to
+    fn cancel_power_ramp(&mut self) -> Option<u32> {
+        self.power_ramp.take().map(|ramp| ramp.cancel())
+    }
+
+    // Writes an already clamped brightness, cancelling any running power ramp
+    fn write_brightness(&mut self, brightness: f32) -> Result<(), DeviceError> {
+        self.cancel_power_ramp();
+
+        let pwm = self.brightness_pin.as_ref().unwrap();
+        if let Err(e) = pwm.set_period_ns(self.config.pwm_period) {
+            return Err(DeviceError::hardware_error(format!(
+                "failed to set brightness: could not set pwm period: {}",
+                e
+            )));
+        }
+
+        let duty_cycle = match self.power_state_on {
+            true => self.duty_cycle_ns(brightness),
+            false => self.config.pwm_0_brightness_duty_cycle,
+        };
+
+        if let Err(e) = pwm.set_duty_cycle_ns(duty_cycle) {
+            return Err(DeviceError::hardware_error(format!(
+                "failed to set brightness: could not set pwm duty cycle: {}",
+                e
+            )));
+        }
+
+        debug!("new brightness: {}", brightness);
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn mode_switch_pin_ids(&self) -> Vec<u8> {
+        let mut pins = vec![self.config.mode_switch_pin];
+        pins.extend_from_slice(&self.config.extra_mode_switch_pins);
+        pins
+    }
+
     fn assert_state(&self, check_mode_pin: bool, check_bright_pin: bool) -> Result<(), DeviceError> {
-        if self.is_loaded && (!check_mode_pin || self.mode_switch_pin.is_some()) && (!check_bright_pin || self.brightness_pin.is_some()) {
+        if self.is_loaded && (!check_mode_pin || !self.mode_switch_pins.is_empty()) && (!check_bright_pin || self.brightness_pin.is_some()) {
             Ok(())
         } else {
             Err(DeviceError::InvalidOperation(
@@ -129,7 +301,7 @@ impl DeviceDriver for SysfsLedController {
 
     fn new(config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
         if config.is_none() {
-            return Err(DeviceError::InvalidConfig("this driver requires a configuration object but none was provided".to_owned()));
+            return Err(DeviceError::invalid_config("this driver requires a configuration object but none was provided"));
         }
 
         let config = config.unwrap();
@@ -140,17 +312,17 @@ impl DeviceDriver for SysfsLedController {
                     match serde_json::to_value(SysfsLedControllerConfig::default()) {
                         Ok(c) => {
                             config.driver_data = c;
-                            return Err(DeviceError::InvalidConfig(
+                            return Err(DeviceError::invalid_config(
                                 ConfigError::MissingEntry(
                                     "device was missing config data, default config was written"
                                         .to_string(),
                                 )
-                                .to_string(),
+                                .to_string()
                             ));
                         }
                         Err(e) => {
                             warn!("Failed to write default configuration: {}", e);
-                            return Err(DeviceError::InvalidConfig(
+                            return Err(DeviceError::invalid_config(
                                 ConfigError::MissingEntry(
                                     format!("device was missing config data, default config failed to be written: {}", e)
                                 ).to_string()
@@ -159,12 +331,12 @@ impl DeviceDriver for SysfsLedController {
                     }
                 }
 
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::SerializeError(format!(
                         "failed to deserialize device config data: {}",
                         e
                     ))
-                    .to_string(),
+                    .to_string()
                 ));
             }
         };
@@ -172,6 +344,40 @@ impl DeviceDriver for SysfsLedController {
         Self::from_config(data)
     }
 
+    fn apply_config(&mut self, config: &DeviceConfig) -> Result<(), DeviceError> {
+        let data: SysfsLedControllerConfig = serde_json::from_value(config.driver_data.clone()).map_err(|e| {
+            DeviceError::invalid_config(
+                ConfigError::SerializeError(format!("failed to deserialize device config data: {}", e)).to_string()
+            )
+        })?;
+
+        // the pins are claimed in start, moving them needs a restart
+        if data.brightness_pwm_channel != self.config.brightness_pwm_channel
+            || data.mode_switch_pin != self.config.mode_switch_pin
+            || data.extra_mode_switch_pins != self.config.extra_mode_switch_pins {
+            return Err(DeviceError::NotSupported);
+        }
+
+        let updated = Self::from_config(data)?;
+        self.config = updated.config;
+        self.mode_table = updated.mode_table;
+
+        if !self.is_loaded {
+            self.mode = updated.mode;
+            self.brightness = updated.brightness;
+            self.power_state_on = updated.power_state_on;
+            return Ok(());
+        }
+
+        // the new defaults are left for the next start, a running LED keeps its state where it can
+        if !self.mode_table.iter().any(|(m, _)| *m == self.mode) {
+            self.set_mode(self.config.default_mode.clone())?;
+        }
+
+        // re-applied so a changed duty cycle range or gamma takes effect
+        self.set_brightness(self.brightness)
+    }
+
     fn start(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
         if self.is_loaded {
             return Err(DeviceError::InvalidOperation(
@@ -188,27 +394,38 @@ impl DeviceDriver for SysfsLedController {
             None => return Err(DeviceError::MissingController("sysfs_pwm".to_string())),
         };
 
-        let mode_switch_pin = match gpio.open_out(self.config.mode_switch_pin) {
-            Ok(pin) => pin,
-            Err(e) => {
-                return Err(DeviceError::HardwareError(format!(
-                    "could not get mode switch pin: {}",
-                    e
-                )))
+        let mut mode_switch_pins = Vec::new();
+        for pin_id in self.mode_switch_pin_ids() {
+            match gpio.open_out(pin_id) {
+                Ok(pin) => mode_switch_pins.push(pin),
+                Err(e) => {
+                    for pin in mode_switch_pins {
+                        if let Err(e) = gpio.close(pin) {
+                            warn!("Failed to close mode switch pin while recovering from an error: {}", e);
+                        }
+                    }
+
+                    return Err(DeviceError::hardware_error(format!(
+                        "could not get mode switch pin {}: {}",
+                        pin_id, e
+                    )))
+                }
             }
-        };
+        }
 
         let brightness_pin = match pwm.open(self.config.brightness_pwm_channel) {
             Ok(channel) => channel,
             Err(e) => {
-                if let Err(e) = gpio.close(mode_switch_pin) {
-                    warn!(
-                        "Failed to close mode switch pin while recovering from an error: {}",
-                        e
-                    );
+                for pin in mode_switch_pins {
+                    if let Err(e) = gpio.close(pin) {
+                        warn!(
+                            "Failed to close mode switch pin while recovering from an error: {}",
+                            e
+                        );
+                    }
                 }
 
-                return Err(DeviceError::HardwareError(format!(
+                return Err(DeviceError::hardware_error(format!(
                     "could not get brightness control pwm channel: {}",
                     e
                 )));
@@ -219,12 +436,12 @@ impl DeviceDriver for SysfsLedController {
             warn!("Failed to enable brightness PWM channel: {}", e);
         }
 
-        self.mode_switch_pin = Some(mode_switch_pin);
-        self.brightness_pin = Some(brightness_pin);
+        self.mode_switch_pins = mode_switch_pins;
+        self.brightness_pin = Some(Arc::new(brightness_pin));
 
         // Try to set the default state on everything
         self.is_loaded = true;
-        if let Err(e) = self.set_mode(self.config.default_mode) {
+        if let Err(e) = self.set_mode(self.config.default_mode.clone()) {
             warn!("Failed to set initial mode: {}", e);
         }
         if let Err(e) = self.set_brightness(self.config.default_brightness) {
@@ -245,7 +462,8 @@ impl DeviceDriver for SysfsLedController {
         }
 
         // Try to reset the state
-        if let Err(e) = self.set_mode(self.config.default_mode) {
+        self.cancel_power_ramp();
+        if let Err(e) = self.set_mode(self.config.default_mode.clone()) {
             warn!("Failed to reset mode: {}", e);
         }
         if let Err(e) = self.set_brightness(0.0) {
@@ -255,17 +473,17 @@ impl DeviceDriver for SysfsLedController {
             warn!("Failed to reset power state: {}", e);
         }
 
-        if self.mode_switch_pin.is_some() {
+        if !self.mode_switch_pins.is_empty() {
             let mut gpio = match parent.get_bus_mut::<SysfsRawBusController>() {
                 Some(bus) => bus,
                 None => return Err(DeviceError::MissingController("sysfs_raw".to_string())),
             };
 
-            if let Err(e) = gpio.close(self.mode_switch_pin.unwrap()) {
-                warn!("Failed to close mode switch pin while shutting down: {}", e);
+            for pin in self.mode_switch_pins.drain(..) {
+                if let Err(e) = gpio.close(pin) {
+                    warn!("Failed to close mode switch pin while shutting down: {}", e);
+                }
             }
-
-            self.mode_switch_pin = None;
         }
 
         if self.brightness_pin.is_some() {
@@ -299,6 +517,10 @@ impl DeviceDriver for SysfsLedController {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+
+    fn required_controllers(&self) -> Vec<&'static str> {
+        vec!["raw_sysfs", "pwm_sysfs"]
+    }
 }
 
 impl Capability for SysfsLedController {}
@@ -313,29 +535,34 @@ impl LEDControllerCapable for SysfsLedController {
     fn set_mode(&mut self, mode: LEDMode) -> Result<(), DeviceError> {
         self.assert_state(true, false)?;
 
-        let gpio_value = match mode {
-            LEDMode::Visible => self.config.vis_mode_gpio_state,
-            LEDMode::Infrared => self.config.ir_mode_gpio_state,
-            _ => {
-                return Err(DeviceError::InvalidOperation(format!(
-                    "LED mode is not supported: {:?}",
-                    mode
-                )))
+        let gpio_values = self.mode_gpio_states(&mode)?.to_vec();
+
+        // dimmed before switching so the new mode never runs above its cap
+        let brightness = self.brightness_for_mode(self.brightness, &mode);
+        if brightness != self.brightness {
+            debug!("clamping brightness to {} for mode {}", brightness, mode.name());
+            match self.brightness_pin.is_some() {
+                true => self.write_brightness(brightness)?,
+                false => self.brightness = brightness,
             }
-        };
+        }
 
-        let pin = self.mode_switch_pin.as_ref().unwrap();
-        match pin.set_value(gpio_value) {
-            Ok(_) => {
-                debug!("new mode: {:?}", mode);
-                self.mode = mode;
-                Ok(())
+        for (pin, value) in self.mode_switch_pins.iter().zip(&gpio_values) {
+            if let Err(e) = pin.set_value(*value) {
+                return Err(DeviceError::hardware_error(format!(
+                    "failed to set mode: {}",
+                    e
+                )));
             }
-            Err(e) => Err(DeviceError::HardwareError(format!(
-                "failed to set mode: {}",
-                e
-            ))),
         }
+
+        debug!("new mode: {:?}", mode);
+        self.mode = mode;
+        Ok(())
+    }
+
+    fn get_modes(&self) -> Result<Vec<LEDMode>, DeviceError> {
+        Ok(self.mode_table.iter().map(|(mode, _)| mode.clone()).collect())
     }
 
     fn get_brightness(&self) -> Result<f32, DeviceError> {
@@ -343,37 +570,14 @@ impl LEDControllerCapable for SysfsLedController {
         Ok(self.brightness.clone())
     }
 
-    fn set_brightness(&mut self, mut brightness: f32) -> Result<(), DeviceError> {
+    fn set_brightness(&mut self, brightness: f32) -> Result<(), DeviceError> {
         self.assert_state(false, true)?;
-
-        brightness = brightness.clamp(0.0, 1.0);
-        let pwm = self.brightness_pin.as_ref().unwrap();
-        if let Err(e) = pwm.set_period_ns(self.config.pwm_period) {
-            return Err(DeviceError::HardwareError(format!(
-                "failed to set brightness: could not set pwm period: {}",
-                e
-            )));
-        }
-
-        let duty_cycle = match self.power_state_on {
-            true => {
-                (((self.config.pwm_100_brightness_duty_cycle
-                    - self.config.pwm_0_brightness_duty_cycle) as f32)
-                    * brightness) as u32
-            }
-            false => self.config.pwm_0_brightness_duty_cycle,
-        };
-
-        if let Err(e) = pwm.set_duty_cycle_ns(duty_cycle) {
-            return Err(DeviceError::HardwareError(format!(
-                "failed to set brightness: could not set pwm duty cycle: {}",
-                e
-            )));
+        let clamped = self.brightness_for_mode(brightness, &self.mode);
+        if clamped < brightness {
+            debug!("brightness {} is above the cap for mode {}, using {}", brightness, self.mode.name(), clamped);
         }
 
-        debug!("new brightness: {}", brightness);
-        self.brightness = brightness;
-        Ok(())
+        self.write_brightness(clamped)
     }
 
     fn get_power_state(&self) -> Result<bool, DeviceError> {
@@ -388,26 +592,32 @@ impl LEDControllerCapable for SysfsLedController {
 
     fn set_power_state(&mut self, powered_on: bool) -> Result<(), DeviceError> {
         self.assert_state(false, true)?;
+        let ramped_from = self.cancel_power_ramp();
 
         let pwm = self.brightness_pin.as_ref().unwrap();
         if let Err(e) = pwm.set_period_ns(self.config.pwm_period) {
-            return Err(DeviceError::HardwareError(format!(
+            return Err(DeviceError::hardware_error(format!(
                 "failed to set power state: could not set pwm period: {}",
                 e
             )));
         }
 
         let duty_cycle = match powered_on {
-            true => {
-                (((self.config.pwm_100_brightness_duty_cycle
-                    - self.config.pwm_0_brightness_duty_cycle) as f32)
-                    * self.brightness) as u32
-            }
+            true => self.duty_cycle_ns(self.brightness),
             false => self.config.pwm_0_brightness_duty_cycle,
         };
 
-        if let Err(e) = pwm.set_duty_cycle_ns(duty_cycle) {
-            return Err(DeviceError::HardwareError(format!(
+        // an interrupted ramp carries on from where it was, otherwise it starts at the current state
+        let ramp_from = ramped_from.unwrap_or(match self.power_state_on {
+            true => self.duty_cycle_ns(self.brightness),
+            false => self.config.pwm_0_brightness_duty_cycle,
+        });
+
+        if self.config.power_ramp_ms > 0 && ramp_from != duty_cycle {
+            let duration = Duration::from_millis(self.config.power_ramp_ms);
+            self.power_ramp = Some(PowerRamp::start(pwm.clone(), ramp_from, duty_cycle, duration));
+        } else if let Err(e) = pwm.set_duty_cycle_ns(duty_cycle) {
+            return Err(DeviceError::hardware_error(format!(
                 "failed to set power state: could not set pwm duty cycle: {}",
                 e
             )));
@@ -1,7 +1,6 @@
 use i2c_linux::I2c;
 use intertrait::cast_to;
 use log::{debug, error, warn};
-use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
@@ -9,20 +8,25 @@ use std::{
     fs::File,
     io::{Error, Read, Write},
     os::fd::AsRawFd,
-    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    bus::bus_lock::BusClient,
+    bus::i2c::{I2cAddress, I2cDeviceConfig},
     bus::i2c_sysfs,
-    bus::i2c_sysfs::SysfsI2CBusController,
-    capabilities::{Capability, LightSensorCapable},
+    bus::i2c_sysfs::{SmbusTransfer, SysfsI2CBusController},
+    capabilities::{self, Capability, CapabilityId, DiagnosticsCapable, HealthReport, LightSensorCapable, ReadingCalibration, ReadingUnit, SensorCapable},
     config::ConfigError,
     device::{DeviceDriver, DeviceError, DeviceServer},
+    drivers::filter::EmaFilter,
+    drivers::retry::{self, StartRetry},
 };
-type I2cBus = Arc<Mutex<I2c<File>>>;
+type I2cBus = BusClient<I2c<File>>;
 
-const LUX_DF: f32 = 735.0;
-const DEFAULT_I2C_ADDR: u8 = 0x29;
+const DEFAULT_LUX_COEFFICIENT: f32 = 735.0;
+const DEFAULT_I2C_ADDR: u16 = 0x29;
 const CHIP_ID: u8 = 0x50;
 
 const COMMAND_BIT: u8 = 0xA0;
@@ -31,7 +35,6 @@ const REGISTER_CONTROL: u8 = 0x01;
 const REGISTER_ID_ADDR: u8 = 0x12;
 const REGISTER_STATUS: u8 = 0x13;
 const REGISTER_CHAN0_LSB: u8 = 0x14;
-const REGISTER_CHAN1_LSB: u8 = 0x16;
 
 const ENABLE_POWEROFF: u8 = 0x00;
 const ENABLE_POWERON: u8 = 0x01;
@@ -39,8 +42,18 @@ const ENABLE_AEN: u8 = 0x02;
 
 const SUPPORTED_CHANNELS: [&str; 3] = ["Visible+Infrared", "Infrared", "Visible"];
 
+// ADC full scale for a single 100ms integration cycle, longer cycles accumulate
+// counts until the 16-bit channel registers saturate
+const COUNTS_PER_CYCLE: u32 = 37888;
+
+const SPINWAIT_INTERVAL: u16 = 5;
+
+const DEFAULT_AUTO_GAIN_HYSTERESIS: f32 = 0.05;
+const DEFAULT_AUTO_GAIN_DWELL_SAMPLES: u32 = 2;
+const DEFAULT_AUTO_GAIN_DWELL_MS: u64 = 0;
+
 #[derive(Copy, Clone, PartialEq, Debug)]
-enum IntegrationTime {
+pub(crate) enum IntegrationTime {
     _100MS = 0x00,
     _200MS = 0x01,
     _300MS = 0x02,
@@ -75,7 +88,7 @@ impl IntegrationTime {
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
-enum GainValue {
+pub(crate) enum GainValue {
     _1X = 0x00,
     _25X = 0x10,
     _428X = 0x20,
@@ -142,8 +155,64 @@ pub struct Tsl2591SysfsConfig {
     pub auto_gain_enabled: bool,
     pub default_gain: u16,
     pub default_integration_time: u16,
-    pub device_address: u8,
-    pub bus_id: u8,
+    #[serde(flatten)]
+    pub i2c: I2cDeviceConfig,
+    #[serde(default = "default_auto_gain_hysteresis")]
+    pub auto_gain_hysteresis: f32,
+    #[serde(default = "default_auto_gain_dwell_samples")]
+    pub auto_gain_dwell_samples: u32,
+    #[serde(default = "default_auto_gain_dwell_ms")]
+    pub auto_gain_dwell_ms: u64,
+    // when set, reads wait up to this many milliseconds for a completed ADC cycle
+    pub adc_ready_timeout: Option<u16>,
+    // EMA weight of the newest illuminance reading, 0 disables smoothing
+    #[serde(default)]
+    pub smoothing_alpha: f32,
+    // counts per lux scale (LUX_DF in the datasheet), depends on the glass in front of the sensor
+    #[serde(default = "default_lux_coefficient")]
+    pub lux_coefficient: f32,
+    // extra chip detection attempts in start for chips that are slow to power up, the delay doubles every time
+    #[serde(default)]
+    pub start_retries: u32,
+    #[serde(default = "default_start_retry_delay_ms")]
+    pub start_retry_delay_ms: u64,
+    // linear correction of the illuminance, the raw channel counts are left alone
+    #[serde(default = "default_calibration_scale")]
+    pub calibration_scale: f32,
+    #[serde(default)]
+    pub calibration_offset: f32,
+}
+
+fn default_calibration_scale() -> f32 {
+    1.0
+}
+
+fn default_start_retry_delay_ms() -> u64 {
+    retry::DEFAULT_START_RETRY_DELAY_MS
+}
+
+fn default_auto_gain_hysteresis() -> f32 {
+    DEFAULT_AUTO_GAIN_HYSTERESIS
+}
+
+fn default_auto_gain_dwell_samples() -> u32 {
+    DEFAULT_AUTO_GAIN_DWELL_SAMPLES
+}
+
+fn default_auto_gain_dwell_ms() -> u64 {
+    DEFAULT_AUTO_GAIN_DWELL_MS
+}
+
+fn default_lux_coefficient() -> f32 {
+    DEFAULT_LUX_COEFFICIENT
+}
+
+fn check_lux_coefficient(coefficient: f32) -> Result<(), String> {
+    if !coefficient.is_finite() || coefficient <= 0.0 {
+        return Err(format!("invalid lux coefficient: {}, value must be greater than zero", coefficient));
+    }
+
+    Ok(())
 }
 
 impl Default for Tsl2591SysfsConfig {
@@ -152,74 +221,354 @@ impl Default for Tsl2591SysfsConfig {
             auto_gain_enabled: true,
             default_gain: GainValue::_1X.into_multiplier(),
             default_integration_time: IntegrationTime::_100MS.into_millis(),
-            device_address: DEFAULT_I2C_ADDR,
-            bus_id: 0,
+            i2c: I2cDeviceConfig::new(0, DEFAULT_I2C_ADDR),
+            auto_gain_hysteresis: DEFAULT_AUTO_GAIN_HYSTERESIS,
+            auto_gain_dwell_samples: DEFAULT_AUTO_GAIN_DWELL_SAMPLES,
+            auto_gain_dwell_ms: DEFAULT_AUTO_GAIN_DWELL_MS,
+            adc_ready_timeout: None,
+            smoothing_alpha: 0.0,
+            lux_coefficient: DEFAULT_LUX_COEFFICIENT,
+            start_retries: 0,
+            start_retry_delay_ms: retry::DEFAULT_START_RETRY_DELAY_MS,
+            calibration_scale: 1.0,
+            calibration_offset: 0.0,
+        }
+    }
+}
+
+pub(crate) fn max_count(integration_time: IntegrationTime) -> u16 {
+    let cycles = integration_time.into_millis() as u32 / 100;
+    (COUNTS_PER_CYCLE * cycles - 1).min(u16::MAX as u32) as u16
+}
+
+pub(crate) fn compute_lux(
+    integration_time: IntegrationTime,
+    gain: GainValue,
+    mut c0: u16,
+    c1: u16,
+    lux_coefficient: f32,
+) -> f32 {
+    // bug fix for thing
+    if c0 == 0x0000 {
+        c0 = 1;
+    }
+
+    let cpl = (integration_time.into_millis() as f32 * gain.into_multiplier() as f32) / lux_coefficient;
+    ((c0 as f32 - c1 as f32) * (1.0 - (c1 as f32 / c0 as f32))) / cpl
+}
+
+pub(crate) fn is_overflow(integration_time: IntegrationTime, c0: u16, c1: u16) -> bool {
+    let max_count = max_count(integration_time);
+    c0 >= max_count || c1 >= max_count
+}
+
+// Works the same way as the esphome tsl2591 compensation algorithm does.
+// Tries to keep the sensor saturation within the (1/3; 2/3) range.
+// The hysteresis margin pushes the step-up thresholds down and the step-down thresholds up
+// so that a reading sitting right on a boundary doesn't bounce between two gain values.
+pub(crate) fn compute_auto_gain(
+    current_gain: GainValue,
+    integration_time: IntegrationTime,
+    c0: u16,
+    hysteresis: f32,
+) -> GainValue {
+    let divider = if integration_time == IntegrationTime::_100MS {
+        2
+    } else {
+        1
+    };
+    let step_up = |threshold: u16| (threshold as f32 * (1.0 - hysteresis)) as u16;
+    let step_down =
+        |threshold: u16| (threshold as f32 * (1.0 + hysteresis)).min(u16::MAX as f32) as u16;
+    let saturation = ((u16::MAX as f32) * 0.945) as u16 / divider;
+
+    match current_gain {
+        GainValue::_1X => {
+            if c0 < step_up((u16::MAX / 3) / GainValue::_428X.into_multiplier()) {
+                // Very low, go up to high
+                GainValue::_428X
+            } else if c0 < step_up((u16::MAX / 3) / GainValue::_25X.into_multiplier()) {
+                // Kinda low, go up to med
+                GainValue::_25X
+            } else {
+                current_gain
+            }
+        }
+        GainValue::_25X => {
+            if c0
+                < step_up(
+                    (u16::MAX / 3)
+                        / (GainValue::_9876X.into_multiplier() / GainValue::_25X.into_multiplier()),
+                )
+            {
+                // Very low, go up to max
+                GainValue::_9876X
+            } else if c0
+                < step_up(
+                    (u16::MAX / 3)
+                        / (GainValue::_428X.into_multiplier() / GainValue::_25X.into_multiplier()),
+                )
+            {
+                // Kinda low, go up to high
+                GainValue::_428X
+            } else if c0 > step_down(saturation) {
+                // Too high, go down to low
+                GainValue::_1X
+            } else {
+                current_gain
+            }
+        }
+        GainValue::_428X => {
+            if c0
+                < step_up(
+                    (u16::MAX / 3)
+                        / (GainValue::_9876X.into_multiplier() / GainValue::_428X.into_multiplier()),
+                )
+            {
+                // Kinda low, go up to max
+                GainValue::_9876X
+            } else if c0 > step_down(saturation) {
+                // Too high, go down to mid
+                GainValue::_25X
+            } else {
+                current_gain
+            }
+        }
+        GainValue::_9876X => {
+            if c0 > step_down(saturation) {
+                // Too high, go down to high
+                GainValue::_428X
+            } else {
+                current_gain
+            }
+        }
+    }
+}
+
+// Rate limits auto gain changes, a new gain is only proposed once both the
+// sample and time based dwell windows since the last change have passed.
+pub(crate) struct AutoGainTracker {
+    hysteresis: f32,
+    dwell_samples: u32,
+    dwell_time: Duration,
+    samples_since_change: u32,
+    last_change: Option<Instant>,
+}
+
+impl AutoGainTracker {
+    pub(crate) fn new(hysteresis: f32, dwell_samples: u32, dwell_millis: u64) -> Self {
+        AutoGainTracker {
+            hysteresis: hysteresis,
+            dwell_samples: dwell_samples,
+            dwell_time: Duration::from_millis(dwell_millis),
+            samples_since_change: 0,
+            last_change: None,
+        }
+    }
+
+    pub(crate) fn update(
+        &mut self,
+        current_gain: GainValue,
+        integration_time: IntegrationTime,
+        c0: u16,
+    ) -> Option<GainValue> {
+        self.samples_since_change = self.samples_since_change.saturating_add(1);
+
+        let new_gain = compute_auto_gain(current_gain, integration_time, c0, self.hysteresis);
+        if new_gain == current_gain {
+            return None;
+        }
+
+        if self.in_dwell() {
+            debug!(
+                "Suppressing auto gain change from {:?} to {:?} during dwell window",
+                current_gain, new_gain
+            );
+            return None;
+        }
+
+        Some(new_gain)
+    }
+
+    pub(crate) fn gain_changed(&mut self) {
+        self.samples_since_change = 0;
+        self.last_change = Some(Instant::now());
+    }
+
+    fn in_dwell(&self) -> bool {
+        match self.last_change {
+            Some(time) => {
+                self.samples_since_change <= self.dwell_samples
+                    || time.elapsed() < self.dwell_time
+            }
+            None => false,
         }
     }
 }
 
 // helper methods for managing the device
-fn set_timing_and_gain<T: Write + AsRawFd>(
-    bus: &mut I2c<T>,
-    address: u8,
+fn set_timing_and_gain<T: SmbusTransfer + Write + ?Sized>(
+    bus: &mut T,
+    address: I2cAddress,
     timing: IntegrationTime,
     gain: GainValue,
+    pec: bool,
 ) -> Result<(), Error> {
-    i2c_sysfs::write_register(
+    i2c_sysfs::write_register_with(
         bus,
         address,
         COMMAND_BIT | REGISTER_CONTROL,
         timing as u8 | gain as u8,
+        pec,
     )?;
     Ok(())
 }
 
-fn enable<T: Write + AsRawFd>(bus: &mut I2c<T>, address: u8) -> Result<(), Error> {
-    i2c_sysfs::write_register(
+fn enable<T: SmbusTransfer + Write + ?Sized>(bus: &mut T, address: I2cAddress, pec: bool) -> Result<(), Error> {
+    i2c_sysfs::write_register_with(
         bus,
         address,
         COMMAND_BIT | REGISTER_ENABLE,
         ENABLE_POWERON | ENABLE_AEN,
+        pec,
     )
 }
 
-fn disable<T: Write + AsRawFd>(bus: &mut I2c<T>, address: u8) -> Result<(), Error> {
-    i2c_sysfs::write_register(bus, address, COMMAND_BIT | REGISTER_ENABLE, ENABLE_POWEROFF)
+fn disable<T: SmbusTransfer + Write + ?Sized>(bus: &mut T, address: I2cAddress, pec: bool) -> Result<(), Error> {
+    i2c_sysfs::write_register_with(bus, address, COMMAND_BIT | REGISTER_ENABLE, ENABLE_POWEROFF, pec)
 }
 
-fn is_adc_valid<T: Write + Read + AsRawFd>(bus: &mut I2c<T>, address: u8) -> Result<bool, Error> {
+fn is_adc_valid<T: SmbusTransfer + Write + Read + ?Sized>(bus: &mut T, pec: bool) -> Result<bool, Error> {
     let mut status_buf = [0u8; 1];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | REGISTER_STATUS, &mut status_buf)?;
+    match pec {
+        true => status_buf[0] = bus.read_byte_data(COMMAND_BIT | REGISTER_STATUS)?,
+        false => i2c_sysfs::transfer_block(bus, COMMAND_BIT | REGISTER_STATUS, &mut status_buf)?,
+    }
 
     return Ok((status_buf[0] & 0x01) != 0);
 }
 
-fn get_chip_id<T: Write + Read + AsRawFd>(bus: &mut I2c<T>, address: u8) -> Result<u8, Error> {
+fn wait_adc_valid<T: Write + Read + AsRawFd>(
+    bus: &mut I2c<T>,
+    address: I2cAddress,
+    step: u16,
+    timeout: u16,
+    pec: bool,
+) -> Result<(), DeviceError> {
+    SmbusTransfer::set_slave_address(bus, address).map_err(|e| {
+        DeviceError::hardware(format!("failed to address chip: {}", e), e)
+    })?;
+
+    let elapsed = poll_adc_valid(bus, step, timeout, pec)?;
+    debug!("ADC ready after ~{} ms", elapsed);
+    Ok(())
+}
+
+// Polls the status register of an already addressed chip until the AVALID bit is set,
+// returns the approximate time spent waiting
+pub(crate) fn poll_adc_valid<T: SmbusTransfer + Write + Read + ?Sized>(
+    bus: &mut T,
+    step: u16,
+    timeout: u16,
+    pec: bool,
+) -> Result<u16, DeviceError> {
+    let mut elapsed = 0;
+    let wait_interval = Duration::from_millis(step as u64);
+    loop {
+        match is_adc_valid(bus, pec) {
+            Ok(result) => {
+                if result {
+                    return Ok(elapsed);
+                }
+            }
+            Err(e) => {
+                return Err(DeviceError::hardware(format!("failed to read chip status: {}", e), e))
+            }
+        };
+
+        if elapsed >= timeout {
+            return Err(DeviceError::hardware_error(format!(
+                "timed out waiting for ADC data to become valid"
+            )));
+        }
+
+        elapsed += step;
+        thread::sleep(wait_interval)
+    }
+}
+
+fn get_chip_id<T: SmbusTransfer + Read + Write + ?Sized>(bus: &mut T, address: I2cAddress, pec: bool) -> Result<u8, Error> {
     let mut buf = [0u8; 1];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | REGISTER_ID_ADDR, &mut buf)?;
+    i2c_sysfs::read_register_with(bus, address, COMMAND_BIT | REGISTER_ID_ADDR, &mut buf, pec)?;
 
     Ok(buf[0])
 }
 
-fn read_adc<T: Write + Read + AsRawFd>(bus: &mut I2c<T>, address: u8) -> Result<(u16, u16), Error> {
-    let mut c0_buf = [0u8; 2];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | REGISTER_CHAN0_LSB, &mut c0_buf)?;
+// Checks that a TSL2591 answers at the address, this is the part of start that gets retried
+pub(crate) fn detect_chip<T: SmbusTransfer + Read + Write + ?Sized>(bus: &mut T, bus_id: u8, address: I2cAddress, pec: bool) -> Result<(), DeviceError> {
+    let chip_id = match get_chip_id(bus, address, pec) {
+        Ok(id) => id,
+        Err(e) => {
+            return Err(DeviceError::hardware(format!("failed to identify chip: {}", e), e))
+        }
+    };
+
+    if chip_id != CHIP_ID {
+        return Err(DeviceError::hardware_error(format!(
+            "bus {} address {} contains an invalid device - reported chipID {} but expected {}",
+            bus_id, address, chip_id, CHIP_ID
+        )));
+    }
 
-    let c0 = (c0_buf[1] as u16) << 8 | c0_buf[0] as u16;
+    Ok(())
+}
 
-    let mut c1_buf = [0u8; 2];
-    i2c_sysfs::read_register(bus, address, COMMAND_BIT | REGISTER_CHAN1_LSB, &mut c1_buf)?;
+fn read_adc<T: Write + Read + AsRawFd>(bus: &mut I2c<T>, address: I2cAddress, pec: bool) -> Result<(u16, u16), Error> {
+    // CHAN0 and CHAN1 are laid out back to back, grab both in one go, byte by byte when each read has to carry a PEC
+    let mut adc_buf = [0u8; 4];
+    match pec {
+        true => i2c_sysfs::read_register_pec(bus, address, COMMAND_BIT | REGISTER_CHAN0_LSB, &mut adc_buf)?,
+        false => i2c_sysfs::read_block(bus, address, COMMAND_BIT | REGISTER_CHAN0_LSB, &mut adc_buf)?,
+    }
 
-    let c1 = (c1_buf[1] as u16) << 8 | c1_buf[0] as u16;
+    let c0 = (adc_buf[1] as u16) << 8 | adc_buf[0] as u16;
+    let c1 = (adc_buf[3] as u16) << 8 | adc_buf[2] as u16;
     Ok((c0, c1))
 }
 
+// A single ADC read, the channel counts only make sense together with the settings they were taken at
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) struct Sample {
+    pub c0: u16,
+    pub c1: u16,
+    pub gain: GainValue,
+    pub integration_time: IntegrationTime,
+}
+
+impl Sample {
+    pub(crate) fn is_overflow(&self) -> bool {
+        is_overflow(self.integration_time, self.c0, self.c1)
+    }
+
+    pub(crate) fn lux(&self, lux_coefficient: f32) -> f32 {
+        compute_lux(self.integration_time, self.gain, self.c0, self.c1, lux_coefficient)
+    }
+}
+
 pub struct Tsl2591SysfsDriver {
     auto_gain_enabled: bool,
+    auto_gain: AutoGainTracker,
     config: Tsl2591SysfsConfig,
+    address: I2cAddress,
     bus: Option<I2cBus>,
+    pec: bool,
     gain: GainValue,
     integration_time: IntegrationTime,
+    last_error: Option<DeviceError>,
+    lux_filter: EmaFilter,
+    lux_coefficient: f32,
+    calibration: ReadingCalibration,
+    started_at: Option<Instant>,
     is_loaded: bool,
 }
 
@@ -228,13 +577,13 @@ impl Tsl2591SysfsDriver {
         let gain = match GainValue::from_multiplier(config.default_gain) {
             Some(g) => g,
             None => {
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::InvalidEntry(format!(
                         "invalid gain multiplier: {}, supported gain values are {}",
                         config.default_gain,
                         SUPPORTED_GAIN_VALUES.map(|x| x.to_string()).join(", ")
                     ))
-                    .to_string(),
+                    .to_string()
                 ))
             }
         };
@@ -242,7 +591,7 @@ impl Tsl2591SysfsDriver {
         let integration_time = match IntegrationTime::from_millis(config.default_integration_time) {
             Some(t) => t,
             None => {
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::InvalidEntry(format!(
                         "invalid integration time: {}, supported values are {}",
                         config.default_integration_time,
@@ -250,21 +599,82 @@ impl Tsl2591SysfsDriver {
                             .map(|x| x.to_string())
                             .join(", ")
                     ))
-                    .to_string(),
+                    .to_string()
                 ))
             }
         };
 
+        if !(0.0..1.0).contains(&config.auto_gain_hysteresis) {
+            return Err(DeviceError::invalid_config(
+                ConfigError::InvalidEntry(format!(
+                    "invalid auto gain hysteresis: {}, value must be within [0; 1)",
+                    config.auto_gain_hysteresis
+                ))
+                .to_string()
+            ));
+        }
+
+        if let Err(e) = EmaFilter::validate_alpha(config.smoothing_alpha) {
+            return Err(DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string()));
+        }
+
+        if let Err(e) = check_lux_coefficient(config.lux_coefficient) {
+            return Err(DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string()));
+        }
+
+        let calibration = ReadingCalibration::new(config.calibration_scale, config.calibration_offset)
+            .map_err(|e| DeviceError::invalid_config(ConfigError::InvalidEntry(e).to_string()))?;
+        let lux_filter = EmaFilter::new(config.smoothing_alpha);
+
+        let address = config.i2c.address().map_err(|e| DeviceError::invalid_config(e.to_string()))?;
+
         Ok(Self {
+            address,
             auto_gain_enabled: config.auto_gain_enabled,
+            auto_gain: AutoGainTracker::new(
+                config.auto_gain_hysteresis,
+                config.auto_gain_dwell_samples,
+                config.auto_gain_dwell_ms,
+            ),
+            lux_coefficient: config.lux_coefficient,
             config: config,
             bus: None,
+            pec: false,
             gain: gain,
             integration_time: integration_time,
+            last_error: None,
+            lux_filter: lux_filter,
+            calibration,
+            started_at: None,
             is_loaded: false,
         })
     }
 
+    // Identifies and powers on the chip
+    fn init_chip(&self, transaction: &mut I2c<File>) -> Result<(), DeviceError> {
+        let address = self.address;
+        let bus_id = self.config.i2c.bus_id;
+        let pec = self.pec;
+        StartRetry::new(self.config.start_retries, self.config.start_retry_delay_ms)
+            .run("detect TSL2591", || detect_chip(transaction, bus_id, address, pec))?;
+
+        if let Err(e) = enable(transaction, address, pec) {
+            return Err(DeviceError::hardware(format!("failed to enable device: {}", e), e));
+        }
+
+        if let Err(e) = set_timing_and_gain(
+            transaction,
+            self.address,
+            self.integration_time,
+            self.gain,
+            pec,
+        ) {
+            warn!("Failed to set initial timing and gain: {}", e);
+        }
+
+        Ok(())
+    }
+
     fn assert_state(&self, check_bus: bool) -> Result<(), DeviceError> {
         if self.is_loaded && (!check_bus || self.bus.is_some()) {
             Ok(())
@@ -275,99 +685,103 @@ impl Tsl2591SysfsDriver {
         }
     }
 
-    fn get_sensor_data(&mut self) -> Result<(u16, u16), DeviceError> {
+    // Swaps in new gain, integration time and auto gain settings, the chip is reprogrammed when a bus is given
+    pub(crate) fn reconfigure<T: SmbusTransfer + Write + ?Sized>(
+        &mut self,
+        data: Tsl2591SysfsConfig,
+        bus: Option<&mut T>,
+    ) -> Result<(), DeviceError> {
+        // the chip has to be detected again on a different bus or address, the bus priority is set when it is opened
+        if data.i2c != self.config.i2c {
+            return Err(DeviceError::NotSupported);
+        }
+
+        let mut updated = Self::from_config(data)?;
+        if let Some(bus) = bus {
+            set_timing_and_gain(bus, updated.address, updated.integration_time, updated.gain, self.pec)
+                .map_err(|e| DeviceError::hardware(format!("failed to apply new timing and gain: {}", e), e))?;
+        }
+
+        debug!("Applying new config, gain {:?} and integration time {:?}", updated.gain, updated.integration_time);
+        updated.bus = self.bus.take();
+        updated.pec = self.pec;
+        updated.last_error = self.last_error.take();
+        updated.started_at = self.started_at;
+        updated.is_loaded = self.is_loaded;
+        *self = updated;
+        Ok(())
+    }
+
+    pub fn get_max_count(&self) -> u16 {
+        max_count(self.integration_time)
+    }
+
+    fn get_sensor_data(&mut self) -> Result<Sample, DeviceError> {
+        let result = self.read_sensor_data();
+        if let Err(e) = &result {
+            self.last_error = Some(e.clone());
+        }
+
+        result
+    }
+
+    fn read_sensor_data(&mut self) -> Result<Sample, DeviceError> {
         self.assert_state(true)?;
-        let mut transaction = self.bus.as_ref().unwrap().lock();
+        let bus = self.bus.clone().unwrap();
+        let mut transaction = bus.lock();
 
-        let (c0, c1) = read_adc(&mut transaction, self.config.device_address).map_err(|e| {
-            DeviceError::HardwareError(format!("failed to read sensor data: {}", e))
+        if let Some(timeout) = self.config.adc_ready_timeout {
+            wait_adc_valid(&mut transaction, self.address, SPINWAIT_INTERVAL, timeout, self.pec)?;
+        }
+
+        let (c0, c1) = read_adc(&mut transaction, self.address, self.pec).map_err(|e| {
+            DeviceError::hardware(format!("failed to read sensor data: {}", e), e)
         })?;
 
+        Ok(self.take_sample(&mut *transaction, c0, c1))
+    }
+
+    // Tags the counts with the gain and integration time they were integrated at before auto gain
+    // gets a chance to move the chip on to a new gain for the next read
+    pub(crate) fn take_sample<T: SmbusTransfer + Write + ?Sized>(&mut self, bus: &mut T, c0: u16, c1: u16) -> Sample {
+        let sample = Sample {
+            c0,
+            c1,
+            gain: self.gain,
+            integration_time: self.integration_time,
+        };
+
         if self.auto_gain_enabled {
-            drop(transaction);
-            self.auto_gain_update(c0);
+            self.auto_gain_update(bus, c0);
         }
 
-        Ok((c0, c1))
+        sample
     }
 
-    // Works the same way as the esphome tsl2591 compensation algorithm does.
-    // Tries to keep the sensor saturation within the (1/3; 2/3) range.
-    fn auto_gain_update(&mut self, c0: u16) {
-        let divider = if self.integration_time == IntegrationTime::_100MS {
-            2
-        } else {
-            1
-        };
+    fn auto_gain_update<T: SmbusTransfer + Write + ?Sized>(&mut self, bus: &mut T, c0: u16) {
         let current_gain = self.gain;
-        let mut new_gain = current_gain;
-
-        match current_gain {
-            GainValue::_1X => {
-                if c0 < (u16::MAX / 3) / GainValue::_428X.into_multiplier() {
-                    // Very low, go up to high
-                    new_gain = GainValue::_428X;
-                } else if c0 < (u16::MAX / 3) / GainValue::_25X.into_multiplier() {
-                    // Kinda low, go up to med
-                    new_gain = GainValue::_25X;
-                }
-            }
-            GainValue::_25X => {
-                if c0
-                    < (u16::MAX / 3)
-                        / (GainValue::_9876X.into_multiplier() / GainValue::_25X.into_multiplier())
-                {
-                    // Very low, go up to max
-                    new_gain = GainValue::_9876X;
-                } else if c0
-                    < (u16::MAX / 3)
-                        / (GainValue::_428X.into_multiplier() / GainValue::_25X.into_multiplier())
-                {
-                    // Kinda low, go up to high
-                    new_gain = GainValue::_428X;
-                } else if c0 > ((u16::MAX as f32) * 0.945) as u16 / divider {
-                    // Too high, go down to low
-                    new_gain = GainValue::_1X;
-                }
-            }
-            GainValue::_428X => {
-                if c0
-                    < (u16::MAX / 3)
-                        / (GainValue::_9876X.into_multiplier() / GainValue::_428X.into_multiplier())
-                {
-                    // Kinda low, go up to max
-                    new_gain = GainValue::_9876X;
-                } else if c0 > ((u16::MAX as f32) * 0.945) as u16 / divider {
-                    // Too high, go down to mid
-                    new_gain = GainValue::_25X;
-                }
-            }
-            GainValue::_9876X => {
-                if c0 > ((u16::MAX as f32) * 0.945) as u16 / divider {
-                    // Too high, go down to high
-                    new_gain = GainValue::_428X;
-                }
-            }
+        let new_gain = match self.auto_gain.update(current_gain, self.integration_time, c0) {
+            Some(gain) => gain,
+            None => return,
         };
 
-        if current_gain != new_gain {
-            debug!(
-                "Auto gain updating from {:?} to {:?}",
-                current_gain, new_gain
-            );
-            let mut transaction = self.bus.as_ref().unwrap().lock();
-            match set_timing_and_gain(
-                &mut transaction,
-                self.config.device_address,
-                self.integration_time,
-                new_gain,
-            ) {
-                Ok(_) => {
-                    self.gain = new_gain;
-                }
-                Err(e) => {
-                    warn!("Failed to auto update gain: {}", e);
-                }
+        debug!(
+            "Auto gain updating from {:?} to {:?}",
+            current_gain, new_gain
+        );
+        match set_timing_and_gain(
+            bus,
+            self.address,
+            self.integration_time,
+            new_gain,
+            self.pec,
+        ) {
+            Ok(_) => {
+                self.gain = new_gain;
+                self.auto_gain.gain_changed();
+            }
+            Err(e) => {
+                warn!("Failed to auto update gain: {}", e);
             }
         }
     }
@@ -382,6 +796,10 @@ impl DeviceDriver for Tsl2591SysfsDriver {
         self
     }
 
+    fn required_controllers(&self) -> Vec<&'static str> {
+        vec!["i2c_sysfs"]
+    }
+
     fn name(&self) -> String {
         "tsl2591_sysfs".to_string()
     }
@@ -397,8 +815,8 @@ impl DeviceDriver for Tsl2591SysfsDriver {
         Self: Sized,
     {
         if config.is_none() {
-            return Err(DeviceError::InvalidConfig(
-                "this driver requires a configuration object but none was provided".to_owned(),
+            return Err(DeviceError::invalid_config(
+                "this driver requires a configuration object but none was provided".to_owned()
             ));
         }
 
@@ -410,17 +828,17 @@ impl DeviceDriver for Tsl2591SysfsDriver {
                     match serde_json::to_value(Tsl2591SysfsConfig::default()) {
                         Ok(c) => {
                             config.driver_data = c;
-                            return Err(DeviceError::InvalidConfig(
+                            return Err(DeviceError::invalid_config(
                                 ConfigError::MissingEntry(
                                     "device was missing config data, default config was written"
                                         .to_string(),
                                 )
-                                .to_string(),
+                                .to_string()
                             ));
                         }
                         Err(e) => {
                             warn!("Failed to write default configuration: {}", e);
-                            return Err(DeviceError::InvalidConfig(
+                            return Err(DeviceError::invalid_config(
                                 ConfigError::MissingEntry(
                                     format!("device was missing config data, default config failed to be written: {}", e)
                                 ).to_string()
@@ -429,12 +847,12 @@ impl DeviceDriver for Tsl2591SysfsDriver {
                     }
                 }
 
-                return Err(DeviceError::InvalidConfig(
+                return Err(DeviceError::invalid_config(
                     ConfigError::SerializeError(format!(
                         "failed to deserialize device config data: {}",
                         e
                     ))
-                    .to_string(),
+                    .to_string()
                 ));
             }
         };
@@ -442,6 +860,19 @@ impl DeviceDriver for Tsl2591SysfsDriver {
         Self::from_config(data)
     }
 
+    fn apply_config(&mut self, config: &crate::config::DeviceConfig) -> Result<(), DeviceError> {
+        let data: Tsl2591SysfsConfig = serde_json::from_value(config.driver_data.clone()).map_err(|e| {
+            DeviceError::invalid_config(
+                ConfigError::SerializeError(format!("failed to deserialize device config data: {}", e)).to_string()
+            )
+        })?;
+
+        match self.bus.clone() {
+            Some(bus) => self.reconfigure(data, Some(&mut *bus.lock())),
+            None => self.reconfigure::<I2c<File>>(data, None)
+        }
+    }
+
     fn start(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
         if self.is_loaded {
             return Err(DeviceError::InvalidOperation(
@@ -449,60 +880,36 @@ impl DeviceDriver for Tsl2591SysfsDriver {
             ));
         }
 
-        let address = self.config.device_address;
-        let bus_id = self.config.bus_id;
-
+        let bus_id = self.config.i2c.bus_id;
         let mut i2c = match parent.get_bus_mut::<SysfsI2CBusController>() {
             Some(controller) => controller,
             None => return Err(DeviceError::MissingController("i2c_sysfs".to_string())),
         };
 
-        let bus = match i2c.get(bus_id) {
+        let bus = match i2c.client(&self.config.i2c, &format!("{}@{}", self.name(), self.address)) {
             Ok(bus) => bus,
-            Err(e) => return Err(DeviceError::HardwareError(e.to_string())),
+            Err(e) => return Err(DeviceError::hardware_error(e.to_string())),
         };
 
-        let mut transaction = bus.lock();
-        let chip_id = match get_chip_id(&mut transaction, address) {
-            Ok(id) => id,
-            Err(e) => {
-                return Err(DeviceError::HardwareError(format!(
-                    "failed to identify chip: {}",
-                    e
-                )))
+        self.pec = i2c.pec_enabled();
+        let init = self.init_chip(&mut bus.lock());
+        if let Err(e) = init {
+            drop(bus);
+            if let Err(e) = i2c.close(bus_id) {
+                warn!("Failed to release I2C bus {} while recovering from an error: {}", bus_id, e);
             }
-        };
-
-        if chip_id != CHIP_ID {
-            return Err(DeviceError::HardwareError(format!(
-                "bus {} address {} contains an invalid device - reported chipID {} but expected {}",
-                bus_id, address, chip_id, CHIP_ID
-            )));
-        }
-
-        if let Err(e) = enable(&mut transaction, address) {
-            return Err(DeviceError::HardwareError(format!(
-                "failed to enable device: {}",
-                e
-            )));
-        }
 
-        if let Err(e) = set_timing_and_gain(
-            &mut transaction,
-            self.config.device_address,
-            self.integration_time,
-            self.gain,
-        ) {
-            warn!("Failed to set initial timing and gain: {}", e);
+            return Err(e);
         }
 
-        drop(transaction);
         self.bus = Some(bus);
+        self.lux_filter.reset();
+        self.started_at = Some(Instant::now());
         self.is_loaded = true;
         Ok(())
     }
 
-    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+    fn stop(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
         if !self.is_loaded {
             return Err(DeviceError::InvalidOperation(
                 "device unload requested but this device isn't loaded".to_string(),
@@ -511,17 +918,29 @@ impl DeviceDriver for Tsl2591SysfsDriver {
 
         match self.bus {
             Some(ref bus) => {
-                let address = self.config.device_address;
+                let address = self.address;
                 let mut transaction = bus.lock();
 
-                if let Err(e) = disable(&mut transaction, address) {
+                if let Err(e) = disable(&mut *transaction, address, self.pec) {
                     warn!("Failed to disable device: {}", e);
                 }
             }
             None => warn!("Failed to disable hardware: I2C bus was uninitialized"),
         };
 
-        self.bus = None;
+        // other devices may still be using the bus, the controller only closes it after the last one
+        if self.bus.take().is_some() {
+            match parent.get_bus_mut::<SysfsI2CBusController>() {
+                Some(mut i2c) => {
+                    if let Err(e) = i2c.close(self.config.i2c.bus_id) {
+                        warn!("Failed to release I2C bus {}: {}", self.config.i2c.bus_id, e);
+                    }
+                }
+                None => warn!("Failed to release I2C bus: controller is unavailable"),
+            }
+        }
+
+        self.started_at = None;
         self.is_loaded = false;
         Ok(())
     }
@@ -530,7 +949,7 @@ impl DeviceDriver for Tsl2591SysfsDriver {
 impl Capability for Tsl2591SysfsDriver {}
 
 #[cast_to]
-impl LightSensorCapable for Tsl2591SysfsDriver {
+impl SensorCapable for Tsl2591SysfsDriver {
     fn get_supported_gains(&self) -> HashMap<u8, u16> {
         SUPPORTED_GAIN_VALUES
             .iter()
@@ -547,31 +966,12 @@ impl LightSensorCapable for Tsl2591SysfsDriver {
             .collect()
     }
 
-    fn get_supported_channels(&self) -> HashMap<u8, String> {
-        SUPPORTED_CHANNELS
-            .iter()
-            .enumerate()
-            .map(|(index, &value)| (index as u8, value.to_owned()))
-            .collect()
-    }
-
-    fn get_auto_gain_enabled(&self) -> Result<bool, DeviceError> {
-        self.assert_state(false)?;
-        Ok(self.auto_gain_enabled)
-    }
-
-    fn set_auto_gain_enabled(&mut self, _enabled: bool) -> Result<(), DeviceError> {
-        self.assert_state(false)?;
-        self.auto_gain_enabled = _enabled;
-        Ok(())
-    }
-
-    fn get_gain(&self) -> Result<u16, DeviceError> {
+    fn get_gain(&self, _reading: CapabilityId) -> Result<u16, DeviceError> {
         self.assert_state(false)?;
         Ok(self.gain.into_multiplier())
     }
 
-    fn set_gain(&mut self, gain_id: u8) -> Result<(), DeviceError> {
+    fn set_gain(&mut self, _reading: CapabilityId, gain_id: u8) -> Result<(), DeviceError> {
         self.assert_state(true)?;
         let gain_multiplier = match SUPPORTED_GAIN_VALUES.get(gain_id as usize) {
             Some(gain) => gain,
@@ -593,16 +993,18 @@ impl LightSensorCapable for Tsl2591SysfsDriver {
 
         let mut transaction = self.bus.as_ref().unwrap().lock();
         set_timing_and_gain(
-            &mut transaction,
-            self.config.device_address,
+            &mut *transaction,
+            self.address,
             self.integration_time,
             gain_value,
+            self.pec,
         )
         .map_err(|e| {
-            DeviceError::HardwareError(format!("failed to apply new gain value: {}", e))
+            DeviceError::hardware(format!("failed to apply new gain value: {}", e), e)
         })?;
 
         self.gain = gain_value;
+        self.auto_gain.gain_changed();
         Ok(())
     }
 
@@ -633,19 +1035,51 @@ impl LightSensorCapable for Tsl2591SysfsDriver {
 
         let mut transaction = self.bus.as_ref().unwrap().lock();
         set_timing_and_gain(
-            &mut transaction,
-            self.config.device_address,
+            &mut *transaction,
+            self.address,
             integration_time,
             self.gain,
+            self.pec,
         )
         .map_err(|e| {
-            DeviceError::HardwareError(format!("failed to apply new integration time: {}", e))
+            DeviceError::hardware(format!("failed to apply new integration time: {}", e), e)
         })?;
 
         self.integration_time = integration_time;
         Ok(())
     }
 
+    fn get_calibration(&self, _reading: CapabilityId) -> Result<ReadingCalibration, DeviceError> {
+        Ok(self.calibration)
+    }
+
+    fn set_calibration(&mut self, _reading: CapabilityId, calibration: ReadingCalibration) -> Result<(), DeviceError> {
+        self.calibration = ReadingCalibration::new(calibration.scale, calibration.offset).map_err(DeviceError::InvalidOperation)?;
+        Ok(())
+    }
+}
+
+#[cast_to]
+impl LightSensorCapable for Tsl2591SysfsDriver {
+    fn get_supported_channels(&self) -> HashMap<u8, String> {
+        SUPPORTED_CHANNELS
+            .iter()
+            .enumerate()
+            .map(|(index, &value)| (index as u8, value.to_owned()))
+            .collect()
+    }
+
+    fn get_auto_gain_enabled(&self) -> Result<bool, DeviceError> {
+        self.assert_state(false)?;
+        Ok(self.auto_gain_enabled)
+    }
+
+    fn set_auto_gain_enabled(&mut self, _enabled: bool) -> Result<(), DeviceError> {
+        self.assert_state(false)?;
+        self.auto_gain_enabled = _enabled;
+        Ok(())
+    }
+
     fn get_luminosity(&mut self, channel_id: u8) -> Result<u32, DeviceError> {
         self.assert_state(false)?;
 
@@ -659,7 +1093,7 @@ impl LightSensorCapable for Tsl2591SysfsDriver {
             }
         };
 
-        let (c0, c1) = self.get_sensor_data()?;
+        let Sample { c0, c1, .. } = self.get_sensor_data()?;
 
         match channel {
             ChannelId::FullSpectrum => Ok(c0.into()),
@@ -676,28 +1110,65 @@ impl LightSensorCapable for Tsl2591SysfsDriver {
 
     fn get_illuminance(&mut self) -> Result<f32, DeviceError> {
         self.assert_state(false)?;
-        let integration_time = self.integration_time.into_millis() as f32;
-        let gain_value = self.gain.into_multiplier() as f32;
+        let sample = self.get_sensor_data()?;
+        if sample.is_overflow() {
+            return Err(DeviceError::Other("sensor reading overflow".to_string()));
+        }
 
-        let (mut c0, c1) = self.get_sensor_data()?;
-        let overflow_value = if self.integration_time == IntegrationTime::_100MS {
-            36863
-        } else {
-            65535
-        };
+        let lux = sample.lux(self.lux_coefficient);
+        Ok(self.calibration.apply(self.lux_filter.apply(lux)))
+    }
+
+    fn get_lux_coefficient(&self) -> Result<f32, DeviceError> {
+        Ok(self.lux_coefficient)
+    }
+
+    fn set_lux_coefficient(&mut self, coefficient: f32) -> Result<(), DeviceError> {
+        check_lux_coefficient(coefficient).map_err(DeviceError::InvalidOperation)?;
+        self.lux_coefficient = coefficient;
+        // smoothed values from the old scale would drag the new readings
+        self.lux_filter.reset();
+        Ok(())
+    }
 
-        if c0 == overflow_value || c1 == overflow_value {
+    fn reset_filter(&mut self) -> Result<(), DeviceError> {
+        self.lux_filter.reset();
+        Ok(())
+    }
+
+    // Estimated from the IR share of the full spectrum channel, see capabilities::estimate_color_temperature
+    fn get_color_temperature(&mut self) -> Result<f32, DeviceError> {
+        self.assert_state(false)?;
+        let sample = self.get_sensor_data()?;
+        if sample.is_overflow() {
             return Err(DeviceError::Other("sensor reading overflow".to_string()));
         }
 
-        // bug fix for thing
-        if c0 == 0x0000 {
-            c0 = 1;
+        capabilities::estimate_color_temperature(sample.c0.into(), sample.c1.into())
+    }
+
+    // 88k lux is the top of the datasheet's dynamic range, the channels are raw 16 bit ADC counts
+    fn get_units(&self) -> Vec<ReadingUnit> {
+        vec![
+            ReadingUnit::new("illuminance", "lx", 0.0, 88000.0),
+            ReadingUnit::new("luminosity", "counts", 0.0, u16::MAX as f32),
+            ReadingUnit::new("color_temperature", "K", capabilities::MIN_COLOR_TEMPERATURE, capabilities::MAX_COLOR_TEMPERATURE),
+        ]
+    }
+}
+
+#[cast_to]
+impl DiagnosticsCapable for Tsl2591SysfsDriver {
+    fn self_test(&mut self) -> Result<HealthReport, DeviceError> {
+        if self.assert_state(true).is_err() {
+            return Ok(HealthReport::new(None, CHIP_ID, self.last_error.as_ref(), Duration::ZERO));
         }
 
-        let cpl = (integration_time * gain_value) / LUX_DF;
-        let lux = ((c0 as f32 - c1 as f32) * (1.0 - (c1 as f32 / c0 as f32))) / cpl;
+        let chip_id = {
+            let mut transaction = self.bus.as_ref().unwrap().lock();
+            get_chip_id(&mut *transaction, self.address, self.pec)
+        };
 
-        Ok(lux)
+        Ok(HealthReport::from_chip_id_read(chip_id, CHIP_ID, &mut self.last_error, self.started_at))
     }
 }
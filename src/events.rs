@@ -0,0 +1,212 @@
+use crate::device::DeviceError;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+const ERROR_SPIKE_THRESHOLD: u32 = 5;
+const ERROR_SPIKE_WINDOW: Duration = Duration::from_secs(10);
+pub const DEFAULT_UNHEALTHY_ERROR_THRESHOLD: u64 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceEventKind {
+    DeviceRegistered,
+    DeviceRemoved,
+    DeviceStarted,
+    DeviceStopped,
+    HardwareErrors { count: u32, last_error: String },
+    GeofenceEntered { geofence: String },
+    GeofenceExited { geofence: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceEvent {
+    pub kind: DeviceEventKind,
+    pub address: Uuid,
+    pub timestamp: SystemTime,
+}
+
+impl DeviceEvent {
+    pub fn timestamp_ms(&self) -> u64 {
+        self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+// What a device has served since it was last started, kept up to date from the RPC paths
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DeviceStats {
+    // None while the device isn't running
+    pub started_at: Option<Instant>,
+    pub read_count: u64,
+    pub error_count: u64,
+    // hardware errors since the last read that went through
+    pub consecutive_errors: u64,
+    // outcome of the last diagnostics self-test, None until one has run
+    pub self_test_healthy: Option<bool>,
+}
+
+impl DeviceStats {
+    pub fn uptime(&self) -> Option<Duration> {
+        self.started_at.map(|x| x.elapsed())
+    }
+}
+
+struct ErrorWindow {
+    started: Instant,
+    count: u32,
+}
+
+// Fan-out of device events, receivers that fall behind lose the oldest events instead of blocking senders
+#[derive(Clone)]
+pub struct DeviceEvents {
+    sender: broadcast::Sender<DeviceEvent>,
+    errors: Arc<Mutex<HashMap<Uuid, ErrorWindow>>>,
+    stats: Arc<Mutex<HashMap<Uuid, DeviceStats>>>,
+    unhealthy_after: Arc<AtomicU64>,
+}
+
+impl DeviceEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            sender,
+            errors: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::new(Mutex::new(HashMap::new())),
+            unhealthy_after: Arc::new(AtomicU64::new(DEFAULT_UNHEALTHY_ERROR_THRESHOLD)),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn emit(&self, address: Uuid, kind: DeviceEventKind) {
+        // stats start over with every start of the device
+        match kind {
+            DeviceEventKind::DeviceStarted => {
+                self.stats.lock().insert(address, DeviceStats { started_at: Some(Instant::now()), ..Default::default() });
+            }
+            DeviceEventKind::DeviceStopped => {
+                if let Some(stats) = self.stats.lock().get_mut(&address) {
+                    stats.started_at = None;
+                }
+            }
+            DeviceEventKind::DeviceRemoved => {
+                self.stats.lock().remove(&address);
+            }
+            _ => {}
+        }
+
+        // Sending only fails when nobody is subscribed
+        let _ = self.sender.send(DeviceEvent {
+            kind,
+            address,
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    // Counts towards the device stats, and emits once per window when a device keeps failing with hardware errors
+    pub fn report_error(&self, address: Uuid, err: &DeviceError) {
+        let mut stats = self.stats.lock();
+        let entry = stats.entry(address).or_default();
+        entry.error_count += 1;
+        if !matches!(err, DeviceError::HardwareError(..)) {
+            return;
+        }
+
+        entry.consecutive_errors += 1;
+        drop(stats);
+
+        let mut errors = self.errors.lock();
+        let window = errors.entry(address).or_insert(ErrorWindow {
+            started: Instant::now(),
+            count: 0,
+        });
+
+        if window.started.elapsed() > ERROR_SPIKE_WINDOW {
+            window.started = Instant::now();
+            window.count = 0;
+        }
+
+        window.count += 1;
+        if window.count == ERROR_SPIKE_THRESHOLD {
+            let count = window.count;
+            drop(errors);
+            self.emit(address, DeviceEventKind::HardwareErrors { count, last_error: err.to_string() });
+        }
+    }
+
+    pub fn record_read(&self, address: Uuid) {
+        self.stats.lock().entry(address).or_default().read_count += 1;
+    }
+
+    // A read that went through, the device is healthy again as far as its errors go
+    pub fn record_success(&self, address: Uuid) {
+        self.stats.lock().entry(address).or_default().consecutive_errors = 0;
+    }
+
+    pub fn record_self_test(&self, address: Uuid, healthy: bool) {
+        self.stats.lock().entry(address).or_default().self_test_healthy = Some(healthy);
+    }
+
+    pub fn stats(&self, address: &Uuid) -> DeviceStats {
+        self.stats.lock().get(address).copied().unwrap_or_default()
+    }
+
+    // Hardware errors in a row before a device counts as unhealthy
+    pub fn set_unhealthy_threshold(&self, errors: u64) {
+        self.unhealthy_after.store(errors, Ordering::Relaxed);
+    }
+
+    // Unhealthy after too many hardware errors in a row or a failed self-test
+    pub fn is_healthy(&self, address: &Uuid) -> bool {
+        let stats = self.stats(address);
+        stats.consecutive_errors < self.unhealthy_after.load(Ordering::Relaxed) && stats.self_test_healthy != Some(false)
+    }
+
+    pub fn sink(&self, address: Uuid) -> DeviceEventSink {
+        DeviceEventSink { events: self.clone(), address }
+    }
+
+    pub fn clear_errors(&self, address: &Uuid) {
+        self.errors.lock().remove(address);
+    }
+}
+
+// DeviceEvents bound to one device, given to drivers that raise events of their own
+#[derive(Clone)]
+pub struct DeviceEventSink {
+    events: DeviceEvents,
+    address: Uuid,
+}
+
+impl DeviceEventSink {
+    pub fn emit(&self, kind: DeviceEventKind) {
+        self.events.emit(self.address, kind);
+    }
+
+    pub fn address(&self) -> Uuid {
+        self.address
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.events.is_healthy(&self.address)
+    }
+}
+
+impl Default for DeviceEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
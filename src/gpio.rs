@@ -1,10 +1,19 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, time::Duration};
 use uuid::Uuid;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PinDirection {
+    Input,
+    Output
+}
+
 pub struct PinState {
     pin_number: u8,
     bcm_id: u8,
-    leased: bool
+    active_low: bool,
+    leased: bool,
+    direction: Option<PinDirection>,
+    last_value: Option<u8>
 }
 
 impl PinState {
@@ -12,10 +21,18 @@ impl PinState {
         PinState {
             pin_number: pin_number,
             bcm_id: bcm_id,
-            leased: false
+            active_low: false,
+            leased: false,
+            direction: None,
+            last_value: None
         }
     }
 
+    pub fn with_active_low(mut self, active_low: bool) -> Self {
+        self.active_low = active_low;
+        self
+    }
+
     pub fn pin_id(&self) -> u8 {
         self.pin_number
     }
@@ -23,16 +40,83 @@ impl PinState {
     pub fn bcm_id(&self) -> u8 {
         self.bcm_id
     }
+
+    pub fn is_active_low(&self) -> bool {
+        self.active_low
+    }
+
+    pub fn is_leased(&self) -> bool {
+        self.leased
+    }
+
+    // Only known while a controller holds the pin
+    pub fn direction(&self) -> Option<PinDirection> {
+        self.direction
+    }
+
+    // Logical value last written or read through a controller
+    pub fn last_value(&self) -> Option<u8> {
+        self.last_value
+    }
+
+    // Maps a logical value to the line level and back, the inversion is its own inverse
+    pub fn apply_polarity(&self, value: u8) -> u8 {
+        ((value != 0) != self.active_low) as u8
+    }
+}
+
+// Time between samples taken by a debounced read
+pub const DEBOUNCE_SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+// A debounced read gives up when the pin hasn't settled after this many windows
+pub const DEBOUNCE_MAX_WINDOWS: u32 = 10;
+
+// Settles on a value once every sample over a whole window read the same
+pub struct Debouncer {
+    needed: u32,
+    last: Option<u8>,
+    count: u32
+}
+
+impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        let needed = (window.as_millis() / DEBOUNCE_SAMPLE_INTERVAL.as_millis()).max(1) as u32;
+        Debouncer { needed, last: None, count: 0 }
+    }
+
+    // Samples to take before giving up
+    pub fn max_samples(&self) -> u32 {
+        self.needed * DEBOUNCE_MAX_WINDOWS
+    }
+
+    // The stable value once the window is full, any change starts it over
+    pub fn push(&mut self, value: u8) -> Option<u8> {
+        let value = (value != 0) as u8;
+        match self.last == Some(value) {
+            true => self.count += 1,
+            false => {
+                self.last = Some(value);
+                self.count = 1;
+            }
+        }
+
+        match self.count >= self.needed {
+            true => self.last,
+            false => None
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum GpioError {
     Busy(u8),
     PinNotFound(u8),
+    // the pin is configured but the kernel has no GPIO node for it, holds the path that was tried
+    PinNodeNotFound(u8, String),
     LeaseNotFound,
     PermissionDenied(String),
     OsError(String),
     Unsupported(String),
+    InvalidConfig(String),
     Other(String)
 }
 
@@ -41,10 +125,12 @@ impl Display for GpioError {
         f.write_str(&match self {
             GpioError::Busy(p) => format!("pin {} is busy", p),
             GpioError::PinNotFound(p) => format!("pin {} is not available", p),
+            GpioError::PinNodeNotFound(p, path) => format!("pin {} is not available: {} does not exist", p, path),
             GpioError::LeaseNotFound => format!("specified lease does not exist"),
             GpioError::PermissionDenied(s) => format!("permission denied: {}", s),
             GpioError::OsError(s) => format!("os error: {}", s),
             GpioError::Unsupported(s) => format!("not supported: {}", s),
+            GpioError::InvalidConfig(s) => format!("invalid config: {}", s),
             GpioError::Other(s) => format!("{}", s),
         })
     }
@@ -70,6 +156,14 @@ impl GpioBorrowChecker {
         }
     }
 
+    // Controllers that borrow pins call this first, so an empty GPIO section fails with one clear message
+    pub fn ensure_configured(&self) -> Result<(), String> {
+        match self.pins.is_empty() {
+            true => Err("no GPIO pins are configured, add the board's pins to the GPIO section".to_string()),
+            false => Ok(())
+        }
+    }
+
     pub fn get_pins(&self) -> Vec<&PinState> {
         self.pins.values().collect()
     }
@@ -147,9 +241,39 @@ impl GpioBorrowChecker {
         for pin in lease {
             let pin_state = self.pins.get_mut(&pin).unwrap();
             pin_state.leased = false;
+            pin_state.direction = None;
+            pin_state.last_value = None;
         }
 
         self.leases.remove(borrow_id);
         Ok(())
     }
+
+    // For when every controller holding a lease is gone, e.g. after the device server was cleared
+    pub fn release_all(&mut self) {
+        let leases: Vec<Uuid> = self.leases.keys().copied().collect();
+        for lease in leases {
+            let _ = self.release(&lease);
+        }
+    }
+
+    pub fn record_direction(&mut self, pin: u8, direction: PinDirection) -> Result<(), GpioError> {
+        match self.pins.get_mut(&pin) {
+            Some(state) => {
+                state.direction = Some(direction);
+                Ok(())
+            },
+            None => Err(GpioError::PinNotFound(pin))
+        }
+    }
+
+    pub fn record_value(&mut self, pin: u8, value: u8) -> Result<(), GpioError> {
+        match self.pins.get_mut(&pin) {
+            Some(state) => {
+                state.last_value = Some(value);
+                Ok(())
+            },
+            None => Err(GpioError::PinNotFound(pin))
+        }
+    }
 }
\ No newline at end of file
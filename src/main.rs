@@ -6,54 +6,70 @@ mod capabilities;
 mod config;
 mod device;
 mod drivers;
+mod events;
 mod gpio;
 mod rpc;
+mod self_test;
+mod shutdown;
+mod supervisor;
 mod tests;
+mod topology;
 
-use config::{ConfigError, Configuration};
-use device::{Device, DeviceError, DeviceServer};
+use config::{
+    source::{ConfigLoader, ConfigOrigin},
+    store::{write_config_file, ConfigStore},
+    Configuration,
+};
+use device::{Device, DeviceServer};
 use gpio::{GpioBorrowChecker, PinState};
 use log::{debug, error, info, warn, LevelFilter, SetLoggerError};
 use parking_lot::RwLock;
+use rpc::access_log::AccessLogLayer;
+use rpc::concurrency_limit::ConcurrencyLimitLayer;
 use rpc::reflection::{device_reflection_server::DeviceReflectionServer, DeviceReflectionService};
+use shutdown::ShutdownHook;
 use simple_logger::SimpleLogger;
 use std::{
+    collections::{HashMap, HashSet},
+    env,
     error::Error,
-    fs::{self, File},
-    io::{BufReader, BufWriter},
-    path::Path,
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 use tokio::sync::mpsc;
-use tonic::transport::Server;
-use uuid::Uuid;
+use tonic::{service::interceptor::InterceptedService, transport::Server};
 
 use crate::{
     adb::{AdbServer, PortType},
-    drivers::{
-        gps_uart::UartGps, sysfs_led::SysfsLedController, tsl2591_sysfs::Tsl2591SysfsDriver, bmp280_sysfs::Bmp280SysfsDriver,
-    },
+    drivers::DriverRegistry,
     rpc::{
+        auth::{self, AuthTokens},
         gps::{gps_server::GpsServer, GpsService},
         heartbeat::{heartbeat_server::HeartbeatServer, HeartbeatService},
         led::{led_controller_server::LedControllerServer, LEDControllerService},
-        light_sensor::{light_sensor_server::LightSensorServer, LightSensorService},
+        light_sensor::light_sensor_server::LightSensorServer,
         network::{network_manager_server::NetworkManagerServer, NetworkManagerService},
-        thermometer::{thermometer_server::ThermometerServer, ThermometerService}, 
-        barometer::{barometer_server::BarometerServer, BarometerService}
+        thermometer::thermometer_server::ThermometerServer, 
+        barometer::barometer_server::BarometerServer,
+        gpio::{gpio_server::GpioServer, GpioService},
+        serial::{serial_port_server::SerialPortServer, SerialPortService},
+        telemetry::{telemetry_server::TelemetryServer, TelemetryService},
+        power_monitor::{power_monitor_server::PowerMonitorServer, PowerMonitorService},
+        servo::{servo_server::ServoServer, ServoService},
+        switch::{switch_server::SwitchServer, SwitchService},
+        i2c_debug::{i2c_debug_server::I2cDebugServer, I2cDebugService},
+        rest::{RestGateway, SensorServices},
     },
 };
-use bus::i2c::I2CBusController;
-use bus::i2c_sysfs::SysfsI2CBusController;
-use bus::pwm::PWMBusController;
-use bus::pwm_sysfs::SysfsPWMBusController;
-use bus::raw::RawBusController;
-use bus::raw_sysfs::SysfsRawBusController;
-use bus::uart::UARTBusController;
-use bus::BusController;
+use bus::ControllerRegistry;
 
 const CONFIG_PATH: &str = "nvos_config.json";
+const PRINT_CONFIG_FLAG: &str = "--print-config";
+const CONFIG_URL_FLAG: &str = "--config-url";
+const CONTROLLERS_REQUIRING_PINS: [&str; 7] = ["raw", "raw_sysfs", "pwm", "pwm_sysfs", "uart", "i2c", "i2c_sysfs"];
 
 #[cfg(debug_assertions)]
 fn setup_logger() -> Result<(), SetLoggerError> {
@@ -71,47 +87,85 @@ fn setup_logger() -> Result<(), SetLoggerError> {
         .init()
 }
 
+// Writes the config back to CONFIG_PATH, the previous files are kept as rotated backups
+fn sync_config_file(config: &Configuration) {
+    info!("Syncing config to disk");
+    match write_config_file(config, Path::new(CONFIG_PATH)) {
+        Ok(_) => info!("Config file written to {}", CONFIG_PATH),
+        Err(e) => error!("Failed to write config file: {}", e),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    setup_logger()?;
-    info!("Loading configuration file at {}", CONFIG_PATH);
+    // The logger writes to stdout, keep it quiet when the config is being printed
+    let print_config = env::args().skip(1).any(|x| x == PRINT_CONFIG_FLAG);
+    if !print_config {
+        setup_logger()?;
+    }
+
+    let config_url = env::args().skip_while(|x| x != CONFIG_URL_FLAG).nth(1);
+    match &config_url {
+        Some(url) => info!("Loading configuration from {} (falling back to {})", url, CONFIG_PATH),
+        None => info!("Loading configuration file at {}", CONFIG_PATH)
+    }
+
     let mut config;
+    let mut sync_config = true;
+    let loader = ConfigLoader::new(CONFIG_PATH)
+        .with_url(config_url)
+        .with_env(env::vars());
+
+    match loader.load() {
+        Ok(loaded) => {
+            if loaded.origin == ConfigOrigin::Default {
+                warn!("Config file does not exist or is inaccessible");
+                warn!("Creating default config file");
 
-    if !Path::new(CONFIG_PATH).exists() {
-        warn!("Config file does not exist or is inaccessible");
-        warn!("Creating default config file");
-        config = Configuration::default();
-
-        match File::create(CONFIG_PATH) {
-            Ok(f) => {
-                let writer = BufWriter::new(f);
-                match config.to_writer(writer, true) {
-                    Ok(_) => info!("Config file written to {}", CONFIG_PATH),
-                    Err(e) => error!("Failed to write config file: {}", e),
-                };
+                // written without any env overrides, those only live for this run
+                match File::create(CONFIG_PATH) {
+                    Ok(f) => {
+                        let writer = BufWriter::new(f);
+                        match Configuration::default().to_writer(writer, true) {
+                            Ok(_) => info!("Config file written to {}", CONFIG_PATH),
+                            Err(e) => error!("Failed to write config file: {}", e),
+                        };
+                    }
+                    Err(e) => error!("Failed to open config file for write: {}", e),
+                }
             }
-            Err(e) => error!("Failed to open config file for write: {}", e),
-        }
-    } else {
-        config = match File::open(CONFIG_PATH)
-            .map_err(|err| ConfigError::Other(format!("failed to read config file: {}", err)))
-            .and_then(|f| Configuration::from_reader(BufReader::new(f)))
-        {
-            Ok(c) => c,
-            Err(e) => {
-                error!(
-                    "Failed to read config file at location {}: {}",
-                    CONFIG_PATH, e
-                );
-                warn!("Using default config file instead.");
-                Configuration::default()
+
+            // The migrated config is saved back once devices have been registered, after the usual backup
+            if loaded.version < config::CONFIG_VERSION {
+                info!("Migrated config from version {} to {}", loaded.version, config::CONFIG_VERSION);
             }
-        };
+
+            for key in &loaded.overrides {
+                info!("Config value overridden by environment variable {}", key);
+            }
+
+            sync_config = !loaded.is_layered();
+            config = loaded.config;
+        }
+        Err(e) => {
+            error!(
+                "Failed to read config file at location {}: {}",
+                CONFIG_PATH, e
+            );
+            warn!("Using default config file instead.");
+            config = Configuration::default();
+        }
+    }
+
+    if print_config {
+        println!("{}", config.to_redacted_str(true)?);
+        return Ok(());
     }
 
     info!("Building GPIO borrow checker");
-    if config.gpio_section.pin_config.len() == 0 {
-        warn!("Config does not have any GPIO entries. This will not work.");
+    let gpio_missing = config.gpio_section.pin_config.is_empty();
+    if gpio_missing {
+        error!("Config does not have any GPIO entries, bus controllers that need pins will be skipped. Add the board's pins to the GPIO section to enable them.");
     }
 
     let gpio_borrow = Arc::new(RwLock::new(GpioBorrowChecker::new(
@@ -119,10 +173,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .gpio_section
             .pin_config
             .iter()
-            .map(|(pin_id, bcm_id)| {
+            .map(|(pin_id, pin)| {
                 (
                     pin_id.clone(),
-                    PinState::new(pin_id.clone(), bcm_id.clone()),
+                    PinState::new(pin_id.clone(), pin.bcm_id).with_active_low(pin.active_low),
                 )
             })
             .collect(),
@@ -130,55 +184,84 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     info!("Building server");
     let mut device_server = DeviceServer::new();
+    device_server.set_device_lock_timeout(Duration::from_millis(config.device_section.device_lock_timeout_ms));
+    device_server.set_recover_stuck_locks(config.device_section.recover_stuck_locks);
+    device_server.set_unhealthy_error_threshold(config.device_section.unhealthy_error_threshold);
+
+    let controller_registry = Arc::new(ControllerRegistry::builtin());
+    let driver_registry = Arc::new(DriverRegistry::builtin());
 
     info!("Registering bus controllers");
     if config.controller_section.controllers.len() == 0 {
         warn!("Config does not have any bus controller entries.");
     }
 
-    for bus_config in &mut config.controller_section.controllers {
+    // Devices that need one of these are skipped with a clear message instead of failing with MissingController
+    let mut failed_controllers: HashSet<String> = HashSet::new();
+
+    // Pin names are resolved on copies so the config file keeps them
+    let mut resolved_controllers = Vec::new();
+    for bus_config in &config.controller_section.controllers {
+        let mut resolved = bus_config.clone();
+        if let Err(e) = config.gpio_section.resolve_pin_names(&mut resolved.data) {
+            error!("Skipping bus controller \"{}\": {}", bus_config.name, e);
+            failed_controllers.insert(bus_config.name.to_lowercase());
+        }
+
+        resolved_controllers.push(resolved);
+    }
+
+    // Checked up front from the config alone, otherwise the clash only shows once a device borrows the pin
+    let mut conflicting_controllers: HashSet<String> = HashSet::new();
+    for conflict in bus::find_pin_conflicts(&resolved_controllers) {
+        error!("GPIO pin conflict: {}, keeping it on \"{}\"", conflict, conflict.controllers[0]);
+        conflicting_controllers.extend(conflict.controllers[1..].iter()
+            .filter(|x| !x.eq_ignore_ascii_case(&conflict.controllers[0]))
+            .map(|x| x.to_lowercase()));
+    }
+
+    for (bus_config, resolved) in config.controller_section.controllers.iter_mut().zip(resolved_controllers.iter_mut()) {
+        if failed_controllers.contains(&bus_config.name.to_lowercase()) {
+            continue;
+        }
+
+        if conflicting_controllers.contains(&bus_config.name.to_lowercase()) {
+            error!("Skipping bus controller \"{}\" because its pins are already declared by another controller", bus_config.name);
+            failed_controllers.insert(bus_config.name.to_lowercase());
+            continue;
+        }
+
+        if gpio_missing && CONTROLLERS_REQUIRING_PINS.contains(&bus_config.name.to_lowercase().as_str()) {
+            debug!("Skipping bus controller \"{}\" because no GPIO pins are configured", bus_config.name);
+            failed_controllers.insert(bus_config.name.to_lowercase());
+            continue;
+        }
+
         info!("Initializing bus controller \"{}\"", bus_config.name);
-        let controller_instance: Result<Arc<RwLock<dyn BusController>>, String> =
-            match bus_config.name.to_lowercase().as_str() {
-                "raw" => RawBusController::from_config(&gpio_borrow, bus_config)
-                    .map(|bus| Arc::new(RwLock::new(bus)) as Arc<RwLock<dyn BusController>>)
-                    .map_err(|err| err.to_string()),
-                "raw_sysfs" => SysfsRawBusController::from_config(&gpio_borrow, bus_config)
-                    .map(|bus| Arc::new(RwLock::new(bus)) as Arc<RwLock<dyn BusController>>)
-                    .map_err(|err| err.to_string()),
-                "pwm" => PWMBusController::from_config(&gpio_borrow, bus_config)
-                    .map(|bus| Arc::new(RwLock::new(bus)) as Arc<RwLock<dyn BusController>>)
-                    .map_err(|err| err.to_string()),
-                "pwm_sysfs" => SysfsPWMBusController::from_config(&gpio_borrow, bus_config)
-                    .map(|bus| Arc::new(RwLock::new(bus)) as Arc<RwLock<dyn BusController>>)
-                    .map_err(|err| err.to_string()),
-                "uart" => UARTBusController::from_config(&gpio_borrow, bus_config)
-                    .map(|bus| Arc::new(RwLock::new(bus)) as Arc<RwLock<dyn BusController>>)
-                    .map_err(|err| err.to_string()),
-                "i2c" => I2CBusController::from_config(&gpio_borrow, bus_config)
-                    .map(|bus| Arc::new(RwLock::new(bus)) as Arc<RwLock<dyn BusController>>)
-                    .map_err(|err| err.to_string()),
-                "i2c_sysfs" => SysfsI2CBusController::from_config(&gpio_borrow, bus_config)
-                    .map(|bus| Arc::new(RwLock::new(bus)) as Arc<RwLock<dyn BusController>>)
-                    .map_err(|err| err.to_string()),
-                unknown_bus => Err(format!(
-                    "Bus controller {} is not implemented by this server",
-                    unknown_bus
-                )),
-            };
+        let controller_instance = controller_registry.build(&gpio_borrow, resolved);
+        // controllers fill in defaults for empty data, those are written back to the config
+        if bus_config.data.is_null() {
+            bus_config.data = resolved.data.clone();
+        }
 
         match controller_instance {
             Ok(b) => match device_server.register_bus(b) {
                 Ok(_) => info!("Bus controller \"{}\" is OK", bus_config.name),
-                Err(e) => error!(
-                    "Failed to register bus controller \"{}\": {}",
-                    bus_config.name, e
-                ),
+                Err(e) => {
+                    error!(
+                        "Failed to register bus controller \"{}\": {}",
+                        bus_config.name, e
+                    );
+                    failed_controllers.insert(bus_config.name.to_lowercase());
+                }
             },
-            Err(e) => error!(
-                "Failed to build bus controller \"{}\": {}",
-                bus_config.name, e
-            ),
+            Err(e) => {
+                error!(
+                    "Failed to build bus controller \"{}\": {}",
+                    bus_config.name, e
+                );
+                failed_controllers.insert(bus_config.name.to_lowercase());
+            }
         }
     }
 
@@ -187,162 +270,357 @@ async fn main() -> Result<(), Box<dyn Error>> {
         warn!("Config does not have any device entries.");
     }
 
-    for device_config in &mut config.device_section.devices {
+    // Build every device first so they can be started in dependency order
+    let mut config_indices = Vec::new();
+    let mut devices = Vec::new();
+    let mut failed_devices: HashSet<String> = HashSet::new();
+    for (index, device_config) in config.device_section.devices.iter_mut().enumerate() {
         info!("Initializing device: (driver: {})", device_config.driver);
-        let device_instance = match device_config.driver.to_lowercase().as_str() {
-            "sysfs_generic_led" => Device::from_config::<SysfsLedController>(device_config, None),
-            "gps_uart" => Device::from_config::<UartGps>(device_config, None),
-            "tsl2591_sysfs" => Device::from_config::<Tsl2591SysfsDriver>(device_config, None),
-            "bmp280_sysfs" => Device::from_config::<Bmp280SysfsDriver>(device_config, None),
-            unknown_driver => Err(DeviceError::InvalidConfig(format!(
-                "device driver {} is not supported by this server",
-                unknown_driver
-            ))),
-        };
+        let mut resolved = device_config.clone();
+        if let Err(e) = config.gpio_section.resolve_pin_names(&mut resolved.driver_data) {
+            error!("Failed to build device (driver: {}): {}", device_config.driver, e);
+            // devices that depend on it are skipped with a clear message instead of failing on a missing dependency
+            failed_devices.extend(device_config.friendly_name.clone());
+            continue;
+        }
+
+        let device_instance = driver_registry.build(&mut resolved);
+        if device_config.driver_data.is_null() {
+            device_config.driver_data = resolved.driver_data;
+        }
 
         match device_instance {
-            Ok(d) => match device_server.register_device(d, true) {
-                Ok(id) => {
-                    info!("Device (driver: {}) is OK", device_config.driver);
-                    debug!("Device assigned address is {}", id);
-                    match device_server.get_device(&id) {
-                        Some(device) => {
-                            debug!("Device capabilities:");
-                            for cap in device.get_capabilities() {
-                                debug!("  - {:?}", cap);
-                            }
+            Ok(d) => {
+                config_indices.push(index);
+                devices.push(d);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to build device (driver: {}): {}",
+                    device_config.driver, e
+                );
+                failed_devices.extend(device_config.friendly_name.clone());
+            }
+        }
+    }
+
+    // Devices with a dependency that isn't there are skipped below, only a dependency cycle stops the boot
+    let (start_order, missing_dependencies) = match device_server.resolve_partial_start_order(&devices) {
+        Ok(order) => order,
+        Err(e) => {
+            error!("Failed to resolve device dependencies: {}", e);
+            return Err(e.to_string().into());
+        }
+    };
+    let missing_dependencies: HashMap<usize, String> = missing_dependencies.into_iter().collect();
+
+    let mut devices: Vec<Option<Device>> = devices.into_iter().map(Some).collect();
+    // registered devices and their config entries, handed to the config store for config updates
+    let mut device_entries = Vec::new();
+    for index in start_order {
+        let device = match devices[index].take() {
+            Some(device) => device,
+            None => continue,
+        };
+
+        let device_config = &mut config.device_section.devices[config_indices[index]];
+        if let Some(controller) = device_server.missing_controllers(&device).first() {
+            let reason = match failed_controllers.contains(*controller) {
+                true => "failed to initialize",
+                false => "is not configured",
+            };
+
+            error!(
+                "Skipping device \"{}\" (driver: {}): required controller \"{}\" {}",
+                device.device_name(), device_config.driver, controller, reason
+            );
+            failed_devices.insert(device.device_name());
+            continue;
+        }
+
+        if let Some(dependency) = device.dependencies().iter().find(|x| failed_devices.contains(*x)) {
+            error!(
+                "Skipping device (driver: {}): dependency \"{}\" failed to start",
+                device_config.driver, dependency
+            );
+            failed_devices.insert(device.device_name());
+            continue;
+        }
+
+        if let Some(dependency) = missing_dependencies.get(&index) {
+            error!(
+                "Skipping device (driver: {}): dependency \"{}\" is neither a bus controller nor a device",
+                device_config.driver, dependency
+            );
+            failed_devices.insert(device.device_name());
+            continue;
+        }
+
+        let device_name = device.device_name();
+        match device_server.register_device(device, true) {
+            Ok(id) if device_server.get_device(&id).is_some_and(|x| !x.is_running()) => {
+                warn!("Optional device (driver: {}) is registered but not running yet", device_config.driver);
+                device_entries.push((id, config_indices[index]));
+            }
+            Ok(id) => {
+                device_entries.push((id, config_indices[index]));
+                info!("Device (driver: {}) is OK", device_config.driver);
+                debug!("Device assigned address is {}", id);
+                match device_server.get_device(&id) {
+                    Some(device) => {
+                        debug!("Device capabilities:");
+                        for cap in device.get_capabilities() {
+                            debug!("  - {:?}", cap);
+                        }
+
+                        if let Some(data) = device.as_ref().updated_driver_data() {
+                            info!("Device (driver: {}) updated its config data", device_config.driver);
+                            device_config.driver_data = data;
                         }
-                        None => warn!("Failed to list device capabilities: device not found"),
                     }
+                    None => warn!("Failed to list device capabilities: device not found"),
                 }
-                Err(e) => error!(
+            }
+            Err(e) => {
+                error!(
                     "Failed to register device (driver: {}): {}",
                     device_config.driver, e
-                ),
-            },
-            Err(e) => error!(
-                "Failed to build device (driver: {}): {}",
-                device_config.driver, e
-            ),
+                );
+                failed_devices.insert(device_name);
+            }
         }
     }
 
-    info!("Syncing config to disk");
-    if Path::new(CONFIG_PATH).exists() {
-        // Backup config
-        let backup_path = CONFIG_PATH.to_string() + ".bak";
-        match fs::copy(CONFIG_PATH, &backup_path) {
-            Ok(_) => info!("Backed up config file to {}", backup_path),
-            Err(err) => warn!("Failed to backup config file: {}", err),
-        }
+    if config.device_section.startup_self_test {
+        info!("Running startup self-test");
+        self_test::run_self_test(&device_server).log();
     }
 
-    match File::create(CONFIG_PATH) {
-        Ok(f) => {
-            let writer = BufWriter::new(f);
-            match config.to_writer(writer, true) {
-                Ok(_) => info!("Config file written to {}", CONFIG_PATH),
-                Err(e) => error!("Failed to write config file: {}", e),
-            };
+    if sync_config {
+        sync_config_file(&config);
+    } else {
+        info!("Config came from a URL or has environment overrides, not syncing it to disk");
+    }
+
+    // Config updates from RPCs are only saved when the file is the config's only source
+    let config_store = Arc::new(ConfigStore::new(config.clone(), sync_config.then(|| PathBuf::from(CONFIG_PATH))));
+    for (address, index) in device_entries {
+        config_store.bind_device(address, index);
+    }
+
+    let effective_config = match config.to_redacted_str(true) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            warn!("Failed to serialize effective config: {}", e);
+            None
         }
-        Err(e) => error!("Failed to open config file for write: {}", e),
+    };
+
+    if config.adb_section.enabled {
+        info!("Starting ADB server connection");
     }
 
-    info!("Starting ADB server connection");
-    let adb_server = AdbServer::with_timeout(
-        &config.adb_section.server_host,
-        config.adb_section.server_port,
-        Duration::from_millis(config.adb_section.read_timeout_ms),
-        Duration::from_millis(config.adb_section.write_timeout_ms),
-    );
-    info!("Forwarding gRPC server port");
-    match adb_server.add_port(
-        PortType::Reverse,
-        config.rpc_section.server_port,
-        config.rpc_section.server_port,
-        false,
-    ) {
-        Ok(_) => info!("Port forwarded: {}", config.rpc_section.server_port),
-        Err(err) => error!("Failed to forward port: {}", err),
+    let adb_server = AdbServer::from_config(&config.adb_section);
+    match &adb_server {
+        Some(adb_server) => {
+            info!("Forwarding gRPC server port");
+            match adb_server.add_port(
+                PortType::Reverse,
+                config.rpc_section.server_port,
+                config.rpc_section.server_port,
+                false,
+            ) {
+                Ok(_) => info!("Port forwarded: {}", config.rpc_section.server_port),
+                Err(err) => error!("Failed to forward port: {}", err),
+            }
+        }
+        None => info!("ADB is disabled, the gRPC server port will not be forwarded"),
     }
 
     info!("Starting device server");
     // Prepare the device server for multi threading
     let device_server = Arc::new(RwLock::new(device_server));
+    device::spawn_optional_device_retry(
+        &device_server,
+        Duration::from_millis(config.device_section.optional_retry_interval_ms),
+    );
 
     // Prepare the ADB server for multi threading
-    let adb_server = Arc::new(RwLock::new(adb_server));
+    let adb_server = adb_server.map(|x| Arc::new(RwLock::new(x)));
 
-    // Prepare shutdown hook
+    // Prepare shutdown hook, SIGINT and SIGTERM share the same graceful shutdown path
     let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
-    let device_server_ref = device_server.clone();
-    let adb_server_ref = adb_server.clone();
-    let mut tried_graceful_shutdown = false;
-    let ctrlc_result = ctrlc::set_handler(move || {
-        info!("Received shutdown signal");
-        if tried_graceful_shutdown {
-            info!("Already tried graceful shutdown, forcibly shutting down.");
-            panic!("Terminated");
-        }
+    let shutdown_hook = Arc::new(ShutdownHook::new(&device_server, adb_server.as_ref(), shutdown_tx));
+    let rest_shutdown = shutdown_hook.stopped();
+    tokio::spawn(async move {
+        loop {
+            match shutdown::wait_for_signal().await {
+                Ok(signal) => info!("Received shutdown signal ({})", signal),
+                Err(e) => {
+                    warn!("Failed to set shutdown handler: {}", e);
+                    return;
+                }
+            }
 
-        tried_graceful_shutdown = true;
+            if shutdown_hook.is_triggered() {
+                info!("Already tried graceful shutdown, forcibly shutting down.");
+                std::process::exit(1);
+            }
 
-        info!("Shutting down device server");
-        let mut ds = device_server_ref.write();
-        for id in ds
-            .get_devices()
-            .iter()
-            .map(|(k, _)| **k)
-            .collect::<Vec<Uuid>>()
-            .clone()
-        {
-            info!("Unloading device {}", id);
-            if let Err(err) = ds.remove_device(&id) {
-                error!("Failed to gracefully shutdown device {}: {}", id, err);
+            // runs on its own so a device stuck in a call can't keep the next signal from forcing the exit
+            let hook = shutdown_hook.clone();
+            tokio::spawn(async move { hook.shutdown().await });
+        }
+    });
+    info!("Shutdown handler set");
+
+    // Serve gRPC
+    let mut rpc_builder = Server::builder().tcp_nodelay(true).accept_http1(true);
+    match rpc::load_tls_config(&config.rpc_section) {
+        Ok(Some(tls)) => {
+            info!("TLS is enabled for the RPC server");
+            if config.rpc_section.tls_client_ca_path.is_some() {
+                info!("Client certificates will be verified");
             }
+
+            rpc_builder = rpc_builder.tls_config(tls)?;
+        }
+        Ok(None) => warn!("TLS is not configured, the RPC server will accept plaintext connections"),
+        Err(e) => {
+            error!("Failed to load TLS config: {}", e);
+            return Err(e.to_string().into());
+        }
+    }
+
+    let auth_tokens = match AuthTokens::from_config(&config.rpc_section.auth_tokens) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            error!("Failed to load auth tokens: {}", e);
+            return Err(e.to_string().into());
         }
+    };
 
-        info!("Shutting down ADB server");
-        adb_server_ref.write().shutdown();
+    if !auth_tokens.is_enabled() {
+        warn!("No auth tokens are configured, RPC calls will not be authenticated");
+    }
 
-        info!("Gracefully shutting down RPC server");
-        let _ = shutdown_tx.send(());
-    });
+    let server_reflection = match rpc::server_reflection_service() {
+        Ok(service) => service,
+        Err(e) => {
+            error!("Failed to build the server reflection service: {}", e);
+            return Err(e.to_string().into());
+        }
+    };
 
-    match ctrlc_result {
-        Ok(_) => info!("Shutdown handler set"),
-        Err(e) => warn!("Failed to set shutdown handler: {}", e),
+    let command_timeout = Duration::from_millis(config.rpc_section.command_timeout_ms);
+    let sensors = SensorServices::from_config(&device_server, &config.rpc_section);
+    if let Some(rest_port) = config.rpc_section.rest_port {
+        let rest_addr = config.rpc_section.server_host.clone() + ":" + &rest_port.to_string();
+        match rest_addr.parse() {
+            // the gateway only speaks plain HTTP, it would hand out what TLS is protecting on the gRPC port
+            Ok(_) if config.rpc_section.tls_cert_path.is_some() => error!("Not starting the REST gateway: it does not support TLS, which is configured for RPC"),
+            Ok(rest_addr) => {
+                let gateway = RestGateway::new(&device_server, auth_tokens.clone(), &sensors)
+                    .with_command_timeout(command_timeout);
+                tokio::spawn(async move {
+                    match gateway.serve(rest_addr, rest_shutdown).await {
+                        Ok(_) => info!("REST gateway stopped"),
+                        Err(e) => error!("REST gateway stopped: {}", e),
+                    }
+                });
+                info!("REST gateway running on {}", rest_addr);
+            }
+            Err(e) => error!("Invalid REST gateway address {}: {}", rest_addr, e),
+        }
     }
 
-    // Serve gRPC
+    // the access log sits outside of the limit so rejected calls are logged too
+    let layers = tower::ServiceBuilder::new()
+        .layer(AccessLogLayer::new(config.rpc_section.access_log_level()))
+        .layer(ConcurrencyLimitLayer::new(config.rpc_section.max_in_flight_requests))
+        .into_inner();
     let serve_addr =
         config.rpc_section.server_host + ":" + &config.rpc_section.server_port.to_string();
-    let rpc_server = Server::builder()
-        .tcp_nodelay(true)
-        .accept_http1(true)
-        .add_service(tonic_web::enable(DeviceReflectionServer::new(
-            DeviceReflectionService::new(&device_server),
+    let rpc_server = rpc_builder
+        .layer(layers)
+        .add_service(tonic_web::enable(DeviceReflectionServer::with_interceptor(
+            match effective_config {
+                Some(effective_config) => DeviceReflectionService::with_config(&device_server, effective_config),
+                None => DeviceReflectionService::new(&device_server),
+            }
+            .with_gpio(&gpio_borrow)
+            .with_config_store(&config_store, &driver_registry)
+            .with_controllers(&controller_registry)
+            .with_command_timeout(command_timeout),
+            auth_tokens.interceptor(auth::REFLECTION_SCOPE),
+        )))
+        .add_service(tonic_web::enable(LedControllerServer::with_interceptor(
+            LEDControllerService::new(&device_server).with_command_timeout(command_timeout),
+            auth_tokens.interceptor(auth::LED_SCOPE),
+        )))
+        .add_service(tonic_web::enable(InterceptedService::new(
+            LightSensorServer::from_arc(sensors.light_sensor.clone()),
+            auth_tokens.interceptor(auth::LIGHT_SENSOR_SCOPE),
+        )))
+        .add_service(tonic_web::enable(GpsServer::with_interceptor(
+            GpsService::new(&device_server).with_barometer_service(sensors.barometer.clone()),
+            auth_tokens.interceptor(auth::GPS_SCOPE),
         )))
-        .add_service(tonic_web::enable(LedControllerServer::new(
-            LEDControllerService::new(&device_server),
+        .add_service(tonic_web::enable(InterceptedService::new(
+            ThermometerServer::from_arc(sensors.thermometer.clone()),
+            auth_tokens.interceptor(auth::THERMOMETER_SCOPE),
         )))
-        .add_service(tonic_web::enable(LightSensorServer::new(
-            LightSensorService::new(&device_server),
+        .add_service(tonic_web::enable(InterceptedService::new(
+            BarometerServer::from_arc(sensors.barometer.clone()),
+            auth_tokens.interceptor(auth::BAROMETER_SCOPE),
         )))
-        .add_service(tonic_web::enable(GpsServer::new(GpsService::new(
-            &device_server,
-        ))))
-        .add_service(tonic_web::enable(ThermometerServer::new(
-            ThermometerService::new(&device_server),
+        .add_service(tonic_web::enable(GpioServer::with_interceptor(
+            GpioService::new(&device_server),
+            auth_tokens.interceptor(auth::GPIO_SCOPE),
         )))
-        .add_service(tonic_web::enable(BarometerServer::new(
-            BarometerService::new(&device_server),
+        .add_service(tonic_web::enable(SerialPortServer::with_interceptor(
+            SerialPortService::new(&device_server).with_command_timeout(command_timeout),
+            auth_tokens.interceptor(auth::SERIAL_SCOPE),
         )))
-        .add_service(tonic_web::enable(NetworkManagerServer::new(
-            NetworkManagerService::new(&adb_server),
+        .add_service(tonic_web::enable(TelemetryServer::with_interceptor(
+            TelemetryService::new(&device_server)
+                .with_sensor_services(&sensors)
+                .with_command_timeout(command_timeout),
+            auth_tokens.interceptor(auth::TELEMETRY_SCOPE),
+        )))
+        .add_service(tonic_web::enable(PowerMonitorServer::with_interceptor(
+            PowerMonitorService::new(&device_server).with_command_timeout(command_timeout),
+            auth_tokens.interceptor(auth::POWER_MONITOR_SCOPE),
+        )))
+        .add_service(tonic_web::enable(ServoServer::with_interceptor(
+            ServoService::new(&device_server).with_command_timeout(command_timeout),
+            auth_tokens.interceptor(auth::SERVO_SCOPE),
+        )))
+        .add_service(tonic_web::enable(SwitchServer::with_interceptor(
+            SwitchService::new(&device_server).with_command_timeout(command_timeout),
+            auth_tokens.interceptor(auth::SWITCH_SCOPE),
+        )))
+        .add_service(tonic_web::enable(I2cDebugServer::with_interceptor(
+            I2cDebugService::new(&device_server),
+            auth_tokens.interceptor(auth::I2C_DEBUG_SCOPE),
+        )))
+        .add_service(tonic_web::enable(NetworkManagerServer::with_interceptor(
+            match &adb_server {
+                Some(adb_server) => NetworkManagerService::new(adb_server),
+                None => NetworkManagerService::disabled(),
+            },
+            auth_tokens.interceptor(auth::NETWORK_SCOPE),
         )))
         .add_service(tonic_web::enable(HeartbeatServer::new(
-            HeartbeatService::new(),
+            match &adb_server {
+                Some(adb_server) => HeartbeatService::new(&device_server).with_adb(adb_server),
+                None => HeartbeatService::new(&device_server),
+            },
+        )))
+        // the schema is only listed to callers allowed to use the device reflection service
+        .add_service(tonic_web::enable(InterceptedService::new(
+            server_reflection,
+            auth_tokens.interceptor(auth::REFLECTION_SCOPE),
         )))
         .serve_with_shutdown(serve_addr.parse().unwrap(), async {
             let _ = shutdown_rx.recv().await;
@@ -1,5 +1,28 @@
+use std::fs;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
+use parking_lot::{RawRwLock, RwLock};
+use parking_lot::lock_api::{ArcRwLockReadGuard, ArcRwLockWriteGuard};
+use tonic::Status;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+use tonic_reflection::server::{ServerReflection, ServerReflectionServer};
+use uuid::Uuid;
+use crate::capabilities::Capability;
+use crate::config::{ConfigError, ConfigSectionRPC};
+use crate::device::{Device, DeviceError, DeviceServer};
+use crate::events::DeviceEvents;
+use crate::supervisor::{self, LockMetrics};
+
 pub mod void;
+pub mod calibration;
 pub mod errors;
+pub mod auth;
+pub mod access_log;
+pub mod concurrency_limit;
+pub mod rate_limit;
+pub mod timeout;
 pub mod reflection;
 pub mod heartbeat;
 pub mod led;
@@ -7,4 +30,267 @@ pub mod gps;
 pub mod network;
 pub mod light_sensor;
 pub mod thermometer;
-pub mod barometer;
\ No newline at end of file
+pub mod barometer;
+pub mod gpio;
+pub mod serial;
+pub mod telemetry;
+pub mod power_monitor;
+pub mod servo;
+pub mod switch;
+pub mod i2c_debug;
+pub mod rest;
+
+// Descriptors of every proto in protos/, lets grpcurl and similar tools discover the services
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("nvos_descriptor");
+
+// Resolves a client supplied device address, which can either be a UUID or a device friendly name
+pub fn resolve_address(server: &DeviceServer, address: &str) -> Result<Uuid, Status> {
+    if let Ok(address) = Uuid::parse_str(address) {
+        return Ok(address);
+    }
+
+    match server.address_for_name(address) {
+        Some(address) => Ok(address),
+        None => Err(Status::not_found(format!("Device \"{}\" does not exist", address)))
+    }
+}
+
+// Finds the device under a short server read lock, the device itself is locked by the caller
+pub fn get_device_ptr(server: &Arc<RwLock<DeviceServer>>, address: &str) -> Result<Arc<RwLock<Device>>, Status> {
+    let server = server.read();
+    let address = resolve_address(&server, address)?;
+    match server.get_device_ptr(&address) {
+        Some(device) => Ok(device),
+        None => Err(Status::not_found("Device does not exist"))
+    }
+}
+
+// Read lock on a single device, viewed as one of its capabilities
+pub struct CapabilityRef<T: ?Sized> {
+    device: ArcRwLockReadGuard<RawRwLock, Device>,
+    _capability: PhantomData<*const T>
+}
+
+impl<T: Capability + ?Sized + 'static> Deref for CapabilityRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.device.as_capability_ref::<T>().unwrap()
+    }
+}
+
+// Write lock on a single device, viewed as one of its capabilities
+pub struct CapabilityMut<T: ?Sized> {
+    device: ArcRwLockWriteGuard<RawRwLock, Device>,
+    _capability: PhantomData<*const T>
+}
+
+impl<T: Capability + ?Sized + 'static> Deref for CapabilityMut<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.device.as_capability_ref::<T>().unwrap()
+    }
+}
+
+impl<T: ?Sized> CapabilityMut<T> {
+    pub fn address(&self) -> Uuid {
+        self.device.address()
+    }
+}
+
+impl<T: Capability + ?Sized + 'static> DerefMut for CapabilityMut<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.device.as_capability_mut::<T>().unwrap()
+    }
+}
+
+// Unlocked handle to a single device, for work that locks it on another thread
+pub struct CapabilityPtr<T: ?Sized> {
+    server: Arc<RwLock<DeviceServer>>,
+    target: SupervisedDevice,
+    events: DeviceEvents,
+    _capability: PhantomData<fn() -> *const T>
+}
+
+impl<T: Capability + ?Sized + 'static> CapabilityPtr<T> {
+    pub fn address(&self) -> Uuid {
+        self.target.address
+    }
+
+    // Supervised like lock_capability_mut, a stuck holder times out instead of pinning the blocking thread
+    pub fn lock_mut(&self) -> Result<CapabilityMut<T>, DeviceError> {
+        let device = self.target.write(&self.server)?;
+        if !device.has_capability::<T>() {
+            return Err(DeviceError::NotSupported);
+        }
+
+        self.events.record_read(device.address());
+        Ok(CapabilityMut { device, _capability: PhantomData })
+    }
+}
+
+// The capability is only checked once the device is locked, so a device stuck in a call can't block the caller here
+pub fn capability_ptr<T: Capability + ?Sized + 'static>(server: &Arc<RwLock<DeviceServer>>, address: &str) -> Result<CapabilityPtr<T>, Status> {
+    let events = server.read().events().clone();
+    let target = get_supervised_device(server, address)?;
+    Ok(CapabilityPtr { server: server.clone(), target, events, _capability: PhantomData })
+}
+
+// Same as CapabilityPtr, for calls on the driver itself rather than one of its capabilities
+pub struct DevicePtr {
+    server: Arc<RwLock<DeviceServer>>,
+    target: SupervisedDevice
+}
+
+impl DevicePtr {
+    pub fn address(&self) -> Uuid {
+        self.target.address
+    }
+
+    pub fn lock_mut(&self) -> Result<ArcRwLockWriteGuard<RawRwLock, Device>, DeviceError> {
+        self.target.write(&self.server)
+    }
+}
+
+pub fn device_ptr(server: &Arc<RwLock<DeviceServer>>, address: &str) -> Result<DevicePtr, Status> {
+    let target = get_supervised_device(server, address)?;
+    Ok(DevicePtr { server: server.clone(), target })
+}
+
+// Runs one call on a capability under the command timeout, the outcome counts towards the device's health
+pub async fn call_with_timeout<T, R, F>(
+    server: &Arc<RwLock<DeviceServer>>,
+    events: &DeviceEvents,
+    command_timeout: Duration,
+    address: &str,
+    call: F
+) -> Result<R, Status>
+where
+    T: Capability + ?Sized + 'static,
+    R: Send + 'static,
+    F: FnOnce(&mut T) -> Result<R, DeviceError> + Send + 'static,
+{
+    let device = capability_ptr::<T>(server, address)?;
+    let address = device.address();
+    let result = timeout::run_with_timeout(command_timeout, move || call(&mut *device.lock_mut()?));
+    errors::track_device_result(events, address, result.await)
+}
+
+fn unsupported_capability() -> Status {
+    Status::invalid_argument("This device does not support this capability")
+}
+
+// Everything needed to lock a device once the server lock is released
+struct SupervisedDevice {
+    address: Uuid,
+    device: Arc<RwLock<Device>>,
+    name: String,
+    timeout: Duration,
+    metrics: Arc<LockMetrics>,
+    recover: bool
+}
+
+impl SupervisedDevice {
+    fn write(&self, server: &Arc<RwLock<DeviceServer>>) -> Result<ArcRwLockWriteGuard<RawRwLock, Device>, DeviceError> {
+        match supervisor::write_supervised(&self.device, &self.name, self.timeout, &self.metrics) {
+            Err(err @ DeviceError::LockTimeout(_)) if self.recover => {
+                DeviceServer::restart_when_released(server, &self.address);
+                Err(err)
+            }
+            result => result
+        }
+    }
+}
+
+fn get_supervised_device(server: &Arc<RwLock<DeviceServer>>, address: &str) -> Result<SupervisedDevice, Status> {
+    let server = server.read();
+    let address = resolve_address(&server, address)?;
+    let device = match server.get_device_ptr(&address) {
+        Some(device) => device,
+        None => return Err(Status::not_found("Device does not exist"))
+    };
+
+    Ok(SupervisedDevice {
+        address,
+        device,
+        name: server.describe_device(&address),
+        timeout: server.device_lock_timeout(),
+        metrics: server.lock_metrics().clone(),
+        recover: server.recovers_stuck_locks()
+    })
+}
+
+// Waits at most the server's device lock timeout, so a stuck holder shows up as an error instead of a hung handler.
+// When the server is set up to recover, the device is restarted once the holder releases it.
+fn lock_supervised<G>(
+    server: &Arc<RwLock<DeviceServer>>,
+    address: &str,
+    lock: impl Fn(&Arc<RwLock<Device>>, &str, Duration, &LockMetrics) -> Result<G, DeviceError>
+) -> Result<G, Status> {
+    let target = get_supervised_device(server, address)?;
+    match lock(&target.device, &target.name, target.timeout, &target.metrics) {
+        Ok(guard) => Ok(guard),
+        Err(err @ DeviceError::LockTimeout(_)) if target.recover => {
+            DeviceServer::restart_when_released(server, &target.address);
+            Err(errors::map_device_error(err))
+        }
+        Err(err) => Err(errors::map_device_error(err))
+    }
+}
+
+// Every capability call counts as a read in the device stats, see DeviceEvents::stats
+pub fn lock_capability<T: Capability + ?Sized + 'static>(server: &Arc<RwLock<DeviceServer>>, address: &str) -> Result<CapabilityRef<T>, Status> {
+    // taken before the device is locked, the server lock is never waited on while holding a device
+    let events = server.read().events().clone();
+    let device = lock_supervised(server, address, supervisor::read_supervised::<Device>)?;
+    if !device.has_capability::<T>() {
+        return Err(unsupported_capability());
+    }
+
+    events.record_read(device.address());
+    Ok(CapabilityRef { device, _capability: PhantomData })
+}
+
+pub fn lock_capability_mut<T: Capability + ?Sized + 'static>(server: &Arc<RwLock<DeviceServer>>, address: &str) -> Result<CapabilityMut<T>, Status> {
+    let events = server.read().events().clone();
+    let device = lock_supervised(server, address, supervisor::write_supervised::<Device>)?;
+    if !device.has_capability::<T>() {
+        return Err(unsupported_capability());
+    }
+
+    events.record_read(device.address());
+    Ok(CapabilityMut { device, _capability: PhantomData })
+}
+
+// For calls on the driver itself rather than one of its capabilities
+pub fn lock_device_mut(server: &Arc<RwLock<DeviceServer>>, address: &str) -> Result<ArcRwLockWriteGuard<RawRwLock, Device>, Status> {
+    lock_supervised(server, address, supervisor::write_supervised::<Device>)
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, ConfigError> {
+    fs::read(path).map_err(|err| ConfigError::InvalidEntry(format!("failed to read {}: {}", path, err)))
+}
+
+// Builds the server TLS config from the RPC section, None means the server should stay on plaintext
+pub fn load_tls_config(config: &ConfigSectionRPC) -> Result<Option<ServerTlsConfig>, ConfigError> {
+    let (cert_path, key_path) = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Ok(None)
+    };
+
+    let identity = Identity::from_pem(read_pem(cert_path)?, read_pem(key_path)?);
+    let mut tls = ServerTlsConfig::new().identity(identity);
+    if let Some(ca_path) = &config.tls_client_ca_path {
+        tls = tls.client_ca_root(Certificate::from_pem(read_pem(ca_path)?));
+    }
+
+    Ok(Some(tls))
+}
+
+// Standard gRPC server reflection, separate from DeviceReflection which describes the devices
+pub fn server_reflection_service() -> Result<ServerReflectionServer<impl ServerReflection>, tonic_reflection::server::Error> {
+    tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build()
+}
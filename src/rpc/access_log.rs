@@ -0,0 +1,140 @@
+use std::{
+    cell::RefCell,
+    fmt::Display,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use log::{log, Level};
+use tonic::codegen::{http, BoxFuture};
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::{Code, Status};
+use tower::{Layer, Service};
+
+tokio::task_local! {
+    // device the call being handled looked up, filled in by the rpc device helpers
+    static CALL_DEVICE: RefCell<Option<String>>;
+}
+
+// Notes the device address a call asked for, does nothing outside of a logged call
+pub fn record_device(address: &str) {
+    let _ = CALL_DEVICE.try_with(|x| *x.borrow_mut() = Some(address.to_string()));
+}
+
+// One handled call, payloads are never part of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogEntry {
+    pub method: String,
+    pub caller: Option<String>,
+    pub device: Option<String>,
+    pub code: Code,
+    pub latency: Duration,
+}
+
+impl Display for AccessLogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "{} from {} device={} status={:?} in {:.1}ms",
+            self.method,
+            self.caller.as_deref().unwrap_or("unknown"),
+            self.device.as_deref().unwrap_or("-"),
+            self.code,
+            self.latency.as_secs_f64() * 1000.0
+        ))
+    }
+}
+
+pub type AccessLogSink = Arc<dyn Fn(&AccessLogEntry) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct AccessLogLayer {
+    sink: Option<AccessLogSink>,
+}
+
+impl AccessLogLayer {
+    // Logs every call at the given level, without a level calls go through untouched
+    pub fn new(level: Option<Level>) -> Self {
+        Self { sink: level.map(|level| Arc::new(move |entry: &AccessLogEntry| log!(level, "RPC {}", entry)) as AccessLogSink) }
+    }
+
+    pub fn with_sink(mut self, sink: AccessLogSink) -> Self {
+        self.sink = Some(sink);
+        self
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLog<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLog { inner, sink: self.sink.clone() }
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLog<S> {
+    inner: S,
+    sink: Option<AccessLogSink>,
+}
+
+fn caller<B>(req: &http::Request<B>) -> Option<String> {
+    let extensions = req.extensions();
+    extensions.get::<TcpConnectInfo>()
+        .or_else(|| extensions.get::<TlsConnectInfo<TcpConnectInfo>>().map(|x| x.get_ref()))
+        .and_then(|x| x.remote_addr())
+        .map(|x| x.to_string())
+}
+
+// Failed unary calls answer with the status in the headers. A call that got that far without one is only known to
+// have started fine, errors sent later in the trailers of a stream aren't seen here.
+fn response_code<B>(response: &http::Response<B>) -> Code {
+    match Status::from_header_map(response.headers()) {
+        Some(status) => status.code(),
+        None if response.status().is_success() => Code::Ok,
+        None => Code::Unknown,
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for AccessLog<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let sink = match &self.sink {
+            Some(sink) => sink.clone(),
+            None => return Box::pin(self.inner.call(req)),
+        };
+
+        let method = req.uri().path().to_string();
+        let caller = caller(&req);
+        let started = Instant::now();
+        let call = self.inner.call(req);
+        Box::pin(async move {
+            let (result, device) = CALL_DEVICE.scope(RefCell::new(None), async move {
+                let result = call.await;
+                (result, CALL_DEVICE.with(|x| x.borrow_mut().take()))
+            }).await;
+
+            sink(&AccessLogEntry {
+                method,
+                caller,
+                device,
+                code: match &result {
+                    Ok(response) => response_code(response),
+                    Err(_) => Code::Internal,
+                },
+                latency: started.elapsed(),
+            });
+            result
+        })
+    }
+}
@@ -0,0 +1,164 @@
+use std::{collections::HashMap, sync::Arc};
+use tonic::{service::Interceptor, Request, Status};
+use crate::config::{ApiTokenConfig, ConfigError};
+
+const AUTHORIZATION_HEADER: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+const ANY_SCOPE: &str = "*";
+
+pub const REFLECTION_SCOPE: &str = "reflection";
+pub const LED_SCOPE: &str = "led";
+pub const LIGHT_SENSOR_SCOPE: &str = "light_sensor";
+pub const GPS_SCOPE: &str = "gps";
+pub const THERMOMETER_SCOPE: &str = "thermometer";
+pub const BAROMETER_SCOPE: &str = "barometer";
+pub const GPIO_SCOPE: &str = "gpio";
+pub const SERIAL_SCOPE: &str = "serial";
+pub const NETWORK_SCOPE: &str = "network";
+pub const TELEMETRY_SCOPE: &str = "telemetry";
+pub const POWER_MONITOR_SCOPE: &str = "power_monitor";
+pub const SERVO_SCOPE: &str = "servo";
+pub const SWITCH_SCOPE: &str = "switch";
+pub const I2C_DEBUG_SCOPE: &str = "i2c_debug";
+
+const KNOWN_SCOPES: [&str; 14] = [
+    REFLECTION_SCOPE,
+    LED_SCOPE,
+    LIGHT_SENSOR_SCOPE,
+    GPS_SCOPE,
+    THERMOMETER_SCOPE,
+    BAROMETER_SCOPE,
+    GPIO_SCOPE,
+    SERIAL_SCOPE,
+    NETWORK_SCOPE,
+    TELEMETRY_SCOPE,
+    POWER_MONITOR_SCOPE,
+    SERVO_SCOPE,
+    SWITCH_SCOPE,
+    I2C_DEBUG_SCOPE,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum AccessLevel {
+    Read,
+    Write
+}
+
+// Scopes are written as "<scope>:<read|write>", a bare scope grants write access and "*" matches every scope
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthScope {
+    pub scope: String,
+    pub access: AccessLevel
+}
+
+impl AuthScope {
+    pub fn parse(value: &str) -> Result<Self, ConfigError> {
+        let (scope, access) = match value.split_once(':') {
+            Some((scope, "read")) => (scope, AccessLevel::Read),
+            Some((scope, "write")) => (scope, AccessLevel::Write),
+            Some((_, access)) => return Err(ConfigError::InvalidEntry(
+                format!("unknown access level \"{}\" in auth scope \"{}\"", access, value)
+            )),
+            None => (value, AccessLevel::Write)
+        };
+
+        if scope != ANY_SCOPE && !KNOWN_SCOPES.contains(&scope) {
+            return Err(ConfigError::InvalidEntry(format!("unknown auth scope \"{}\"", scope)));
+        }
+
+        Ok(Self { scope: scope.to_string(), access })
+    }
+
+    fn access_to(&self, scope: &str) -> Option<AccessLevel> {
+        match self.scope == ANY_SCOPE || self.scope == scope {
+            true => Some(self.access),
+            false => None
+        }
+    }
+}
+
+// Access granted to a request, attached to its extensions by the interceptor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grant(pub AccessLevel);
+
+#[derive(Clone, Default)]
+pub struct AuthTokens {
+    tokens: Arc<HashMap<String, Vec<AuthScope>>>
+}
+
+impl AuthTokens {
+    pub fn from_config(tokens: &[ApiTokenConfig]) -> Result<Self, ConfigError> {
+        let mut map = HashMap::new();
+        for token in tokens {
+            let scopes = token.scopes.iter()
+                .map(|scope| AuthScope::parse(scope))
+                .collect::<Result<Vec<AuthScope>, ConfigError>>()?;
+
+            if map.insert(token.token.clone(), scopes).is_some() {
+                return Err(ConfigError::DuplicateEntry("auth token is defined more than once".to_string()));
+            }
+        }
+
+        Ok(Self { tokens: Arc::new(map) })
+    }
+
+    // Auth is only enforced once at least one token is configured
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    pub fn interceptor(&self, scope: &'static str) -> AuthInterceptor {
+        AuthInterceptor { tokens: self.clone(), scope }
+    }
+
+    fn access_to(&self, token: &str, scope: &str) -> Result<AccessLevel, Status> {
+        let scopes = match self.tokens.get(token) {
+            Some(scopes) => scopes,
+            None => return Err(Status::unauthenticated("Invalid auth token"))
+        };
+
+        scopes.iter()
+            .filter_map(|x| x.access_to(scope))
+            .fold(None, |best, access| match best {
+                Some(best) if best >= access => Some(best),
+                _ => Some(access)
+            })
+            .ok_or_else(|| Status::permission_denied(format!("Auth token is not allowed to access {}", scope)))
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthInterceptor {
+    tokens: AuthTokens,
+    scope: &'static str
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        if !self.tokens.is_enabled() {
+            req.extensions_mut().insert(Grant(AccessLevel::Write));
+            return Ok(req);
+        }
+
+        let token = match req.metadata().get(AUTHORIZATION_HEADER).map(|x| x.to_str()) {
+            Some(Ok(value)) => match value.strip_prefix(BEARER_PREFIX) {
+                Some(token) => token.trim().to_string(),
+                None => return Err(Status::unauthenticated("Authorization must be a bearer token"))
+            },
+            Some(Err(_)) => return Err(Status::unauthenticated("Malformed authorization header")),
+            None => return Err(Status::unauthenticated("Missing auth token"))
+        };
+
+        let access = self.tokens.access_to(&token, self.scope)?;
+        req.extensions_mut().insert(Grant(access));
+        Ok(req)
+    }
+}
+
+// Called by handlers that change state, requests that never went through an interceptor are not restricted
+pub fn require_write<T>(req: &Request<T>) -> Result<(), Status> {
+    match req.extensions().get::<Grant>() {
+        Some(Grant(AccessLevel::Read)) => Err(Status::permission_denied("Auth token is read-only for this service")),
+        _ => Ok(())
+    }
+}
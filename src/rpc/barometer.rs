@@ -1,104 +1,113 @@
 use self::barometer_server::Barometer;
-use crate::capabilities::BarometerCapable;
+use crate::capabilities::{BarometerCapable, CapabilityId, ReadingCalibration};
 use crate::device::DeviceServer;
-use parking_lot::{
-    MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
-};
-use std::sync::Arc;
+use crate::events::DeviceEvents;
+use parking_lot::RwLock;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use tonic::{Request, Response, Status};
 use uuid::Uuid;
 
+use super::access_log;
+use super::{CapabilityMut, CapabilityRef};
+use super::auth;
+use super::rate_limit::{self, CachedRead, ReadCache};
+use super::timeout;
 use super::errors;
+use super::calibration::{GetCalibrationResponse, SetCalibrationRequest};
 use super::void::Void;
 
 tonic::include_proto!("barometer");
 
 pub struct BarometerService {
     server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
+    pressure_cache: ReadCache<Uuid, f32>,
+    altitude_cache: ReadCache<Uuid, f32>,
 }
 
 impl BarometerService {
     pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self::with_read_limits(server, &HashMap::new())
+    }
+
+    pub fn with_read_limits(server: &Arc<RwLock<DeviceServer>>, limits: &HashMap<String, u64>) -> Self {
         Self {
             server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
+            pressure_cache: ReadCache::from_limits(limits, rate_limit::GET_PRESSURE),
+            altitude_cache: ReadCache::from_limits(limits, rate_limit::GET_ALTITUDE),
         }
     }
 
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    // Cached pressure read under the command timeout, batch telemetry reads through this too
+    pub async fn read_pressure(&self, reference: &str) -> Result<CachedRead<f32>, Status> {
+        let address = super::resolve_address(&self.server.read(), reference)?;
+        let device = super::capability_ptr::<dyn BarometerCapable>(&self.server, reference)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_pressure());
+        self.pressure_cache.get_or_read_async(address, async {
+            errors::track_device_result(&self.events, address, read.await)
+        }).await
+    }
+
+    // Cached altitude read under the command timeout, the GPS service reads linked barometers through this too
+    pub async fn read_altitude(&self, reference: &str) -> Result<CachedRead<f32>, Status> {
+        let address = super::resolve_address(&self.server.read(), reference)?;
+        let device = super::capability_ptr::<dyn BarometerCapable>(&self.server, reference)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_altitude());
+        self.altitude_cache.get_or_read_async(address, async {
+            errors::track_device_result(&self.events, address, read.await)
+        }).await
+    }
+
     fn get_device(
         &self,
         address: String,
-    ) -> Result<MappedRwLockReadGuard<'_, dyn BarometerCapable>, Status> {
-        let guard = self.server.read();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn BarometerCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
-
-        Ok(RwLockReadGuard::map(guard, |x| {
-            x.get_device(&address)
-                .unwrap()
-                .as_capability_ref::<dyn BarometerCapable>()
-                .unwrap()
-        }))
+    ) -> Result<CapabilityRef<dyn BarometerCapable>, Status> {
+        super::lock_capability::<dyn BarometerCapable>(&self.server, &address)
     }
 
     fn get_device_mut(
         &self,
         address: String,
-    ) -> Result<MappedRwLockWriteGuard<'_, dyn BarometerCapable>, Status> {
-        let guard = self.server.write();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn BarometerCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
+    ) -> Result<CapabilityMut<dyn BarometerCapable>, Status> {
+        super::lock_capability_mut::<dyn BarometerCapable>(&self.server, &address)
+    }
 
-        Ok(RwLockWriteGuard::map(guard, |x| {
-            x.get_device_mut(&address)
-                .unwrap()
-                .as_capability_mut::<dyn BarometerCapable>()
-                .unwrap()
-        }))
+    // Cached pressures and altitudes no longer match a device whose gain, interval or calibration changed
+    fn invalidate_reads(&self, address: &Uuid) {
+        self.pressure_cache.invalidate(address);
+        self.altitude_cache.invalidate(address);
     }
 }
 
 #[tonic::async_trait]
 impl Barometer for BarometerService {
+    async fn get_units(
+        &self,
+        request: Request<BarometerRequest>,
+    ) -> Result<Response<GetUnitsResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let device = self.get_device(request.get_ref().address.to_owned())?;
+        let units = device.get_units()
+            .into_iter()
+            .map(|x| ReadingUnit { reading: x.reading, unit: x.unit, min: x.min, max: x.max })
+            .collect();
+
+        Ok(Response::new(GetUnitsResponse { units }))
+    }
+
     async fn get_supported_gains(
         &self,
         request: Request<BarometerRequest>,
     ) -> Result<Response<GetSupportedGainsResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
         let device = self.get_device(request.get_ref().address.to_owned())?;
         let gains = device.get_supported_gains();
 
@@ -117,6 +126,7 @@ impl Barometer for BarometerService {
         &self,
         request: Request<BarometerRequest>,
     ) -> Result<Response<GetSupportedIntervalsResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
         let device = self.get_device(request.get_ref().address.to_owned())?;
         let intervals = device.get_supported_intervals();
 
@@ -135,18 +145,23 @@ impl Barometer for BarometerService {
         &self,
         request: Request<BarometerRequest>,
     ) -> Result<Response<GetGainResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
         let device = self.get_device(request.get_ref().address.to_owned())?;
-        let gain_multiplier = device.get_gain().map_err(errors::map_device_error)?;
+        let gain_multiplier = device.get_gain(CapabilityId::Barometer).map_err(errors::map_device_error)?;
         Ok(Response::new(GetGainResponse {
             gain_multiplier: gain_multiplier as u32,
         }))
     }
 
     async fn set_gain(&self, request: Request<SetGainRequest>) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
         let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
         device
-            .set_gain(request.get_ref().gain_id as u8)
+            .set_gain(CapabilityId::Barometer, request.get_ref().gain_id as u8)
             .map_err(errors::map_device_error)?;
+        self.invalidate_reads(&address);
         Ok(Response::new(Void::default()))
     }
 
@@ -154,6 +169,7 @@ impl Barometer for BarometerService {
         &self,
         request: Request<BarometerRequest>,
     ) -> Result<Response<GetIntervalResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
         let device = self.get_device(request.get_ref().address.to_owned())?;
         let sleep_interval_ms = device.get_interval().map_err(errors::map_device_error)?;
         Ok(Response::new(GetIntervalResponse {
@@ -165,10 +181,14 @@ impl Barometer for BarometerService {
         &self,
         request: Request<SetIntervalRequest>,
     ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
         let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
         device
             .set_interval(request.get_ref().interval_id as u8)
             .map_err(errors::map_device_error)?;
+        self.invalidate_reads(&address);
         Ok(Response::new(Void::default()))
     }
 
@@ -176,17 +196,83 @@ impl Barometer for BarometerService {
         &self,
         request: Request<BarometerRequest>,
     ) -> Result<Response<GetPressureResponse>, Status> {
-        let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
-        let pressure = device.get_pressure().map_err(errors::map_device_error)?;
-        Ok(Response::new(GetPressureResponse { value: pressure }))
+        access_log::record_device(&request.get_ref().address);
+        let pressure = self.read_pressure(&request.get_ref().address).await?;
+
+        Ok(Response::new(GetPressureResponse {
+            value: pressure.value,
+            read_at_ms: pressure.read_at_ms(),
+            cached: pressure.cached,
+        }))
     }
 
     async fn get_altitude(
         &self,
         request: Request<BarometerRequest>,
     ) -> Result<Response<GetAltitudeResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let altitude = self.read_altitude(&request.get_ref().address).await?;
+
+        Ok(Response::new(GetAltitudeResponse {
+            value: altitude.value,
+            read_at_ms: altitude.read_at_ms(),
+            cached: altitude.cached,
+        }))
+    }
+
+    async fn get_reference_pressure(
+        &self,
+        request: Request<BarometerRequest>,
+    ) -> Result<Response<GetReferencePressureResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let device = self.get_device(request.get_ref().address.to_owned())?;
+        let pressure_hpa = device.get_reference_pressure().map_err(errors::map_device_error)?;
+        Ok(Response::new(GetReferencePressureResponse { pressure_hpa }))
+    }
+
+    async fn set_reference_pressure(
+        &self,
+        request: Request<SetReferencePressureRequest>,
+    ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let pressure_hpa = request.get_ref().pressure_hpa;
+        if !pressure_hpa.is_finite() || pressure_hpa <= 0.0 {
+            return Err(Status::out_of_range("Reference pressure must be a positive value"));
+        }
+
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
+        let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
+        device.set_reference_pressure(pressure_hpa).map_err(errors::map_device_error)?;
+
+        // cached altitudes were computed against the old reference
+        self.altitude_cache.invalidate(&address);
+        Ok(Response::new(Void::default()))
+    }
+
+    async fn get_calibration(
+        &self,
+        request: Request<BarometerRequest>,
+    ) -> Result<Response<GetCalibrationResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let device = self.get_device(request.get_ref().address.to_owned())?;
+        let calibration = device.get_calibration(CapabilityId::Barometer).map_err(errors::map_device_error)?;
+        Ok(Response::new(GetCalibrationResponse { scale: calibration.scale, offset: calibration.offset }))
+    }
+
+    async fn set_calibration(
+        &self,
+        request: Request<SetCalibrationRequest>,
+    ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let calibration = ReadingCalibration::new(request.get_ref().scale, request.get_ref().offset)
+            .map_err(Status::out_of_range)?;
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
         let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
-        let altitude = device.get_altitude().map_err(errors::map_device_error)?;
-        Ok(Response::new(GetAltitudeResponse { value: altitude }))
+        device.set_calibration(CapabilityId::Barometer, calibration).map_err(errors::map_device_error)?;
+
+        self.invalidate_reads(&address);
+        Ok(Response::new(Void::default()))
     }
 }
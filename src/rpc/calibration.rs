@@ -0,0 +1 @@
+tonic::include_proto!("calibration");
\ No newline at end of file
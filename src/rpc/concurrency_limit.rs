@@ -0,0 +1,87 @@
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::sync::Semaphore;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture};
+use tonic::Status;
+use tower::{Layer, Service};
+
+// Caps how many calls are handled at once, calls over the cap are turned away instead of queued
+#[derive(Clone, Default)]
+pub struct ConcurrencyLimitLayer {
+    // None lets every call through
+    permits: Option<Arc<Semaphore>>,
+    max_in_flight: usize,
+}
+
+impl ConcurrencyLimitLayer {
+    // No limit, or a limit of 0, lets every call through
+    pub fn new(max_in_flight: Option<usize>) -> Self {
+        match max_in_flight {
+            Some(max_in_flight) if max_in_flight > 0 => Self {
+                permits: Some(Arc::new(Semaphore::new(max_in_flight))),
+                max_in_flight,
+            },
+            _ => Self::default(),
+        }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConcurrencyLimit { inner, permits: self.permits.clone(), max_in_flight: self.max_in_flight }
+    }
+}
+
+// Every service the layer wraps shares the same permits, so the cap covers the whole server
+#[derive(Clone)]
+pub struct ConcurrencyLimit<S> {
+    inner: S,
+    permits: Option<Arc<Semaphore>>,
+    max_in_flight: usize,
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for ConcurrencyLimit<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let permits = match &self.permits {
+            Some(permits) => permits.clone(),
+            None => return Box::pin(self.inner.call(req)),
+        };
+
+        let permit = match permits.try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let status = Status::resource_exhausted(format!(
+                    "server is already handling {} calls, try again later",
+                    self.max_in_flight
+                ));
+                return Box::pin(async move { Ok(status.to_http()) });
+            }
+        };
+
+        // the permit is given back once the response starts, a stream doesn't hold it while it keeps sending
+        let call = self.inner.call(req);
+        Box::pin(async move {
+            let response = call.await;
+            drop(permit);
+            response
+        })
+    }
+}
@@ -1,5 +1,6 @@
 use tonic::Status;
-use crate::device::DeviceError;
+use uuid::Uuid;
+use crate::{bus::i2c::I2CError, device::DeviceError, events::DeviceEvents, gpio::GpioError};
 
 pub fn map_device_error(err: DeviceError) -> Status {
     match err {
@@ -7,11 +8,59 @@ pub fn map_device_error(err: DeviceError) -> Status {
         DeviceError::MissingController(_) => Status::unavailable(err.to_string()),
         DeviceError::DuplicateController => Status::already_exists(err.to_string()),
         DeviceError::DuplicateDevice(_) => Status::already_exists(err.to_string()),
-        DeviceError::HardwareError(_) => Status::internal(err.to_string()),
+        DeviceError::HardwareError(..) => Status::internal(err.to_string()),
         DeviceError::InvalidOperation(_) => Status::failed_precondition(err.to_string()),
-        DeviceError::InvalidConfig(_) => Status::invalid_argument(err.to_string()),
+        DeviceError::InvalidConfig(..) => Status::invalid_argument(err.to_string()),
         DeviceError::NotSupported => Status::unimplemented(err.to_string()),
+        DeviceError::LockTimeout(_) => Status::unavailable(err.to_string()),
         DeviceError::Internal => Status::internal(err.to_string()),
         DeviceError::Other(_) => Status::unknown(err.to_string()),
     }
-}
\ No newline at end of file
+}
+
+// Maps like map_device_error, but also counts the failure towards the device's error events
+pub fn map_tracked_device_error(events: &DeviceEvents, address: Uuid) -> impl Fn(DeviceError) -> Status + '_ {
+    move |err| {
+        events.report_error(address, &err);
+        map_device_error(err)
+    }
+}
+
+// Tracks a read either way, a success resets the device's run of errors, see DeviceEvents::is_healthy
+pub fn track_device_result<T>(events: &DeviceEvents, address: Uuid, result: Result<T, DeviceError>) -> Result<T, Status> {
+    match result {
+        Ok(value) => {
+            events.record_success(address);
+            Ok(value)
+        }
+        Err(err) => Err(map_tracked_device_error(events, address)(err))
+    }
+}
+
+pub fn map_gpio_error(err: GpioError) -> Status {
+    match err {
+        GpioError::Busy(_) => Status::failed_precondition(err.to_string()),
+        GpioError::PinNotFound(_) => Status::not_found(err.to_string()),
+        GpioError::PinNodeNotFound(..) => Status::not_found(err.to_string()),
+        GpioError::LeaseNotFound => Status::failed_precondition(err.to_string()),
+        GpioError::PermissionDenied(_) => Status::permission_denied(err.to_string()),
+        GpioError::OsError(_) => Status::internal(err.to_string()),
+        GpioError::Unsupported(_) => Status::unimplemented(err.to_string()),
+        GpioError::InvalidConfig(_) => Status::invalid_argument(err.to_string()),
+        GpioError::Other(_) => Status::unknown(err.to_string()),
+    }
+}
+
+pub fn map_i2c_error(err: I2CError) -> Status {
+    match err {
+        I2CError::InvalidConfig(_) => Status::failed_precondition(err.to_string()),
+        I2CError::BusNotFound(_) => Status::not_found(err.to_string()),
+        I2CError::LeaseNotFound => Status::failed_precondition(err.to_string()),
+        I2CError::InvalidAddress(_) => Status::invalid_argument(err.to_string()),
+        I2CError::Unsupported => Status::unimplemented(err.to_string()),
+        I2CError::ChannelBusy(_) => Status::failed_precondition(err.to_string()),
+        I2CError::HardwareError(_) => Status::internal(err.to_string()),
+        I2CError::OsError(_) => Status::internal(err.to_string()),
+        I2CError::Other(_) => Status::unknown(err.to_string()),
+    }
+}
@@ -0,0 +1,144 @@
+use parking_lot::RwLock;
+use std::sync::Arc;
+use tonic::{Status, Response, Request};
+use crate::bus::GpioController;
+use crate::device::DeviceServer;
+use crate::gpio::{GpioError, PinDirection as GpioPinDirection};
+use self::gpio_server::Gpio;
+
+use super::auth;
+use super::errors;
+use super::void::Void;
+
+tonic::include_proto!("gpio");
+
+// The read can take up to DEBOUNCE_MAX_WINDOWS of these with the GPIO bus locked
+pub const MAX_DEBOUNCE_WINDOW_MS: u32 = 200;
+
+fn parse_pin(pin: u32) -> Result<u8, Status> {
+    u8::try_from(pin).map_err(|_| Status::invalid_argument(format!("Pin {} is out of range", pin)))
+}
+
+fn parse_level(value: u32) -> Result<u8, Status> {
+    match value {
+        0 => Ok(0),
+        1 => Ok(1),
+        v => Err(Status::out_of_range(format!("Pin value {} is not a logic level", v)))
+    }
+}
+
+pub struct GpioService {
+    server: Arc<RwLock<DeviceServer>>,
+}
+
+impl GpioService {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self {
+            server: server.clone(),
+        }
+    }
+
+    fn with_controller<R>(
+        &self,
+        op: impl FnOnce(&mut dyn GpioController) -> Result<R, GpioError>,
+    ) -> Result<R, Status> {
+        let guard = self.server.read();
+        let mut controller = match guard.get_gpio_bus_mut() {
+            Some(controller) => controller,
+            None => return Err(Status::unavailable("No GPIO capable bus controller is loaded")),
+        };
+
+        op(&mut *controller).map_err(errors::map_gpio_error)
+    }
+}
+
+#[tonic::async_trait]
+impl Gpio for GpioService {
+    async fn read_pin(&self, req: Request<ReadPinRequest>) -> Result<Response<ReadPinResponse>, Status> {
+        let pin = parse_pin(req.get_ref().pin)?;
+        let value = self.with_controller(|controller| controller.read_pin(pin))?;
+        Ok(Response::new(ReadPinResponse { value: value as u32 }))
+    }
+
+    async fn read_pin_debounced(&self, req: Request<ReadPinDebouncedRequest>) -> Result<Response<ReadPinResponse>, Status> {
+        let pin = parse_pin(req.get_ref().pin)?;
+        let window_ms = req.get_ref().window_ms;
+        if window_ms == 0 || window_ms > MAX_DEBOUNCE_WINDOW_MS {
+            return Err(Status::invalid_argument(format!("Debounce window must be between 1 and {} ms", MAX_DEBOUNCE_WINDOW_MS)));
+        }
+
+        // sampling sleeps between reads, keep it off the async workers
+        let server = self.server.clone();
+        let value = tokio::task::spawn_blocking(move || {
+            let guard = server.read();
+            let mut controller = match guard.get_gpio_bus_mut() {
+                Some(controller) => controller,
+                None => return Err(Status::unavailable("No GPIO capable bus controller is loaded")),
+            };
+
+            controller.read_debounced(pin, window_ms as u64).map_err(errors::map_gpio_error)
+        }).await.map_err(|_| Status::internal("Debounced read did not complete"))??;
+
+        Ok(Response::new(ReadPinResponse { value: value as u32 }))
+    }
+
+    async fn write_pin(&self, req: Request<WritePinRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
+        let pin = parse_pin(req.get_ref().pin)?;
+        let value = parse_level(req.get_ref().value)?;
+
+        self.with_controller(|controller| controller.write_pin(pin, value))?;
+        Ok(Response::new(Void::default()))
+    }
+
+    async fn write_pins(&self, req: Request<WritePinsRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
+        if req.get_ref().pins.is_empty() {
+            return Err(Status::invalid_argument("No pins were given"));
+        }
+
+        let values = req.get_ref().pins.iter()
+            .map(|x| Ok((parse_pin(x.pin)?, parse_level(x.value)?)))
+            .collect::<Result<Vec<(u8, u8)>, Status>>()?;
+
+        let guard = self.server.read();
+        let mut controller = match guard.get_gpio_bus_mut() {
+            Some(controller) => controller,
+            None => return Err(Status::unavailable("No GPIO capable bus controller is loaded")),
+        };
+
+        match controller.set_values(&values) {
+            Ok(_) => Ok(Response::new(Void::default())),
+            Err(failures) => {
+                let message = failures.iter()
+                    .map(|(pin, err)| format!("pin {}: {}", pin, err))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+
+                // the first failure decides the status code, the message lists all of them
+                let (_, first) = failures.into_iter().next().unwrap();
+                Err(Status::new(errors::map_gpio_error(first).code(), format!("Failed to write pins: {}", message)))
+            }
+        }
+    }
+
+    async fn set_direction(&self, req: Request<SetDirectionRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
+        let pin = parse_pin(req.get_ref().pin)?;
+        let direction = match PinDirection::try_from(req.get_ref().direction) {
+            Ok(PinDirection::Input) => GpioPinDirection::Input,
+            Ok(PinDirection::Output) => GpioPinDirection::Output,
+            Err(_) => return Err(Status::invalid_argument("Unsupported pin direction"))
+        };
+
+        self.with_controller(|controller| controller.set_direction(pin, direction))?;
+        Ok(Response::new(Void::default()))
+    }
+
+    async fn release_pin(&self, req: Request<ReleasePinRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
+        let pin = parse_pin(req.get_ref().pin)?;
+        self.with_controller(|controller| controller.release_pin(pin))?;
+        Ok(Response::new(Void::default()))
+    }
+}
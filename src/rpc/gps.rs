@@ -1,91 +1,50 @@
-use crate::{capabilities::GpsCapable, device::DeviceServer};
-use parking_lot::{RwLock, RwLockReadGuard, MappedRwLockReadGuard, RwLockWriteGuard, MappedRwLockWriteGuard};
+use crate::{capabilities::{self, GpsCapable}, device::DeviceServer};
+use log::warn;
+use parking_lot::RwLock;
+use super::access_log;
+use super::barometer::BarometerService;
+use super::{CapabilityMut, CapabilityRef};
 use std::sync::Arc;
 use tonic::{Status, Response, Request};
-use uuid::Uuid;
 
 use self::gps_server::Gps;
 
 tonic::include_proto!("gps");
 
+const DEFAULT_COORDINATE_PRECISION: u32 = 6;
+
 
 pub struct GpsService {
-    server: Arc<RwLock<DeviceServer>>
+    server: Arc<RwLock<DeviceServer>>,
+    // linked barometers are read through it so fused altitudes share its cache and timeout
+    barometer: Arc<BarometerService>,
 }
 
 impl GpsService {
     pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
         Self {
             server: server.clone(),
+            barometer: Arc::new(BarometerService::new(server)),
         }
     }
 
+    pub fn with_barometer_service(mut self, barometer: Arc<BarometerService>) -> Self {
+        self.barometer = barometer;
+        self
+    }
+
     fn get_device(
         &self,
         address: String,
-    ) -> Result<MappedRwLockReadGuard<'_, dyn GpsCapable>, Status> {
-        let guard = self.server.read();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn GpsCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
-
-        Ok(RwLockReadGuard::map(guard, |x| {
-            x.get_device(&address)
-                .unwrap()
-                .as_capability_ref::<dyn GpsCapable>()
-                .unwrap()
-        }))
+    ) -> Result<CapabilityRef<dyn GpsCapable>, Status> {
+        super::lock_capability::<dyn GpsCapable>(&self.server, &address)
     }
 
     fn get_device_mut(
         &self,
         address: String,
-    ) -> Result<MappedRwLockWriteGuard<'_, dyn GpsCapable>, Status> {
-        let guard = self.server.write();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn GpsCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
-
-        Ok(RwLockWriteGuard::map(guard, |x| {
-            x.get_device_mut(&address)
-                .unwrap()
-                .as_capability_mut::<dyn GpsCapable>()
-                .unwrap()
-        }))
+    ) -> Result<CapabilityMut<dyn GpsCapable>, Status> {
+        super::lock_capability_mut::<dyn GpsCapable>(&self.server, &address)
     }
 
 }
@@ -93,6 +52,7 @@ impl GpsService {
 #[tonic::async_trait]
 impl Gps for GpsService {
     async fn get_location(&self, req: Request<GpsRequest>) -> Result<Response<GetLocationResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
 
@@ -102,7 +62,31 @@ impl Gps for GpsService {
         }
     }
 
+    async fn get_location_formatted(&self, req: Request<GetLocationFormattedRequest>) -> Result<Response<GetLocationFormattedResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let format = match CoordinateFormat::try_from(req.get_ref().format) {
+            Ok(CoordinateFormat::DecimalDegrees) => capabilities::CoordinateFormat::DecimalDegrees,
+            Ok(CoordinateFormat::DegreesMinutesSeconds) => capabilities::CoordinateFormat::DegreesMinutesSeconds,
+            Ok(CoordinateFormat::DegreesDecimalMinutes) => capabilities::CoordinateFormat::DegreesDecimalMinutes,
+            Err(_) => return Err(Status::invalid_argument("Unsupported coordinate format"))
+        };
+
+        let precision = req.get_ref().precision.unwrap_or(DEFAULT_COORDINATE_PRECISION);
+        if precision > capabilities::MAX_COORDINATE_PRECISION as u32 {
+            return Err(Status::out_of_range(format!("Precision cannot be above {} decimals", capabilities::MAX_COORDINATE_PRECISION)));
+        }
+
+        let address = req.get_ref().address.to_owned();
+        let device = self.get_device(address)?;
+
+        match device.get_location_formatted(format, precision as u8) {
+            Ok((latitude, longitude)) => Ok(Response::new(GetLocationFormattedResponse { latitude, longitude })),
+            Err(e) => Err(Status::internal(format!("Failed to get location: {}", e)))
+        }
+    }
+
     async fn get_altitude(&self, req: Request<GpsRequest>) -> Result<Response<GetAltitudeResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
 
@@ -112,7 +96,33 @@ impl Gps for GpsService {
         }
     }
 
+    async fn get_fused_altitude(&self, req: Request<GpsRequest>) -> Result<Response<GetAltitudeResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let address = req.get_ref().address.to_owned();
+        let barometer = self.get_device(address.clone())?.get_barometer().map(|(barometer, _)| barometer.name().to_owned());
+        let altitude = match barometer {
+            Some(barometer) => {
+                let barometer_altitude = match self.barometer.read_altitude(&barometer).await {
+                    Ok(altitude) => Some(altitude.value),
+                    Err(e) => {
+                        warn!("Failed to read altitude from barometer \"{}\", using GPS altitude only: {}", barometer, e.message());
+                        None
+                    }
+                };
+
+                self.get_device(address)?.fuse_barometer_altitude(barometer_altitude)
+            }
+            None => self.get_device(address)?.get_altitude()
+        };
+
+        match altitude {
+            Ok(alt) => Ok(Response::new(GetAltitudeResponse { altitude: alt })),
+            Err(e) => Err(Status::internal(format!("Failed to get fused altitude: {}", e)))
+        }
+    }
+
     async fn has_fix(&self, req: Request<GpsRequest>) -> Result<Response<HasFixResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
 
@@ -122,7 +132,19 @@ impl Gps for GpsService {
         }
     }
 
+    async fn get_fix_age(&self, req: Request<GpsRequest>) -> Result<Response<GetFixAgeResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let address = req.get_ref().address.to_owned();
+        let device = self.get_device(address)?;
+
+        match device.get_fix_age() {
+            Ok(age) => Ok(Response::new(GetFixAgeResponse { age_ms: u64::try_from(age.as_millis()).unwrap_or(u64::MAX) })),
+            Err(e) => Err(Status::internal(format!("Failed to get fix age: {}", e)))
+        }
+    }
+
     async fn get_speed(&self, req: Request<GpsRequest>) -> Result<Response<GetSpeedResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
 
@@ -133,6 +155,7 @@ impl Gps for GpsService {
     }
 
     async fn get_heading(&self, req: Request<GpsRequest>) -> Result<Response<GetHeadingResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
 
@@ -143,6 +166,7 @@ impl Gps for GpsService {
     }
 
     async fn get_num_satellites(&self, req: Request<GpsRequest>) -> Result<Response<GetNumSatellitesResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
 
@@ -153,6 +177,7 @@ impl Gps for GpsService {
     }
 
     async fn get_vertical_accuracy(&self, req: Request<GpsRequest>) -> Result<Response<GetAccuracyResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
 
@@ -163,6 +188,7 @@ impl Gps for GpsService {
     }
 
     async fn get_horizontal_accuracy(&self, req: Request<GpsRequest>) -> Result<Response<GetAccuracyResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
 
@@ -172,7 +198,19 @@ impl Gps for GpsService {
         }
     }
 
+    async fn get_active_geofences(&self, req: Request<GpsRequest>) -> Result<Response<GetActiveGeofencesResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let address = req.get_ref().address.to_owned();
+        let device = self.get_device(address)?;
+
+        match device.get_active_geofences() {
+            Ok(names) => Ok(Response::new(GetActiveGeofencesResponse { names })),
+            Err(e) => Err(super::errors::map_device_error(e))
+        }
+    }
+
     async fn get_full_report(&self, req: Request<GpsRequest>) -> Result<Response<GetFullReportResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let address = req.get_ref().address.to_owned();
         let device = self.get_device(address)?;
         let mut response = GetFullReportResponse::default();
@@ -1,16 +1,32 @@
+use std::sync::Arc;
+use std::time::Instant;
+use parking_lot::RwLock;
 use tonic::{Response, Request, Status};
 
+use crate::adb::AdbServer;
+use crate::device::DeviceServer;
+
 use self::heartbeat_server::Heartbeat;
 
 use super::void::Void;
 
 tonic::include_proto!("heartbeat");
 
-pub struct HeartbeatService;
+pub struct HeartbeatService {
+    server: Arc<RwLock<DeviceServer>>,
+    // None while ADB is turned off in the config
+    adb_server: Option<Arc<RwLock<AdbServer>>>,
+    started_at: Instant
+}
 
 impl HeartbeatService {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self { server: server.clone(), adb_server: None, started_at: Instant::now() }
+    }
+
+    pub fn with_adb(mut self, adb_server: &Arc<RwLock<AdbServer>>) -> Self {
+        self.adb_server = Some(adb_server.clone());
+        self
     }
 }
 
@@ -19,4 +35,21 @@ impl Heartbeat for HeartbeatService {
     async fn ping(&self, _req: Request<Void>) -> Result<Response<Void>, Status> {
         Ok(Response::new(Void::default()))
     }
-}
\ No newline at end of file
+
+    async fn status(&self, _req: Request<Void>) -> Result<Response<StatusResponse>, Status> {
+        let adb = match &self.adb_server {
+            Some(adb_server) if adb_server.read().is_connected() => AdbState::Connected,
+            Some(_) => AdbState::Disconnected,
+            None => AdbState::Disabled
+        };
+
+        let server = self.server.read();
+        Ok(Response::new(StatusResponse {
+            uptime_ms: self.started_at.elapsed().as_millis() as u64,
+            device_count: server.device_count() as u32,
+            bus_count: server.bus_count() as u32,
+            adb: adb as i32,
+            version: env!("CARGO_PKG_VERSION").to_owned()
+        }))
+    }
+}
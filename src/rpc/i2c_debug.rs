@@ -0,0 +1,161 @@
+use self::i2c_debug_server::I2cDebug;
+use crate::bus::i2c::{I2cAddress, I2cAddressing};
+use crate::bus::i2c_sysfs::{self, SmbusTransfer, SysfsI2CBusController};
+use crate::device::DeviceServer;
+use log::warn;
+use parking_lot::RwLock;
+use i2c_linux::I2c;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+use super::auth;
+use super::errors;
+use super::void::Void;
+
+tonic::include_proto!("i2c_debug");
+
+pub const MAX_TRANSFER_LENGTH: usize = 32;
+// how debug transfers show up in the bus wait stats
+const DEBUG_BUS_CLIENT: &str = "i2c_debug";
+
+pub trait RegisterIo: SmbusTransfer + Read + Write {}
+impl<T: SmbusTransfer + Read + Write> RegisterIo for T {}
+
+// Where the debug service runs its transfers, the sysfs bus controller outside of tests
+pub trait RegisterBus: Send + Sync {
+    fn debug_enabled(&self) -> bool;
+    fn transfer(&self, bus_id: u8, op: &mut dyn FnMut(&mut dyn RegisterIo) -> std::io::Result<()>) -> Result<(), Status>;
+}
+
+pub struct ControllerRegisterBus {
+    server: Arc<RwLock<DeviceServer>>,
+    open: fn(&Path) -> std::io::Result<I2c<File>>,
+}
+
+impl ControllerRegisterBus {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self { server: server.clone(), open: |path| I2c::from_path(path) }
+    }
+
+    // Opens buses that aren't open yet through open instead of the device node, see SysfsI2CBusController::get_with
+    pub(crate) fn with_opener(mut self, open: fn(&Path) -> std::io::Result<I2c<File>>) -> Self {
+        self.open = open;
+        self
+    }
+
+    fn controller(&self) -> Option<Arc<RwLock<SysfsI2CBusController>>> {
+        self.server.read().get_bus_ptr::<SysfsI2CBusController>()
+    }
+}
+
+impl RegisterBus for ControllerRegisterBus {
+    fn debug_enabled(&self) -> bool {
+        self.controller().map_or(false, |controller| controller.read().debug_enabled())
+    }
+
+    fn transfer(&self, bus_id: u8, op: &mut dyn FnMut(&mut dyn RegisterIo) -> std::io::Result<()>) -> Result<(), Status> {
+        let controller = match self.controller() {
+            Some(controller) => controller,
+            None => return Err(Status::unavailable("I2C sysfs bus controller is not registered"))
+        };
+
+        // shares the bus with drivers that already have it open, their transfers are serialized by the bus lock
+        let bus = controller.write().get_with(bus_id, self.open).map_err(errors::map_i2c_error)?;
+        let result = op(&mut *bus.client(DEBUG_BUS_CLIENT, 0).lock());
+        drop(bus);
+        if let Err(e) = controller.write().close(bus_id) {
+            warn!("Failed to release I2C bus {} after a debug transfer: {}", bus_id, e);
+        }
+
+        result.map_err(|e| Status::internal(format!("I2C transfer failed: {}", e)))
+    }
+}
+
+// Raw register access for bringing up new chips, only served when the controller has debug_enabled set
+pub struct I2cDebugService {
+    bus: Box<dyn RegisterBus>,
+}
+
+impl I2cDebugService {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self::with_bus(ControllerRegisterBus::new(server))
+    }
+
+    pub fn with_bus(bus: impl RegisterBus + 'static) -> Self {
+        Self { bus: Box::new(bus) }
+    }
+
+    fn check_enabled(&self) -> Result<(), Status> {
+        match self.bus.debug_enabled() {
+            true => Ok(()),
+            false => Err(Status::failed_precondition("I2C debug access is disabled, set debug_enabled in the I2C controller config"))
+        }
+    }
+}
+
+fn check_target(bus_id: u32, address: u32, addressing: i32, register: u32) -> Result<(u8, I2cAddress, u8), Status> {
+    if bus_id > u8::MAX as u32 {
+        return Err(Status::out_of_range("bus ID was out of range"));
+    }
+
+    let addressing = match AddressingMode::try_from(addressing) {
+        Ok(AddressingMode::SevenBit) => I2cAddressing::SevenBit,
+        Ok(AddressingMode::TenBit) => I2cAddressing::TenBit,
+        Err(_) => return Err(Status::invalid_argument("unsupported addressing mode"))
+    };
+
+    let address = u16::try_from(address)
+        .ok()
+        .and_then(|address| I2cAddress::new(address, addressing).ok())
+        .ok_or_else(|| Status::out_of_range(format!("slave address must be a {} address", addressing)))?;
+
+    if register > u8::MAX as u32 {
+        return Err(Status::out_of_range("register was out of range"));
+    }
+
+    Ok((bus_id as u8, address, register as u8))
+}
+
+fn check_length(length: usize) -> Result<(), Status> {
+    if length == 0 || length > MAX_TRANSFER_LENGTH {
+        return Err(Status::out_of_range(format!("transfer length must be between 1 and {} bytes", MAX_TRANSFER_LENGTH)));
+    }
+
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl I2cDebug for I2cDebugService {
+    async fn read_register(&self, req: Request<ReadRegisterRequest>) -> Result<Response<ReadRegisterResponse>, Status> {
+        self.check_enabled()?;
+        let req = req.get_ref();
+        let (bus_id, address, register) = check_target(req.bus_id, req.address, req.addressing, req.register)?;
+        check_length(req.length as usize)?;
+
+        let mut data = vec![0u8; req.length as usize];
+        self.bus.transfer(bus_id, &mut |bus| i2c_sysfs::read_register(bus, address, register, &mut data))?;
+        Ok(Response::new(ReadRegisterResponse { data }))
+    }
+
+    async fn write_register(&self, req: Request<WriteRegisterRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
+        self.check_enabled()?;
+        let req = req.get_ref();
+        let (bus_id, address, register) = check_target(req.bus_id, req.address, req.addressing, req.register)?;
+        check_length(req.data.len())?;
+
+        // one write per byte, so chips without register auto-increment behave the same
+        self.bus.transfer(bus_id, &mut |bus| {
+            for (offset, value) in req.data.iter().enumerate() {
+                i2c_sysfs::write_register(bus, address, register.wrapping_add(offset as u8), *value)?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Response::new(Void::default()))
+    }
+}
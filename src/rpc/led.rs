@@ -1,155 +1,134 @@
 use self::led_controller_server::LedController;
-use crate::{capabilities::{LEDControllerCapable, LEDMode}, device::DeviceServer};
-use parking_lot::{RwLock, RwLockReadGuard, MappedRwLockReadGuard, RwLockWriteGuard, MappedRwLockWriteGuard};
-use std::sync::Arc;
+use crate::{capabilities::{LEDControllerCapable, LEDMode}, device::{DeviceError, DeviceServer}, events::DeviceEvents};
+use parking_lot::RwLock;
+use std::{sync::Arc, time::Duration};
 use tonic::{Status, Response, Request};
-use uuid::Uuid;
 
+use super::access_log;
+use super::CapabilityRef;
+use super::errors;
+use super::auth;
+use super::timeout;
 use super::void::Void;
 
 tonic::include_proto!("led");
 
-fn map_led_mode(mode: LEDMode) -> LedMode {
+fn map_led_mode(mode: &LEDMode) -> LedMode {
     match mode {
         LEDMode::Visible => LedMode::Vis,
-        LEDMode::Infrared => LedMode::Ir
+        LEDMode::Infrared => LedMode::Ir,
+        LEDMode::Named(_) => LedMode::Named
     }
 }
 
-fn reverse_map_led_mode(mode: LedMode) -> LEDMode {
+fn reverse_map_led_mode(mode: LedMode, name: &str) -> Result<LEDMode, Status> {
     match mode {
-        LedMode::Vis => LEDMode::Visible,
-        LedMode::Ir => LEDMode::Infrared
+        LedMode::Vis => Ok(LEDMode::Visible),
+        LedMode::Ir => Ok(LEDMode::Infrared),
+        LedMode::Named if name.is_empty() => Err(Status::invalid_argument("Mode name is required for named LED modes")),
+        LedMode::Named => Ok(LEDMode::from(name.to_owned()))
     }
 }
 
 pub struct LEDControllerService {
     server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
 }
 
 impl LEDControllerService {
     pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
         Self {
             server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
         }
     }
 
-    fn get_device(
-        &self,
-        address: String,
-    ) -> Result<MappedRwLockReadGuard<'_, dyn LEDControllerCapable>, Status> {
-        let guard = self.server.read();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn LEDControllerCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
-
-        Ok(RwLockReadGuard::map(guard, |x| {
-            x.get_device(&address)
-                .unwrap()
-                .as_capability_ref::<dyn LEDControllerCapable>()
-                .unwrap()
-        }))
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
     }
 
-    fn get_device_mut(
+    fn get_device(
         &self,
         address: String,
-    ) -> Result<MappedRwLockWriteGuard<'_, dyn LEDControllerCapable>, Status> {
-        let guard = self.server.write();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn LEDControllerCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
-
-        Ok(RwLockWriteGuard::map(guard, |x| {
-            x.get_device_mut(&address)
-                .unwrap()
-                .as_capability_mut::<dyn LEDControllerCapable>()
-                .unwrap()
-        }))
+    ) -> Result<CapabilityRef<dyn LEDControllerCapable>, Status> {
+        super::lock_capability::<dyn LEDControllerCapable>(&self.server, &address)
     }
 
+    // Changes go out to the hardware, so they run under the command timeout like the sensor reads
+    async fn update<R, F>(&self, address: &str, change: F) -> Result<R, Status>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut (dyn LEDControllerCapable + 'static)) -> Result<R, DeviceError> + Send + 'static,
+    {
+        super::call_with_timeout::<dyn LEDControllerCapable, _, _>(&self.server, &self.events, self.command_timeout, address, change).await
+    }
 }
 
 #[tonic::async_trait]
 impl LedController for LEDControllerService {
     async fn get_state(&self, req: Request<GetStateRequest>) -> Result<Response<GetStateResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let device = self.get_device(req.get_ref().address.to_owned())?;
         let power_state = device.get_power_state();
         let brightness = device.get_brightness();
+        let effective_brightness = device.get_effective_brightness();
         let mode = device.get_mode();
         let mut response = GetStateResponse::default();
 
         response.powered_on = power_state.unwrap_or(false);
         response.brightness = brightness.unwrap_or(0.0);
-        response.mode = map_led_mode(mode.unwrap_or(LEDMode::Infrared)) as i32;
+        response.effective_brightness = effective_brightness.unwrap_or(0.0);
+        let mode = mode.unwrap_or(LEDMode::Infrared);
+        response.mode = map_led_mode(&mode) as i32;
+        response.mode_name = mode.name().to_owned();
         Ok(Response::new(response))
     }
 
-    async fn set_brightness(&self, req: Request<SetBrightnessRequest>) -> Result<Response<Void>, Status> {
+    async fn set_brightness(&self, req: Request<SetBrightnessRequest>) -> Result<Response<SetBrightnessResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
         let brightness = req.get_ref().brightness;
         if brightness < 0.0 || brightness > 1.0 {
             return Err(Status::out_of_range("Brightness value was out of range"));
         }
 
-        let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
-        match device.set_brightness(brightness) {
-            Ok(_) => Ok(Response::new(Void::default())),
-            Err(e) => Err(Status::internal(format!("Failed to set brightness: {}", e)))
-        }
+        let brightness = self.update(&req.get_ref().address, move |device| {
+            device.set_brightness(brightness)?;
+            device.get_brightness()
+        }).await?;
+        Ok(Response::new(SetBrightnessResponse { brightness }))
     }
 
     async fn set_mode(&self, req: Request<SetModeRequest>) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
         let mode = match LedMode::try_from(req.get_ref().mode) {
             Ok(mode) => mode,
             Err(_) => return Err(Status::invalid_argument("Unsupported LED mode"))
         };
 
-        let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
-        match device.set_mode(reverse_map_led_mode(mode)) {
-            Ok(_) => Ok(Response::new(Void::default())),
-            Err(e) => Err(Status::internal(format!("Failed to set mode: {}", e)))
-        }
+        let mode = reverse_map_led_mode(mode, &req.get_ref().mode_name)?;
+        self.update(&req.get_ref().address, move |device| device.set_mode(mode)).await?;
+        Ok(Response::new(Void::default()))
     }
 
     async fn set_power_state(&self, req: Request<SetPowerStateRequest>) -> Result<Response<Void>, Status> {
-        let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
-        match device.set_power_state(req.get_ref().powered_on) {
-            Ok(_) => Ok(Response::new(Void::default())),
-            Err(e) => Err(Status::internal(format!("Failed to set power state: {}", e)))
-        }
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let powered_on = req.get_ref().powered_on;
+        self.update(&req.get_ref().address, move |device| device.set_power_state(powered_on)).await?;
+        Ok(Response::new(Void::default()))
+    }
+
+    async fn list_modes(&self, req: Request<ListModesRequest>) -> Result<Response<ListModesResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let device = self.get_device(req.get_ref().address.to_owned())?;
+        let modes = device.get_modes().map_err(errors::map_device_error)?;
+        Ok(Response::new(ListModesResponse {
+            modes: modes.iter().map(|mode| mode.name().to_owned()).collect()
+        }))
     }
 }
\ No newline at end of file
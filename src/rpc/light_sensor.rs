@@ -1,10 +1,16 @@
 use self::light_sensor_server::LightSensor;
-use crate::{capabilities::LightSensorCapable, device::DeviceServer};
-use parking_lot::{RwLock, RwLockReadGuard, MappedRwLockReadGuard, RwLockWriteGuard, MappedRwLockWriteGuard};
-use std::sync::Arc;
+use crate::{capabilities::{CapabilityId, LightSensorCapable, ReadingCalibration}, device::DeviceServer, events::DeviceEvents};
+use parking_lot::RwLock;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use tonic::{Status, Response, Request};
 use uuid::Uuid;
 
+use super::access_log;
+use super::{CapabilityMut, CapabilityRef};
+use super::auth;
+use super::rate_limit::{self, CachedRead, ReadCache};
+use super::timeout;
+use super::calibration::{GetCalibrationResponse, SetCalibrationRequest};
 use super::void::Void;
 use crate::rpc::errors;
 
@@ -12,81 +18,60 @@ tonic::include_proto!("light_sensor");
 
 pub struct LightSensorService {
     server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
+    luminosity_cache: ReadCache<(Uuid, u8), u32>,
+    illuminance_cache: ReadCache<Uuid, f32>,
 }
 
 impl LightSensorService {
     pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self::with_read_limits(server, &HashMap::new())
+    }
+
+    pub fn with_read_limits(server: &Arc<RwLock<DeviceServer>>, limits: &HashMap<String, u64>) -> Self {
         Self {
             server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
+            luminosity_cache: ReadCache::from_limits(limits, rate_limit::GET_LUMINOSITY),
+            illuminance_cache: ReadCache::from_limits(limits, rate_limit::GET_ILLUMINANCE),
         }
     }
 
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    // Cached illuminance read under the command timeout, batch telemetry reads through this too
+    pub async fn read_illuminance(&self, reference: &str) -> Result<CachedRead<f32>, Status> {
+        let address = super::resolve_address(&self.server.read(), reference)?;
+        let device = super::capability_ptr::<dyn LightSensorCapable>(&self.server, reference)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_illuminance());
+        self.illuminance_cache.get_or_read_async(address, async {
+            errors::track_device_result(&self.events, address, read.await)
+        }).await
+    }
+
     fn get_device(
         &self,
         address: String,
-    ) -> Result<MappedRwLockReadGuard<'_, dyn LightSensorCapable>, Status> {
-        let guard = self.server.read();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn LightSensorCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
-
-        Ok(RwLockReadGuard::map(guard, |x| {
-            x.get_device(&address)
-                .unwrap()
-                .as_capability_ref::<dyn LightSensorCapable>()
-                .unwrap()
-        }))
+    ) -> Result<CapabilityRef<dyn LightSensorCapable>, Status> {
+        super::lock_capability::<dyn LightSensorCapable>(&self.server, &address)
     }
 
     fn get_device_mut(
         &self,
         address: String,
-    ) -> Result<MappedRwLockWriteGuard<'_, dyn LightSensorCapable>, Status> {
-        let guard = self.server.write();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn LightSensorCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
+    ) -> Result<CapabilityMut<dyn LightSensorCapable>, Status> {
+        super::lock_capability_mut::<dyn LightSensorCapable>(&self.server, &address)
+    }
 
-        Ok(RwLockWriteGuard::map(guard, |x| {
-            x.get_device_mut(&address)
-                .unwrap()
-                .as_capability_mut::<dyn LightSensorCapable>()
-                .unwrap()
-        }))
+    // Cached readings of every channel no longer match a device whose gain or interval changed
+    fn invalidate_reads(&self, address: &Uuid) {
+        self.luminosity_cache.invalidate_where(|(cached, _)| cached == address);
+        self.illuminance_cache.invalidate(address);
     }
 }
 
@@ -96,6 +81,7 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<LightSensorRequest>,
     ) -> Result<Response<GetSupportedChannelsResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let device = self.get_device(req.get_ref().address.to_owned())?;
         let supported_channels = device.get_supported_channels();
         
@@ -108,10 +94,25 @@ impl LightSensor for LightSensorService {
         Ok(Response::new(response))
     }
 
+    async fn get_units(
+        &self,
+        req: Request<LightSensorRequest>,
+    ) -> Result<Response<GetUnitsResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let device = self.get_device(req.get_ref().address.to_owned())?;
+        let units = device.get_units()
+            .into_iter()
+            .map(|x| ReadingUnit { reading: x.reading, unit: x.unit, min: x.min, max: x.max })
+            .collect();
+
+        Ok(Response::new(GetUnitsResponse { units }))
+    }
+
     async fn get_supported_gains(
         &self,
         req: Request<LightSensorRequest>,
     ) -> Result<Response<GetSupportedGainsResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let device = self.get_device(req.get_ref().address.to_owned())?;
         let supported_gains = device.get_supported_gains();
         
@@ -128,6 +129,7 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<LightSensorRequest>,
     ) -> Result<Response<GetSupportedIntervalsResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let device = self.get_device(req.get_ref().address.to_owned())?;
         let supported_intervals = device.get_supported_intervals();
         
@@ -144,6 +146,7 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<LightSensorRequest>,
     ) -> Result<Response<GetAutoGainEnabledResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let device = self.get_device(req.get_ref().address.to_owned())?;
         let auto_gain_enabled = device.get_auto_gain_enabled().map_err(errors::map_device_error)?;
         let response = GetAutoGainEnabledResponse { enabled: auto_gain_enabled };
@@ -154,6 +157,8 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<SetAutoGainEnabledRequest>,
     ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
         let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
         device.set_auto_gain_enabled(req.get_ref().enabled).map_err(errors::map_device_error)?;
         Ok(Response::new(Void::default()))
@@ -163,8 +168,9 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<LightSensorRequest>,
     ) -> Result<Response<GetGainResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let device = self.get_device(req.get_ref().address.to_owned())?;
-        let gain = device.get_gain().map_err(errors::map_device_error)?;
+        let gain = device.get_gain(CapabilityId::LightSensor).map_err(errors::map_device_error)?;
         let response = GetGainResponse {
             gain_multiplier: gain as u32,
         };
@@ -175,13 +181,17 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<SetGainRequest>,
     ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let address = super::resolve_address(&self.server.read(), &req.get_ref().address)?;
         let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
         let gain_id = req.get_ref().gain_id;
         if gain_id > u8::MAX as u32 {
             return Err(Status::out_of_range("gain ID was out of range"));
         }
 
-        device.set_gain(gain_id as u8).map_err(errors::map_device_error)?;
+        device.set_gain(CapabilityId::LightSensor, gain_id as u8).map_err(errors::map_device_error)?;
+        self.invalidate_reads(&address);
         Ok(Response::new(Void::default()))
     }
 
@@ -189,6 +199,7 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<LightSensorRequest>,
     ) -> Result<Response<GetIntervalResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
         let device = self.get_device(req.get_ref().address.to_owned())?;
         let interval = device.get_interval().map_err(errors::map_device_error)?;
         let response = GetIntervalResponse {
@@ -201,6 +212,9 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<SetIntervalRequest>,
     ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let address = super::resolve_address(&self.server.read(), &req.get_ref().address)?;
         let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
         let interval_id = req.get_ref().interval_id;
         if interval_id > u8::MAX as u32 {
@@ -208,6 +222,7 @@ impl LightSensor for LightSensorService {
         }
 
         device.set_interval(interval_id as u8).map_err(errors::map_device_error)?;
+        self.invalidate_reads(&address);
         Ok(Response::new(Void::default()))
     }
 
@@ -215,14 +230,24 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<GetLuminosityRequest>,
     ) -> Result<Response<GetLuminosityResponse>, Status> {
-        let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
+        access_log::record_device(&req.get_ref().address);
         let channel_id = req.get_ref().channel_id;
         if channel_id > u8::MAX as u32 {
             return Err(Status::out_of_range("channel ID was out of range"));
         }
 
-        let luminosity = device.get_luminosity(channel_id as u8).map_err(errors::map_device_error)?;
-        let response = GetLuminosityResponse { value: luminosity };
+        let address = super::resolve_address(&self.server.read(), &req.get_ref().address)?;
+        let device = super::capability_ptr::<dyn LightSensorCapable>(&self.server, &req.get_ref().address)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_luminosity(channel_id as u8));
+        let luminosity = self.luminosity_cache.get_or_read_async((address, channel_id as u8), async {
+            errors::track_device_result(&self.events, address, read.await)
+        }).await?;
+
+        let response = GetLuminosityResponse {
+            value: luminosity.value,
+            read_at_ms: luminosity.read_at_ms(),
+            cached: luminosity.cached,
+        };
         Ok(Response::new(response))
     }
 
@@ -230,9 +255,84 @@ impl LightSensor for LightSensorService {
         &self,
         req: Request<LightSensorRequest>,
     ) -> Result<Response<GetIlluminanceResponse>, Status> {
-        let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
-        let illuminance = device.get_illuminance().map_err(errors::map_device_error)?;
-        let response = GetIlluminanceResponse { value: illuminance };
+        access_log::record_device(&req.get_ref().address);
+        let illuminance = self.read_illuminance(&req.get_ref().address).await?;
+
+        let response = GetIlluminanceResponse {
+            value: illuminance.value,
+            read_at_ms: illuminance.read_at_ms(),
+            cached: illuminance.cached,
+        };
         Ok(Response::new(response))
     }
-}
\ No newline at end of file
+
+    async fn get_color_temperature(
+        &self,
+        req: Request<LightSensorRequest>,
+    ) -> Result<Response<GetColorTemperatureResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let address = super::resolve_address(&self.server.read(), &req.get_ref().address)?;
+        let device = super::capability_ptr::<dyn LightSensorCapable>(&self.server, &req.get_ref().address)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_color_temperature());
+        let kelvin = errors::track_device_result(&self.events, address, read.await)?;
+
+        Ok(Response::new(GetColorTemperatureResponse { kelvin }))
+    }
+
+    async fn get_lux_coefficient(
+        &self,
+        req: Request<LightSensorRequest>,
+    ) -> Result<Response<GetLuxCoefficientResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let device = self.get_device(req.get_ref().address.to_owned())?;
+        let coefficient = device.get_lux_coefficient().map_err(errors::map_device_error)?;
+        Ok(Response::new(GetLuxCoefficientResponse { coefficient }))
+    }
+
+    async fn set_lux_coefficient(
+        &self,
+        req: Request<SetLuxCoefficientRequest>,
+    ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let coefficient = req.get_ref().coefficient;
+        if !coefficient.is_finite() || coefficient <= 0.0 {
+            return Err(Status::out_of_range("Lux coefficient must be a positive value"));
+        }
+
+        let address = super::resolve_address(&self.server.read(), &req.get_ref().address)?;
+        let mut device = self.get_device_mut(req.get_ref().address.to_owned())?;
+        device.set_lux_coefficient(coefficient).map_err(errors::map_device_error)?;
+
+        // cached illuminance was computed with the old coefficient
+        self.illuminance_cache.invalidate(&address);
+        Ok(Response::new(Void::default()))
+    }
+
+    async fn get_calibration(
+        &self,
+        request: Request<LightSensorRequest>,
+    ) -> Result<Response<GetCalibrationResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let device = self.get_device(request.get_ref().address.to_owned())?;
+        let calibration = device.get_calibration(CapabilityId::LightSensor).map_err(errors::map_device_error)?;
+        Ok(Response::new(GetCalibrationResponse { scale: calibration.scale, offset: calibration.offset }))
+    }
+
+    async fn set_calibration(
+        &self,
+        request: Request<SetCalibrationRequest>,
+    ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let calibration = ReadingCalibration::new(request.get_ref().scale, request.get_ref().offset)
+            .map_err(Status::out_of_range)?;
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
+        let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
+        device.set_calibration(CapabilityId::LightSensor, calibration).map_err(errors::map_device_error)?;
+
+        // cached illuminance was read with the old calibration, the raw luminosity never is
+        self.illuminance_cache.invalidate(&address);
+        Ok(Response::new(Void::default()))
+    }
+}
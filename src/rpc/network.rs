@@ -1,43 +1,76 @@
 use std::sync::Arc;
+use log::warn;
 use parking_lot::RwLock;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Status, Response};
 use crate::adb::{AdbServer, self};
 use self::network_manager_server::NetworkManager;
+use super::auth;
 use super::void::Void;
 
 tonic::include_proto!("network");
 
+const EVENT_STREAM_BUFFER_SIZE: usize = 16;
+
 pub struct NetworkManagerService {
-    server: Arc<RwLock<AdbServer>>
+    // None while ADB is turned off in the config
+    server: Option<Arc<RwLock<AdbServer>>>
 }
 
 impl NetworkManagerService {
     pub fn new(server: &Arc<RwLock<AdbServer>>) -> Self {
         Self {
-            server: server.clone(),
+            server: Some(server.clone()),
         }
     }
+
+    // Registered when ADB is turned off so clients get unavailable instead of an unknown service
+    pub fn disabled() -> Self {
+        Self { server: None }
+    }
+
+    fn server(&self) -> Result<&Arc<RwLock<AdbServer>>, Status> {
+        self.server.as_ref().ok_or_else(|| Status::unavailable("ADB is disabled in the config"))
+    }
+}
+
+fn map_port_to_rpc(port: &adb::Port) -> Port {
+    let port_type = match port.port_type {
+        adb::PortType::Forward => PortType::Forward,
+        adb::PortType::Reverse => PortType::Reverse
+    };
+
+    Port { r#type: port_type as i32, local_port: port.local_port_num as u32, remote_port: port.remote_port_num as u32 }
+}
+
+pub fn map_connection_event_to_rpc(event: &adb::ConnectionEvent) -> ConnectionEvent {
+    let (event_type, restored_ports, failed_ports) = match &event.kind {
+        adb::ConnectionEventKind::Connected => (ConnectionEventType::Connected, Vec::new(), Vec::new()),
+        adb::ConnectionEventKind::Disconnected => (ConnectionEventType::Disconnected, Vec::new(), Vec::new()),
+        adb::ConnectionEventKind::PortsRestored { restored, failed } => (
+            ConnectionEventType::PortsRestored,
+            restored.iter().map(map_port_to_rpc).collect(),
+            failed.iter().map(map_port_to_rpc).collect()
+        )
+    };
+
+    ConnectionEvent { r#type: event_type as i32, serial: event.serial.clone(), restored_ports, failed_ports }
 }
 
 #[tonic::async_trait]
 impl NetworkManager for NetworkManagerService {
-    async fn get_running_ports(&self, _req: Request<Void>) -> Result<Response<GetRunningPortsResponse>, Status> {
-        let server = self.server.read();
-        let mut ports = Vec::new();
-
-        for port in server.get_running_ports().iter() {
-            let port_type = match port.port_type {
-                adb::PortType::Forward => PortType::Forward,
-                adb::PortType::Reverse => PortType::Reverse
-            };
+    type SubscribeConnectionEventsStream = ReceiverStream<Result<ConnectionEvent, Status>>;
 
-            ports.push(Port { r#type: port_type as i32, local_port: port.local_port_num as u32, remote_port: port.remote_port_num as u32 });
-        }
+    async fn get_running_ports(&self, _req: Request<Void>) -> Result<Response<GetRunningPortsResponse>, Status> {
+        let server = self.server()?.read();
+        let ports = server.get_running_ports().iter().map(map_port_to_rpc).collect();
 
         Ok(Response::new(GetRunningPortsResponse { ports }))
     }
 
     async fn add_forward_port(&self, req: Request<AddPortRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
         let data = req.get_ref();
         let device_port: u16 = match data.device_port.try_into() {
             Ok(port) => port,
@@ -48,7 +81,7 @@ impl NetworkManager for NetworkManagerService {
             Err(e) => return Err(Status::invalid_argument(format!("Server port was out of range: {}", e)))
         };
 
-        let server = self.server.read();
+        let server = self.server()?.read();
         match server.add_port(adb::PortType::Forward, server_port, device_port, true) {
             Ok(_) => Ok(Response::new(Void::default())),
             Err(e) => Err(Status::internal(format!("Failed to add port: {}", e)))
@@ -56,6 +89,7 @@ impl NetworkManager for NetworkManagerService {
     }
 
     async fn add_reverse_port(&self, req: Request<AddPortRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
         let data = req.get_ref();
         let device_port: u16 = match data.device_port.try_into() {
             Ok(port) => port,
@@ -66,7 +100,7 @@ impl NetworkManager for NetworkManagerService {
             Err(e) => return Err(Status::invalid_argument(format!("Server port was out of range: {}", e)))
         };
 
-        let server = self.server.read();
+        let server = self.server()?.read();
         match server.add_port(adb::PortType::Forward, server_port, device_port, false) {
             Ok(_) => Ok(Response::new(Void::default())),
             Err(e) => Err(Status::internal(format!("Failed to add port: {}", e)))
@@ -74,13 +108,14 @@ impl NetworkManager for NetworkManagerService {
     }
 
     async fn remove_forward_port(&self, req: Request<RemoveForwardPortRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
         let data = req.get_ref();
         let server_port: u16 = match data.server_port.try_into() {
             Ok(port) => port,
             Err(e) => return Err(Status::invalid_argument(format!("Server port was out of range: {}", e)))
         };
 
-        let server = self.server.read();
+        let server = self.server()?.read();
         match server.remove_forward_port(server_port, false) {
             Ok(_) => Ok(Response::new(Void::default())),
             Err(e) => Err(Status::internal(format!("Failed to remove port: {}", e)))
@@ -88,16 +123,44 @@ impl NetworkManager for NetworkManagerService {
     }
 
     async fn remove_reverse_port(&self, req: Request<RemoveReversePortRequest>) -> Result<Response<Void>, Status> {
+        auth::require_write(&req)?;
         let data = req.get_ref();
         let device_port: u16 = match data.device_port.try_into() {
             Ok(port) => port,
             Err(e) => return Err(Status::invalid_argument(format!("Device port was out of range: {}", e)))
         };
 
-        let server = self.server.read();
+        let server = self.server()?.read();
         match server.remove_reverse_port(device_port, false) {
             Ok(_) => Ok(Response::new(Void::default())),
             Err(e) => Err(Status::internal(format!("Failed to remove port: {}", e)))
         }
     }
-}
\ No newline at end of file
+
+    async fn subscribe_connection_events(&self, _req: Request<Void>) -> Result<Response<Self::SubscribeConnectionEventsStream>, Status> {
+        let mut events = match self.server()?.read().subscribe_connection_events() {
+            Some(events) => events,
+            None => return Err(Status::failed_precondition("connection events are disabled in the config"))
+        };
+        let (sender, receiver) = mpsc::channel(EVENT_STREAM_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Connection event subscriber fell behind, skipped {} events", skipped);
+                        continue;
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break
+                };
+
+                if sender.send(Ok(map_connection_event_to_rpc(&event))).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
@@ -0,0 +1,96 @@
+use self::power_monitor_server::PowerMonitor;
+use crate::capabilities::PowerMonitorCapable;
+use crate::device::DeviceServer;
+use crate::events::DeviceEvents;
+use parking_lot::RwLock;
+use std::{sync::Arc, time::Duration};
+use tonic::{Request, Response, Status};
+
+use super::access_log;
+use super::CapabilityMut;
+use super::auth;
+use super::errors;
+use super::timeout;
+use super::void::Void;
+
+tonic::include_proto!("power_monitor");
+
+pub struct PowerMonitorService {
+    server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
+}
+
+impl PowerMonitorService {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self {
+            server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
+        }
+    }
+
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    fn get_device_mut(
+        &self,
+        address: String,
+    ) -> Result<CapabilityMut<dyn PowerMonitorCapable>, Status> {
+        super::lock_capability_mut::<dyn PowerMonitorCapable>(&self.server, &address)
+    }
+}
+
+#[tonic::async_trait]
+impl PowerMonitor for PowerMonitorService {
+    async fn get_bus_voltage(
+        &self,
+        request: Request<PowerMonitorRequest>,
+    ) -> Result<Response<GetBusVoltageResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
+        let device = super::capability_ptr::<dyn PowerMonitorCapable>(&self.server, &request.get_ref().address)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_bus_voltage());
+        let volts = errors::track_device_result(&self.events, address, read.await)?;
+        Ok(Response::new(GetBusVoltageResponse { volts }))
+    }
+
+    async fn get_current(
+        &self,
+        request: Request<PowerMonitorRequest>,
+    ) -> Result<Response<GetCurrentResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
+        let device = super::capability_ptr::<dyn PowerMonitorCapable>(&self.server, &request.get_ref().address)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_current_ma());
+        let milliamps = errors::track_device_result(&self.events, address, read.await)?;
+        Ok(Response::new(GetCurrentResponse { milliamps }))
+    }
+
+    async fn get_power(
+        &self,
+        request: Request<PowerMonitorRequest>,
+    ) -> Result<Response<GetPowerResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
+        let device = super::capability_ptr::<dyn PowerMonitorCapable>(&self.server, &request.get_ref().address)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_power_mw());
+        let milliwatts = errors::track_device_result(&self.events, address, read.await)?;
+        Ok(Response::new(GetPowerResponse { milliwatts }))
+    }
+
+    async fn set_calibration(
+        &self,
+        request: Request<SetCalibrationRequest>,
+    ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
+        device
+            .set_calibration(request.get_ref().shunt_resistance_ohms, request.get_ref().max_expected_current_a)
+            .map_err(errors::map_device_error)?;
+        Ok(Response::new(Void::default()))
+    }
+}
@@ -0,0 +1,91 @@
+use parking_lot::Mutex;
+use std::{collections::HashMap, future::Future, hash::Hash, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use tonic::Status;
+
+pub const GET_LUMINOSITY: &str = "get_luminosity";
+pub const GET_ILLUMINANCE: &str = "get_illuminance";
+pub const GET_TEMPERATURE_CELSIUS: &str = "get_temperature_celsius";
+pub const GET_TEMPERATURE_FAHRENHEIT: &str = "get_temperature_fahrenheit";
+pub const GET_PRESSURE: &str = "get_pressure";
+pub const GET_ALTITUDE: &str = "get_altitude";
+
+pub const RATE_LIMITED_METHODS: [&str; 6] = [
+    GET_LUMINOSITY,
+    GET_ILLUMINANCE,
+    GET_TEMPERATURE_CELSIUS,
+    GET_TEMPERATURE_FAHRENHEIT,
+    GET_PRESSURE,
+    GET_ALTITUDE,
+];
+
+pub struct CachedRead<T> {
+    pub value: T,
+    pub read_at: SystemTime,
+    pub cached: bool,
+}
+
+impl<T> CachedRead<T> {
+    pub fn read_at_ms(&self) -> u64 {
+        self.read_at
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
+struct CacheEntry<T> {
+    value: T,
+    read_at: SystemTime,
+    refreshed: Instant,
+}
+
+// Serves repeated reads of the same key from memory until min_interval has passed since the last hardware read
+pub struct ReadCache<K, T> {
+    min_interval: Duration,
+    entries: Mutex<HashMap<K, CacheEntry<T>>>,
+}
+
+impl<K: Eq + Hash, T: Clone> ReadCache<K, T> {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_limits(limits: &HashMap<String, u64>, method: &str) -> Self {
+        Self::new(Duration::from_millis(limits.get(method).copied().unwrap_or(0)))
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    // The cache can't stay locked across the await, so concurrent misses may both reach the device
+    pub async fn get_or_read_async<F: Future<Output = Result<T, Status>>>(&self, key: K, read: F) -> Result<CachedRead<T>, Status> {
+        if self.min_interval.is_zero() {
+            return Ok(CachedRead { value: read.await?, read_at: SystemTime::now(), cached: false });
+        }
+
+        let cached = self.entries.lock().get(&key)
+            .filter(|entry| entry.refreshed.elapsed() < self.min_interval)
+            .map(|entry| CachedRead { value: entry.value.clone(), read_at: entry.read_at, cached: true });
+        if let Some(cached) = cached {
+            return Ok(cached);
+        }
+
+        let value = read.await?;
+        let read_at = SystemTime::now();
+        self.entries.lock().insert(key, CacheEntry { value: value.clone(), read_at, refreshed: Instant::now() });
+        Ok(CachedRead { value, read_at, cached: false })
+    }
+
+    pub fn invalidate(&self, key: &K) {
+        self.entries.lock().remove(key);
+    }
+
+    // For caches keyed by more than the device address
+    pub fn invalidate_where<P: Fn(&K) -> bool>(&self, matches: P) {
+        self.entries.lock().retain(|key, _| !matches(key));
+    }
+}
@@ -1,19 +1,92 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use log::{error, warn};
 use parking_lot::RwLock;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Result, Request, Response, Status};
-use crate::device::DeviceServer;
+use crate::bus::BusController as _;
+use crate::bus::pwm::{PWMBusController, PWMChannelBacking, PWMChannelInfo};
+use crate::bus::pwm_sysfs::SysfsPWMBusController;
+use crate::bus::i2c_sysfs::SysfsI2CBusController;
+use crate::bus::ControllerRegistry;
+use crate::capabilities::DiagnosticsCapable;
+use crate::config::store::{merge_patch, ConfigStore};
+use crate::config::Topology;
+use crate::device::{DeviceError, DeviceOrder, DeviceServer, DeviceSnapshot};
+use crate::drivers::DriverRegistry;
+use crate::events::DeviceEventKind;
+use crate::gpio::{GpioBorrowChecker, PinDirection, PinState};
+use crate::topology::{load_topology, restore_topology, validate_topology};
 use self::device_reflection_server::DeviceReflection;
+use super::access_log;
+use super::auth;
+use super::errors;
+use super::timeout;
 use super::void::Void;
 
 tonic::include_proto!("reflection");
 
+const EVENT_STREAM_BUFFER_SIZE: usize = 16;
+
 pub struct DeviceReflectionService {
-    server: Arc<RwLock<DeviceServer>>
+    server: Arc<RwLock<DeviceServer>>,
+    effective_config: Option<String>,
+    gpio: Option<Arc<RwLock<GpioBorrowChecker>>>,
+    // both are needed to validate and save device config updates
+    config_store: Option<Arc<ConfigStore>>,
+    drivers: Option<Arc<DriverRegistry>>,
+    // with the above and gpio, lets a topology be imported
+    controllers: Option<Arc<ControllerRegistry>>,
+    command_timeout: Duration
 }
 
 impl DeviceReflectionService {
     pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
-        DeviceReflectionService { server: server.clone() }
+        DeviceReflectionService {
+            server: server.clone(),
+            effective_config: None,
+            gpio: None,
+            config_store: None,
+            drivers: None,
+            controllers: None,
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS)
+        }
+    }
+
+    // effective_config is expected to be redacted already
+    pub fn with_config(server: &Arc<RwLock<DeviceServer>>, effective_config: String) -> Self {
+        DeviceReflectionService {
+            server: server.clone(),
+            effective_config: Some(effective_config),
+            gpio: None,
+            config_store: None,
+            drivers: None,
+            controllers: None,
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS)
+        }
+    }
+
+    pub fn with_gpio(mut self, gpio: &Arc<RwLock<GpioBorrowChecker>>) -> Self {
+        self.gpio = Some(gpio.clone());
+        self
+    }
+
+    pub fn with_config_store(mut self, store: &Arc<ConfigStore>, drivers: &Arc<DriverRegistry>) -> Self {
+        self.config_store = Some(store.clone());
+        self.drivers = Some(drivers.clone());
+        self
+    }
+
+    pub fn with_controllers(mut self, controllers: &Arc<ControllerRegistry>) -> Self {
+        self.controllers = Some(controllers.clone());
+        self
+    }
+
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
     }
 }
 
@@ -23,7 +96,27 @@ fn map_capability_to_rpc(cap: crate::capabilities::CapabilityId) -> self::Capabi
         crate::capabilities::CapabilityId::GPS => CapabilityId::Gps,
         crate::capabilities::CapabilityId::LightSensor => CapabilityId::LightSensor,
         crate::capabilities::CapabilityId::Thermometer => CapabilityId::Thermometer,
-        crate::capabilities::CapabilityId::Barometer => CapabilityId::Barometer
+        crate::capabilities::CapabilityId::Barometer => CapabilityId::Barometer,
+        crate::capabilities::CapabilityId::SerialPort => CapabilityId::SerialPort,
+        crate::capabilities::CapabilityId::Diagnostics => CapabilityId::Diagnostics,
+        crate::capabilities::CapabilityId::PowerMonitor => CapabilityId::PowerMonitor,
+        crate::capabilities::CapabilityId::Servo => CapabilityId::Servo,
+        crate::capabilities::CapabilityId::Switch => CapabilityId::Switch
+    }
+}
+
+pub(crate) fn map_capability_from_rpc(cap: self::CapabilityId) -> crate::capabilities::CapabilityId {
+    match cap {
+        CapabilityId::LedController => crate::capabilities::CapabilityId::LEDController,
+        CapabilityId::Gps => crate::capabilities::CapabilityId::GPS,
+        CapabilityId::LightSensor => crate::capabilities::CapabilityId::LightSensor,
+        CapabilityId::Thermometer => crate::capabilities::CapabilityId::Thermometer,
+        CapabilityId::Barometer => crate::capabilities::CapabilityId::Barometer,
+        CapabilityId::SerialPort => crate::capabilities::CapabilityId::SerialPort,
+        CapabilityId::Diagnostics => crate::capabilities::CapabilityId::Diagnostics,
+        CapabilityId::PowerMonitor => crate::capabilities::CapabilityId::PowerMonitor,
+        CapabilityId::Servo => crate::capabilities::CapabilityId::Servo,
+        CapabilityId::Switch => crate::capabilities::CapabilityId::Switch
     }
 }
 
@@ -31,20 +124,84 @@ fn map_capabilities_to_rpc(caps: Vec<crate::capabilities::CapabilityId>) -> Vec<
     caps.iter().map(|x| map_capability_to_rpc(x.to_owned())).collect()
 }
 
+fn map_event_to_rpc(event: &crate::events::DeviceEvent) -> DeviceEvent {
+    let (event_type, error_count, last_error, geofence) = match &event.kind {
+        DeviceEventKind::DeviceRegistered => (DeviceEventType::DeviceRegistered, None, None, None),
+        DeviceEventKind::DeviceRemoved => (DeviceEventType::DeviceRemoved, None, None, None),
+        DeviceEventKind::DeviceStarted => (DeviceEventType::DeviceStarted, None, None, None),
+        DeviceEventKind::DeviceStopped => (DeviceEventType::DeviceStopped, None, None, None),
+        DeviceEventKind::HardwareErrors { count, last_error } => (DeviceEventType::HardwareErrors, Some(*count), Some(last_error.clone()), None),
+        DeviceEventKind::GeofenceEntered { geofence } => (DeviceEventType::GeofenceEntered, None, None, Some(geofence.clone())),
+        DeviceEventKind::GeofenceExited { geofence } => (DeviceEventType::GeofenceExited, None, None, Some(geofence.clone()))
+    };
+
+    DeviceEvent {
+        r#type: event_type as i32,
+        address: event.address.to_string(),
+        timestamp_ms: event.timestamp_ms(),
+        error_count,
+        last_error,
+        geofence
+    }
+}
+
+fn map_pwm_channel_to_rpc(controller: String, channel: PWMChannelInfo) -> PwmChannel {
+    let (rppal_channel, chip_num, chip_channel) = match channel.backing {
+        PWMChannelBacking::Rppal(id) => (Some(id as u32), None, None),
+        PWMChannelBacking::Sysfs { chip_num, chip_channel } => (None, Some(chip_num as u32), Some(chip_channel as u32))
+    };
+
+    PwmChannel {
+        controller,
+        channel_id: channel.channel_id as u32,
+        rppal_channel,
+        chip_num,
+        chip_channel,
+        gpio_pin: channel.gpio_pin as u32,
+        busy: channel.busy
+    }
+}
+
+fn map_pin_to_rpc(pin: &PinState) -> GpioPinState {
+    GpioPinState {
+        pin: pin.pin_id() as u32,
+        bcm_id: pin.bcm_id() as u32,
+        leased: pin.is_leased(),
+        direction: match pin.direction() {
+            Some(PinDirection::Input) => GpioPinDirection::Input,
+            Some(PinDirection::Output) => GpioPinDirection::Output,
+            None => GpioPinDirection::Unconfigured
+        } as i32,
+        last_value: pin.last_value().map(|x| x as u32),
+        active_low: pin.is_active_low()
+    }
+}
+
+fn map_device_to_rpc(device: DeviceSnapshot) -> Device {
+    Device { 
+        address: device.address.to_string(),
+        capabilities: map_capabilities_to_rpc(device.capabilities)
+            .into_iter().map(|x| x as i32).collect(),
+        device_name: device.name,
+        driver_name: device.driver_name,
+        is_running: device.is_running,
+        is_healthy: device.is_healthy,
+        tags: device.tags
+    }
+}
+
 #[tonic::async_trait]
 impl DeviceReflection for DeviceReflectionService {
-    async fn list_devices(&self, _req: Request<Void>) -> Result<Response<ListDevicesResponse>, Status> {
-        let mut devices = Vec::<Device>::new();
-        for (address, device) in self.server.read().get_devices() {
-            devices.push(Device { 
-                address: address.to_string(),
-                capabilities: map_capabilities_to_rpc(device.get_capabilities())
-                    .into_iter().map(|x| x as i32).collect(),
-                device_name: device.device_name(),
-                driver_name: device.driver_name(),
-                is_running: device.is_running()
-            });
-        }
+    type SubscribeEventsStream = ReceiverStream<Result<DeviceEvent, Status>>;
+
+    async fn list_devices(&self, req: Request<ListDevicesRequest>) -> Result<Response<ListDevicesResponse>, Status> {
+        let order = match req.get_ref().sort_by_name {
+            true => DeviceOrder::Name,
+            false => DeviceOrder::Registration
+        };
+
+        let snapshot = self.server.read().snapshot_ordered(order);
+        let devices: Vec<Device> = snapshot.into_iter().map(map_device_to_rpc).collect();
 
         Ok(Response::new(ListDevicesResponse { count: devices.len() as u32, devices: devices }))
     }
@@ -57,4 +214,323 @@ impl DeviceReflection for DeviceReflectionService {
 
         Ok(Response::new(ListControllersResponse { count: controllers.len() as u32, controllers: controllers }))
     }
+
+    async fn find_devices(&self, req: Request<FindDevicesRequest>) -> Result<Response<ListDevicesResponse>, Status> {
+        let capability = match CapabilityId::try_from(req.get_ref().capability) {
+            Ok(cap) => map_capability_from_rpc(cap),
+            Err(_) => return Err(Status::invalid_argument("Unsupported capability"))
+        };
+
+        let snapshot = self.server.read().snapshot();
+        let devices: Vec<Device> = snapshot.into_iter()
+            .filter(|device| device.capabilities.contains(&capability))
+            .map(map_device_to_rpc)
+            .collect();
+
+        Ok(Response::new(ListDevicesResponse { count: devices.len() as u32, devices: devices }))
+    }
+
+    async fn find_devices_by_tag(&self, req: Request<FindDevicesByTagRequest>) -> Result<Response<ListDevicesResponse>, Status> {
+        let tag = req.get_ref().tag.trim();
+        if tag.is_empty() {
+            return Err(Status::invalid_argument("Tag cannot be empty"));
+        }
+
+        let snapshot = self.server.read().snapshot();
+        let devices: Vec<Device> = snapshot.into_iter()
+            .filter(|device| device.tags.iter().any(|x| x == tag))
+            .map(map_device_to_rpc)
+            .collect();
+
+        Ok(Response::new(ListDevicesResponse { count: devices.len() as u32, devices: devices }))
+    }
+
+    async fn list_tags(&self, _req: Request<Void>) -> Result<Response<ListTagsResponse>, Status> {
+        let snapshot = self.server.read().snapshot();
+        let mut tags: Vec<String> = snapshot.into_iter()
+            .flat_map(|device| device.tags)
+            .collect();
+
+        tags.sort();
+        tags.dedup();
+        Ok(Response::new(ListTagsResponse { tags }))
+    }
+
+    async fn get_device_info(&self, req: Request<GetDeviceInfoRequest>) -> Result<Response<Device>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let server = self.server.read();
+        let address = super::resolve_address(&server, &req.get_ref().address)?;
+        match server.snapshot_device(&address) {
+            Some(device) => Ok(Response::new(map_device_to_rpc(device))),
+            None => Err(Status::not_found("Device does not exist"))
+        }
+    }
+
+    async fn run_self_test(&self, req: Request<RunSelfTestRequest>) -> Result<Response<RunSelfTestResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let events = self.server.read().events().clone();
+        let mut diagnostics = super::lock_capability_mut::<dyn DiagnosticsCapable>(&self.server, &req.get_ref().address)?;
+        let address = diagnostics.address();
+        let report = diagnostics.self_test().map_err(errors::map_device_error)?;
+        events.record_self_test(address, report.healthy);
+        Ok(Response::new(RunSelfTestResponse {
+            healthy: report.healthy,
+            chip_id_verified: report.chip_id_verified,
+            reported_chip_id: report.reported_chip_id.map(|x| x as u32),
+            last_error: report.last_error,
+            uptime_ms: report.uptime.as_millis() as u64
+        }))
+    }
+
+    async fn update_device_config(&self, req: Request<UpdateDeviceConfigRequest>) -> Result<Response<UpdateDeviceConfigResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let (store, drivers) = match (&self.config_store, &self.drivers) {
+            (Some(store), Some(drivers)) => (store, drivers),
+            _ => return Err(Status::unavailable("Device config updates are not available"))
+        };
+
+        let data = req.get_ref();
+        let patch: serde_json::Value = serde_json::from_str(&data.patch)
+            .map_err(|e| Status::invalid_argument(format!("Patch is not valid JSON: {}", e)))?;
+
+        // held until the new driver data is saved, so two updates to the same device can't drop each other's fields
+        let _update = store.lock_updates().await;
+        let address = super::resolve_address(&self.server.read(), &data.address)?;
+        let mut device_config = match store.device_config(&address) {
+            Some(config) => config,
+            None => return Err(Status::failed_precondition("Device was not created from the config file"))
+        };
+
+        merge_patch(&mut device_config.driver_data, &patch);
+
+        // the driver gets pin IDs, the saved config keeps the pin names
+        let mut resolved = device_config.clone();
+        store.config().gpio_section.resolve_pin_names(&mut resolved.driver_data)
+            .map_err(|e| Status::invalid_argument(format!("Invalid driver data: {}", e)))?;
+
+        // building a throwaway device checks the data against the driver's config struct without touching hardware
+        drivers.build(&mut resolved.clone())
+            .map_err(|e| Status::invalid_argument(format!("Invalid driver data: {}", e)))?;
+
+        // applying it reconfigures the hardware, so it runs under the command timeout like any other device call
+        let device = super::device_ptr(&self.server, &data.address)?;
+        let applied_live = match timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.as_mut().apply_config(&resolved)).await {
+            Ok(_) => true,
+            Err(DeviceError::NotSupported) => false,
+            Err(e) => return Err(errors::map_device_error(e))
+        };
+
+        store.set_driver_data(&address, device_config.driver_data.clone())
+            .map_err(|e| Status::internal(format!("Failed to save config: {}", e)))?;
+
+        Ok(Response::new(UpdateDeviceConfigResponse {
+            applied_live,
+            driver_data: device_config.driver_data.to_string()
+        }))
+    }
+
+    async fn subscribe_events(&self, _req: Request<Void>) -> Result<Response<Self::SubscribeEventsStream>, Status> {
+        let mut events = self.server.read().events().subscribe();
+        let (sender, receiver) = mpsc::channel(EVENT_STREAM_BUFFER_SIZE);
+
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Event subscriber fell behind, skipped {} events", skipped);
+                        continue;
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break
+                };
+
+                if sender.send(Ok(map_event_to_rpc(&event))).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    // Rendered from the config store when there is one, so config updates and topology imports show up
+    async fn get_effective_config(&self, _req: Request<Void>) -> Result<Response<GetEffectiveConfigResponse>, Status> {
+        if let Some(store) = &self.config_store {
+            let config = store.config().to_redacted_str(true)
+                .map_err(|e| Status::internal(format!("Failed to serialize effective config: {}", e)))?;
+            return Ok(Response::new(GetEffectiveConfigResponse { config }));
+        }
+
+        match &self.effective_config {
+            Some(config) => Ok(Response::new(GetEffectiveConfigResponse { config: config.clone() })),
+            None => Err(Status::unavailable("Effective config is not available"))
+        }
+    }
+
+    async fn get_gpio_state(&self, _req: Request<Void>) -> Result<Response<GetGpioStateResponse>, Status> {
+        let gpio = match &self.gpio {
+            Some(gpio) => gpio.read(),
+            None => return Err(Status::unavailable("GPIO state is not available"))
+        };
+
+        let mut pins: Vec<GpioPinState> = gpio.get_pins().into_iter().map(map_pin_to_rpc).collect();
+        pins.sort_by_key(|x| x.pin);
+        Ok(Response::new(GetGpioStateResponse { pins }))
+    }
+
+    async fn list_gpio_pins(&self, _req: Request<Void>) -> Result<Response<ListGpioPinsResponse>, Status> {
+        let gpio = match &self.gpio {
+            Some(gpio) => gpio,
+            None => return Err(Status::unavailable("GPIO state is not available"))
+        };
+
+        // controllers lock the borrow checker while holding their own lock, so collect owners before locking it here
+        let mut owners = HashMap::new();
+        for controller in self.server.read().get_buses() {
+            for pin in controller.owned_pins() {
+                owners.insert(pin, controller.name());
+            }
+        }
+
+        let mut pins: Vec<GpioPin> = gpio.read().get_pins().into_iter().map(|pin| GpioPin {
+            pin_id: pin.pin_id() as u32,
+            bcm_id: pin.bcm_id() as u32,
+            leased: pin.is_leased(),
+            owner: owners.remove(&pin.pin_id())
+        }).collect();
+
+        pins.sort_by_key(|x| x.pin_id);
+        Ok(Response::new(ListGpioPinsResponse { count: pins.len() as u32, pins }))
+    }
+
+    async fn get_lock_stats(&self, _req: Request<Void>) -> Result<Response<GetLockStatsResponse>, Status> {
+        let stats = self.server.read().lock_wait_stats();
+        Ok(Response::new(GetLockStatsResponse {
+            acquisitions: stats.acquisitions,
+            timeouts: stats.timeouts,
+            average_wait_us: stats.average_wait().as_micros() as u64,
+            max_wait_us: stats.max_wait.as_micros() as u64
+        }))
+    }
+
+    async fn get_bus_wait_stats(&self, _req: Request<Void>) -> Result<Response<GetBusWaitStatsResponse>, Status> {
+        let clients = match self.server.read().get_bus::<SysfsI2CBusController>() {
+            Some(i2c) => i2c.wait_stats().into_iter().map(|(bus_id, client, stats)| BusWaitStats {
+                bus_id: bus_id as u32,
+                client,
+                acquisitions: stats.acquisitions,
+                average_wait_us: stats.average_wait().as_micros() as u64,
+                max_wait_us: stats.max_wait.as_micros() as u64
+            }).collect(),
+            None => Vec::new()
+        };
+
+        Ok(Response::new(GetBusWaitStatsResponse { clients }))
+    }
+
+    async fn get_device_stats(&self, req: Request<GetDeviceStatsRequest>) -> Result<Response<GetDeviceStatsResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let server = self.server.read();
+        let address = super::resolve_address(&server, &req.get_ref().address)?;
+        if !server.has_device(&address) {
+            return Err(Status::not_found("Device does not exist"));
+        }
+
+        let stats = server.events().stats(&address);
+        Ok(Response::new(GetDeviceStatsResponse {
+            running: stats.started_at.is_some(),
+            uptime_ms: stats.uptime().map_or(0, |x| x.as_millis() as u64),
+            read_count: stats.read_count,
+            error_count: stats.error_count
+        }))
+    }
+
+    async fn list_pwm_channels(&self, _req: Request<Void>) -> Result<Response<ListPwmChannelsResponse>, Status> {
+        let server = self.server.read();
+        let mut channels = Vec::new();
+        if let Some(pwm) = server.get_bus::<PWMBusController>() {
+            channels.extend(pwm.list_channels().into_iter().map(|x| map_pwm_channel_to_rpc(pwm.name(), x)));
+        }
+
+        if let Some(pwm) = server.get_bus::<SysfsPWMBusController>() {
+            channels.extend(pwm.list_channels().into_iter().map(|x| map_pwm_channel_to_rpc(pwm.name(), x)));
+        }
+
+        Ok(Response::new(ListPwmChannelsResponse { channels }))
+    }
+
+    async fn get_server_info(&self, _req: Request<Void>) -> Result<Response<GetServerInfoResponse>, Status> {
+        Ok(Response::new(GetServerInfoResponse {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            git_hash: env!("NVOS_GIT_HASH").to_owned(),
+            build_timestamp: env!("NVOS_BUILD_TIMESTAMP").parse().unwrap_or(0),
+            drivers: DriverRegistry::builtin().names(),
+            controllers: ControllerRegistry::builtin().names()
+        }))
+    }
+
+    async fn export_topology(&self, req: Request<Void>) -> Result<Response<ExportTopologyResponse>, Status> {
+        // not redacted so it can be imported again, which makes it as sensitive as a write
+        auth::require_write(&req)?;
+        let store = match &self.config_store {
+            Some(store) => store,
+            None => return Err(Status::unavailable("Topology export is not available"))
+        };
+
+        let topology = store.export_topology().to_str(true)
+            .map_err(|e| Status::internal(format!("Failed to serialize topology: {}", e)))?;
+
+        Ok(Response::new(ExportTopologyResponse { topology }))
+    }
+
+    async fn import_topology(&self, req: Request<ImportTopologyRequest>) -> Result<Response<ListDevicesResponse>, Status> {
+        auth::require_write(&req)?;
+        let (store, drivers, controllers, gpio) = match (&self.config_store, &self.drivers, &self.controllers, &self.gpio) {
+            (Some(store), Some(drivers), Some(controllers), Some(gpio)) => (store, drivers, controllers, gpio),
+            _ => return Err(Status::unavailable("Topology import is not available"))
+        };
+
+        let mut topology = Topology::from_str(&req.get_ref().topology)
+            .map_err(|e| Status::invalid_argument(format!("Invalid topology: {}", e)))?;
+        let _update = store.lock_updates().await;
+        let pins = store.config().gpio_section.clone();
+        validate_topology(&topology, &pins, controllers, drivers)
+            .map_err(|e| Status::invalid_argument(format!("Invalid topology: {}", e)))?;
+
+        // stopping and starting every device can take a while, so it runs on the blocking pool
+        let (server, store, drivers, controllers, gpio) = (self.server.clone(), store.clone(), drivers.clone(), controllers.clone(), gpio.clone());
+        let import = tokio::task::spawn_blocking(move || -> Result<Vec<Device>, Status> {
+            let mut server = server.write();
+            let mut previous = store.export_topology();
+            let entries = match load_topology(&mut server, &mut topology, &pins, &controllers, &drivers, &gpio) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Failed to import topology, restoring the previous one: {}", e);
+                    // whatever part of the previous topology still comes up is kept, like at startup
+                    return match restore_topology(&mut server, &mut previous, &pins, &controllers, &drivers, &gpio) {
+                        Ok(entries) => {
+                            if let Err(save_error) = store.set_topology(previous, &entries) {
+                                warn!("Failed to save the restored topology: {}", save_error);
+                            }
+
+                            Err(Status::aborted(format!("Failed to import topology, the previous one was restored: {}", e)))
+                        }
+                        Err(restore_error) => {
+                            error!("Failed to restore the previous topology: {}", restore_error);
+                            Err(Status::internal(format!("Failed to import topology and to restore the previous one: {}", e)))
+                        }
+                    };
+                }
+            };
+
+            store.set_topology(topology, &entries)
+                .map_err(|e| Status::internal(format!("Topology was imported but could not be saved: {}", e)))?;
+
+            Ok(server.snapshot_ordered(DeviceOrder::Registration).into_iter().map(map_device_to_rpc).collect())
+        });
+
+        let devices = import.await.map_err(|_| Status::internal("Topology import did not finish"))??;
+        Ok(Response::new(ListDevicesResponse { count: devices.len() as u32, devices }))
+    }
 }
\ No newline at end of file
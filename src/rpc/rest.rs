@@ -0,0 +1,264 @@
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tonic::{metadata::MetadataMap, service::Interceptor, Code, Extensions, Request, Status};
+use crate::config::ConfigSectionRPC;
+use crate::device::DeviceServer;
+
+use super::auth::{self, AuthTokens};
+use super::barometer::{barometer_server::Barometer, BarometerRequest, BarometerService};
+use super::led::{
+    led_controller_server::LedController, GetStateRequest, LEDControllerService, LedMode, SetBrightnessRequest,
+    SetModeRequest, SetPowerStateRequest,
+};
+use super::light_sensor::{light_sensor_server::LightSensor, LightSensorRequest, LightSensorService};
+use super::reflection::{
+    device_reflection_server::DeviceReflection, DeviceReflectionService, GetDeviceInfoRequest, ListDevicesRequest,
+};
+use super::thermometer::{thermometer_server::Thermometer, ThermometerRequest, ThermometerService};
+
+// Thin HTTP/JSON front for clients that can't speak gRPC, every route calls into the same services
+pub struct RestGateway {
+    auth: AuthTokens,
+    reflection: DeviceReflectionService,
+    led: LEDControllerService,
+    sensors: SensorServices,
+}
+
+// Sensor services shared with the gRPC server, so both fronts use the same read caches and intervals
+#[derive(Clone)]
+pub struct SensorServices {
+    pub thermometer: Arc<ThermometerService>,
+    pub barometer: Arc<BarometerService>,
+    pub light_sensor: Arc<LightSensorService>,
+}
+
+impl SensorServices {
+    pub fn from_config(server: &Arc<RwLock<DeviceServer>>, config: &ConfigSectionRPC) -> Self {
+        let command_timeout = Duration::from_millis(config.command_timeout_ms);
+        Self {
+            thermometer: Arc::new(ThermometerService::with_read_limits(server, &config.min_read_interval_ms)
+                .with_command_timeout(command_timeout)),
+            barometer: Arc::new(BarometerService::with_read_limits(server, &config.min_read_interval_ms)
+                .with_command_timeout(command_timeout)),
+            light_sensor: Arc::new(LightSensorService::with_read_limits(server, &config.min_read_interval_ms)
+                .with_command_timeout(command_timeout)),
+        }
+    }
+}
+
+pub struct RestError(Status);
+
+impl From<Status> for RestError {
+    fn from(status: Status) -> Self {
+        RestError(status)
+    }
+}
+
+fn map_code(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::OutOfRange => StatusCode::BAD_REQUEST,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::FailedPrecondition | Code::Aborted => StatusCode::CONFLICT,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl IntoResponse for RestError {
+    fn into_response(self) -> Response {
+        (map_code(self.0.code()), Json(json!({ "error": self.0.message() }))).into_response()
+    }
+}
+
+type RestResult = Result<Json<Value>, RestError>;
+type GatewayState = State<Arc<RestGateway>>;
+
+#[derive(Deserialize)]
+pub struct ListDevicesQuery {
+    // "name" sorts by device name, anything else keeps registration order
+    pub sort: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BrightnessBody {
+    pub brightness: f32,
+}
+
+#[derive(Deserialize)]
+pub struct PowerStateBody {
+    pub powered_on: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ModeBody {
+    pub mode: String,
+}
+
+impl RestGateway {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>, auth: AuthTokens, sensors: &SensorServices) -> Self {
+        Self {
+            auth,
+            reflection: DeviceReflectionService::new(server),
+            led: LEDControllerService::new(server),
+            sensors: sensors.clone(),
+        }
+    }
+
+    // The sensor services bring their own, this covers the LED routes
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.led = self.led.with_command_timeout(timeout);
+        self
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/devices", get(list_devices))
+            .route("/devices/:address", get(get_device_info))
+            .route("/devices/:address/led/state", get(get_led_state))
+            .route("/devices/:address/led/brightness", post(set_led_brightness))
+            .route("/devices/:address/led/power", post(set_led_power_state))
+            .route("/devices/:address/led/mode", post(set_led_mode))
+            .route("/devices/:address/thermometer/celsius", get(get_temperature_celsius))
+            .route("/devices/:address/barometer/pressure", get(get_pressure))
+            .route("/devices/:address/light_sensor/illuminance", get(get_illuminance))
+            .with_state(Arc::new(self))
+    }
+
+    // Stops taking connections once shutdown resolves and returns after the open requests are done
+    pub async fn serve(self, addr: SocketAddr, shutdown: impl Future<Output = ()>) -> Result<(), String> {
+        axum::Server::try_bind(&addr)
+            .map_err(|e| e.to_string())?
+            .serve(self.router().into_make_service())
+            .with_graceful_shutdown(shutdown)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // Runs the same token check as the gRPC interceptors, so handlers see the same grant
+    fn authorize<T>(&self, scope: &'static str, headers: &HeaderMap, message: T) -> Result<Request<T>, Status> {
+        let req = Request::from_parts(MetadataMap::from_headers(headers.clone()), Extensions::default(), ());
+        let (metadata, extensions, _) = self.auth.interceptor(scope).call(req)?.into_parts();
+        Ok(Request::from_parts(metadata, extensions, message))
+    }
+}
+
+async fn list_devices(State(gateway): GatewayState, Query(query): Query<ListDevicesQuery>, headers: HeaderMap) -> RestResult {
+    let sort_by_name = query.sort.as_deref() == Some("name");
+    let req = gateway.authorize(auth::REFLECTION_SCOPE, &headers, ListDevicesRequest { sort_by_name })?;
+    let devices = gateway.reflection.list_devices(req).await?.into_inner();
+    Ok(Json(Value::Array(devices.devices.iter().map(map_device).collect())))
+}
+
+async fn get_device_info(State(gateway): GatewayState, Path(address): Path<String>, headers: HeaderMap) -> RestResult {
+    let req = gateway.authorize(auth::REFLECTION_SCOPE, &headers, GetDeviceInfoRequest { address })?;
+    let device = gateway.reflection.get_device_info(req).await?.into_inner();
+    Ok(Json(map_device(&device)))
+}
+
+fn map_device(device: &super::reflection::Device) -> Value {
+    json!({
+        "address": device.address,
+        "device_name": device.device_name,
+        "driver_name": device.driver_name,
+        "is_running": device.is_running,
+        "is_healthy": device.is_healthy,
+        "tags": device.tags,
+        "capabilities": device.capabilities.iter()
+            .filter_map(|x| super::reflection::CapabilityId::try_from(*x).ok())
+            .map(|x| x.as_str_name())
+            .collect::<Vec<&str>>(),
+    })
+}
+
+async fn get_led_state(State(gateway): GatewayState, Path(address): Path<String>, headers: HeaderMap) -> RestResult {
+    let req = gateway.authorize(auth::LED_SCOPE, &headers, GetStateRequest { address })?;
+    let state = gateway.led.get_state(req).await?.into_inner();
+    Ok(Json(json!({
+        "powered_on": state.powered_on,
+        "brightness": state.brightness,
+        "effective_brightness": state.effective_brightness,
+        "mode": match LedMode::try_from(state.mode) {
+            Ok(LedMode::Ir) => "infrared".to_string(),
+            Ok(LedMode::Named) => state.mode_name,
+            _ => "visible".to_string(),
+        },
+    })))
+}
+
+async fn set_led_brightness(
+    State(gateway): GatewayState,
+    Path(address): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<BrightnessBody>,
+) -> RestResult {
+    let req = gateway.authorize(auth::LED_SCOPE, &headers, SetBrightnessRequest { address, brightness: body.brightness })?;
+    let res = gateway.led.set_brightness(req).await?;
+    Ok(Json(json!({ "brightness": res.get_ref().brightness })))
+}
+
+async fn set_led_power_state(
+    State(gateway): GatewayState,
+    Path(address): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<PowerStateBody>,
+) -> RestResult {
+    let req = gateway.authorize(auth::LED_SCOPE, &headers, SetPowerStateRequest { address, powered_on: body.powered_on })?;
+    gateway.led.set_power_state(req).await?;
+    Ok(Json(json!({})))
+}
+
+async fn set_led_mode(
+    State(gateway): GatewayState,
+    Path(address): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<ModeBody>,
+) -> RestResult {
+    // anything else is a named mode, the driver rejects names it doesn't have
+    let mode = match body.mode.to_lowercase().as_str() {
+        "" => return Err(Status::invalid_argument("LED mode is required").into()),
+        "visible" => LedMode::Vis,
+        "infrared" => LedMode::Ir,
+        _ => LedMode::Named,
+    };
+
+    let req = gateway.authorize(auth::LED_SCOPE, &headers, SetModeRequest { address, mode: mode as i32, mode_name: body.mode })?;
+    gateway.led.set_mode(req).await?;
+    Ok(Json(json!({})))
+}
+
+fn map_reading(value: f32, read_at_ms: u64, cached: bool) -> Value {
+    json!({ "value": value, "read_at_ms": read_at_ms, "cached": cached })
+}
+
+async fn get_temperature_celsius(State(gateway): GatewayState, Path(address): Path<String>, headers: HeaderMap) -> RestResult {
+    let req = gateway.authorize(auth::THERMOMETER_SCOPE, &headers, ThermometerRequest { address })?;
+    let reading = gateway.sensors.thermometer.get_temperature_celsius(req).await?.into_inner();
+    Ok(Json(map_reading(reading.value, reading.read_at_ms, reading.cached)))
+}
+
+async fn get_pressure(State(gateway): GatewayState, Path(address): Path<String>, headers: HeaderMap) -> RestResult {
+    let req = gateway.authorize(auth::BAROMETER_SCOPE, &headers, BarometerRequest { address })?;
+    let reading = gateway.sensors.barometer.get_pressure(req).await?.into_inner();
+    Ok(Json(map_reading(reading.value, reading.read_at_ms, reading.cached)))
+}
+
+async fn get_illuminance(State(gateway): GatewayState, Path(address): Path<String>, headers: HeaderMap) -> RestResult {
+    let req = gateway.authorize(auth::LIGHT_SENSOR_SCOPE, &headers, LightSensorRequest { address })?;
+    let reading = gateway.sensors.light_sensor.get_illuminance(req).await?.into_inner();
+    Ok(Json(map_reading(reading.value, reading.read_at_ms, reading.cached)))
+}
@@ -0,0 +1,164 @@
+use parking_lot::RwLock;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Status, Response, Request, Streaming};
+use crate::capabilities::SerialPortCapable;
+use crate::device::DeviceServer;
+use crate::events::DeviceEvents;
+use self::serial_port_server::SerialPort;
+
+use super::access_log;
+use super::auth;
+use super::errors;
+use super::timeout;
+use super::void::Void;
+
+tonic::include_proto!("serial");
+
+const DEFAULT_READ_LENGTH: usize = 256;
+const MAX_READ_LENGTH: usize = 4096;
+const EXCHANGE_BUFFER_SIZE: usize = 16;
+
+fn read_length(max_length: u32) -> Result<usize, Status> {
+    match max_length as usize {
+        0 => Ok(DEFAULT_READ_LENGTH),
+        len if len > MAX_READ_LENGTH => Err(Status::out_of_range(format!(
+            "Read length cannot exceed {} bytes",
+            MAX_READ_LENGTH
+        ))),
+        len => Ok(len),
+    }
+}
+
+pub struct SerialPortService {
+    server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
+}
+
+impl SerialPortService {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self {
+            server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
+        }
+    }
+
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    // A UART read can legitimately take as long as the client asked for, the command timeout only covers the time past that
+    fn read_deadline(&self, read_timeout: Duration) -> Duration {
+        match self.command_timeout.is_zero() {
+            true => Duration::ZERO,
+            false => read_timeout + self.command_timeout
+        }
+    }
+
+
+    // Writes the request payload (if any) and returns whatever the device sent back within the timeout
+    async fn exchange_once(&self, address: &str, req: ExchangeRequest) -> Result<ExchangeResponse, Status> {
+        let max_length = read_length(req.max_length)?;
+        let read_timeout = Duration::from_millis(req.timeout_ms as u64);
+        let device = super::capability_ptr::<dyn SerialPortCapable>(&self.server, address)?;
+        let address = device.address();
+
+        let exchange = timeout::run_with_timeout(self.read_deadline(read_timeout), move || {
+            let mut device = device.lock_mut()?;
+            if !req.data.is_empty() {
+                device.write_bytes(&req.data)?;
+            }
+
+            device.read_bytes(max_length, read_timeout)
+        });
+        let data = errors::track_device_result(&self.events, address, exchange.await)?;
+
+        Ok(ExchangeResponse { data })
+    }
+}
+
+#[tonic::async_trait]
+impl SerialPort for SerialPortService {
+    type ExchangeStream = ReceiverStream<Result<ExchangeResponse, Status>>;
+
+    async fn write(&self, req: Request<WriteRequest>) -> Result<Response<WriteResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let WriteRequest { address, data } = req.into_inner();
+        let written = super::call_with_timeout::<dyn SerialPortCapable, _, _>(
+            &self.server, &self.events, self.command_timeout, &address, move |device| device.write_bytes(&data)
+        ).await?;
+        Ok(Response::new(WriteResponse { written: written as u32 }))
+    }
+
+    async fn read(&self, req: Request<ReadRequest>) -> Result<Response<ReadResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let max_length = read_length(req.get_ref().max_length)?;
+        let read_timeout = Duration::from_millis(req.get_ref().timeout_ms as u64);
+
+        let device = super::capability_ptr::<dyn SerialPortCapable>(&self.server, &req.get_ref().address)?;
+        let address = device.address();
+        let read = timeout::run_with_timeout(self.read_deadline(read_timeout), move || device.lock_mut()?.read_bytes(max_length, read_timeout));
+        let data = errors::track_device_result(&self.events, address, read.await)?;
+
+        Ok(Response::new(ReadResponse { data }))
+    }
+
+    async fn read_line(&self, req: Request<ReadLineRequest>) -> Result<Response<ReadLineResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let read_timeout = Duration::from_millis(req.get_ref().timeout_ms as u64);
+
+        let device = super::capability_ptr::<dyn SerialPortCapable>(&self.server, &req.get_ref().address)?;
+        let address = device.address();
+        let read = timeout::run_with_timeout(self.read_deadline(read_timeout), move || device.lock_mut()?.read_line(read_timeout));
+        let line = errors::track_device_result(&self.events, address, read.await)?;
+
+        Ok(Response::new(ReadLineResponse { line }))
+    }
+
+    async fn flush(&self, req: Request<FlushRequest>) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        super::call_with_timeout::<dyn SerialPortCapable, _, _>(
+            &self.server, &self.events, self.command_timeout, &req.get_ref().address, |device| device.flush()
+        ).await?;
+        Ok(Response::new(Void::default()))
+    }
+
+    async fn exchange(&self, req: Request<Streaming<ExchangeRequest>>) -> Result<Response<Self::ExchangeStream>, Status> {
+        auth::require_write(&req)?;
+        let mut inbound = req.into_inner();
+        let (sender, receiver) = mpsc::channel(EXCHANGE_BUFFER_SIZE);
+        let service = SerialPortService::new(&self.server).with_command_timeout(self.command_timeout);
+
+        tokio::spawn(async move {
+            let mut address = String::new();
+            loop {
+                let message = match inbound.message().await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(status) => {
+                        let _ = sender.send(Err(status)).await;
+                        break;
+                    }
+                };
+
+                if !message.address.is_empty() {
+                    address = message.address.clone();
+                }
+
+                let result = service.exchange_once(&address, message).await;
+                let failed = result.is_err();
+                if sender.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
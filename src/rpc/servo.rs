@@ -0,0 +1,86 @@
+use self::servo_server::Servo;
+use crate::capabilities::ServoCapable;
+use crate::device::{DeviceError, DeviceServer};
+use crate::events::DeviceEvents;
+use parking_lot::RwLock;
+use std::{sync::Arc, time::Duration};
+use tonic::{Request, Response, Status};
+
+use super::access_log;
+use super::CapabilityRef;
+use super::auth;
+use super::errors;
+use super::timeout;
+use super::void::Void;
+
+tonic::include_proto!("servo");
+
+pub struct ServoService {
+    server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
+}
+
+impl ServoService {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self {
+            server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
+        }
+    }
+
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    fn get_device(
+        &self,
+        address: String,
+    ) -> Result<CapabilityRef<dyn ServoCapable>, Status> {
+        super::lock_capability::<dyn ServoCapable>(&self.server, &address)
+    }
+
+    // Moving the servo drives the hardware, so it runs under the command timeout like the sensor reads
+    async fn update<R, F>(&self, address: &str, change: F) -> Result<R, Status>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut (dyn ServoCapable + 'static)) -> Result<R, DeviceError> + Send + 'static,
+    {
+        super::call_with_timeout::<dyn ServoCapable, _, _>(&self.server, &self.events, self.command_timeout, address, change).await
+    }
+}
+
+#[tonic::async_trait]
+impl Servo for ServoService {
+    async fn get_angle(&self, req: Request<ServoRequest>) -> Result<Response<GetAngleResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let device = self.get_device(req.get_ref().address.to_owned())?;
+        let degrees = device.get_angle().map_err(errors::map_device_error)?;
+        Ok(Response::new(GetAngleResponse { degrees }))
+    }
+
+    async fn set_angle(&self, req: Request<SetAngleRequest>) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let degrees = req.get_ref().degrees;
+        self.update(&req.get_ref().address, move |device| device.set_angle(degrees)).await?;
+        Ok(Response::new(Void::default()))
+    }
+
+    async fn get_pulse_range(&self, req: Request<ServoRequest>) -> Result<Response<GetPulseRangeResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let device = self.get_device(req.get_ref().address.to_owned())?;
+        let (min_us, max_us) = device.get_pulse_range().map_err(errors::map_device_error)?;
+        Ok(Response::new(GetPulseRangeResponse { min_us, max_us }))
+    }
+
+    async fn set_pulse_range(&self, req: Request<SetPulseRangeRequest>) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let (min_us, max_us) = (req.get_ref().min_us, req.get_ref().max_us);
+        self.update(&req.get_ref().address, move |device| device.set_pulse_range(min_us, max_us)).await?;
+        Ok(Response::new(Void::default()))
+    }
+}
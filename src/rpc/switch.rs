@@ -0,0 +1,78 @@
+use self::switch_server::Switch;
+use crate::capabilities::SwitchCapable;
+use crate::device::{DeviceError, DeviceServer};
+use crate::events::DeviceEvents;
+use parking_lot::RwLock;
+use std::{sync::Arc, time::Duration};
+use tonic::{Request, Response, Status};
+
+use super::access_log;
+use super::CapabilityRef;
+use super::auth;
+use super::errors;
+use super::timeout;
+use super::void::Void;
+
+tonic::include_proto!("switch");
+
+pub struct SwitchService {
+    server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
+}
+
+impl SwitchService {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self {
+            server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
+        }
+    }
+
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    fn get_device(
+        &self,
+        address: String,
+    ) -> Result<CapabilityRef<dyn SwitchCapable>, Status> {
+        super::lock_capability::<dyn SwitchCapable>(&self.server, &address)
+    }
+
+    // Switching drives the hardware, so it runs under the command timeout like the sensor reads
+    async fn update<R, F>(&self, address: &str, change: F) -> Result<R, Status>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut (dyn SwitchCapable + 'static)) -> Result<R, DeviceError> + Send + 'static,
+    {
+        super::call_with_timeout::<dyn SwitchCapable, _, _>(&self.server, &self.events, self.command_timeout, address, change).await
+    }
+}
+
+#[tonic::async_trait]
+impl Switch for SwitchService {
+    async fn get_state(&self, req: Request<SwitchRequest>) -> Result<Response<GetStateResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        let device = self.get_device(req.get_ref().address.to_owned())?;
+        let on = device.get_state().map_err(errors::map_device_error)?;
+        Ok(Response::new(GetStateResponse { on }))
+    }
+
+    async fn set_state(&self, req: Request<SetStateRequest>) -> Result<Response<Void>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let on = req.get_ref().on;
+        self.update(&req.get_ref().address, move |device| device.set_state(on)).await?;
+        Ok(Response::new(Void::default()))
+    }
+
+    async fn toggle(&self, req: Request<SwitchRequest>) -> Result<Response<ToggleResponse>, Status> {
+        access_log::record_device(&req.get_ref().address);
+        auth::require_write(&req)?;
+        let on = self.update(&req.get_ref().address, |device| device.toggle()).await?;
+        Ok(Response::new(ToggleResponse { on }))
+    }
+}
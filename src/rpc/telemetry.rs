@@ -0,0 +1,209 @@
+use parking_lot::RwLock;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Status, Response, Request};
+use crate::capabilities::{
+    BarometerCapable, Capability, CapabilityId, GpsCapable, LEDControllerCapable, LightSensorCapable,
+    ThermometerCapable,
+};
+use crate::device::{DeviceError, DeviceServer};
+use crate::events::DeviceEvents;
+use self::telemetry_server::Telemetry;
+use self::read_result::Value;
+
+use super::errors;
+use super::barometer::BarometerService;
+use super::light_sensor::LightSensorService;
+use super::rate_limit::{self, CachedRead};
+use super::rest::SensorServices;
+use super::thermometer::ThermometerService;
+use super::timeout;
+use super::reflection::{map_capability_from_rpc, CapabilityId as RpcCapabilityId};
+
+tonic::include_proto!("telemetry");
+
+const MAX_BATCH_SIZE: usize = 64;
+
+fn unknown_method(capability: CapabilityId, method: &str) -> Status {
+    Status::unimplemented(format!("Method {} is not available for batch reads on {:?}", method, capability))
+}
+
+type Reader<T> = fn(&mut T) -> Result<Value, DeviceError>;
+
+fn led_reader(method: &str) -> Option<Reader<dyn LEDControllerCapable>> {
+    let reader: Reader<dyn LEDControllerCapable> = match method {
+        "get_brightness" => |led| Ok(Value::Number(led.get_brightness()? as f64)),
+        "get_effective_brightness" => |led| Ok(Value::Number(led.get_effective_brightness()? as f64)),
+        "get_power_state" => |led| Ok(Value::Flag(led.get_power_state()?)),
+        "get_mode" => |led| Ok(Value::Text(led.get_mode()?.name().to_owned())),
+        _ => return None
+    };
+
+    Some(reader)
+}
+
+fn gps_reader(method: &str) -> Option<Reader<dyn GpsCapable>> {
+    let reader: Reader<dyn GpsCapable> = match method {
+        "has_fix" => |gps| Ok(Value::Flag(gps.has_fix()?)),
+        "get_altitude" => |gps| Ok(Value::Number(gps.get_altitude()? as f64)),
+        "get_speed" => |gps| Ok(Value::Number(gps.get_speed()? as f64)),
+        "get_heading" => |gps| Ok(Value::Number(gps.get_heading()? as f64)),
+        "get_vertical_accuracy" => |gps| Ok(Value::Number(gps.get_vertical_accuracy()? as f64)),
+        "get_horizontal_accuracy" => |gps| Ok(Value::Number(gps.get_horizontal_accuracy()? as f64)),
+        _ => return None
+    };
+
+    Some(reader)
+}
+
+fn light_sensor_reader(method: &str) -> Option<Reader<dyn LightSensorCapable>> {
+    let reader: Reader<dyn LightSensorCapable> = match method {
+        "get_gain" => |sensor| Ok(Value::Number(sensor.get_gain(CapabilityId::LightSensor)? as f64)),
+        "get_interval" => |sensor| Ok(Value::Number(sensor.get_interval()? as f64)),
+        "get_auto_gain_enabled" => |sensor| Ok(Value::Flag(sensor.get_auto_gain_enabled()?)),
+        _ => return None
+    };
+
+    Some(reader)
+}
+
+fn thermometer_reader(method: &str) -> Option<Reader<dyn ThermometerCapable>> {
+    let reader: Reader<dyn ThermometerCapable> = match method {
+        "get_gain" => |thermometer| Ok(Value::Number(thermometer.get_gain(CapabilityId::Thermometer)? as f64)),
+        "get_interval" => |thermometer| Ok(Value::Number(thermometer.get_interval()? as f64)),
+        _ => return None
+    };
+
+    Some(reader)
+}
+
+fn barometer_reader(method: &str) -> Option<Reader<dyn BarometerCapable>> {
+    let reader: Reader<dyn BarometerCapable> = match method {
+        "get_gain" => |barometer| Ok(Value::Number(barometer.get_gain(CapabilityId::Barometer)? as f64)),
+        "get_interval" => |barometer| Ok(Value::Number(barometer.get_interval()? as f64)),
+        _ => return None
+    };
+
+    Some(reader)
+}
+
+pub struct TelemetryService {
+    server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
+    // rate limited reads go through the sensor services so both share one cache and interval
+    thermometer: Arc<ThermometerService>,
+    barometer: Arc<BarometerService>,
+    light_sensor: Arc<LightSensorService>,
+}
+
+impl TelemetryService {
+    pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self {
+            server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
+            thermometer: Arc::new(ThermometerService::new(server)),
+            barometer: Arc::new(BarometerService::new(server)),
+            light_sensor: Arc::new(LightSensorService::new(server)),
+        }
+    }
+
+    pub fn with_sensor_services(mut self, sensors: &SensorServices) -> Self {
+        self.thermometer = sensors.thermometer.clone();
+        self.barometer = sensors.barometer.clone();
+        self.light_sensor = sensors.light_sensor.clone();
+        self
+    }
+
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    async fn read_one(&self, request: &ReadRequest) -> Result<Value, Status> {
+        let capability = match RpcCapabilityId::try_from(request.capability) {
+            Ok(capability) => map_capability_from_rpc(capability),
+            Err(_) => return Err(Status::invalid_argument("Unknown capability"))
+        };
+
+        let address = request.address.as_str();
+        let method = request.method.as_str();
+        if let Some(read) = self.read_cached(address, capability, method).await {
+            return read.map(|x| Value::Number(x.value as f64));
+        }
+
+        match capability {
+            CapabilityId::LEDController => self.read(address, capability, method, led_reader(method)).await,
+            CapabilityId::GPS => self.read(address, capability, method, gps_reader(method)).await,
+            CapabilityId::LightSensor => self.read(address, capability, method, light_sensor_reader(method)).await,
+            CapabilityId::Thermometer => self.read(address, capability, method, thermometer_reader(method)).await,
+            CapabilityId::Barometer => self.read(address, capability, method, barometer_reader(method)).await,
+            _ => Err(Status::unimplemented(format!("{:?} does not support batch reads", capability)))
+        }
+    }
+
+    async fn read_cached(&self, address: &str, capability: CapabilityId, method: &str) -> Option<Result<CachedRead<f32>, Status>> {
+        let read = match (capability, method) {
+            (CapabilityId::LightSensor, rate_limit::GET_ILLUMINANCE) => self.light_sensor.read_illuminance(address).await,
+            (CapabilityId::Thermometer, rate_limit::GET_TEMPERATURE_CELSIUS) => self.thermometer.read_celsius(address).await,
+            (CapabilityId::Thermometer, rate_limit::GET_TEMPERATURE_FAHRENHEIT) => self.thermometer.read_fahrenheit(address).await,
+            (CapabilityId::Barometer, rate_limit::GET_PRESSURE) => self.barometer.read_pressure(address).await,
+            (CapabilityId::Barometer, rate_limit::GET_ALTITUDE) => self.barometer.read_altitude(address).await,
+            _ => return None
+        };
+
+        Some(read)
+    }
+
+    // Same path as the single capability services: supervised lock, command timeout and error tracking.
+    // Only the target device is locked, and only for a single item so other clients can interleave with large batches
+    async fn read<T: Capability + ?Sized + 'static>(
+        &self,
+        address: &str,
+        capability: CapabilityId,
+        method: &str,
+        reader: Option<Reader<T>>,
+    ) -> Result<Value, Status> {
+        let reader = reader.ok_or_else(|| unknown_method(capability, method))?;
+        let device = super::capability_ptr::<T>(&self.server, address)?;
+        let address = device.address();
+        let read = timeout::run_with_timeout(self.command_timeout, move || reader(&mut *device.lock_mut()?));
+        errors::track_device_result(&self.events, address, read.await)
+    }
+}
+
+#[tonic::async_trait]
+impl Telemetry for TelemetryService {
+    async fn batch_read(&self, req: Request<BatchReadRequest>) -> Result<Response<BatchReadResponse>, Status> {
+        let requests = &req.get_ref().requests;
+        if requests.len() > MAX_BATCH_SIZE {
+            return Err(Status::out_of_range(format!("Batch reads are limited to {} items", MAX_BATCH_SIZE)));
+        }
+
+        // items are read one after the other, the batch never holds more than one device at a time
+        let mut results = Vec::with_capacity(requests.len());
+        for request in requests {
+            let mut result = ReadResult {
+                address: request.address.clone(),
+                method: request.method.clone(),
+                ..Default::default()
+            };
+
+            match self.read_one(request).await {
+                Ok(value) => {
+                    result.success = true;
+                    result.value = Some(value);
+                },
+                Err(status) => {
+                    result.error = status.message().to_string();
+                    result.error_code = status.code() as i32;
+                }
+            }
+
+            results.push(result);
+        }
+
+        Ok(Response::new(BatchReadResponse { results }))
+    }
+}
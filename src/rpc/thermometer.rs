@@ -1,102 +1,112 @@
-use parking_lot::{RwLock, RwLockReadGuard, MappedRwLockReadGuard, RwLockWriteGuard, MappedRwLockWriteGuard};
-use std::sync::Arc;
+use parking_lot::RwLock;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use tonic::{Status, Response, Request};
 use uuid::Uuid;
-use crate::capabilities::ThermometerCapable;
+use crate::capabilities::{CapabilityId, ReadingCalibration, ThermometerCapable};
 use crate::device::DeviceServer;
+use crate::events::DeviceEvents;
 use self::thermometer_server::Thermometer;
 
+use super::access_log;
+use super::{CapabilityMut, CapabilityRef};
+use super::auth;
+use super::rate_limit::{self, CachedRead, ReadCache};
+use super::timeout;
 use super::errors;
+use super::calibration::{GetCalibrationResponse, SetCalibrationRequest};
 use super::void::Void;
 
 tonic::include_proto!("thermometer");
 
 pub struct ThermometerService {
     server: Arc<RwLock<DeviceServer>>,
+    events: DeviceEvents,
+    command_timeout: Duration,
+    celsius_cache: ReadCache<Uuid, f32>,
+    fahrenheit_cache: ReadCache<Uuid, f32>,
 }
 
 impl ThermometerService {
     pub fn new(server: &Arc<RwLock<DeviceServer>>) -> Self {
+        Self::with_read_limits(server, &HashMap::new())
+    }
+
+    pub fn with_read_limits(server: &Arc<RwLock<DeviceServer>>, limits: &HashMap<String, u64>) -> Self {
         Self {
             server: server.clone(),
+            events: server.read().events().clone(),
+            command_timeout: Duration::from_millis(timeout::DEFAULT_COMMAND_TIMEOUT_MS),
+            celsius_cache: ReadCache::from_limits(limits, rate_limit::GET_TEMPERATURE_CELSIUS),
+            fahrenheit_cache: ReadCache::from_limits(limits, rate_limit::GET_TEMPERATURE_FAHRENHEIT),
         }
     }
 
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = timeout;
+        self
+    }
+
+    // Cached temperature reads under the command timeout, batch telemetry reads through these too
+    pub async fn read_celsius(&self, reference: &str) -> Result<CachedRead<f32>, Status> {
+        let address = super::resolve_address(&self.server.read(), reference)?;
+        let device = super::capability_ptr::<dyn ThermometerCapable>(&self.server, reference)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_temperature_celsius());
+        self.celsius_cache.get_or_read_async(address, async {
+            errors::track_device_result(&self.events, address, read.await)
+        }).await
+    }
+
+    pub async fn read_fahrenheit(&self, reference: &str) -> Result<CachedRead<f32>, Status> {
+        let address = super::resolve_address(&self.server.read(), reference)?;
+        let device = super::capability_ptr::<dyn ThermometerCapable>(&self.server, reference)?;
+        let read = timeout::run_with_timeout(self.command_timeout, move || device.lock_mut()?.get_temperature_fahrenheit());
+        self.fahrenheit_cache.get_or_read_async(address, async {
+            errors::track_device_result(&self.events, address, read.await)
+        }).await
+    }
+
     fn get_device(
         &self,
         address: String,
-    ) -> Result<MappedRwLockReadGuard<'_, dyn ThermometerCapable>, Status> {
-        let guard = self.server.read();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn ThermometerCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
-
-        Ok(RwLockReadGuard::map(guard, |x| {
-            x.get_device(&address)
-                .unwrap()
-                .as_capability_ref::<dyn ThermometerCapable>()
-                .unwrap()
-        }))
+    ) -> Result<CapabilityRef<dyn ThermometerCapable>, Status> {
+        super::lock_capability::<dyn ThermometerCapable>(&self.server, &address)
     }
 
     fn get_device_mut(
         &self,
         address: String,
-    ) -> Result<MappedRwLockWriteGuard<'_, dyn ThermometerCapable>, Status> {
-        let guard = self.server.write();
-        let address = match Uuid::parse_str(&address) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Err(Status::invalid_argument(format!(
-                    "Failed to parse device address: {}",
-                    e
-                )))
-            }
-        };
-
-        let device = match guard.get_device(&address) {
-            Some(device) => device,
-            None => return Err(Status::not_found("Device does not exist")),
-        };
-
-        if !device.has_capability::<dyn ThermometerCapable>() {
-            return Err(Status::invalid_argument(
-                "This device does not support this capability",
-            ));
-        }
+    ) -> Result<CapabilityMut<dyn ThermometerCapable>, Status> {
+        super::lock_capability_mut::<dyn ThermometerCapable>(&self.server, &address)
+    }
 
-        Ok(RwLockWriteGuard::map(guard, |x| {
-            x.get_device_mut(&address)
-                .unwrap()
-                .as_capability_mut::<dyn ThermometerCapable>()
-                .unwrap()
-        }))
+    // Cached temperatures no longer match a device whose gain, interval or calibration changed
+    fn invalidate_reads(&self, address: &Uuid) {
+        self.celsius_cache.invalidate(address);
+        self.fahrenheit_cache.invalidate(address);
     }
 }
 
 #[tonic::async_trait]
 impl Thermometer for ThermometerService {
+    async fn get_units(
+        &self,
+        request: Request<ThermometerRequest>,
+    ) -> Result<Response<GetUnitsResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let device = self.get_device(request.get_ref().address.to_owned())?;
+        let units = device.get_units()
+            .into_iter()
+            .map(|x| ReadingUnit { reading: x.reading, unit: x.unit, min: x.min, max: x.max })
+            .collect();
+
+        Ok(Response::new(GetUnitsResponse { units }))
+    }
+
     async fn get_supported_gains(
         &self,
         request: Request<ThermometerRequest>,
     ) -> Result<Response<GetSupportedGainsResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
         let device = self.get_device(request.get_ref().address.to_owned())?;
         let gains = device.get_supported_gains();
 
@@ -114,6 +124,7 @@ impl Thermometer for ThermometerService {
         &self,
         request: Request<ThermometerRequest>,
     ) -> Result<Response<GetSupportedIntervalsResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
         let device = self.get_device(request.get_ref().address.to_owned())?;
         let intervals = device.get_supported_intervals();
 
@@ -131,8 +142,9 @@ impl Thermometer for ThermometerService {
         &self,
         request: Request<ThermometerRequest>,
     ) -> Result<Response<GetGainResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
         let device = self.get_device(request.get_ref().address.to_owned())?;
-        let gain_multiplier = device.get_gain().map_err(errors::map_device_error)?;
+        let gain_multiplier = device.get_gain(CapabilityId::Thermometer).map_err(errors::map_device_error)?;
         Ok(Response::new(GetGainResponse { gain_multiplier: gain_multiplier as u32 }))
     }
 
@@ -140,8 +152,12 @@ impl Thermometer for ThermometerService {
         &self,
         request: Request<SetGainRequest>,
     ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
         let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
-        device.set_gain(request.get_ref().gain_id as u8).map_err(errors::map_device_error)?;
+        device.set_gain(CapabilityId::Thermometer, request.get_ref().gain_id as u8).map_err(errors::map_device_error)?;
+        self.invalidate_reads(&address);
         Ok(Response::new(Void::default()))
     }
 
@@ -149,6 +165,7 @@ impl Thermometer for ThermometerService {
         &self,
         request: Request<ThermometerRequest>,
     ) -> Result<Response<GetIntervalResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
         let device = self.get_device(request.get_ref().address.to_owned())?;
         let sleep_interval_ms = device.get_interval().map_err(errors::map_device_error)?;
         Ok(Response::new(GetIntervalResponse { sleep_interval_ms: sleep_interval_ms as u32 }))
@@ -158,8 +175,12 @@ impl Thermometer for ThermometerService {
         &self,
         request: Request<SetIntervalRequest>,
     ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
         let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
         device.set_interval(request.get_ref().interval_id as u8).map_err(errors::map_device_error)?;
+        self.invalidate_reads(&address);
         Ok(Response::new(Void::default()))
     }
 
@@ -167,17 +188,53 @@ impl Thermometer for ThermometerService {
         &self,
         request: Request<ThermometerRequest>,
     ) -> Result<Response<GetTemperatureResponse>, Status> {
-        let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
-        let temperature = device.get_temperature_celsius().map_err(errors::map_device_error)?;
-        Ok(Response::new(GetTemperatureResponse { value: temperature }))
+        access_log::record_device(&request.get_ref().address);
+        let temperature = self.read_celsius(&request.get_ref().address).await?;
+
+        Ok(Response::new(GetTemperatureResponse {
+            value: temperature.value,
+            read_at_ms: temperature.read_at_ms(),
+            cached: temperature.cached,
+        }))
     }
 
     async fn get_temperature_fahrenheit(
         &self,
         request: Request<ThermometerRequest>,
     ) -> Result<Response<GetTemperatureResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let temperature = self.read_fahrenheit(&request.get_ref().address).await?;
+
+        Ok(Response::new(GetTemperatureResponse {
+            value: temperature.value,
+            read_at_ms: temperature.read_at_ms(),
+            cached: temperature.cached,
+        }))
+    }
+
+    async fn get_calibration(
+        &self,
+        request: Request<ThermometerRequest>,
+    ) -> Result<Response<GetCalibrationResponse>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        let device = self.get_device(request.get_ref().address.to_owned())?;
+        let calibration = device.get_calibration(CapabilityId::Thermometer).map_err(errors::map_device_error)?;
+        Ok(Response::new(GetCalibrationResponse { scale: calibration.scale, offset: calibration.offset }))
+    }
+
+    async fn set_calibration(
+        &self,
+        request: Request<SetCalibrationRequest>,
+    ) -> Result<Response<Void>, Status> {
+        access_log::record_device(&request.get_ref().address);
+        auth::require_write(&request)?;
+        let calibration = ReadingCalibration::new(request.get_ref().scale, request.get_ref().offset)
+            .map_err(Status::out_of_range)?;
+        let address = super::resolve_address(&self.server.read(), &request.get_ref().address)?;
         let mut device = self.get_device_mut(request.get_ref().address.to_owned())?;
-        let temperature = device.get_temperature_fahrenheit().map_err(errors::map_device_error)?;
-        Ok(Response::new(GetTemperatureResponse { value: temperature }))
+        device.set_calibration(CapabilityId::Thermometer, calibration).map_err(errors::map_device_error)?;
+
+        self.invalidate_reads(&address);
+        Ok(Response::new(Void::default()))
     }
 }
\ No newline at end of file
@@ -0,0 +1,36 @@
+use std::time::{Duration, Instant};
+use log::warn;
+use crate::device::DeviceError;
+
+pub const DEFAULT_COMMAND_TIMEOUT_MS: u64 = 2000;
+
+// Runs a blocking device call on the blocking thread pool so a stuck bus transaction can't hold up the handler.
+// The call itself can't be cancelled: once the deadline passes it keeps running, and keeps the device locked,
+// until the hardware returns. Calls that lock the device through the supervisor give up after the device lock
+// timeout in the meantime instead of piling up behind it, and the late result is logged once it comes in.
+pub async fn run_with_timeout<T, F>(timeout: Duration, work: F) -> Result<T, DeviceError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, DeviceError> + Send + 'static,
+{
+    let mut task = tokio::task::spawn_blocking(work);
+    if timeout.is_zero() {
+        return task.await.map_err(|_| DeviceError::Internal)?;
+    }
+
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, &mut task).await {
+        Ok(result) => result.map_err(|_| DeviceError::Internal)?,
+        Err(_) => {
+            tokio::spawn(async move {
+                match task.await {
+                    Ok(Ok(_)) => warn!("Timed out device call finished after {:?}, the device is usable again", started.elapsed()),
+                    Ok(Err(e)) => warn!("Timed out device call failed after {:?}: {}", started.elapsed(), e),
+                    Err(e) => warn!("Timed out device call did not complete: {}", e),
+                }
+            });
+
+            Err(DeviceError::hardware_error("timeout"))
+        }
+    }
+}
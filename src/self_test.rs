@@ -0,0 +1,156 @@
+use crate::{
+    capabilities::{BarometerCapable, CapabilityId, DiagnosticsCapable, GpsCapable, LightSensorCapable, ThermometerCapable},
+    device::{Device, DeviceError, DeviceServer},
+};
+use log::{info, warn};
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestResult {
+    pub device_name: String,
+    pub driver_name: String,
+    // what was read, e.g. "temperature"
+    pub check: &'static str,
+    pub passed: bool,
+    // the reading when it passed, the error when it didn't
+    pub detail: String,
+}
+
+impl Display for SelfTestResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "{} \"{}\" (driver: {}) {}: {}",
+            match self.passed { true => "PASS", false => "FAIL" },
+            self.device_name, self.driver_name, self.check, self.detail
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SelfTestReport {
+    pub results: Vec<SelfTestResult>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|x| x.passed).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|x| !x.passed).count()
+    }
+
+    pub fn log(&self) {
+        if self.results.is_empty() {
+            info!("Startup self-test: no sensors to test");
+            return;
+        }
+
+        info!("Startup self-test: {} passed, {} failed", self.passed(), self.failed());
+        for result in &self.results {
+            match result.passed {
+                true => info!("  {}", result),
+                false => warn!("  {}", result),
+            }
+        }
+    }
+}
+
+// Reads every sensor once, failures are only reported so a broken sensor never stops the server from coming up.
+// Devices without a sensor capability are left out.
+pub fn run_self_test(server: &DeviceServer) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    for address in server.get_device_addresses() {
+        let device = match server.get_device_ptr(&address) {
+            Some(d) => d,
+            None => continue,
+        };
+
+        let mut device = match device.try_write_for(server.device_lock_timeout()) {
+            Some(d) => d,
+            None => {
+                report.results.push(SelfTestResult {
+                    device_name: address.to_string(),
+                    driver_name: "unknown".to_string(),
+                    check: "lock",
+                    passed: false,
+                    detail: DeviceError::LockTimeout(server.describe_device(&address)).to_string(),
+                });
+                continue;
+            }
+        };
+
+        let results = test_device(&mut device);
+        if let Some(diagnostics) = results.iter().find(|x| x.check == "diagnostics") {
+            server.events().record_self_test(address, diagnostics.passed);
+        }
+
+        report.results.extend(results);
+    }
+
+    report
+}
+
+const SENSOR_CAPABILITIES: [CapabilityId; 5] = [
+    CapabilityId::Diagnostics,
+    CapabilityId::Thermometer,
+    CapabilityId::LightSensor,
+    CapabilityId::Barometer,
+    CapabilityId::GPS,
+];
+
+pub fn test_device(device: &mut Device) -> Vec<SelfTestResult> {
+    if !device.get_capabilities().iter().any(|x| SENSOR_CAPABILITIES.contains(x)) {
+        return Vec::new();
+    }
+
+    let checks = match device.is_running() {
+        true => read_sensors(device),
+        // a stopped sensor has nothing to read
+        false => vec![("running", Err(DeviceError::InvalidOperation("device is not running".to_string())))],
+    };
+
+    checks.into_iter().map(|(check, result)| SelfTestResult {
+        device_name: device.device_name(),
+        driver_name: device.driver_name(),
+        check,
+        passed: result.is_ok(),
+        detail: match result {
+            Ok(detail) => detail,
+            Err(e) => e.to_string(),
+        },
+    }).collect()
+}
+
+fn read_sensors(device: &mut Device) -> Vec<(&'static str, Result<String, DeviceError>)> {
+    let mut checks = Vec::new();
+    if let Some(diagnostics) = device.as_capability_mut::<dyn DiagnosticsCapable>() {
+        checks.push(("diagnostics", diagnostics.self_test().and_then(|report| match report.healthy {
+            true => Ok(format!("healthy, up for {:?}", report.uptime)),
+            false => Err(DeviceError::hardware_error(
+                report.last_error.unwrap_or_else(|| "chip ID could not be verified".to_string()),
+            )),
+        })));
+    }
+
+    if let Some(thermometer) = device.as_capability_mut::<dyn ThermometerCapable>() {
+        checks.push(("temperature", thermometer.get_temperature_celsius().map(|x| format!("{:.1} °C", x))));
+    }
+
+    if let Some(light_sensor) = device.as_capability_mut::<dyn LightSensorCapable>() {
+        checks.push(("illuminance", light_sensor.get_illuminance().map(|x| format!("{:.1} lx", x))));
+    }
+
+    if let Some(barometer) = device.as_capability_mut::<dyn BarometerCapable>() {
+        checks.push(("pressure", barometer.get_pressure().map(|x| format!("{:.1} hPa", x))));
+    }
+
+    if let Some(gps) = device.as_capability_ref::<dyn GpsCapable>() {
+        checks.push(("fix status", gps.has_fix().map(|x| match x {
+            true => "fix".to_string(),
+            false => "no fix yet".to_string(),
+        })));
+    }
+
+    checks
+}
@@ -0,0 +1,101 @@
+use crate::{adb::AdbServer, device::DeviceServer};
+use log::{info, warn};
+use parking_lot::RwLock;
+use std::{
+    future::Future,
+    io::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+};
+
+// Graceful shutdown path shared by every signal the server listens for
+pub struct ShutdownHook {
+    device_server: Arc<RwLock<DeviceServer>>,
+    // None when ADB is turned off
+    adb_server: Option<Arc<RwLock<AdbServer>>>,
+    rpc_shutdown: mpsc::Sender<()>,
+    // Other servers like the REST gateway wait on this, see stopped()
+    stopped: watch::Sender<bool>,
+    triggered: AtomicBool,
+}
+
+impl ShutdownHook {
+    pub fn new(
+        device_server: &Arc<RwLock<DeviceServer>>,
+        adb_server: Option<&Arc<RwLock<AdbServer>>>,
+        rpc_shutdown: mpsc::Sender<()>,
+    ) -> Self {
+        Self {
+            device_server: device_server.clone(),
+            adb_server: adb_server.cloned(),
+            rpc_shutdown,
+            stopped: watch::channel(false).0,
+            triggered: AtomicBool::new(false),
+        }
+    }
+
+    // Resolves once a shutdown has started, or when the hook is dropped
+    pub fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut stopped = self.stopped.subscribe();
+        async move {
+            loop {
+                if *stopped.borrow_and_update() {
+                    return;
+                }
+
+                if stopped.changed().await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    // Returns false if a shutdown has already been performed
+    pub async fn shutdown(&self) -> bool {
+        if self.triggered.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        // stopping devices and ADB blocks, that stays off the async workers
+        let device_server = self.device_server.clone();
+        let adb_server = self.adb_server.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            info!("Shutting down device server");
+            let stopped = device_server.write().shutdown();
+            info!("Stopped {} device(s) and released all bus controllers", stopped);
+
+            if let Some(adb_server) = adb_server {
+                info!("Shutting down ADB server");
+                adb_server.read().shutdown();
+            }
+        }).await;
+
+        if let Err(e) = result {
+            warn!("Failed to shut down devices: {}", e);
+        }
+
+        info!("Gracefully shutting down RPC server");
+        self.stopped.send_replace(true);
+        let _ = self.rpc_shutdown.send(()).await;
+        true
+    }
+}
+
+// Resolves with the name of the first termination signal received (SIGINT or SIGTERM)
+pub async fn wait_for_signal() -> Result<&'static str, Error> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+        _ = sigterm.recv() => Ok("SIGTERM"),
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::warn;
+use parking_lot::{RawRwLock, RwLock};
+use parking_lot::lock_api::{ArcRwLockReadGuard, ArcRwLockWriteGuard};
+use crate::device::DeviceError;
+
+pub const DEFAULT_DEVICE_LOCK_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LockWaitStats {
+    pub acquisitions: u64,
+    pub timeouts: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration
+}
+
+impl LockWaitStats {
+    pub fn average_wait(&self) -> Duration {
+        match self.acquisitions {
+            0 => Duration::ZERO,
+            n => self.total_wait / n as u32
+        }
+    }
+}
+
+// Wait times of every supervised lock acquisition, shared by all devices on a server
+#[derive(Debug, Default)]
+pub struct LockMetrics {
+    acquisitions: AtomicU64,
+    timeouts: AtomicU64,
+    total_wait_us: AtomicU64,
+    max_wait_us: AtomicU64
+}
+
+impl LockMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, waited: Duration, acquired: bool) {
+        let waited_us = waited.as_micros() as u64;
+        match acquired {
+            true => self.acquisitions.fetch_add(1, Ordering::Relaxed),
+            false => self.timeouts.fetch_add(1, Ordering::Relaxed)
+        };
+
+        self.total_wait_us.fetch_add(waited_us, Ordering::Relaxed);
+        self.max_wait_us.fetch_max(waited_us, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LockWaitStats {
+        LockWaitStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            total_wait: Duration::from_micros(self.total_wait_us.load(Ordering::Relaxed)),
+            max_wait: Duration::from_micros(self.max_wait_us.load(Ordering::Relaxed))
+        }
+    }
+}
+
+fn timed_out(name: &str, timeout: Duration) -> DeviceError {
+    warn!("Timed out after {:?} waiting for {}, a guard may have been leaked or a call is stuck", timeout, name);
+    DeviceError::LockTimeout(name.to_owned())
+}
+
+// Like read_arc, but gives up after the timeout instead of waiting on a guard that may never be dropped
+pub fn read_supervised<T: ?Sized>(
+    lock: &Arc<RwLock<T>>,
+    name: &str,
+    timeout: Duration,
+    metrics: &LockMetrics
+) -> Result<ArcRwLockReadGuard<RawRwLock, T>, DeviceError> {
+    let started = Instant::now();
+    let guard = lock.try_read_arc_for(timeout);
+    metrics.record(started.elapsed(), guard.is_some());
+    guard.ok_or_else(|| timed_out(name, timeout))
+}
+
+pub fn write_supervised<T: ?Sized>(
+    lock: &Arc<RwLock<T>>,
+    name: &str,
+    timeout: Duration,
+    metrics: &LockMetrics
+) -> Result<ArcRwLockWriteGuard<RawRwLock, T>, DeviceError> {
+    let started = Instant::now();
+    let guard = lock.try_write_arc_for(timeout);
+    metrics.record(started.elapsed(), guard.is_some());
+    guard.ok_or_else(|| timed_out(name, timeout))
+}
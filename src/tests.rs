@@ -1,4 +1,54 @@
 #[cfg(test)]
 pub mod gpio_tests;
 #[cfg(test)]
-pub mod device_tests;
\ No newline at end of file
+pub mod device_tests;
+#[cfg(test)]
+pub mod i2c_tests;
+#[cfg(test)]
+pub mod rpc_tests;
+#[cfg(test)]
+pub mod tsl2591_tests;
+#[cfg(test)]
+pub mod serial_tests;
+#[cfg(test)]
+pub mod config_tests;
+#[cfg(test)]
+pub mod shutdown_tests;
+#[cfg(test)]
+pub mod pwm_tests;
+#[cfg(test)]
+pub mod auth_tests;
+#[cfg(test)]
+pub mod rate_limit_tests;
+#[cfg(test)]
+pub mod bmp280_tests;
+#[cfg(test)]
+pub mod filter_tests;
+#[cfg(test)]
+pub mod uart_tests;
+#[cfg(test)]
+pub mod gps_tests;
+#[cfg(test)]
+pub mod events_tests;
+#[cfg(test)]
+pub mod ina219_tests;
+#[cfg(test)]
+pub mod servo_tests;
+#[cfg(test)]
+pub mod timeout_tests;
+#[cfg(test)]
+pub mod adb_tests;
+#[cfg(test)]
+pub mod rest_tests;
+#[cfg(test)]
+pub mod led_tests;
+#[cfg(test)]
+pub mod led_group_tests;
+#[cfg(test)]
+pub mod registry_tests;
+#[cfg(test)]
+pub mod self_test_tests;
+#[cfg(test)]
+pub mod gpio_relay_tests;
+#[cfg(test)]
+pub mod support;
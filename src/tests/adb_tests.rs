@@ -0,0 +1,196 @@
+use std::time::Duration;
+
+use crate::adb::{
+    self, AdbServer, ConnectionEvent, ConnectionEventKind, ConnectionTracker, Port, PortMapper, PortRestorePolicy, PortType,
+};
+use crate::config::ConfigSectionADB;
+use crate::rpc::network::{network_manager_server::NetworkManager, NetworkManagerService};
+use crate::rpc::void::Void;
+use mozdevice::DeviceError;
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tonic::{Code, Request};
+
+#[derive(Debug, Clone, PartialEq)]
+enum MappingCall {
+    Forward { local: u16, remote: u16 },
+    Reverse { remote: u16, local: u16 },
+}
+
+// Records every mapping request and fails the first few calls for the configured local ports
+struct MockDevice {
+    calls: Mutex<Vec<MappingCall>>,
+    failures: Mutex<Vec<(u16, u32)>>,
+}
+
+impl MockDevice {
+    fn new(failures: Vec<(u16, u32)>) -> Self {
+        MockDevice { calls: Mutex::new(Vec::new()), failures: Mutex::new(failures) }
+    }
+
+    fn should_fail(&self, local_port: u16) -> bool {
+        let mut failures = self.failures.lock();
+        match failures.iter_mut().find(|(port, _)| *port == local_port) {
+            Some((_, remaining)) if *remaining > 0 => {
+                *remaining -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl PortMapper for MockDevice {
+    fn forward_port(&self, local_port: u16, remote_port: u16) -> Result<(), DeviceError> {
+        self.calls.lock().push(MappingCall::Forward { local: local_port, remote: remote_port });
+        match self.should_fail(local_port) {
+            true => Err(DeviceError::Adb("forward failed".to_string())),
+            false => Ok(()),
+        }
+    }
+
+    fn reverse_port(&self, remote_port: u16, local_port: u16) -> Result<(), DeviceError> {
+        self.calls.lock().push(MappingCall::Reverse { remote: remote_port, local: local_port });
+        match self.should_fail(local_port) {
+            true => Err(DeviceError::Adb("reverse failed".to_string())),
+            false => Ok(()),
+        }
+    }
+}
+
+fn policy(attempts: u32) -> PortRestorePolicy {
+    PortRestorePolicy { attempts, retry_delay: Duration::from_millis(1) }
+}
+
+#[tokio::test]
+async fn restored_mappings_use_local_and_remote_ports() {
+    let device = Mutex::new(Some(MockDevice::new(Vec::new())));
+    let ports = vec![
+        Port::new(PortType::Forward, 8080, 9090),
+        Port::new(PortType::Reverse, 30000, 30001),
+    ];
+
+    let failed = adb::restore_ports(&device, &ports, policy(3)).await;
+    assert!(failed.is_empty());
+
+    let calls = device.lock().as_ref().unwrap().calls.lock().clone();
+    assert_eq!(calls, vec![
+        MappingCall::Forward { local: 8080, remote: 9090 },
+        MappingCall::Reverse { remote: 30001, local: 30000 },
+    ]);
+}
+
+#[tokio::test]
+async fn restore_retries_each_mapping() {
+    // the first mapping recovers on its last attempt, the second never does
+    let device = Mutex::new(Some(MockDevice::new(vec![(8080, 2), (8081, 10)])));
+    let ports = vec![
+        Port::new(PortType::Forward, 8080, 9090),
+        Port::new(PortType::Forward, 8081, 9091),
+        Port::new(PortType::Reverse, 30000, 30000),
+    ];
+
+    let failed = adb::restore_ports(&device, &ports, policy(3)).await;
+    assert_eq!(failed, vec![Port::new(PortType::Forward, 8081, 9091)]);
+
+    // a failing mapping doesn't stop the ones after it
+    let calls = device.lock().as_ref().unwrap().calls.lock().clone();
+    assert_eq!(calls.iter().filter(|x| **x == MappingCall::Forward { local: 8080, remote: 9090 }).count(), 3);
+    assert_eq!(calls.iter().filter(|x| **x == MappingCall::Forward { local: 8081, remote: 9091 }).count(), 3);
+    assert_eq!(calls.last(), Some(&MappingCall::Reverse { remote: 30000, local: 30000 }));
+}
+
+#[tokio::test]
+async fn restore_without_device_fails_all_mappings() {
+    let device: Mutex<Option<MockDevice>> = Mutex::new(None);
+    let ports = vec![Port::new(PortType::Forward, 8080, 9090)];
+
+    let failed = adb::restore_ports(&device, &ports, policy(2)).await;
+    assert_eq!(failed, ports);
+}
+
+fn drain(receiver: &mut broadcast::Receiver<ConnectionEvent>) -> Vec<ConnectionEvent> {
+    std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+}
+
+fn event(serial: &str, kind: ConnectionEventKind) -> ConnectionEvent {
+    ConnectionEvent { kind, serial: serial.to_string() }
+}
+
+#[tokio::test]
+async fn reconnect_cycle_emits_connection_events() {
+    let (sender, mut receiver) = broadcast::channel(16);
+    let mut tracker = ConnectionTracker::new(Some(sender));
+    let ports = vec![
+        Port::new(PortType::Forward, 8080, 9090),
+        Port::new(PortType::Reverse, 30000, 30000),
+    ];
+
+    let device = Mutex::new(Some(MockDevice::new(Vec::new())));
+    let failed = adb::reconnect(&device, "emulator-5554", &ports, policy(1), &mut tracker).await;
+    assert!(failed.is_empty());
+    assert_eq!(drain(&mut receiver), vec![
+        event("emulator-5554", ConnectionEventKind::Connected),
+        event("emulator-5554", ConnectionEventKind::PortsRestored { restored: ports.clone(), failed: Vec::new() }),
+    ]);
+
+    // the device drops, losing it twice only reports once
+    *device.lock() = None;
+    tracker.disconnected();
+    tracker.disconnected();
+    assert_eq!(tracker.serial(), None);
+    assert_eq!(drain(&mut receiver), vec![event("emulator-5554", ConnectionEventKind::Disconnected)]);
+
+    // it comes back but one mapping can't be restored
+    *device.lock() = Some(MockDevice::new(vec![(8080, 10)]));
+    let failed = adb::reconnect(&device, "emulator-5554", &ports, policy(1), &mut tracker).await;
+    assert_eq!(failed, vec![ports[0].clone()]);
+    assert_eq!(drain(&mut receiver), vec![
+        event("emulator-5554", ConnectionEventKind::Connected),
+        event("emulator-5554", ConnectionEventKind::PortsRestored { restored: vec![ports[1].clone()], failed: vec![ports[0].clone()] }),
+    ]);
+}
+
+#[tokio::test]
+async fn reconnect_reports_swapped_device() {
+    let (sender, mut receiver) = broadcast::channel(16);
+    let mut tracker = ConnectionTracker::new(Some(sender));
+    let device = Mutex::new(Some(MockDevice::new(Vec::new())));
+
+    adb::reconnect(&device, "first", &[], policy(1), &mut tracker).await;
+    adb::reconnect(&device, "second", &[], policy(1), &mut tracker).await;
+
+    // nothing to restore, so no PortsRestored
+    assert_eq!(drain(&mut receiver), vec![
+        event("first", ConnectionEventKind::Connected),
+        event("first", ConnectionEventKind::Disconnected),
+        event("second", ConnectionEventKind::Connected),
+    ]);
+    assert_eq!(tracker.serial(), Some("second"));
+}
+
+// Not a tokio test on purpose, spawning the worker without a runtime would panic
+#[test]
+fn disabled_adb_spawns_no_worker() {
+    let mut config = ConfigSectionADB::default();
+    config.enabled = false;
+    // the connection settings aren't looked at while ADB is off
+    config.server_port = 0;
+    assert!(config.validate().is_ok());
+    assert!(AdbServer::from_config(&config).is_none());
+
+    let config: ConfigSectionADB = serde_json::from_str(
+        r#"{ "server_host": "localhost", "server_port": 5037, "read_timeout_ms": 1000, "write_timeout_ms": 1000 }"#,
+    ).unwrap();
+    assert!(config.enabled);
+}
+
+#[tokio::test]
+async fn disabled_network_manager_is_unavailable() {
+    let service = NetworkManagerService::disabled();
+    let err = service.get_running_ports(Request::new(Void::default())).await.unwrap_err();
+    assert_eq!(err.code(), Code::Unavailable);
+
+    let err = service.subscribe_connection_events(Request::new(Void::default())).await.err().expect("ADB is disabled");
+    assert_eq!(err.code(), Code::Unavailable);
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use crate::config::{ApiTokenConfig, ConfigError};
+use crate::device::DeviceServer;
+use crate::rpc::auth::{self, AccessLevel, AuthScope, AuthTokens, Grant};
+use crate::rpc::led::{led_controller_server::LedController, LEDControllerService, SetBrightnessRequest};
+use parking_lot::RwLock;
+use tonic::service::Interceptor;
+use tonic::{Code, Request, Status};
+
+const ADMIN_TOKEN: &str = "admin-token";
+const VIEWER_TOKEN: &str = "viewer-token";
+
+fn make_tokens() -> AuthTokens {
+    AuthTokens::from_config(&[
+        ApiTokenConfig::new(ADMIN_TOKEN.to_string(), vec!["*".to_string()]),
+        ApiTokenConfig::new(VIEWER_TOKEN.to_string(), vec!["led:read".to_string(), "gps:read".to_string()]),
+    ]).expect("failed to load tokens")
+}
+
+fn authorize(tokens: &AuthTokens, scope: &'static str, token: Option<&str>) -> Result<Request<()>, Status> {
+    let mut req = Request::new(());
+    if let Some(token) = token {
+        req.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+    }
+
+    tokens.interceptor(scope).call(req)
+}
+
+// Moves the extensions the interceptor attached onto a typed request, like tonic does before calling the handler
+fn with_grant<T>(authorized: Request<()>, message: T) -> Request<T> {
+    let (metadata, extensions, _) = authorized.into_parts();
+    Request::from_parts(metadata, extensions, message)
+}
+
+#[test]
+fn auth_scope_parse() {
+    assert_eq!(AuthScope::parse("led:read").unwrap(), AuthScope { scope: "led".to_string(), access: AccessLevel::Read });
+    assert_eq!(AuthScope::parse("gpio").unwrap().access, AccessLevel::Write);
+    assert!(matches!(AuthScope::parse("led:delete"), Err(ConfigError::InvalidEntry(_))));
+    assert!(matches!(AuthScope::parse("lde:read"), Err(ConfigError::InvalidEntry(_))));
+}
+
+#[test]
+fn auth_allowed_token() {
+    let tokens = make_tokens();
+    let req = authorize(&tokens, auth::LED_SCOPE, Some(ADMIN_TOKEN)).expect("admin token was rejected");
+    assert_eq!(req.extensions().get::<Grant>(), Some(&Grant(AccessLevel::Write)));
+
+    let req = authorize(&tokens, auth::GPS_SCOPE, Some(VIEWER_TOKEN)).expect("viewer token was rejected");
+    assert_eq!(req.extensions().get::<Grant>(), Some(&Grant(AccessLevel::Read)));
+    assert!(auth::require_write(&req).is_err());
+}
+
+#[test]
+fn auth_missing_or_invalid_token() {
+    let tokens = make_tokens();
+    assert_eq!(authorize(&tokens, auth::LED_SCOPE, None).unwrap_err().code(), Code::Unauthenticated);
+    assert_eq!(authorize(&tokens, auth::LED_SCOPE, Some("wrong-token")).unwrap_err().code(), Code::Unauthenticated);
+
+    let mut req = Request::new(());
+    req.metadata_mut().insert("authorization", ADMIN_TOKEN.parse().unwrap());
+    assert_eq!(tokens.interceptor(auth::LED_SCOPE).call(req).unwrap_err().code(), Code::Unauthenticated);
+}
+
+#[test]
+fn auth_disabled_without_tokens() {
+    let tokens = AuthTokens::from_config(&[]).unwrap();
+    assert!(!tokens.is_enabled());
+    let req = authorize(&tokens, auth::GPIO_SCOPE, None).expect("request was rejected with auth disabled");
+    assert!(auth::require_write(&req).is_ok());
+}
+
+#[tokio::test]
+async fn auth_scope_violation() {
+    let tokens = make_tokens();
+    assert_eq!(authorize(&tokens, auth::GPIO_SCOPE, Some(VIEWER_TOKEN)).unwrap_err().code(), Code::PermissionDenied);
+
+    let service = LEDControllerService::new(&Arc::new(RwLock::new(DeviceServer::new())));
+    let message = SetBrightnessRequest { address: "led0".to_string(), brightness: 0.5 };
+
+    let authorized = authorize(&tokens, auth::LED_SCOPE, Some(VIEWER_TOKEN)).unwrap();
+    let err = service.set_brightness(with_grant(authorized, message.clone())).await.unwrap_err();
+    assert_eq!(err.code(), Code::PermissionDenied);
+
+    // the admin token gets past auth and fails on the missing device instead
+    let authorized = authorize(&tokens, auth::LED_SCOPE, Some(ADMIN_TOKEN)).unwrap();
+    let err = service.set_brightness(with_grant(authorized, message)).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[test]
+fn auth_duplicate_token_rejected() {
+    let result = AuthTokens::from_config(&[
+        ApiTokenConfig::new(ADMIN_TOKEN.to_string(), vec!["*".to_string()]),
+        ApiTokenConfig::new(ADMIN_TOKEN.to_string(), vec!["led:read".to_string()]),
+    ]);
+    assert!(matches!(result, Err(ConfigError::DuplicateEntry(_))));
+}
@@ -0,0 +1,323 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
+
+use intertrait::cast::CastRef;
+
+use crate::bus::i2c::I2cAddress;
+use crate::bus::i2c_sysfs::SmbusTransfer;
+use crate::capabilities::{BarometerCapable, CapabilityId, ReadingCalibration, SensorCapable, ThermometerCapable};
+use crate::config::DeviceConfig;
+use crate::device::{DeviceDriver, DeviceError};
+use crate::drivers::bmp280_sysfs::{detect_chip, hypsometric_altitude, poll_adc_valid, Bmp280SysfsConfig, Bmp280SysfsDriver};
+use crate::drivers::retry::StartRetry;
+use super::support;
+
+// Chip that answers ID reads with the given IDs in turn, the last one repeats
+struct ChipIdBus {
+    ids: Vec<u8>,
+    reads: usize,
+    pec_reads: usize,
+}
+
+impl ChipIdBus {
+    fn new(ids: Vec<u8>) -> Self {
+        Self { ids, reads: 0, pec_reads: 0 }
+    }
+
+    fn next_id(&mut self) -> u8 {
+        let id = self.ids[self.reads.min(self.ids.len() - 1)];
+        self.reads += 1;
+        id
+    }
+}
+
+impl SmbusTransfer for ChipIdBus {
+    fn set_slave_address(&mut self, _address: I2cAddress) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_byte_data(&mut self, register: u8) -> io::Result<u8> {
+        assert_eq!(register, 0xD0, "ID read went to the wrong register");
+        self.pec_reads += 1;
+        Ok(self.next_id())
+    }
+
+    fn write_byte_data(&mut self, _register: u8, _value: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_word_data(&mut self, _register: u8) -> io::Result<u16> {
+        Ok(0)
+    }
+
+    fn write_word_data(&mut self, _register: u8, _value: u16) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for ChipIdBus {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ChipIdBus {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        buf[0] = self.next_id();
+        Ok(1)
+    }
+}
+
+// Chip whose status reads fail with the given error a set number of times before reporting ready
+struct StretchingChip {
+    failures: usize,
+    error: ErrorKind,
+    reads: usize,
+}
+
+impl StretchingChip {
+    fn new(failures: usize, error: ErrorKind) -> Self {
+        Self { failures, error, reads: 0 }
+    }
+}
+
+impl SmbusTransfer for StretchingChip {
+    fn set_slave_address(&mut self, _address: I2cAddress) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_byte_data(&mut self, _register: u8) -> io::Result<u8> {
+        Ok(0)
+    }
+
+    fn write_byte_data(&mut self, _register: u8, _value: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_word_data(&mut self, _register: u8) -> io::Result<u16> {
+        Ok(0)
+    }
+
+    fn write_word_data(&mut self, _register: u8, _value: u16) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for StretchingChip {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for StretchingChip {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        if self.reads <= self.failures {
+            return Err(io::Error::from(self.error));
+        }
+
+        buf[0] = 0x00;
+        Ok(1)
+    }
+}
+
+fn make_driver(pressure_at_sea_level: u32) -> Result<Bmp280SysfsDriver, DeviceError> {
+    let mut config = Bmp280SysfsConfig::default();
+    config.pressure_at_sea_level = pressure_at_sea_level;
+    support::build_driver("bmp280_sysfs", config)
+}
+
+#[test]
+fn altitude_known_pressure() {
+    // 900 hPa against the standard reference at 15 C
+    let altitude = hypsometric_altitude(900.0, 1013.25, 15.0);
+    assert!((altitude - 1010.83).abs() < 0.5, "unexpected altitude {}", altitude);
+
+    assert!(hypsometric_altitude(1013.25, 1013.25, 15.0).abs() < 0.01);
+    // a higher reference pressure places the same reading higher up
+    assert!(hypsometric_altitude(1000.0, 1020.0, 15.0) > hypsometric_altitude(1000.0, 1013.25, 15.0));
+}
+
+#[test]
+fn reference_pressure_from_config() {
+    let mut driver = make_driver(101325).expect("failed to build driver");
+    assert_eq!(driver.get_reference_pressure().unwrap(), 1013.25);
+
+    driver.set_reference_pressure(1020.5).unwrap();
+    assert_eq!(driver.get_reference_pressure().unwrap(), 1020.5);
+
+    assert!(matches!(driver.set_reference_pressure(5000.0), Err(DeviceError::InvalidOperation(_))));
+    assert_eq!(driver.get_reference_pressure().unwrap(), 1020.5);
+
+    assert!(matches!(make_driver(0), Err(DeviceError::InvalidConfig(..))));
+}
+
+#[test]
+fn bmp280_reports_reading_units() {
+    let driver = make_driver(101325).unwrap();
+
+    let temperature = ThermometerCapable::get_units(&driver);
+    let celsius = temperature.iter().find(|x| x.reading == "temperature_celsius").unwrap();
+    assert_eq!(celsius.unit, "°C");
+    assert_eq!((celsius.min, celsius.max), (-40.0, 85.0));
+
+    // the driver's compensation yields Pa, not hPa
+    let pressure = BarometerCapable::get_units(&driver);
+    let pa = pressure.iter().find(|x| x.reading == "pressure").unwrap();
+    assert_eq!(pa.unit, "Pa");
+    let altitude = pressure.iter().find(|x| x.reading == "altitude").unwrap();
+    assert!(altitude.min < 0.0 && altitude.max > 0.0);
+}
+
+#[test]
+fn bmp280_exposes_gains_through_sensor_capable() {
+    let driver = make_driver(101325).unwrap();
+    let device: &dyn DeviceDriver = &driver;
+    let sensor = device.cast::<dyn SensorCapable>().expect("BMP280 should be castable to SensorCapable");
+
+    let mut gains: Vec<u16> = sensor.get_supported_gains().into_values().collect();
+    gains.sort();
+    assert_eq!(gains, vec![1, 2, 4, 8, 16]);
+    assert!(!sensor.get_supported_intervals().is_empty());
+
+    // gains are kept per reading, anything the chip doesn't measure is turned away before the load check
+    assert!(matches!(sensor.get_gain(CapabilityId::Thermometer), Err(DeviceError::InvalidOperation(_))));
+    assert!(matches!(sensor.get_gain(CapabilityId::Barometer), Err(DeviceError::InvalidOperation(_))));
+    assert!(matches!(sensor.get_gain(CapabilityId::LightSensor), Err(DeviceError::NotSupported)));
+}
+
+#[test]
+fn calibration_corrects_a_known_reading() {
+    // a sensor reading 2 % high with a 0.5 °C offset
+    let calibration = ReadingCalibration::new(1.0 / 1.02, -0.5).unwrap();
+    assert!((calibration.apply(25.5) - 24.5).abs() < 1e-4, "unexpected reading {}", calibration.apply(25.5));
+
+    // the default leaves readings alone
+    for raw in [-40.0, 0.0, 21.37, 101325.0] {
+        assert_eq!(ReadingCalibration::default().apply(raw), raw);
+    }
+
+    assert!(ReadingCalibration::new(0.0, 1.0).is_err());
+    assert!(ReadingCalibration::new(1.0, f32::NAN).is_err());
+}
+
+#[test]
+fn bmp280_calibration_is_kept_per_reading() {
+    // configs written before calibration existed don't change any reading
+    let mut data = serde_json::to_value(Bmp280SysfsConfig::default()).unwrap();
+    for field in ["temperature_calibration_scale", "temperature_calibration_offset", "pressure_calibration_scale", "pressure_calibration_offset"] {
+        data.as_object_mut().unwrap().remove(field);
+    }
+    let mut driver = Bmp280SysfsDriver::new(Some(&mut DeviceConfig::new("bmp280_sysfs".to_string(), None, data))).unwrap();
+    assert_eq!(driver.get_calibration(CapabilityId::Thermometer).unwrap(), ReadingCalibration::default());
+    assert_eq!(driver.get_calibration(CapabilityId::Barometer).unwrap(), ReadingCalibration::default());
+
+    let pressure = ReadingCalibration::new(1.0, 150.0).unwrap();
+    driver.set_calibration(CapabilityId::Barometer, pressure).unwrap();
+    assert_eq!(driver.get_calibration(CapabilityId::Barometer).unwrap(), pressure);
+    assert_eq!(driver.get_calibration(CapabilityId::Thermometer).unwrap(), ReadingCalibration::default());
+
+    let invalid = ReadingCalibration { scale: 0.0, offset: 0.0 };
+    assert!(matches!(driver.set_calibration(CapabilityId::Thermometer, invalid), Err(DeviceError::InvalidOperation(_))));
+    assert!(matches!(driver.get_calibration(CapabilityId::LightSensor), Err(DeviceError::NotSupported)));
+
+    let mut config = Bmp280SysfsConfig::default();
+    config.temperature_calibration_scale = 0.98;
+    config.temperature_calibration_offset = -0.5;
+    let mut device_config = DeviceConfig::new("bmp280_sysfs".to_string(), None, serde_json::to_value(&config).unwrap());
+    let driver = Bmp280SysfsDriver::new(Some(&mut device_config)).unwrap();
+    assert_eq!(driver.get_calibration(CapabilityId::Thermometer).unwrap(), ReadingCalibration::new(0.98, -0.5).unwrap());
+
+    config.pressure_calibration_scale = 0.0;
+    let mut device_config = DeviceConfig::new("bmp280_sysfs".to_string(), None, serde_json::to_value(&config).unwrap());
+    assert!(matches!(Bmp280SysfsDriver::new(Some(&mut device_config)), Err(DeviceError::InvalidConfig(..))));
+}
+
+#[test]
+fn bmp280_requires_i2c_sysfs() {
+    let driver = make_driver(101325).expect("failed to build driver");
+    assert_eq!(driver.required_controllers(), vec!["i2c_sysfs"]);
+}
+
+#[test]
+fn adc_wait_retries_timed_out_status_reads() {
+    let mut chip = StretchingChip::new(3, ErrorKind::TimedOut);
+    let elapsed = poll_adc_valid(&mut chip, I2cAddress::seven_bit(0x76), 1, 100, Some(Duration::from_millis(5)), false)
+        .expect("timed out status reads should be retried");
+
+    assert_eq!(chip.reads, 4);
+    // every timed out read counts the bus timeout on top of the poll interval
+    assert_eq!(elapsed, 18);
+}
+
+#[test]
+fn adc_wait_gives_up_once_timeouts_exhaust_the_wait() {
+    let mut chip = StretchingChip::new(usize::MAX, ErrorKind::TimedOut);
+    let result = poll_adc_valid(&mut chip, I2cAddress::seven_bit(0x76), 1, 20, Some(Duration::from_millis(5)), false);
+
+    assert!(matches!(result, Err(DeviceError::HardwareError(ref msg, Some(_))) if msg.contains("timing out")));
+    assert_eq!(chip.reads, 4);
+}
+
+#[test]
+fn adc_wait_aborts_on_genuine_failures() {
+    let mut chip = StretchingChip::new(1, ErrorKind::Other);
+    let result = poll_adc_valid(&mut chip, I2cAddress::seven_bit(0x76), 1, 100, None, false);
+
+    assert!(matches!(result, Err(DeviceError::HardwareError(ref msg, _)) if msg.contains("failed to read chip status")));
+    assert_eq!(chip.reads, 1);
+}
+
+#[test]
+fn chip_detection_retries_until_the_chip_answers() {
+    // still powering up for the first two reads
+    let mut bus = ChipIdBus::new(vec![0xFF, 0x00, 0x58]);
+    StartRetry::new(2, 1)
+        .run("detect BMP280", || detect_chip(&mut bus, 0, I2cAddress::seven_bit(0x76), false))
+        .expect("chip was not detected after retrying");
+    assert_eq!(bus.reads, 3);
+
+    let mut bus = ChipIdBus::new(vec![0xFF, 0x00, 0x58]);
+    let result = StartRetry::new(1, 1).run("detect BMP280", || detect_chip(&mut bus, 0, I2cAddress::seven_bit(0x76), false));
+    assert!(matches!(result, Err(DeviceError::HardwareError(ref msg, _)) if msg.contains("invalid device")));
+    assert_eq!(bus.reads, 2);
+}
+
+#[test]
+fn chip_detection_uses_pec_transfers_when_enabled() {
+    let mut bus = ChipIdBus::new(vec![0x58]);
+    detect_chip(&mut bus, 0, I2cAddress::seven_bit(0x76), true).expect("chip was not detected over PEC");
+    assert_eq!(bus.pec_reads, 1);
+
+    let mut bus = ChipIdBus::new(vec![0x58]);
+    detect_chip(&mut bus, 0, I2cAddress::seven_bit(0x76), false).expect("chip was not detected");
+    assert_eq!(bus.pec_reads, 0);
+}
+
+#[test]
+fn start_retry_skips_config_errors() {
+    let mut attempts = 0;
+    let result: Result<(), DeviceError> = StartRetry::new(5, 1).run("start", || {
+        attempts += 1;
+        Err(DeviceError::invalid_config("bad config"))
+    });
+
+    assert!(matches!(result, Err(DeviceError::InvalidConfig(..))));
+    assert_eq!(attempts, 1);
+
+    // older configs without the retry fields keep failing on the first attempt
+    let config: Bmp280SysfsConfig = serde_json::from_value(serde_json::json!({
+        "default_thermometer_gain": 1, "default_pressure_gain": 4, "default_standby_time": 63,
+        "device_address": 118, "device_ready_timeout": 100, "pressure_at_sea_level": 101325, "bus_id": 0
+    })).unwrap();
+    assert_eq!(config.start_retries, 0);
+    assert_eq!(config.start_retry_delay_ms, 50);
+}
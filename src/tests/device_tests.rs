@@ -1,9 +1,12 @@
 use std::any::{Any, TypeId};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::bus::BusController;
-use crate::capabilities::{Capability, LEDControllerCapable};
-use crate::device::{DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder, Device};
+use crate::capabilities::{Capability, CapabilityId, DiagnosticsCapable, HealthReport, LEDControllerCapable};
+use crate::device::{spawn_optional_device_retry, DeviceDriver, DeviceError, DeviceOrder, DeviceServer, DeviceServerBuilder, Device};
 use intertrait::cast_to;
 use parking_lot::RwLock;
 use uuid::Uuid;
@@ -126,6 +129,42 @@ impl DeviceDriver for NoCapDevice {
     }
 }
 
+struct BusBoundDevice {}
+
+impl DeviceDriver for BusBoundDevice {
+    fn name(&self) -> String {
+        "busbound".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        false
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(BusBoundDevice {})
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn required_controllers(&self) -> Vec<&'static str> {
+        vec!["stub"]
+    }
+}
+
 impl DeviceDriver for FunDevice {
     fn name(&self) -> String {
         "fun".to_string()
@@ -367,6 +406,30 @@ fn ds_has_bus() {
     assert!(!server.has_bus::<StubController>());
 }
 
+#[test]
+fn ds_reports_missing_controllers() {
+    let mut server = DeviceServer::new();
+    let device = Device::new::<BusBoundDevice>(None, None).unwrap();
+    assert_eq!(server.missing_controllers(&device), vec!["stub"]);
+    assert!(server.missing_controllers(&Device::new::<NoCapDevice>(None, None).unwrap()).is_empty());
+
+    // bus names are matched case-insensitively, StubController reports "STUB"
+    server.register_bus(Arc::new(RwLock::new(StubController::new()))).expect("failed to register bus");
+    assert!(server.has_bus_named("stub"));
+    assert!(server.missing_controllers(&device).is_empty());
+}
+
+#[test]
+fn ds_register_fails_early_without_required_controller() {
+    let mut server = DeviceServer::new();
+    let result = server.register_device(Device::new::<BusBoundDevice>(None, None).unwrap(), true);
+    assert_eq!(result.unwrap_err(), DeviceError::MissingController("stub".to_string()));
+    assert_eq!(server.get_devices().len(), 0);
+
+    // registering without starting doesn't need the controller yet
+    server.register_device(Device::new::<BusBoundDevice>(None, None).unwrap(), false).expect("failed to add stopped device");
+}
+
 #[test]
 fn ds_has_device() {
     let server = DeviceServerBuilder::configure()
@@ -504,7 +567,7 @@ fn device_as_capability_mut() {
         .build(true).expect("failed to build server");
 
     let id = server.register_device(Device::new::<SleepyDevice>(None, None).unwrap(), true).expect("failed to register device");
-    let device = server.get_device_mut(&id).expect("failed to get device");
+    let mut device = server.get_device_mut(&id).expect("failed to get device");
     let sleepy = device.as_capability_mut::<dyn SleepCapable>().expect("failed to cast device");
 
     // go to sleep
@@ -525,7 +588,7 @@ fn device_bus_access() {
         .build(true).expect("failed to build server");
 
     let id = server.register_device(Device::new::<FunDevice>(None, None).unwrap(), true).expect("failed to register device");
-    let device = server.get_device_mut(&id).expect("failed to get device");
+    let mut device = server.get_device_mut(&id).expect("failed to get device");
     let fun = device.as_capability_mut::<dyn FunCapable>().expect("failed to cast device");
 
     for i in 0..10 {
@@ -612,6 +675,29 @@ fn ds_bus_ptr_access() {
     assert_eq!(fun.read().get_fun_count(), 1);
 }
 
+#[test]
+fn ds_bus_ptr_shares_registered_controller() {
+    let server = DeviceServerBuilder::configure()
+        .add_bus(FunController::new())
+        .add_bus(StubController::new())
+        .build(false).expect("failed to build server");
+
+    let fun = server.get_bus_ptr::<FunController>().expect("failed to get fun ptr");
+    fun.write().increase_fun();
+    assert_eq!(server.get_bus::<FunController>().unwrap().get_fun_count(), 1);
+
+    // typed handles are found without locking, so a held controller doesn't hide them
+    let guard = fun.write();
+    assert!(server.get_bus_ptr::<FunController>().is_some());
+    drop(guard);
+
+    let mut server = DeviceServer::new();
+    let bus = Arc::new(RwLock::new(StubController::new()));
+    server.register_bus(bus.clone()).expect("failed to register bus");
+    assert!(Arc::ptr_eq(&bus, &server.get_bus_ptr::<StubController>().unwrap()));
+    assert!(server.get_bus_ptr::<FunController>().is_none());
+}
+
 #[test]
 fn ds_start_devices() {
     let address = Uuid::new_v4();
@@ -630,12 +716,10 @@ fn ds_device_start() {
         .add_device(Device::new::<SleepyDevice>(Some(address), None).unwrap())
         .build(false).expect("failed to build server");
 
-    let device = server.get_device(&address).expect("failed to get device by id");
-    assert_eq!(device.is_running(), false);
+    assert_eq!(server.get_device(&address).expect("failed to get device by id").is_running(), false);
 
     server.start_device(&address).expect("failed to start device");
-    let device = server.get_device(&address).expect("failed to get device by id");
-    assert_eq!(device.is_running(), true);
+    assert_eq!(server.get_device(&address).expect("failed to get device by id").is_running(), true);
 
     server.start_device(&address).expect_err("started device twice");
 }
@@ -647,12 +731,10 @@ fn ds_stop_device() {
         .add_device(Device::new::<SleepyDevice>(Some(address), None).unwrap())
         .build(true).expect("failed to build server");
 
-    let device = server.get_device(&address).expect("failed to get device by id");
-    assert_eq!(device.is_running(), true);
+    assert_eq!(server.get_device(&address).expect("failed to get device by id").is_running(), true);
 
     server.stop_device(&address).expect("failed to stop device");
-    let device = server.get_device(&address).expect("failed to get device by id");
-    assert_eq!(device.is_running(), false);
+    assert_eq!(server.get_device(&address).expect("failed to get device by id").is_running(), false);
 
     server.stop_device(&address).expect_err("attempted to stop device twice");
 }
@@ -693,4 +775,500 @@ fn ds_get_device_by_name() {
     assert!(server.get_device_with_name("device2").is_some(), "failed to find valid device");
     assert!(server.get_device_with_name("device3").is_none(), "found non-existent device");
     assert!(server.get_device_with_name("device7").is_some(), "failed to find valid device");
-}
\ No newline at end of file
+}
+
+#[test]
+fn ds_rename_updates_name_index() {
+    let mut server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<SleepyDevice>(None, Some("device1".to_owned())).unwrap())
+        .add_device(Device::new::<SleepyDevice>(None, Some("device2".to_owned())).unwrap())
+        .build(true).expect("failed to build device server");
+
+    let address = server.get_device_with_name("device1").unwrap().address();
+    assert!(server.rename_device(&address, "renamed").is_ok());
+    assert!(server.get_device_with_name("device1").is_none(), "old name is still indexed");
+    assert_eq!(server.get_device_with_name("renamed").unwrap().address(), address);
+    assert_eq!(server.get_device_with_name_mut("renamed").unwrap().device_name(), "renamed");
+
+    // the old name is free again
+    assert!(server.register_device(Device::new::<SleepyDevice>(None, Some("device1".to_owned())).unwrap(), false).is_ok());
+}
+
+#[test]
+fn ds_rename_duplicate_rejected() {
+    let mut server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<SleepyDevice>(None, Some("device1".to_owned())).unwrap())
+        .add_device(Device::new::<SleepyDevice>(None, Some("device2".to_owned())).unwrap())
+        .build(true).expect("failed to build device server");
+
+    let address = server.get_device_with_name("device1").unwrap().address();
+    assert!(matches!(server.rename_device(&address, "device2"), Err(DeviceError::DuplicateDevice(_))));
+    assert_eq!(server.get_device_with_name("device1").unwrap().address(), address);
+    assert!(matches!(server.rename_device(&Uuid::new_v4(), "device3"), Err(DeviceError::NotFound(_))));
+}
+
+#[test]
+fn ds_remove_clears_name_index() {
+    let mut server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<SleepyDevice>(None, Some("device1".to_owned())).unwrap())
+        .build(true).expect("failed to build device server");
+
+    let address = server.get_device_with_name("device1").unwrap().address();
+    assert!(server.remove_device(&address).is_ok());
+    assert!(server.get_device_with_name("device1").is_none(), "removed device is still indexed");
+    assert!(server.register_device(Device::new::<SleepyDevice>(None, Some("device1".to_owned())).unwrap(), true).is_ok());
+}
+
+// only exposes diagnostics while running, like a sensor that needs its bus to self test
+struct HotSwapDevice {
+    is_loaded: bool
+}
+
+impl DeviceDriver for HotSwapDevice {
+    fn name(&self) -> String {
+        "hotswap".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(HotSwapDevice { is_loaded: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn supports_capability(&self, capability: CapabilityId) -> bool {
+        capability != CapabilityId::Diagnostics || self.is_loaded
+    }
+}
+
+impl Capability for HotSwapDevice {}
+
+#[cast_to]
+impl DiagnosticsCapable for HotSwapDevice {
+    fn self_test(&mut self) -> Result<HealthReport, DeviceError> {
+        Ok(HealthReport::new(Some(0), 0, None, Duration::ZERO))
+    }
+}
+
+#[test]
+fn device_refresh_capabilities() {
+    let mut device = Device::new::<HotSwapDevice>(None, None).unwrap();
+    assert!(!device.get_capabilities().contains(&CapabilityId::Diagnostics));
+
+    let mut server = DeviceServerBuilder::configure()
+        .build(true).expect("failed to build server");
+    let id = server.register_device(device, true).expect("failed to register device");
+    assert!(server.get_device(&id).unwrap().get_capabilities().contains(&CapabilityId::Diagnostics));
+
+    server.stop_device(&id).expect("failed to stop device");
+    assert!(!server.get_device(&id).unwrap().get_capabilities().contains(&CapabilityId::Diagnostics));
+
+    server.start_device(&id).expect("failed to start device");
+    assert!(server.get_device(&id).unwrap().get_capabilities().contains(&CapabilityId::Diagnostics));
+
+    // manual refresh picks up changes made outside the server
+    device = Device::new::<HotSwapDevice>(None, None).unwrap();
+    device.as_mut().as_any_mut().downcast_mut::<HotSwapDevice>().unwrap().is_loaded = true;
+    assert!(!device.get_capabilities().contains(&CapabilityId::Diagnostics));
+    device.refresh_capabilities();
+    assert!(device.get_capabilities().contains(&CapabilityId::Diagnostics));
+}
+
+#[test]
+fn ds_get_bus_timeout_waits_for_lock() {
+    let server = DeviceServerBuilder::configure()
+        .add_bus(StubController::new())
+        .add_bus(FunController::new())
+        .build(false).expect("failed to build server");
+
+    let fun_bus = server.get_bus_ptr::<FunController>().unwrap();
+    let (locked_tx, locked_rx) = mpsc::channel();
+    let holder = thread::spawn(move || {
+        let mut bus = fun_bus.write();
+        locked_tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        bus.increase_fun();
+    });
+
+    locked_rx.recv().unwrap();
+    // the plain lookup skips the locked controller
+    assert!(server.get_bus::<FunController>().is_none());
+
+    let bus = server.get_bus_timeout::<FunController>(Duration::from_secs(2)).expect("controller was not found");
+    assert_eq!(bus.get_fun_count(), 1);
+    drop(bus);
+    holder.join().unwrap();
+
+    let mut bus = server.get_bus_mut_timeout::<FunController>(Duration::from_secs(2)).unwrap();
+    assert_eq!(bus.increase_fun(), Some(2));
+}
+
+#[test]
+fn ds_get_bus_timeout_expires() {
+    let server = DeviceServerBuilder::configure()
+        .add_bus(StubController::new())
+        .add_bus(FunController::new())
+        .with_bus_lock_timeout(Duration::from_millis(20))
+        .build(false).expect("failed to build server");
+
+    let fun_bus = server.get_bus_ptr::<FunController>().unwrap();
+    let guard = fun_bus.write();
+
+    assert!(server.try_get_bus::<FunController>().is_none());
+    assert!(server.try_get_bus_mut::<FunController>().is_none());
+    // other controllers are still reachable while one is held
+    assert_eq!(server.try_get_bus::<StubController>().unwrap().do_thing(), "hello");
+
+    drop(guard);
+    assert!(server.try_get_bus::<FunController>().is_some());
+}
+
+#[test]
+fn device_error_keeps_source() {
+    use std::error::Error;
+    use std::io;
+
+    let cause = io::Error::new(io::ErrorKind::TimedOut, "bus timed out");
+    let err = DeviceError::hardware(format!("failed to read sensor data: {}", cause), cause);
+    assert_eq!(err.to_string(), "a hardware error has occurred: failed to read sensor data: bus timed out");
+
+    let source = err.source().expect("hardware error lost its source");
+    let io_err = source.downcast_ref::<io::Error>().expect("source is not an io::Error");
+    assert_eq!(io_err.kind(), io::ErrorKind::TimedOut);
+
+    // clones share the source, and sources don't take part in equality
+    assert!(err.clone().source().is_some());
+    assert_eq!(err, DeviceError::hardware_error("failed to read sensor data: bus timed out"));
+
+    let err = DeviceError::invalid_config_from("bad data", io::Error::new(io::ErrorKind::InvalidData, "not json"));
+    assert_eq!(err.to_string(), "invalid config: bad data");
+    assert_eq!(err.source().unwrap().to_string(), "not json");
+
+    assert!(DeviceError::hardware_error("no cause").source().is_none());
+    assert!(DeviceError::NotSupported.source().is_none());
+}
+
+// Only starts once a device named "base" is already running on the server
+struct DependentDevice {
+    is_loaded: bool
+}
+
+impl DeviceDriver for DependentDevice {
+    fn name(&self) -> String {
+        "dependent".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(DependentDevice { is_loaded: false })
+    }
+
+    fn start(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if parent.get_device_with_name("base").is_none() {
+            return Err(DeviceError::InvalidOperation("base device is not running".to_string()));
+        }
+
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+fn named_device(name: &str, depends_on: &[&str]) -> Device {
+    Device::new::<NoCapDevice>(None, Some(name.to_string()))
+        .unwrap()
+        .with_dependencies(depends_on.iter().map(|x| x.to_string()).collect())
+}
+
+#[test]
+fn ds_resolve_dependency_chain() {
+    let server = DeviceServerBuilder::configure()
+        .add_bus(FunController::new())
+        .build(false)
+        .unwrap();
+
+    let devices = vec![
+        named_device("c", &["b"]),
+        named_device("b", &["a", "fun"]),
+        named_device("a", &[]),
+        named_device("d", &[]),
+    ];
+
+    assert_eq!(server.resolve_start_order(&devices).unwrap(), vec![2, 1, 0, 3]);
+}
+
+#[test]
+fn ds_build_starts_dependencies_first() {
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<DependentDevice>(None, Some("top".to_string())).unwrap().with_dependencies(vec!["base".to_string()]))
+        .add_device(Device::new::<NoCapDevice>(None, Some("base".to_string())).unwrap())
+        .build(true)
+        .expect("failed to build server");
+
+    assert_eq!(server.get_devices().len(), 2);
+    assert!(server.get_device_with_name("top").unwrap().is_running());
+}
+
+#[test]
+fn ds_dependency_cycle_rejected() {
+    let result = DeviceServerBuilder::configure()
+        .add_device(named_device("a", &["c"]))
+        .add_device(named_device("b", &["a"]))
+        .add_device(named_device("c", &["b"]))
+        .build(true);
+
+    match result {
+        Err(DeviceError::InvalidConfig(msg, _)) => assert!(msg.contains("a -> c -> b -> a"), "{}", msg),
+        _ => panic!("dependency cycle was not detected"),
+    }
+
+    let self_cycle = DeviceServer::new().resolve_start_order(&[named_device("a", &["a"])]);
+    assert!(matches!(self_cycle, Err(DeviceError::InvalidConfig(..))));
+}
+
+#[test]
+fn ds_missing_dependency_rejected() {
+    let result = DeviceServerBuilder::configure()
+        .add_device(named_device("a", &["missing"]))
+        .build(true);
+
+    match result {
+        Err(DeviceError::InvalidConfig(msg, _)) => assert!(msg.contains("\"missing\""), "{}", msg),
+        _ => panic!("missing dependency was not detected"),
+    }
+}
+
+#[test]
+fn ds_partial_start_order_reports_missing_dependencies() {
+    let devices = vec![
+        named_device("b", &["a", "missing"]),
+        named_device("a", &[]),
+        named_device("c", &["b"]),
+    ];
+
+    // the rest still gets an order, the caller decides what to skip
+    let (order, missing) = DeviceServer::new().resolve_partial_start_order(&devices).unwrap();
+    assert_eq!(order, vec![1, 0, 2]);
+    assert_eq!(missing, vec![(0, "missing".to_string())]);
+
+    // cycles still fail the whole order
+    let cycle = DeviceServer::new().resolve_partial_start_order(&[named_device("a", &["b"]), named_device("b", &["a"])]);
+    assert!(matches!(cycle, Err(DeviceError::InvalidConfig(..))));
+}
+
+static FLAKY_CHIP_PRESENT: AtomicBool = AtomicBool::new(false);
+
+// Stands in for a sensor that is only found once the chip is connected
+struct FlakyChipDevice {
+    is_loaded: bool
+}
+
+impl DeviceDriver for FlakyChipDevice {
+    fn name(&self) -> String {
+        "flaky_chip".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(FlakyChipDevice { is_loaded: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if !FLAKY_CHIP_PRESENT.load(Ordering::SeqCst) {
+            return Err(DeviceError::hardware_error("chip id mismatch"));
+        }
+
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[test]
+fn ds_required_device_start_failure_aborts() {
+    let mut server = DeviceServer::new();
+    let device = Device::new::<DependentDevice>(None, Some("required".to_string())).unwrap();
+    assert!(server.register_device(device, true).is_err());
+    assert_eq!(server.get_devices().len(), 0);
+}
+
+#[tokio::test]
+async fn ds_optional_device_retried_until_present() {
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<FlakyChipDevice>(None, Some("flaky".to_string())).unwrap().with_optional(true))
+        .build(true)
+        .expect("optional device should not fail the build");
+
+    let server = Arc::new(RwLock::new(server));
+    assert!(!server.read().get_device_with_name("flaky").unwrap().is_running());
+    assert!(server.read().has_pending_optional_devices());
+
+    let retry = spawn_optional_device_retry(&server, Duration::from_millis(10));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!server.read().get_device_with_name("flaky").unwrap().is_running());
+
+    FLAKY_CHIP_PRESENT.store(true, Ordering::SeqCst);
+    let mut running = false;
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if server.read().get_device_with_name("flaky").unwrap().is_running() {
+            running = true;
+            break;
+        }
+    }
+
+    retry.abort();
+    assert!(running, "optional device was never started");
+    assert!(!server.read().has_pending_optional_devices());
+}
+
+#[test]
+fn ds_retry_skips_devices_stopped_on_purpose() {
+    let mut server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<NoCapDevice>(None, Some("stopped".to_string())).unwrap().with_optional(true))
+        .build(true)
+        .expect("failed to build server");
+
+    let address = server.get_device_with_name("stopped").unwrap().address();
+    server.stop_device(&address).unwrap();
+    assert!(!server.has_pending_optional_devices());
+
+    let server = Arc::new(RwLock::new(server));
+    assert!(DeviceServer::retry_optional_devices(&server).is_empty());
+    assert!(!server.read().get_device_with_name("stopped").unwrap().is_running());
+}
+
+#[test]
+fn ds_snapshot_is_independent_of_later_changes() {
+    let mut server = DeviceServerBuilder::configure()
+        .add_bus(FunController::new())
+        .add_device(Device::new::<DummyLedController>(None, Some("led".to_string())).unwrap())
+        .add_device(Device::new::<NoCapDevice>(None, Some("nocap".to_string())).unwrap())
+        .build(true).expect("failed to build server");
+
+    let led = server.get_device_with_name("led").unwrap().address();
+    let nocap = server.get_device_with_name("nocap").unwrap().address();
+
+    let mut snapshot = server.snapshot();
+    snapshot.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(snapshot[0].address, led);
+    assert_eq!(snapshot[0].driver_name, "sleepy");
+    assert_eq!(snapshot[0].capabilities, vec![CapabilityId::LEDController]);
+    assert!(snapshot[0].is_running);
+    assert_eq!(snapshot[1].address, nocap);
+
+    let copy = snapshot.clone();
+    server.stop_device(&led).unwrap();
+    server.rename_device(&led, "renamed").unwrap();
+    server.remove_device(&nocap).unwrap();
+
+    // the snapshot keeps the state it was taken with
+    assert_eq!(snapshot, copy);
+    assert!(snapshot[0].is_running);
+    assert_eq!(snapshot[0].name, "led");
+
+    let current = server.snapshot();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0].name, "renamed");
+    assert!(!current[0].is_running);
+}
+
+#[test]
+fn ds_snapshot_does_not_wait_on_device_locks() {
+    let mut server = DeviceServerBuilder::configure()
+        .add_bus(FunController::new())
+        .add_device(Device::new::<DummyLedController>(None, Some("led".to_string())).unwrap())
+        .build(true).expect("failed to build server");
+    server.set_device_lock_timeout(Duration::from_millis(500));
+
+    let led = server.get_device_with_name("led").unwrap().address();
+    let device = server.get_device_ptr(&led).unwrap();
+    let _held = device.write();
+
+    let started = Instant::now();
+    let snapshot = server.snapshot();
+    assert!(started.elapsed() < Duration::from_millis(100), "snapshot waited on the device lock");
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].name, "led");
+    assert!(snapshot[0].is_running);
+    assert_eq!(server.snapshot_device(&led), snapshot.into_iter().next());
+}
+
+#[test]
+fn ds_enumerates_devices_in_registration_order() {
+    let mut server = DeviceServer::new();
+    server.register_bus(Arc::new(RwLock::new(FunController::new()))).expect("failed to register bus");
+
+    let mut expected = Vec::new();
+    for name in ["zulu", "alpha", "mike", "bravo", "yankee"] {
+        let device = Device::new::<NoCapDevice>(None, Some(name.to_string())).unwrap();
+        expected.push(server.register_device(device, true).expect("failed to add device"));
+    }
+
+    // the same order every time, however the addresses hash
+    for _ in 0..10 {
+        assert_eq!(server.get_device_addresses(), expected);
+        assert_eq!(server.get_devices().iter().map(|(k, _)| **k).collect::<Vec<Uuid>>(), expected);
+        assert_eq!(server.snapshot().iter().map(|x| x.address).collect::<Vec<Uuid>>(), expected);
+    }
+
+    // restarting a device doesn't move it
+    server.stop_device(&expected[1]).unwrap();
+    server.start_device(&expected[1]).unwrap();
+    server.remove_device(&expected[2]).unwrap();
+    expected.remove(2);
+    assert_eq!(server.get_device_addresses(), expected);
+
+    let names: Vec<String> = server.snapshot_ordered(DeviceOrder::Name).into_iter().map(|x| x.name).collect();
+    assert_eq!(names, vec!["alpha", "bravo", "yankee", "zulu"]);
+}
@@ -0,0 +1,105 @@
+use std::any::Any;
+
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer};
+use crate::events::{DeviceEventKind, DeviceEvents};
+use tokio::sync::broadcast::error::TryRecvError;
+use uuid::Uuid;
+
+struct QuietDevice {
+    is_loaded: bool,
+}
+
+impl DeviceDriver for QuietDevice {
+    fn name(&self) -> String {
+        "quiet".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(QuietDevice { is_loaded: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[test]
+fn register_emits_device_registered() {
+    let mut server = DeviceServer::new();
+    let mut events = server.events().subscribe();
+
+    let address = server.register_device(Device::new::<QuietDevice>(None, None).unwrap(), false).unwrap();
+    let event = events.try_recv().expect("no event was emitted");
+    assert_eq!(event.kind, DeviceEventKind::DeviceRegistered);
+    assert_eq!(event.address, address);
+    assert!(event.timestamp_ms() > 0);
+    assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+}
+
+#[test]
+fn device_lifecycle_events() {
+    let mut server = DeviceServer::new();
+    let address = server.register_device(Device::new::<QuietDevice>(None, None).unwrap(), true).unwrap();
+    let mut events = server.events().subscribe();
+
+    server.stop_device(&address).unwrap();
+    server.start_device(&address).unwrap();
+    server.remove_device(&address).unwrap();
+
+    let kinds: Vec<DeviceEventKind> = std::iter::from_fn(|| events.try_recv().ok()).map(|x| x.kind).collect();
+    assert_eq!(kinds, vec![
+        DeviceEventKind::DeviceStopped,
+        DeviceEventKind::DeviceStarted,
+        DeviceEventKind::DeviceStopped,
+        DeviceEventKind::DeviceRemoved,
+    ]);
+}
+
+#[test]
+fn repeated_hardware_errors_emit_once() {
+    let events = DeviceEvents::new();
+    let mut receiver = events.subscribe();
+    let address = Uuid::new_v4();
+
+    events.report_error(address, &DeviceError::InvalidOperation("ignored".to_string()));
+    for _ in 0..7 {
+        events.report_error(address, &DeviceError::hardware_error("bus timeout"));
+    }
+
+    let event = receiver.try_recv().expect("no event was emitted");
+    assert_eq!(event.address, address);
+    assert!(matches!(event.kind, DeviceEventKind::HardwareErrors { count: 5, .. }));
+    assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+}
+
+#[test]
+fn slow_subscriber_lags_instead_of_blocking() {
+    let events = DeviceEvents::new();
+    let mut receiver = events.subscribe();
+    let address = Uuid::new_v4();
+
+    for _ in 0..200 {
+        events.emit(address, DeviceEventKind::DeviceStarted);
+    }
+
+    assert!(matches!(receiver.try_recv(), Err(TryRecvError::Lagged(_))));
+    assert!(receiver.try_recv().is_ok());
+}
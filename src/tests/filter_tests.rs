@@ -0,0 +1,69 @@
+use crate::config::DeviceConfig;
+use crate::device::DeviceDriver;
+use crate::drivers::bmp280_sysfs::{Bmp280SysfsConfig, Bmp280SysfsDriver};
+use crate::drivers::filter::EmaFilter;
+use crate::drivers::tsl2591_sysfs::{Tsl2591SysfsConfig, Tsl2591SysfsDriver};
+
+#[test]
+fn ema_step_response() {
+    let alpha = 0.25;
+    let mut filter = EmaFilter::new(alpha);
+    assert_eq!(filter.apply(0.0), 0.0);
+
+    // after n samples of a step the remaining error is (1 - alpha)^n of the step size
+    for n in 1..=20 {
+        let value = filter.apply(100.0);
+        let expected = 100.0 * (1.0 - (1.0 - alpha).powi(n));
+        assert!((value - expected).abs() < 0.001, "sample {}: expected {}, got {}", n, expected, value);
+    }
+
+    assert!((filter.apply(100.0) - 100.0).abs() < 0.5);
+}
+
+#[test]
+fn ema_disabled_and_reset() {
+    let mut filter = EmaFilter::new(0.0);
+    assert!(!filter.is_enabled());
+    assert_eq!(filter.apply(5.0), 5.0);
+    assert_eq!(filter.apply(50.0), 50.0);
+    assert_eq!(filter.value(), None);
+
+    let mut filter = EmaFilter::new(0.5);
+    filter.apply(0.0);
+    assert_eq!(filter.apply(10.0), 5.0);
+
+    // the first sample after a reset is passed through unchanged
+    filter.reset();
+    assert_eq!(filter.value(), None);
+    assert_eq!(filter.apply(10.0), 10.0);
+
+    let mut filter = EmaFilter::new(1.0);
+    filter.apply(3.0);
+    assert_eq!(filter.apply(7.0), 7.0);
+}
+
+#[test]
+fn smoothing_alpha_validated() {
+    assert!(EmaFilter::validate_alpha(0.0).is_ok());
+    assert!(EmaFilter::validate_alpha(1.0).is_ok());
+    assert!(EmaFilter::validate_alpha(-0.1).is_err());
+    assert!(EmaFilter::validate_alpha(1.5).is_err());
+
+    let mut config = Bmp280SysfsConfig::default();
+    config.smoothing_alpha = 2.0;
+    let mut device_config = DeviceConfig::new("bmp280_sysfs".to_string(), None, serde_json::to_value(config).unwrap());
+    assert!(Bmp280SysfsDriver::new(Some(&mut device_config)).is_err());
+
+    let mut config = Tsl2591SysfsConfig::default();
+    config.smoothing_alpha = -1.0;
+    let mut device_config = DeviceConfig::new("tsl2591_sysfs".to_string(), None, serde_json::to_value(config).unwrap());
+    assert!(Tsl2591SysfsDriver::new(Some(&mut device_config)).is_err());
+}
+
+#[test]
+fn smoothing_alpha_defaults_to_disabled() {
+    let mut config = serde_json::to_value(Bmp280SysfsConfig::default()).unwrap();
+    config.as_object_mut().unwrap().remove("smoothing_alpha");
+    let config: Bmp280SysfsConfig = serde_json::from_value(config).unwrap();
+    assert_eq!(config.smoothing_alpha, 0.0);
+}
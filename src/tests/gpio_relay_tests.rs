@@ -0,0 +1,139 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::capabilities::SwitchCapable;
+use crate::config::DeviceConfig;
+use crate::device::{DeviceDriver, DeviceError, DeviceServer};
+use crate::drivers::gpio_relay::{GpioRelay, GpioRelayConfig, RelayOutput};
+use super::support;
+
+// Records every line level written to it, and like a real pin resets the line on drop unless told otherwise
+struct FakeOutput {
+    levels: Arc<Mutex<Vec<bool>>>,
+    reset_on_drop: bool,
+    was_reset: Arc<AtomicBool>,
+}
+
+impl FakeOutput {
+    fn new(levels: &Arc<Mutex<Vec<bool>>>) -> Self {
+        Self { levels: levels.clone(), reset_on_drop: true, was_reset: Arc::default() }
+    }
+}
+
+impl RelayOutput for FakeOutput {
+    fn set_line(&mut self, high: bool) -> Result<(), DeviceError> {
+        self.levels.lock().push(high);
+        Ok(())
+    }
+
+    fn keep_level_on_release(&mut self) {
+        self.reset_on_drop = false;
+    }
+}
+
+impl Drop for FakeOutput {
+    fn drop(&mut self) {
+        self.was_reset.store(self.reset_on_drop, Ordering::SeqCst);
+    }
+}
+
+fn make_driver(config: GpioRelayConfig) -> GpioRelay {
+    support::build_driver("gpio_relay", config).expect("failed to build driver")
+}
+
+fn attach_fake(relay: &mut GpioRelay) -> Arc<Mutex<Vec<bool>>> {
+    let levels = Arc::new(Mutex::new(Vec::new()));
+    relay.attach(Box::new(FakeOutput::new(&levels))).expect("failed to attach output");
+    levels
+}
+
+#[test]
+fn relay_state_set_and_get() {
+    let mut relay = make_driver(GpioRelayConfig::default());
+    // the relay has to be started before it can switch
+    assert!(matches!(relay.set_state(true), Err(DeviceError::InvalidOperation(_))));
+    assert!(matches!(relay.get_state(), Err(DeviceError::InvalidOperation(_))));
+
+    let levels = attach_fake(&mut relay);
+    assert!(relay.is_running());
+    assert_eq!(relay.get_state().unwrap(), false);
+
+    relay.set_state(true).unwrap();
+    assert_eq!(relay.get_state().unwrap(), true);
+    assert_eq!(relay.toggle().unwrap(), false);
+    assert_eq!(relay.get_state().unwrap(), false);
+    assert_eq!(*levels.lock(), vec![false, true, false]);
+}
+
+#[test]
+fn active_low_relay_inverts_the_line() {
+    let mut config = GpioRelayConfig::default();
+    config.active_low = true;
+    let mut relay = make_driver(config);
+    assert!(relay.line_level(false));
+    assert!(!relay.line_level(true));
+
+    let levels = attach_fake(&mut relay);
+    relay.set_state(true).unwrap();
+    assert_eq!(relay.get_state().unwrap(), true);
+    // off is written as a high line
+    assert_eq!(*levels.lock(), vec![true, false]);
+}
+
+#[test]
+fn relay_returns_to_default_state_on_unload() {
+    let mut config = GpioRelayConfig::default();
+    config.active_low = true;
+    config.default_state = false;
+    let mut relay = make_driver(config);
+    let levels = attach_fake(&mut relay);
+
+    relay.set_state(true).unwrap();
+    relay.stop(&mut DeviceServer::new()).expect("failed to stop relay");
+    assert!(!relay.is_running());
+    // safe state on load, on, then back to the safe state
+    assert_eq!(*levels.lock(), vec![true, false, true]);
+    assert!(matches!(relay.get_state(), Err(DeviceError::InvalidOperation(_))));
+    assert!(matches!(relay.stop(&mut DeviceServer::new()), Err(DeviceError::InvalidOperation(_))));
+
+    // a relay that defaults to on starts and stops on
+    let mut config = GpioRelayConfig::default();
+    config.default_state = true;
+    let mut relay = make_driver(config);
+    let levels = attach_fake(&mut relay);
+    assert_eq!(relay.get_state().unwrap(), true);
+    relay.set_state(false).unwrap();
+    assert!(relay.detach().is_some());
+    assert_eq!(*levels.lock(), vec![true, false, true]);
+}
+
+#[test]
+fn relay_config_defaults() {
+    let mut device_config = DeviceConfig::new("gpio_relay".to_string(), None, serde_json::json!({ "pin": 4 }));
+    let relay = GpioRelay::new(Some(&mut device_config)).expect("failed to build driver");
+    assert!(relay.line_level(true));
+    assert_eq!(relay.required_controllers(), vec!["raw"]);
+
+    // missing data gets the default config written
+    let mut device_config = DeviceConfig::new("gpio_relay".to_string(), None, serde_json::Value::Null);
+    assert!(matches!(GpioRelay::new(Some(&mut device_config)), Err(DeviceError::InvalidConfig(..))));
+    assert!(device_config.driver_data.is_object());
+}
+
+#[test]
+fn relay_keeps_its_safe_level_after_unload() {
+    let mut relay = make_driver(GpioRelayConfig::default());
+    let levels = Arc::new(Mutex::new(Vec::new()));
+    let output = FakeOutput::new(&levels);
+    let was_reset = output.was_reset.clone();
+    relay.attach(Box::new(output)).expect("failed to attach output");
+
+    relay.set_state(true).unwrap();
+    relay.stop(&mut DeviceServer::new()).expect("failed to stop relay");
+    // the output is gone, but the line was left driven at the safe level
+    assert_eq!(*levels.lock(), vec![false, true, false]);
+    assert!(!was_reset.load(Ordering::SeqCst));
+    assert_eq!(Arc::strong_count(&levels), 1);
+}
@@ -1,5 +1,10 @@
-use crate::gpio::{GpioBorrowChecker, GpioError, PinState};
-use std::collections::HashMap;
+use crate::bus::raw::ensure_line_level;
+use crate::bus::raw_sysfs::SysfsRawBusController;
+use crate::bus::GpioController;
+use crate::gpio::{Debouncer, GpioBorrowChecker, GpioError, PinDirection, PinState, DEBOUNCE_MAX_WINDOWS};
+use parking_lot::RwLock;
+use std::{collections::{HashMap, VecDeque}, fs, sync::Arc, time::Duration};
+use uuid::Uuid;
 
 #[test]
 fn has_pin_test() {
@@ -146,3 +151,177 @@ fn borrow_one_and_release() {
     let r = r.unwrap();
     assert_eq!(gpio.release(&r), Ok(()));
 }
+
+#[test]
+fn active_low_pins_invert_values() {
+    let mut pin_map = HashMap::new();
+    pin_map.insert(2, PinState::new(2, 12));
+    pin_map.insert(3, PinState::new(3, 13).with_active_low(true));
+    let gpio = GpioBorrowChecker::new(pin_map);
+
+    let normal = gpio.get(&2).unwrap();
+    assert!(!normal.is_active_low());
+    assert_eq!(normal.apply_polarity(0), 0);
+    assert_eq!(normal.apply_polarity(1), 1);
+
+    // writing 1 drives the line low, and a low line reads back as 1
+    let inverted = gpio.get(&3).unwrap();
+    assert!(inverted.is_active_low());
+    assert_eq!(inverted.apply_polarity(1), 0);
+    assert_eq!(inverted.apply_polarity(0), 1);
+    assert_eq!(inverted.apply_polarity(inverted.apply_polarity(1)), 1);
+
+    // any non-zero value counts as active
+    assert_eq!(inverted.apply_polarity(255), 0);
+}
+
+#[test]
+fn pin_state_tracks_direction_and_value() {
+    let mut pin_map = HashMap::new();
+    pin_map.insert(2, PinState::new(2, 12));
+    let mut gpio = GpioBorrowChecker::new(pin_map);
+
+    let state = gpio.get(&2).unwrap();
+    assert!(!state.is_leased());
+    assert_eq!(state.direction(), None);
+    assert_eq!(state.last_value(), None);
+
+    let lease = gpio.borrow_one(2).unwrap();
+    gpio.record_direction(2, PinDirection::Output).unwrap();
+    gpio.record_value(2, 1).unwrap();
+    let state = gpio.get(&2).unwrap();
+    assert!(state.is_leased());
+    assert_eq!(state.direction(), Some(PinDirection::Output));
+    assert_eq!(state.last_value(), Some(1));
+
+    gpio.record_value(2, 0).unwrap();
+    assert_eq!(gpio.get(&2).unwrap().last_value(), Some(0));
+
+    assert_eq!(gpio.record_value(7, 1), Err(GpioError::PinNotFound(7)));
+    assert_eq!(gpio.record_direction(7, PinDirection::Input), Err(GpioError::PinNotFound(7)));
+
+    gpio.release(&lease).unwrap();
+    let state = gpio.get(&2).unwrap();
+    assert_eq!(state.direction(), None);
+    assert_eq!(state.last_value(), None);
+}
+
+#[test]
+fn sysfs_pin_without_gpiochip_reports_path() {
+    // gpiochip0 only covers 0-27, pin 2 maps to 40 which no chip provides
+    let root = std::env::temp_dir().join(format!("nvos_gpio_{}", Uuid::new_v4()));
+    fs::create_dir_all(root.join("gpiochip0")).unwrap();
+    fs::write(root.join("gpiochip0/base"), "0\n").unwrap();
+    fs::write(root.join("gpiochip0/ngpio"), "28\n").unwrap();
+
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(HashMap::from([(2, PinState::new(2, 40))]))));
+    let mut controller = SysfsRawBusController::with_sysfs_root(&gpio, &root).expect("failed to build controller");
+
+    let pin_path = root.join("gpio40").display().to_string();
+    match controller.open_out(2) {
+        Err(GpioError::PinNodeNotFound(2, path)) => assert_eq!(path, pin_path),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a pin no gpiochip provides"),
+    }
+
+    assert!(gpio.read().can_borrow_one(2));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn sysfs_pins_are_opened_with_their_polarity() {
+    // the nodes already exist, as if the pins were exported before
+    let root = std::env::temp_dir().join(format!("nvos_gpio_{}", Uuid::new_v4()));
+    fs::create_dir_all(root.join("gpio40")).unwrap();
+    fs::create_dir_all(root.join("gpio41")).unwrap();
+
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(HashMap::from([
+        (2, PinState::new(2, 40).with_active_low(true)),
+        (3, PinState::new(3, 41)),
+    ]))));
+    let mut controller = SysfsRawBusController::with_sysfs_root(&gpio, &root).expect("failed to build controller");
+
+    let inverted = controller.open_out(2).expect("failed to open the active low pin");
+    assert_eq!(fs::read_to_string(root.join("gpio40/active_low")).unwrap(), "1");
+    assert_eq!(fs::read_to_string(root.join("gpio40/direction")).unwrap(), "out");
+
+    let normal = controller.open_in(3).expect("failed to open the pin");
+    assert_eq!(fs::read_to_string(root.join("gpio41/active_low")).unwrap(), "0");
+    assert_eq!(fs::read_to_string(root.join("gpio41/direction")).unwrap(), "in");
+
+    controller.close(inverted).unwrap();
+    controller.close(normal).unwrap();
+    assert!(gpio.read().can_borrow_one(2));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn raw_pins_handed_out_directly_reject_active_low() {
+    let gpio = GpioBorrowChecker::new(HashMap::from([
+        (2, PinState::new(2, 12)),
+        (3, PinState::new(3, 13).with_active_low(true)),
+    ]));
+
+    // rppal pins always work on line levels, an active low pin would silently lose its inversion
+    assert_eq!(ensure_line_level(&gpio, 2), Ok(()));
+    assert!(matches!(ensure_line_level(&gpio, 3), Err(GpioError::Unsupported(_))));
+    assert_eq!(ensure_line_level(&gpio, 7), Err(GpioError::PinNotFound(7)));
+}
+
+#[test]
+fn debouncer_waits_out_bounces() {
+    let mut debouncer = Debouncer::new(Duration::from_millis(4));
+    // a switch closing: the contacts bounce a few times before the line stays high
+    let samples = [0, 1, 0, 1, 1, 0, 1, 1, 1, 1, 1];
+    let settled: Vec<Option<u8>> = samples.iter().map(|x| debouncer.push(*x)).collect();
+    assert_eq!(settled[..9], [None; 9]);
+    assert_eq!(settled[9..], [Some(1), Some(1)]);
+
+    // a window shorter than one sample still needs a sample
+    assert_eq!(Debouncer::new(Duration::ZERO).push(0), Some(0));
+}
+
+// Plays back a fixed sequence of levels, then keeps returning the last one
+struct BouncingInput {
+    levels: VecDeque<u8>,
+    last: u8,
+}
+
+impl GpioController for BouncingInput {
+    fn read_pin(&mut self, _pin: u8) -> Result<u8, GpioError> {
+        if let Some(level) = self.levels.pop_front() {
+            self.last = level;
+        }
+
+        Ok(self.last)
+    }
+
+    fn write_pin(&mut self, _pin: u8, _value: u8) -> Result<(), GpioError> {
+        Err(GpioError::Unsupported("input only".to_string()))
+    }
+
+    fn set_direction(&mut self, _pin: u8, _direction: PinDirection) -> Result<(), GpioError> {
+        Ok(())
+    }
+
+    fn release_pin(&mut self, _pin: u8) -> Result<(), GpioError> {
+        Ok(())
+    }
+
+    fn holds_pin(&self, _pin: u8) -> bool {
+        true
+    }
+}
+
+#[test]
+fn debounced_read_returns_settled_value() {
+    let mut input = BouncingInput { levels: VecDeque::from(vec![1, 0, 1, 0, 0, 1, 0]), last: 0 };
+    assert_eq!(input.read_debounced(2, 3), Ok(0));
+    // the bounces and the window were all read
+    assert!(input.levels.is_empty());
+
+    // a pin that never settles gives up instead of blocking forever
+    let window_samples = 3 * DEBOUNCE_MAX_WINDOWS as usize;
+    let mut input = BouncingInput { levels: (0..window_samples).map(|x| (x % 2) as u8).collect(), last: 0 };
+    assert!(matches!(input.read_debounced(2, 3), Err(GpioError::Other(_))));
+}
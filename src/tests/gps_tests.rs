@@ -0,0 +1,754 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::capabilities::{
+    format_coordinate, BarometerCapable, Capability, CapabilityId, CoordinateFormat, GpsCapable, SensorCapable,
+};
+use crate::config::DeviceConfig;
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceLink, DeviceServer, DeviceServerBuilder};
+use crate::events::{DeviceEventKind, DeviceEvents};
+use crate::rpc::barometer::BarometerService;
+use crate::rpc::gps::{gps_server::Gps, GpsRequest, GpsService};
+use crate::rpc::rate_limit;
+use intertrait::cast_to;
+use nmea::{Nmea, Satellite};
+use parking_lot::{Mutex, RwLock};
+use tonic::Request;
+
+use crate::drivers::geofence::{Geofence, GeofenceRegion, GeofenceTracker, GeofenceTransition};
+use crate::drivers::gps_uart::{
+    detect_baud_rate, is_valid_nmea, read_error_backoff, BaudProbe, GpsState, GpsWorker, NmeaSource, ReadMode,
+    SentenceAssembler, UartGps, UartGpsConfig, WorkerMessage, DISCONNECT_ERROR_THRESHOLD, MAX_REOPEN_ATTEMPTS,
+};
+
+const GGA_SENTENCE: &str = "$GPGGA,092750.000,5321.6802,N,00630.3372,W,1,8,1.03,61.7,M,55.2,M,,*76\r\n";
+
+// Only produces readable NMEA when set to the module's real baud rate
+struct FakeStream {
+    actual_rate: u32,
+    current_rate: u32,
+    probed_rates: Vec<u32>,
+}
+
+impl BaudProbe for FakeStream {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), DeviceError> {
+        self.current_rate = baud_rate;
+        self.probed_rates.push(baud_rate);
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        let data: Vec<u8> = match self.current_rate == self.actual_rate {
+            true => GGA_SENTENCE.as_bytes().to_vec(),
+            false => vec![0xF0, 0x80, 0x24, 0xFE, 0x0A, 0x2A, 0x13],
+        };
+
+        let count = data.len().min(buf.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        Ok(count)
+    }
+}
+
+#[test]
+fn nmea_checksum_validation() {
+    assert!(is_valid_nmea(GGA_SENTENCE));
+    assert!(!is_valid_nmea("$GPGGA,092750.000,5321.6802,N,00630.3372,W,1,8,1.03,61.7,M,55.2,M,,*77"));
+    assert!(!is_valid_nmea("GPGGA,092750.000*76"));
+    assert!(!is_valid_nmea("$*00"));
+    assert!(!is_valid_nmea("\u{fffd}$\u{fffd}\n"));
+}
+
+#[test]
+fn gps_autodetect_finds_rate() {
+    let mut stream = FakeStream { actual_rate: 38400, current_rate: 0, probed_rates: Vec::new() };
+    let rate = detect_baud_rate(&mut stream, &[9600, 38400, 115200], Duration::from_millis(20)).unwrap();
+
+    assert_eq!(rate, Some(38400));
+    // detection stops at the first rate that produced a valid sentence
+    assert_eq!(stream.probed_rates, vec![9600, 38400]);
+}
+
+#[test]
+fn gps_autodetect_no_data() {
+    let mut stream = FakeStream { actual_rate: 4800, current_rate: 0, probed_rates: Vec::new() };
+    let rate = detect_baud_rate(&mut stream, &[9600, 38400, 115200], Duration::from_millis(10)).unwrap();
+
+    assert_eq!(rate, None);
+    assert_eq!(stream.probed_rates, vec![9600, 38400, 115200]);
+}
+
+#[test]
+fn coordinate_decimal_degrees() {
+    assert_eq!(format_coordinate(-33.8688, true, CoordinateFormat::DecimalDegrees, 4), "-33.8688");
+    assert_eq!(format_coordinate(151.2093, false, CoordinateFormat::DecimalDegrees, 4), "151.2093");
+    assert_eq!(format_coordinate(-74.006, false, CoordinateFormat::DecimalDegrees, 4), "-74.0060");
+    assert_eq!(format_coordinate(40.7128, true, CoordinateFormat::DecimalDegrees, 0), "41");
+}
+
+#[test]
+fn coordinate_degrees_minutes_seconds() {
+    assert_eq!(format_coordinate(-33.8688, true, CoordinateFormat::DegreesMinutesSeconds, 2), "33°52'7.68\" S");
+    assert_eq!(format_coordinate(151.2093, false, CoordinateFormat::DegreesMinutesSeconds, 2), "151°12'33.48\" E");
+    assert_eq!(format_coordinate(40.7128, true, CoordinateFormat::DegreesMinutesSeconds, 0), "40°42'46\" N");
+    assert_eq!(format_coordinate(-74.006, false, CoordinateFormat::DegreesMinutesSeconds, 1), "74°0'21.6\" W");
+}
+
+#[test]
+fn coordinate_degrees_decimal_minutes() {
+    assert_eq!(format_coordinate(-33.8688, true, CoordinateFormat::DegreesDecimalMinutes, 3), "33°52.128' S");
+    assert_eq!(format_coordinate(151.2093, false, CoordinateFormat::DegreesDecimalMinutes, 3), "151°12.558' E");
+    assert_eq!(format_coordinate(40.7128, true, CoordinateFormat::DegreesDecimalMinutes, 3), "40°42.768' N");
+    assert_eq!(format_coordinate(-74.006, false, CoordinateFormat::DegreesDecimalMinutes, 3), "74°0.360' W");
+}
+
+#[test]
+fn coordinate_rounding_carries() {
+    assert_eq!(format_coordinate(10.9999999, true, CoordinateFormat::DegreesMinutesSeconds, 2), "11°0'0.00\" N");
+    assert_eq!(format_coordinate(-10.9999999, false, CoordinateFormat::DegreesDecimalMinutes, 2), "11°0.00' W");
+}
+
+const RMC_SENTENCE: &str = "$GPRMC,092750.000,A,5321.6802,N,00630.3372,W,0.02,31.66,280511,,,A*43\r\n";
+
+#[test]
+fn sentences_reassembled_at_every_split() {
+    let stream = format!("{}{}", GGA_SENTENCE, RMC_SENTENCE);
+    let bytes = stream.as_bytes();
+
+    for split in 0..=bytes.len() {
+        let mut assembler = SentenceAssembler::new(1024);
+        let mut sentences = assembler.push(&bytes[..split]);
+        sentences.extend(assembler.push(&bytes[split..]));
+
+        assert_eq!(sentences, vec![GGA_SENTENCE.trim().to_string(), RMC_SENTENCE.trim().to_string()], "split at {}", split);
+        assert!(assembler.pending().is_empty());
+    }
+}
+
+#[test]
+fn sentences_reassembled_from_small_chunks() {
+    let stream = format!("{}{}{}", GGA_SENTENCE, RMC_SENTENCE, GGA_SENTENCE);
+    for chunk_size in [1, 3, 7, 64] {
+        let mut assembler = SentenceAssembler::new(1024);
+        let sentences: Vec<String> = stream.as_bytes().chunks(chunk_size).flat_map(|x| assembler.push(x)).collect();
+
+        assert_eq!(sentences.len(), 3, "chunk size {}", chunk_size);
+        assert!(sentences.iter().all(|x| is_valid_nmea(x)), "chunk size {}", chunk_size);
+    }
+}
+
+#[test]
+fn sentence_assembler_keeps_partial_and_skips_blank_lines() {
+    let mut assembler = SentenceAssembler::new(1024);
+    assert!(assembler.push(b"\r\n\n$GPGGA,0927").is_empty());
+    assert_eq!(assembler.pending(), b"$GPGGA,0927");
+
+    // unterminated garbage past the limit is dropped instead of buffered forever
+    let mut assembler = SentenceAssembler::new(16);
+    assert!(assembler.push(&[b'x'; 32]).is_empty());
+    assert!(assembler.pending().is_empty());
+    assert_eq!(assembler.push(GGA_SENTENCE.as_bytes()), vec![GGA_SENTENCE.trim().to_string()]);
+}
+
+#[test]
+fn gps_requires_uart() {
+    let mut device_config = DeviceConfig::new(
+        "gps_uart".to_string(),
+        None,
+        serde_json::to_value(UartGpsConfig::default()).unwrap(),
+    );
+
+    let gps = UartGps::new(Some(&mut device_config)).expect("failed to build driver");
+    assert_eq!(gps.required_controllers(), vec!["uart"]);
+}
+
+struct StubBarometer {
+    is_loaded: bool,
+}
+
+impl DeviceDriver for StubBarometer {
+    fn name(&self) -> String {
+        "stub_barometer".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(StubBarometer { is_loaded: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for StubBarometer {}
+
+impl SensorCapable for StubBarometer {
+    fn get_supported_gains(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_supported_intervals(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_gain(&self, _reading: CapabilityId) -> Result<u16, DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+
+    fn set_gain(&mut self, _reading: CapabilityId, _gain_id: u8) -> Result<(), DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+
+    fn get_interval(&self) -> Result<u16, DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+
+    fn set_interval(&mut self, _interval_id: u8) -> Result<(), DeviceError> {
+        Err(DeviceError::NotSupported)
+    }
+}
+
+#[cast_to]
+impl BarometerCapable for StubBarometer {
+    fn get_pressure(&mut self) -> Result<f32, DeviceError> {
+        Ok(100000.0)
+    }
+
+    fn get_altitude(&mut self) -> Result<f32, DeviceError> {
+        Ok(110.0)
+    }
+
+    fn get_reference_pressure(&self) -> Result<f32, DeviceError> {
+        Ok(1013.25)
+    }
+
+    fn set_reference_pressure(&mut self, _pressure_hpa: f32) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn reset_filter(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+// Reports 100 m and links the device named "baro" on start, like UartGps does with its barometer config
+struct StubGps {
+    barometer: Option<DeviceLink>,
+    has_fix: bool,
+    is_loaded: bool,
+}
+
+impl DeviceDriver for StubGps {
+    fn name(&self) -> String {
+        "stub_gps".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(StubGps { barometer: None, has_fix: true, is_loaded: false })
+    }
+
+    fn start(&mut self, parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.barometer = Some(parent.link_device("baro")?);
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.barometer = None;
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for StubGps {}
+
+#[cast_to]
+impl GpsCapable for StubGps {
+    fn get_location(&self) -> Result<(f64, f64), DeviceError> {
+        Ok((0.0, 0.0))
+    }
+
+    fn get_altitude(&self) -> Result<f32, DeviceError> {
+        Ok(100.0)
+    }
+
+    fn has_fix(&self) -> Result<bool, DeviceError> {
+        Ok(self.has_fix)
+    }
+
+    fn get_speed(&self) -> Result<f32, DeviceError> {
+        Ok(0.0)
+    }
+
+    fn get_heading(&self) -> Result<f32, DeviceError> {
+        Ok(0.0)
+    }
+
+    fn get_satellites(&self) -> Result<Vec<Satellite>, DeviceError> {
+        Ok(Vec::new())
+    }
+
+    fn get_nmea(&self) -> Result<Nmea, DeviceError> {
+        Ok(Nmea::default())
+    }
+
+    fn get_vertical_accuracy(&self) -> Result<f32, DeviceError> {
+        Ok(3.0)
+    }
+
+    fn get_horizontal_accuracy(&self) -> Result<f32, DeviceError> {
+        Ok(3.0)
+    }
+
+    fn get_barometer(&self) -> Option<(&DeviceLink, f32)> {
+        self.barometer.as_ref().map(|barometer| (barometer, 0.7))
+    }
+}
+
+fn fused_altitude(server: &DeviceServer) -> f32 {
+    let gps = server.get_device_with_name("gps").unwrap();
+    gps.as_capability_ref::<dyn GpsCapable>().unwrap().get_fused_altitude().unwrap()
+}
+
+#[test]
+fn fused_altitude_blends_linked_barometer() {
+    let mut server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<StubBarometer>(None, Some("baro".to_string())).unwrap())
+        .add_device(Device::new::<StubGps>(None, Some("gps".to_string())).unwrap())
+        .build(true)
+        .expect("failed to build server");
+
+    // 70% of the barometer's 110 m, 30% of the GPS's 100 m
+    assert!((fused_altitude(&server) - 107.0).abs() < 1e-4, "unexpected altitude {}", fused_altitude(&server));
+
+    // without a fix only the barometer is used
+    server.get_device_with_name_mut("gps").unwrap().as_mut().as_any_mut().downcast_mut::<StubGps>().unwrap().has_fix = false;
+    assert!((fused_altitude(&server) - 110.0).abs() < 1e-4);
+
+    // and without the barometer only the GPS
+    let barometer = server.get_device_with_name("baro").unwrap().address();
+    server.remove_device(&barometer).expect("failed to remove barometer");
+    server.get_device_with_name_mut("gps").unwrap().as_mut().as_any_mut().downcast_mut::<StubGps>().unwrap().has_fix = true;
+    assert!((fused_altitude(&server) - 100.0).abs() < 1e-4);
+}
+
+#[tokio::test]
+async fn fused_altitude_rpc_reads_barometer_through_its_service() {
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<StubBarometer>(None, Some("baro".to_string())).unwrap())
+        .add_device(Device::new::<StubGps>(None, Some("gps".to_string())).unwrap())
+        .build(true)
+        .expect("failed to build server");
+    let server = Arc::new(RwLock::new(server));
+    let limits = HashMap::from([(rate_limit::GET_ALTITUDE.to_string(), 60_000)]);
+    let barometer = Arc::new(BarometerService::with_read_limits(&server, &limits));
+    let service = GpsService::new(&server).with_barometer_service(barometer.clone());
+
+    let response = service.get_fused_altitude(Request::new(GpsRequest { address: "gps".to_string() })).await.unwrap();
+    assert!((response.get_ref().altitude - 107.0).abs() < 1e-4, "unexpected altitude {}", response.get_ref().altitude);
+
+    // the barometer read went through the barometer service's cache
+    let cached = barometer.read_altitude("baro").await.unwrap();
+    assert!(cached.cached);
+}
+
+#[test]
+fn linking_unknown_device_fails() {
+    let server = DeviceServer::new();
+    assert!(server.link_device("baro").is_err());
+
+    let result = DeviceServerBuilder::configure()
+        .add_device(Device::new::<StubGps>(None, Some("gps".to_string())).unwrap())
+        .build(true);
+    assert!(result.is_err(), "GPS started without its barometer");
+}
+
+#[test]
+fn stale_fix_is_reported_lost() {
+    let staleness = Duration::from_millis(2000);
+    let received = Instant::now();
+    let mut state = GpsState::default();
+    assert!(!state.has_fix(received, staleness));
+    assert_eq!(state.fix_age(received), Duration::MAX);
+
+    assert!(state.parse(GGA_SENTENCE.trim(), received));
+    assert!(state.parse(RMC_SENTENCE.trim(), received));
+    assert!(state.has_fix(received + Duration::from_millis(1500), staleness));
+
+    // nothing parsed since, the receiver is treated as having lost the signal
+    let later = received + Duration::from_millis(5000);
+    assert!(!state.has_fix(later, staleness));
+    assert_eq!(state.fix_age(later), Duration::from_millis(5000));
+
+    // a failed parse doesn't refresh the fix
+    assert!(!state.parse("$GPGGA,garbage*00", later));
+    assert!(!state.has_fix(later, staleness));
+
+    assert!(state.parse(RMC_SENTENCE.trim(), later));
+    assert!(state.has_fix(later, staleness));
+}
+
+// Receiver sending a sentence every interval into a small FIFO, sentences that don't fit are lost like on a UART overrun
+struct FastReceiver {
+    started: Instant,
+    interval: Duration,
+    sent: usize,
+    fifo: VecDeque<u8>,
+    capacity: usize,
+    delivered: Arc<AtomicUsize>,
+    overruns: Arc<AtomicUsize>,
+}
+
+impl FastReceiver {
+    fn receive(&mut self) {
+        let due = (self.started.elapsed().as_nanos() / self.interval.as_nanos()) as usize;
+        while self.sent < due {
+            if self.fifo.len() + GGA_SENTENCE.len() > self.capacity {
+                self.overruns.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.fifo.extend(GGA_SENTENCE.as_bytes());
+                self.delivered.fetch_add(1, Ordering::SeqCst);
+            }
+
+            self.sent += 1;
+        }
+    }
+}
+
+impl NmeaSource for FastReceiver {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        self.receive();
+        if self.fifo.is_empty() {
+            // a blocking read waits for the next byte
+            thread::sleep(self.interval);
+            self.receive();
+        }
+
+        let count = self.fifo.len().min(buf.len());
+        for (i, byte) in self.fifo.drain(..count).enumerate() {
+            buf[i] = byte;
+        }
+
+        Ok(count)
+    }
+}
+
+// Runs a worker over a fast receiver for a while, returns the sentences lost to overruns
+fn run_fast_receiver(read_mode: ReadMode, poll_interval: u32) -> usize {
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let overruns = Arc::new(AtomicUsize::new(0));
+    let receiver = FastReceiver {
+        started: Instant::now(),
+        interval: Duration::from_millis(2),
+        sent: 0,
+        fifo: VecDeque::new(),
+        capacity: GGA_SENTENCE.len() * 16,
+        delivered: delivered.clone(),
+        overruns: overruns.clone(),
+    };
+
+    let state = Arc::new(Mutex::new(GpsState::default()));
+    let (command_sender, command_receiver) = mpsc::channel();
+    let (callback_sender, callback_receiver) = mpsc::channel();
+    let worker_state = state.clone();
+    thread::spawn(move || {
+        GpsWorker::new(receiver, command_receiver, callback_sender, poll_interval, 256, read_mode, worker_state).run();
+    });
+
+    thread::sleep(Duration::from_millis(300));
+    let stop_requested = Instant::now();
+    command_sender.send(WorkerMessage::Shutdown).unwrap();
+    callback_receiver.recv_timeout(Duration::from_secs(5)).expect("worker did not shut down");
+    if read_mode == ReadMode::Continuous {
+        assert!(stop_requested.elapsed() < Duration::from_millis(100), "continuous worker was slow to shut down");
+    }
+
+    assert!(delivered.load(Ordering::SeqCst) > 0);
+    assert!(state.lock().nmea.latitude.is_some());
+    overruns.load(Ordering::SeqCst)
+}
+
+#[test]
+fn continuous_read_keeps_up_with_fast_receiver() {
+    assert_eq!(run_fast_receiver(ReadMode::Continuous, 200), 0);
+
+    // the polled worker only drains the FIFO every 200ms and loses sentences in between
+    assert!(run_fast_receiver(ReadMode::Polled, 200) > 0);
+}
+
+#[test]
+fn read_mode_defaults_to_polled() {
+    let mut data = serde_json::to_value(UartGpsConfig::default()).unwrap();
+    data.as_object_mut().unwrap().remove("read_mode");
+    let config: UartGpsConfig = serde_json::from_value(data).unwrap();
+    assert_eq!(config.read_mode, ReadMode::Polled);
+
+    let mut data = serde_json::to_value(UartGpsConfig::default()).unwrap();
+    data["read_mode"] = serde_json::Value::String("Continuous".to_string());
+    let config: UartGpsConfig = serde_json::from_value(data).unwrap();
+    assert_eq!(config.read_mode, ReadMode::Continuous);
+}
+
+// Unplugged receiver, every read fails at once. Reopening works after a set number of attempts, if ever.
+struct UnpluggedReceiver {
+    reads: Arc<Mutex<Vec<Instant>>>,
+    reopens: Arc<AtomicUsize>,
+    reopen_after: Option<usize>,
+    reconnected: bool,
+}
+
+impl NmeaSource for UnpluggedReceiver {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        self.reads.lock().push(Instant::now());
+        if !self.reconnected {
+            return Err(DeviceError::hardware_error("read failed: device disconnected"));
+        }
+
+        buf[..GGA_SENTENCE.len()].copy_from_slice(GGA_SENTENCE.as_bytes());
+        Ok(GGA_SENTENCE.len())
+    }
+
+    fn reopen(&mut self) -> Result<(), DeviceError> {
+        let attempt = self.reopens.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.reopen_after {
+            Some(after) if attempt >= after => {
+                self.reconnected = true;
+                Ok(())
+            },
+            _ => Err(DeviceError::hardware_error("no such device")),
+        }
+    }
+}
+
+struct UnpluggedWorker {
+    state: Arc<Mutex<GpsState>>,
+    reads: Arc<Mutex<Vec<Instant>>>,
+    reopens: Arc<AtomicUsize>,
+    commands: mpsc::Sender<WorkerMessage>,
+    shutdown_callback: mpsc::Receiver<()>,
+}
+
+impl UnpluggedWorker {
+    fn shutdown(&self) {
+        self.commands.send(WorkerMessage::Shutdown).unwrap();
+        self.shutdown_callback.recv_timeout(Duration::from_secs(5)).expect("worker did not shut down");
+    }
+}
+
+fn spawn_unplugged_worker(reopen_after: Option<usize>) -> UnpluggedWorker {
+    let reads = Arc::new(Mutex::new(Vec::new()));
+    let reopens = Arc::new(AtomicUsize::new(0));
+    let receiver = UnpluggedReceiver { reads: reads.clone(), reopens: reopens.clone(), reopen_after, reconnected: false };
+
+    // a fix from before the receiver went away
+    let state = Arc::new(Mutex::new(GpsState::default()));
+    state.lock().parse(GGA_SENTENCE.trim(), Instant::now());
+    assert!(state.lock().nmea.latitude.is_some());
+
+    let (command_sender, command_receiver) = mpsc::channel();
+    let (callback_sender, callback_receiver) = mpsc::channel();
+    let worker_state = state.clone();
+    thread::spawn(move || {
+        GpsWorker::new(receiver, command_receiver, callback_sender, 1000, 256, ReadMode::Continuous, worker_state).run();
+    });
+
+    UnpluggedWorker { state, reads, reopens, commands: command_sender, shutdown_callback: callback_receiver }
+}
+
+#[test]
+fn failing_reads_back_off_and_give_up() {
+    assert_eq!(read_error_backoff(1), Duration::from_millis(20));
+    assert_eq!(read_error_backoff(2), Duration::from_millis(40));
+    assert_eq!(read_error_backoff(5), Duration::from_millis(320));
+    assert_eq!(read_error_backoff(u32::MAX), Duration::from_secs(1));
+
+    let worker = spawn_unplugged_worker(None);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while worker.state.lock().failure().is_none() {
+        assert!(Instant::now() < deadline, "worker never gave up on the receiver");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // the disconnect threshold is reached instead of spinning on the port, each retry waits longer than the last
+    let reads = worker.reads.lock().clone();
+    assert_eq!(reads.len(), DISCONNECT_ERROR_THRESHOLD as usize);
+    for (i, gap) in reads.windows(2).map(|x| x[1] - x[0]).enumerate() {
+        assert!(gap >= read_error_backoff(i as u32 + 1), "read {} came after only {:?}", i + 2, gap);
+    }
+
+    assert_eq!(worker.reopens.load(Ordering::SeqCst), MAX_REOPEN_ATTEMPTS as usize);
+    assert!(worker.state.lock().nmea.latitude.is_none(), "stale fix survived the disconnect");
+    worker.shutdown();
+}
+
+#[test]
+fn reopened_receiver_resumes() {
+    let worker = spawn_unplugged_worker(Some(2));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while worker.reopens.load(Ordering::SeqCst) < 2 || worker.state.lock().nmea.latitude.is_none() {
+        assert!(Instant::now() < deadline, "worker never read from the reopened receiver");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(worker.state.lock().failure().is_none());
+    worker.shutdown();
+}
+
+fn nmea_sentence(body: &str) -> String {
+    let checksum = body.bytes().fold(0u8, |acc, x| acc ^ x);
+    format!("${}*{:02X}\r\n", body, checksum)
+}
+
+// GGA sentence with a fix at the given position
+fn gga_sentence(latitude: f64, longitude: f64) -> String {
+    let to_nmea = |value: f64| value.abs().trunc() * 100.0 + value.abs().fract() * 60.0;
+    let body = format!(
+        "GPGGA,092750.000,{:09.4},{},{:010.4},{},1,8,1.03,61.7,M,55.2,M,,",
+        to_nmea(latitude), if latitude < 0.0 { 'S' } else { 'N' },
+        to_nmea(longitude), if longitude < 0.0 { 'W' } else { 'E' }
+    );
+
+    nmea_sentence(&body)
+}
+
+fn test_fences() -> Vec<Geofence> {
+    vec![
+        Geofence {
+            name: "yard".to_string(),
+            region: GeofenceRegion::Circle { latitude: 53.36, longitude: -6.5, radius_meters: 500.0 },
+        },
+        Geofence {
+            name: "field".to_string(),
+            region: GeofenceRegion::Rectangle { min_latitude: 53.0, min_longitude: -7.0, max_latitude: 54.0, max_longitude: -6.0 },
+        },
+    ]
+}
+
+#[test]
+fn geofence_tracker_reports_transitions() {
+    let mut tracker = GeofenceTracker::new(test_fences());
+    assert_eq!(tracker.update(52.0, -6.5), vec![]);
+    assert_eq!(tracker.update(53.5, -6.5), vec![GeofenceTransition::Entered("field".to_string())]);
+    // ~330 m from the center of the yard
+    assert_eq!(tracker.update(53.363, -6.5), vec![GeofenceTransition::Entered("yard".to_string())]);
+    assert_eq!(tracker.active(), vec!["yard".to_string(), "field".to_string()]);
+    assert_eq!(tracker.update(53.363, -6.5), vec![]);
+    assert_eq!(tracker.update(53.5, -6.5), vec![GeofenceTransition::Exited("yard".to_string())]);
+    assert_eq!(tracker.update(55.0, -6.5), vec![GeofenceTransition::Exited("field".to_string())]);
+    assert!(tracker.active().is_empty());
+}
+
+#[test]
+fn invalid_geofences_rejected() {
+    let mut config = UartGpsConfig::default();
+    config.geofences = test_fences();
+    config.geofences[1].name = "yard".to_string();
+    let data = serde_json::to_value(&config).unwrap();
+    assert!(matches!(UartGps::new(Some(&mut DeviceConfig::new("gps_uart".to_string(), None, data))), Err(DeviceError::InvalidConfig(..))));
+
+    config.geofences = vec![Geofence { name: "yard".to_string(), region: GeofenceRegion::Circle { latitude: 91.0, longitude: 0.0, radius_meters: 10.0 } }];
+    let data = serde_json::to_value(&config).unwrap();
+    assert!(matches!(UartGps::new(Some(&mut DeviceConfig::new("gps_uart".to_string(), None, data))), Err(DeviceError::InvalidConfig(..))));
+
+    // fences are read from the config file with the shape as a tag
+    let fence: Geofence = serde_json::from_value(serde_json::json!({
+        "name": "yard", "shape": "Circle", "latitude": 53.36, "longitude": -6.5, "radius_meters": 500.0
+    })).unwrap();
+    assert_eq!(fence, test_fences()[0]);
+}
+
+// Replays one sentence per read, then has nothing more to send
+struct TrackReceiver {
+    sentences: VecDeque<String>,
+}
+
+impl NmeaSource for TrackReceiver {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        let sentence = match self.sentences.pop_front() {
+            Some(sentence) => sentence,
+            None => return Ok(0),
+        };
+
+        buf[..sentence.len()].copy_from_slice(sentence.as_bytes());
+        Ok(sentence.len())
+    }
+}
+
+#[test]
+fn worker_emits_geofence_events_along_track() {
+    let events = DeviceEvents::new();
+    let mut subscriber = events.subscribe();
+    let address = uuid::Uuid::new_v4();
+
+    // drives into the yard, loses the fix inside it, then leaves
+    let track = vec![
+        gga_sentence(52.0, -6.5),
+        gga_sentence(53.363, -6.5),
+        nmea_sentence("GPGGA,092751.000,,,,,0,0,,,M,,M,,"),
+        gga_sentence(53.363, -6.5),
+        gga_sentence(55.0, -6.5),
+    ];
+
+    let state = Arc::new(Mutex::new(GpsState::with_geofences(test_fences())));
+    let (command_sender, command_receiver) = mpsc::channel();
+    let (callback_sender, callback_receiver) = mpsc::channel();
+    let worker_state = state.clone();
+    let sink = events.sink(address);
+    thread::spawn(move || {
+        GpsWorker::new(TrackReceiver { sentences: track.into() }, command_receiver, callback_sender, 5, 256, ReadMode::Polled, worker_state)
+            .with_events(sink)
+            .run();
+    });
+
+    thread::sleep(Duration::from_millis(200));
+    command_sender.send(WorkerMessage::Shutdown).unwrap();
+    callback_receiver.recv_timeout(Duration::from_secs(5)).expect("worker did not shut down");
+
+    let mut received = Vec::new();
+    while let Ok(event) = subscriber.try_recv() {
+        assert_eq!(event.address, address);
+        received.push(event.kind);
+    }
+
+    assert_eq!(received, vec![
+        DeviceEventKind::GeofenceEntered { geofence: "yard".to_string() },
+        DeviceEventKind::GeofenceEntered { geofence: "field".to_string() },
+        DeviceEventKind::GeofenceExited { geofence: "yard".to_string() },
+        DeviceEventKind::GeofenceExited { geofence: "field".to_string() },
+    ]);
+    assert!(state.lock().geofences.active().is_empty());
+}
@@ -0,0 +1,590 @@
+use crate::bus::bus_lock::{BusLock, BusLockPolicy, MAX_PRIORITY_BYPASSES};
+use crate::bus::i2c::{I2CError, I2CPinDefinition, I2cAddress, I2cAddressing, I2cConfigData};
+use crate::bus::i2c_sysfs::{
+    read_register_pec, read_register_with, transfer_block, write_register_pec, write_register_with, SharedI2cBus, SmbusTransfer,
+    SysfsI2CBusController,
+};
+use crate::config::BusControllerConfig;
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::gpio::{GpioBorrowChecker, PinState};
+use crate::rpc::i2c_debug::{
+    i2c_debug_server::I2cDebug, AddressingMode, ControllerRegisterBus, I2cDebugService, ReadRegisterRequest, RegisterBus, RegisterIo,
+    WriteRegisterRequest,
+};
+use i2c_linux::I2c;
+use parking_lot::{Mutex, RwLock};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Result, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+#[derive(Debug, PartialEq)]
+enum Op {
+    Write(Vec<u8>),
+    Read(usize),
+    SlaveAddress(I2cAddress),
+    ReadByteData(u8),
+    WriteByteData(u8, u8),
+    ReadWordData(u8),
+    WriteWordData(u8, u16),
+}
+
+struct FakeTransaction {
+    ops: Vec<Op>,
+    data: Vec<u8>,
+}
+
+impl FakeTransaction {
+    fn new(data: Vec<u8>) -> Self {
+        Self { ops: Vec::new(), data }
+    }
+}
+
+impl Write for FakeTransaction {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.ops.push(Op::Write(buf.to_vec()));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for FakeTransaction {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data.drain(..len);
+        self.ops.push(Op::Read(len));
+        Ok(len)
+    }
+}
+
+#[test]
+fn block_read_single_transfer() {
+    let mut transaction = FakeTransaction::new(vec![0x11, 0x22, 0x33, 0x44]);
+    let mut buf = [0u8; 4];
+
+    transfer_block(&mut transaction, 0xB4, &mut buf).expect("block read failed");
+
+    assert_eq!(buf, [0x11, 0x22, 0x33, 0x44]);
+    assert_eq!(transaction.ops, vec![Op::Write(vec![0xB4]), Op::Read(4)]);
+}
+
+#[test]
+fn block_read_short_data() {
+    let mut transaction = FakeTransaction::new(vec![0x11, 0x22]);
+    let mut buf = [0u8; 4];
+
+    assert!(transfer_block(&mut transaction, 0xB4, &mut buf).is_err());
+}
+
+const SHARED_BUS_ID: u8 = 1;
+
+// Stands in for a sensor driver, it shares the bus the same way the sysfs drivers do
+struct SharedBusDevice {
+    bus: Option<SharedI2cBus>,
+}
+
+impl DeviceDriver for SharedBusDevice {
+    fn name(&self) -> String {
+        "shared_bus".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.bus.is_some()
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> std::result::Result<Self, DeviceError> where Self: Sized {
+        Ok(SharedBusDevice { bus: None })
+    }
+
+    fn start(&mut self, parent: &mut DeviceServer) -> std::result::Result<(), DeviceError> {
+        let mut i2c = parent.get_bus_mut::<SysfsI2CBusController>().unwrap();
+        let bus = i2c.get_with(SHARED_BUS_ID, |_| Ok(I2c::new(File::open("/dev/null")?)))
+            .map_err(|e| DeviceError::hardware_error(e.to_string()))?;
+
+        self.bus = Some(bus);
+        Ok(())
+    }
+
+    fn stop(&mut self, parent: &mut DeviceServer) -> std::result::Result<(), DeviceError> {
+        self.bus = None;
+        parent.get_bus_mut::<SysfsI2CBusController>().unwrap().close(SHARED_BUS_ID)
+            .map_err(|e| DeviceError::hardware_error(e.to_string()))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[test]
+fn bus_shared_until_last_device_stops() {
+    let mut pin_map = HashMap::new();
+    for pin in 2..4 {
+        pin_map.insert(pin, PinState::new(pin, pin + 10));
+    }
+
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+    let mut pin_config = HashMap::new();
+    pin_config.insert(SHARED_BUS_ID, I2CPinDefinition::new(2, 3));
+    let controller = SysfsI2CBusController::with_pin_config(&gpio, pin_config).expect("failed to build controller");
+
+    let first = uuid::Uuid::new_v4();
+    let second = uuid::Uuid::new_v4();
+    let mut server = DeviceServerBuilder::configure()
+        .add_bus(controller)
+        .add_device(Device::new::<SharedBusDevice>(Some(first), None).unwrap())
+        .add_device(Device::new::<SharedBusDevice>(Some(second), None).unwrap())
+        .build(true)
+        .expect("failed to build server");
+
+    assert_eq!(server.get_bus::<SysfsI2CBusController>().unwrap().user_count(SHARED_BUS_ID), 2);
+    assert!(!gpio.read().can_borrow_many(&[2, 3]));
+
+    server.stop_device(&first).expect("failed to stop first device");
+    assert_eq!(server.get_bus::<SysfsI2CBusController>().unwrap().user_count(SHARED_BUS_ID), 1);
+    assert!(!gpio.read().can_borrow_many(&[2, 3]), "bus was released while still in use");
+
+    server.stop_device(&second).expect("failed to stop second device");
+    assert_eq!(server.get_bus::<SysfsI2CBusController>().unwrap().user_count(SHARED_BUS_ID), 0);
+    assert!(gpio.read().can_borrow_many(&[2, 3]), "bus pins were not released");
+
+    // an unpaired close is rejected instead of underflowing the count
+    assert!(server.get_bus_mut::<SysfsI2CBusController>().unwrap().close(SHARED_BUS_ID).is_err());
+}
+
+#[test]
+fn fair_bus_lock_bounds_waits_under_contention() {
+    const DEVICES: usize = 4;
+    const ROUNDS: u64 = 20;
+
+    let data: I2cConfigData = serde_json::from_value(serde_json::json!({ "channels": {}, "lock_policies": { "1": "fair" } })).unwrap();
+    assert_eq!(data.lock_policies.get(&SHARED_BUS_ID), Some(&BusLockPolicy::Fair));
+
+    let mut pin_map = HashMap::new();
+    for pin in 2..4 {
+        pin_map.insert(pin, PinState::new(pin, pin + 10));
+    }
+
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+    let mut pin_config = HashMap::new();
+    pin_config.insert(SHARED_BUS_ID, I2CPinDefinition::new(2, 3));
+    let mut controller = SysfsI2CBusController::with_pin_config(&gpio, pin_config)
+        .expect("failed to build controller")
+        .with_lock_policy(SHARED_BUS_ID, BusLockPolicy::Fair);
+    assert_eq!(controller.lock_policy(SHARED_BUS_ID), BusLockPolicy::Fair);
+
+    let bus = controller.get_with(SHARED_BUS_ID, |_| Ok(I2c::new(File::open("/dev/null")?))).expect("failed to open bus");
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let threads: Vec<_> = (0..DEVICES).map(|device| {
+        let client = bus.client(&format!("device{}", device), 0);
+        let order = order.clone();
+        thread::spawn(move || {
+            for _ in 0..ROUNDS {
+                let _transaction = client.lock();
+                order.lock().push(device);
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    }).collect();
+
+    for thread in threads {
+        thread.join().expect("device thread panicked");
+    }
+
+    // a device that is waiting gets the bus before anyone that already had it can take it again
+    let order = order.lock();
+    for device in 0..DEVICES {
+        let turns: Vec<usize> = order.iter().enumerate().filter(|(_, x)| **x == device).map(|(i, _)| i).collect();
+        for pair in turns.windows(2) {
+            assert!(pair[1] - pair[0] <= DEVICES, "device{} waited {} turns: {:?}", device, pair[1] - pair[0] - 1, order);
+        }
+    }
+
+    let stats = controller.wait_stats();
+    assert_eq!(stats.len(), DEVICES);
+    for (device, (bus_id, client, stats)) in stats.iter().enumerate() {
+        assert_eq!(*bus_id, SHARED_BUS_ID);
+        assert_eq!(client, &format!("device{}", device));
+        assert_eq!(stats.acquisitions, ROUNDS);
+    }
+}
+
+#[test]
+fn priority_bus_lock_lets_bypassed_devices_through() {
+    // long enough for a spawned thread to queue up on the bus
+    const QUEUE_DELAY: Duration = Duration::from_millis(20);
+
+    let bus = Arc::new(BusLock::new(Vec::new(), BusLockPolicy::Priority));
+    let holder = bus.client("holder", 0);
+    let guard = holder.lock();
+
+    let spawn = |name: String, priority: u8| {
+        let client = bus.client(&name, priority);
+        thread::spawn(move || client.lock().push(name))
+    };
+
+    let mut threads = vec![spawn("low".to_string(), 0)];
+    thread::sleep(QUEUE_DELAY);
+    for i in 0..MAX_PRIORITY_BYPASSES + 2 {
+        threads.push(spawn(format!("high{}", i), 10));
+        thread::sleep(QUEUE_DELAY);
+    }
+
+    drop(guard);
+    for thread in threads {
+        thread.join().expect("device thread panicked");
+    }
+
+    let order = holder.lock().clone();
+    assert_eq!(order[0], "high0");
+    assert_eq!(order.iter().position(|x| x == "low"), Some(MAX_PRIORITY_BYPASSES as usize), "{:?}", order);
+
+    let stats = bus.wait_stats();
+    let (_, low) = stats.iter().find(|(name, _)| name == "low").expect("missing stats for the low priority device");
+    assert_eq!(low.acquisitions, 1);
+    assert!(low.max_wait >= QUEUE_DELAY * (MAX_PRIORITY_BYPASSES + 2), "{:?}", low);
+}
+
+impl SmbusTransfer for FakeTransaction {
+    fn set_slave_address(&mut self, address: I2cAddress) -> Result<()> {
+        self.ops.push(Op::SlaveAddress(address));
+        Ok(())
+    }
+
+    fn read_byte_data(&mut self, register: u8) -> Result<u8> {
+        self.ops.push(Op::ReadByteData(register));
+        Ok(self.data.remove(0))
+    }
+
+    fn write_byte_data(&mut self, register: u8, value: u8) -> Result<()> {
+        self.ops.push(Op::WriteByteData(register, value));
+        Ok(())
+    }
+
+    fn read_word_data(&mut self, register: u8) -> Result<u16> {
+        self.ops.push(Op::ReadWordData(register));
+        let low = self.data.remove(0);
+        Ok(u16::from_le_bytes([low, self.data.remove(0)]))
+    }
+
+    fn write_word_data(&mut self, register: u8, value: u16) -> Result<()> {
+        self.ops.push(Op::WriteWordData(register, value));
+        Ok(())
+    }
+}
+
+#[test]
+fn pec_register_helpers_use_smbus_transfers() {
+    let mut transaction = FakeTransaction::new(vec![0x58, 0x60]);
+    let mut buf = [0u8; 2];
+
+    read_register_pec(&mut transaction, I2cAddress::seven_bit(0x76), 0xD0, &mut buf).expect("PEC read failed");
+    write_register_pec(&mut transaction, I2cAddress::seven_bit(0x76), 0xF4, 0x27).expect("PEC write failed");
+
+    assert_eq!(buf, [0x58, 0x60]);
+    assert_eq!(transaction.ops, vec![
+        Op::SlaveAddress(I2cAddress::seven_bit(0x76)),
+        Op::ReadByteData(0xD0),
+        Op::ReadByteData(0xD1),
+        Op::SlaveAddress(I2cAddress::seven_bit(0x76)),
+        Op::WriteByteData(0xF4, 0x27),
+    ]);
+}
+
+#[test]
+fn register_helpers_follow_the_pec_flag() {
+    let address = I2cAddress::seven_bit(0x29);
+    let mut transaction = FakeTransaction::new(vec![0x50, 0x50]);
+    let mut buf = [0u8; 1];
+
+    read_register_with(&mut transaction, address, 0xB2, &mut buf, false).expect("plain read failed");
+    write_register_with(&mut transaction, address, 0xA0, 0x03, false).expect("plain write failed");
+    assert_eq!(buf, [0x50]);
+    assert_eq!(transaction.ops, vec![
+        Op::SlaveAddress(address),
+        Op::Write(vec![0xB2]),
+        Op::Read(1),
+        Op::SlaveAddress(address),
+        Op::Write(vec![0xA0, 0x03]),
+    ]);
+
+    transaction.ops.clear();
+    read_register_with(&mut transaction, address, 0xB2, &mut buf, true).expect("PEC read failed");
+    write_register_with(&mut transaction, address, 0xA0, 0x03, true).expect("PEC write failed");
+    assert_eq!(buf, [0x50]);
+    assert_eq!(transaction.ops, vec![
+        Op::SlaveAddress(address),
+        Op::ReadByteData(0xB2),
+        Op::SlaveAddress(address),
+        Op::WriteByteData(0xA0, 0x03),
+    ]);
+}
+
+#[test]
+fn pec_flag_propagates_to_opened_buses() {
+    let data: I2cConfigData = serde_json::from_value(serde_json::json!({ "channels": {} })).unwrap();
+    assert!(!data.pec);
+    let data: I2cConfigData = serde_json::from_value(serde_json::json!({ "channels": {}, "pec": true })).unwrap();
+    assert!(data.pec);
+
+    let mut pin_map = HashMap::new();
+    for pin in 2..4 {
+        pin_map.insert(pin, PinState::new(pin, pin + 10));
+    }
+
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+    let mut pin_config = HashMap::new();
+    pin_config.insert(SHARED_BUS_ID, I2CPinDefinition::new(2, 3));
+    let mut controller = SysfsI2CBusController::with_pin_config(&gpio, pin_config)
+        .expect("failed to build controller")
+        .with_pec(data.pec);
+    assert!(controller.pec_enabled());
+
+    // /dev/null rejects the PEC ioctl, so the open only fails if PEC was actually requested
+    assert!(controller.open_with(SHARED_BUS_ID, |_| Ok(I2c::new(File::open("/dev/null")?))).is_err());
+    assert!(gpio.read().can_borrow_many(&[2, 3]), "pins were leased by a bus that failed to open");
+
+    let mut controller = controller.with_pec(false);
+    assert!(controller.open_with(SHARED_BUS_ID, |_| Ok(I2c::new(File::open("/dev/null")?))).is_ok());
+}
+
+#[test]
+fn controller_requires_gpio_entries() {
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(HashMap::new())));
+    let mut config = BusControllerConfig::new_without_data("i2c_sysfs".to_string());
+
+    match SysfsI2CBusController::from_config(&gpio, &mut config) {
+        Err(I2CError::InvalidConfig(msg)) => assert!(msg.contains("GPIO"), "{}", msg),
+        Err(e) => panic!("expected InvalidConfig, got {}", e),
+        Ok(_) => panic!("controller built without any GPIO pins"),
+    }
+}
+
+// Register file behind a single slave address, the register pointer auto-increments like most chips do
+struct FakeRegisterChip {
+    address: I2cAddress,
+    selected: Option<I2cAddress>,
+    pointer: u8,
+    registers: [u8; 256],
+}
+
+impl FakeRegisterChip {
+    fn check_selected(&self) -> Result<()> {
+        match self.selected == Some(self.address) {
+            true => Ok(()),
+            false => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no chip at this address")),
+        }
+    }
+}
+
+impl SmbusTransfer for FakeRegisterChip {
+    fn set_slave_address(&mut self, address: I2cAddress) -> Result<()> {
+        self.selected = Some(address);
+        Ok(())
+    }
+
+    fn read_byte_data(&mut self, register: u8) -> Result<u8> {
+        self.check_selected()?;
+        Ok(self.registers[register as usize])
+    }
+
+    fn write_byte_data(&mut self, register: u8, value: u8) -> Result<()> {
+        self.check_selected()?;
+        self.registers[register as usize] = value;
+        Ok(())
+    }
+
+    fn read_word_data(&mut self, register: u8) -> Result<u16> {
+        self.check_selected()?;
+        Ok(u16::from_le_bytes([self.registers[register as usize], self.registers[register.wrapping_add(1) as usize]]))
+    }
+
+    fn write_word_data(&mut self, register: u8, value: u16) -> Result<()> {
+        self.check_selected()?;
+        let [low, high] = value.to_le_bytes();
+        self.registers[register as usize] = low;
+        self.registers[register.wrapping_add(1) as usize] = high;
+        Ok(())
+    }
+}
+
+impl Write for FakeRegisterChip {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.check_selected()?;
+        if let Some((pointer, data)) = buf.split_first() {
+            self.pointer = *pointer;
+            for value in data {
+                self.registers[self.pointer as usize] = *value;
+                self.pointer = self.pointer.wrapping_add(1);
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for FakeRegisterChip {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.check_selected()?;
+        for value in buf.iter_mut() {
+            *value = self.registers[self.pointer as usize];
+            self.pointer = self.pointer.wrapping_add(1);
+        }
+
+        Ok(buf.len())
+    }
+}
+
+struct FakeRegisterBus {
+    enabled: bool,
+    chip: Mutex<FakeRegisterChip>,
+}
+
+impl RegisterBus for FakeRegisterBus {
+    fn debug_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn transfer(&self, bus_id: u8, op: &mut dyn FnMut(&mut dyn RegisterIo) -> Result<()>) -> std::result::Result<(), Status> {
+        if bus_id != SHARED_BUS_ID {
+            return Err(Status::not_found("bus does not exist"));
+        }
+
+        op(&mut *self.chip.lock()).map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+fn make_debug_service(enabled: bool) -> I2cDebugService {
+    make_debug_service_at(enabled, I2cAddress::seven_bit(0x77))
+}
+
+fn make_debug_service_at(enabled: bool, address: I2cAddress) -> I2cDebugService {
+    let mut registers = [0u8; 256];
+    registers[0xD0] = 0x58;
+    I2cDebugService::with_bus(FakeRegisterBus {
+        enabled,
+        chip: Mutex::new(FakeRegisterChip { address, selected: None, pointer: 0, registers }),
+    })
+}
+
+#[tokio::test]
+async fn debug_register_round_trip() {
+    let service = make_debug_service(true);
+
+    let id = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x77, register: 0xD0, length: 1, addressing: AddressingMode::SevenBit as i32 })).await.unwrap();
+    assert_eq!(id.get_ref().data, vec![0x58]);
+
+    service.write_register(tonic::Request::new(WriteRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x77, register: 0xF4, data: vec![0x27, 0xA0], addressing: AddressingMode::SevenBit as i32 })).await.unwrap();
+    let data = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x77, register: 0xF4, length: 2, addressing: AddressingMode::SevenBit as i32 })).await.unwrap();
+    assert_eq!(data.get_ref().data, vec![0x27, 0xA0]);
+
+    let err = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x76, register: 0xD0, length: 1, addressing: AddressingMode::SevenBit as i32 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::Internal);
+    let err = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x77, register: 0xD0, length: 0, addressing: AddressingMode::SevenBit as i32 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::OutOfRange);
+}
+
+#[tokio::test]
+async fn debug_register_ten_bit_address() {
+    let address = I2cAddress::new(0x3A5, I2cAddressing::TenBit).unwrap();
+    let service = make_debug_service_at(true, address);
+    let ten_bit = AddressingMode::TenBit as i32;
+
+    let id = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x3A5, register: 0xD0, length: 1, addressing: ten_bit })).await.unwrap();
+    assert_eq!(id.get_ref().data, vec![0x58]);
+    service.write_register(tonic::Request::new(WriteRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x3A5, register: 0xF4, data: vec![0x27], addressing: ten_bit })).await.unwrap();
+    let data = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x3A5, register: 0xF4, length: 1, addressing: ten_bit })).await.unwrap();
+    assert_eq!(data.get_ref().data, vec![0x27]);
+
+    // past the 10-bit range, and a 10-bit address sent in 7-bit mode
+    let err = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x400, register: 0xD0, length: 1, addressing: ten_bit })).await.unwrap_err();
+    assert_eq!(err.code(), Code::OutOfRange);
+    let err = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x3A5, register: 0xD0, length: 1, addressing: AddressingMode::SevenBit as i32 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::OutOfRange);
+    let err = service.write_register(tonic::Request::new(WriteRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x3A5, register: 0xF4, data: vec![0x27], addressing: 7 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn debug_access_disabled_by_default() {
+    let data: I2cConfigData = serde_json::from_value(serde_json::json!({ "channels": {} })).unwrap();
+    assert!(!data.debug_enabled);
+
+    let service = make_debug_service(false);
+    let err = service.read_register(tonic::Request::new(ReadRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x77, register: 0xD0, length: 1, addressing: AddressingMode::SevenBit as i32 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+
+    // the real service asks the registered controller, which starts out disabled
+    let mut pin_map = HashMap::new();
+    for pin in 2..4 {
+        pin_map.insert(pin, PinState::new(pin, pin + 10));
+    }
+
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+    let mut pin_config = HashMap::new();
+    pin_config.insert(SHARED_BUS_ID, I2CPinDefinition::new(2, 3));
+    let controller = SysfsI2CBusController::with_pin_config(&gpio, pin_config).expect("failed to build controller");
+    let server = DeviceServerBuilder::configure().add_bus(controller).build(false).unwrap();
+
+    let service = I2cDebugService::new(&Arc::new(RwLock::new(server)));
+    let err = service.write_register(tonic::Request::new(WriteRegisterRequest { bus_id: SHARED_BUS_ID as u32, address: 0x77, register: 0xF4, data: vec![0x27], addressing: AddressingMode::SevenBit as i32 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+    assert!(gpio.read().can_borrow_many(&[2, 3]), "disabled debug access opened the bus");
+}
+
+#[test]
+fn debug_transfer_closes_the_bus() {
+    let mut pin_map = HashMap::new();
+    for pin in 2..4 {
+        pin_map.insert(pin, PinState::new(pin, pin + 10));
+    }
+
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+    let mut pin_config = HashMap::new();
+    pin_config.insert(SHARED_BUS_ID, I2CPinDefinition::new(2, 3));
+    let controller = SysfsI2CBusController::with_pin_config(&gpio, pin_config)
+        .expect("failed to build controller")
+        .with_debug_enabled(true);
+    let server = Arc::new(RwLock::new(DeviceServerBuilder::configure().add_bus(controller).build(false).unwrap()));
+
+    // nobody else has the bus open, so the transfer is its only user
+    let bus = ControllerRegisterBus::new(&server).with_opener(|_| Ok(I2c::new(File::open("/dev/null")?)));
+    let mut transfers = 0;
+    bus.transfer(SHARED_BUS_ID, &mut |_| {
+        transfers += 1;
+        Ok(())
+    }).expect("debug transfer failed");
+
+    assert_eq!(transfers, 1);
+    let controller = server.read().get_bus_ptr::<SysfsI2CBusController>().unwrap();
+    assert_eq!(controller.read().user_count(SHARED_BUS_ID), 0);
+    assert!(gpio.read().can_borrow_many(&[2, 3]), "the bus was left open after the transfer");
+}
+
+#[test]
+fn address_range_depends_on_addressing() {
+    let address = I2cAddress::new(0x3A5, I2cAddressing::TenBit).expect("10-bit address was rejected");
+    assert_eq!(address.address(), 0x3A5);
+    assert!(address.addressing().is_ten_bit());
+    assert!(I2cAddress::new(0x3FF, I2cAddressing::TenBit).is_ok());
+    assert!(matches!(I2cAddress::new(0x400, I2cAddressing::TenBit), Err(I2CError::InvalidAddress(0x400))));
+
+    assert!(I2cAddress::new(0x7F, I2cAddressing::SevenBit).is_ok());
+    assert!(matches!(I2cAddress::new(0x80, I2cAddressing::SevenBit), Err(I2CError::InvalidAddress(0x80))));
+}
@@ -0,0 +1,163 @@
+use crate::bus::i2c::I2cAddress;
+use crate::bus::i2c_sysfs::SmbusTransfer;
+use crate::capabilities::PowerMonitorCapable;
+use crate::device::DeviceError;
+use crate::drivers::ina219_sysfs::{
+    bus_voltage_from_register, read_overflow_checked, read_register, write_register, Ina219Calibration, Ina219SysfsConfig,
+    Ina219SysfsDriver,
+};
+use super::support;
+use std::io::{Read, Result, Write};
+
+// 16 bit register file stored MSB first like the chip, counts how many transfers went over each path
+struct WordRegisterChip {
+    registers: [u16; 6],
+    pointer: u8,
+    plain_transfers: usize,
+    word_transfers: usize,
+}
+
+impl WordRegisterChip {
+    fn new() -> Self {
+        Self { registers: [0; 6], pointer: 0, plain_transfers: 0, word_transfers: 0 }
+    }
+}
+
+impl SmbusTransfer for WordRegisterChip {
+    fn set_slave_address(&mut self, _address: I2cAddress) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_byte_data(&mut self, _register: u8) -> Result<u8> {
+        panic!("16 bit registers can't be read a byte at a time");
+    }
+
+    fn write_byte_data(&mut self, _register: u8, _value: u8) -> Result<()> {
+        panic!("16 bit registers can't be written a byte at a time");
+    }
+
+    // the MSB goes out first, which SMBus sees as the low byte
+    fn read_word_data(&mut self, register: u8) -> Result<u16> {
+        self.word_transfers += 1;
+        Ok(self.registers[register as usize].swap_bytes())
+    }
+
+    fn write_word_data(&mut self, register: u8, value: u16) -> Result<()> {
+        self.word_transfers += 1;
+        self.registers[register as usize] = value.swap_bytes();
+        Ok(())
+    }
+}
+
+impl Write for WordRegisterChip {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.plain_transfers += 1;
+        self.pointer = buf[0];
+        if let [_, msb, lsb] = buf {
+            self.registers[self.pointer as usize] = u16::from_be_bytes([*msb, *lsb]);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for WordRegisterChip {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.plain_transfers += 1;
+        buf[..2].copy_from_slice(&self.registers[self.pointer as usize].to_be_bytes());
+        Ok(2)
+    }
+}
+
+fn make_driver(config: Ina219SysfsConfig) -> std::result::Result<Ina219SysfsDriver, DeviceError> {
+    support::build_driver("ina219_sysfs", config)
+}
+
+#[test]
+fn calibration_datasheet_example() {
+    // 0.1 ohm shunt with a current LSB of 100 uA
+    let calibration = Ina219Calibration::compute(0.1, 3.2768).unwrap();
+    assert_eq!(calibration.register, 4096);
+    assert!((calibration.current_lsb_a - 0.0001).abs() < 1e-9);
+    assert!((calibration.power_lsb_w - 0.002).abs() < 1e-9);
+
+    // a smaller current LSB raises the calibration value, the result is truncated to an even number
+    let calibration = Ina219Calibration::compute(0.1, 2.0).unwrap();
+    assert_eq!(calibration.register, 6710);
+
+    assert!(Ina219Calibration::compute(0.0, 2.0).is_err());
+    assert!(Ina219Calibration::compute(0.1, -1.0).is_err());
+    assert!(Ina219Calibration::compute(0.0001, 0.001).is_err());
+}
+
+#[test]
+fn register_conversions() {
+    let calibration = Ina219Calibration::compute(0.1, 3.2768).unwrap();
+
+    // 12 V bus with the conversion ready bit set
+    assert!((bus_voltage_from_register(0x5DC2).unwrap() - 12.0).abs() < 1e-4);
+    assert!(matches!(bus_voltage_from_register(0x5DC3), Err(DeviceError::HardwareError(..))));
+
+    assert!((calibration.current_ma(0x07D0) - 200.0).abs() < 1e-3);
+    // current is signed, reverse flow reads negative
+    assert!((calibration.current_ma(0xF830) + 200.0).abs() < 1e-3);
+    assert!((calibration.power_mw(0x04B0) - 2400.0).abs() < 1e-2);
+}
+
+#[test]
+fn config_validation() {
+    make_driver(Ina219SysfsConfig::default()).expect("default config should be valid");
+
+    let mut config = Ina219SysfsConfig::default();
+    config.bus_voltage_range = 24;
+    assert!(matches!(make_driver(config), Err(DeviceError::InvalidConfig(..))));
+
+    // 1 V across the shunt is beyond the largest gain range
+    let mut config = Ina219SysfsConfig::default();
+    config.shunt_resistance_ohms = 1.0;
+    config.max_expected_current_a = 1.0;
+    assert!(matches!(make_driver(config), Err(DeviceError::InvalidConfig(..))));
+}
+
+#[test]
+fn set_calibration_while_stopped() {
+    let mut driver = make_driver(Ina219SysfsConfig::default()).unwrap();
+    driver.set_calibration(0.05, 4.0).expect("failed to apply calibration");
+    assert!(matches!(driver.set_calibration(1.0, 1.0), Err(DeviceError::InvalidConfig(..))));
+    assert!(matches!(driver.get_current_ma(), Err(DeviceError::InvalidOperation(_))));
+}
+
+#[test]
+fn registers_use_word_transfers_with_pec() {
+    let address = I2cAddress::seven_bit(0x40);
+    let mut chip = WordRegisterChip::new();
+
+    write_register(&mut chip, address, 0x05, 0x1234, true).expect("PEC write failed");
+    assert_eq!(chip.registers[0x05], 0x1234);
+    assert_eq!(read_register(&mut chip, address, 0x05, true).unwrap(), 0x1234);
+    assert_eq!((chip.word_transfers, chip.plain_transfers), (2, 0));
+
+    // both paths see the same register contents
+    write_register(&mut chip, address, 0x00, 0x399F, false).expect("plain write failed");
+    assert_eq!(read_register(&mut chip, address, 0x00, true).unwrap(), 0x399F);
+    assert_eq!(read_register(&mut chip, address, 0x05, false).unwrap(), 0x1234);
+    assert_eq!(chip.word_transfers, 3);
+}
+
+#[test]
+fn current_reads_check_the_overflow_flag() {
+    let address = I2cAddress::seven_bit(0x40);
+    let mut chip = WordRegisterChip::new();
+    chip.registers[0x04] = 0x07D0;
+
+    // 12 V bus with the conversion ready bit set
+    chip.registers[0x02] = 0x5DC2;
+    assert_eq!(read_overflow_checked(&mut chip, address, 0x04, true).unwrap(), 0x07D0);
+
+    chip.registers[0x02] = 0x5DC3;
+    assert!(matches!(read_overflow_checked(&mut chip, address, 0x04, true), Err(DeviceError::HardwareError(..))));
+}
@@ -0,0 +1,175 @@
+use std::any::Any;
+
+use intertrait::cast_to;
+use serde_json::json;
+
+use crate::capabilities::{Capability, LEDControllerCapable, LEDMode};
+use crate::config::DeviceConfig;
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::drivers::led_group::LedGroup;
+
+// Records what it was told, or rejects every write when broken
+struct StubLed {
+    mode: LEDMode,
+    brightness: f32,
+    powered_on: bool,
+    broken: bool,
+    is_loaded: bool,
+}
+
+impl DeviceDriver for StubLed {
+    fn name(&self) -> String {
+        "stub_led".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(StubLed { mode: LEDMode::Visible, brightness: 0.0, powered_on: false, broken: false, is_loaded: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl StubLed {
+    fn check_broken(&self) -> Result<(), DeviceError> {
+        match self.broken {
+            true => Err(DeviceError::hardware_error("stub LED is broken")),
+            false => Ok(())
+        }
+    }
+}
+
+impl Capability for StubLed {}
+
+#[cast_to]
+impl LEDControllerCapable for StubLed {
+    fn get_mode(&self) -> Result<LEDMode, DeviceError> {
+        Ok(self.mode.clone())
+    }
+
+    fn set_mode(&mut self, mode: LEDMode) -> Result<(), DeviceError> {
+        self.check_broken()?;
+        self.mode = mode;
+        Ok(())
+    }
+
+    fn get_brightness(&self) -> Result<f32, DeviceError> {
+        Ok(self.brightness)
+    }
+
+    fn set_brightness(&mut self, brightness: f32) -> Result<(), DeviceError> {
+        self.check_broken()?;
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn get_power_state(&self) -> Result<bool, DeviceError> {
+        Ok(self.powered_on)
+    }
+
+    fn set_power_state(&mut self, powered_on: bool) -> Result<(), DeviceError> {
+        self.check_broken()?;
+        self.powered_on = powered_on;
+        Ok(())
+    }
+}
+
+fn make_server(members: &[&str]) -> DeviceServer {
+    let mut builder = DeviceServerBuilder::configure();
+    for name in members {
+        builder = builder.add_device(Device::new::<StubLed>(None, Some(name.to_string())).unwrap());
+    }
+
+    let mut group_config = DeviceConfig::new(
+        "led_group".to_string(),
+        Some("group".to_string()),
+        json!({ "members": members }),
+    );
+
+    builder
+        .add_device(Device::from_config::<LedGroup>(&mut group_config, None).unwrap())
+        .build(true)
+        .expect("failed to build server")
+}
+
+fn member_state(server: &DeviceServer, name: &str) -> (LEDMode, f32, bool) {
+    let device = server.get_device_with_name(name).unwrap();
+    let led = device.as_any().downcast_ref::<StubLed>().unwrap();
+    (led.mode.clone(), led.brightness, led.powered_on)
+}
+
+#[test]
+fn group_command_reaches_all_members() {
+    let mut server = make_server(&["led-a", "led-b", "led-c"]);
+    {
+        let mut group = server.get_device_with_name_mut("group").unwrap();
+        let led = group.as_capability_mut::<dyn LEDControllerCapable>().expect("group is not an LED controller");
+        led.set_brightness(0.25).expect("failed to set brightness");
+        led.set_power_state(true).expect("failed to set power state");
+        led.set_mode(LEDMode::Infrared).expect("failed to set mode");
+        assert_eq!(led.get_brightness().unwrap(), 0.25);
+    }
+
+    for name in ["led-a", "led-b", "led-c"] {
+        assert_eq!(member_state(&server, name), (LEDMode::Infrared, 0.25, true), "{} was not updated", name);
+    }
+}
+
+#[test]
+fn group_reports_partial_failure() {
+    let mut server = make_server(&["led-a", "led-b", "led-c"]);
+    server.get_device_with_name_mut("led-b").unwrap().as_mut().as_any_mut().downcast_mut::<StubLed>().unwrap().broken = true;
+
+    let result = {
+        let mut group = server.get_device_with_name_mut("group").unwrap();
+        group.as_capability_mut::<dyn LEDControllerCapable>().unwrap().set_brightness(0.5)
+    };
+
+    match result {
+        Err(DeviceError::HardwareError(message, _)) => {
+            assert!(message.contains("1 of 3"), "unexpected error: {}", message);
+            assert!(message.contains("led-b"), "failed member is not named: {}", message);
+        }
+        other => panic!("expected a hardware error, got {:?}", other)
+    }
+
+    // the healthy members still got the command
+    assert_eq!(member_state(&server, "led-a").1, 0.5);
+    assert_eq!(member_state(&server, "led-b").1, 0.0);
+    assert_eq!(member_state(&server, "led-c").1, 0.5);
+}
+
+#[test]
+fn group_config_is_validated() {
+    let mut config = DeviceConfig::new("led_group".to_string(), None, json!({ "members": [] }));
+    assert!(Device::from_config::<LedGroup>(&mut config, None).is_err(), "empty group was accepted");
+
+    let mut config = DeviceConfig::new("led_group".to_string(), None, json!({ "members": ["led-a", "led-a"] }));
+    assert!(Device::from_config::<LedGroup>(&mut config, None).is_err(), "duplicate member was accepted");
+
+    // members have to be registered before the group starts
+    let mut config = DeviceConfig::new("led_group".to_string(), None, json!({ "members": ["missing"] }));
+    let result = DeviceServerBuilder::configure()
+        .add_device(Device::from_config::<LedGroup>(&mut config, None).unwrap())
+        .build(true);
+    assert!(result.is_err(), "group started without its members");
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::capabilities::{LEDControllerCapable, LEDMode};
+use crate::config::DeviceConfig;
+use crate::device::{DeviceDriver, DeviceError};
+use crate::drivers::pwm_ramp::{ramp_steps, DutyCycleOutput, PowerRamp, RAMP_STEP_INTERVAL};
+use crate::drivers::sysfs_led::{SysfsLedController, SysfsLedControllerConfig};
+use super::support;
+use parking_lot::Mutex;
+
+fn make_driver(config: SysfsLedControllerConfig) -> Result<SysfsLedController, DeviceError> {
+    support::build_driver("sysfs_generic_led", config)
+}
+
+fn make_config(gamma: f32) -> SysfsLedControllerConfig {
+    let mut config = SysfsLedControllerConfig::default();
+    config.pwm_period = 1000;
+    config.pwm_0_brightness_duty_cycle = 0;
+    config.pwm_100_brightness_duty_cycle = 1000;
+    config.brightness_gamma = gamma;
+    config
+}
+
+#[test]
+fn linear_brightness_by_default() {
+    let led = make_driver(make_config(1.0)).expect("failed to build driver");
+    assert_eq!(led.duty_cycle_ns(0.0), 0);
+    assert_eq!(led.duty_cycle_ns(0.25), 250);
+    assert_eq!(led.duty_cycle_ns(0.5), 500);
+    assert_eq!(led.duty_cycle_ns(1.0), 1000);
+
+    // configs written before the gamma field existed stay linear
+    let mut data = serde_json::to_value(SysfsLedControllerConfig::default()).unwrap();
+    data.as_object_mut().unwrap().remove("brightness_gamma");
+    let config: SysfsLedControllerConfig = serde_json::from_value(data).unwrap();
+    assert_eq!(config.brightness_gamma, 1.0);
+}
+
+#[test]
+fn gamma_corrected_brightness() {
+    let led = make_driver(make_config(2.2)).expect("failed to build driver");
+    assert_eq!(led.duty_cycle_ns(0.0), 0);
+    assert_eq!(led.duty_cycle_ns(0.1), 6);
+    assert_eq!(led.duty_cycle_ns(0.25), 47);
+    assert_eq!(led.duty_cycle_ns(0.5), 218);
+    assert_eq!(led.duty_cycle_ns(0.75), 531);
+    assert_eq!(led.duty_cycle_ns(1.0), 1000);
+}
+
+#[test]
+fn gamma_curve_starts_at_minimum_duty_cycle() {
+    let mut config = make_config(2.2);
+    config.pwm_0_brightness_duty_cycle = 100;
+    let led = make_driver(config).expect("failed to build driver");
+    assert_eq!(led.duty_cycle_ns(0.0), 100);
+    assert_eq!(led.duty_cycle_ns(0.25), 143);
+    assert_eq!(led.duty_cycle_ns(0.5), 296);
+    assert_eq!(led.duty_cycle_ns(0.75), 578);
+    assert_eq!(led.duty_cycle_ns(1.0), 1000);
+}
+
+#[test]
+fn invalid_gamma_rejected() {
+    assert!(matches!(make_driver(make_config(0.0)), Err(DeviceError::InvalidConfig(..))));
+    assert!(matches!(make_driver(make_config(-1.0)), Err(DeviceError::InvalidConfig(..))));
+    assert!(matches!(make_driver(make_config(f32::NAN)), Err(DeviceError::InvalidConfig(..))));
+}
+
+#[test]
+fn led_requires_raw_and_pwm_sysfs() {
+    let led = make_driver(make_config(1.0)).expect("failed to build driver");
+    assert_eq!(led.required_controllers(), vec!["raw_sysfs", "pwm_sysfs"]);
+}
+
+fn make_three_mode_config() -> SysfsLedControllerConfig {
+    let mut config = make_config(1.0);
+    config.mode_switch_pin = 5;
+    config.extra_mode_switch_pins = vec![6];
+    config.modes = BTreeMap::from([
+        ("Visible".to_string(), vec![1, 0]),
+        ("Infrared".to_string(), vec![0, 1]),
+        ("ultraviolet".to_string(), vec![1, 1]),
+    ]);
+    config
+}
+
+#[test]
+fn legacy_modes_map_to_single_pin() {
+    let led = make_driver(make_config(1.0)).expect("failed to build driver");
+    assert_eq!(led.get_modes().unwrap(), vec![LEDMode::Visible, LEDMode::Infrared]);
+    assert_eq!(led.mode_gpio_states(&LEDMode::Visible).unwrap(), &[1]);
+    assert_eq!(led.mode_gpio_states(&LEDMode::Infrared).unwrap(), &[0]);
+    assert!(led.mode_gpio_states(&LEDMode::Named("ultraviolet".to_string())).is_err());
+}
+
+#[test]
+fn three_mode_config_switches_all_pins() {
+    let led = make_driver(make_three_mode_config()).expect("failed to build driver");
+    let ultraviolet = LEDMode::Named("ultraviolet".to_string());
+
+    assert_eq!(led.get_modes().unwrap().len(), 3);
+    assert_eq!(led.mode_gpio_states(&LEDMode::Visible).unwrap(), &[1, 0]);
+    assert_eq!(led.mode_gpio_states(&LEDMode::Infrared).unwrap(), &[0, 1]);
+    assert_eq!(led.mode_gpio_states(&ultraviolet).unwrap(), &[1, 1]);
+    assert!(led.mode_gpio_states(&LEDMode::Named("green".to_string())).is_err());
+}
+
+#[test]
+fn mode_states_must_match_pins() {
+    let mut config = make_three_mode_config();
+    config.modes.insert("green".to_string(), vec![0]);
+    assert!(make_driver(config).is_err(), "mode with too few states was accepted");
+
+    let mut config = make_three_mode_config();
+    config.modes.insert("green".to_string(), vec![1, 1]);
+    assert!(make_driver(config).is_err(), "modes with the same states were accepted");
+
+    let mut config = make_three_mode_config();
+    config.default_mode = LEDMode::Named("green".to_string());
+    assert!(make_driver(config).is_err(), "unknown default mode was accepted");
+}
+
+#[test]
+fn led_mode_names_round_trip() {
+    let modes: Vec<LEDMode> = serde_json::from_str("[\"Visible\", \"infrared\", \"ultraviolet\"]").unwrap();
+    assert_eq!(modes, vec![LEDMode::Visible, LEDMode::Infrared, LEDMode::Named("ultraviolet".to_string())]);
+    assert_eq!(serde_json::to_string(&modes).unwrap(), "[\"Visible\",\"Infrared\",\"ultraviolet\"]");
+}
+
+fn device_config(config: SysfsLedControllerConfig) -> DeviceConfig {
+    DeviceConfig::new("sysfs_generic_led".to_string(), None, serde_json::to_value(config).unwrap())
+}
+
+#[test]
+fn apply_config_updates_brightness_mapping() {
+    let mut led = make_driver(make_config(1.0)).expect("failed to build driver");
+    let mut config = make_config(2.2);
+    config.default_brightness = 0.25;
+    config.default_mode = LEDMode::Infrared;
+
+    led.apply_config(&device_config(config)).expect("failed to apply config");
+    assert_eq!(led.duty_cycle_ns(0.5), 218);
+}
+
+#[test]
+fn apply_config_rejects_pin_changes() {
+    let mut led = make_driver(make_config(1.0)).expect("failed to build driver");
+    let mut config = make_config(1.0);
+    config.mode_switch_pin = 7;
+    assert!(matches!(led.apply_config(&device_config(config)), Err(DeviceError::NotSupported)));
+
+    assert!(matches!(led.apply_config(&device_config(make_config(0.0))), Err(DeviceError::InvalidConfig(..))));
+    assert_eq!(led.duty_cycle_ns(0.5), 500);
+}
+
+// Records every duty cycle written along with when it was written
+#[derive(Default)]
+struct RecordingOutput {
+    writes: Mutex<Vec<(Instant, u32)>>,
+}
+
+impl DutyCycleOutput for RecordingOutput {
+    fn set_duty_cycle_ns(&self, duty_cycle: u32) -> Result<(), DeviceError> {
+        self.writes.lock().push((Instant::now(), duty_cycle));
+        Ok(())
+    }
+}
+
+#[test]
+fn power_ramp_increases_over_window() {
+    let output = Arc::new(RecordingOutput::default());
+    let started = Instant::now();
+    PowerRamp::start(output.clone(), 0, 1000, Duration::from_millis(100)).wait();
+
+    let writes = output.writes.lock();
+    let duty_cycles: Vec<u32> = writes.iter().map(|(_, duty)| *duty).collect();
+    assert_eq!(duty_cycles, ramp_steps(0, 1000, Duration::from_millis(100)));
+    assert_eq!(duty_cycles.len(), 10);
+    assert!(duty_cycles.windows(2).all(|x| x[0] < x[1]));
+    assert_eq!(*duty_cycles.last().unwrap(), 1000);
+
+    // spread over the window instead of written all at once
+    assert!(writes[0].0 - started >= RAMP_STEP_INTERVAL);
+    assert!(writes.last().unwrap().0 - started >= Duration::from_millis(100));
+}
+
+#[test]
+fn power_ramp_cancel_stops_writes() {
+    let output = Arc::new(RecordingOutput::default());
+    let ramp = PowerRamp::start(output.clone(), 1000, 0, Duration::from_secs(10));
+    std::thread::sleep(Duration::from_millis(50));
+
+    let reached = ramp.cancel();
+    let written = output.writes.lock().len();
+    assert!(reached < 1000);
+    assert_eq!(output.writes.lock().last().map(|(_, duty)| *duty), Some(reached));
+
+    std::thread::sleep(RAMP_STEP_INTERVAL * 3);
+    assert_eq!(output.writes.lock().len(), written);
+}
+
+#[test]
+fn power_ramp_disabled_by_default() {
+    assert_eq!(SysfsLedControllerConfig::default().power_ramp_ms, 0);
+    assert_eq!(ramp_steps(0, 100, Duration::ZERO), vec![100]);
+}
+
+fn make_capped_config() -> SysfsLedControllerConfig {
+    let mut config = make_config(1.0);
+    config.max_brightness_ir = 0.3;
+    config.max_brightness_visible = 0.8;
+    config
+}
+
+#[test]
+fn switching_to_ir_reclamps_brightness() {
+    let led = make_driver(make_capped_config()).expect("failed to build driver");
+    assert_eq!(led.brightness_for_mode(0.7, &LEDMode::Visible), 0.7);
+    assert_eq!(led.brightness_for_mode(0.7, &LEDMode::Infrared), 0.3);
+    assert_eq!(led.brightness_for_mode(1.0, &LEDMode::Visible), 0.8);
+    assert_eq!(led.brightness_for_mode(0.2, &LEDMode::Infrared), 0.2);
+    assert_eq!(led.brightness_for_mode(-1.0, &LEDMode::Infrared), 0.0);
+    assert_eq!(led.brightness_for_mode(1.0, &LEDMode::Named("UV".to_string())), 1.0);
+
+    // configs written before the caps existed are not capped
+    let mut data = serde_json::to_value(make_config(1.0)).unwrap();
+    data.as_object_mut().unwrap().remove("max_brightness_ir");
+    data.as_object_mut().unwrap().remove("max_brightness_visible");
+    let config: SysfsLedControllerConfig = serde_json::from_value(data).unwrap();
+    assert_eq!(config.max_brightness_ir, 1.0);
+    assert_eq!(config.max_brightness_visible, 1.0);
+}
+
+#[test]
+fn invalid_brightness_cap_rejected() {
+    let mut config = make_capped_config();
+    config.max_brightness_ir = 1.5;
+    assert!(matches!(make_driver(config), Err(DeviceError::InvalidConfig(..))));
+
+    let mut config = make_capped_config();
+    config.max_brightness_visible = f32::NAN;
+    assert!(matches!(make_driver(config), Err(DeviceError::InvalidConfig(..))));
+}
@@ -0,0 +1,210 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+
+use crate::bus::pwm::{
+    apply_polarity, probe_polarity_support, set_polarity_checked, PWMBusController, PWMChannelBacking, PWMChannelInfo,
+    PWMError, PolarityControl,
+};
+use crate::bus::pwm_sysfs::{complementary_timing, ComplementaryPair, PWMChannel, SysfsPWMBusController};
+use crate::gpio::{GpioBorrowChecker, PinState};
+use parking_lot::RwLock;
+use rppal::pwm::{Error, Polarity};
+use uuid::Uuid;
+
+struct FakePwm {
+    polarity: Cell<Polarity>,
+    readable: bool,
+    accepts_writes: bool,
+    applies_writes: bool,
+}
+
+impl FakePwm {
+    fn new(readable: bool, accepts_writes: bool, applies_writes: bool) -> Self {
+        FakePwm { polarity: Cell::new(Polarity::Inverse), readable, accepts_writes, applies_writes }
+    }
+}
+
+impl PolarityControl for FakePwm {
+    fn set_polarity(&self, polarity: Polarity) -> Result<(), Error> {
+        if !self.accepts_writes {
+            return Err(Error::Io(io::Error::new(ErrorKind::InvalidInput, "polarity write rejected")));
+        }
+
+        if self.applies_writes {
+            self.polarity.set(polarity);
+        }
+
+        Ok(())
+    }
+
+    fn polarity(&self) -> Result<Polarity, Error> {
+        match self.readable {
+            true => Ok(self.polarity.get()),
+            false => Err(Error::Io(io::Error::new(ErrorKind::NotFound, "no polarity attribute"))),
+        }
+    }
+}
+
+#[test]
+fn polarity_applied() {
+    let pwm = FakePwm::new(true, true, true);
+    assert!(probe_polarity_support(&pwm));
+    assert_eq!(set_polarity_checked(&pwm, Polarity::Normal), Ok(()));
+    assert_eq!(pwm.polarity.get(), Polarity::Normal);
+    assert_eq!(apply_polarity(&pwm, Polarity::Normal, false), Ok(()));
+}
+
+#[test]
+fn polarity_write_rejected() {
+    let pwm = FakePwm::new(true, false, false);
+    assert_eq!(set_polarity_checked(&pwm, Polarity::Normal), Err(PWMError::Unsupported));
+    assert_eq!(apply_polarity(&pwm, Polarity::Normal, false), Err(PWMError::Unsupported));
+    assert_eq!(apply_polarity(&pwm, Polarity::Normal, true), Ok(()));
+}
+
+#[test]
+fn polarity_write_not_applied() {
+    let pwm = FakePwm::new(true, true, false);
+    assert_eq!(set_polarity_checked(&pwm, Polarity::Normal), Err(PWMError::Unsupported));
+    assert_eq!(apply_polarity(&pwm, Polarity::Normal, false), Err(PWMError::Unsupported));
+}
+
+#[test]
+fn polarity_not_exposed() {
+    let pwm = FakePwm::new(false, true, true);
+    assert!(!probe_polarity_support(&pwm));
+    assert_eq!(apply_polarity(&pwm, Polarity::Normal, false), Err(PWMError::Unsupported));
+    assert_eq!(apply_polarity(&pwm, Polarity::Normal, true), Ok(()));
+}
+
+fn make_gpio() -> Arc<RwLock<GpioBorrowChecker>> {
+    let mut pin_map = HashMap::new();
+    for pin in 12..14 {
+        pin_map.insert(pin, PinState::new(pin, pin + 10));
+    }
+
+    Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)))
+}
+
+#[test]
+fn rppal_channels_listed() {
+    let gpio = make_gpio();
+    let mut controller = PWMBusController::new(&gpio, HashMap::from([(1, 13), (0, 12)])).expect("failed to build controller");
+    assert_eq!(controller.list_channels(), vec![
+        PWMChannelInfo { channel_id: 0, backing: PWMChannelBacking::Rppal(0), gpio_pin: 12, busy: false },
+        PWMChannelInfo { channel_id: 1, backing: PWMChannelBacking::Rppal(1), gpio_pin: 13, busy: false },
+    ]);
+
+    controller.open_with(1, |_| Ok(FakePwm::new(true, true, true))).expect("failed to open channel");
+    let busy: Vec<bool> = controller.list_channels().iter().map(|x| x.busy).collect();
+    assert_eq!(busy, vec![false, true]);
+}
+
+#[test]
+fn sysfs_channels_listed() {
+    let gpio = make_gpio();
+    let pin_config = HashMap::from([
+        (0, PWMChannel::new(0, 0, 12)),
+        (1, PWMChannel::new(2, 1, 13)),
+    ]);
+    let mut controller = SysfsPWMBusController::with_pin_config(&gpio, pin_config).expect("failed to build controller");
+    assert_eq!(controller.list_channels(), vec![
+        PWMChannelInfo { channel_id: 0, backing: PWMChannelBacking::Sysfs { chip_num: 0, chip_channel: 0 }, gpio_pin: 12, busy: false },
+        PWMChannelInfo { channel_id: 1, backing: PWMChannelBacking::Sysfs { chip_num: 2, chip_channel: 1 }, gpio_pin: 13, busy: false },
+    ]);
+
+    controller.open_with(0, |_| Ok(())).expect("failed to open channel");
+    let busy: Vec<bool> = controller.list_channels().iter().map(|x| x.busy).collect();
+    assert_eq!(busy, vec![true, false]);
+
+    // a failed open leaves the channel free
+    assert!(controller.open_with(1, |_| Err::<(), _>(PWMError::OsError("no such chip".to_string()))).is_err());
+    assert!(!controller.list_channels()[1].busy);
+}
+
+#[test]
+fn sysfs_missing_chip_reports_path() {
+    // the class directory is there but pwmchip1 is not
+    let root = std::env::temp_dir().join(format!("nvos_pwm_{}", Uuid::new_v4()));
+    fs::create_dir_all(root.join("pwmchip0")).unwrap();
+
+    let gpio = make_gpio();
+    let pin_config = HashMap::from([(0, PWMChannel::new(1, 0, 12))]);
+    let mut controller = SysfsPWMBusController::with_sysfs_root(&gpio, pin_config, &root).expect("failed to build controller");
+
+    let chip_path = root.join("pwmchip1").display().to_string();
+    match controller.open(0) {
+        Err(PWMError::ChannelNodeNotFound(0, path)) => assert_eq!(path, chip_path),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a channel on a missing chip"),
+    }
+
+    assert!(!controller.list_channels()[0].busy);
+    assert!(gpio.read().can_borrow_one(12));
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn sysfs_missing_class_directory_rejected() {
+    let root = std::env::temp_dir().join(format!("nvos_pwm_{}", Uuid::new_v4()));
+    let result = SysfsPWMBusController::with_sysfs_root(&make_gpio(), HashMap::new(), &root);
+    assert!(matches!(result, Err(PWMError::OsError(_))));
+}
+
+#[test]
+fn complementary_timing_never_overlaps() {
+    let period = 20_000;
+    let dead_time = 500;
+    for step in 0..=100 {
+        let duty = step as f32 / 100.0;
+        let timing = complementary_timing(period, duty, dead_time).expect("failed to compute timing");
+
+        // A is high over [0, a), B over [b, period)
+        assert!(timing.a_duty_ns + dead_time <= timing.b_duty_ns, "channels overlap at duty {}", duty);
+        assert!(timing.b_duty_ns <= period);
+        assert_eq!(timing.period_ns, period);
+    }
+
+    let half = complementary_timing(period, 0.5, dead_time).unwrap();
+    assert_eq!((half.a_duty_ns, half.b_duty_ns), (10_000, 10_500));
+
+    // no room for the dead time at full duty, A gives way and B stays off
+    let full = complementary_timing(period, 1.0, dead_time).unwrap();
+    assert_eq!((full.a_duty_ns, full.b_duty_ns), (19_500, 20_000));
+}
+
+#[test]
+fn complementary_timing_rejects_bad_input() {
+    assert!(matches!(complementary_timing(0, 0.5, 0), Err(PWMError::InvalidConfig(_))));
+    assert!(matches!(complementary_timing(1000, 1.5, 0), Err(PWMError::InvalidConfig(_))));
+    assert!(matches!(complementary_timing(1000, f32::NAN, 0), Err(PWMError::InvalidConfig(_))));
+    assert!(matches!(complementary_timing(1000, 0.5, 1000), Err(PWMError::InvalidConfig(_))));
+}
+
+#[test]
+fn complementary_pairs_validated() {
+    let gpio = make_gpio();
+    let build = |pairs: Vec<(u8, u8)>| {
+        let pin_config = HashMap::from([(0, PWMChannel::new(0, 0, 12)), (1, PWMChannel::new(0, 1, 13))]);
+        SysfsPWMBusController::with_pin_config(&gpio, pin_config)
+            .expect("failed to build controller")
+            .with_complementary_pairs(pairs.into_iter().map(|(a, b)| ComplementaryPair { channel_a: a, channel_b: b }).collect())
+    };
+
+    assert!(build(vec![(0, 1)]).is_ok());
+    assert!(matches!(build(vec![(0, 2)]), Err(PWMError::InvalidConfig(_))));
+    assert!(matches!(build(vec![(0, 0)]), Err(PWMError::InvalidConfig(_))));
+    assert!(matches!(build(vec![(0, 1), (1, 0)]), Err(PWMError::InvalidConfig(_))));
+
+    let mut controller = build(vec![(0, 1)]).unwrap();
+    assert!(matches!(controller.check_complementary(1, 0), Err(PWMError::InvalidConfig(_))));
+
+    // both channels have to be open
+    controller.open_with(0, |_| Ok(())).expect("failed to open channel");
+    assert!(matches!(controller.check_complementary(0, 1), Err(PWMError::LeaseNotFound)));
+    controller.open_with(1, |_| Ok(())).expect("failed to open channel");
+    assert!(controller.check_complementary(0, 1).is_ok());
+}
@@ -0,0 +1,221 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::capabilities::{Capability, CapabilityId, SensorCapable, ThermometerCapable};
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::rpc::rate_limit::{self, ReadCache};
+use crate::rpc::thermometer::{
+    thermometer_server::Thermometer, SetGainRequest, SetIntervalRequest, ThermometerRequest, ThermometerService,
+};
+use intertrait::cast_to;
+use parking_lot::RwLock;
+use tonic::{Code, Request, Status};
+use uuid::Uuid;
+
+struct CountingThermometer {
+    is_loaded: bool,
+    reads: Arc<AtomicUsize>,
+}
+
+impl DeviceDriver for CountingThermometer {
+    fn name(&self) -> String {
+        "counting_thermometer".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(CountingThermometer { is_loaded: false, reads: Arc::new(AtomicUsize::new(0)) })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for CountingThermometer {}
+
+impl SensorCapable for CountingThermometer {
+    fn get_supported_gains(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_supported_intervals(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_gain(&self, _reading: CapabilityId) -> Result<u16, DeviceError> {
+        Ok(1)
+    }
+
+    fn set_gain(&mut self, _reading: CapabilityId, _gain_id: u8) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn get_interval(&self) -> Result<u16, DeviceError> {
+        Ok(100)
+    }
+
+    fn set_interval(&mut self, _interval_id: u8) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+#[cast_to]
+impl ThermometerCapable for CountingThermometer {
+    // every hardware read returns a new value so cached responses are easy to spot
+    fn get_temperature_celsius(&mut self) -> Result<f32, DeviceError> {
+        Ok(self.reads.fetch_add(1, Ordering::SeqCst) as f32 + 1.0)
+    }
+
+    fn get_temperature_fahrenheit(&mut self) -> Result<f32, DeviceError> {
+        Ok(self.reads.fetch_add(1, Ordering::SeqCst) as f32 + 1.0)
+    }
+
+    fn reset_filter(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+fn make_service(min_interval_ms: u64) -> (ThermometerService, Arc<AtomicUsize>) {
+    let reads = Arc::new(AtomicUsize::new(0));
+    let driver = CountingThermometer { is_loaded: false, reads: reads.clone() };
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::from_driver(Box::new(driver), None, Some("thermo".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+
+    let limits = HashMap::from([(rate_limit::GET_TEMPERATURE_CELSIUS.to_string(), min_interval_ms)]);
+    (ThermometerService::with_read_limits(&Arc::new(RwLock::new(server)), &limits), reads)
+}
+
+fn celsius_request() -> Request<ThermometerRequest> {
+    Request::new(ThermometerRequest { address: "thermo".to_string() })
+}
+
+#[tokio::test]
+async fn read_cache_serves_rapid_reads() {
+    let cache = ReadCache::<Uuid, u32>::new(Duration::from_millis(50));
+    let address = Uuid::new_v4();
+    let mut reads = 0;
+
+    let first = cache.get_or_read_async(address, async { reads += 1; Ok(reads) }).await.unwrap();
+    assert!(!first.cached);
+    for _ in 0..5 {
+        let next = cache.get_or_read_async(address, async { reads += 1; Ok(reads) }).await.unwrap();
+        assert!(next.cached);
+        assert_eq!(next.value, 1);
+        assert_eq!(next.read_at, first.read_at);
+    }
+
+    // other keys are limited separately
+    assert!(!cache.get_or_read_async(Uuid::new_v4(), async { Ok(7) }).await.unwrap().cached);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let fresh = cache.get_or_read_async(address, async { reads += 1; Ok(reads) }).await.unwrap();
+    assert!(!fresh.cached);
+    assert_eq!(fresh.value, 2);
+    assert!(fresh.read_at > first.read_at);
+}
+
+#[tokio::test]
+async fn read_cache_does_not_store_errors() {
+    let cache = ReadCache::<Uuid, u32>::new(Duration::from_secs(60));
+    let address = Uuid::new_v4();
+
+    assert!(cache.get_or_read_async(address, async { Err(Status::internal("bus error")) }).await.is_err());
+    let value = cache.get_or_read_async(address, async { Ok(3) }).await.unwrap();
+    assert!(!value.cached);
+    assert_eq!(value.value, 3);
+}
+
+#[tokio::test]
+async fn read_cache_invalidates_matching_keys() {
+    let cache = ReadCache::<(Uuid, u8), u32>::new(Duration::from_secs(60));
+    let (address, other) = (Uuid::new_v4(), Uuid::new_v4());
+    for key in [(address, 0), (address, 1), (other, 0)] {
+        cache.get_or_read_async(key, async { Ok(1) }).await.unwrap();
+    }
+
+    cache.invalidate_where(|(cached, _)| *cached == address);
+    assert!(!cache.get_or_read_async((address, 0), async { Ok(2) }).await.unwrap().cached);
+    assert!(!cache.get_or_read_async((address, 1), async { Ok(2) }).await.unwrap().cached);
+    assert!(cache.get_or_read_async((other, 0), async { Ok(2) }).await.unwrap().cached);
+}
+
+#[tokio::test]
+async fn rate_limited_thermometer_reads() {
+    let (service, reads) = make_service(50);
+
+    let first = service.get_temperature_celsius(celsius_request()).await.unwrap().into_inner();
+    assert!(!first.cached);
+    assert_eq!(first.value, 1.0);
+    assert!(first.read_at_ms > 0);
+
+    for _ in 0..10 {
+        let cached = service.get_temperature_celsius(celsius_request()).await.unwrap().into_inner();
+        assert!(cached.cached);
+        assert_eq!(cached.value, 1.0);
+        assert_eq!(cached.read_at_ms, first.read_at_ms);
+    }
+    assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+    // methods without a configured interval always hit the hardware
+    service.get_temperature_fahrenheit(celsius_request()).await.unwrap();
+    service.get_temperature_fahrenheit(celsius_request()).await.unwrap();
+    assert_eq!(reads.load(Ordering::SeqCst), 3);
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let fresh = service.get_temperature_celsius(celsius_request()).await.unwrap().into_inner();
+    assert!(!fresh.cached);
+    assert_eq!(fresh.value, 4.0);
+    assert_eq!(reads.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn rate_limit_disabled_by_default() {
+    let (service, reads) = make_service(0);
+    for _ in 0..3 {
+        let response = service.get_temperature_celsius(celsius_request()).await.unwrap().into_inner();
+        assert!(!response.cached);
+    }
+
+    assert_eq!(reads.load(Ordering::SeqCst), 3);
+    let err = service.get_temperature_celsius(Request::new(ThermometerRequest { address: "missing".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn gain_and_interval_changes_drop_cached_reads() {
+    let (service, reads) = make_service(60_000);
+    service.get_temperature_celsius(celsius_request()).await.unwrap();
+    assert!(service.get_temperature_celsius(celsius_request()).await.unwrap().into_inner().cached);
+
+    service.set_gain(Request::new(SetGainRequest { address: "thermo".to_string(), gain_id: 1 })).await.unwrap();
+    let after_gain = service.get_temperature_celsius(celsius_request()).await.unwrap().into_inner();
+    assert!(!after_gain.cached);
+    assert_eq!(after_gain.value, 2.0);
+
+    service.set_interval(Request::new(SetIntervalRequest { address: "thermo".to_string(), interval_id: 1 })).await.unwrap();
+    assert!(!service.get_temperature_celsius(celsius_request()).await.unwrap().into_inner().cached);
+    assert_eq!(reads.load(Ordering::SeqCst), 3);
+}
@@ -0,0 +1,143 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use serde_json::{json, Value};
+
+use crate::bus::{declared_pins, find_pin_conflicts, BusController, ControllerRegistry, PinConflict};
+use crate::config::{BusControllerConfig, DeviceConfig};
+use crate::device::{BusEntry, DeviceDriver, DeviceError, DeviceServer};
+use crate::drivers::DriverRegistry;
+use crate::gpio::GpioBorrowChecker;
+
+struct StubDriver {
+    is_loaded: bool,
+}
+
+impl DeviceDriver for StubDriver {
+    fn name(&self) -> String {
+        "stub".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self : Sized {
+        Ok(StubDriver { is_loaded: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct StubController {}
+
+impl BusController for StubController {
+    fn name(&self) -> String {
+        "stub".to_string()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[test]
+fn registered_driver_is_constructed() {
+    let mut registry = DriverRegistry::new();
+    registry.register_driver::<StubDriver>("stub");
+    assert!(registry.contains("STUB"));
+
+    let mut config = DeviceConfig::new("Stub".to_string(), Some("my-stub".to_string()), Value::Null);
+    let device = registry.build(&mut config).expect("failed to build device");
+    assert_eq!(device.driver_name(), "stub");
+    assert_eq!(device.device_name(), "my-stub");
+    assert!(device.as_any().downcast_ref::<StubDriver>().is_some());
+}
+
+#[test]
+fn unknown_driver_is_rejected() {
+    let registry = DriverRegistry::builtin();
+    let mut config = DeviceConfig::new("flux_capacitor".to_string(), None, Value::Null);
+    match registry.build(&mut config) {
+        Err(DeviceError::InvalidConfig(message, _)) => assert!(message.contains("flux_capacitor"), "unexpected error: {}", message),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("unknown driver was built")
+    }
+}
+
+#[test]
+fn builtin_registries_cover_shipped_drivers() {
+    let drivers = DriverRegistry::builtin();
+    for name in ["sysfs_generic_led", "gps_uart", "tsl2591_sysfs", "bmp280_sysfs", "led_group"] {
+        assert!(drivers.contains(name), "driver {} is not registered", name);
+    }
+
+    let controllers = ControllerRegistry::builtin();
+    for name in ["raw", "raw_sysfs", "pwm", "pwm_sysfs", "uart", "i2c", "i2c_sysfs"] {
+        assert!(controllers.contains(name), "controller {} is not registered", name);
+    }
+}
+
+#[test]
+fn registered_controller_is_constructed() {
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(HashMap::new())));
+    let mut registry = ControllerRegistry::new();
+    registry.register("stub", |_, _| Ok(BusEntry::new(Arc::new(RwLock::new(StubController {})))));
+
+    let mut config = BusControllerConfig { name: "Stub".to_string(), data: Value::Null };
+    let mut server = DeviceServer::new();
+    server.register_bus(registry.build(&gpio, &mut config).expect("failed to build controller")).unwrap();
+    assert!(server.has_bus::<StubController>());
+
+    config.name = "flux_capacitor".to_string();
+    let err = registry.build(&gpio, &mut config).err().expect("unknown controller was built");
+    assert!(err.contains("flux_capacitor"), "unexpected error: {}", err);
+}
+
+#[test]
+fn pin_conflicts_across_controllers_are_detected() {
+    let configs = vec![
+        BusControllerConfig::new("i2c_sysfs".to_string(), json!({ "channels": { "1": { "sda": 2, "scl": 3 } } })),
+        BusControllerConfig::new("pwm".to_string(), json!({ "channels": { "0": 3, "1": 13 } })),
+        BusControllerConfig::new("uart".to_string(), json!({ "internal_ports": { "0": { "path": "/dev/ttyS0", "rx": 15, "tx": 14 } } })),
+    ];
+
+    let conflicts = find_pin_conflicts(&configs);
+    assert_eq!(conflicts, vec![PinConflict { pin: 3, controllers: vec!["i2c_sysfs".to_string(), "pwm".to_string()] }]);
+    assert_eq!(declared_pins(&configs[2]), vec![14, 15]);
+}
+
+#[test]
+fn pin_overlap_within_one_controller_is_left_to_the_controller() {
+    let configs = vec![
+        BusControllerConfig::new("pwm_sysfs".to_string(), json!({ "channels": {
+            "0": { "chip_num": 0, "chip_channel": 0, "gpio_num": 12 },
+            "1": { "chip_num": 0, "chip_channel": 1, "gpio_num": 12 }
+        } })),
+        BusControllerConfig::new("raw_sysfs".to_string(), Value::Null),
+    ];
+
+    assert!(find_pin_conflicts(&configs).is_empty());
+}
@@ -0,0 +1,195 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::capabilities::{Capability, LEDControllerCapable, LEDMode};
+use crate::config::{ApiTokenConfig, ConfigSectionRPC};
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::rpc::auth::AuthTokens;
+use crate::rpc::rest::{RestGateway, SensorServices};
+use crate::shutdown::ShutdownHook;
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use axum::Router;
+use intertrait::cast_to;
+use parking_lot::RwLock;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+struct StubLed {
+    is_loaded: bool,
+    brightness: f32,
+    powered_on: bool,
+}
+
+impl DeviceDriver for StubLed {
+    fn name(&self) -> String {
+        "stub_led".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(StubLed { is_loaded: false, brightness: 0.5, powered_on: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for StubLed {}
+
+#[cast_to]
+impl LEDControllerCapable for StubLed {
+    fn get_mode(&self) -> Result<LEDMode, DeviceError> {
+        Ok(LEDMode::Visible)
+    }
+
+    fn set_mode(&mut self, _mode: LEDMode) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn get_brightness(&self) -> Result<f32, DeviceError> {
+        Ok(self.brightness)
+    }
+
+    fn set_brightness(&mut self, brightness: f32) -> Result<(), DeviceError> {
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    fn get_power_state(&self) -> Result<bool, DeviceError> {
+        Ok(self.powered_on)
+    }
+
+    fn set_power_state(&mut self, powered_on: bool) -> Result<(), DeviceError> {
+        self.powered_on = powered_on;
+        Ok(())
+    }
+}
+
+fn make_router(auth: AuthTokens) -> Router {
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<StubLed>(None, Some("led0".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+
+    let server = Arc::new(RwLock::new(server));
+    let sensors = SensorServices::from_config(&server, &ConfigSectionRPC::new("127.0.0.1".to_string(), 30000));
+    RestGateway::new(&server, auth, &sensors).router()
+}
+
+async fn send(router: &Router, method: Method, uri: &str, token: Option<&str>, body: Option<Value>) -> (StatusCode, Value) {
+    let mut req = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        req = req.header("authorization", format!("Bearer {}", token));
+    }
+
+    let req = match body {
+        Some(body) => req.header("content-type", "application/json").body(Body::from(body.to_string())),
+        None => req.body(Body::empty()),
+    }.unwrap();
+
+    let response = router.clone().oneshot(req).await.unwrap();
+    let status = response.status();
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+#[tokio::test]
+async fn rest_led_state_round_trip() {
+    let router = make_router(AuthTokens::default());
+
+    let (status, body) = send(&router, Method::POST, "/devices/led0/led/brightness", None, Some(json!({ "brightness": 0.25 }))).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["brightness"], json!(0.25));
+    let (status, _) = send(&router, Method::POST, "/devices/led0/led/power", None, Some(json!({ "powered_on": true }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = send(&router, Method::GET, "/devices/led0/led/state", None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["brightness"], json!(0.25));
+    assert_eq!(body["powered_on"], json!(true));
+    assert_eq!(body["mode"], json!("visible"));
+}
+
+#[tokio::test]
+async fn led_effective_brightness_is_zero_while_powered_off() {
+    let router = make_router(AuthTokens::default());
+
+    // the stub starts powered off
+    send(&router, Method::POST, "/devices/led0/led/brightness", None, Some(json!({ "brightness": 0.25 }))).await;
+    let (status, body) = send(&router, Method::GET, "/devices/led0/led/state", None, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["powered_on"], json!(false));
+    assert_eq!(body["brightness"], json!(0.25));
+    assert_eq!(body["effective_brightness"], json!(0.0));
+
+    send(&router, Method::POST, "/devices/led0/led/power", None, Some(json!({ "powered_on": true }))).await;
+    let (_, body) = send(&router, Method::GET, "/devices/led0/led/state", None, None).await;
+    assert_eq!(body["brightness"], json!(0.25));
+    assert_eq!(body["effective_brightness"], json!(0.25));
+}
+
+#[tokio::test]
+async fn rest_maps_status_codes() {
+    let router = make_router(AuthTokens::default());
+
+    let (status, body) = send(&router, Method::POST, "/devices/led0/led/brightness", None, Some(json!({ "brightness": 1.5 }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body["error"].is_string());
+
+    let (status, _) = send(&router, Method::GET, "/devices/missing/led/state", None, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = send(&router, Method::GET, "/devices/led0/thermometer/celsius", None, None).await;
+    assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+}
+
+#[tokio::test]
+async fn rest_applies_auth_tokens() {
+    let tokens = AuthTokens::from_config(&[
+        ApiTokenConfig::new("viewer".to_string(), vec!["led:read".to_string()]),
+    ]).unwrap();
+    let router = make_router(tokens);
+
+    let (status, _) = send(&router, Method::GET, "/devices/led0/led/state", None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = send(&router, Method::GET, "/devices/led0/led/state", Some("viewer"), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&router, Method::POST, "/devices/led0/led/power", Some("viewer"), Some(json!({ "powered_on": true }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn rest_gateway_stops_with_the_shutdown_hook() {
+    let server = Arc::new(RwLock::new(DeviceServer::new()));
+    let (rpc_shutdown, _rpc_receiver) = tokio::sync::mpsc::channel(1);
+    let hook = ShutdownHook::new(&server, None, rpc_shutdown);
+    let sensors = SensorServices::from_config(&server, &ConfigSectionRPC::new("127.0.0.1".to_string(), 30000));
+    let gateway = RestGateway::new(&server, AuthTokens::default(), &sensors);
+
+    let serving = tokio::spawn(gateway.serve("127.0.0.1:0".parse().unwrap(), hook.stopped()));
+    assert!(hook.shutdown().await);
+    let result = tokio::time::timeout(Duration::from_secs(2), serving).await.expect("gateway kept running after shutdown");
+    assert!(result.unwrap().is_ok());
+}
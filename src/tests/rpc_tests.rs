@@ -0,0 +1,1510 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bus::{BusController, ControllerRegistry, GpioController};
+use crate::capabilities::{
+    Capability, CapabilityId, DiagnosticsCapable, HealthReport, LEDControllerCapable, LEDMode, SensorCapable,
+    ThermometerCapable,
+};
+use crate::config::store::{backup_path, merge_patch, ConfigStore};
+use crate::config::{BusControllerConfig, Configuration, DeviceConfig};
+use crate::device::{BusEntry, Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::drivers::DriverRegistry;
+use crate::events::DeviceEventKind;
+use crate::gpio::{GpioBorrowChecker, GpioError, PinDirection, PinState};
+use crate::rpc::gpio::{
+    gpio_server::Gpio, GpioService, PinDirection as RpcPinDirection, ReadPinRequest,
+    ReleasePinRequest, SetDirectionRequest, WritePinRequest, WritePinsRequest,
+};
+use crate::rpc::barometer::BarometerService;
+use crate::rpc::heartbeat::{heartbeat_server::Heartbeat, AdbState, HeartbeatService};
+use crate::rpc::led::{
+    led_controller_server::LedController, GetStateRequest, LEDControllerService, LedMode as RpcLedMode, SetModeRequest,
+};
+use crate::rpc::light_sensor::LightSensorService;
+use crate::rpc::rest::SensorServices;
+use crate::rpc::{resolve_address, server_reflection_service};
+use crate::rpc::telemetry::{
+    read_result::Value as ReadValue, telemetry_server::Telemetry, BatchReadRequest, ReadRequest, TelemetryService,
+};
+use crate::rpc::reflection::{
+    device_reflection_server::DeviceReflection, CapabilityId as RpcCapabilityId,
+    DeviceReflectionService, FindDevicesByTagRequest, FindDevicesRequest, GetDeviceInfoRequest, GetDeviceStatsRequest,
+    GpioPinDirection as RpcGpioPinDirection, ImportTopologyRequest,
+    RunSelfTestRequest, UpdateDeviceConfigRequest,
+};
+use crate::rpc::access_log::{AccessLogEntry, AccessLogLayer};
+use crate::rpc::concurrency_limit::ConcurrencyLimitLayer;
+use crate::rpc::thermometer::{
+    thermometer_server::{Thermometer, ThermometerServer}, ThermometerRequest,
+    ThermometerService,
+};
+use crate::rpc::void::Void;
+use crate::topology::{load_topology, restore_topology};
+use intertrait::cast_to;
+use parking_lot::RwLock;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tonic::{Code, Request};
+use tonic_reflection::pb::{
+    server_reflection_client::ServerReflectionClient, server_reflection_request::MessageRequest,
+    server_reflection_response::MessageResponse, ServerReflectionRequest,
+};
+use uuid::Uuid;
+
+struct MockPin {
+    lease: Uuid,
+    direction: PinDirection,
+    value: u8,
+}
+
+struct MockGpioController {
+    gpio_borrow: Arc<RwLock<GpioBorrowChecker>>,
+    held_pins: HashMap<u8, MockPin>,
+}
+
+impl BusController for MockGpioController {
+    fn name(&self) -> String {
+        "MOCK_GPIO".to_string()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn as_gpio_mut(&mut self) -> Option<&mut (dyn GpioController + 'static)> {
+        Some(self)
+    }
+
+    fn owned_pins(&self) -> Vec<u8> {
+        self.held_pins.keys().copied().collect()
+    }
+}
+
+impl GpioController for MockGpioController {
+    fn read_pin(&mut self, pin: u8) -> Result<u8, GpioError> {
+        if let Some(held) = self.held_pins.get(&pin) {
+            return Ok(held.value);
+        }
+
+        let mut borrow_checker = self.gpio_borrow.write();
+        let lease = borrow_checker.borrow_one(pin)?;
+        borrow_checker.release(&lease)?;
+        Ok(0)
+    }
+
+    fn write_pin(&mut self, pin: u8, value: u8) -> Result<(), GpioError> {
+        if !self.held_pins.contains_key(&pin) {
+            self.set_direction(pin, PinDirection::Output)?;
+        }
+
+        let held = self.held_pins.get_mut(&pin).unwrap();
+        if held.direction != PinDirection::Output {
+            return Err(GpioError::Other("pin is not an output".to_string()));
+        }
+
+        held.value = value;
+        self.gpio_borrow.write().record_value(pin, value)
+    }
+
+    fn set_direction(&mut self, pin: u8, direction: PinDirection) -> Result<(), GpioError> {
+        if let Some(held) = self.held_pins.get_mut(&pin) {
+            held.direction = direction;
+            return self.gpio_borrow.write().record_direction(pin, direction);
+        }
+
+        let mut borrow_checker = self.gpio_borrow.write();
+        let lease = borrow_checker.borrow_one(pin)?;
+        borrow_checker.record_direction(pin, direction)?;
+        self.held_pins.insert(pin, MockPin { lease, direction, value: 0 });
+        Ok(())
+    }
+
+    fn release_pin(&mut self, pin: u8) -> Result<(), GpioError> {
+        match self.held_pins.remove(&pin) {
+            Some(held) => self.gpio_borrow.write().release(&held.lease),
+            None => Err(GpioError::LeaseNotFound),
+        }
+    }
+
+    fn holds_pin(&self, pin: u8) -> bool {
+        self.held_pins.contains_key(&pin)
+    }
+}
+
+fn make_gpio_service() -> (GpioService, Arc<RwLock<GpioBorrowChecker>>) {
+    let mut pin_map = HashMap::new();
+    pin_map.insert(2, PinState::new(2, 12));
+    pin_map.insert(3, PinState::new(3, 13));
+    let gpio_borrow = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+
+    let server = DeviceServerBuilder::configure()
+        .add_bus(MockGpioController {
+            gpio_borrow: gpio_borrow.clone(),
+            held_pins: HashMap::new(),
+        })
+        .build(false)
+        .unwrap();
+
+    (GpioService::new(&Arc::new(RwLock::new(server))), gpio_borrow)
+}
+
+#[tokio::test]
+async fn gpio_write_read_round_trip() {
+    let (service, _) = make_gpio_service();
+
+    service.write_pin(Request::new(WritePinRequest { pin: 2, value: 1 })).await.unwrap();
+    let response = service.read_pin(Request::new(ReadPinRequest { pin: 2 })).await.unwrap();
+    assert_eq!(response.get_ref().value, 1);
+
+    service.write_pin(Request::new(WritePinRequest { pin: 2, value: 0 })).await.unwrap();
+    let response = service.read_pin(Request::new(ReadPinRequest { pin: 2 })).await.unwrap();
+    assert_eq!(response.get_ref().value, 0);
+}
+
+#[tokio::test]
+async fn gpio_direction_change() {
+    let (service, gpio_borrow) = make_gpio_service();
+
+    service.set_direction(Request::new(SetDirectionRequest { pin: 3, direction: RpcPinDirection::Input as i32 })).await.unwrap();
+    assert!(!gpio_borrow.read().can_borrow_one(3));
+
+    let err = service.write_pin(Request::new(WritePinRequest { pin: 3, value: 1 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::Unknown);
+
+    service.set_direction(Request::new(SetDirectionRequest { pin: 3, direction: RpcPinDirection::Output as i32 })).await.unwrap();
+    assert!(service.write_pin(Request::new(WritePinRequest { pin: 3, value: 1 })).await.is_ok());
+
+    service.release_pin(Request::new(ReleasePinRequest { pin: 3 })).await.unwrap();
+    assert!(gpio_borrow.read().can_borrow_one(3));
+}
+
+#[tokio::test]
+async fn gpio_state_tracks_pin_values() {
+    let (service, gpio_borrow) = make_gpio_service();
+    let reflection = DeviceReflectionService::new(&Arc::new(RwLock::new(DeviceServer::new()))).with_gpio(&gpio_borrow);
+
+    let pins = reflection.get_gpio_state(Request::new(Void::default())).await.unwrap().into_inner().pins;
+    assert_eq!(pins.len(), 2);
+    assert_eq!((pins[0].pin, pins[0].bcm_id), (2, 12));
+    assert!(!pins[0].leased);
+    assert_eq!(pins[0].direction, RpcGpioPinDirection::Unconfigured as i32);
+    assert_eq!(pins[0].last_value, None);
+
+    service.write_pin(Request::new(WritePinRequest { pin: 2, value: 1 })).await.unwrap();
+    assert_eq!(gpio_borrow.read().get(&2).unwrap().direction(), Some(PinDirection::Output));
+    assert_eq!(gpio_borrow.read().get(&2).unwrap().last_value(), Some(1));
+
+    let pins = reflection.get_gpio_state(Request::new(Void::default())).await.unwrap().into_inner().pins;
+    assert!(pins[0].leased);
+    assert_eq!(pins[0].direction, RpcGpioPinDirection::Output as i32);
+    assert_eq!(pins[0].last_value, Some(1));
+
+    service.write_pin(Request::new(WritePinRequest { pin: 2, value: 0 })).await.unwrap();
+    assert_eq!(gpio_borrow.read().get(&2).unwrap().last_value(), Some(0));
+
+    // released pins go back to an unknown state
+    service.release_pin(Request::new(ReleasePinRequest { pin: 2 })).await.unwrap();
+    let pins = reflection.get_gpio_state(Request::new(Void::default())).await.unwrap().into_inner().pins;
+    assert!(!pins[0].leased);
+    assert_eq!(pins[0].direction, RpcGpioPinDirection::Unconfigured as i32);
+    assert_eq!(pins[0].last_value, None);
+
+    let err = DeviceReflectionService::new(&Arc::new(RwLock::new(DeviceServer::new())))
+        .get_gpio_state(Request::new(Void::default())).await.unwrap_err();
+    assert_eq!(err.code(), Code::Unavailable);
+}
+
+#[tokio::test]
+async fn reflection_reports_server_info() {
+    let reflection = DeviceReflectionService::new(&Arc::new(RwLock::new(DeviceServer::new())));
+    let info = reflection.get_server_info(Request::new(Void::default())).await.unwrap().into_inner();
+
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(!info.git_hash.is_empty());
+    for driver in ["sysfs_generic_led", "gps_uart", "bmp280_sysfs", "tsl2591_sysfs"] {
+        assert!(info.drivers.iter().any(|x| x == driver), "driver {} is missing", driver);
+    }
+
+    for controller in ["raw_sysfs", "pwm_sysfs", "i2c_sysfs", "uart"] {
+        assert!(info.controllers.iter().any(|x| x == controller), "controller {} is missing", controller);
+    }
+}
+
+#[tokio::test]
+async fn reflection_lists_gpio_pin_owners() {
+    let mut pin_map = HashMap::new();
+    pin_map.insert(2, PinState::new(2, 12));
+    pin_map.insert(3, PinState::new(3, 13));
+    pin_map.insert(4, PinState::new(4, 14));
+    let gpio_borrow = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+
+    let mut controller = MockGpioController { gpio_borrow: gpio_borrow.clone(), held_pins: HashMap::new() };
+    controller.set_direction(3, PinDirection::Output).unwrap();
+    // leased outside of any controller
+    gpio_borrow.write().borrow_one(4).unwrap();
+
+    let server = DeviceServerBuilder::configure().add_bus(controller).build(false).unwrap();
+    let service = DeviceReflectionService::new(&Arc::new(RwLock::new(server))).with_gpio(&gpio_borrow);
+
+    let response = service.list_gpio_pins(Request::new(Void::default())).await.unwrap().into_inner();
+    assert_eq!(response.count, 3);
+    let pins: Vec<(u32, u32, bool, Option<String>)> = response.pins.into_iter()
+        .map(|x| (x.pin_id, x.bcm_id, x.leased, x.owner))
+        .collect();
+    assert_eq!(pins, vec![
+        (2, 12, false, None),
+        (3, 13, true, Some("MOCK_GPIO".to_string())),
+        (4, 14, true, None),
+    ]);
+}
+
+#[tokio::test]
+async fn gpio_leased_pin_rejected() {
+    let (service, gpio_borrow) = make_gpio_service();
+    gpio_borrow.write().borrow_one(2).unwrap();
+
+    let err = service.write_pin(Request::new(WritePinRequest { pin: 2, value: 1 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+
+    let err = service.read_pin(Request::new(ReadPinRequest { pin: 2 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+}
+
+#[tokio::test]
+async fn gpio_invalid_arguments() {
+    let (service, _) = make_gpio_service();
+
+    let err = service.read_pin(Request::new(ReadPinRequest { pin: 300 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let err = service.write_pin(Request::new(WritePinRequest { pin: 2, value: 5 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::OutOfRange);
+
+    let err = service.read_pin(Request::new(ReadPinRequest { pin: 7 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+struct PlainDevice {
+    is_loaded: bool,
+}
+
+impl DeviceDriver for PlainDevice {
+    fn name(&self) -> String {
+        "plain".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(PlainDevice { is_loaded: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct StubLed {
+    is_loaded: bool,
+}
+
+impl DeviceDriver for StubLed {
+    fn name(&self) -> String {
+        "stub_led".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(StubLed { is_loaded: false })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for StubLed {}
+
+#[cast_to]
+impl LEDControllerCapable for StubLed {
+    fn get_mode(&self) -> Result<LEDMode, DeviceError> {
+        Ok(LEDMode::Visible)
+    }
+
+    fn set_mode(&mut self, mode: LEDMode) -> Result<(), DeviceError> {
+        match mode {
+            LEDMode::Visible => Ok(()),
+            mode => Err(DeviceError::InvalidOperation(format!("LED mode {} is not supported", mode.name())))
+        }
+    }
+
+    fn get_brightness(&self) -> Result<f32, DeviceError> {
+        Ok(1.0)
+    }
+
+    fn set_brightness(&mut self, _brightness: f32) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn get_power_state(&self) -> Result<bool, DeviceError> {
+        Ok(true)
+    }
+
+    fn set_power_state(&mut self, _powered_on: bool) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+struct StubThermometer {
+    is_loaded: bool,
+    // reads fail with a hardware error while set
+    failing: Arc<AtomicBool>,
+}
+
+impl DeviceDriver for StubThermometer {
+    fn name(&self) -> String {
+        "stub_thermometer".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(StubThermometer { is_loaded: false, failing: Arc::default() })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for StubThermometer {}
+
+impl SensorCapable for StubThermometer {
+    fn get_supported_gains(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_supported_intervals(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_gain(&self, _reading: CapabilityId) -> Result<u16, DeviceError> {
+        Ok(1)
+    }
+
+    fn set_gain(&mut self, _reading: CapabilityId, _gain_id: u8) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn get_interval(&self) -> Result<u16, DeviceError> {
+        Ok(100)
+    }
+
+    fn set_interval(&mut self, _interval_id: u8) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+#[cast_to]
+impl ThermometerCapable for StubThermometer {
+    fn get_temperature_celsius(&mut self) -> Result<f32, DeviceError> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(DeviceError::hardware_error("sensor did not respond"));
+        }
+
+        Ok(20.0)
+    }
+
+    fn get_temperature_fahrenheit(&mut self) -> Result<f32, DeviceError> {
+        Ok(68.0)
+    }
+
+    fn reset_filter(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+fn make_mixed_server() -> Arc<RwLock<DeviceServer>> {
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<PlainDevice>(None, Some("plain".to_string())).unwrap())
+        .add_device(Device::new::<StubLed>(None, Some("led0".to_string())).unwrap())
+        .add_device(Device::new::<StubLed>(None, Some("led1".to_string())).unwrap())
+        .add_device(Device::new::<StubThermometer>(None, Some("thermo".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+
+    Arc::new(RwLock::new(server))
+}
+
+#[tokio::test]
+async fn heartbeat_status_reflects_the_server() {
+    let server = make_mixed_server();
+    let service = HeartbeatService::new(&server);
+
+    let status = service.status(Request::new(Void::default())).await.unwrap().into_inner();
+    assert_eq!(status.device_count, 4);
+    assert_eq!(status.bus_count, 0);
+    assert_eq!(status.adb, AdbState::Disabled as i32);
+    assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+
+    let address = resolve_address(&server.read(), "plain").unwrap();
+    server.write().remove_device(&address).unwrap();
+    let later = service.status(Request::new(Void::default())).await.unwrap().into_inner();
+    assert_eq!(later.device_count, 3);
+    assert!(later.uptime_ms >= status.uptime_ms);
+
+    // ping stays as the lightweight check
+    service.ping(Request::new(Void::default())).await.unwrap();
+}
+
+#[tokio::test]
+async fn reflection_find_devices_filters_by_capability() {
+    let service = DeviceReflectionService::new(&make_mixed_server());
+
+    let response = service.find_devices(Request::new(FindDevicesRequest { capability: RpcCapabilityId::LedController as i32 })).await.unwrap();
+    let mut names: Vec<String> = response.get_ref().devices.iter().map(|d| d.device_name.clone()).collect();
+    names.sort();
+    assert_eq!(response.get_ref().count, 2);
+    assert_eq!(names, vec!["led0".to_string(), "led1".to_string()]);
+
+    let response = service.find_devices(Request::new(FindDevicesRequest { capability: RpcCapabilityId::Thermometer as i32 })).await.unwrap();
+    assert_eq!(response.get_ref().count, 1);
+    assert_eq!(response.get_ref().devices[0].driver_name, "stub_thermometer");
+
+    let response = service.find_devices(Request::new(FindDevicesRequest { capability: RpcCapabilityId::Gps as i32 })).await.unwrap();
+    assert_eq!(response.get_ref().count, 0);
+
+    let err = service.find_devices(Request::new(FindDevicesRequest { capability: 99 })).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn reflection_get_device_info() {
+    let server = make_mixed_server();
+    let address = server.read().get_device_with_name("led0").unwrap().address();
+    let service = DeviceReflectionService::new(&server);
+
+    let response = service.get_device_info(Request::new(GetDeviceInfoRequest { address: address.to_string() })).await.unwrap();
+    let info = response.get_ref();
+    assert_eq!(info.device_name, "led0");
+    assert_eq!(info.driver_name, "stub_led");
+    assert!(info.is_running);
+    assert_eq!(info.capabilities, vec![RpcCapabilityId::LedController as i32]);
+
+    let err = service.get_device_info(Request::new(GetDeviceInfoRequest { address: Uuid::new_v4().to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+
+    let err = service.get_device_info(Request::new(GetDeviceInfoRequest { address: "nope".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[test]
+fn resolve_address_by_uuid_and_name() {
+    let server = make_mixed_server();
+    let server = server.read();
+    let address = server.get_device_with_name("thermo").unwrap().address();
+
+    assert_eq!(resolve_address(&server, &address.to_string()).unwrap(), address);
+    assert_eq!(resolve_address(&server, "thermo").unwrap(), address);
+
+    let err = resolve_address(&server, "missing").unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn led_service_accepts_friendly_name() {
+    let service = LEDControllerService::new(&make_mixed_server());
+
+    let response = service.get_state(Request::new(GetStateRequest { address: "led1".to_string() })).await.unwrap();
+    assert!(response.get_ref().powered_on);
+
+    let err = service.get_state(Request::new(GetStateRequest { address: "thermo".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let err = service.get_state(Request::new(GetStateRequest { address: "led9".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+const STUB_CHIP_ID: u8 = 0x50;
+
+struct StubChip {
+    reported_chip_id: u8,
+    last_error: Option<DeviceError>,
+}
+
+impl DeviceDriver for StubChip {
+    fn name(&self) -> String {
+        "stub_chip".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        true
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(StubChip { reported_chip_id: STUB_CHIP_ID, last_error: None })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for StubChip {}
+
+#[cast_to]
+impl DiagnosticsCapable for StubChip {
+    fn self_test(&mut self) -> Result<HealthReport, DeviceError> {
+        Ok(HealthReport::new(Some(self.reported_chip_id), STUB_CHIP_ID, self.last_error.as_ref(), Duration::from_secs(5)))
+    }
+}
+
+#[test]
+fn health_report_wrong_chip_id() {
+    let report = HealthReport::new(Some(0x51), 0x50, None, Duration::ZERO);
+    assert!(!report.healthy);
+    assert!(!report.chip_id_verified);
+
+    let report = HealthReport::new(None, 0x50, Some(&DeviceError::hardware_error("bus timeout")), Duration::ZERO);
+    assert!(!report.healthy);
+    assert!(report.last_error.unwrap().contains("bus timeout"));
+
+    let report = HealthReport::new(Some(0x50), 0x50, None, Duration::from_secs(1));
+    assert!(report.healthy);
+}
+
+#[test]
+fn health_report_from_chip_id_read() {
+    let mut last_error = None;
+    let report = HealthReport::from_chip_id_read(Ok::<u8, std::io::Error>(0x50), 0x50, &mut last_error, Some(Instant::now()));
+    assert!(report.healthy);
+    assert!(last_error.is_none());
+
+    // a failed read is kept as the last error until the driver clears it
+    let failed = Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "bus timeout"));
+    let report = HealthReport::from_chip_id_read(failed, 0x50, &mut last_error, None);
+    assert!(!report.healthy);
+    assert_eq!(report.reported_chip_id, None);
+    assert_eq!(report.uptime, Duration::ZERO);
+    assert!(report.last_error.unwrap().contains("failed to identify chip: bus timeout"));
+    assert!(matches!(last_error, Some(DeviceError::HardwareError(..))));
+}
+
+#[tokio::test]
+async fn reflection_run_self_test() {
+    let healthy = StubChip { reported_chip_id: STUB_CHIP_ID, last_error: None };
+    let broken = StubChip {
+        reported_chip_id: 0x00,
+        last_error: Some(DeviceError::hardware_error("failed to read sensor data")),
+    };
+
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::from_driver(Box::new(healthy), None, Some("good".to_string())).unwrap())
+        .add_device(Device::from_driver(Box::new(broken), None, Some("bad".to_string())).unwrap())
+        .add_device(Device::new::<PlainDevice>(None, Some("plain".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+    let service = DeviceReflectionService::new(&Arc::new(RwLock::new(server)));
+
+    let response = service.run_self_test(Request::new(RunSelfTestRequest { address: "good".to_string() })).await.unwrap();
+    assert!(response.get_ref().healthy);
+    assert_eq!(response.get_ref().uptime_ms, 5000);
+
+    let response = service.run_self_test(Request::new(RunSelfTestRequest { address: "bad".to_string() })).await.unwrap();
+    let report = response.get_ref();
+    assert!(!report.healthy);
+    assert!(!report.chip_id_verified);
+    assert_eq!(report.reported_chip_id, Some(0x00));
+    assert!(report.last_error.as_ref().unwrap().contains("failed to read sensor data"));
+
+    let err = service.run_self_test(Request::new(RunSelfTestRequest { address: "plain".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+}
+
+fn read_request(address: &str, capability: RpcCapabilityId, method: &str) -> ReadRequest {
+    ReadRequest { address: address.to_string(), capability: capability as i32, method: method.to_string() }
+}
+
+#[tokio::test]
+async fn telemetry_batch_read_mixed_results() {
+    let service = TelemetryService::new(&make_mixed_server());
+    let request = BatchReadRequest {
+        requests: vec![
+            read_request("led0", RpcCapabilityId::LedController, "get_brightness"),
+            read_request("thermo", RpcCapabilityId::Thermometer, "get_temperature_celsius"),
+            read_request("missing", RpcCapabilityId::Thermometer, "get_temperature_celsius"),
+            read_request("led1", RpcCapabilityId::LedController, "get_power_state"),
+            read_request("plain", RpcCapabilityId::Thermometer, "get_temperature_celsius"),
+            read_request("thermo", RpcCapabilityId::Thermometer, "set_gain"),
+        ],
+    };
+
+    let results = service.batch_read(Request::new(request)).await.unwrap().into_inner().results;
+    assert_eq!(results.len(), 6);
+
+    assert!(results[0].success);
+    assert_eq!(results[0].address, "led0");
+    assert_eq!(results[0].value, Some(ReadValue::Number(1.0)));
+
+    assert!(results[1].success);
+    assert_eq!(results[1].value, Some(ReadValue::Number(20.0)));
+
+    assert!(!results[2].success);
+    assert_eq!(results[2].value, None);
+    assert_eq!(results[2].error_code, Code::NotFound as i32);
+    assert!(results[2].error.contains("missing"));
+
+    // a failed item must not stop the rest of the batch
+    assert!(results[3].success);
+    assert_eq!(results[3].value, Some(ReadValue::Flag(true)));
+
+    assert!(!results[4].success);
+    assert_eq!(results[4].error_code, Code::Unimplemented as i32);
+
+    assert!(!results[5].success);
+    assert_eq!(results[5].error_code, Code::Unimplemented as i32);
+}
+
+#[tokio::test]
+async fn telemetry_reads_are_supervised_cached_and_tracked() {
+    let server = make_mixed_server();
+    server.write().set_device_lock_timeout(Duration::from_millis(50));
+    let limits = HashMap::from([("get_temperature_celsius".to_string(), 60_000)]);
+    let sensors = SensorServices {
+        thermometer: Arc::new(ThermometerService::with_read_limits(&server, &limits)),
+        barometer: Arc::new(BarometerService::new(&server)),
+        light_sensor: Arc::new(LightSensorService::new(&server)),
+    };
+    let service = TelemetryService::new(&server).with_sensor_services(&sensors);
+    let thermo = resolve_address(&server.read(), "thermo").unwrap();
+    let request = || BatchReadRequest {
+        requests: vec![
+            read_request("thermo", RpcCapabilityId::Thermometer, "get_temperature_celsius"),
+            read_request("led0", RpcCapabilityId::LedController, "get_brightness"),
+        ],
+    };
+
+    let results = service.batch_read(Request::new(request())).await.unwrap().into_inner().results;
+    assert!(results.iter().all(|x| x.success));
+    assert_eq!(server.read().events().stats(&thermo).read_count, 1);
+
+    // a held device times out its own item only, the cached reading doesn't need the lock at all
+    let held = crate::rpc::get_device_ptr(&server, "led0").unwrap().write_arc();
+    let results = service.batch_read(Request::new(request())).await.unwrap().into_inner().results;
+    assert!(results[0].success);
+    assert_eq!(results[0].value, Some(ReadValue::Number(20.0)));
+    assert!(!results[1].success);
+    assert_eq!(results[1].error_code, Code::Unavailable as i32);
+    assert_eq!(server.read().events().stats(&thermo).read_count, 1);
+    drop(held);
+
+    // the batch shares its cache with the thermometer service, so neither front bypasses the other's interval
+    let single = sensors.thermometer
+        .get_temperature_celsius(Request::new(ThermometerRequest { address: "thermo".to_string() }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(single.cached);
+    assert_eq!(server.read().events().stats(&thermo).read_count, 1);
+}
+
+#[test]
+fn devices_are_locked_individually() {
+    let server = make_mixed_server();
+    let mut led0 = crate::rpc::lock_capability_mut::<dyn LEDControllerCapable>(&server, "led0").unwrap();
+
+    // led1 stays usable from another thread while led0 is held
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let thread_server = server.clone();
+    std::thread::spawn(move || {
+        let mut led1 = crate::rpc::lock_capability_mut::<dyn LEDControllerCapable>(&thread_server, "led1").unwrap();
+        let _ = sender.send(led1.set_brightness(0.5).map(|_| thread_server.read().get_device_addresses().len()));
+    });
+
+    let result = receiver.recv_timeout(Duration::from_secs(2)).expect("led1 was blocked by the lock on led0");
+    assert_eq!(result.unwrap(), 4);
+
+    // led0 itself is still exclusively held
+    let led0_ptr = crate::rpc::get_device_ptr(&server, "led0").unwrap();
+    assert!(led0_ptr.try_read().is_none());
+    led0.set_brightness(1.0).unwrap();
+    drop(led0);
+    assert!(led0_ptr.try_read().is_some());
+}
+
+#[test]
+fn held_device_lock_times_out_with_error() {
+    let server = make_mixed_server();
+    server.write().set_device_lock_timeout(Duration::from_millis(50));
+    let _held = crate::rpc::get_device_ptr(&server, "led0").unwrap().write_arc();
+
+    let err = match crate::rpc::lock_capability::<dyn LEDControllerCapable>(&server, "led0") {
+        Err(err) => err,
+        Ok(_) => panic!("lock was handed out while another guard held it"),
+    };
+    assert_eq!(err.code(), Code::Unavailable);
+    assert!(err.message().contains("\"led0\""), "{}", err.message());
+
+    let stats = server.read().lock_wait_stats();
+    assert_eq!(stats.timeouts, 1);
+    assert!(stats.max_wait >= Duration::from_millis(50));
+}
+
+#[test]
+fn stuck_device_is_restarted_once_released() {
+    let server = make_mixed_server();
+    server.write().set_device_lock_timeout(Duration::from_millis(50));
+    server.write().set_recover_stuck_locks(true);
+    let mut events = server.read().events().subscribe();
+    let held = crate::rpc::get_device_ptr(&server, "led0").unwrap().write_arc();
+
+    // the caller gets the timeout, the holder is left alone
+    match crate::rpc::lock_capability_mut::<dyn LEDControllerCapable>(&server, "led0") {
+        Err(err) => assert_eq!(err.code(), Code::Unavailable),
+        Ok(_) => panic!("lock was handed out while another guard held it"),
+    }
+    assert!(crate::rpc::lock_capability_mut::<dyn LEDControllerCapable>(&server, "led0").is_err());
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(events.try_recv().is_err());
+    assert!(held.is_running());
+
+    drop(held);
+    let mut kinds = Vec::new();
+    let deadline = std::time::Instant::now() + Duration::from_secs(2);
+    while kinds.len() < 2 && std::time::Instant::now() < deadline {
+        match events.try_recv() {
+            Ok(event) => kinds.push(event.kind),
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+
+    // restarted once, even though two calls timed out
+    assert_eq!(kinds, vec![DeviceEventKind::DeviceStopped, DeviceEventKind::DeviceStarted]);
+    let mut led0 = crate::rpc::lock_capability_mut::<dyn LEDControllerCapable>(&server, "led0").unwrap();
+    led0.set_brightness(0.5).unwrap();
+}
+
+fn tagged(device: Device, tags: &[&str]) -> Device {
+    device.with_tags(tags.iter().map(|x| x.to_string()).collect())
+}
+
+#[tokio::test]
+async fn reflection_filters_devices_by_tag() {
+    let server = DeviceServerBuilder::configure()
+        .add_device(tagged(Device::new::<StubLed>(None, Some("led0".to_string())).unwrap(), &["rack-1", "outdoor"]))
+        .add_device(tagged(Device::new::<StubLed>(None, Some("led1".to_string())).unwrap(), &["rack-2"]))
+        .add_device(tagged(Device::new::<StubThermometer>(None, Some("thermo".to_string())).unwrap(), &["outdoor"]))
+        .add_device(Device::new::<PlainDevice>(None, Some("plain".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+    let service = DeviceReflectionService::new(&Arc::new(RwLock::new(server)));
+
+    let response = service.find_devices_by_tag(Request::new(FindDevicesByTagRequest { tag: "outdoor".to_string() })).await.unwrap();
+    let mut names: Vec<String> = response.get_ref().devices.iter().map(|d| d.device_name.clone()).collect();
+    names.sort();
+    assert_eq!(response.get_ref().count, 2);
+    assert_eq!(names, vec!["led0".to_string(), "thermo".to_string()]);
+
+    let response = service.find_devices_by_tag(Request::new(FindDevicesByTagRequest { tag: "rack-3".to_string() })).await.unwrap();
+    assert_eq!(response.get_ref().count, 0);
+
+    let err = service.find_devices_by_tag(Request::new(FindDevicesByTagRequest { tag: " ".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let tags = service.list_tags(Request::new(Void::default())).await.unwrap();
+    assert_eq!(tags.get_ref().tags, vec!["outdoor".to_string(), "rack-1".to_string(), "rack-2".to_string()]);
+
+    let info = service.get_device_info(Request::new(GetDeviceInfoRequest { address: "led0".to_string() })).await.unwrap();
+    assert_eq!(info.get_ref().tags, vec!["rack-1".to_string(), "outdoor".to_string()]);
+}
+
+#[tokio::test]
+async fn led_unsupported_mode_is_failed_precondition() {
+    let service = LEDControllerService::new(&make_mixed_server());
+    let request = |mode: RpcLedMode, mode_name: &str| Request::new(SetModeRequest {
+        address: "led0".to_string(),
+        mode: mode as i32,
+        mode_name: mode_name.to_string(),
+    });
+
+    service.set_mode(request(RpcLedMode::Vis, "")).await.expect("supported mode was rejected");
+
+    // the driver refusing a mode is a precondition failure, not a server fault
+    let err = service.set_mode(request(RpcLedMode::Ir, "")).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+    let err = service.set_mode(request(RpcLedMode::Named, "ultraviolet")).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+
+    let err = service.set_mode(Request::new(SetModeRequest { address: "led0".to_string(), mode: 42, mode_name: String::new() }))
+        .await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+}
+
+fn make_gpio_controller() -> MockGpioController {
+    let pin_map = (2..6).map(|pin| (pin, PinState::new(pin, pin + 10))).collect();
+    MockGpioController {
+        gpio_borrow: Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map))),
+        held_pins: HashMap::new(),
+    }
+}
+
+#[test]
+fn gpio_batch_set_and_read() {
+    let mut controller = make_gpio_controller();
+    for pin in [2, 3, 4] {
+        controller.set_direction(pin, PinDirection::Output).unwrap();
+    }
+
+    controller.set_values(&[(2, 1), (3, 0), (4, 1)]).expect("batch write failed");
+    assert_eq!(controller.read_values(&[4, 2, 3]).expect("batch read failed"), vec![1, 1, 0]);
+
+    // single pin access still sees the batch
+    assert_eq!(controller.read_pin(2).unwrap(), 1);
+}
+
+#[test]
+fn gpio_batch_reports_unowned_pins() {
+    let mut controller = make_gpio_controller();
+    controller.set_direction(2, PinDirection::Output).unwrap();
+    controller.set_direction(3, PinDirection::Output).unwrap();
+
+    let failures = controller.set_values(&[(2, 1), (4, 1), (3, 1), (5, 1)]).unwrap_err();
+    assert_eq!(failures, vec![(4, GpioError::LeaseNotFound), (5, GpioError::LeaseNotFound)]);
+    // nothing was written and the unowned pins were not leased
+    assert_eq!(controller.read_values(&[2, 3]).unwrap(), vec![0, 0]);
+    assert!(!controller.holds_pin(4));
+
+    let failures = controller.read_values(&[2, 5]).unwrap_err();
+    assert_eq!(failures, vec![(5, GpioError::LeaseNotFound)]);
+}
+
+#[tokio::test]
+async fn gpio_write_pins_rpc() {
+    let (service, _) = make_gpio_service();
+    service.write_pin(Request::new(WritePinRequest { pin: 2, value: 0 })).await.unwrap();
+    service.write_pin(Request::new(WritePinRequest { pin: 3, value: 0 })).await.unwrap();
+
+    let pins = vec![WritePinRequest { pin: 2, value: 1 }, WritePinRequest { pin: 3, value: 1 }];
+    service.write_pins(Request::new(WritePinsRequest { pins })).await.unwrap();
+    let response = service.read_pin(Request::new(ReadPinRequest { pin: 3 })).await.unwrap();
+    assert_eq!(response.get_ref().value, 1);
+
+    service.release_pin(Request::new(ReleasePinRequest { pin: 3 })).await.unwrap();
+    let pins = vec![WritePinRequest { pin: 2, value: 0 }, WritePinRequest { pin: 3, value: 0 }];
+    let err = service.write_pins(Request::new(WritePinsRequest { pins })).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+    assert!(err.message().contains("pin 3"), "unexpected message: {}", err.message());
+
+    let pins = vec![WritePinRequest { pin: 2, value: 3 }];
+    let err = service.write_pins(Request::new(WritePinsRequest { pins })).await.unwrap_err();
+    assert_eq!(err.code(), Code::OutOfRange);
+}
+
+#[derive(Serialize, Deserialize)]
+struct TunableConfig {
+    interval_ms: u32,
+    bus_id: u8,
+}
+
+// Takes interval changes live, a different bus needs a restart
+struct TunableSensor {
+    config: TunableConfig,
+}
+
+impl TunableSensor {
+    fn parse(data: &Value) -> Result<TunableConfig, DeviceError> {
+        let config: TunableConfig = serde_json::from_value(data.clone())
+            .map_err(|e| DeviceError::invalid_config(e.to_string()))?;
+
+        match config.interval_ms {
+            0 => Err(DeviceError::invalid_config("interval must be positive")),
+            _ => Ok(config)
+        }
+    }
+}
+
+impl DeviceDriver for TunableSensor {
+    fn name(&self) -> String {
+        "tunable".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        true
+    }
+
+    fn new(config: Option<&mut DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        let config = config.ok_or(DeviceError::invalid_config("missing config"))?;
+        Ok(TunableSensor { config: Self::parse(&config.driver_data)? })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn apply_config(&mut self, config: &DeviceConfig) -> Result<(), DeviceError> {
+        let config = Self::parse(&config.driver_data)?;
+        if config.bus_id != self.config.bus_id {
+            return Err(DeviceError::NotSupported);
+        }
+
+        self.config = config;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for TunableSensor {}
+
+fn live_interval(server: &Arc<RwLock<DeviceServer>>, address: &Uuid) -> u32 {
+    server.read().get_device(address).unwrap().as_any().downcast_ref::<TunableSensor>().unwrap().config.interval_ms
+}
+
+fn update_request(address: &str, patch: &str) -> Request<UpdateDeviceConfigRequest> {
+    Request::new(UpdateDeviceConfigRequest { address: address.to_string(), patch: patch.to_string() })
+}
+
+#[test]
+fn merge_patch_replaces_and_removes_fields() {
+    let mut target = json!({ "a": 1, "nested": { "b": 2, "c": 3 } });
+    merge_patch(&mut target, &json!({ "a": 5, "nested": { "c": null, "d": 4 } }));
+    assert_eq!(target, json!({ "a": 5, "nested": { "b": 2, "d": 4 } }));
+}
+
+#[tokio::test]
+async fn device_config_update_is_applied_and_saved() {
+    let mut drivers = DriverRegistry::new();
+    drivers.register_driver::<TunableSensor>("tunable");
+    let drivers = Arc::new(drivers);
+
+    let mut config = Configuration::default();
+    config.device_section.devices.push(DeviceConfig::new(
+        "tunable".to_string(),
+        Some("sensor".to_string()),
+        json!({ "interval_ms": 100, "bus_id": 0 }),
+    ));
+
+    let device = drivers.build(&mut config.device_section.devices[0]).unwrap();
+    let address = device.address();
+    let server = Arc::new(RwLock::new(DeviceServerBuilder::configure().add_device(device).build(true).unwrap()));
+
+    let path = std::env::temp_dir().join(format!("nvos_update_{}.json", Uuid::new_v4()));
+    let store = Arc::new(ConfigStore::new(config, Some(path.clone())));
+    store.bind_device(address, 0);
+    let service = DeviceReflectionService::new(&server).with_config_store(&store, &drivers);
+
+    let response = service.update_device_config(update_request("sensor", r#"{ "interval_ms": 250 }"#)).await.unwrap();
+    assert!(response.get_ref().applied_live);
+    assert_eq!(live_interval(&server, &address), 250);
+
+    let saved = Configuration::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(saved.device_section.devices[0].driver_data, json!({ "interval_ms": 250, "bus_id": 0 }));
+
+    // saved for the next start, the running driver keeps its bus
+    let response = service.update_device_config(update_request("sensor", r#"{ "bus_id": 1 }"#)).await.unwrap();
+    assert!(!response.get_ref().applied_live);
+    assert_eq!(store.device_config(&address).unwrap().driver_data, json!({ "interval_ms": 250, "bus_id": 1 }));
+
+    // the effective config follows the updates instead of showing the config the server started with
+    let effective = service.get_effective_config(Request::new(Void::default())).await.unwrap().into_inner().config;
+    let effective: Value = serde_json::from_str(&effective).unwrap();
+    assert_eq!(effective["device_section"]["devices"][0]["driver_data"], json!({ "interval_ms": 250, "bus_id": 1 }));
+
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(backup_path(&path, 1));
+    let _ = std::fs::remove_file(backup_path(&path, 2));
+}
+
+#[tokio::test]
+async fn concurrent_device_config_updates_keep_both_changes() {
+    let mut drivers = DriverRegistry::new();
+    drivers.register_driver::<TunableSensor>("tunable");
+    let drivers = Arc::new(drivers);
+
+    let mut config = Configuration::default();
+    config.device_section.devices.push(DeviceConfig::new(
+        "tunable".to_string(),
+        Some("sensor".to_string()),
+        json!({ "interval_ms": 100, "bus_id": 0 }),
+    ));
+
+    let device = drivers.build(&mut config.device_section.devices[0]).unwrap();
+    let address = device.address();
+    let server = Arc::new(RwLock::new(DeviceServerBuilder::configure().add_device(device).build(true).unwrap()));
+    server.write().set_device_lock_timeout(Duration::from_secs(2));
+
+    let store = Arc::new(ConfigStore::new(config, None));
+    store.bind_device(address, 0);
+    let service = DeviceReflectionService::new(&server).with_config_store(&store, &drivers);
+
+    // the first update waits for the device off the async worker, the second one waits for the first
+    let held = crate::rpc::get_device_ptr(&server, "sensor").unwrap().write_arc();
+    let release = async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(held);
+    };
+    let (first, second, _) = tokio::join!(
+        service.update_device_config(update_request("sensor", r#"{ "interval_ms": 250 }"#)),
+        service.update_device_config(update_request("sensor", r#"{ "bus_id": 1 }"#)),
+        release
+    );
+
+    assert!(first.unwrap().get_ref().applied_live);
+    assert!(!second.unwrap().get_ref().applied_live);
+    assert_eq!(live_interval(&server, &address), 250);
+    assert_eq!(store.device_config(&address).unwrap().driver_data, json!({ "interval_ms": 250, "bus_id": 1 }));
+}
+
+#[tokio::test]
+async fn invalid_device_config_update_is_rejected() {
+    let mut drivers = DriverRegistry::new();
+    drivers.register_driver::<TunableSensor>("tunable");
+    let drivers = Arc::new(drivers);
+
+    let mut config = Configuration::default();
+    config.device_section.devices.push(DeviceConfig::new(
+        "tunable".to_string(),
+        Some("sensor".to_string()),
+        json!({ "interval_ms": 100, "bus_id": 0 }),
+    ));
+
+    let device = drivers.build(&mut config.device_section.devices[0]).unwrap();
+    let address = device.address();
+    let server = Arc::new(RwLock::new(DeviceServerBuilder::configure().add_device(device).build(true).unwrap()));
+
+    // no path, nothing is written to disk
+    let store = Arc::new(ConfigStore::new(config, None));
+    store.bind_device(address, 0);
+    let service = DeviceReflectionService::new(&server).with_config_store(&store, &drivers);
+
+    for patch in [r#"{ "interval_ms": 0 }"#, r#"{ "interval_ms": "fast" }"#, "not json"] {
+        let err = service.update_device_config(update_request("sensor", patch)).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument, "patch {} was accepted", patch);
+    }
+
+    assert_eq!(live_interval(&server, &address), 100);
+    assert_eq!(store.device_config(&address).unwrap().driver_data, json!({ "interval_ms": 100, "bus_id": 0 }));
+
+    // devices that didn't come from the config can't be updated
+    let unbound = DeviceReflectionService::new(&server).with_config_store(&Arc::new(ConfigStore::new(Configuration::default(), None)), &drivers);
+    let err = unbound.update_device_config(update_request("sensor", "{}")).await.unwrap_err();
+    assert_eq!(err.code(), Code::FailedPrecondition);
+}
+
+struct TopologyBus {}
+
+impl BusController for TopologyBus {
+    fn name(&self) -> String {
+        "topology_bus".to_string()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+fn topology_config() -> Configuration {
+    let mut config = Configuration::default();
+    config.controller_section.controllers.push(BusControllerConfig::new_without_data("topology_bus".to_string()));
+    for (name, interval_ms) in [("sensor-a", 100), ("sensor-b", 200)] {
+        let mut device = DeviceConfig::new(
+            "tunable".to_string(),
+            Some(name.to_string()),
+            json!({ "interval_ms": interval_ms, "bus_id": 0 }),
+        );
+        device.tags = vec!["outdoor".to_string()];
+        config.device_section.devices.push(device);
+    }
+
+    config
+}
+
+// A unit started from the config the same way an import fills it
+fn topology_unit(config: Configuration) -> (Arc<RwLock<DeviceServer>>, DeviceReflectionService) {
+    let mut drivers = DriverRegistry::new();
+    drivers.register_driver::<TunableSensor>("tunable");
+    let mut controllers = ControllerRegistry::new();
+    controllers.register("topology_bus", |_, _| Ok(BusEntry::new(Arc::new(RwLock::new(TopologyBus {})))));
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(HashMap::new())));
+
+    let mut server = DeviceServer::new();
+    let mut topology = config.topology();
+    let entries = load_topology(&mut server, &mut topology, &config.gpio_section, &controllers, &drivers, &gpio).unwrap();
+    let store = Arc::new(ConfigStore::new(config, None));
+    store.set_topology(topology, &entries).unwrap();
+
+    let server = Arc::new(RwLock::new(server));
+    let service = DeviceReflectionService::new(&server)
+        .with_gpio(&gpio)
+        .with_config_store(&store, &Arc::new(drivers))
+        .with_controllers(&Arc::new(controllers));
+    (server, service)
+}
+
+async fn export_topology(service: &DeviceReflectionService) -> String {
+    service.export_topology(Request::new(Void::default())).await.unwrap().into_inner().topology
+}
+
+fn import_request(topology: &str) -> Request<ImportTopologyRequest> {
+    Request::new(ImportTopologyRequest { topology: topology.to_string() })
+}
+
+#[tokio::test]
+async fn topology_round_trips_through_export_and_import() {
+    let (server, service) = topology_unit(topology_config());
+    let mut addresses = server.read().get_device_addresses();
+    addresses.sort();
+    let exported = export_topology(&service).await;
+
+    // an empty unit takes over the controllers and devices, addresses included
+    let (clone_server, clone_service) = topology_unit(Configuration::default());
+    let response = clone_service.import_topology(import_request(&exported)).await.unwrap().into_inner();
+    assert_eq!(response.count, 2);
+    let names: Vec<&str> = response.devices.iter().map(|x| x.device_name.as_str()).collect();
+    assert_eq!(names, vec!["sensor-a", "sensor-b"]);
+    assert!(response.devices.iter().all(|x| x.is_running && x.tags == vec!["outdoor".to_string()]));
+    assert!(clone_server.read().has_bus_named("topology_bus"));
+
+    let mut cloned_addresses = clone_server.read().get_device_addresses();
+    cloned_addresses.sort();
+    assert_eq!(cloned_addresses, addresses);
+    let sensor = resolve_address(&clone_server.read(), "sensor-b").unwrap();
+    assert_eq!(live_interval(&clone_server, &sensor), 200);
+
+    let reexported: Value = serde_json::from_str(&export_topology(&clone_service).await).unwrap();
+    assert_eq!(reexported, serde_json::from_str::<Value>(&exported).unwrap());
+
+    // a topology the drivers reject is turned away before anything is stopped
+    let mut invalid: Value = serde_json::from_str(&exported).unwrap();
+    invalid["device_section"]["devices"][0]["driver_data"]["interval_ms"] = json!(0);
+    let err = clone_service.import_topology(import_request(&invalid.to_string())).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    assert_eq!(live_interval(&clone_server, &sensor), 200);
+
+    // restoring onto the original unit brings its devices back under the same addresses
+    service.import_topology(import_request(&exported)).await.unwrap();
+    let mut restored = server.read().get_device_addresses();
+    restored.sort();
+    assert_eq!(restored, addresses);
+    assert_eq!(server.read().get_buses().len(), 1);
+}
+
+#[test]
+fn restored_topology_skips_what_fails_to_come_up() {
+    let mut drivers = DriverRegistry::new();
+    drivers.register_driver::<TunableSensor>("tunable");
+    let mut controllers = ControllerRegistry::new();
+    controllers.register("topology_bus", |_, _| Ok(BusEntry::new(Arc::new(RwLock::new(TopologyBus {})))));
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(HashMap::new())));
+
+    let mut config = topology_config();
+    config.controller_section.controllers.push(BusControllerConfig::new_without_data("unknown_bus".to_string()));
+    config.device_section.devices[0].driver_data["interval_ms"] = json!(0);
+    config.device_section.devices[1].depends_on = vec!["sensor-a".to_string()];
+    config.device_section.devices.push(DeviceConfig::new(
+        "tunable".to_string(),
+        Some("sensor-c".to_string()),
+        json!({ "interval_ms": 300, "bus_id": 0 }),
+    ));
+    let mut topology = config.topology();
+
+    let mut server = DeviceServer::new();
+    assert!(load_topology(&mut server, &mut topology.clone(), &config.gpio_section, &controllers, &drivers, &gpio).is_err());
+
+    // the broken device and the one depending on it are left out, the rest comes up
+    let entries = restore_topology(&mut server, &mut topology, &config.gpio_section, &controllers, &drivers, &gpio).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].1, 2);
+    assert!(server.get_device_with_name("sensor-c").is_some_and(|x| x.is_running()));
+    assert!(server.get_device_with_name("sensor-b").is_none());
+    assert!(server.has_bus_named("topology_bus"));
+}
+
+#[tokio::test]
+async fn topology_import_resolves_pin_names() {
+    let mut config = Configuration::default();
+    config.gpio_section = serde_json::from_value(json!({ "pin_config": { "7": { "bcm_id": 4, "name": "sensor_bus" } } })).unwrap();
+    let (server, service) = topology_unit(config);
+
+    let mut topology: Value = serde_json::from_str(&export_topology(&service).await).unwrap();
+    topology["device_section"]["devices"] = json!([
+        { "driver": "tunable", "friendly_name": "named", "driver_data": { "interval_ms": 100, "bus_id": "gpio:SENSOR_BUS" } }
+    ]);
+    service.import_topology(import_request(&topology.to_string())).await.unwrap();
+
+    // the driver got the pin ID, the saved topology keeps the name
+    let address = resolve_address(&server.read(), "named").unwrap();
+    let bus_id = server.read().get_device(&address).unwrap().as_any().downcast_ref::<TunableSensor>().unwrap().config.bus_id;
+    assert_eq!(bus_id, 7);
+    let exported: Value = serde_json::from_str(&export_topology(&service).await).unwrap();
+    assert_eq!(exported["device_section"]["devices"][0]["driver_data"]["bus_id"], json!("gpio:SENSOR_BUS"));
+
+    // so does a config update, and an unknown name is rejected before the device sees it
+    service.update_device_config(update_request("named", r#"{ "interval_ms": 300 }"#)).await.unwrap();
+    assert_eq!(live_interval(&server, &address), 300);
+    let err = service.update_device_config(update_request("named", r#"{ "bus_id": "gpio:missing" }"#)).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    topology["device_section"]["devices"][0]["driver_data"]["bus_id"] = json!("gpio:missing");
+    let err = service.import_topology(import_request(&topology.to_string())).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    assert!(server.read().has_device(&address));
+}
+
+#[tokio::test]
+async fn server_reflection_lists_services() {
+    // the client drives the tower service directly, no listening socket needed
+    let mut client = ServerReflectionClient::new(server_reflection_service().expect("failed to build reflection service"));
+    let request = ServerReflectionRequest {
+        host: String::new(),
+        message_request: Some(MessageRequest::ListServices(String::new())),
+    };
+
+    let mut responses = client
+        .server_reflection_info(tokio_stream::iter(vec![request]))
+        .await
+        .expect("reflection call failed")
+        .into_inner();
+
+    let services: Vec<String> = match responses.message().await.unwrap().and_then(|x| x.message_response) {
+        Some(MessageResponse::ListServicesResponse(list)) => list.service.into_iter().map(|x| x.name).collect(),
+        other => panic!("expected a service list, got {:?}", other),
+    };
+
+    for name in ["reflection.DeviceReflection", "led.LEDController", "gps.Gps", "network.NetworkManager", "heartbeat.Heartbeat"] {
+        assert!(services.contains(&name.to_string()), "{} is missing from {:?}", name, services);
+    }
+}
+
+#[tokio::test]
+async fn device_stats_count_reads_and_reset_on_restart() {
+    let server = make_mixed_server();
+    let reflection = DeviceReflectionService::new(&server);
+    let thermometer = ThermometerService::new(&server);
+    let stats_request = || Request::new(GetDeviceStatsRequest { address: "thermo".to_string() });
+    let read_request = || Request::new(ThermometerRequest { address: "thermo".to_string() });
+
+    let stats = reflection.get_device_stats(stats_request()).await.unwrap().into_inner();
+    assert!(stats.running);
+    assert_eq!((stats.read_count, stats.error_count), (0, 0));
+
+    thermometer.get_temperature_celsius(read_request()).await.unwrap();
+    thermometer.get_gain(read_request()).await.unwrap();
+    thermometer.get_temperature_celsius(read_request()).await.unwrap();
+    // a device without the capability isn't a read
+    let err = thermometer.get_gain(Request::new(ThermometerRequest { address: "plain".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    std::thread::sleep(Duration::from_millis(50));
+    let stats = reflection.get_device_stats(stats_request()).await.unwrap().into_inner();
+    assert_eq!((stats.read_count, stats.error_count), (3, 0));
+    assert!(stats.uptime_ms >= 50);
+
+    let address = resolve_address(&server.read(), "thermo").unwrap();
+    server.write().stop_device(&address).unwrap();
+    let stats = reflection.get_device_stats(stats_request()).await.unwrap().into_inner();
+    assert!(!stats.running);
+    assert_eq!(stats.uptime_ms, 0);
+
+    server.write().start_device(&address).unwrap();
+    let stats = reflection.get_device_stats(stats_request()).await.unwrap().into_inner();
+    assert!(stats.running);
+    assert!(stats.uptime_ms < 50);
+    assert_eq!(stats.read_count, 0);
+
+    let err = reflection.get_device_stats(Request::new(GetDeviceStatsRequest { address: "missing".to_string() })).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}
+
+#[tokio::test]
+async fn access_log_records_method_device_and_status() {
+    let entries: Arc<parking_lot::Mutex<Vec<AccessLogEntry>>> = Arc::default();
+    let recorded = entries.clone();
+    let layer = AccessLogLayer::new(None).with_sink(Arc::new(move |entry: &AccessLogEntry| recorded.lock().push(entry.clone())));
+    let service = tower::Layer::layer(&layer, ThermometerServer::new(ThermometerService::new(&make_mixed_server())));
+
+    // no clients are generated, so the calls go into the service as raw unary gRPC requests
+    let mut codes = Vec::new();
+    for address in ["thermo", "missing"] {
+        let message = ThermometerRequest { address: address.to_string() };
+        let mut body = vec![0u8];
+        body.extend_from_slice(&(message.encoded_len() as u32).to_be_bytes());
+        message.encode(&mut body).unwrap();
+        let req = hyper::Request::post("/thermometer.Thermometer/GetTemperatureCelsius")
+            .header("content-type", "application/grpc")
+            .body(hyper::Body::from(body))
+            .unwrap();
+
+        let response = tower::ServiceExt::oneshot(service.clone(), req).await.unwrap();
+        codes.push(tonic::Status::from_header_map(response.headers()).map(|x| x.code()));
+    }
+    assert_eq!(codes, vec![None, Some(Code::NotFound)]);
+
+    let entries = entries.lock();
+    assert_eq!(entries.len(), 2);
+    for entry in entries.iter() {
+        assert_eq!(entry.method, "/thermometer.Thermometer/GetTemperatureCelsius");
+        assert_eq!(entry.caller, None);
+    }
+
+    assert_eq!((entries[0].device.as_deref(), entries[0].code), (Some("thermo"), Code::Ok));
+    assert_eq!((entries[1].device.as_deref(), entries[1].code), (Some("missing"), Code::NotFound));
+}
+
+#[tokio::test]
+async fn concurrency_limit_rejects_calls_over_the_cap() {
+    use tonic::codegen::http;
+    use tower::Service;
+
+    // every call blocks until the test hands out a permit
+    let gate = Arc::new(tokio::sync::Semaphore::new(0));
+    let inner_gate = gate.clone();
+    let inner = tower::service_fn(move |_req: http::Request<()>| {
+        let gate = inner_gate.clone();
+        async move {
+            gate.acquire().await.unwrap().forget();
+            Ok::<_, std::convert::Infallible>(http::Response::new(tonic::body::empty_body()))
+        }
+    });
+    let mut service = tower::Layer::layer(&ConcurrencyLimitLayer::new(Some(2)), inner.clone());
+    let code = |response: &http::Response<_>| tonic::Status::from_header_map(response.headers()).map(|x| x.code());
+
+    let first = tokio::spawn(service.call(http::Request::new(())));
+    let second = tokio::spawn(service.call(http::Request::new(())));
+    let rejected = service.call(http::Request::new(())).await.unwrap();
+    assert_eq!(code(&rejected), Some(Code::ResourceExhausted));
+
+    gate.add_permits(2);
+    for call in [first, second] {
+        let response = call.await.unwrap().unwrap();
+        assert_eq!(code(&response), None);
+    }
+
+    // finished calls give their slot back
+    gate.add_permits(1);
+    let response = service.call(http::Request::new(())).await.unwrap();
+    assert_eq!(code(&response), None);
+
+    // without a limit nothing is turned away
+    gate.add_permits(3);
+    let mut unlimited = tower::Layer::layer(&ConcurrencyLimitLayer::new(None), inner);
+    let calls: Vec<_> = (0..3).map(|_| unlimited.call(http::Request::new(()))).collect();
+    for call in calls {
+        assert_eq!(code(&call.await.unwrap()), None);
+    }
+}
+
+#[tokio::test]
+async fn repeated_read_errors_mark_device_unhealthy_until_a_read_succeeds() {
+    let failing = Arc::new(AtomicBool::new(true));
+    let thermometer = StubThermometer { is_loaded: false, failing: failing.clone() };
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::from_driver(Box::new(thermometer), None, Some("thermo".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+    let server = Arc::new(RwLock::new(server));
+    server.write().set_unhealthy_error_threshold(3);
+    let address = resolve_address(&server.read(), "thermo").unwrap();
+    let service = ThermometerService::new(&server);
+    let reflection = DeviceReflectionService::new(&server);
+    let read_request = || Request::new(ThermometerRequest { address: "thermo".to_string() });
+    let info_request = || Request::new(GetDeviceInfoRequest { address: "thermo".to_string() });
+
+    for _ in 0..2 {
+        service.get_temperature_celsius(read_request()).await.unwrap_err();
+    }
+    assert!(server.read().get_device(&address).unwrap().is_healthy());
+
+    service.get_temperature_celsius(read_request()).await.unwrap_err();
+    assert!(!server.read().get_device(&address).unwrap().is_healthy());
+    assert!(!reflection.get_device_info(info_request()).await.unwrap().into_inner().is_healthy);
+
+    failing.store(false, Ordering::SeqCst);
+    service.get_temperature_celsius(read_request()).await.unwrap();
+    assert!(server.read().get_device(&address).unwrap().is_healthy());
+    assert!(reflection.get_device_info(info_request()).await.unwrap().into_inner().is_healthy);
+}
@@ -0,0 +1,144 @@
+use std::any::Any;
+use std::collections::HashMap;
+
+use crate::capabilities::{Capability, CapabilityId, SensorCapable, ThermometerCapable};
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::self_test::run_self_test;
+use intertrait::cast_to;
+
+struct MockThermometer {
+    reading: Result<f32, DeviceError>,
+}
+
+impl DeviceDriver for MockThermometer {
+    fn name(&self) -> String {
+        "mock_thermometer".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        true
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(MockThermometer { reading: Ok(21.5) })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for MockThermometer {}
+
+impl SensorCapable for MockThermometer {
+    fn get_supported_gains(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_supported_intervals(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_gain(&self, _reading: CapabilityId) -> Result<u16, DeviceError> {
+        Ok(1)
+    }
+
+    fn set_gain(&mut self, _reading: CapabilityId, _gain_id: u8) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn get_interval(&self) -> Result<u16, DeviceError> {
+        Ok(100)
+    }
+
+    fn set_interval(&mut self, _interval_id: u8) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+#[cast_to]
+impl ThermometerCapable for MockThermometer {
+    fn get_temperature_celsius(&mut self) -> Result<f32, DeviceError> {
+        self.reading.clone()
+    }
+
+    fn get_temperature_fahrenheit(&mut self) -> Result<f32, DeviceError> {
+        self.reading.clone().map(|x| x * 1.8 + 32.0)
+    }
+
+    fn reset_filter(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+struct PlainDevice {}
+
+impl DeviceDriver for PlainDevice {
+    fn name(&self) -> String {
+        "plain".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        true
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(PlainDevice {})
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[test]
+fn failing_sensor_reported_as_failed() {
+    let broken = MockThermometer { reading: Err(DeviceError::hardware_error("no ACK from sensor")) };
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::new::<MockThermometer>(None, Some("good".to_string())).unwrap())
+        .add_device(Device::from_driver(Box::new(broken), None, Some("bad".to_string())).unwrap())
+        .add_device(Device::new::<PlainDevice>(None, Some("plain".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+
+    let report = run_self_test(&server);
+    assert_eq!(report.results.len(), 2);
+    assert_eq!((report.passed(), report.failed()), (1, 1));
+
+    let good = report.results.iter().find(|x| x.device_name == "good").unwrap();
+    assert!(good.passed);
+    assert_eq!(good.check, "temperature");
+    assert_eq!(good.detail, "21.5 °C");
+
+    let bad = report.results.iter().find(|x| x.device_name == "bad").unwrap();
+    assert!(!bad.passed);
+    assert!(bad.detail.contains("no ACK from sensor"));
+    assert!(bad.to_string().starts_with("FAIL \"bad\""));
+
+    // logging a report with failures is fine too
+    report.log();
+}
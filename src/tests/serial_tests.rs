@@ -0,0 +1,137 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::capabilities::SerialPortCapable;
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::drivers::serial_passthrough::{SerialPassthrough, SerialPassthroughConfig, SerialTransport};
+use crate::rpc::serial::{serial_port_server::SerialPort, ReadRequest, SerialPortService, WriteRequest};
+use parking_lot::{Mutex, RwLock};
+use tonic::Request;
+
+// Echoes every written byte back on the read side
+struct LoopbackTransport {
+    buffer: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl SerialTransport for LoopbackTransport {
+    fn write(&mut self, data: &[u8]) -> Result<usize, DeviceError> {
+        self.buffer.lock().extend(data);
+        Ok(data.len())
+    }
+
+    fn read(&mut self, buf: &mut [u8], _timeout: Duration) -> Result<usize, DeviceError> {
+        let mut buffer = self.buffer.lock();
+        let count = buf.len().min(buffer.len());
+        for (index, byte) in buffer.drain(..count).enumerate() {
+            buf[index] = byte;
+        }
+
+        Ok(count)
+    }
+
+    fn drain(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+fn make_loopback() -> (SerialPassthrough, Arc<Mutex<VecDeque<u8>>>) {
+    let buffer = Arc::new(Mutex::new(VecDeque::new()));
+    let transport = LoopbackTransport { buffer: buffer.clone() };
+    let device = SerialPassthrough::with_transport(SerialPassthroughConfig::default(), Box::new(transport))
+        .expect("failed to build device");
+
+    (device, buffer)
+}
+
+#[test]
+fn serial_byte_round_trip() {
+    let (mut device, _) = make_loopback();
+
+    assert_eq!(device.write_bytes(b"ping").unwrap(), 4);
+    assert_eq!(device.read_bytes(16, Duration::ZERO).unwrap(), b"ping".to_vec());
+    assert!(device.read_bytes(16, Duration::ZERO).unwrap().is_empty());
+}
+
+#[test]
+fn serial_read_line_keeps_remainder() {
+    let (mut device, _) = make_loopback();
+
+    device.write_bytes(b"hello\r\nworld\nrest").unwrap();
+    assert_eq!(device.read_line(Duration::from_millis(10)).unwrap(), "hello");
+    assert_eq!(device.read_line(Duration::from_millis(10)).unwrap(), "world");
+    assert_eq!(device.read_bytes(16, Duration::ZERO).unwrap(), b"rest".to_vec());
+}
+
+#[test]
+fn serial_read_line_timeout() {
+    let (mut device, _) = make_loopback();
+
+    device.write_bytes(b"partial").unwrap();
+    assert!(device.read_line(Duration::from_millis(5)).is_err());
+    // nothing is lost on timeout
+    assert_eq!(device.read_bytes(16, Duration::ZERO).unwrap(), b"partial".to_vec());
+}
+
+#[test]
+fn serial_stop_releases_port() {
+    let (mut device, buffer) = make_loopback();
+    let mut server = DeviceServer::new();
+
+    // there is no UART controller to hand the channel back to, but the handle must still be dropped
+    assert!(device.stop(&mut server).is_err());
+    assert!(!device.is_running());
+    assert_eq!(Arc::strong_count(&buffer), 1);
+    assert!(device.write_bytes(b"x").is_err());
+}
+
+#[tokio::test]
+async fn serial_rpc_round_trip() {
+    let (device, _) = make_loopback();
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::from_driver(Box::new(device), None, Some("modem".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+    let service = SerialPortService::new(&Arc::new(RwLock::new(server)));
+
+    let response = service.write(Request::new(WriteRequest { address: "modem".to_string(), data: b"AT\r\n".to_vec() })).await.unwrap();
+    assert_eq!(response.get_ref().written, 4);
+
+    let response = service.read(Request::new(ReadRequest { address: "modem".to_string(), max_length: 0, timeout_ms: 0 })).await.unwrap();
+    assert_eq!(response.get_ref().data, b"AT\r\n".to_vec());
+}
+
+// Blocks every read for longer than any test waits for
+struct StuckTransport;
+
+impl SerialTransport for StuckTransport {
+    fn write(&mut self, data: &[u8]) -> Result<usize, DeviceError> {
+        Ok(data.len())
+    }
+
+    fn read(&mut self, _buf: &mut [u8], _timeout: Duration) -> Result<usize, DeviceError> {
+        std::thread::sleep(Duration::from_millis(500));
+        Ok(0)
+    }
+
+    fn drain(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn serial_rpc_read_gives_up_on_a_stuck_port() {
+    let device = SerialPassthrough::with_transport(SerialPassthroughConfig::default(), Box::new(StuckTransport))
+        .expect("failed to build device");
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::from_driver(Box::new(device), None, Some("modem".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+    let service = SerialPortService::new(&Arc::new(RwLock::new(server))).with_command_timeout(Duration::from_millis(50));
+
+    // the read runs off the async workers, so the handler returns once the deadline passes
+    let started = std::time::Instant::now();
+    let err = service.read(Request::new(ReadRequest { address: "modem".to_string(), max_length: 0, timeout_ms: 10 })).await.unwrap_err();
+    assert!(err.message().contains("timeout"), "{}", err.message());
+    assert!(started.elapsed() < Duration::from_millis(400));
+}
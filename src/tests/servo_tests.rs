@@ -0,0 +1,60 @@
+use crate::capabilities::ServoCapable;
+use crate::device::DeviceError;
+use crate::drivers::servo_pwm::{ServoPwm, ServoPwmConfig};
+use super::support;
+
+fn make_driver(config: ServoPwmConfig) -> Result<ServoPwm, DeviceError> {
+    support::build_driver("servo_pwm", config)
+}
+
+#[test]
+fn angle_to_pulse_width() {
+    let servo = make_driver(ServoPwmConfig::default()).expect("failed to build driver");
+    assert_eq!(servo.pulse_width_us(0.0), 1000);
+    assert_eq!(servo.pulse_width_us(45.0), 1250);
+    assert_eq!(servo.pulse_width_us(90.0), 1500);
+    assert_eq!(servo.pulse_width_us(180.0), 2000);
+
+    // angles outside of the configured range are clamped
+    assert_eq!(servo.pulse_width_us(-30.0), 1000);
+    assert_eq!(servo.pulse_width_us(270.0), 2000);
+}
+
+#[test]
+fn angle_to_pulse_width_custom_range() {
+    let mut config = ServoPwmConfig::default();
+    config.min_angle = -90.0;
+    config.max_angle = 90.0;
+    config.min_pulse_us = 500;
+    config.max_pulse_us = 2500;
+    let mut servo = make_driver(config).expect("failed to build driver");
+
+    assert_eq!(servo.pulse_width_us(-90.0), 500);
+    assert_eq!(servo.pulse_width_us(0.0), 1500);
+    assert_eq!(servo.pulse_width_us(90.0), 2500);
+
+    servo.set_pulse_range(1000, 2000).expect("failed to set pulse range");
+    assert_eq!(servo.get_pulse_range().unwrap(), (1000, 2000));
+    assert_eq!(servo.pulse_width_us(0.0), 1500);
+    assert_eq!(servo.pulse_width_us(90.0), 2000);
+}
+
+#[test]
+fn invalid_ranges_rejected() {
+    let mut servo = make_driver(ServoPwmConfig::default()).unwrap();
+    assert!(matches!(servo.set_pulse_range(2000, 1000), Err(DeviceError::InvalidOperation(_))));
+    assert!(matches!(servo.set_pulse_range(1000, 30000), Err(DeviceError::InvalidOperation(_))));
+    assert_eq!(servo.get_pulse_range().unwrap(), (1000, 2000));
+
+    let mut config = ServoPwmConfig::default();
+    config.min_angle = 180.0;
+    config.max_angle = 0.0;
+    assert!(matches!(make_driver(config), Err(DeviceError::InvalidConfig(..))));
+
+    let mut config = ServoPwmConfig::default();
+    config.max_pulse_us = 25000;
+    assert!(matches!(make_driver(config), Err(DeviceError::InvalidConfig(..))));
+
+    // the servo has to be started before it can move
+    assert!(matches!(servo.set_angle(10.0), Err(DeviceError::InvalidOperation(_))));
+}
@@ -0,0 +1,239 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::adb::AdbServer;
+use crate::bus::BusController;
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::gpio::{GpioBorrowChecker, PinState};
+use crate::shutdown::ShutdownHook;
+use parking_lot::{Mutex, RwLock};
+use uuid::Uuid;
+use tokio::sync::mpsc;
+
+struct CountingDevice {
+    is_loaded: bool,
+    stop_count: Arc<AtomicUsize>,
+}
+
+impl CountingDevice {
+    fn with_counter(stop_count: &Arc<AtomicUsize>) -> Self {
+        CountingDevice { is_loaded: false, stop_count: stop_count.clone() }
+    }
+}
+
+impl DeviceDriver for CountingDevice {
+    fn name(&self) -> String {
+        "counting".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(CountingDevice { is_loaded: false, stop_count: Arc::new(AtomicUsize::new(0)) })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        self.stop_count.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+#[tokio::test]
+async fn shutdown_unloads_devices_once() {
+    let stop_count = Arc::new(AtomicUsize::new(0));
+    let mut builder = DeviceServerBuilder::configure();
+    for _ in 0..3 {
+        let driver = CountingDevice::with_counter(&stop_count);
+        builder = builder.add_device(Device::from_driver(Box::new(driver), None, None).unwrap());
+    }
+
+    let device_server = Arc::new(RwLock::new(builder.build(true).unwrap()));
+    let adb_server = Arc::new(RwLock::new(AdbServer::default()));
+    let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(2);
+    let hook = ShutdownHook::new(&device_server, Some(&adb_server), shutdown_tx);
+
+    assert!(!hook.is_triggered());
+    assert!(hook.shutdown().await);
+    assert!(hook.is_triggered());
+    assert_eq!(stop_count.load(Ordering::SeqCst), 3);
+    assert!(device_server.read().get_devices().is_empty());
+
+    // a second signal must not run the shutdown path again
+    assert!(!hook.shutdown().await);
+    assert_eq!(stop_count.load(Ordering::SeqCst), 3);
+
+    assert_eq!(shutdown_rx.recv().await, Some(()));
+    assert!(shutdown_rx.try_recv().is_err());
+}
+
+type ShutdownLog = Arc<Mutex<Vec<String>>>;
+
+// Logs its stop, and refuses to stop when fail_stop is set
+struct LoggingDevice {
+    is_loaded: bool,
+    fail_stop: bool,
+    log: ShutdownLog,
+}
+
+impl DeviceDriver for LoggingDevice {
+    fn name(&self) -> String {
+        "logging".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(LoggingDevice { is_loaded: false, fail_stop: false, log: ShutdownLog::default() })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        if self.fail_stop {
+            return Err(DeviceError::hardware_error("stuck"));
+        }
+
+        self.is_loaded = false;
+        self.log.lock().push("device stopped".to_string());
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Holds a pin lease the way a real controller does for an open channel
+struct LeasingController {
+    gpio: Arc<RwLock<GpioBorrowChecker>>,
+    lease: Option<Uuid>,
+    log: ShutdownLog,
+}
+
+impl BusController for LeasingController {
+    fn name(&self) -> String {
+        "leasing".to_string()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn release_all(&mut self) -> Vec<String> {
+        self.log.lock().push("bus released".to_string());
+        match self.lease.take() {
+            Some(lease) => self.gpio.write().release(&lease).err().map(|e| e.to_string()).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn shutdown_stops_devices_before_releasing_buses() {
+    let mut pin_map = HashMap::new();
+    pin_map.insert(4, PinState::new(4, 4));
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+    let lease = gpio.write().borrow_one(4).expect("failed to lease pin");
+
+    let log = ShutdownLog::default();
+    let mut builder = DeviceServerBuilder::configure()
+        .add_bus(LeasingController { gpio: gpio.clone(), lease: Some(lease), log: log.clone() });
+    for fail_stop in [false, true, false] {
+        let driver = LoggingDevice { is_loaded: false, fail_stop, log: log.clone() };
+        builder = builder.add_device(Device::from_driver(Box::new(driver), None, None).unwrap());
+    }
+
+    let mut server = builder.build(true).unwrap();
+    assert_eq!(server.shutdown(), 2);
+    assert_eq!(*log.lock(), vec!["device stopped", "device stopped", "bus released"]);
+
+    // the device that wouldn't stop doesn't keep the pin leased
+    assert!(gpio.read().get_borrowed().is_empty());
+    assert!(server.get_bus::<LeasingController>().is_none());
+}
+
+#[test]
+fn shutdown_skips_devices_it_cannot_lock() {
+    let mut pin_map = HashMap::new();
+    pin_map.insert(4, PinState::new(4, 4));
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+    let lease = gpio.write().borrow_one(4).expect("failed to lease pin");
+
+    let log = ShutdownLog::default();
+    let mut builder = DeviceServerBuilder::configure()
+        .add_bus(LeasingController { gpio: gpio.clone(), lease: Some(lease), log: log.clone() });
+    for _ in 0..2 {
+        let driver = LoggingDevice { is_loaded: false, fail_stop: false, log: log.clone() };
+        builder = builder.add_device(Device::from_driver(Box::new(driver), None, None).unwrap());
+    }
+
+    let mut server = builder.build(true).unwrap();
+    server.set_device_lock_timeout(std::time::Duration::from_millis(10));
+    let address = *server.get_devices()[0].0;
+    let device = server.get_device_ptr(&address).unwrap();
+
+    // a caller still holding the device doesn't hang the shutdown
+    let held = device.read();
+    assert_eq!(server.shutdown(), 1);
+    assert_eq!(*log.lock(), vec!["device stopped", "bus released"]);
+    assert!(held.is_running());
+    drop(held);
+
+    assert!(gpio.read().get_borrowed().is_empty());
+    assert!(server.get_bus::<LeasingController>().is_none());
+}
+
+#[tokio::test]
+async fn shutdown_does_not_block_the_runtime() {
+    let device_server = Arc::new(RwLock::new(DeviceServerBuilder::configure().build(true).unwrap()));
+    let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
+    let hook = Arc::new(ShutdownHook::new(&device_server, None, shutdown_tx));
+
+    // a caller stuck on the server keeps the device shutdown waiting
+    let held = device_server.read();
+    let task = tokio::spawn({
+        let hook = hook.clone();
+        async move { hook.shutdown().await }
+    });
+
+    // the single threaded test runtime still gets to run, so a second signal can be handled
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(hook.is_triggered());
+    assert!(!task.is_finished());
+    assert!(!hook.shutdown().await);
+
+    drop(held);
+    assert!(task.await.unwrap());
+    assert_eq!(shutdown_rx.recv().await, Some(()));
+}
@@ -0,0 +1,14 @@
+use crate::config::DeviceConfig;
+use crate::device::{DeviceDriver, DeviceError};
+use serde::Serialize;
+
+// Builds a driver straight from its typed config, the same way the device server would from a config file
+pub fn build_driver<D: DeviceDriver, C: Serialize>(driver: &str, config: C) -> Result<D, DeviceError> {
+    let mut device_config = DeviceConfig::new(
+        driver.to_string(),
+        None,
+        serde_json::to_value(config).unwrap(),
+    );
+
+    D::new(Some(&mut device_config))
+}
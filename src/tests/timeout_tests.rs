@@ -0,0 +1,244 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::capabilities::{Capability, CapabilityId, SensorCapable, SwitchCapable, ThermometerCapable};
+use crate::device::{Device, DeviceDriver, DeviceError, DeviceServer, DeviceServerBuilder};
+use crate::rpc::switch::{switch_server::Switch, SetStateRequest, SwitchService};
+use crate::rpc::thermometer::{thermometer_server::Thermometer, ThermometerRequest, ThermometerService};
+use crate::rpc::timeout;
+use intertrait::cast_to;
+use parking_lot::RwLock;
+use tonic::{Code, Request};
+
+struct SlowThermometer {
+    is_loaded: bool,
+    read_delay: Duration,
+}
+
+impl DeviceDriver for SlowThermometer {
+    fn name(&self) -> String {
+        "slow_thermometer".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        self.is_loaded
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(SlowThermometer { is_loaded: false, read_delay: Duration::ZERO })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = true;
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        self.is_loaded = false;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for SlowThermometer {}
+
+impl SensorCapable for SlowThermometer {
+    fn get_supported_gains(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_supported_intervals(&self) -> HashMap<u8, u16> {
+        HashMap::new()
+    }
+
+    fn get_gain(&self, _reading: CapabilityId) -> Result<u16, DeviceError> {
+        Ok(1)
+    }
+
+    fn set_gain(&mut self, _reading: CapabilityId, _gain_id: u8) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn get_interval(&self) -> Result<u16, DeviceError> {
+        Ok(100)
+    }
+
+    fn set_interval(&mut self, _interval_id: u8) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+#[cast_to]
+impl ThermometerCapable for SlowThermometer {
+    // simulates a bus transaction that hangs on the hardware
+    fn get_temperature_celsius(&mut self) -> Result<f32, DeviceError> {
+        std::thread::sleep(self.read_delay);
+        Ok(21.5)
+    }
+
+    fn get_temperature_fahrenheit(&mut self) -> Result<f32, DeviceError> {
+        std::thread::sleep(self.read_delay);
+        Ok(70.7)
+    }
+
+    fn reset_filter(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+fn make_service(read_delay: Duration, command_timeout: Duration) -> (ThermometerService, Arc<RwLock<DeviceServer>>) {
+    let driver = SlowThermometer { is_loaded: false, read_delay };
+    let server = DeviceServerBuilder::configure()
+        .add_device(Device::from_driver(Box::new(driver), None, Some("thermo".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+
+    let server = Arc::new(RwLock::new(server));
+    (ThermometerService::new(&server).with_command_timeout(command_timeout), server)
+}
+
+fn celsius_request() -> Request<ThermometerRequest> {
+    Request::new(ThermometerRequest { address: "thermo".to_string() })
+}
+
+#[tokio::test]
+async fn fast_work_finishes_in_time() {
+    let value = timeout::run_with_timeout(Duration::from_millis(500), || Ok(5)).await.unwrap();
+    assert_eq!(value, 5);
+
+    let err = timeout::run_with_timeout::<u32, _>(Duration::from_millis(500), || Err(DeviceError::NotSupported))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DeviceError::NotSupported));
+}
+
+#[tokio::test]
+async fn slow_work_times_out() {
+    let started = Instant::now();
+    let err = timeout::run_with_timeout(Duration::from_millis(50), || {
+        std::thread::sleep(Duration::from_millis(500));
+        Ok(())
+    })
+    .await
+    .unwrap_err();
+
+    assert!(matches!(err, DeviceError::HardwareError(ref msg, _) if msg == "timeout"));
+    assert!(started.elapsed() < Duration::from_millis(400));
+}
+
+#[tokio::test]
+async fn zero_timeout_waits_for_work() {
+    let value = timeout::run_with_timeout(Duration::ZERO, || {
+        std::thread::sleep(Duration::from_millis(50));
+        Ok(1)
+    })
+    .await
+    .unwrap();
+    assert_eq!(value, 1);
+}
+
+#[tokio::test]
+async fn slow_thermometer_read_trips_timeout() {
+    let (service, server) = make_service(Duration::from_millis(500), Duration::from_millis(50));
+
+    let err = service.get_temperature_celsius(celsius_request()).await.unwrap_err();
+    assert_eq!(err.code(), Code::Internal);
+    assert!(err.message().contains("timeout"));
+
+    // the read is still running on the blocking pool, but only the device is locked
+    assert!(server.try_write_for(Duration::from_millis(100)).is_some());
+}
+
+#[tokio::test]
+async fn thermometer_read_within_timeout() {
+    let (service, _server) = make_service(Duration::from_millis(10), Duration::from_millis(500));
+    let response = service.get_temperature_celsius(celsius_request()).await.unwrap().into_inner();
+    assert_eq!(response.value, 21.5);
+}
+
+// Relay whose line takes a while to switch, like a GPIO expander behind a stuck bus
+struct SlowSwitch {
+    on: bool,
+    switch_delay: Duration,
+}
+
+impl DeviceDriver for SlowSwitch {
+    fn name(&self) -> String {
+        "slow_switch".to_string()
+    }
+
+    fn is_running(&self) -> bool {
+        true
+    }
+
+    fn new(_config: Option<&mut crate::config::DeviceConfig>) -> Result<Self, DeviceError> where Self: Sized {
+        Ok(SlowSwitch { on: false, switch_delay: Duration::ZERO })
+    }
+
+    fn start(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn stop(&mut self, _parent: &mut DeviceServer) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Capability for SlowSwitch {}
+
+#[cast_to]
+impl SwitchCapable for SlowSwitch {
+    fn get_state(&self) -> Result<bool, DeviceError> {
+        Ok(self.on)
+    }
+
+    fn set_state(&mut self, on: bool) -> Result<(), DeviceError> {
+        std::thread::sleep(self.switch_delay);
+        self.on = on;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn stuck_switch_times_out_without_queueing_callers() {
+    let driver = SlowSwitch { on: false, switch_delay: Duration::from_millis(1000) };
+    let mut server = DeviceServerBuilder::configure()
+        .add_device(Device::from_driver(Box::new(driver), None, Some("relay".to_string())).unwrap())
+        .build(true)
+        .unwrap();
+    server.set_device_lock_timeout(Duration::from_millis(50));
+    let address = server.get_device_addresses()[0];
+    let events = server.events().clone();
+
+    let server = Arc::new(RwLock::new(server));
+    let service = SwitchService::new(&server).with_command_timeout(Duration::from_millis(200));
+    let request = || Request::new(SetStateRequest { address: "relay".to_string(), on: true });
+
+    let err = service.set_state(request()).await.unwrap_err();
+    assert_eq!(err.code(), Code::Internal);
+    assert!(err.message().contains("timeout"));
+    assert_eq!(events.stats(&address).consecutive_errors, 1);
+
+    // the first change still holds the device, the next caller gives up on the lock instead of waiting behind it
+    let started = Instant::now();
+    let err = service.set_state(request()).await.unwrap_err();
+    assert_eq!(err.code(), Code::Unavailable);
+    assert!(started.elapsed() < Duration::from_millis(300));
+}
@@ -0,0 +1,321 @@
+use crate::bus::i2c::{I2cAddress, I2cAddressing};
+use crate::bus::i2c_sysfs::SmbusTransfer;
+use crate::capabilities::{estimate_color_temperature, CapabilityId, ReadingCalibration, SensorCapable, MAX_COLOR_TEMPERATURE, MIN_COLOR_TEMPERATURE};
+use crate::config::DeviceConfig;
+use crate::device::{DeviceDriver, DeviceError};
+use crate::drivers::tsl2591_sysfs::{
+    compute_auto_gain, compute_lux, is_overflow, max_count, poll_adc_valid, AutoGainTracker, GainValue,
+    IntegrationTime, Sample, Tsl2591SysfsConfig, Tsl2591SysfsDriver,
+};
+use std::io::{Read, Result, Write};
+
+// Fake chip whose status register reports AVALID after a set number of polls
+struct StatusBus {
+    valid_after: usize,
+    polls: usize,
+    writes: Vec<u8>,
+    pec_reads: Vec<u8>,
+}
+
+impl StatusBus {
+    fn new(valid_after: usize) -> Self {
+        Self { valid_after, polls: 0, writes: Vec::new(), pec_reads: Vec::new() }
+    }
+
+    fn poll(&mut self) -> u8 {
+        let status = if self.polls >= self.valid_after { 0x01 } else { 0x00 };
+        self.polls += 1;
+        status
+    }
+}
+
+impl Write for StatusBus {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.writes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl SmbusTransfer for StatusBus {
+    fn set_slave_address(&mut self, _address: I2cAddress) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_byte_data(&mut self, register: u8) -> Result<u8> {
+        self.pec_reads.push(register);
+        Ok(self.poll())
+    }
+
+    fn write_byte_data(&mut self, _register: u8, _value: u8) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_word_data(&mut self, _register: u8) -> Result<u16> {
+        Ok(0)
+    }
+
+    fn write_word_data(&mut self, _register: u8, _value: u16) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for StatusBus {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        buf[0] = self.poll();
+        Ok(1)
+    }
+}
+
+#[test]
+fn auto_gain_dwell_suppresses_flapping() {
+    let mut tracker = AutoGainTracker::new(0.0, 3, 0);
+    let mut gain = GainValue::_1X;
+    let mut changes = 0;
+
+    // alternate between a very dark and a saturated reading
+    for c0 in [10, 65000, 10, 65000] {
+        if let Some(new_gain) = tracker.update(gain, IntegrationTime::_100MS, c0) {
+            gain = new_gain;
+            tracker.gain_changed();
+            changes += 1;
+        }
+    }
+
+    assert_eq!(changes, 1, "gain changed more than once within the dwell window");
+    assert_eq!(gain, GainValue::_428X);
+
+    // dwell window has passed, the next change is allowed through
+    assert_eq!(tracker.update(gain, IntegrationTime::_100MS, 10), Some(GainValue::_9876X));
+}
+
+#[test]
+fn auto_gain_first_change_not_delayed() {
+    let mut tracker = AutoGainTracker::new(0.05, 10, 60_000);
+    assert_eq!(tracker.update(GainValue::_1X, IntegrationTime::_100MS, 10), Some(GainValue::_428X));
+}
+
+#[test]
+fn auto_gain_hysteresis_margin() {
+    // step up threshold for 1x -> 25x is 873 counts
+    assert_eq!(compute_auto_gain(GainValue::_1X, IntegrationTime::_100MS, 850, 0.0), GainValue::_25X);
+    assert_eq!(compute_auto_gain(GainValue::_1X, IntegrationTime::_100MS, 850, 0.05), GainValue::_1X);
+
+    // step down threshold for 428x at 200ms is 61931 counts
+    assert_eq!(compute_auto_gain(GainValue::_428X, IntegrationTime::_200MS, 62500, 0.0), GainValue::_25X);
+    assert_eq!(compute_auto_gain(GainValue::_428X, IntegrationTime::_200MS, 62500, 0.05), GainValue::_428X);
+}
+
+#[test]
+fn max_count_per_integration_time() {
+    assert_eq!(max_count(IntegrationTime::_100MS), 37887);
+    assert_eq!(max_count(IntegrationTime::_200MS), 65535);
+    assert_eq!(max_count(IntegrationTime::_300MS), 65535);
+    assert_eq!(max_count(IntegrationTime::_400MS), 65535);
+    assert_eq!(max_count(IntegrationTime::_500MS), 65535);
+    assert_eq!(max_count(IntegrationTime::_600MS), 65535);
+}
+
+#[test]
+fn near_max_reading_not_overflow() {
+    assert!(!is_overflow(IntegrationTime::_100MS, 37000, 1200));
+    assert!(!is_overflow(IntegrationTime::_100MS, 37886, 1200));
+    assert!(is_overflow(IntegrationTime::_100MS, 37887, 1200));
+    assert!(is_overflow(IntegrationTime::_100MS, 1200, 40000));
+
+    // bright but legitimate readings at longer integration times
+    assert!(!is_overflow(IntegrationTime::_200MS, 40000, 12000));
+    assert!(!is_overflow(IntegrationTime::_600MS, 65534, 30000));
+    assert!(is_overflow(IntegrationTime::_600MS, 65535, 30000));
+}
+
+#[test]
+fn adc_wait_until_valid() {
+    let mut bus = StatusBus::new(3);
+
+    let elapsed = poll_adc_valid(&mut bus, 1, 50, false).expect("ADC never became valid");
+    assert_eq!(bus.polls, 4);
+    assert_eq!(elapsed, 3);
+    // every poll addresses the status register
+    assert!(bus.writes.iter().all(|&x| x == 0xB3));
+}
+
+#[test]
+fn adc_wait_already_valid() {
+    let mut bus = StatusBus::new(0);
+
+    assert_eq!(poll_adc_valid(&mut bus, 1, 0, false).unwrap(), 0);
+    assert_eq!(bus.polls, 1);
+}
+
+#[test]
+fn adc_wait_timeout() {
+    let mut bus = StatusBus::new(usize::MAX);
+
+    assert!(matches!(poll_adc_valid(&mut bus, 1, 5, false), Err(DeviceError::HardwareError(..))));
+    assert_eq!(bus.polls, 6);
+}
+
+#[test]
+fn adc_wait_uses_pec_transfers_when_enabled() {
+    let mut bus = StatusBus::new(2);
+
+    poll_adc_valid(&mut bus, 1, 50, true).expect("ADC never became valid");
+    assert_eq!(bus.polls, 3);
+    // the status register is read through the SMBus transfer, nothing goes out as a plain write
+    assert_eq!(bus.pec_reads, vec![0xB3; 3]);
+    assert!(bus.writes.is_empty());
+}
+
+#[test]
+fn lux_scales_with_coefficient() {
+    let base = compute_lux(IntegrationTime::_100MS, GainValue::_25X, 12000, 3000, 735.0);
+    let doubled = compute_lux(IntegrationTime::_100MS, GainValue::_25X, 12000, 3000, 1470.0);
+    let halved = compute_lux(IntegrationTime::_100MS, GainValue::_25X, 12000, 3000, 367.5);
+
+    assert!(base > 0.0);
+    assert!((doubled / base - 2.0).abs() < 1e-4, "{} vs {}", doubled, base);
+    assert!((halved / base - 0.5).abs() < 1e-4, "{} vs {}", halved, base);
+}
+
+#[test]
+fn color_temperature_from_ir_ratio() {
+    let cct = |full, ir| estimate_color_temperature(full, ir).unwrap();
+
+    // incandescent, direct sunlight and overcast daylight
+    assert!((cct(10000, 4000) - 2660.0).abs() < 5.0, "{}", cct(10000, 4000));
+    assert!((cct(10000, 2000) - 4442.0).abs() < 5.0, "{}", cct(10000, 2000));
+    assert!((cct(10000, 1200) - 6483.0).abs() < 5.0, "{}", cct(10000, 1200));
+
+    // only the ratio matters, not the brightness
+    assert_eq!(cct(500, 200), cct(10000, 4000));
+    assert!(cct(10000, 3000) > cct(10000, 4000));
+
+    assert_eq!(cct(10000, 10000), MIN_COLOR_TEMPERATURE);
+    assert_eq!(cct(10000, 10), MAX_COLOR_TEMPERATURE);
+    assert_eq!(cct(10000, 0), MAX_COLOR_TEMPERATURE);
+    assert!(matches!(estimate_color_temperature(0, 0), Err(DeviceError::InvalidOperation(_))));
+    assert!(matches!(estimate_color_temperature(100, 200), Err(DeviceError::Other(_))));
+}
+
+#[test]
+fn tsl2591_requires_i2c_sysfs() {
+    let mut device_config = DeviceConfig::new(
+        "tsl2591_sysfs".to_string(),
+        None,
+        serde_json::to_value(Tsl2591SysfsConfig::default()).unwrap(),
+    );
+
+    let driver = Tsl2591SysfsDriver::new(Some(&mut device_config)).expect("failed to build driver");
+    assert_eq!(driver.required_controllers(), vec!["i2c_sysfs"]);
+}
+
+fn make_config(integration_time: u16, gain: u16) -> DeviceConfig {
+    let mut data = Tsl2591SysfsConfig::default();
+    data.default_integration_time = integration_time;
+    data.default_gain = gain;
+    DeviceConfig::new("tsl2591_sysfs".to_string(), None, serde_json::to_value(data).unwrap())
+}
+
+fn config_data(config: &DeviceConfig) -> Tsl2591SysfsConfig {
+    serde_json::from_value(config.driver_data.clone()).unwrap()
+}
+
+#[test]
+fn reconfigure_reprograms_chip() {
+    let mut driver = Tsl2591SysfsDriver::new(Some(&mut make_config(100, 25))).expect("failed to build driver");
+    assert_eq!(driver.get_max_count(), max_count(IntegrationTime::_100MS));
+
+    // a loaded device holds the bus, the new timing and gain go straight to the chip
+    let mut bus = StatusBus::new(0);
+    driver
+        .reconfigure(config_data(&make_config(400, 428)), Some(&mut bus))
+        .expect("failed to reconfigure");
+
+    assert_eq!(bus.writes, vec![0xA0 | 0x01, IntegrationTime::_400MS as u8 | GainValue::_428X as u8]);
+    assert_eq!(driver.get_max_count(), max_count(IntegrationTime::_400MS));
+}
+
+#[test]
+fn sample_keeps_gain_it_was_read_at() {
+    let mut driver = Tsl2591SysfsDriver::new(Some(&mut make_config(100, 25))).expect("failed to build driver");
+    let mut bus = StatusBus::new(0);
+
+    // a dark reading makes auto gain step up right after the counts come in
+    let sample = driver.take_sample(&mut bus, 500, 100);
+    assert_eq!(
+        sample,
+        Sample { c0: 500, c1: 100, gain: GainValue::_25X, integration_time: IntegrationTime::_100MS }
+    );
+    assert_eq!(bus.writes, vec![0xA0 | 0x01, IntegrationTime::_100MS as u8 | GainValue::_428X as u8]);
+
+    // lux comes from the gain the counts were integrated at, not the one auto gain just picked
+    let lux = sample.lux(735.0);
+    assert_eq!(lux, compute_lux(IntegrationTime::_100MS, GainValue::_25X, 500, 100, 735.0));
+    assert_ne!(lux, compute_lux(IntegrationTime::_100MS, GainValue::_428X, 500, 100, 735.0));
+
+    // the next read is tagged with the new gain
+    assert_eq!(driver.take_sample(&mut bus, 700, 100).gain, GainValue::_428X);
+}
+
+#[test]
+fn apply_config_without_bus() {
+    let mut driver = Tsl2591SysfsDriver::new(Some(&mut make_config(100, 25))).expect("failed to build driver");
+    driver.apply_config(&make_config(600, 1)).expect("failed to apply config");
+    assert_eq!(driver.get_max_count(), max_count(IntegrationTime::_600MS));
+}
+
+#[test]
+fn apply_config_rejects_bad_values() {
+    let mut driver = Tsl2591SysfsDriver::new(Some(&mut make_config(100, 25))).expect("failed to build driver");
+    assert!(matches!(driver.apply_config(&make_config(150, 25)), Err(DeviceError::InvalidConfig(..))));
+    assert_eq!(driver.get_max_count(), max_count(IntegrationTime::_100MS));
+
+    // moving the chip needs a restart
+    let mut moved = config_data(&make_config(200, 25));
+    moved.i2c.device_address = 0x30;
+    let mut bus = StatusBus::new(0);
+    assert!(matches!(driver.reconfigure(moved, Some(&mut bus)), Err(DeviceError::NotSupported)));
+    assert!(bus.writes.is_empty());
+}
+
+#[test]
+fn device_address_checked_against_addressing() {
+    let mut config = make_config(100, 25);
+    config.driver_data["device_address"] = serde_json::json!(0x129);
+    assert!(matches!(Tsl2591SysfsDriver::new(Some(&mut config)), Err(DeviceError::InvalidConfig(..))));
+
+    config.driver_data["addressing"] = serde_json::json!("TenBit");
+    assert!(Tsl2591SysfsDriver::new(Some(&mut config)).is_ok());
+
+    // configs written before the field existed stay 7-bit
+    let mut legacy = make_config(100, 25);
+    legacy.driver_data.as_object_mut().unwrap().remove("addressing");
+    assert_eq!(config_data(&legacy).i2c.addressing, I2cAddressing::SevenBit);
+}
+
+#[test]
+fn illuminance_calibration_from_config() {
+    let driver = Tsl2591SysfsDriver::new(Some(&mut make_config(100, 25))).expect("failed to build driver");
+    assert_eq!(driver.get_calibration(CapabilityId::LightSensor).unwrap(), ReadingCalibration::default());
+
+    let mut config = make_config(100, 25);
+    config.driver_data["calibration_scale"] = serde_json::json!(1.25);
+    config.driver_data["calibration_offset"] = serde_json::json!(-2.0);
+    let mut driver = Tsl2591SysfsDriver::new(Some(&mut config)).expect("failed to build driver");
+    let calibration = driver.get_calibration(CapabilityId::LightSensor).unwrap();
+    assert_eq!(calibration, ReadingCalibration::new(1.25, -2.0).unwrap());
+
+    // a known illuminance comes out corrected
+    let lux = compute_lux(IntegrationTime::_100MS, GainValue::_25X, 1000, 200, 735.0);
+    assert!((calibration.apply(lux) - (lux * 1.25 - 2.0)).abs() < 1e-4);
+
+    driver.set_calibration(CapabilityId::LightSensor, ReadingCalibration::default()).unwrap();
+    assert_eq!(driver.get_calibration(CapabilityId::LightSensor).unwrap().apply(lux), lux);
+
+    config.driver_data["calibration_scale"] = serde_json::json!(0.0);
+    assert!(matches!(Tsl2591SysfsDriver::new(Some(&mut config)), Err(DeviceError::InvalidConfig(..))));
+}
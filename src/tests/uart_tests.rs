@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::bus::BusController;
+use crate::bus::uart::{FlowControl, SelectLines, UARTBusController, UARTDefinition, UARTError, UartSettings};
+use crate::drivers::serial_passthrough::SerialPassthroughConfig;
+use crate::gpio::{GpioBorrowChecker, PinState};
+use parking_lot::{Mutex, RwLock};
+use rppal::uart::{Error, Parity};
+
+// Records the line settings instead of touching a tty
+#[derive(Debug, PartialEq)]
+struct FakeUart {
+    baud_rate: u32,
+    parity: Parity,
+    data_bits: u8,
+    stop_bits: u8,
+    flow_control: bool,
+}
+
+impl UartSettings for FakeUart {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), Error> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> Result<(), Error> {
+        self.parity = parity;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, data_bits: u8) -> Result<(), Error> {
+        self.data_bits = data_bits;
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: u8) -> Result<(), Error> {
+        self.stop_bits = stop_bits;
+        Ok(())
+    }
+
+    fn set_hardware_flow_control(&mut self, enabled: bool) -> Result<(), Error> {
+        self.flow_control = enabled;
+        Ok(())
+    }
+}
+
+fn make_controller() -> (UARTBusController, Arc<RwLock<GpioBorrowChecker>>) {
+    let mut pin_map = HashMap::new();
+    for pin in 2..8 {
+        pin_map.insert(pin, PinState::new(pin, pin + 10));
+    }
+
+    let gpio = Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)));
+    let mut ports = HashMap::new();
+    ports.insert(0, UARTDefinition::new("/dev/null", 2, 3));
+    ports.insert(1, UARTDefinition::new("/dev/zero", 4, 5).with_flow_control(6, 7));
+
+    let controller = UARTBusController::with_internals(&gpio, ports).expect("failed to build controller");
+    (controller, gpio)
+}
+
+#[test]
+fn uart_reconfigure_keeps_lease() {
+    let (mut controller, gpio) = make_controller();
+    let mut uart = controller
+        .open_with(0, FlowControl::None, |_| Ok(FakeUart { baud_rate: 115200, parity: Parity::None, data_bits: 8, stop_bits: 1, flow_control: false }))
+        .unwrap();
+
+    let lease_id = controller.lease_id(0).expect("port should be leased");
+    controller.reconfigure(0, &mut uart, 9600, Parity::Even, 7, 2).unwrap();
+
+    assert_eq!(uart, FakeUart { baud_rate: 9600, parity: Parity::Even, data_bits: 7, stop_bits: 2, flow_control: false });
+    assert_eq!(controller.lease_id(0), Some(lease_id));
+    assert!(!gpio.read().can_borrow_many(&[2, 3]));
+
+    controller.close(0).unwrap();
+    assert_eq!(controller.lease_id(0), None);
+    assert!(gpio.read().can_borrow_many(&[2, 3]));
+}
+
+#[test]
+fn uart_reconfigure_requires_open_port() {
+    let (mut controller, _) = make_controller();
+    let mut uart = FakeUart { baud_rate: 115200, parity: Parity::None, data_bits: 8, stop_bits: 1, flow_control: false };
+
+    assert_eq!(controller.reconfigure(1, &mut uart, 9600, Parity::None, 8, 1), Err(UARTError::LeaseNotFound));
+    assert_eq!(controller.reconfigure(7, &mut uart, 9600, Parity::None, 8, 1), Err(UARTError::PortNotFound));
+    assert_eq!(uart.baud_rate, 115200);
+}
+
+fn open_fake(controller: &mut UARTBusController, port: u8, flow_control: FlowControl) -> Result<FakeUart, UARTError> {
+    controller.open_with(port, flow_control, |_| {
+        Ok(FakeUart { baud_rate: 115200, parity: Parity::None, data_bits: 8, stop_bits: 1, flow_control: false })
+    })
+}
+
+#[test]
+fn uart_flow_control_round_trips_through_config() {
+    let mut config = SerialPassthroughConfig::default();
+    config.flow_control = FlowControl::RtsCts;
+    let data = serde_json::to_value(&config).unwrap();
+    let parsed: SerialPassthroughConfig = serde_json::from_value(data.clone()).unwrap();
+    assert_eq!(parsed.flow_control, FlowControl::RtsCts);
+
+    // configs written before flow control existed leave it off
+    let mut data = data;
+    data.as_object_mut().unwrap().remove("flow_control");
+    let parsed: SerialPassthroughConfig = serde_json::from_value(data).unwrap();
+    assert_eq!(parsed.flow_control, FlowControl::None);
+
+    let definition: UARTDefinition = serde_json::from_str("{\"path\": \"/dev/null\", \"rx\": 2, \"tx\": 3}").unwrap();
+    assert_eq!(definition.pins(), vec![2, 3]);
+}
+
+#[test]
+fn uart_flow_control_reserves_rts_cts_pins() {
+    let (mut controller, gpio) = make_controller();
+    let uart = open_fake(&mut controller, 1, FlowControl::RtsCts).unwrap();
+
+    assert!(uart.flow_control);
+    assert!(!gpio.read().can_borrow_many(&[6]));
+    assert!(!gpio.read().can_borrow_many(&[7]));
+
+    let mut owned = controller.owned_pins();
+    owned.sort();
+    assert_eq!(owned, vec![4, 5, 6, 7]);
+
+    controller.close(1).unwrap();
+    assert!(gpio.read().can_borrow_many(&[4, 5, 6, 7]));
+
+    // without flow control the RTS/CTS pins stay free
+    let uart = open_fake(&mut controller, 1, FlowControl::None).unwrap();
+    assert!(!uart.flow_control);
+    assert!(gpio.read().can_borrow_many(&[6, 7]));
+}
+
+#[test]
+fn uart_flow_control_needs_rts_cts_pins() {
+    let (mut controller, gpio) = make_controller();
+    assert!(matches!(open_fake(&mut controller, 0, FlowControl::RtsCts), Err(UARTError::InvalidConfig(_))));
+    assert_eq!(controller.lease_id(0), None);
+    assert!(gpio.read().can_borrow_many(&[2, 3]));
+}
+
+// Records every select line write as (BCM number, level)
+#[derive(Clone, Default)]
+struct FakeSelectLines(Arc<Mutex<Vec<(u8, u8)>>>);
+
+impl SelectLines for FakeSelectLines {
+    fn write(&mut self, bcm_id: u8, level: u8) -> Result<(), UARTError> {
+        self.0.lock().push((bcm_id, level));
+        Ok(())
+    }
+}
+
+fn make_gpio(active_low: &[u8]) -> Arc<RwLock<GpioBorrowChecker>> {
+    let mut pin_map = HashMap::new();
+    for pin in 2..8 {
+        pin_map.insert(pin, PinState::new(pin, pin + 10).with_active_low(active_low.contains(&pin)));
+    }
+
+    Arc::new(RwLock::new(GpioBorrowChecker::new(pin_map)))
+}
+
+#[test]
+fn muxed_ports_are_selected_one_at_a_time() {
+    let gpio = make_gpio(&[7]);
+    let mut ports = HashMap::new();
+    ports.insert(0, UARTDefinition::new("/dev/null", 2, 3).with_select(6));
+    ports.insert(1, UARTDefinition::new("/dev/null", 2, 3).with_select(7));
+    let select_lines = FakeSelectLines::default();
+    let mut controller = UARTBusController::with_internals(&gpio, ports).unwrap().with_select_lines(select_lines.clone());
+
+    open_fake(&mut controller, 0, FlowControl::None).unwrap();
+    assert_eq!(*select_lines.0.lock(), vec![(16, 1)]);
+    assert!(!gpio.read().can_borrow_many(&[6]));
+
+    // the other side of the mux waits until the first port is closed
+    assert!(matches!(open_fake(&mut controller, 1, FlowControl::None), Err(UARTError::Busy)));
+    assert_eq!(controller.lease_id(1), None);
+    assert_eq!(controller.close(1), Err(UARTError::LeaseNotFound));
+    assert!(controller.lease_id(0).is_some());
+
+    controller.close(0).unwrap();
+    assert_eq!(*select_lines.0.lock(), vec![(16, 1), (16, 0)]);
+    assert!(gpio.read().can_borrow_many(&[2, 3, 6, 7]));
+
+    // pin 7 is active low, selecting it pulls the line down
+    open_fake(&mut controller, 1, FlowControl::None).unwrap();
+    controller.close(1).unwrap();
+    assert_eq!(*select_lines.0.lock(), vec![(16, 1), (16, 0), (17, 0), (17, 1)]);
+
+    // a port that fails to open doesn't stay selected
+    let err = controller.open_with(0, FlowControl::None, |_| Err::<FakeUart, _>(UARTError::HardwareError("no device".to_string())));
+    assert!(err.is_err());
+    assert_eq!(select_lines.0.lock()[4..], [(16, 1), (16, 0)]);
+    assert_eq!(controller.lease_id(0), None);
+}
+
+#[test]
+fn muxed_ports_need_their_own_select_lines() {
+    let gpio = make_gpio(&[]);
+    let build = |second: UARTDefinition| {
+        let mut ports = HashMap::new();
+        ports.insert(0, UARTDefinition::new("/dev/null", 2, 3).with_select(6));
+        ports.insert(1, second);
+        UARTBusController::with_internals(&gpio, ports)
+    };
+
+    assert!(build(UARTDefinition::new("/dev/null", 2, 3).with_select(7)).is_ok());
+    assert!(matches!(build(UARTDefinition::new("/dev/null", 2, 3).with_select(6)), Err(UARTError::InvalidConfig(_))));
+    assert!(matches!(build(UARTDefinition::new("/dev/null", 2, 3)), Err(UARTError::InvalidConfig(_))));
+    // a select line can't double as one of the port's own pins
+    assert!(matches!(build(UARTDefinition::new("/dev/null", 2, 3).with_select(2)), Err(UARTError::InvalidConfig(_))));
+}
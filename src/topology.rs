@@ -0,0 +1,203 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use log::{error, info};
+use parking_lot::RwLock;
+use uuid::Uuid;
+use crate::bus::{self, ControllerRegistry};
+use crate::config::{BusControllerConfig, ConfigSectionGPIO, Topology};
+use crate::device::{Device, DeviceServer};
+use crate::drivers::DriverRegistry;
+use crate::gpio::GpioBorrowChecker;
+
+// Checks a topology without touching the running server or any hardware
+pub fn validate_topology(
+    topology: &Topology,
+    pins: &ConfigSectionGPIO,
+    controllers: &ControllerRegistry,
+    drivers: &DriverRegistry
+) -> Result<(), String> {
+    topology.validate().map_err(|e| e.to_string())?;
+
+    let mut resolved_controllers = topology.controller_section.controllers.clone();
+    for controller in &mut resolved_controllers {
+        pins.resolve_pin_names(&mut controller.data)
+            .map_err(|e| format!("invalid bus controller {}: {}", controller.name, e))?;
+    }
+
+    if let Some(conflict) = bus::find_pin_conflicts(&resolved_controllers).first() {
+        return Err(format!("GPIO pin conflict: {}", conflict));
+    }
+
+    for controller in &topology.controller_section.controllers {
+        if !controllers.contains(&controller.name) {
+            return Err(format!("bus controller {} is not implemented by this server", controller.name));
+        }
+    }
+
+    // building throwaway devices checks their data against the drivers' config structs
+    for device in &topology.device_section.devices {
+        let mut resolved = device.clone();
+        pins.resolve_pin_names(&mut resolved.driver_data)
+            .map_err(|e| format!("invalid device (driver: {}): {}", device.driver, e))?;
+        drivers.build(&mut resolved)
+            .map_err(|e| format!("invalid device (driver: {}): {}", device.driver, e))?;
+    }
+
+    Ok(())
+}
+
+// Clears the server and fills it from the topology. Unlike at startup nothing is skipped, a controller or device
+// that doesn't come up fails the whole load. Returns the registered devices with their index in the device section.
+// Pin names are resolved on copies like at startup, so the topology keeps them.
+pub fn load_topology(
+    server: &mut DeviceServer,
+    topology: &mut Topology,
+    pins: &ConfigSectionGPIO,
+    controllers: &ControllerRegistry,
+    drivers: &DriverRegistry,
+    gpio: &Arc<RwLock<GpioBorrowChecker>>
+) -> Result<Vec<(Uuid, usize)>, String> {
+    fill_server(server, topology, pins, controllers, drivers, gpio, false)
+}
+
+// Like load_topology, but a controller or device that doesn't come up is skipped and logged the way startup does it.
+// Used to bring back a topology that was running before, where part of it is better than an empty unit.
+pub fn restore_topology(
+    server: &mut DeviceServer,
+    topology: &mut Topology,
+    pins: &ConfigSectionGPIO,
+    controllers: &ControllerRegistry,
+    drivers: &DriverRegistry,
+    gpio: &Arc<RwLock<GpioBorrowChecker>>
+) -> Result<Vec<(Uuid, usize)>, String> {
+    fill_server(server, topology, pins, controllers, drivers, gpio, true)
+}
+
+// Fails the load, or logs and carries on when failures are skipped
+fn skip_or_fail(skip_failures: bool, message: String) -> Result<(), String> {
+    match skip_failures {
+        true => {
+            error!("{}, skipping it", message);
+            Ok(())
+        }
+        false => Err(message)
+    }
+}
+
+fn fill_server(
+    server: &mut DeviceServer,
+    topology: &mut Topology,
+    pins: &ConfigSectionGPIO,
+    controllers: &ControllerRegistry,
+    drivers: &DriverRegistry,
+    gpio: &Arc<RwLock<GpioBorrowChecker>>,
+    skip_failures: bool
+) -> Result<Vec<(Uuid, usize)>, String> {
+    server.clear().map_err(|e| format!("failed to stop the current devices: {}", e))?;
+    // the controllers that held these are gone with the server's bus list
+    gpio.write().release_all();
+
+    let section = &topology.device_section;
+    server.set_device_lock_timeout(Duration::from_millis(section.device_lock_timeout_ms));
+    server.set_recover_stuck_locks(section.recover_stuck_locks);
+    server.set_unhealthy_error_threshold(section.unhealthy_error_threshold);
+
+    for bus_config in &mut topology.controller_section.controllers {
+        if let Err(e) = register_bus(server, bus_config, pins, controllers, gpio) {
+            skip_or_fail(skip_failures, e)?;
+        }
+    }
+
+    let mut config_indices = Vec::new();
+    let mut devices = Vec::new();
+    // devices that depend on one of these are skipped as well
+    let mut failed_devices: HashSet<String> = HashSet::new();
+    for (index, device_config) in topology.device_section.devices.iter_mut().enumerate() {
+        let mut resolved = device_config.clone();
+        let device = pins.resolve_pin_names(&mut resolved.driver_data)
+            .map_err(|e| e.to_string())
+            .and_then(|_| drivers.build(&mut resolved).map_err(|e| e.to_string()));
+        if device_config.driver_data.is_null() {
+            device_config.driver_data = resolved.driver_data;
+        }
+
+        match device {
+            Ok(device) => {
+                config_indices.push(index);
+                devices.push(device);
+            }
+            Err(e) => {
+                skip_or_fail(skip_failures, format!("failed to build device (driver: {}): {}", device_config.driver, e))?;
+                failed_devices.extend(device_config.friendly_name.clone());
+            }
+        }
+    }
+
+    let (order, missing_dependencies) = server.resolve_partial_start_order(&devices).map_err(|e| e.to_string())?;
+    let missing_dependencies: HashMap<usize, String> = missing_dependencies.into_iter().collect();
+    let mut devices: Vec<Option<Device>> = devices.into_iter().map(Some).collect();
+    let mut entries = Vec::new();
+    for index in order {
+        let device = match devices[index].take() {
+            Some(device) => device,
+            None => continue
+        };
+
+        let device_config = &mut topology.device_section.devices[config_indices[index]];
+        let dependency = device.dependencies().iter()
+            .find(|x| failed_devices.contains(*x))
+            .or_else(|| missing_dependencies.get(&index));
+        if let Some(dependency) = dependency {
+            skip_or_fail(skip_failures, format!(
+                "device \"{}\" (driver: {}) can't start, dependency \"{}\" is not available",
+                device.device_name(), device_config.driver, dependency
+            ))?;
+            failed_devices.insert(device.device_name());
+            continue;
+        }
+
+        let device_name = device.device_name();
+        let address = match server.register_device(device, true) {
+            Ok(address) => address,
+            Err(e) => {
+                skip_or_fail(skip_failures, format!("failed to register device (driver: {}): {}", device_config.driver, e))?;
+                failed_devices.insert(device_name);
+                continue;
+            }
+        };
+
+        if let Some(data) = server.get_device(&address).and_then(|x| x.as_ref().updated_driver_data()) {
+            device_config.driver_data = data;
+        }
+
+        entries.push((address, config_indices[index]));
+    }
+
+    info!(
+        "Loaded topology with {} bus controller(s) and {} device(s)",
+        server.get_buses().len(),
+        entries.len()
+    );
+    Ok(entries)
+}
+
+fn register_bus(
+    server: &mut DeviceServer,
+    bus_config: &mut BusControllerConfig,
+    pins: &ConfigSectionGPIO,
+    controllers: &ControllerRegistry,
+    gpio: &Arc<RwLock<GpioBorrowChecker>>
+) -> Result<(), String> {
+    let mut resolved = bus_config.clone();
+    pins.resolve_pin_names(&mut resolved.data)
+        .map_err(|e| format!("failed to build bus controller {}: {}", bus_config.name, e))?;
+    let bus = controllers.build(gpio, &mut resolved);
+    if bus_config.data.is_null() {
+        bus_config.data = resolved.data;
+    }
+
+    let bus = bus.map_err(|e| format!("failed to build bus controller {}: {}", bus_config.name, e))?;
+    server.register_bus(bus)
+        .map_err(|e| format!("failed to register bus controller {}: {}", bus_config.name, e))
+}